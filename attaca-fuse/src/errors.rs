@@ -0,0 +1,22 @@
+use attaca::marshal::ObjectHash;
+
+
+error_chain! {
+    types { Error, ErrorKind, ResultExt, Result; }
+
+    links {
+        Attaca(::attaca::Error, ::attaca::ErrorKind);
+    }
+
+    foreign_links {
+        Clap(::clap::Error);
+        Io(::std::io::Error);
+    }
+
+    errors {
+        NotMountable(hash: ObjectHash) {
+            description("only commits and subtrees may be mounted"),
+            display("{} is neither a commit nor a subtree", hash),
+        }
+    }
+}
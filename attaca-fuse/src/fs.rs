@@ -0,0 +1,444 @@
+//! A read-only FUSE filesystem presenting a single subtree of an object store.
+//!
+//! Inodes are allocated lazily: a directory's children are only read from the store (and given
+//! inode numbers) the first time the directory is looked into. File reads are served a page at a
+//! time from an LRU page cache, and a page is only fetched from the store when it is not cached,
+//! in which case only the chunks overlapping the page are loaded, through a `ChunkedReader` kept
+//! for as long as the file is open. Where the store supports it, only the part of a chunk which
+//! overlaps the page is read.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+
+use fuse::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+           ReplyEntry, ReplyOpen, Request};
+use futures::prelude::*;
+use libc::{self, c_int};
+use time::Timespec;
+
+use attaca::lazy::{self, ChunkedReader};
+use attaca::marshal::{Object, ObjectHash, SubtreeEntry};
+use attaca::store::RangeStore;
+
+
+/// Snapshots never change, so the kernel may cache attributes and entries for as long as it likes.
+const TTL: Timespec = Timespec { sec: 3600, nsec: 0 };
+
+
+/// The inode number of the root directory of the mount.
+const ROOT_INO: u64 = 1;
+
+
+/// The size of a single page in the page cache.
+pub const PAGE_SIZE: u64 = 1 << 20;
+
+
+enum Node {
+    Directory {
+        parent: u64,
+        hash: ObjectHash,
+        children: Option<BTreeMap<OsString, u64>>,
+    },
+    File {
+        hash: ObjectHash,
+        size: u64,
+        executable: bool,
+    },
+    Symlink { hash: ObjectHash },
+}
+
+
+/// A least-recently-used cache of fixed-size pages of file data, keyed by data object and page
+/// index.
+struct PageCache {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<(ObjectHash, u64), (u64, Vec<u8>)>,
+    recency: BTreeMap<u64, (ObjectHash, u64)>,
+}
+
+
+impl PageCache {
+    fn new(capacity: usize) -> Self {
+        PageCache {
+            capacity,
+            tick: 0,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: (ObjectHash, u64)) -> Option<&[u8]> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        match self.pages.get_mut(&key) {
+            Some(&mut (ref mut last_used, ref page)) => {
+                self.recency.remove(last_used);
+                self.recency.insert(tick, key);
+                *last_used = tick;
+
+                Some(&page[..])
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: (ObjectHash, u64), page: Vec<u8>) {
+        self.tick += 1;
+        self.recency.insert(self.tick, key);
+        if let Some((last_used, _)) = self.pages.insert(key, (self.tick, page)) {
+            self.recency.remove(&last_used);
+        }
+
+        while self.pages.len() > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let evicted = self.recency.remove(&oldest).unwrap();
+            self.pages.remove(&evicted);
+        }
+    }
+}
+
+
+pub struct SnapshotFs<S: RangeStore> {
+    store: S,
+    nodes: Vec<Node>,
+    timestamp: Timespec,
+    uid: u32,
+    gid: u32,
+    pages: PageCache,
+
+    /// Readers over the contents of open files, by file handle.
+    readers: HashMap<u64, ChunkedReader<S>>,
+    next_fh: u64,
+}
+
+
+/// Append the part of `page`, which starts at `page_start`, from `position` up to `end` to `buf`.
+/// Returns where the part ends, or `None` if the page ends before `position`.
+fn copy_page(
+    buf: &mut Vec<u8>,
+    page: &[u8],
+    page_start: u64,
+    position: u64,
+    end: u64,
+) -> Option<u64> {
+    let page_end = cmp::min(page_start + page.len() as u64, end);
+    if page_end <= position {
+        return None;
+    }
+
+    buf.extend_from_slice(
+        &page[(position - page_start) as usize..(page_end - page_start) as usize],
+    );
+    Some(page_end)
+}
+
+
+impl<S: RangeStore> SnapshotFs<S> {
+    /// Create a filesystem presenting the given subtree. All files and directories report
+    /// `timestamp` as their times, and at most `cache_pages` pages of `PAGE_SIZE` bytes are
+    /// cached at once.
+    pub fn new(
+        store: S,
+        subtree_hash: ObjectHash,
+        timestamp: Timespec,
+        cache_pages: usize,
+    ) -> Self {
+        let root = Node::Directory {
+            parent: ROOT_INO,
+            hash: subtree_hash,
+            children: None,
+        };
+
+        SnapshotFs {
+            store,
+            nodes: vec![root],
+            timestamp,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            pages: PageCache::new(cache_pages),
+            readers: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        if ino == 0 {
+            None
+        } else {
+            self.nodes.get((ino - 1) as usize)
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm, nlink) = match self.node(ino) {
+            Some(&Node::Directory { .. }) => (FileType::Directory, 0, 0o555, 2),
+            Some(&Node::File { size, executable: false, .. }) => {
+                (FileType::RegularFile, size, 0o444, 1)
+            }
+            Some(&Node::File { size, executable: true, .. }) => {
+                (FileType::RegularFile, size, 0o555, 1)
+            }
+            Some(&Node::Symlink { .. }) => (FileType::Symlink, 0, 0o777, 1),
+            None => return None,
+        };
+
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: self.timestamp,
+            mtime: self.timestamp,
+            ctime: self.timestamp,
+            crtime: self.timestamp,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        })
+    }
+
+    /// Read a directory's subtree object and allocate inodes for its children, if this has not
+    /// already been done.
+    fn expand(&mut self, ino: u64) -> Result<(), c_int> {
+        let hash = match self.node(ino) {
+            Some(&Node::Directory { children: Some(_), .. }) => return Ok(()),
+            Some(&Node::Directory { hash, .. }) => hash,
+            Some(_) => return Err(libc::ENOTDIR),
+            None => return Err(libc::ENOENT),
+        };
+
+        let subtree_object = match self.store.read_object(hash).wait() {
+            Ok(Object::Subtree(subtree_object)) => subtree_object,
+            _ => return Err(libc::EIO),
+        };
+
+        let mut children = BTreeMap::new();
+        for (name, entry) in subtree_object.entries {
+            let node = match *entry.unannotated() {
+                SubtreeEntry::File(hash, size) => Node::File {
+                    hash,
+                    size,
+                    executable: false,
+                },
+                SubtreeEntry::Executable(hash, size) => Node::File {
+                    hash,
+                    size,
+                    executable: true,
+                },
+                SubtreeEntry::Symlink(hash) => Node::Symlink { hash },
+                SubtreeEntry::Subtree(hash) => Node::Directory {
+                    parent: ino,
+                    hash,
+                    children: None,
+                },
+                // A nested repository's commit is in another store, so it shows as empty.
+                SubtreeEntry::Subrepository(_, hash) => Node::Directory {
+                    parent: ino,
+                    hash,
+                    children: Some(BTreeMap::new()),
+                },
+                SubtreeEntry::Annotated(..) => unreachable!("unannotated entries are never annotated"),
+            };
+
+            self.nodes.push(node);
+            children.insert(name, self.nodes.len() as u64);
+        }
+
+        if let Node::Directory { children: ref mut slot, .. } = self.nodes[(ino - 1) as usize] {
+            *slot = Some(children);
+        }
+
+        Ok(())
+    }
+
+    fn lookup_child(&mut self, parent: u64, name: &OsStr) -> Result<u64, c_int> {
+        self.expand(parent)?;
+
+        match self.node(parent) {
+            Some(&Node::Directory { children: Some(ref children), .. }) => {
+                children.get(name).cloned().ok_or(libc::ENOENT)
+            }
+            _ => Err(libc::ENOENT),
+        }
+    }
+
+    /// Read a byte range of the file open as `fh` through the page cache.
+    fn read_file(
+        &mut self,
+        fh: u64,
+        hash: ObjectHash,
+        size: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, c_int> {
+        let end = cmp::min(offset + len, size);
+        let mut buf = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut position = offset;
+
+        while position < end {
+            let index = position / PAGE_SIZE;
+            let page_start = index * PAGE_SIZE;
+
+            let cached = match self.pages.get((hash, index)) {
+                Some(page) => Some(copy_page(&mut buf, page, page_start, position, end)),
+                None => None,
+            };
+
+            // A page just read is copied from before it is cached, as the cache may not keep it.
+            let copied = match cached {
+                Some(copied) => copied,
+                None => {
+                    let reader = self.readers.get_mut(&fh).ok_or(libc::EBADF)?;
+                    let mut page = vec![0u8; PAGE_SIZE as usize];
+                    let read = reader.read_at(page_start, &mut page).map_err(|_| libc::EIO)?;
+                    page.truncate(read);
+
+                    let copied = copy_page(&mut buf, &page, page_start, position, end);
+                    self.pages.insert((hash, index), page);
+                    copied
+                }
+            };
+
+            match copied {
+                Some(page_end) => position = page_end,
+                None => break,
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+
+impl<S: RangeStore> Filesystem for SnapshotFs<S> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_child(parent, name) {
+            Ok(ino) => reply.entry(&TTL, &self.attr(ino).unwrap(), 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let hash = match self.node(ino) {
+            Some(&Node::Symlink { hash }) => hash,
+            Some(_) => return reply.error(libc::EINVAL),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        // Symlink targets are small, so they are read whole rather than through the page cache.
+        match lazy::read_range(&self.store, hash, 0, PAGE_SIZE).wait() {
+            Ok(target) => reply.data(&target),
+            Err(_) => reply.error(libc::EIO),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let file = match self.node(ino) {
+            Some(&Node::File { hash, size, .. }) => Some((hash, size)),
+            _ => None,
+        };
+
+        if flags as c_int & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(libc::EROFS);
+        } else if let Some((hash, size)) = file {
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.readers.insert(fh, ChunkedReader::new(&self.store, hash, size));
+
+            reply.opened(fh, 0);
+        } else if self.node(ino).is_none() {
+            reply.error(libc::ENOENT);
+        } else {
+            reply.opened(0, 0);
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.readers.remove(&fh);
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let (hash, file_size) = match self.node(ino) {
+            Some(&Node::File { hash, size, .. }) => (hash, size),
+            Some(&Node::Directory { .. }) => return reply.error(libc::EISDIR),
+            Some(&Node::Symlink { .. }) => return reply.error(libc::EINVAL),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        match self.read_file(fh, hash, file_size, offset as u64, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if let Err(errno) = self.expand(ino) {
+            return reply.error(errno);
+        }
+
+        let mut entries = Vec::new();
+        if let Some(&Node::Directory { parent, children: Some(ref children), .. }) = self.node(ino) {
+            entries.push((ino, FileType::Directory, OsString::from(".")));
+            entries.push((parent, FileType::Directory, OsString::from("..")));
+
+            for (name, &child) in children {
+                let kind = match self.node(child) {
+                    Some(&Node::Directory { .. }) => FileType::Directory,
+                    Some(&Node::Symlink { .. }) => FileType::Symlink,
+                    _ => FileType::RegularFile,
+                };
+                entries.push((child, kind, name.clone()));
+            }
+        }
+
+        for (i, (child, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // `add` returns true once the reply buffer is full.
+            if reply.add(child, (i + 1) as i64, kind, &name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
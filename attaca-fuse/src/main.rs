@@ -0,0 +1,132 @@
+extern crate attaca;
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+extern crate fuse;
+extern crate futures;
+extern crate libc;
+extern crate time;
+
+mod errors;
+mod fs;
+
+use std::cmp;
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use clap::{App, Arg};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::marshal::{Object, ObjectHash};
+use attaca::store::RangeStore;
+use attaca::warning;
+
+use errors::*;
+use fs::{SnapshotFs, PAGE_SIZE};
+
+
+quick_main!(run);
+
+
+/// The default size of the page cache, in bytes.
+const DEFAULT_CACHE_SIZE: u64 = 256 * PAGE_SIZE;
+
+
+fn command() -> App<'static, 'static> {
+    App::new(crate_name!())
+        .author(crate_authors!("\n"))
+        .about(crate_description!())
+        .version(crate_version!())
+        .arg(
+            Arg::with_name("HASH")
+                .index(1)
+                .required(true)
+                .help("The hash of the commit or subtree to mount."),
+        )
+        .arg(
+            Arg::with_name("MOUNTPOINT")
+                .index(2)
+                .required(true)
+                .help("The directory to mount the filesystem on."),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .short("r")
+                .long("remote")
+                .takes_value(true)
+                .help("Read objects from this remote rather than the local store."),
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("The maximum number of bytes of file data to keep cached in memory."),
+        )
+}
+
+
+fn mount<S: RangeStore>(
+    store: S,
+    hash: ObjectHash,
+    mountpoint: &Path,
+    cache_pages: usize,
+) -> Result<()> {
+    let (subtree_hash, timestamp) = match store.read_object(hash).wait()? {
+        Object::Commit(commit_object) => {
+            let timestamp = time::Timespec::new(commit_object.timestamp.timestamp(), 0);
+            (commit_object.subtree, timestamp)
+        }
+        Object::Subtree(_) => (hash, time::get_time()),
+        _ => bail!(ErrorKind::NotMountable(hash)),
+    };
+
+    let filesystem = SnapshotFs::new(store, subtree_hash, timestamp, cache_pages);
+    let options = ["-o", "ro", "-o", "fsname=attaca"]
+        .iter()
+        .map(OsStr::new)
+        .collect::<Vec<_>>();
+
+    fuse::mount(filesystem, &mountpoint, &options)?;
+
+    Ok(())
+}
+
+
+fn run() -> Result<()> {
+    warning::set_handler(|warning| eprintln!("Warning: {}", warning));
+
+    let matches = command().get_matches();
+
+    let hash = matches.value_of("HASH").unwrap().parse()?;
+    let mountpoint = Path::new(matches.value_of("MOUNTPOINT").unwrap());
+    let cache_size = if matches.is_present("cache-size") {
+        value_t!(matches.value_of("cache-size"), u64)?
+    } else {
+        DEFAULT_CACHE_SIZE
+    };
+    // A cache with room for no pages would be no cache at all.
+    let cache_pages = cmp::max(((cache_size + PAGE_SIZE - 1) / PAGE_SIZE) as usize, 1);
+
+    let mut repository = Repository::find(env::current_dir()?)?;
+
+    match matches.value_of("remote") {
+        Some(remote_name) => {
+            let ctx = repository.remote(remote_name, ())?;
+            mount(ctx.store().clone(), hash, mountpoint, cache_pages)?;
+            ctx.close().wait()?;
+        }
+        None => {
+            let ctx = repository.local(())?;
+            mount(ctx.store().clone(), hash, mountpoint, cache_pages)?;
+            ctx.close().wait()?;
+        }
+    }
+
+    repository.cleanup()?;
+
+    Ok(())
+}
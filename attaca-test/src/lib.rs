@@ -0,0 +1,41 @@
+//! # `attaca-test` - a conformance suite for attaca object and ref stores.
+//!
+//! Every backend has to behave the same way for pushes, fetches and branch updates to be correct
+//! on it, but each used to be checked, if at all, by tests of its own. This crate collects those
+//! checks so that a new backend can prove itself by running them:
+//!
+//! ```ignore
+//! #[test]
+//! fn my_store_conforms() {
+//!     attaca_test::objects::check(|| MyStore::connect_to_a_fresh_bucket());
+//!     attaca_test::refs::check(|| MyRefStore::connect_to_a_fresh_bucket());
+//! }
+//! ```
+//!
+//! `objects::check` runs every check on `ObjectStore`s, and `refs::check` every check on
+//! `RefStore`s; each check is also public on its own. Every check takes a function which makes a
+//! fresh, empty store, and panics with a description of what went wrong if the store does not
+//! conform. Inputs are generated by `quickcheck`, so a failing check should be run a few times
+//! before it is believed fixed.
+//!
+//! `memory::MemoryStore` is a reference implementation of both traits, which passes every check.
+//! It also reads ranges of small objects and logs every read, for tests of code which reads through
+//! a store, such as `attaca::lazy`.
+
+extern crate attaca;
+extern crate futures;
+extern crate quickcheck;
+extern crate rand;
+
+pub mod memory;
+pub mod objects;
+pub mod refs;
+
+
+/// How many generated inputs each property is checked against.
+pub const TESTS: usize = 100;
+
+
+/// The size passed to `quickcheck` generators, bounding the lengths of the chunks and lists they
+/// make.
+pub const SIZE: usize = 256;
@@ -0,0 +1,131 @@
+//! An in-memory object and ref store, the reference every other store is checked against.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, FutureResult};
+use futures::prelude::*;
+
+use attaca::arc_slice;
+use attaca::errors::*;
+use attaca::marshal::{DataObject, Hashed, Object, ObjectHash};
+use attaca::store::{ObjectStore, RangeStore, RefStore};
+
+
+/// Objects and branches kept in memory. Clones share their contents, along with a log of what has
+/// been read from them, so that tests can check which objects a caller fetched.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    objects: Arc<Mutex<HashMap<ObjectHash, Vec<u8>>>>,
+    branches: Arc<Mutex<HashMap<String, ObjectHash>>>,
+    reads: Arc<Mutex<Vec<ObjectHash>>>,
+    ranges: Arc<Mutex<Vec<(ObjectHash, u64, u64)>>>,
+}
+
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every object read whole so far, in order.
+    pub fn reads(&self) -> Vec<ObjectHash> {
+        self.reads.lock().unwrap().clone()
+    }
+
+    /// Every range read so far, as `(object_hash, offset, len)`, in order.
+    pub fn ranges(&self) -> Vec<(ObjectHash, u64, u64)> {
+        self.ranges.lock().unwrap().clone()
+    }
+}
+
+
+impl ObjectStore for MemoryStore {
+    type Read = FutureResult<Object, Error>;
+    type Write = FutureResult<bool, Error>;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        self.reads.lock().unwrap().push(object_hash);
+
+        let bytes = match self.objects.lock().unwrap().get(&object_hash) {
+            Some(bytes) => bytes.clone(),
+            None => return future::err(ErrorKind::ObjectMissing(object_hash).into()),
+        };
+
+        future::result(Object::from_bytes(arc_slice::owned(bytes)))
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        let (object_hash, bytes_opt) = hashed.into_components();
+        let mut objects = self.objects.lock().unwrap();
+
+        if objects.contains_key(&object_hash) {
+            return future::ok(false);
+        }
+
+        match bytes_opt {
+            Some(bytes) => {
+                objects.insert(object_hash, bytes);
+                future::ok(true)
+            }
+            None => future::err(ErrorKind::ObjectMissing(object_hash).into()),
+        }
+    }
+}
+
+
+impl RangeStore for MemoryStore {
+    fn read_range(
+        &self,
+        object_hash: ObjectHash,
+        offset: u64,
+        len: u64,
+    ) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send> {
+        self.ranges.lock().unwrap().push((object_hash, offset, len));
+
+        let bytes = match self.objects.lock().unwrap().get(&object_hash) {
+            Some(bytes) => bytes.clone(),
+            None => return Box::new(future::err(ErrorKind::ObjectMissing(object_hash).into())),
+        };
+
+        let range = match Object::from_bytes(arc_slice::owned(bytes)) {
+            Ok(Object::Data(DataObject::Small(small_object))) => {
+                let start = cmp::min(offset, small_object.size()) as usize;
+                let end = cmp::min(offset.saturating_add(len), small_object.size()) as usize;
+                Some(small_object.chunk[start..end].to_vec())
+            }
+            Ok(_) => None,
+            Err(error) => return Box::new(future::err(error)),
+        };
+
+        Box::new(future::ok(range))
+    }
+}
+
+
+impl RefStore for MemoryStore {
+    type CompareAndSwap = FutureResult<ObjectHash, Error>;
+    type Get = FutureResult<ObjectHash, Error>;
+
+    fn compare_and_swap(
+        &self,
+        branch: String,
+        prev_hash: ObjectHash,
+        new_hash: ObjectHash,
+    ) -> Self::CompareAndSwap {
+        let mut branches = self.branches.lock().unwrap();
+        let actual = branches.get(&branch).cloned().unwrap_or_else(ObjectHash::zero);
+        if actual == prev_hash {
+            branches.insert(branch, new_hash);
+        }
+
+        future::ok(actual)
+    }
+
+    fn get(&self, branch: String) -> Self::Get {
+        future::ok(self.branches.lock().unwrap().get(&branch).cloned().unwrap_or_else(
+            ObjectHash::zero,
+        ))
+    }
+}
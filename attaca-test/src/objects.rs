@@ -0,0 +1,171 @@
+//! Checks of `ObjectStore` implementations.
+
+use std::collections::HashSet;
+use std::thread;
+
+use futures::future;
+use futures::prelude::*;
+use quickcheck::{Arbitrary, StdGen};
+use rand;
+
+use attaca::arc_slice;
+use attaca::marshal::{self, DataObject, Hashed, LargeObject, Object, SmallObject};
+use attaca::store::ObjectStore;
+
+use {SIZE, TESTS};
+
+
+/// Generate `TESTS` lists of arbitrary chunks, distinct within each list.
+fn generate_chunks() -> Vec<Vec<Vec<u8>>> {
+    let mut gen = StdGen::new(rand::thread_rng(), SIZE);
+
+    (0..TESTS)
+        .map(|_| {
+            let mut seen = HashSet::new();
+            let chunks: Vec<Vec<u8>> = Arbitrary::arbitrary(&mut gen);
+            chunks.into_iter().filter(|chunk| seen.insert(chunk.clone())).collect()
+        })
+        .collect()
+}
+
+
+/// Encode each chunk as a small object, followed by a large object made of all of them.
+fn objects(chunks: &[Vec<u8>]) -> Vec<Hashed> {
+    let mut hashed = chunks
+        .iter()
+        .map(|chunk| {
+            let small = SmallObject { chunk: arc_slice::owned(chunk.clone()) };
+            marshal::serialize_and_hash(&Object::Data(DataObject::Small(small)))
+        })
+        .collect::<Vec<_>>();
+
+    let children = chunks
+        .iter()
+        .zip(&hashed)
+        .map(|(chunk, hashed)| (chunk.len() as u64, *hashed.as_hash()))
+        .collect::<Vec<_>>();
+    if !children.is_empty() {
+        let large = LargeObject {
+            size: children.iter().map(|&(size, _)| size).sum(),
+            children,
+        };
+        hashed.push(marshal::serialize_and_hash(&Object::Data(DataObject::Large(large))));
+    }
+
+    hashed
+}
+
+
+/// Every object written reads back as an object with the same hash.
+pub fn round_trip<S: ObjectStore, F: FnMut() -> S>(mut fresh: F) {
+    for chunks in generate_chunks() {
+        let store = fresh();
+        let hashed = objects(&chunks);
+
+        for object in &hashed {
+            if let Err(error) = store.write_object(object.clone()).wait() {
+                panic!("writing {} failed: {}", object.as_hash(), error);
+            }
+        }
+
+        for object in &hashed {
+            match store.read_object(*object.as_hash()).wait() {
+                Ok(read) => assert_eq!(
+                    marshal::hash(&read),
+                    *object.as_hash(),
+                    "{} read back as a different object",
+                    object.as_hash()
+                ),
+                Err(error) => panic!("reading {} back failed: {}", object.as_hash(), error),
+            }
+        }
+    }
+}
+
+
+/// Writing an object reports it as new the first time only.
+pub fn rewrite_is_not_new<S: ObjectStore, F: FnMut() -> S>(mut fresh: F) {
+    for chunks in generate_chunks() {
+        let store = fresh();
+
+        for object in objects(&chunks) {
+            let first = store.write_object(object.clone()).wait().unwrap();
+            let second = store.write_object(object.clone()).wait().unwrap();
+            assert!(first, "{} was not new the first time it was written", object.as_hash());
+            assert!(!second, "{} was new the second time it was written", object.as_hash());
+        }
+    }
+}
+
+
+/// Reading an object which was never written fails, rather than hanging or inventing one.
+pub fn missing_object_fails<S: ObjectStore, F: FnMut() -> S>(mut fresh: F) {
+    let store = fresh();
+    let absent = marshal::hash(&Object::Data(DataObject::Small(
+        SmallObject { chunk: arc_slice::owned(b"never written".to_vec()) },
+    )));
+
+    assert!(
+        store.read_object(absent).wait().is_err(),
+        "reading {}, which was never written, succeeded",
+        absent
+    );
+}
+
+
+/// Writing the same objects from many threads at once succeeds everywhere, reports each object as
+/// new exactly once, and leaves every object readable.
+pub fn concurrent_writes<S: ObjectStore, F: FnMut() -> S>(mut fresh: F) {
+    const THREADS: usize = 8;
+
+    for chunks in generate_chunks().into_iter().take(TESTS / 10) {
+        let store = fresh();
+        let hashed = objects(&chunks);
+
+        let writers = (0..THREADS)
+            .map(|_| {
+                let store = store.clone();
+                let hashed = hashed.clone();
+                thread::spawn(move || {
+                    let writes = hashed.into_iter().map(|object| store.write_object(object));
+                    future::join_all(writes.collect::<Vec<_>>()).wait()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut new_counts = vec![0; hashed.len()];
+        for writer in writers {
+            match writer.join().expect("a writer thread panicked") {
+                Ok(written) => {
+                    for (count, new) in new_counts.iter_mut().zip(written) {
+                        if new {
+                            *count += 1;
+                        }
+                    }
+                }
+                Err(error) => panic!("a concurrent write failed: {}", error),
+            }
+        }
+
+        for (object, count) in hashed.iter().zip(new_counts) {
+            assert_eq!(count, 1, "{} was reported new {} times", object.as_hash(), count);
+
+            let read = store.read_object(*object.as_hash()).wait().unwrap();
+            assert_eq!(
+                marshal::hash(&read),
+                *object.as_hash(),
+                "{} read back as a different object",
+                object.as_hash()
+            );
+        }
+    }
+}
+
+
+/// Run every check in this module.
+pub fn check<S: ObjectStore, F: FnMut() -> S>(mut fresh: F) {
+    round_trip(&mut fresh);
+    rewrite_is_not_new(&mut fresh);
+    missing_object_fails(&mut fresh);
+    concurrent_writes(&mut fresh);
+}
@@ -0,0 +1,152 @@
+//! Checks of `RefStore` implementations.
+//!
+//! A `RefStore` has no way to list its branches, so only getting and moving them are checked.
+
+use std::thread;
+
+use futures::prelude::*;
+use quickcheck::{Arbitrary, StdGen};
+use rand;
+
+use attaca::arc_slice;
+use attaca::marshal::{self, DataObject, Object, ObjectHash, SmallObject};
+use attaca::store::RefStore;
+
+use {SIZE, TESTS};
+
+
+/// A commit hash standing in for whatever a branch points to. Ref stores never look at what it
+/// names, so any hash will do.
+fn commit(seed: &[u8]) -> ObjectHash {
+    marshal::hash(&Object::Data(DataObject::Small(
+        SmallObject { chunk: arc_slice::owned(seed.to_vec()) },
+    )))
+}
+
+
+/// Generate `TESTS` branch names, each with a few distinct commits to move it between.
+fn generate_branches() -> Vec<(String, Vec<ObjectHash>)> {
+    let mut gen = StdGen::new(rand::thread_rng(), SIZE);
+
+    (0..TESTS)
+        .map(|i| {
+            let name: String = Arbitrary::arbitrary(&mut gen);
+            let commits = (0..4u8).map(|n| commit(&[i as u8, n])).collect();
+            (format!("{}-{}", i, name), commits)
+        })
+        .collect()
+}
+
+
+/// A branch which was never set reads as the zero hash.
+pub fn absent_branch_is_zero<R: RefStore, F: FnMut() -> R>(mut fresh: F) {
+    let refs = fresh();
+
+    for (branch, _) in generate_branches() {
+        assert_eq!(
+            refs.get(branch.clone()).wait().unwrap(),
+            ObjectHash::zero(),
+            "the unset branch `{}` was not zero",
+            branch
+        );
+    }
+}
+
+
+/// Compare-and-swap moves a branch only from the commit it is expected to be at, and always
+/// returns the commit the branch was actually at.
+pub fn compare_and_swap<R: RefStore, F: FnMut() -> R>(mut fresh: F) {
+    let refs = fresh();
+
+    for (branch, commits) in generate_branches() {
+        let (first, second, stray) = (commits[0], commits[1], commits[2]);
+        let cas = |prev, new| refs.compare_and_swap(branch.clone(), prev, new).wait().unwrap();
+
+        assert_eq!(cas(ObjectHash::zero(), first), ObjectHash::zero());
+        assert_eq!(refs.get(branch.clone()).wait().unwrap(), first);
+
+        // Expecting the wrong commit, or expecting the branch to be unset, must change nothing.
+        assert_eq!(cas(stray, second), first, "`{}` was not at the commit reported", branch);
+        assert_eq!(cas(ObjectHash::zero(), second), first);
+        assert_eq!(
+            refs.get(branch.clone()).wait().unwrap(),
+            first,
+            "`{}` moved although it was not where it was expected to be",
+            branch
+        );
+
+        assert_eq!(cas(first, second), first);
+        assert_eq!(refs.get(branch.clone()).wait().unwrap(), second);
+    }
+}
+
+
+/// Branches move independently of each other.
+pub fn branches_are_independent<R: RefStore, F: FnMut() -> R>(mut fresh: F) {
+    let refs = fresh();
+    let branches = generate_branches();
+
+    for &(ref branch, ref commits) in &branches {
+        refs.compare_and_swap(branch.clone(), ObjectHash::zero(), commits[0]).wait().unwrap();
+    }
+
+    for &(ref branch, ref commits) in &branches {
+        assert_eq!(
+            refs.get(branch.clone()).wait().unwrap(),
+            commits[0],
+            "`{}` was moved by an update to another branch",
+            branch
+        );
+    }
+}
+
+
+/// Of many racing to move a branch from the same commit, exactly one wins, and every loser is told
+/// where the winner moved it.
+pub fn concurrent_compare_and_swap<R: RefStore, F: FnMut() -> R>(mut fresh: F) {
+    const THREADS: u8 = 8;
+
+    for (branch, commits) in generate_branches().into_iter().take(TESTS / 10) {
+        let refs = fresh();
+        let start = commits[0];
+        refs.compare_and_swap(branch.clone(), ObjectHash::zero(), start).wait().unwrap();
+
+        let racers = (0..THREADS)
+            .map(|n| {
+                let refs = refs.clone();
+                let branch = branch.clone();
+                let new = commit(&[0xff, n]);
+                thread::spawn(move || {
+                    (new, refs.compare_and_swap(branch, start, new).wait())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut winners = Vec::new();
+        let mut losers = Vec::new();
+        for racer in racers {
+            match racer.join().expect("a racing thread panicked") {
+                (new, Ok(actual)) if actual == start => winners.push(new),
+                (_, Ok(actual)) => losers.push(actual),
+                (_, Err(error)) => panic!("a racing compare-and-swap failed: {}", error),
+            }
+        }
+
+        assert_eq!(winners.len(), 1, "{} racers moved `{}`", winners.len(), branch);
+        assert_eq!(refs.get(branch.clone()).wait().unwrap(), winners[0]);
+        assert!(
+            losers.iter().all(|&actual| actual == winners[0]),
+            "a racer which lost was not told where `{}` was moved to",
+            branch
+        );
+    }
+}
+
+
+/// Run every check in this module.
+pub fn check<R: RefStore, F: FnMut() -> R>(mut fresh: F) {
+    absent_branch_is_zero(&mut fresh);
+    compare_and_swap(&mut fresh);
+    branches_are_independent(&mut fresh);
+    concurrent_compare_and_swap(&mut fresh);
+}
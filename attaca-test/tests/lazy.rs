@@ -0,0 +1,58 @@
+extern crate attaca;
+extern crate attaca_test;
+extern crate futures;
+
+use std::io::{Read, Seek, SeekFrom};
+
+use futures::prelude::*;
+
+use attaca::arc_slice;
+use attaca::lazy::ChunkedReader;
+use attaca::marshal::{self, DataObject, LargeObject, Object, ObjectHash, SmallObject};
+use attaca::store::ObjectStore;
+use attaca_test::memory::MemoryStore;
+
+
+fn insert(store: &MemoryStore, object: Object) -> ObjectHash {
+    let hashed = marshal::serialize_and_hash(&object);
+    let object_hash = *hashed.as_hash();
+    store.write_object(hashed).wait().unwrap();
+    object_hash
+}
+
+
+#[test]
+fn chunked_reads_fetch_only_overlapping_chunks() {
+    let store = MemoryStore::new();
+    let chunks = vec![b"hello, ".to_vec(), b"chunked ".to_vec(), b"world".to_vec()];
+    let children = chunks
+        .iter()
+        .map(|chunk| {
+            let small_object = SmallObject { chunk: arc_slice::owned(chunk.clone()) };
+            let hash = insert(&store, Object::Data(DataObject::Small(small_object)));
+            (chunk.len() as u64, hash)
+        })
+        .collect::<Vec<_>>();
+    let large_object = LargeObject {
+        size: 20,
+        children: children.clone(),
+    };
+    let object_hash = insert(&store, Object::Data(DataObject::Large(large_object)));
+
+    let mut reader = ChunkedReader::open(&store, object_hash).unwrap();
+    reader.seek(SeekFrom::Start(15)).unwrap();
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents).unwrap();
+
+    assert_eq!(contents, "world");
+    assert_eq!(store.reads(), vec![object_hash, children[2].1]);
+
+    let mut buf = [0u8; 6];
+    assert_eq!(reader.read_at(5, &mut buf).unwrap(), 6);
+    assert_eq!(&buf, b", chun");
+
+    // Neither of the chunks overlapping the range lies wholly inside it, so only the overlapping
+    // parts of them are read.
+    assert_eq!(store.reads().len(), 2);
+    assert_eq!(store.ranges(), vec![(children[0].1, 5, 2), (children[1].1, 0, 4)]);
+}
@@ -0,0 +1,23 @@
+extern crate attaca;
+extern crate attaca_test;
+
+use attaca::Repository;
+use attaca::bench::Scratch;
+use attaca::store::Local;
+
+
+#[test]
+fn local_store_conforms() {
+    // The stores outlive the closure which makes them, so their scratch directories are kept here until
+    // the checks are done.
+    let mut scratches = Vec::new();
+
+    attaca_test::objects::check(|| -> Local {
+        let scratch = Scratch::new("attaca-conformance").unwrap();
+        Repository::init(scratch.path()).unwrap();
+        let store = Repository::load(scratch.path()).unwrap().local(()).unwrap().store().clone();
+        scratches.push(scratch);
+
+        store
+    });
+}
@@ -0,0 +1,10 @@
+extern crate attaca_test;
+
+use attaca_test::memory::MemoryStore;
+
+
+#[test]
+fn memory_store_conforms() {
+    attaca_test::objects::check(MemoryStore::new);
+    attaca_test::refs::check(MemoryStore::new);
+}
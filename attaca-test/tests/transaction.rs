@@ -0,0 +1,37 @@
+extern crate attaca;
+extern crate attaca_test;
+extern crate futures;
+
+use futures::prelude::*;
+
+use attaca::arc_slice;
+use attaca::marshal::{self, DataObject, Object, ObjectHash, SmallObject};
+use attaca::store::{Batch, Empty, ObjectStore, RefStore, StoreTransaction};
+use attaca_test::memory::MemoryStore;
+
+
+fn object() -> Object {
+    let small_object = SmallObject { chunk: arc_slice::owned(b"transaction".to_vec()) };
+    Object::Data(DataObject::Small(small_object))
+}
+
+
+#[test]
+fn branch_moves_only_after_objects_are_written() {
+    let store = MemoryStore::new();
+    let object_hash = *marshal::serialize_and_hash(&object()).as_hash();
+
+    // Every write to the empty store fails, so the branch must stay where it was.
+    let mut failing = Batch::new(&Empty, &store);
+    failing.write_object(marshal::serialize_and_hash(&object()));
+    failing.update_branch("master".to_owned(), ObjectHash::zero(), object_hash);
+    assert!(failing.commit().wait().is_err());
+    assert_eq!(store.get("master".to_owned()).wait().unwrap(), ObjectHash::zero());
+
+    let mut batch = Batch::new(&store, &store);
+    batch.write_object(marshal::serialize_and_hash(&object()));
+    batch.update_branch("master".to_owned(), ObjectHash::zero(), object_hash);
+    assert_eq!(batch.commit().wait().unwrap(), 1);
+    assert!(store.read_object(object_hash).wait().is_ok());
+    assert_eq!(store.get("master".to_owned()).wait().unwrap(), object_hash);
+}
@@ -0,0 +1,118 @@
+//! Benchmarks of chunking, hashing, and local store throughput, for catching regressions between
+//! builds. Run with `cargo bench`; `attaca bench` measures the same stages on whole workloads and
+//! on remotes as well.
+
+#![feature(test)]
+
+extern crate attaca;
+extern crate test;
+
+use test::Bencher;
+
+use attaca::Repository;
+use attaca::arc_slice::{self, ArcSlice};
+use attaca::bench::{self, Scratch, Workload};
+use attaca::chunker::{Chunker, Fixed, Rolling};
+use attaca::dataset;
+use attaca::marshal::{Hashed, ObjectHash};
+
+
+/// The bytes of every file in a small workload, concatenated.
+fn workload(workload: Workload) -> ArcSlice {
+    let scratch = Scratch::new("attaca-bench").unwrap();
+    dataset::generate(&workload.spec(0, 16 << 20), scratch.path()).unwrap();
+
+    let mut bytes = Vec::new();
+    for file in bench::load(scratch.path()).unwrap() {
+        bytes.extend_from_slice(&file);
+    }
+
+    arc_slice::owned(bytes)
+}
+
+
+fn bench_chunk<C: Chunker>(b: &mut Bencher, chunker: &C, data: ArcSlice) {
+    b.bytes = data.len() as u64;
+    b.iter(|| bench::chunk(chunker, &[data.clone()]).unwrap());
+}
+
+
+#[bench]
+fn chunk_rolling_huge_files(b: &mut Bencher) {
+    bench_chunk(b, &Rolling, workload(Workload::HugeFiles));
+}
+
+
+#[bench]
+fn chunk_rolling_small_files(b: &mut Bencher) {
+    bench_chunk(b, &Rolling, workload(Workload::SmallFiles));
+}
+
+
+#[bench]
+fn chunk_fixed_huge_files(b: &mut Bencher) {
+    bench_chunk(b, &Fixed { size: 1 << 20 }, workload(Workload::HugeFiles));
+}
+
+
+fn chunks(workload_kind: Workload) -> Vec<ArcSlice> {
+    bench::chunk(&Rolling, &[workload(workload_kind)]).unwrap().0
+}
+
+
+#[bench]
+fn hash_huge_files(b: &mut Bencher) {
+    let chunks = chunks(Workload::HugeFiles);
+    b.bytes = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+    b.iter(|| bench::hash(&chunks));
+}
+
+
+fn hashed(workload_kind: Workload) -> (Vec<Hashed>, Vec<ObjectHash>) {
+    let (hashed, _) = bench::hash(&chunks(workload_kind));
+    let hashes = hashed.iter().map(|hashed| *hashed.as_hash()).collect();
+
+    (hashed, hashes)
+}
+
+
+#[bench]
+fn local_write(b: &mut Bencher) {
+    let (hashed, _) = hashed(Workload::Modified);
+    b.bytes = hashed.iter().map(|hashed| hashed.as_bytes().unwrap().len() as u64).sum();
+
+    // Every iteration writes to a fresh store, or all but the first would find every object
+    // already written. Creating the store is cheap next to filling it.
+    b.iter(|| {
+        let scratch = Scratch::new("attaca-bench-store").unwrap();
+        Repository::init(scratch.path()).unwrap();
+        let mut repository = Repository::load(scratch.path()).unwrap();
+        let ctx = repository.local(()).unwrap();
+
+        bench::write(ctx.store(), &hashed, 16).unwrap()
+    });
+}
+
+
+#[bench]
+fn local_read(b: &mut Bencher) {
+    let (hashed, hashes) = hashed(Workload::Modified);
+    let scratch = Scratch::new("attaca-bench-store").unwrap();
+    Repository::init(scratch.path()).unwrap();
+
+    {
+        let mut repository = Repository::load(scratch.path()).unwrap();
+        let ctx = repository.local(()).unwrap();
+        bench::write(ctx.store(), &hashed, 16).unwrap();
+    }
+
+    // Every iteration reads through a freshly loaded store, or all but the first would be served
+    // from memory.
+    b.bytes = hashed.iter().map(|hashed| hashed.as_bytes().unwrap().len() as u64).sum();
+    b.iter(|| {
+        let mut repository = Repository::load(scratch.path()).unwrap();
+        let ctx = repository.local(()).unwrap();
+
+        bench::read(ctx.store(), &hashes, 16).unwrap()
+    });
+}
@@ -0,0 +1,9 @@
+//! # `archive` - the tar and zip formats.
+//!
+//! Archives are read in three places - by the `Tar` and `Zip` chunkers, which only need to know
+//! where each member begins, and by `import` - and written by `export`. The layout of headers,
+//! their checksums, and the records ending a zip archive are kept here, so that all of them agree
+//! on what an archive looks like.
+
+pub mod tar;
+pub mod zip;
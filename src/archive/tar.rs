@@ -0,0 +1,311 @@
+//! Tar archives.
+//!
+//! An archive is a sequence of 512-byte header blocks, each followed by the member's data padded
+//! out to a whole number of blocks, and ended by two zero blocks. Both ustar and GNU headers are
+//! understood. So are the GNU base-256 encoding of large numbers and pax extended headers, which
+//! may give the size of the member after them when it does not fit in a ustar header.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::str;
+
+use errors::*;
+
+
+/// The size of a tar header, and the unit every member is padded to.
+pub const BLOCK: usize = 512;
+
+
+/// The largest size a ustar header can hold: eleven octal digits.
+pub const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+
+/// Parse a numeric header field: octal digits, or a big-endian binary number if the high bit of
+/// the first byte is set (a GNU extension for large values).
+pub fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = (field[0] & 0x7f) as u64;
+        for &byte in &field[1..] {
+            value = value.checked_mul(256)? | byte as u64;
+        }
+
+        return Some(value);
+    }
+
+    let digits = field
+        .iter()
+        .cloned()
+        .skip_while(|&byte| byte == b' ')
+        .take_while(|&byte| byte >= b'0' && byte <= b'7');
+    let mut value = 0u64;
+    for digit in digits {
+        value = value.checked_mul(8)? + (digit - b'0') as u64;
+    }
+
+    Some(value)
+}
+
+
+/// Write `value` as a NUL-terminated octal number filling `field`, or zero if it does not fit.
+pub fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let value = if value >> (3 * width) == 0 { value } else { 0 };
+    let digits = format!("{:01$o}", value, width);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+
+/// A NUL-terminated header field.
+pub fn text(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+
+/// The checksum of a header block, which is computed with its own field taken as spaces.
+pub fn checksum(header: &[u8]) -> u64 {
+    let sum = header[..148].iter().chain(&header[156..]).map(|&byte| byte as u64).sum::<u64>();
+    sum + 8 * b' ' as u64
+}
+
+
+/// The number of zeroes padding a member of `size` bytes out to a whole number of blocks.
+pub fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+
+/// Build a pax extended header record, whose length prefix counts itself.
+pub fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let unprefixed = key.len() + value.len() + 3;
+    let mut len = unprefixed + 1;
+    while len != unprefixed + len.to_string().len() {
+        len = unprefixed + len.to_string().len();
+    }
+
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+
+/// Parse the records of a pax extended header.
+pub fn pax_records(mut data: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let mut records = HashMap::new();
+
+    while !data.is_empty() && data[0] != 0 {
+        let record = data.iter()
+            .position(|&byte| byte == b' ')
+            .and_then(|space| {
+                let len = str::from_utf8(&data[..space]).ok()?.parse::<usize>().ok()?;
+                let record = data.get(space + 1..len)?;
+                let equals = record.iter().position(|&byte| byte == b'=')?;
+                let key = String::from_utf8(record[..equals].to_vec()).ok()?;
+                let value = record[equals + 1..record.len() - 1].to_vec();
+
+                Some((len, key, value))
+            });
+
+        let (len, key, value) = match record {
+            Some(record) => record,
+            None => {
+                bail!(ErrorKind::MalformedArchive("malformed pax extended header".to_owned()))
+            }
+        };
+
+        records.insert(key, value);
+        data = &data[len..];
+    }
+
+    Ok(records)
+}
+
+
+/// Parse a number out of a pax record, ignoring any fractional part.
+pub fn pax_number(value: &[u8]) -> Option<i64> {
+    let integral = value.split(|&byte| byte == b'.').next()?;
+    str::from_utf8(integral).ok()?.parse().ok()
+}
+
+
+/// Whether headers of type `kind` only extend the header after them: pax extended headers, and GNU
+/// long names and link targets.
+fn is_extension(kind: u8) -> bool {
+    match kind {
+        b'x' | b'g' | b'L' | b'K' => true,
+        _ => false,
+    }
+}
+
+
+/// The size a pax extended header gives, if it gives one.
+fn pax_size(data: &[u8]) -> Result<Option<u64>> {
+    match pax_records(data)?.get("size") {
+        Some(size) => {
+            match pax_number(size) {
+                Some(size) if size >= 0 => Ok(Some(size as u64)),
+                _ => bail!(ErrorKind::MalformedArchive("malformed pax size record".to_owned())),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+
+/// A header block, and where the data following it lies in the archive.
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    /// The offset of the header block.
+    pub offset: usize,
+
+    pub header: &'a [u8],
+    pub data: Range<usize>,
+}
+
+
+impl<'a> Entry<'a> {
+    /// The type flag of the header: `b'0'` for a file, `b'x'` for a pax extended header, and so
+    /// on.
+    pub fn kind(&self) -> u8 {
+        self.header[156]
+    }
+}
+
+
+/// The header blocks of an archive, in order. Extended headers are entries of their own. See
+/// `entries`.
+#[derive(Debug, Clone)]
+pub struct Entries<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    done: bool,
+
+    /// The size given by the pax extended header for the next member, if any.
+    extended_size: Option<u64>,
+
+    /// The size given by the global pax header for every member, if any.
+    global_size: Option<u64>,
+}
+
+
+/// Walk the header blocks of the tar archive `bytes`. Iteration stops at the first zero block, or
+/// at the first header which is corrupt or whose data runs past the end of `bytes`, which is an
+/// error.
+pub fn entries(bytes: &[u8]) -> Entries {
+    Entries {
+        bytes,
+        offset: 0,
+        done: false,
+        extended_size: None,
+        global_size: None,
+    }
+}
+
+
+impl<'a> Entries<'a> {
+    /// The offset of the next header block; once iteration is over, that of the zero block which
+    /// ended the archive, if there was one.
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+
+    fn fail(&mut self, message: String) -> Option<Result<Entry<'a>>> {
+        self.done = true;
+        Some(Err(ErrorKind::MalformedArchive(message).into()))
+    }
+}
+
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = self.bytes;
+        if self.done || self.offset + BLOCK > bytes.len() {
+            return None;
+        }
+
+        let offset = self.offset;
+        let header = &bytes[offset..offset + BLOCK];
+
+        // Two zero blocks end the archive; one is enough to know the rest is padding.
+        if header.iter().all(|&byte| byte == 0) {
+            self.done = true;
+            return None;
+        }
+
+        if number(&header[148..156]) != Some(checksum(header)) {
+            return self.fail(format!("the tar header at offset {} is corrupt", offset));
+        }
+
+        // Sizes from pax headers are for the member after them, not for further extensions.
+        let kind = header[156];
+        let size = if is_extension(kind) {
+            number(&header[124..136])
+        } else {
+            match self.extended_size.take().or(self.global_size) {
+                Some(size) => Some(size),
+                None => number(&header[124..136]),
+            }
+        };
+
+        let start = offset + BLOCK;
+        let end = match size.and_then(|size| start.checked_add(size as usize)) {
+            Some(end) if end <= bytes.len() => end,
+            _ => {
+                return self.fail(format!(
+                    "the tar member at offset {} runs past the end of the archive",
+                    offset
+                ))
+            }
+        };
+        self.offset = end + padding((end - start) as u64);
+
+        match kind {
+            b'x' => {
+                match pax_size(&bytes[start..end]) {
+                    Ok(size) => self.extended_size = size,
+                    Err(error) => {
+                        self.done = true;
+                        return Some(Err(error));
+                    }
+                }
+            }
+            b'g' => {
+                match pax_size(&bytes[start..end]) {
+                    Ok(Some(size)) => self.global_size = Some(size),
+                    Ok(None) => {}
+                    Err(error) => {
+                        self.done = true;
+                        return Some(Err(error));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Some(Ok(Entry {
+            offset,
+            header,
+            data: start..end,
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pax_record_length_counts_itself() {
+        let record = pax_record("path", &[b'a'; 95]);
+        assert_eq!(record.len(), 105);
+        assert!(record.starts_with(b"105 path="));
+
+        let record = pax_record("path", b"ab");
+        assert_eq!(record.len(), 11);
+        assert!(record.starts_with(b"11 path="));
+    }
+}
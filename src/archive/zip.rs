@@ -0,0 +1,302 @@
+//! Zip archives.
+//!
+//! Members are found through the central directory, which is located by the end of central
+//! directory record at the end of the archive, and by the zip64 records before it when the archive
+//! is too large for plain zip. All integers are little-endian. Times are MS-DOS times, which have
+//! no time zone and are taken as UTC.
+
+use std::ops::Range;
+
+use chrono::prelude::*;
+
+use errors::*;
+
+
+pub const LOCAL_HEADER: u32 = 0x0403_4b50;
+pub const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+pub const CENTRAL_HEADER: u32 = 0x0201_4b50;
+pub const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+pub const ZIP64_END_LOCATOR: u32 = 0x0706_4b50;
+pub const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+
+
+/// Unix, as the system which made the archive, in the high byte of "version made by".
+pub const MADE_BY_UNIX: u16 = 3 << 8;
+
+
+lazy_static! {
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+
+        table
+    };
+}
+
+
+/// Continue the CRC-32 `crc` of some bytes over `bytes`. Start from zero.
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+
+pub fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let field = bytes.get(offset..offset + 2)?;
+    Some(field[0] as u16 | (field[1] as u16) << 8)
+}
+
+
+pub fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u16_at(bytes, offset)? as u32 | (u16_at(bytes, offset + 2)? as u32) << 16)
+}
+
+
+pub fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u32_at(bytes, offset)? as u64 | (u32_at(bytes, offset + 4)? as u64) << 32)
+}
+
+
+pub fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+
+pub fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    put_u16(buf, value as u16);
+    put_u16(buf, (value >> 16) as u16);
+}
+
+
+pub fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    put_u32(buf, value as u32);
+    put_u32(buf, (value >> 32) as u32);
+}
+
+
+/// A 32-bit size or offset field, saturated to mark that the real value is in a zip64 record.
+pub fn field_u32(value: u64) -> u32 {
+    if value >= 0xffff_ffff { 0xffff_ffff } else { value as u32 }
+}
+
+
+/// The MS-DOS time and date of a Unix timestamp. Zip can only represent the years 1980 to 2107;
+/// times outside of them are clamped to the nearest end.
+pub fn dos_time(mtime: i64) -> (u16, u16) {
+    let time = match Utc.timestamp_opt(mtime, 0).single() {
+        Some(ref time) if time.year() < 1980 => return (0, (1 << 5) | 1),
+        Some(ref time) if time.year() > 2107 => return (0xbf7d, 0xff9f),
+        Some(time) => time,
+        None if mtime < 0 => return (0, (1 << 5) | 1),
+        None => return (0xbf7d, 0xff9f),
+    };
+
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date = ((time.year() as u32 - 1980) << 9) | (time.month() << 5) | time.day();
+
+    (dos_time as u16, dos_date as u16)
+}
+
+
+/// The Unix timestamp of an MS-DOS time and date, taken as UTC.
+pub fn unix_time(dos_time: u16, dos_date: u16) -> i64 {
+    let year = 1980 + (dos_date >> 9) as i32;
+    let month = ((dos_date >> 5) & 0xf) as u32;
+    let day = (dos_date & 0x1f) as u32;
+    let hour = (dos_time >> 11) as u32;
+    let minute = ((dos_time >> 5) & 0x3f) as u32;
+    let second = ((dos_time & 0x1f) * 2) as u32;
+
+    NaiveDate::from_ymd_opt(year, month, day)
+        .and_then(|date| date.and_hms_opt(hour, minute, second))
+        .map(|time| time.timestamp())
+        .unwrap_or(0)
+}
+
+
+pub fn malformed() -> Error {
+    Error::from_kind(ErrorKind::MalformedArchive(
+        "the central directory of the zip archive is malformed".to_owned(),
+    ))
+}
+
+
+/// A member of an archive, as its central directory header records it. Sizes and offsets are
+/// taken from the zip64 extra field where they overflowed.
+#[derive(Debug, Clone)]
+pub struct CentralEntry<'a> {
+    pub made_by: u16,
+    pub flags: u16,
+    pub method: u16,
+    pub dos_time: u16,
+    pub dos_date: u16,
+    pub crc: u32,
+    pub size: u64,
+    pub external: u32,
+
+    /// The offset of the member's local header.
+    pub local: u64,
+
+    pub name: &'a [u8],
+}
+
+
+impl<'a> CentralEntry<'a> {
+    /// Where the member's data lies in `bytes`, or `None` if its local header is missing or the
+    /// data runs past the end of the archive. Only meaningful for stored members.
+    pub fn data(&self, bytes: &[u8]) -> Option<Range<usize>> {
+        let local = self.local as usize;
+        if u32_at(bytes, local)? != LOCAL_HEADER {
+            return None;
+        }
+
+        let name_len = u16_at(bytes, local + 26)? as usize;
+        let extra_len = u16_at(bytes, local + 28)? as usize;
+        let start = local + 30 + name_len + extra_len;
+        let end = start.checked_add(self.size as usize)?;
+        if end > bytes.len() {
+            return None;
+        }
+
+        Some(start..end)
+    }
+}
+
+
+/// The central directory of an archive.
+#[derive(Debug, Clone)]
+pub struct Directory<'a> {
+    /// The offset of the first central directory header.
+    pub offset: usize,
+
+    pub entries: Vec<CentralEntry<'a>>,
+}
+
+
+/// Read the central directory of the zip archive `bytes`.
+pub fn directory(bytes: &[u8]) -> Result<Directory> {
+    // The end of central directory record is 22 bytes, followed by a comment of up to 64 KB.
+    let earliest = bytes.len().saturating_sub(22 + 0xffff);
+    let end = match (earliest..bytes.len().saturating_sub(21)).rev().find(|&offset| {
+        u32_at(bytes, offset) == Some(END_OF_CENTRAL_DIRECTORY)
+    }) {
+        Some(end) => end,
+        None => bail!(ErrorKind::MalformedArchive("not a zip archive".to_owned())),
+    };
+
+    let mut count = u16_at(bytes, end + 10).ok_or_else(malformed)? as u64;
+    let mut offset = u32_at(bytes, end + 16).ok_or_else(malformed)? as usize;
+
+    // A zip64 locator sits right before the end of central directory record, if there is one.
+    if end >= 20 && u32_at(bytes, end - 20) == Some(ZIP64_END_LOCATOR) {
+        let record = u64_at(bytes, end - 12).ok_or_else(malformed)? as usize;
+        if u32_at(bytes, record) != Some(ZIP64_END_OF_CENTRAL_DIRECTORY) {
+            return Err(malformed());
+        }
+
+        count = u64_at(bytes, record + 32).ok_or_else(malformed)?;
+        offset = u64_at(bytes, record + 48).ok_or_else(malformed)? as usize;
+    }
+
+    let directory = offset;
+    let mut entries = Vec::new();
+
+    for _ in 0..count {
+        if u32_at(bytes, offset) != Some(CENTRAL_HEADER) {
+            return Err(malformed());
+        }
+
+        let entry = offset;
+        let field = move |at| u16_at(bytes, entry + at).ok_or_else(malformed);
+        let wide = move |at| u32_at(bytes, entry + at).ok_or_else(malformed);
+
+        let name_len = field(28)? as usize;
+        let extra_len = field(30)? as usize;
+        let comment_len = field(32)? as usize;
+
+        let compressed = wide(20)?;
+        let mut size = wide(24)? as u64;
+        let mut local = wide(42)? as u64;
+
+        let name_start = entry + 46;
+        let name = bytes.get(name_start..name_start + name_len).ok_or_else(malformed)?;
+
+        // Only the fields which overflowed are given in the zip64 extra field, in this order.
+        let mut extra = name_start + name_len;
+        let extra_end = extra + extra_len;
+        while extra + 4 <= extra_end {
+            let id = u16_at(bytes, extra).ok_or_else(malformed)?;
+            let len = u16_at(bytes, extra + 2).ok_or_else(malformed)? as usize;
+
+            if id == 0x0001 {
+                let mut value = extra + 4;
+                if size == 0xffff_ffff {
+                    size = u64_at(bytes, value).ok_or_else(malformed)?;
+                    value += 8;
+                }
+                if compressed == 0xffff_ffff {
+                    value += 8;
+                }
+                if local == 0xffff_ffff {
+                    local = u64_at(bytes, value).ok_or_else(malformed)?;
+                }
+            }
+
+            extra += 4 + len;
+        }
+
+        entries.push(CentralEntry {
+            made_by: field(4)?,
+            flags: field(8)?,
+            method: field(10)?,
+            dos_time: field(12)?,
+            dos_date: field(14)?,
+            crc: wide(16)?,
+            size,
+            external: wide(38)?,
+            local,
+            name,
+        });
+
+        offset = extra_end + comment_len;
+    }
+
+    Ok(Directory {
+        offset: directory,
+        entries,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_reference() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"12345"), b"6789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn dos_times_are_utc() {
+        // 2017-10-31 12:34:56, as MS-DOS records it.
+        let dos_time = (12 << 11) | (34 << 5) | (56 / 2);
+        let dos_date = ((2017 - 1980) << 9) | (10 << 5) | 31;
+        assert_eq!(
+            unix_time(dos_time, dos_date),
+            Utc.ymd(2017, 10, 31).and_hms(12, 34, 56).timestamp()
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! # `backrefs` - an index from objects to the objects which refer to them.
+//!
+//! Objects only record what they refer to, so finding what refers to an object otherwise means
+//! reading every object in the store. When a repository's config sets `backrefs`, the local store
+//! records the references of each object as it is written, inverted, in `.attaca/backrefs.bin`.
+//! Purging an object, working out what a corrupt object breaks, and tracking down why an object is
+//! still retained all start from this question.
+//!
+//! Objects written before the index was enabled are not in it; `Backrefs::rebuild` recomputes it
+//! from every object in the local store.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bincode;
+
+use errors::*;
+use marshal::ObjectHash;
+use marshal::canonical;
+use repository::Paths;
+use store::Packs;
+
+
+#[derive(Debug)]
+struct BackrefsInner {
+    path: PathBuf,
+    referrers: HashMap<ObjectHash, BTreeSet<ObjectHash>>,
+    dirty: bool,
+}
+
+
+impl BackrefsInner {
+    fn save(&mut self) -> Result<()> {
+        let mut file = File::create(&self.path)?;
+        bincode::serialize_into(&mut file, &self.referrers, bincode::Infinite)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+
+impl Drop for BackrefsInner {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.save().unwrap();
+        }
+    }
+}
+
+
+/// A shared handle to the reverse reference index, written back when the last handle is dropped.
+#[derive(Debug, Clone)]
+pub struct Backrefs {
+    inner: Arc<Mutex<BackrefsInner>>,
+}
+
+
+impl Backrefs {
+    /// Load the index at `path`, or start an empty one if there is none.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let referrers = if path.is_file() {
+            let mut bytes = Vec::new();
+            File::open(&path)?.read_to_end(&mut bytes)?;
+            bincode::deserialize(&bytes)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Backrefs {
+            inner: Arc::new(Mutex::new(BackrefsInner {
+                path,
+                referrers,
+                dirty: false,
+            })),
+        })
+    }
+
+    /// Record that `hash` refers to each of `refs`.
+    pub fn insert(&self, hash: ObjectHash, refs: &[ObjectHash]) {
+        if refs.is_empty() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        for &referenced in refs {
+            inner.referrers.entry(referenced).or_insert_with(BTreeSet::new).insert(hash);
+        }
+        inner.dirty = true;
+    }
+
+    /// Forget that `hash`, which referred to each of `refs`, does so, once it is deleted.
+    pub fn remove(&self, hash: ObjectHash, refs: &[ObjectHash]) {
+        let mut inner = self.inner.lock().unwrap();
+        for referenced in refs {
+            let emptied = match inner.referrers.get_mut(referenced) {
+                Some(referrers) => {
+                    referrers.remove(&hash);
+                    referrers.is_empty()
+                }
+                None => false,
+            };
+
+            if emptied {
+                inner.referrers.remove(referenced);
+            }
+        }
+        inner.dirty = true;
+    }
+
+    /// Record the references of the encoded object `bytes`, stored under `hash`.
+    pub fn record(&self, hash: ObjectHash, bytes: &[u8]) -> Result<()> {
+        let (_, raw_object) = canonical::decode(bytes)?;
+        self.insert(hash, &raw_object.refs());
+
+        Ok(())
+    }
+
+    /// Every object known to refer directly to `hash`, in order.
+    pub fn referrers(&self, hash: ObjectHash) -> Vec<ObjectHash> {
+        self.inner
+            .lock()
+            .unwrap()
+            .referrers
+            .get(&hash)
+            .map(|referrers| referrers.iter().cloned().collect())
+            .unwrap_or_else(Vec::new)
+    }
+
+    /// The number of objects known to be referred to.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().referrers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget everything, and index every object in the local store afresh, whether loose or
+    /// packed.
+    pub fn rebuild(&self, paths: &Paths) -> Result<()> {
+        fn walk(backrefs: &Backrefs, path: &Path) -> Result<()> {
+            for entry_res in fs::read_dir(path)? {
+                let entry = entry_res?;
+                let entry_path = entry.path();
+
+                if entry.metadata()?.is_dir() {
+                    walk(backrefs, &entry_path)?;
+                } else {
+                    let hash = ObjectHash::from_blob_path(&entry_path)?;
+                    let mut bytes = Vec::new();
+                    File::open(&entry_path)?.read_to_end(&mut bytes)?;
+                    backrefs.record(hash, &bytes)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.referrers.clear();
+            inner.dirty = true;
+        }
+
+        if paths.blobs.exists() {
+            walk(self, &paths.blobs)?;
+        }
+        Packs::new(paths.packs.clone()).for_each(|hash, bytes| self.record(hash, bytes))?;
+
+        Ok(())
+    }
+
+    /// Write the index back now, rather than when the last handle is dropped.
+    pub fn save(&self) -> Result<()> {
+        self.inner.lock().unwrap().save()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::{hash_of, Scratch};
+
+    #[test]
+    fn referrers_survive_reopening() {
+        let scratch = Scratch::new("attaca-backrefs").unwrap();
+        let path = scratch.path().join("backrefs");
+
+        {
+            let backrefs = Backrefs::open(path.clone()).unwrap();
+            backrefs.insert(hash_of(1), &[hash_of(3)]);
+            backrefs.insert(hash_of(2), &[hash_of(3), hash_of(4)]);
+        }
+
+        let backrefs = Backrefs::open(path.clone()).unwrap();
+        assert_eq!(backrefs.referrers(hash_of(3)), vec![hash_of(1), hash_of(2)]);
+        assert_eq!(backrefs.referrers(hash_of(4)), vec![hash_of(2)]);
+        assert_eq!(backrefs.referrers(hash_of(1)), Vec::new());
+    }
+}
@@ -0,0 +1,280 @@
+//! # `backup` - snapshot directories on a schedule, and prune old snapshots.
+//!
+//! Each `BackupCfg` in the config names a directory and the schedules it is snapshotted on, each
+//! with a label such as "daily" or "weekly", how often it comes due, and how many of its
+//! snapshots to keep. `run` is meant to be called often, say hourly by cron: whenever one or more
+//! labels are due, it commits the directory once and points a branch at the commit for each of
+//! them, named `backup/<name>/<label>/<timestamp>`. The snapshots of a backup form a single
+//! history, each the child of the one before it, whatever their labels.
+//!
+//! Each run then prunes every label down to the number of snapshots it keeps, newest first, by
+//! deleting their branches. A commit kept by another label, such as a daily snapshot which was
+//! also the week's, stays reachable through that label's branch. The objects of pruned snapshots
+//! stay in the store until garbage is collected.
+
+use chrono::prelude::*;
+use chrono::Duration;
+use futures::prelude::*;
+
+use errors::*;
+use ingest::{self, IngestOptions, Snapshot};
+use marshal::ObjectHash;
+use repository::{BackupCfg, Refs, Repository};
+
+
+/// The prefix of every branch pointing at a backup snapshot.
+pub const BACKUP_PREFIX: &str = "backup/";
+
+
+/// The format of the timestamp ending a snapshot's branch name. Branch names sort as their
+/// timestamps do.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+
+/// A snapshot, as found among the branches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotRef {
+    pub branch: String,
+    pub label: String,
+    pub timestamp: DateTime<Utc>,
+    pub commit: ObjectHash,
+}
+
+
+/// What a run of a backup did.
+#[derive(Debug, Clone, Default)]
+pub struct BackupRun {
+    /// The snapshot taken, if any label was due.
+    pub commit: Option<ObjectHash>,
+
+    /// The labels the snapshot was taken for.
+    pub labels: Vec<String>,
+
+    /// The branches of the snapshots pruned.
+    pub pruned: Vec<String>,
+}
+
+
+/// The name of the branch of the snapshot of backup `name` taken at `timestamp` for `label`.
+pub fn branch_name(name: &str, label: &str, timestamp: DateTime<Utc>) -> String {
+    format!("{}{}/{}/{}", BACKUP_PREFIX, name, label, timestamp.format(TIMESTAMP_FORMAT))
+}
+
+
+/// The backup name, label and timestamp of a snapshot's branch, if `branch` is one.
+fn parse_branch(branch: &str) -> Option<(&str, &str, DateTime<Utc>)> {
+    if !branch.starts_with(BACKUP_PREFIX) {
+        return None;
+    }
+
+    // Backup names may contain `/`; labels and timestamps may not.
+    let mut parts = branch[BACKUP_PREFIX.len()..].rsplitn(3, '/');
+    let timestamp = Utc.datetime_from_str(parts.next()?, TIMESTAMP_FORMAT).ok()?;
+    let label = parts.next()?;
+    let name = parts.next()?;
+
+    Some((name, label, timestamp))
+}
+
+
+/// Every snapshot of the backup `name`, oldest first.
+pub fn snapshots(refs: &Refs, name: &str) -> Vec<SnapshotRef> {
+    let mut snapshots = refs.branches
+        .iter()
+        .filter_map(|(branch, &commit)| {
+            let (snapshot_name, label, timestamp) = parse_branch(branch)?;
+            if snapshot_name != name {
+                return None;
+            }
+
+            Some(SnapshotRef {
+                branch: branch.clone(),
+                label: label.to_owned(),
+                timestamp,
+                commit,
+            })
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort_by(|a, b| (a.timestamp, &a.label).cmp(&(b.timestamp, &b.label)));
+
+    snapshots
+}
+
+
+/// The labels of `cfg` due for a snapshot at `now`: those with no snapshot yet, or whose newest
+/// snapshot is at least as old as their interval.
+pub fn due(cfg: &BackupCfg, snapshots: &[SnapshotRef], now: DateTime<Utc>) -> Vec<String> {
+    cfg.schedules
+        .iter()
+        .filter(|schedule| {
+            let latest = snapshots
+                .iter()
+                .filter(|snapshot| snapshot.label == schedule.label)
+                .map(|snapshot| snapshot.timestamp)
+                .max();
+
+            match latest {
+                Some(latest) => now - latest >= Duration::hours(schedule.every_hours as i64),
+                None => true,
+            }
+        })
+        .map(|schedule| schedule.label.clone())
+        .collect()
+}
+
+
+/// The branches of the snapshots of `cfg` which its schedules no longer keep. Snapshots with
+/// labels no schedule names are left alone.
+pub fn to_prune(cfg: &BackupCfg, snapshots: &[SnapshotRef]) -> Vec<String> {
+    let mut pruned = Vec::new();
+
+    for schedule in &cfg.schedules {
+        let labelled = snapshots
+            .iter()
+            .filter(|snapshot| snapshot.label == schedule.label)
+            .collect::<Vec<_>>();
+        let excess = labelled.len().saturating_sub(schedule.keep);
+        pruned.extend(labelled[..excess].iter().map(|snapshot| snapshot.branch.clone()));
+    }
+
+    pruned
+}
+
+
+/// Take a snapshot of `cfg` if any of its labels are due at `now`, and then prune its old
+/// snapshots. With `dry_run`, only say what would be done.
+pub fn run(
+    repository: &mut Repository,
+    cfg: &BackupCfg,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> Result<BackupRun> {
+    let mut backup_run = BackupRun::default();
+
+    let existing = snapshots(&repository.refs, &cfg.name);
+    backup_run.labels = due(cfg, &existing, now);
+
+    if !backup_run.labels.is_empty() && !dry_run {
+        let parent = existing.last().map(|snapshot| snapshot.commit);
+        let options = IngestOptions {
+            trust_mtimes: false,
+            metadata: repository.config.metadata,
+            author: repository.config.author(),
+            committer: repository.config.committer(),
+        };
+        let snapshot = Snapshot {
+            path: cfg.path.clone(),
+            message: format!("Back up {} ({})", cfg.name, backup_run.labels.join(", ")),
+            timestamp: now,
+        };
+        let chunkers = repository.chunkers.clone();
+
+        let commit = {
+            let ctx = repository.local(())?;
+            let ingested =
+                ingest::ingest(&ctx.marshaller(), &chunkers, &options, parent, vec![snapshot])
+                    .wait()?;
+            ctx.close().wait()?;
+
+            ingested[0].commit
+        };
+
+        for label in &backup_run.labels {
+            repository.compare_and_swap_branch(&branch_name(&cfg.name, label, now), None, commit)?;
+        }
+        backup_run.commit = Some(commit);
+    }
+
+    // A dry run counts the snapshots a real run would have taken, so that what it would prune is
+    // counted too.
+    let mut after = existing;
+    let commit = backup_run.commit.unwrap_or_else(ObjectHash::zero);
+    for label in &backup_run.labels {
+        after.push(SnapshotRef {
+            branch: branch_name(&cfg.name, label, now),
+            label: label.clone(),
+            timestamp: now,
+            commit,
+        });
+    }
+
+    backup_run.pruned = to_prune(cfg, &after);
+    if !dry_run {
+        for branch in &backup_run.pruned {
+            repository.refs.branches.remove(branch);
+        }
+    }
+
+    Ok(backup_run)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use repository::{Head, ScheduleCfg};
+
+    use super::*;
+
+    fn cfg() -> BackupCfg {
+        BackupCfg {
+            name: "home/alice".to_owned(),
+            path: PathBuf::from("/home/alice"),
+            schedules: vec![
+                ScheduleCfg { label: "daily".to_owned(), every_hours: 24, keep: 2 },
+                ScheduleCfg { label: "weekly".to_owned(), every_hours: 168, keep: 1 },
+            ],
+        }
+    }
+
+    fn refs(snapshots: &[(&str, DateTime<Utc>)]) -> Refs {
+        let mut branches = HashMap::new();
+        for (i, &(label, timestamp)) in snapshots.iter().enumerate() {
+            let hash = format!("{:064x}", i).parse().unwrap();
+            branches.insert(branch_name("home/alice", label, timestamp), hash);
+        }
+        branches.insert("master".to_owned(), ObjectHash::zero());
+
+        Refs {
+            head: Head::Root,
+            branches,
+            remotes: HashMap::new(),
+            tags: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn branch_names_round_trip() {
+        let timestamp = Utc.ymd(2017, 11, 5).and_hms(3, 0, 0);
+        let branch = branch_name("home/alice", "daily", timestamp);
+
+        assert_eq!(branch, "backup/home/alice/daily/20171105T030000Z");
+        assert_eq!(parse_branch(&branch), Some(("home/alice", "daily", timestamp)));
+        assert_eq!(parse_branch("backup/daily"), None);
+        assert_eq!(parse_branch("master"), None);
+    }
+
+    #[test]
+    fn labels_come_due_and_are_pruned() {
+        let day = |d| Utc.ymd(2017, 11, d).and_hms(3, 0, 0);
+        let refs = refs(&[
+            ("daily", day(1)),
+            ("weekly", day(1)),
+            ("daily", day(2)),
+            ("daily", day(3)),
+        ]);
+        let existing = snapshots(&refs, "home/alice");
+        assert_eq!(existing.len(), 4);
+
+        assert_eq!(due(&cfg(), &existing, day(3)), Vec::<String>::new());
+        assert_eq!(due(&cfg(), &existing, day(4)), vec!["daily".to_owned()]);
+        assert_eq!(
+            due(&cfg(), &existing, day(8)),
+            vec!["daily".to_owned(), "weekly".to_owned()]
+        );
+
+        assert_eq!(to_prune(&cfg(), &existing), vec![branch_name("home/alice", "daily", day(1))]);
+    }
+}
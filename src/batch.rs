@@ -148,6 +148,6 @@ impl<T: BatchTrace> Batch<T> {
 
     /// Convert this `Batch` into a stream of `Hashed` objects.
     pub fn into_stream(self) -> Box<Stream<Item = Hashed, Error = Error> + Send> {
-        Box::new(self.marshal_rx.map_err(|()| "Upstream error!".into()))
+        Box::new(self.marshal_rx.map_err(|()| ErrorKind::MarshalAbandoned.into()))
     }
 }
@@ -0,0 +1,330 @@
+//! # `bench` - measure chunking, hashing and store throughput on synthetic workloads.
+//!
+//! A `Workload` is one of a few dataset shapes which stress different parts of attaca: many small
+//! files, a few huge ones, and files which are random modifications of each other. Each is
+//! generated by the `dataset` module, so a run can be reproduced from its workload, scale and
+//! seed alone.
+//!
+//! Every stage is measured on its own and in memory where it can be: `chunk` times only the
+//! chunker, `hash` only encoding and hashing the chunks it produced, and `write` and `read` only
+//! moving those objects into and out of a store. Comparing the store stages between backends, or
+//! any stage between builds, then shows where time went rather than that it went somewhere.
+
+use std::cmp;
+use std::fmt;
+use std::fs;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::stream;
+use memmap::{Mmap, Protection};
+use rand;
+
+use arc_slice::{self, ArcSlice};
+use chunker::Chunker;
+use dataset::{DatasetSpec, SizeDistribution};
+use errors::*;
+use marshal::{self, DataObject, Hashed, Object, ObjectHash, SmallObject};
+use store::ObjectStore;
+
+
+/// The shape of a generated workload. See the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Many files of a few kilobytes each, where per-object overhead dominates.
+    SmallFiles,
+
+    /// A few files of a quarter of the total each, where raw throughput dominates.
+    HugeFiles,
+
+    /// Files of varied sizes, half of which are copies of an earlier file with a small region
+    /// rewritten, as successive versions of a dataset would be.
+    Modified,
+}
+
+
+impl Workload {
+    pub fn all() -> &'static [Workload] {
+        &[Workload::SmallFiles, Workload::HugeFiles, Workload::Modified]
+    }
+
+    /// The dataset of this shape adding up to about `scale` bytes.
+    pub fn spec(&self, seed: u64, scale: u64) -> DatasetSpec {
+        match *self {
+            Workload::SmallFiles => DatasetSpec {
+                seed,
+                files: (scale / (16 << 10)) as usize,
+                sizes: SizeDistribution::Uniform(1 << 10, 31 << 10),
+                duplicates: 0.0,
+                near_duplicates: 0.0,
+                ..DatasetSpec::default()
+            },
+            Workload::HugeFiles => DatasetSpec {
+                seed,
+                files: 4,
+                sizes: SizeDistribution::Fixed(scale / 4),
+                max_size: scale,
+                duplicates: 0.0,
+                near_duplicates: 0.0,
+                text: 0.0,
+                depth: 0,
+                ..DatasetSpec::default()
+            },
+            Workload::Modified => DatasetSpec {
+                seed,
+                files: (scale / (64 << 10)) as usize,
+                duplicates: 0.0,
+                near_duplicates: 0.5,
+                ..DatasetSpec::default()
+            },
+        }
+    }
+}
+
+
+impl FromStr for Workload {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "small-files" => Ok(Workload::SmallFiles),
+            "huge-files" => Ok(Workload::HugeFiles),
+            "modified" => Ok(Workload::Modified),
+            _ => bail!("`{}` is not a workload; try small-files, huge-files or modified", s),
+        }
+    }
+}
+
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Workload::SmallFiles => write!(f, "small-files"),
+            Workload::HugeFiles => write!(f, "huge-files"),
+            Workload::Modified => write!(f, "modified"),
+        }
+    }
+}
+
+
+/// How much was processed by a stage, and how long it took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throughput {
+    pub objects: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+
+impl Throughput {
+    fn measure(objects: u64, bytes: u64, started: Instant) -> Self {
+        Throughput {
+            objects,
+            bytes,
+            elapsed: started.elapsed(),
+        }
+    }
+
+    fn seconds(&self) -> f64 {
+        self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.seconds()
+    }
+
+    pub fn objects_per_sec(&self) -> f64 {
+        self.objects as f64 / self.seconds()
+    }
+}
+
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:10.1} MiB/s {:10.0} objects/s ({} objects, {} bytes in {:.3}s)",
+            self.bytes_per_sec() / f64::from(1 << 20),
+            self.objects_per_sec(),
+            self.objects,
+            self.bytes,
+            self.seconds()
+        )
+    }
+}
+
+
+/// A fresh directory beneath the system's temporary directory, deleted along with everything in
+/// it when dropped, for workloads and scratch repositories.
+#[derive(Debug)]
+pub struct Scratch {
+    path: PathBuf,
+}
+
+
+impl Scratch {
+    pub fn new(prefix: &str) -> Result<Self> {
+        let path = env::temp_dir().join(format!("{}-{}", prefix, rand::random::<u64>()));
+        fs::create_dir_all(&path)?;
+
+        Ok(Scratch { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+
+/// The made-up object hash whose hex digits spell out `n`, for tests which need distinct hashes
+/// but not the objects behind them.
+pub fn hash_of(n: u64) -> ObjectHash {
+    format!("{:064x}", n).parse().unwrap()
+}
+
+
+/// Map every file beneath `root`, in a fixed order, so that stages after this one read memory
+/// rather than the disk.
+pub fn load<P: AsRef<Path>>(root: P) -> Result<Vec<ArcSlice>> {
+    let mut paths = Vec::new();
+    let mut stack = vec![root.as_ref().to_owned()];
+
+    while let Some(dir) = stack.pop() {
+        for entry_res in fs::read_dir(&dir)? {
+            let entry = entry_res?;
+            if entry.file_type()?.is_dir() {
+                stack.push(entry.path());
+            } else {
+                paths.push(entry.path());
+            }
+        }
+    }
+    paths.sort();
+
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        files.push(map(&path)?);
+    }
+
+    Ok(files)
+}
+
+
+fn map(path: &Path) -> Result<ArcSlice> {
+    // Empty files cannot be mapped.
+    if fs::metadata(path)?.len() == 0 {
+        return Ok(arc_slice::empty());
+    }
+
+    Ok(arc_slice::mapped(Mmap::open_path(path, Protection::Read)?))
+}
+
+
+/// Split every file with `chunker`.
+pub fn chunk(chunker: &Chunker, files: &[ArcSlice]) -> Result<(Vec<ArcSlice>, Throughput)> {
+    let bytes = files.iter().map(|file| file.len() as u64).sum();
+    let started = Instant::now();
+
+    let mut chunks = Vec::new();
+    for file in files {
+        chunks.extend(chunker.chunk(file.clone()).collect().wait()?);
+    }
+
+    Ok((chunks, Throughput::measure(files.len() as u64, bytes, started)))
+}
+
+
+/// Encode and hash every chunk as a small data object, ready to be written.
+pub fn hash(chunks: &[ArcSlice]) -> (Vec<Hashed>, Throughput) {
+    let bytes = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+    let started = Instant::now();
+
+    let hashed = chunks
+        .iter()
+        .map(|chunk| {
+            let object = Object::Data(DataObject::Small(SmallObject { chunk: chunk.clone() }));
+            marshal::serialize_and_hash(&object)
+        })
+        .collect::<Vec<_>>();
+
+    (hashed, Throughput::measure(chunks.len() as u64, bytes, started))
+}
+
+
+/// Write every object to `store`, with at most `in_flight` writes outstanding at once.
+pub fn write<S: ObjectStore>(
+    store: &S,
+    objects: &[Hashed],
+    in_flight: usize,
+) -> Result<Throughput> {
+    let bytes = objects
+        .iter()
+        .map(|hashed| hashed.as_bytes().map(|bytes| bytes.len() as u64).unwrap_or(0))
+        .sum();
+    let started = Instant::now();
+
+    let store = store.clone();
+    stream::iter_ok::<_, Error>(objects.to_vec())
+        .map(move |hashed| store.write_object(hashed))
+        .buffer_unordered(cmp::max(in_flight, 1))
+        .for_each(|_| Ok(()))
+        .wait()?;
+
+    Ok(Throughput::measure(objects.len() as u64, bytes, started))
+}
+
+
+/// Read every object back from `store`, with at most `in_flight` reads outstanding at once.
+pub fn read<S: ObjectStore>(
+    store: &S,
+    hashes: &[ObjectHash],
+    in_flight: usize,
+) -> Result<Throughput> {
+    let started = Instant::now();
+
+    let store = store.clone();
+    let bytes = stream::iter_ok::<_, Error>(hashes.to_vec())
+        .map(move |object_hash| store.read_object(object_hash))
+        .buffer_unordered(cmp::max(in_flight, 1))
+        .fold(0, |bytes, object| Ok::<_, Error>(bytes + object.encoded_size()))
+        .wait()?;
+
+    Ok(Throughput::measure(hashes.len() as u64, bytes, started))
+}
+
+
+#[cfg(test)]
+mod test {
+    use chunker::Rolling;
+    use dataset;
+
+    use super::*;
+
+    #[test]
+    fn stages_see_every_byte() {
+        let scratch = Scratch::new("attaca-bench").unwrap();
+        let spec = Workload::Modified.spec(7, 4 << 20);
+        let generated = dataset::generate(&spec, scratch.path()).unwrap();
+
+        let files = load(scratch.path()).unwrap();
+        assert_eq!(files.len() as u64, generated.files);
+
+        let (chunks, chunked) = chunk(&Rolling, &files).unwrap();
+        assert_eq!(chunked.bytes, generated.bytes);
+        assert_eq!(chunks.iter().map(|chunk| chunk.len() as u64).sum::<u64>(), generated.bytes);
+
+        let (hashed, hashing) = hash(&chunks);
+        assert_eq!(hashed.len(), chunks.len());
+        assert_eq!(hashing.bytes, generated.bytes);
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::export;
+use attaca::export::seekable::{self, SeekableOptions};
+use attaca::marshal::ObjectHash;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("create")
+        .about("Write the contents of a commit to an archive file.")
+        .arg(
+            Arg::with_name("COMMIT")
+                .index(1)
+                .help("The commit to archive. Defaults to the HEAD."),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .required(true)
+                .value_name("FILE")
+                .help("The archive file to write."),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["zstd-seekable", "tar", "zip"])
+                .default_value("zstd-seekable")
+                .help(
+                    "The archive format. `zstd-seekable` archives can be decompressed by any zstd \
+                     decoder, and also allow single files to be extracted without reading the \
+                     rest of the archive. `tar` and `zip` archives can be read by the usual tools, \
+                     but not by `archive extract` or `archive list`.",
+                ),
+        )
+        .arg(
+            Arg::with_name("frame-size")
+                .long("frame-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("The maximum number of uncompressed bytes in a single zstd frame."),
+        )
+        .arg(
+            Arg::with_name("level")
+                .long("level")
+                .takes_value(true)
+                .help("The zstd compression level."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_hash = match matches.value_of("COMMIT") {
+        Some(commit) => commit.parse::<ObjectHash>()?,
+        None => {
+            match repository.refs.head() {
+                Some(head) => head,
+                None => bail!("nothing to archive; there are no commits yet"),
+            }
+        }
+    };
+
+    let mut options = SeekableOptions::default();
+    if matches.is_present("frame-size") {
+        options.frame_size = value_t!(matches.value_of("frame-size"), usize)?;
+        if options.frame_size == 0 || options.frame_size > u32::max_value() as usize {
+            bail!("the frame size must be between 1 and {} bytes", u32::max_value());
+        }
+    }
+    if matches.is_present("level") {
+        options.level = value_t!(matches.value_of("level"), i32)?;
+    }
+
+    let output = matches.value_of("output").unwrap();
+    let writer = BufWriter::new(File::create(output)?);
+
+    let format = matches.value_of("format").unwrap();
+
+    let archived = {
+        let ctx = repository.local(())?;
+        let subtree_hash = ctx.read_commit(commit_hash).wait()?.subtree;
+        let archived = match format {
+            "tar" => export::to_tar(ctx.store(), subtree_hash, writer).wait()?.1,
+            "zip" => export::to_zip(ctx.store(), subtree_hash, writer).wait()?.1,
+            _ => {
+                let (_, index) = seekable::write(ctx.store(), subtree_hash, writer, options)
+                    .wait()?;
+                index.entries.len() as u64
+            }
+        };
+        ctx.close().wait()?;
+
+        archived
+    };
+
+    match format {
+        "tar" | "zip" => eprintln!("Archived {} files and directories to {}.", archived, output),
+        _ => eprintln!("Archived {} files to {}.", archived, output),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::export::seekable::SeekableArchive;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("extract")
+        .about(
+            "Extract a single file from a seekable archive, reading only the parts of the archive \
+             which hold it.",
+        )
+        .arg(
+            Arg::with_name("ARCHIVE")
+                .index(1)
+                .required(true)
+                .help("The archive to read from."),
+        )
+        .arg(
+            Arg::with_name("PATH")
+                .index(2)
+                .required(true)
+                .help("The path of the file to extract, relative to the root of the archive."),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Where to write the file. Defaults to standard output."),
+        )
+}
+
+
+pub fn go(_repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let mut archive = SeekableArchive::open(File::open(matches.value_of("ARCHIVE").unwrap())?)?;
+    let index = archive.index()?;
+    let path = matches.value_of("PATH").unwrap();
+
+    match matches.value_of("output") {
+        Some(output) => {
+            let mut writer = BufWriter::new(File::create(output)?);
+            archive.extract(&index, path, &mut writer)?;
+            writer.flush()?;
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut writer = stdout.lock();
+            archive.extract(&index, path, &mut writer)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::path::Path;
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+use memmap::{Mmap, Protection};
+
+use attaca::Repository;
+use attaca::arc_slice;
+use attaca::import;
+use attaca::marshal::CommitObject;
+use attaca::repository::Head;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("import")
+        .about(
+            "Read the contents of a tar or zip archive into the repository, without unpacking it \
+             first.",
+        )
+        .arg(
+            Arg::with_name("ARCHIVE")
+                .index(1)
+                .required(true)
+                .help("The archive to import."),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["tar", "zip"])
+                .help(
+                    "The archive format. Defaults to `zip` for files named `*.zip`, and `tar` \
+                     otherwise. Only zip members stored without compression can be imported.",
+                ),
+        )
+        .arg(
+            Arg::with_name("message")
+                .short("m")
+                .long("message")
+                .takes_value(true)
+                .help(
+                    "Commit the contents of the archive on top of the HEAD with the given \
+                     message, replacing everything in it. Without a message, only the hash of \
+                     the imported subtree is printed.",
+                ),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let path = Path::new(matches.value_of("ARCHIVE").unwrap());
+    let format = match matches.value_of("format") {
+        Some(format) => format,
+        None if path.extension().map_or(false, |extension| extension == "zip") => "zip",
+        None => "tar",
+    };
+
+    let archive = arc_slice::mapped(Mmap::open_path(path, Protection::Read)?);
+    let chunkers = repository.chunkers.clone();
+    let metadata = repository.config.metadata;
+    let message = matches.value_of("message").map(ToOwned::to_owned);
+    let author = repository.config.author();
+    let committer = repository.config.committer();
+    let head_opt = repository.refs.head();
+
+    let (imported, commit_opt) = {
+        let ctx = repository.local(())?;
+        let marshaller = ctx.marshaller();
+
+        let imported = match format {
+            "zip" => import::from_zip(&marshaller, &chunkers, metadata, archive).wait()?,
+            _ => import::from_tar(&marshaller, &chunkers, metadata, archive).wait()?,
+        };
+
+        let commit_opt = match message {
+            Some(message) => {
+                let commit_object = CommitObject {
+                    subtree: imported.subtree,
+                    parents: head_opt.into_iter().collect(),
+                    message,
+                    timestamp: Utc::now(),
+                    signature: None,
+                    author,
+                    committer,
+                };
+
+                Some(marshaller.process(commit_object).wait()?)
+            }
+            None => None,
+        };
+
+        ctx.close().wait()?;
+
+        (imported, commit_opt)
+    };
+
+    eprintln!("Imported {} files ({} bytes).", imported.files, imported.bytes);
+
+    let commit_hash = match commit_opt {
+        Some(commit_hash) => commit_hash,
+        None => {
+            println!("{}", imported.subtree);
+            return Ok(());
+        }
+    };
+
+    // Committing on a branch advances it; otherwise, the HEAD is detached at the new commit.
+    let branch_opt = match repository.refs.head {
+        Head::LocalRef(ref branch) => Some(branch.clone()),
+        _ => None,
+    };
+
+    match branch_opt {
+        Some(ref branch) => repository.compare_and_swap_branch(branch, head_opt, commit_hash)?,
+        None => repository.refs.head = Head::Detached(commit_hash),
+    }
+
+    println!("Committed {}.", commit_hash);
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use std::fs::File;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::export::seekable::SeekableArchive;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("list")
+        .about("List the files in a seekable archive.")
+        .arg(
+            Arg::with_name("ARCHIVE")
+                .index(1)
+                .required(true)
+                .help("The archive to list."),
+        )
+}
+
+
+pub fn go(_repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let mut archive = SeekableArchive::open(File::open(matches.value_of("ARCHIVE").unwrap())?)?;
+
+    for (path, entry) in archive.index()?.entries {
+        println!("{}\t{}", entry.size, path.display());
+    }
+
+    Ok(())
+}
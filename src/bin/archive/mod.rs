@@ -0,0 +1,36 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+pub mod create;
+pub mod extract;
+pub mod import;
+pub mod list;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("archive")
+        .about(
+            "Export a commit as a single archive file, read files back out of one, or import the \
+             contents of an archive.",
+        )
+        .subcommand(create::command())
+        .subcommand(extract::command())
+        .subcommand(import::command())
+        .subcommand(list::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("create", Some(sub_m)) => create::go(repository, sub_m),
+        ("extract", Some(sub_m)) => extract::go(repository, sub_m),
+        ("import", Some(sub_m)) => import::go(repository, sub_m),
+        ("list", Some(sub_m)) => list::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::backup;
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Snapshot the directories configured as backups in config.toml, and prune old snapshots. Meant to
+be run often, say hourly from cron; a backup is only snapshotted when one of its schedules is due.
+For example:
+
+    [[backups]]
+    name = "home"
+    path = "/home/alice"
+
+    [[backups.schedules]]
+    label = "daily"
+    every_hours = 24
+    keep = 7
+
+    [[backups.schedules]]
+    label = "weekly"
+    every_hours = 168
+    keep = 4
+
+Each snapshot is a branch named `backup/<name>/<label>/<timestamp>`, which can be checked out like
+any other. Pruning a snapshot deletes its branch; its objects stay in the store until garbage is
+collected.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("backup")
+        .about("Snapshot configured directories on a schedule, pruning old snapshots.")
+        .after_help(HELP_STR)
+        .arg(
+            Arg::with_name("NAME")
+                .index(1)
+                .multiple(true)
+                .help("The backups to run. Defaults to every configured backup."),
+        )
+        .arg(
+            Arg::with_name("list")
+                .short("l")
+                .long("list")
+                .help("List the snapshots of each backup instead of running it."),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .short("n")
+                .long("dry-run")
+                .help("Say which snapshots would be taken and pruned, without doing either."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let cfgs = match matches.values_of("NAME") {
+        Some(names) => {
+            names
+                .map(|name| {
+                    repository
+                        .config
+                        .backups
+                        .iter()
+                        .find(|cfg| cfg.name == name)
+                        .cloned()
+                        .ok_or_else(|| {
+                            Error::from(format!("no backup named `{}` is configured", name))
+                        })
+                })
+                .collect::<Result<Vec<_>>>()?
+        }
+        None => repository.config.backups.clone(),
+    };
+
+    if cfgs.is_empty() {
+        bail!("no backups are configured; see `attaca backup --help`");
+    }
+
+    if matches.is_present("list") {
+        for cfg in &cfgs {
+            for snapshot in backup::snapshots(&repository.refs, &cfg.name) {
+                println!("{} {}", snapshot.commit, snapshot.branch);
+            }
+        }
+
+        return Ok(());
+    }
+
+    let dry_run = matches.is_present("dry-run");
+    let now = Utc::now();
+
+    for cfg in &cfgs {
+        let backup_run = backup::run(repository, cfg, now, dry_run)?;
+
+        match backup_run.commit {
+            Some(commit) => {
+                let labels = backup_run.labels.join(", ");
+                println!("Snapshotted {} as {} ({}).", cfg.name, commit, labels);
+            }
+            None if dry_run && !backup_run.labels.is_empty() => {
+                println!("Would snapshot {} ({}).", cfg.name, backup_run.labels.join(", "))
+            }
+            None => println!("No snapshot of {} is due.", cfg.name),
+        }
+
+        for branch in &backup_run.pruned {
+            if dry_run {
+                println!("Would prune {}.", branch);
+            } else {
+                println!("Pruned {}.", branch);
+            }
+        }
+    }
+
+    Ok(())
+}
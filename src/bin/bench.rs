@@ -0,0 +1,201 @@
+use std::env;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::{ObjectStore, Repository};
+use attaca::bench::{self, Scratch, Throughput, Workload};
+use attaca::chunker::Rolling;
+use attaca::dataset;
+use attaca::marshal::{Hashed, ObjectHash};
+use attaca::sync::DEFAULT_MAX_IN_FLIGHT;
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Generate synthetic workloads and measure how fast they are chunked, hashed, and written to and read
+back from each store. Every stage is timed on its own, so that a regression or a slow backend can be
+pinned to the stage responsible.
+
+The workloads are `small-files` (many files of a few kilobytes), `huge-files` (four files of a
+quarter of the scale each) and `modified` (files of varied sizes, half of which are copies of an
+earlier file with a small region rewritten). The same workload, scale and seed always generate the
+same bytes.
+
+The local store is measured in a scratch repository which is deleted afterwards. Remotes are
+measured in place: the objects written to them are left behind, unreferenced, so use a remote set
+aside for testing.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("bench")
+        .about("Measure chunking, hashing and store throughput on synthetic workloads.")
+        .after_help(HELP_STR)
+        .arg(
+            Arg::with_name("workload")
+                .long("workload")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("WORKLOAD")
+                .help("Run only the given workload. May be given more than once. Defaults to all."),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help(
+                    "The size of each workload, optionally suffixed with K, M or G. Defaults to \
+                     256M.",
+                ),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("The seed workloads are generated from. Defaults to 0."),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("REMOTE")
+                .help(
+                    "Also measure the given remote of the current repository. May be given more \
+                     than once.",
+                ),
+        )
+        .arg(Arg::with_name("no-local").long("no-local").help(
+            "Do not measure the local store.",
+        ))
+        .arg(
+            Arg::with_name("max-in-flight")
+                .long("max-in-flight")
+                .takes_value(true)
+                .value_name("N")
+                .help("Write or read at most N objects at once. Defaults to 16."),
+        )
+}
+
+
+fn report(stage: &str, throughput: &Throughput) {
+    println!("\t{:<24} {}", stage, throughput);
+}
+
+
+fn bench_store<S: ObjectStore>(
+    name: &str,
+    write_store: &S,
+    read_store: &S,
+    hashed: &[Hashed],
+    hashes: &[ObjectHash],
+    in_flight: usize,
+) -> Result<()> {
+    report(&format!("{} write", name), &bench::write(write_store, hashed, in_flight)?);
+    report(&format!("{} read", name), &bench::read(read_store, hashes, in_flight)?);
+
+    Ok(())
+}
+
+
+fn bench_local(hashed: &[Hashed], hashes: &[ObjectHash], in_flight: usize) -> Result<()> {
+    let scratch = Scratch::new("attaca-bench-store")?;
+    Repository::init(scratch.path())?;
+
+    // Reads go through a freshly loaded repository, so that they are not served from the memory
+    // of the one which wrote the objects.
+    let mut writer = Repository::load(scratch.path())?;
+    let mut reader = Repository::load(scratch.path())?;
+    let write_ctx = writer.local(())?;
+    let read_ctx = reader.local(())?;
+
+    bench_store("local", write_ctx.store(), read_ctx.store(), hashed, hashes, in_flight)
+}
+
+
+fn bench_remote(
+    repository: &mut Repository,
+    remote: &str,
+    hashed: &[Hashed],
+    hashes: &[ObjectHash],
+    in_flight: usize,
+) -> Result<()> {
+    let write_store = repository.remote(remote, ())?.store().clone();
+    let read_store = repository.remote(remote, ())?.store().clone();
+
+    bench_store(remote, &write_store, &read_store, hashed, hashes, in_flight)
+}
+
+
+pub fn go(matches: &ArgMatches) -> Result<()> {
+    let workloads = match matches.values_of("workload") {
+        Some(names) => names.map(str::parse).collect::<Result<Vec<Workload>>>()?,
+        None => Workload::all().to_vec(),
+    };
+    let scale = match matches.value_of("scale") {
+        Some(scale) => dataset::parse_size(scale)?,
+        None => 256 << 20,
+    };
+    let seed = if matches.is_present("seed") {
+        value_t!(matches.value_of("seed"), u64)?
+    } else {
+        0
+    };
+    let in_flight = if matches.is_present("max-in-flight") {
+        value_t!(matches.value_of("max-in-flight"), usize)?
+    } else {
+        DEFAULT_MAX_IN_FLIGHT
+    };
+    let remotes = matches.values_of("remote").map(|names| names.collect()).unwrap_or_else(
+        Vec::<&str>::new,
+    );
+
+    // Remotes are configured per repository, so one is only needed to measure them.
+    let mut repository = if remotes.is_empty() {
+        None
+    } else {
+        Some(Repository::find(env::current_dir()?)?)
+    };
+
+    for workload in workloads {
+        let scratch = Scratch::new("attaca-bench")?;
+        let generated = dataset::generate(&workload.spec(seed, scale), scratch.path())?;
+
+        println!(
+            "{} (--scale {} --seed {}): {} files, {} bytes",
+            workload,
+            scale,
+            seed,
+            generated.files,
+            generated.bytes
+        );
+
+        let files = bench::load(scratch.path())?;
+        let (chunks, chunked) = bench::chunk(&Rolling, &files)?;
+        report("chunk", &chunked);
+        let (hashed, hashing) = bench::hash(&chunks);
+        report("hash", &hashing);
+
+        let hashes = hashed.iter().map(|hashed| *hashed.as_hash()).collect::<Vec<_>>();
+
+        if !matches.is_present("no-local") {
+            bench_local(&hashed, &hashes, in_flight)?;
+        }
+
+        if let Some(ref mut repository) = repository {
+            for remote in &remotes {
+                bench_remote(repository, remote, &hashed, &hashes, in_flight)?;
+            }
+        }
+    }
+
+    if let Some(repository) = repository {
+        repository.cleanup()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,27 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("good")
+        .about("Mark a commit good: the change being searched for is not in it.")
+        .arg(
+            Arg::with_name("REV")
+                .index(1)
+                .required(true)
+                .help("The revision to mark."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let hash = repository.refs.resolve(matches.value_of("REV").unwrap())?;
+
+    let mut bisector = super::open(repository)?;
+    bisector.mark_good(hash)?;
+
+    super::report(repository, &bisector)
+}
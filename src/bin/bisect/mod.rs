@@ -0,0 +1,60 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::history::bisect::{Bisector, Step};
+
+use errors::*;
+
+pub mod bad;
+pub mod good;
+pub mod reset;
+pub mod start;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("bisect")
+        .about("Search history for the first bad commit between a bad and a good one.")
+        .subcommand(bad::command())
+        .subcommand(good::command())
+        .subcommand(reset::command())
+        .subcommand(start::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("bad", Some(sub_m)) => bad::go(repository, sub_m),
+        ("good", Some(sub_m)) => good::go(repository, sub_m),
+        ("reset", Some(sub_m)) => reset::go(repository, sub_m),
+        ("start", Some(sub_m)) => start::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
+
+
+/// Load the bisection in progress, failing if there is none.
+fn open(repository: &Repository) -> Result<Bisector> {
+    match Bisector::open(&repository.paths)? {
+        Some(bisector) => Ok(bisector),
+        None => bail!("not bisecting; start with `attaca bisect start <bad> <good>...`"),
+    }
+}
+
+
+/// Save the bisection and report what to do next.
+fn report(repository: &Repository, bisector: &Bisector) -> Result<()> {
+    bisector.save(&repository.paths)?;
+
+    match bisector.next() {
+        Step::Test(hash, remaining) => {
+            println!("{} candidates left. Test:\n{}", remaining, hash);
+        }
+        Step::Found(hash) => {
+            println!("The first bad commit is:\n{}", hash);
+        }
+    }
+
+    Ok(())
+}
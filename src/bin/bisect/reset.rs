@@ -0,0 +1,18 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::history::bisect::Bisector;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("reset").about("Abandon the bisection in progress.")
+}
+
+
+pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
+    Bisector::reset(&repository.paths)?;
+
+    Ok(())
+}
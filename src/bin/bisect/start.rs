@@ -0,0 +1,46 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::history::bisect::Bisector;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("start")
+        .about("Start bisecting, discarding any bisection in progress.")
+        .arg(
+            Arg::with_name("BAD")
+                .index(1)
+                .required(true)
+                .help("A revision known to be bad."),
+        )
+        .arg(
+            Arg::with_name("GOOD")
+                .index(2)
+                .required(true)
+                .multiple(true)
+                .help("Revisions known to be good."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let bad = repository.refs.resolve(matches.value_of("BAD").unwrap())?;
+    let good = matches
+        .values_of("GOOD")
+        .unwrap()
+        .map(|rev| repository.refs.resolve(rev))
+        .collect::<::attaca::Result<Vec<_>>>()?;
+
+    let bisector = {
+        let ctx = repository.local(())?;
+        let bisector = Bisector::start(ctx.store(), bad, good).wait()?;
+        ctx.close().wait()?;
+
+        bisector
+    };
+
+    super::report(repository, &bisector)
+}
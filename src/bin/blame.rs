@@ -0,0 +1,62 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::history::blame;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("blame")
+        .about("Show which commit introduced each range of a file, at chunk granularity.")
+        .arg(
+            Arg::with_name("PATH")
+                .index(1)
+                .required(true)
+                .help("The file to blame, relative to the repository root."),
+        )
+        .arg(
+            Arg::with_name("rev")
+                .long("rev")
+                .takes_value(true)
+                .value_name("REV")
+                .default_value("HEAD")
+                .help("The revision to blame the file as of."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let head = repository.refs.resolve(matches.value_of("rev").unwrap())?;
+    let path = matches.value_of("PATH").unwrap();
+
+    let blame = {
+        let ctx = repository.local(())?;
+        let blame = blame::blame(ctx.store(), head, path).wait()?;
+        ctx.close().wait()?;
+
+        blame
+    };
+
+    for range in &blame.ranges {
+        let commit = &blame.commits[&range.commit];
+        let author = commit
+            .author
+            .as_ref()
+            .map(|author| author.name.clone())
+            .unwrap_or_else(|| "unknown".to_owned());
+
+        println!(
+            "{:>14}..{:<14} {} {} {} {}",
+            range.offset,
+            range.offset + range.size,
+            &range.commit.to_string()[..12],
+            commit.timestamp.format("%Y-%m-%d"),
+            author,
+            commit.message.lines().next().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::process::Command;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::branch_metadata::{self, BranchMetadata};
+use attaca::repository::{Head, RefName};
+
+use errors::*;
+use status;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("branch")
+        .about("List, create, delete, or describe branches.")
+        .arg(Arg::with_name("NAME").index(1).help(
+            "The branch to create, delete or describe. Branches are listed if no name is given.",
+        ))
+        .arg(Arg::with_name("REV").index(2).requires("NAME").help(
+            "The branch or commit to start the new branch at. Defaults to the HEAD.",
+        ))
+        .arg(
+            Arg::with_name("delete")
+                .short("d")
+                .long("delete")
+                .requires("NAME")
+                .conflicts_with("REV")
+                .help("Delete the branch NAME instead."),
+        )
+        .arg(Arg::with_name("list").short("l").long("list").conflicts_with("NAME").help(
+            "List branches. This is what is done when no NAME is given.",
+        ))
+        .arg(
+            Arg::with_name("remotes")
+                .short("r")
+                .long("remotes")
+                .conflicts_with_all(&["NAME", "verbose"])
+                .help("List the remote-tracking branches `remotes/<remote>/<branch>` instead."),
+        )
+        .arg(Arg::with_name("porcelain").long("porcelain").help(
+            "List branches as `<current> <hash> <name>` lines, where `<current>` is `*` for the \
+             branch the HEAD is on and `-` otherwise.",
+        ))
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .conflicts_with("porcelain")
+                .help("List the description, owner and other metadata of each branch."),
+        )
+        .arg(
+            Arg::with_name("edit-description")
+                .long("edit-description")
+                .conflicts_with_all(&["REV", "delete", "list"])
+                .help(
+                    "Edit the description of the branch NAME, or of the branch the HEAD is on, \
+                     in $VISUAL or $EDITOR.",
+                ),
+        )
+        .arg(
+            Arg::with_name("owner")
+                .long("owner")
+                .takes_value(true)
+                .conflicts_with_all(&["REV", "delete", "list"])
+                .help(
+                    "Set the owner of the branch NAME, or of the branch the HEAD is on. An empty \
+                     owner removes it.",
+                ),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .takes_value(true)
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["REV", "delete", "list"])
+                .help(
+                    "Set a field of the metadata of the branch NAME, or of the branch the HEAD is \
+                     on. May be given more than once.",
+                ),
+        )
+        .arg(
+            Arg::with_name("unset")
+                .long("unset")
+                .takes_value(true)
+                .value_name("KEY")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["REV", "delete", "list"])
+                .help("Remove a field of the metadata of a branch. May be given more than once."),
+        )
+}
+
+
+/// Open the user's editor on `text`, returning what they saved with `#` lines dropped.
+fn edit(repository: &Repository, branch: &str, text: &str) -> Result<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let edit_path = repository.paths.metadata.join("BRANCH_DESCRIPTION");
+
+    {
+        let mut edit_file = File::create(&edit_path)?;
+        writeln!(edit_file, "{}", text)?;
+        writeln!(
+            edit_file,
+            "# Describe the branch {}. Lines starting with `#` are ignored.",
+            branch
+        )?;
+    }
+
+    // The editor may be given with arguments of its own, such as `code --wait`.
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let status = Command::new(program).args(words).arg(&edit_path).status()?;
+    if !status.success() {
+        bail!("the editor `{}` failed with {}", editor, status);
+    }
+
+    let mut edited = String::new();
+    File::open(&edit_path)?.read_to_string(&mut edited)?;
+    fs::remove_file(&edit_path)?;
+
+    let description = edited
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(description.trim().to_owned())
+}
+
+
+/// Apply `--edit-description`, `--owner`, `--set` and `--unset` to the metadata of `branch`.
+fn describe(repository: &mut Repository, matches: &ArgMatches, branch: &str) -> Result<()> {
+    if !repository.refs.branches.contains_key(branch) {
+        bail!("no such branch `{}`", branch);
+    }
+
+    let original = branch_metadata::get(repository, branch)?.unwrap_or_default();
+    let mut metadata = original.clone();
+
+    if matches.is_present("edit-description") {
+        metadata.description = edit(repository, branch, &original.description)?;
+    }
+
+    if let Some(owner) = matches.value_of("owner") {
+        metadata.owner = if owner.is_empty() {
+            None
+        } else {
+            Some(owner.to_owned())
+        };
+    }
+
+    for field in matches.values_of("set").into_iter().flat_map(|fields| fields) {
+        match field.find('=') {
+            Some(i) => {
+                metadata.fields.insert(field[..i].to_owned(), field[i + 1..].to_owned());
+            }
+            None => bail!("`{}` is not of the form KEY=VALUE", field),
+        }
+    }
+
+    for key in matches.values_of("unset").into_iter().flat_map(|keys| keys) {
+        metadata.fields.remove(key);
+    }
+
+    if metadata != original {
+        branch_metadata::set(repository, branch, metadata)?;
+    }
+
+    Ok(())
+}
+
+
+/// Print the metadata of a branch beneath it in a verbose listing.
+fn print_metadata(metadata: &BranchMetadata) {
+    for line in metadata.description.lines() {
+        println!("      {}", line);
+    }
+
+    if let Some(ref owner) = metadata.owner {
+        println!("      owner: {}", owner);
+    }
+
+    for (key, value) in &metadata.fields {
+        println!("      {}: {}", key, value);
+    }
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let current = match repository.refs.head {
+        Head::LocalRef(ref branch) => Some(branch.clone()),
+        _ => None,
+    };
+
+    let describing = ["edit-description", "owner", "set", "unset"]
+        .iter()
+        .any(|arg| matches.is_present(arg));
+    if describing {
+        let branch = match matches.value_of("NAME").map(ToOwned::to_owned).or(current) {
+            Some(branch) => branch,
+            None => bail!("the HEAD is not on a branch; name the branch to describe"),
+        };
+
+        return describe(repository, matches, &branch);
+    }
+
+    let name = match matches.value_of("NAME") {
+        Some(name) => name,
+        None if matches.is_present("remotes") => {
+            let mut tracking = repository
+                .refs
+                .remotes
+                .iter()
+                .flat_map(|(remote, branches)| {
+                    branches.iter().map(move |(branch, &hash)| {
+                        (RefName::RemoteBranch(remote.clone(), branch.clone()), hash)
+                    })
+                })
+                .collect::<Vec<_>>();
+            tracking.sort_by(|a, b| a.0.to_string().cmp(&b.0.to_string()));
+
+            for (name, hash) in tracking {
+                if matches.is_present("porcelain") {
+                    println!("- {} {}", hash, name);
+                } else {
+                    println!("  {} {}", name, hash);
+                }
+            }
+
+            return Ok(());
+        }
+        None => {
+            let metadata = if matches.is_present("verbose") {
+                let metadata_head = repository
+                    .refs
+                    .branches
+                    .get(branch_metadata::METADATA_BRANCH)
+                    .cloned();
+                let ctx = repository.local(())?;
+                let metadata = branch_metadata::read_all(ctx.store(), metadata_head).wait()?;
+                ctx.close().wait()?;
+
+                metadata
+            } else {
+                BTreeMap::new()
+            };
+
+            let mut branches = repository
+                .refs
+                .branches
+                .iter()
+                .map(|(branch, &hash)| (branch.clone(), hash))
+                .collect::<Vec<_>>();
+            branches.sort_by(|a, b| a.0.cmp(&b.0));
+
+            for (branch, hash) in branches {
+                let is_current = current.as_ref() == Some(&branch);
+                if matches.is_present("porcelain") {
+                    println!("{} {} {}", if is_current { "*" } else { "-" }, hash, branch);
+                } else {
+                    let tracking = match repository.upstream(&branch) {
+                        Some(upstream) => {
+                            let upstream_hash = repository.refs.get(&upstream).unwrap();
+                            match status::divergence(repository, hash, upstream_hash) {
+                                Ok((0, 0)) => format!(" [{}]", upstream),
+                                Ok((ahead, 0)) => format!(" [{}: ahead {}]", upstream, ahead),
+                                Ok((0, behind)) => format!(" [{}: behind {}]", upstream, behind),
+                                Ok((ahead, behind)) => {
+                                    format!(" [{}: ahead {}, behind {}]", upstream, ahead, behind)
+                                }
+                                Err(_) => format!(" [{}: history unavailable]", upstream),
+                            }
+                        }
+                        None => String::new(),
+                    };
+
+                    println!(
+                        "{} {} {}{}",
+                        if is_current { "*" } else { " " },
+                        branch,
+                        hash,
+                        tracking
+                    );
+                }
+
+                if let Some(branch_metadata) = metadata.get(&branch) {
+                    print_metadata(branch_metadata);
+                }
+            }
+
+            return Ok(());
+        }
+    };
+
+    if matches.is_present("delete") {
+        if current.as_ref().map(String::as_str) == Some(name) {
+            bail!("cannot delete `{}`, the branch the HEAD is on", name);
+        }
+
+        match repository.refs.branches.remove(name) {
+            Some(hash) => println!("Deleted branch {} (was {}).", name, hash),
+            None => bail!("no such branch `{}`", name),
+        }
+
+        if branch_metadata::get(repository, name)?.is_some() {
+            branch_metadata::set(repository, name, BranchMetadata::default())?;
+        }
+
+        return Ok(());
+    }
+
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap_or("HEAD"))?;
+    repository.compare_and_swap_branch(name, None, commit_hash)?;
+    println!("Created branch {} at {}.", name, commit_hash);
+
+    Ok(())
+}
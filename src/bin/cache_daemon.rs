@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::cache;
+use attaca::telemetry;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("cache-daemon")
+        .about(
+            "Run a shared object cache for attaca processes belonging to this user.",
+        )
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("The path of the Unix socket to listen on."),
+        )
+        .arg(
+            Arg::with_name("capacity")
+                .long("capacity")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("The maximum number of bytes of objects to keep in memory."),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help(
+                    "Serve metrics for Prometheus to scrape over HTTP at ADDR, e.g. \
+                     `127.0.0.1:9464`.",
+                ),
+        )
+}
+
+
+pub fn go(matches: &ArgMatches) -> Result<()> {
+    let socket_path = matches.value_of("socket").map(PathBuf::from).unwrap_or_else(
+        cache::default_socket_path,
+    );
+    let capacity = if matches.is_present("capacity") {
+        value_t!(matches.value_of("capacity"), u64)?
+    } else {
+        cache::DEFAULT_CAPACITY
+    };
+
+    if let Some(addr) = matches.value_of("metrics") {
+        telemetry::serve_metrics(addr)?;
+        println!("Serving metrics on {}...", addr);
+    }
+
+    println!("Serving shared cache on {}...", socket_path.display());
+
+    cache::serve(socket_path, capacity)?;
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::inspect;
+
+use errors::*;
+use trace::Progress;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("cat-file")
+        .about("Describe an object as it is stored: its kind, size, encoding and references.")
+        .arg(
+            Arg::with_name("OBJECT")
+                .index(1)
+                .required(true)
+                .help("The hash of the object, or a revision naming a commit."),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .short("r")
+                .long("remote")
+                .takes_value(true)
+                .value_name("REMOTE")
+                .help("Read the object from this remote instead of the local store."),
+        )
+        .arg(Arg::with_name("raw").long("raw").help(
+            "Write the exact bytes the store holds for the object to standard output, and \
+             nothing else.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let hash = repository.refs.resolve(matches.value_of("OBJECT").unwrap())?;
+
+    let stored = match matches.value_of("remote") {
+        Some(remote) => {
+            let ctx = repository.remote(remote, Progress::new(None))?;
+            let stored = ctx.store().read_stored(hash).wait()?;
+            ctx.close().wait()?;
+
+            stored
+        }
+        None => {
+            let ctx = repository.local(())?;
+            let stored = ctx.store().read_raw(hash).wait()?;
+            ctx.close().wait()?;
+
+            stored
+        }
+    };
+
+    if matches.is_present("raw") {
+        io::stdout().write_all(&stored)?;
+        return Ok(());
+    }
+
+    let description = inspect::describe(hash, &stored)?;
+    let mut out = String::new();
+
+    writeln!(out, "hash      {}", description.hash)?;
+    writeln!(out, "kind      {}", description.kind())?;
+    writeln!(out, "encoding  {}", description.encoding)?;
+    writeln!(out, "stored    {} bytes", description.stored_size)?;
+    if let Some(data_size) = description.data_size() {
+        writeln!(out, "data      {} bytes", data_size)?;
+    }
+    writeln!(out, "refs      {}", description.refs.len())?;
+    for reference in &description.refs {
+        writeln!(out, "          {}", reference)?;
+    }
+
+    if let Some(ref object) = description.object {
+        let mut structure = String::new();
+        inspect::pretty(&mut structure, object)?;
+
+        if !structure.is_empty() {
+            writeln!(out, "")?;
+            out.push_str(&structure);
+        }
+    }
+
+    print!("{}", out);
+
+    Ok(())
+}
@@ -1,15 +1,14 @@
-use std::fs::{self, OpenOptions};
-use std::path::PathBuf;
+use std::cmp;
+use std::fs;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
-use memmap::{Mmap, Protection};
 use futures::prelude::*;
 
-use attaca::context::Context;
-use attaca::marshal::{DataObject, Object, ObjectHash, SubtreeEntry, SubtreeObject};
-use attaca::repository::Repository;
-use attaca::store::ObjectStore;
-use attaca::trace::Trace;
+use attaca::checkout::CheckoutOptions;
+use attaca::marshal::{Object, ObjectHash, SubtreeEntry};
+use attaca::repository::{Head, Repository};
+use attaca::sparse::Sparse;
+use attaca::worktree;
 
 use errors::*;
 
@@ -18,62 +17,125 @@ pub fn command() -> App<'static, 'static> {
     SubCommand::with_name("checkout")
         .help("Checkout a file or working directory from a previous revision.")
         .arg(
-            Arg::with_name("COMMIT")
+            Arg::with_name("REV")
                 .index(1)
                 .required(true)
-                .help("The commit hash to checkout."),
+                .help(
+                    "The branch or commit to check out. Checking out a branch makes new commits \
+                     advance it; checking out anything else detaches the HEAD.",
+                ),
         )
+        .arg(
+            Arg::with_name("branch")
+                .short("b")
+                .long("branch")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Create a new branch NAME at REV, and check it out."),
+        )
+        .arg(
+            Arg::with_name("open-files")
+                .short("j")
+                .long("open-files")
+                .takes_value(true)
+                .value_name("N")
+                .help("The maximum number of files to write in parallel."),
+        )
+        .arg(
+            Arg::with_name("chunk-workers")
+                .long("chunk-workers")
+                .takes_value(true)
+                .value_name("N")
+                .help(
+                    "The maximum number of chunks of any one large file to fetch in parallel.",
+                ),
+        )
+        .arg(
+            Arg::with_name("path")
+                .short("p")
+                .long("path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATTERN")
+                .conflicts_with("full")
+                .help(
+                    "Only check out files matching this pattern, and remember the pattern for \
+                     later operations. May be given more than once.",
+                ),
+        )
+        .arg(Arg::with_name("full").long("full").help(
+            "Check out every file, ending any sparse checkout.",
+        ))
+        .arg(Arg::with_name("lazy").long("lazy").help(
+            "Write placeholder files instead of fetching file contents. Placeholders may be \
+             filled in later with `hydrate`.",
+        ))
 }
 
 
-fn write_data_object<T: Trace, S: ObjectStore>(
-    ctx: &Context<T, S>,
-    path: PathBuf,
-    object_hash: ObjectHash,
-    size: u64,
-) -> Result<()> {
-    let mut parent = path.clone();
-    assert!(parent.pop());
-    fs::create_dir_all(parent)?;
-
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&path)?;
-    file.set_len(size)?;
-
-    // PermissionError indicates mmap opened with wrong permissions.
-    let mut mmap = Mmap::open(&file, Protection::ReadWrite)?;
-
-    let slice = unsafe { mmap.as_mut_slice() };
-    let mut stack = vec![(0u64, object_hash)];
-    while let Some((mut offset, object_hash)) = stack.pop() {
-        match ctx.read_object(object_hash).wait()? {
-            Object::Data(DataObject::Small(small_object)) => {
-                slice[offset as usize..(offset as usize + small_object.chunk.len())]
-                    .copy_from_slice(&small_object.chunk);
-            }
-            Object::Data(DataObject::Large(large_object)) => {
-                for (child_size, child_hash) in large_object.children {
-                    stack.push((offset, child_hash));
-                    offset += child_size;
-                }
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let rev = matches.value_of("REV").unwrap();
+    let commit_hash = repository.refs.resolve(rev)?;
+    let head = match matches.value_of("branch") {
+        Some(branch) if repository.refs.branches.contains_key(branch) => {
+            bail!("branch `{}` already exists", branch)
+        }
+        Some(branch) => Head::LocalRef(branch.to_owned()),
+        None if repository.refs.branches.contains_key(rev) => Head::LocalRef(rev.to_owned()),
+        None => Head::Detached(commit_hash),
+    };
+
+    // A branch checked out in two working directories would leave one of them stale whenever
+    // the other commits to it.
+    if let Head::LocalRef(ref branch) = head {
+        if let Some(path) = worktree::checked_out(&repository.paths, branch)? {
+            if path.canonicalize()? != repository.paths.base.canonicalize()? {
+                bail!(::attaca::ErrorKind::BranchCheckedOut(branch.clone(), path));
             }
-            _ => bail!("meep"),
         }
     }
 
+    let mut options = CheckoutOptions::default();
+    if matches.is_present("open-files") {
+        options.open_files = value_t!(matches.value_of("open-files"), usize)?;
+    }
+    if matches.is_present("chunk-workers") {
+        options.chunk_workers = cmp::max(value_t!(matches.value_of("chunk-workers"), usize)?, 1);
+    }
+
+    // Unless told otherwise, a sparse checkout stays sparse with the same patterns.
+    let sparse = if let Some(patterns) = matches.values_of("path") {
+        Some(Sparse::new(patterns)?)
+    } else if matches.is_present("full") {
+        None
+    } else {
+        repository.index.sparse().cloned()
+    };
+    options.filter = sparse.as_ref().map(|sparse| sparse.globset().clone());
+    options.lazy = matches.is_present("lazy");
+
+    check_out(repository, commit_hash, &options, sparse)?;
+
+    if let Some(branch) = matches.value_of("branch") {
+        repository.compare_and_swap_branch(branch, None, commit_hash)?;
+    }
+    repository.refs.head = head;
+
     Ok(())
 }
 
 
+/// Write the files of a commit into the working directory, and record what was written in the
+/// index. The sparse checkout patterns given are remembered for later operations.
 // TODO: Tree diff in order to remove files.
-pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
-    let commit_hash = matches.value_of("COMMIT").unwrap().parse()?;
-
-    {
+pub fn check_out(
+    repository: &mut Repository,
+    commit_hash: ObjectHash,
+    options: &CheckoutOptions,
+    sparse: Option<Sparse>,
+) -> Result<()> {
+    let listing = {
         let ctx = repository.local(())?;
         let commit = ctx.read_object(commit_hash).wait()?;
         let commit_object = match commit {
@@ -81,28 +143,44 @@ pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
             _ => bail!(ErrorKind::NotACommit(commit_hash)),
         };
 
-        let mut stack = vec![(PathBuf::new(), commit_object.subtree)];
-        while let Some((path, object)) = stack.pop() {
-            match ctx.read_object(object).wait()? {
-                Object::Subtree(SubtreeObject { entries }) => for (component, entry) in entries {
-                    let joined = path.join(component);
-
-                    match entry {
-                        SubtreeEntry::File(object_hash, size) => {
-                            write_data_object(&ctx, joined, object_hash, size)
-                                .chain_err(|| "While trying to write file")?;
-                        }
-                        SubtreeEntry::Subtree(object_hash) => {
-                            stack.push((joined, object_hash));
-                        }
-                    }
-                },
-                _ => bail!("Invalid subtree!"),
-            }
-        }
+        let base = ctx.paths.base.clone();
+        let listing = ctx.checkout(commit_object.subtree, base, options)
+            .wait()
+            .chain_err(|| "While trying to check out files")?;
 
         ctx.close().wait()?;
 
-        Ok(())
+        listing
+    };
+
+    for (path, entry) in listing.files {
+        match entry.unannotated() {
+            SubtreeEntry::File(object_hash, _) |
+            SubtreeEntry::Executable(object_hash, _) if options.lazy => {
+                let metadata = fs::symlink_metadata(repository.paths.base.join(&path))?;
+                repository.index.materialized_mut().remove(&path);
+                repository.index.placeholders_mut().insert(path, object_hash, &metadata);
+            }
+            _ => {
+                repository.index.materialized_mut().remove(&path);
+                repository.index.placeholders_mut().remove(&path);
+
+                // Files with restored mtimes can be trusted to match their recorded hashes
+                // without rehashing them.
+                if entry.metadata().is_some() {
+                    // Symlinks are never smudged, so their targets are as long as they are stored.
+                    let size = match entry.unannotated() {
+                        SubtreeEntry::File(_, size) |
+                        SubtreeEntry::Executable(_, size) => size,
+                        _ => fs::symlink_metadata(repository.paths.base.join(&path))?.len(),
+                    };
+                    repository.index.insert_hashed(&path, entry.hash(), size)?;
+                }
+            }
+        }
     }
+
+    repository.index.set_sparse(sparse);
+
+    Ok(())
 }
@@ -1,10 +1,17 @@
+use std::env;
+
 use chrono::prelude::*;
 use clap::{App, SubCommand, Arg, ArgMatches};
 use futures::prelude::*;
 use globset::{Glob, GlobSetBuilder};
 
 use attaca::Repository;
+use attaca::daemon;
+use attaca::hooks::{CommitEvent, Hooks};
+use attaca::marshal::{Identity, ObjectHash};
+use attaca::notes;
 use attaca::repository::Head;
+use attaca::search;
 
 use errors::*;
 use trace::Progress;
@@ -33,12 +40,80 @@ pub fn command() -> App<'static, 'static> {
                     "Zero or more patterns matching files to exclude from the commit.",
                 ),
         )
+        .arg(
+            Arg::with_name("TIMESTAMP")
+                .long("timestamp")
+                .takes_value(true)
+                .help(
+                    "An RFC 3339 timestamp to record in the commit instead of the current time. \
+                     Also read from `ATTACA_COMMIT_TIMESTAMP`, or `SOURCE_DATE_EPOCH` in seconds.",
+                ),
+        )
+        .arg(
+            Arg::with_name("PARENT")
+                .long("parent")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Record the given commit as a parent instead of the HEAD. May be given more \
+                     than once; parents are recorded in the order given.",
+                ),
+        )
+        .arg(
+            Arg::with_name("AUTHOR")
+                .long("author")
+                .takes_value(true)
+                .value_name("NAME <EMAIL>")
+                .help(
+                    "Record the given author instead of the configured identity. The committer \
+                     is still taken from the configuration.",
+                ),
+        )
+        .arg(Arg::with_name("porcelain").long("porcelain").help(
+            "Print only the hash of the new commit.",
+        ))
         .arg(Arg::with_name("MESSAGE").index(1).required(true).help(
             "The commit message.",
         ))
 }
 
 
+/// Determine the commit timestamp. So that re-running an automated pipeline on identical inputs
+/// produces an identical commit hash, an explicit timestamp always takes precedence over the wall
+/// clock.
+fn timestamp(matches: &ArgMatches) -> Result<DateTime<Utc>> {
+    if let Some(timestamp) = matches.value_of("TIMESTAMP") {
+        return parse_rfc3339(timestamp);
+    }
+
+    if let Ok(timestamp) = env::var("ATTACA_COMMIT_TIMESTAMP") {
+        return parse_rfc3339(&timestamp);
+    }
+
+    if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+        let seconds = epoch.trim().parse::<i64>().chain_err(|| {
+            format!("invalid SOURCE_DATE_EPOCH `{}`", epoch)
+        })?;
+        let timestamp = Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| {
+            format!("SOURCE_DATE_EPOCH `{}` is out of range", epoch)
+        })?;
+        return Ok(timestamp);
+    }
+
+    Ok(Utc::now())
+}
+
+
+fn parse_rfc3339(timestamp: &str) -> Result<DateTime<Utc>> {
+    let parsed = DateTime::parse_from_rfc3339(timestamp).chain_err(|| {
+        format!("invalid RFC 3339 timestamp `{}`", timestamp)
+    })?;
+
+    Ok(parsed.with_timezone(&Utc))
+}
+
+
 pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
     let include = if let Some(paths) = matches.values_of("INCLUDE") {
         let mut builder = GlobSetBuilder::new();
@@ -61,31 +136,120 @@ pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
     };
 
     let message = matches.value_of("MESSAGE").unwrap().to_owned();
+    let timestamp = timestamp(matches)?;
+    let parents_opt = match matches.values_of("PARENT") {
+        Some(parents) => Some(parents
+            .map(str::parse)
+            .collect::<::attaca::Result<Vec<ObjectHash>>>()?),
+        None => None,
+    };
+
+    let author = match matches.value_of("AUTHOR") {
+        Some(author) => Some(author.parse::<Identity>()?),
+        None => repository.config.author(),
+    };
+    let committer = repository.config.committer();
 
-    repository.index.update()?;
+    daemon::update_index(repository)?;
 
-    let commit_hash = {
+    // If anything below fails, the objects written so far are discarded rather than left in the
+    // store.
+    repository.begin_staging()?;
+    let (commit_hash, commit) = {
         let ctx = repository.local(Progress::new(None))?;
 
-        let head_hash = ctx.refs.head();
-        // Merges are unimplemented. So, the only possible parent is the head.
+        // Merge commits are made by `attaca merge`. So, unless parents are given explicitly, the
+        // only parent is the head.
+        let parents = parents_opt.unwrap_or_else(|| ctx.refs.head().into_iter().collect());
         let commit_hash = ctx.write_commit(
             include.as_ref(),
             exclude.as_ref(),
-            head_hash.into_iter().collect(),
+            parents,
             message,
-            Utc::now(),
+            timestamp,
+            author,
+            committer,
         ).wait()?;
+        let commit = ctx.read_commit(commit_hash).wait()?;
 
         ctx.close().wait()?;
 
-        commit_hash
+        (commit_hash, commit)
+    };
+
+    // Committing on a branch advances it; otherwise, the HEAD is detached at the new commit.
+    let branch_opt = match repository.refs.head {
+        Head::LocalRef(ref branch) => Some(branch.clone()),
+        _ => None,
     };
 
-    repository.refs.head = Head::Detached(commit_hash);
+    // A pre-commit hook failing leaves staging unfinished, so the commit's objects are discarded.
+    repository.hooks.pre_commit(&CommitEvent {
+        commit_hash,
+        commit: &commit,
+        branch: branch_opt.as_ref().map(String::as_str),
+    })?;
+
+    repository.finish_staging();
+
+    // A required generator failing leaves the commit written but unreferenced.
+    let generated = notes::generate(repository, commit_hash)?;
+    for (name, error) in generated.failed {
+        eprintln!("Warning: generator `{}` failed: {}", name, error);
+    }
+
+    match branch_opt {
+        Some(ref branch) => {
+            let expected = repository.refs.branches.get(branch).cloned();
+            repository.compare_and_swap_branch(branch, expected, commit_hash)?;
+        }
+        None => repository.refs.head = Head::Detached(commit_hash),
+    }
+
     repository.index.iter_mut().for_each(
         |(_, entry)| entry.added = false,
     );
 
+    // Placeholders written to since they were checked out have been committed in full, and are
+    // placeholders no longer.
+    let modified = {
+        let placeholders = repository.index.placeholders();
+        placeholders
+            .iter()
+            .map(|(path, _, _)| path.to_owned())
+            .filter(|path| !placeholders.is_unmodified(&repository.paths.base, path))
+            .collect::<Vec<_>>()
+    };
+    for path in modified {
+        repository.index.placeholders_mut().remove(path);
+    }
+
+    if let Err(error) = search::refresh(repository, &[commit_hash]) {
+        eprintln!("Warning: could not update the search index: {}", error);
+    }
+
+    // The commit has landed, so a post-commit hook failing is only reported.
+    if let Err(error) = repository.hooks.post_commit(&CommitEvent {
+        commit_hash,
+        commit: &commit,
+        branch: branch_opt.as_ref().map(String::as_str),
+    })
+    {
+        eprintln!("Warning: {}", error);
+    }
+
+    if matches.is_present("porcelain") {
+        println!("{}", commit_hash);
+    } else {
+        match branch_opt {
+            Some(branch) => println!("Committed {} to {}.", commit_hash, branch),
+            None => println!("Committed {} (detached HEAD).", commit_hash),
+        }
+
+        if !generated.attached.is_empty() {
+            println!("Attached notes: {}.", generated.attached.join(", "));
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,54 @@
+use std::env;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::daemon;
+use attaca::telemetry;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("daemon")
+        .about(
+            "Run a background daemon keeping repository state warm for faster commands.",
+        )
+        .arg(Arg::with_name("worktree").long("worktree").required(true).help(
+            "Keep the index, history, and HEAD tree of the current worktree in memory, and \
+             answer status, log, and diff queries from them.",
+        ))
+        .arg(Arg::with_name("watch").long("watch").help(
+            "Watch the worktree for changes, so that only files which may have changed are \
+             statted by status, diff, and commit.",
+        ))
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help(
+                    "Serve metrics for Prometheus to scrape over HTTP at ADDR, e.g. \
+                     `127.0.0.1:9464`.",
+                ),
+        )
+}
+
+
+pub fn go(matches: &ArgMatches) -> Result<()> {
+    let repository = Repository::find(env::current_dir()?)?;
+
+    if let Some(addr) = matches.value_of("metrics") {
+        telemetry::serve_metrics(addr)?;
+        println!("Serving metrics on {}...", addr);
+    }
+
+    println!(
+        "Serving worktree daemon on {}...",
+        repository.paths.daemon_socket.display()
+    );
+
+    daemon::serve(repository, matches.is_present("watch"))?;
+
+    Ok(())
+}
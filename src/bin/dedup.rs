@@ -0,0 +1,160 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+use serde_json;
+
+use attaca::Repository;
+use attaca::store::{self, DedupStats};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("dedup")
+        .about(
+            "Report how many chunks the trees of some commits share, and how much deduplication \
+             saves against storing each commit as plain files.",
+        )
+        .arg(
+            Arg::with_name("REV")
+                .index(1)
+                .multiple(true)
+                .help("The revisions to compare. Defaults to the HEAD."),
+        )
+        .arg(
+            Arg::with_name("compression-level")
+                .short("z")
+                .long("compression-level")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .help(
+                    "Also compress every chunk with zstd at this level, to estimate what \
+                     compression would save on top of deduplication.",
+                ),
+        )
+        .arg(Arg::with_name("json").long("json").help(
+            "Print the report as JSON instead of as a table.",
+        ))
+}
+
+
+#[derive(Debug, Serialize)]
+struct CommitReport {
+    commit: String,
+    chunks: u64,
+    logical_bytes: u64,
+    stored_bytes: u64,
+    unique_bytes: u64,
+}
+
+
+#[derive(Debug, Serialize)]
+struct Report {
+    commits: Vec<CommitReport>,
+    chunks: u64,
+    shared_chunks: u64,
+    logical_bytes: u64,
+    stored_bytes: u64,
+    compressed_bytes: Option<u64>,
+    dedup_savings: Option<f64>,
+    compressed_savings: Option<f64>,
+}
+
+
+impl<'a> From<&'a DedupStats> for Report {
+    fn from(stats: &'a DedupStats) -> Self {
+        let commits = stats
+            .commits
+            .iter()
+            .map(|commit| {
+                CommitReport {
+                    commit: commit.commit_hash.to_string(),
+                    chunks: commit.chunks,
+                    logical_bytes: commit.logical_bytes,
+                    stored_bytes: commit.stored_bytes,
+                    unique_bytes: commit.unique_bytes,
+                }
+            })
+            .collect();
+
+        Report {
+            commits,
+            chunks: stats.chunks,
+            shared_chunks: stats.shared_chunks,
+            logical_bytes: stats.logical_bytes,
+            stored_bytes: stats.stored_bytes,
+            compressed_bytes: stats.compressed_bytes,
+            dedup_savings: stats.dedup_savings(),
+            compressed_savings: stats.compressed_savings(),
+        }
+    }
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let revs = matches.values_of("REV").map_or_else(|| vec!["HEAD"], |revs| revs.collect());
+    let commit_hashes = revs.into_iter()
+        .map(|rev| repository.refs.resolve(rev))
+        .collect::<::attaca::Result<Vec<_>>>()?;
+    let compression_level = if matches.is_present("compression-level") {
+        Some(value_t!(matches.value_of("compression-level"), i32)?)
+    } else {
+        None
+    };
+    let version = repository.object_version;
+
+    let stats = {
+        let ctx = repository.local(())?;
+        let stats = store::dedup_stats(ctx.store(), commit_hashes, version, compression_level)
+            .wait()?;
+        ctx.close().wait()?;
+
+        stats
+    };
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string(&Report::from(&stats))?);
+        return Ok(());
+    }
+
+    println!(
+        "{:>16} {:>16} {:>16} {:>12}  {}",
+        "logical",
+        "stored",
+        "unique",
+        "chunks",
+        "commit"
+    );
+    for commit in &stats.commits {
+        println!(
+            "{:>16} {:>16} {:>16} {:>12}  {}",
+            commit.logical_bytes,
+            commit.stored_bytes,
+            commit.unique_bytes,
+            commit.chunks,
+            commit.commit_hash
+        );
+    }
+
+    println!(
+        "{} distinct chunks, {} shared between commits",
+        stats.chunks,
+        stats.shared_chunks
+    );
+    println!(
+        "{} logical bytes stored as {}",
+        stats.logical_bytes,
+        stats.stored_bytes
+    );
+    if let Some(savings) = stats.dedup_savings() {
+        println!("deduplication saves {:.1}%", savings * 100.0);
+    }
+    if let (Some(compressed), Some(savings)) = (stats.compressed_bytes, stats.compressed_savings()) {
+        println!(
+            "{} bytes compressed; deduplication and compression save {:.1}%",
+            compressed,
+            savings * 100.0
+        );
+    }
+
+    Ok(())
+}
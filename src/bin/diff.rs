@@ -0,0 +1,43 @@
+use clap::{App, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::checkout::{self, Listing};
+use attaca::daemon::{self, Change, DaemonClient};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("diff").about("Show tracked files which differ from the HEAD commit.")
+}
+
+
+pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
+    let changes = match DaemonClient::connect(&repository.paths) {
+        Some(mut client) => client.diff()?,
+        None => {
+            repository.index.update()?;
+
+            let ctx = repository.local(())?;
+            let listing = match ctx.read_head().wait()? {
+                Some(commit) => checkout::walk(ctx.store(), commit.subtree).wait()?,
+                None => Listing::default(),
+            };
+            let changes = daemon::changes(&ctx.index, &listing);
+            ctx.close().wait()?;
+
+            changes
+        }
+    };
+
+    for change in changes {
+        match change {
+            Change::Added(path) => println!("A\t{}", path.display()),
+            Change::Modified(path) => println!("M\t{}", path.display()),
+            Change::Removed(path) => println!("D\t{}", path.display()),
+        }
+    }
+
+    Ok(())
+}
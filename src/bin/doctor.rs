@@ -0,0 +1,53 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::integrity;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("doctor")
+        .about("Report problems detected with the repository during normal use.")
+        .arg(Arg::with_name("clear").long("clear").help(
+            "Forget previously reported corruption, e.g. after repairing the store.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("clear") {
+        integrity::clear(&repository.paths)?;
+        return Ok(());
+    }
+
+    let percent = repository.config.integrity_sample_percent;
+    if percent > 0.0 {
+        println!("Verifying {}% of objects read from the local store.", percent);
+    } else {
+        println!(
+            "Integrity sampling is disabled; set `integrity_sample_percent` in the config to \
+             verify a percentage of objects as they are read."
+        );
+    }
+
+    let records = integrity::records(&repository.paths)?;
+    if records.is_empty() {
+        println!("No corruption detected.");
+        return Ok(());
+    }
+
+    println!("{} corrupt objects detected:", records.len());
+    for record in &records {
+        println!(
+            "\t{} (read back as {}), detected {}",
+            record.expected,
+            record.actual,
+            record.detected
+        );
+    }
+    println!("Run `attaca repair` to recover them from a remote, or `attaca fsck --depth data` to \
+              check the whole store.");
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::store;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("du")
+        .about("Show how much space each top-level path of a commit takes up.")
+        .arg(
+            Arg::with_name("REV")
+                .index(1)
+                .default_value("HEAD")
+                .help("The revision to measure: `HEAD`, a branch, or a commit hash."),
+        )
+        .arg(
+            Arg::with_name("sort")
+                .long("sort")
+                .takes_value(true)
+                .possible_values(&["name", "logical", "unique", "exclusive"])
+                .default_value("unique")
+                .help("The column to sort by. Sizes are sorted largest first."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap())?;
+    let version = repository.object_version;
+
+    let usage = {
+        let ctx = repository.local(())?;
+        let usage = store::path_usage(ctx.store(), commit_hash, version).wait()?;
+        ctx.close().wait()?;
+
+        usage
+    };
+
+    let mut rows = usage.into_iter().collect::<Vec<_>>();
+    match matches.value_of("sort").unwrap() {
+        "name" => {}
+        "logical" => rows.sort_by(|a, b| b.1.logical_bytes.cmp(&a.1.logical_bytes)),
+        "unique" => rows.sort_by(|a, b| b.1.unique_bytes.cmp(&a.1.unique_bytes)),
+        "exclusive" => rows.sort_by(|a, b| b.1.exclusive_bytes.cmp(&a.1.exclusive_bytes)),
+        _ => panic!("clap verification failure!"),
+    }
+
+    println!("{:>16} {:>16} {:>16}  {}", "logical", "unique", "exclusive", "path");
+    for (name, path_usage) in rows {
+        println!(
+            "{:>16} {:>16} {:>16}  {}",
+            path_usage.logical_bytes,
+            path_usage.unique_bytes,
+            path_usage.exclusive_bytes,
+            name.to_string_lossy()
+        );
+    }
+
+    Ok(())
+}
@@ -11,9 +11,11 @@ error_chain! {
     foreign_links {
         Clap(::clap::Error);
         Fmt(::std::fmt::Error);
+        Git(::git2::Error) #[cfg(feature = "git")];
         GlobSet(::globset::Error);
         Nul(::std::ffi::NulError);
         Io(::std::io::Error);
+        Json(::serde_json::Error);
     }
 
     errors {
@@ -31,5 +33,20 @@ error_chain! {
             description("not a commit hash"),
             display("{} is not a commit hash", hash),
         }
+
+        PublishRejected(branch: String) {
+            description("a branch was not published"),
+            display("branch `{}` was not published", branch),
+        }
+
+        SuiteFailed(suite: String) {
+            description("a test suite failed"),
+            display("test suite '{}' failed", suite),
+        }
+
+        UntrustedCommit(hash: ObjectHash) {
+            description("a commit is not signed by a trusted key"),
+            display("commit {} is not signed by a trusted key", hash),
+        }
     }
 }
@@ -0,0 +1,109 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use globset::{Glob, GlobSetBuilder};
+
+use attaca::Repository;
+use attaca::evict::{self, EvictionPolicy};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("evict")
+        .about(
+            "Turn files hydrated from placeholders back into placeholders, least recently used \
+             first.",
+        )
+        .arg(Arg::with_name("PATH").index(1).multiple(true).help(
+            "Patterns matching hydrated files to evict regardless of any limits.",
+        ))
+        .arg(
+            Arg::with_name("max-bytes")
+                .long("max-bytes")
+                .takes_value(true)
+                .value_name("BYTES")
+                .conflicts_with("PATH")
+                .help(
+                    "Evict files until those remaining take up at most BYTES. Overrides \
+                     `max_bytes` under `[eviction]` in the config.",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-idle-days")
+                .long("max-idle-days")
+                .takes_value(true)
+                .value_name("DAYS")
+                .conflicts_with("PATH")
+                .help(
+                    "Evict files unused for more than DAYS days. Overrides `max_idle_days` under \
+                     `[eviction]` in the config.",
+                ),
+        )
+        .arg(Arg::with_name("dry-run").long("dry-run").help(
+            "List the files which would be evicted without evicting them.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let plan = if let Some(paths) = matches.values_of("PATH") {
+        let mut builder = GlobSetBuilder::new();
+        for path in paths {
+            builder.add(Glob::new(path)?);
+        }
+
+        evict::plan_matching(&repository.index, &repository.paths.base, &builder.build()?)?
+    } else {
+        let mut policy = EvictionPolicy::configured(&repository.config).unwrap_or_default();
+        if matches.is_present("max-bytes") {
+            policy.max_bytes = Some(value_t!(matches.value_of("max-bytes"), u64)?);
+        }
+        if matches.is_present("max-idle-days") {
+            let days = value_t!(matches.value_of("max-idle-days"), u64)?;
+            policy.max_idle = Some(Duration::days(days as i64));
+        }
+
+        if policy.is_unlimited() {
+            bail!(::attaca::ErrorKind::NoEvictionPolicy);
+        }
+
+        evict::plan(
+            &repository.index,
+            &repository.paths.base,
+            &policy,
+            &Default::default(),
+            Utc::now(),
+        )?
+    };
+
+    if matches.is_present("dry-run") {
+        for candidate in &plan.evict {
+            println!(
+                "{}\t{} bytes, last used {}",
+                candidate.path.display(),
+                candidate.size,
+                candidate.last_used.to_rfc3339()
+            );
+        }
+
+        println!(
+            "Would evict {} files ({} bytes), leaving {} bytes hydrated.",
+            plan.evict.len(),
+            plan.evicted_bytes(),
+            plan.retained_bytes
+        );
+
+        return Ok(());
+    }
+
+    let evicted = evict::apply(&mut repository.index, &repository.paths.base, &plan)?;
+    println!(
+        "Evicted {} files ({} bytes), leaving {} bytes hydrated.",
+        evicted.paths.len(),
+        evicted.bytes,
+        plan.retained_bytes
+    );
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::marshal::ObjectHash;
+use attaca::mirror;
+use attaca::repository::RefName;
+use attaca::search;
+use attaca::sync::{self, FetchOptions};
+
+use errors::*;
+use trace::Progress;
+use transfer;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("fetch")
+        .about("Copy the history behind a commit from a remote into the local store.")
+        .arg(
+            Arg::with_name("REMOTE")
+                .index(1)
+                .required_unless_one(&["deepen", "promised"])
+                .help(
+                    "The remote to fetch from. May be left out in favor of the default pull \
+                     remote, in which case the only argument given is taken as REV.",
+                ),
+        )
+        .arg(
+            Arg::with_name("REV")
+                .index(2)
+                .help(
+                    "The branch of the remote, or the commit hash, to fetch. Defaults to every \
+                     remote-tracking branch moved by --upstream.",
+                ),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with("deepen")
+                .help("Fetch only the N most recent generations of commits."),
+        )
+        .arg(
+            Arg::with_name("deepen")
+                .long("deepen")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with("promised")
+                .help("Fetch N more generations of commits behind a shallow history."),
+        )
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATH")
+                .help(
+                    "Fetch only the files at or beneath PATH, leaving the rest on the remote to be \
+                     fetched later. May be given more than once.",
+                ),
+        )
+        .arg(
+            Arg::with_name("promised")
+                .long("promised")
+                .conflicts_with("REV")
+                .help(
+                    "Fetch what earlier fetches restricted by --path left on the remote, or only \
+                     what lies beneath the paths given with --path.",
+                ),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with_all(&["deepen", "promised"])
+                .help(
+                    "Update the remote-tracking branches `remotes/REMOTE/*` from the branches of \
+                     the repository at PATH, which pushes to the remote.",
+                ),
+        )
+        .args(&transfer::args())
+}
+
+
+/// Replace the remote-tracking branches of `remote` with the branches of the upstream repository
+/// at `path`, printing how they moved and returning the commits they moved to.
+pub fn track_upstream(
+    repository: &mut Repository,
+    remote: &str,
+    path: &str,
+) -> Result<Vec<ObjectHash>> {
+    let upstream = mirror::upstream_branches(path)?;
+    let mut moved = Vec::new();
+
+    for change in repository.refs.track_remote(remote, upstream) {
+        let name = RefName::RemoteBranch(remote.to_owned(), change.branch);
+        match (change.old, change.new) {
+            (Some(old), Some(new)) => println!("{}: {} -> {}", name, old, new),
+            (None, Some(new)) => println!("{}: new at {}", name, new),
+            (_, None) => println!("{}: deleted", name),
+        }
+        moved.extend(change.new);
+    }
+
+    Ok(moved)
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let needs_rev = !matches.is_present("deepen") && !matches.is_present("promised") &&
+        !matches.is_present("upstream");
+    let (remote, rev) = match (matches.value_of("REMOTE"), matches.value_of("REV")) {
+        (remote, Some(rev)) => (repository.config.pull_remote(remote)?, Some(rev)),
+        (Some(rev), None) if needs_rev => (repository.config.pull_remote(None)?, Some(rev)),
+        (remote, None) => (repository.config.pull_remote(remote)?, None),
+    };
+    let local_catalog = repository.catalogs.get(None)?;
+    let paths = matches
+        .values_of("path")
+        .map(|values| values.map(PathBuf::from).collect())
+        .unwrap_or_else(Vec::new);
+    let shallow = repository.shallow.clone();
+    let promised = repository.promised.clone();
+    let mut fetched_heads = Vec::new();
+    let transfer_options = transfer::options(matches)?;
+
+    let moved = match matches.value_of("upstream") {
+        Some(path) => track_upstream(repository, &remote, path)?,
+        None => Vec::new(),
+    };
+
+    let fetched = if matches.is_present("promised") {
+        if promised.is_empty() {
+            bail!("nothing was left on the remote by an earlier fetch");
+        }
+
+        let ctx = repository.remote(&remote, Progress::new(None))?;
+        let fetched = sync::fetch_promised(
+            ctx.store(),
+            &local_catalog,
+            &shallow,
+            &promised,
+            &paths[..],
+            &transfer_options,
+        ).wait()?;
+        ctx.close().wait()?;
+
+        fetched
+    } else if matches.is_present("deepen") {
+        let depth = value_t!(matches.value_of("deepen"), usize)?;
+        if shallow.is_empty() {
+            bail!("history is already complete; there is nothing to deepen");
+        }
+
+        let ctx = repository.remote(&remote, Progress::new(None))?;
+        let fetched = sync::deepen(
+            ctx.store(),
+            &local_catalog,
+            &shallow,
+            &promised,
+            depth,
+            paths,
+            &transfer_options,
+        ).wait()?;
+        ctx.close().wait()?;
+
+        fetched
+    } else {
+        let heads = match rev {
+            Some(rev) => {
+                vec![
+                    repository
+                        .refs
+                        .resolve(&format!("{}/{}", remote, rev))
+                        .or_else(|_| repository.refs.resolve(rev))?,
+                ]
+            }
+            None => moved,
+        };
+        let options = FetchOptions {
+            depth: if matches.is_present("depth") {
+                Some(value_t!(matches.value_of("depth"), usize)?)
+            } else {
+                None
+            },
+            paths,
+            transfer: transfer_options,
+            ..FetchOptions::default()
+        };
+
+        let ctx = repository.remote(&remote, Progress::new(None))?;
+        let fetched = sync::fetch(
+            ctx.store(),
+            &local_catalog,
+            &shallow,
+            &promised,
+            heads.clone(),
+            &options,
+        ).wait()?;
+        ctx.close().wait()?;
+        fetched_heads = heads;
+
+        fetched
+    };
+
+    fetched.shallow.save(&repository.paths)?;
+    fetched.promised.save(&repository.paths)?;
+    repository.shallow = fetched.shallow;
+    repository.promised = fetched.promised;
+
+    if let Err(error) = search::refresh(repository, &fetched_heads) {
+        eprintln!("Warning: could not update the search index: {}", error);
+    }
+
+    println!("Fetched {} objects.", fetched.objects);
+    if !repository.shallow.is_empty() {
+        println!(
+            "History is shallow: {} commits have parents which were not fetched.",
+            repository.shallow.iter().count()
+        );
+    }
+    if !repository.promised.is_empty() {
+        println!(
+            "{} entries were left on the remote; fetch them with --promised.",
+            repository.promised.len()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::search::{self, SearchIndex};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("find")
+        .about(
+            "Find every path which has appeared in the history containing a pattern, ignoring \
+             case. Requires the search index.",
+        )
+        .arg(Arg::with_name("PATTERN").index(1).required(true).help(
+            "The text to look for in paths.",
+        ))
+        .arg(Arg::with_name("porcelain").long("porcelain").help(
+            "Print each match as a `<commit>\\t<path>` line, where `<commit>` is the first commit \
+             the path was indexed in.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if search::refresh(repository, &[])?.is_none() {
+        bail!(::attaca::ErrorKind::SearchIndexDisabled);
+    }
+
+    let index = SearchIndex::open(&repository.paths)?;
+    let found = index.find(matches.value_of("PATTERN").unwrap());
+
+    for found in &found {
+        if matches.is_present("porcelain") {
+            println!("{}\t{}", found.commit, found.path);
+        } else {
+            println!("{} (since {})", found.path, found.commit);
+        }
+    }
+
+    Ok(())
+}
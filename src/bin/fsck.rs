@@ -1,14 +1,19 @@
-use std::collections::HashSet;
 use std::fmt::Write;
 
 use clap::{App, SubCommand, Arg, ArgMatches};
+use futures::future::{self, FutureResult};
 use futures::prelude::*;
-use futures::stream;
 
-use attaca::marshal::{self, Object, DataObject, SubtreeEntry};
+use attaca::graph::{self, Visit, Visitor};
+use attaca::marshal::{self, ObjectHash, Object, DataObject, SubtreeEntry};
+use attaca::marshal::sealed;
 use attaca::Repository;
+use attaca::promised::Promised;
+use attaca::shallow::Shallow;
+use attaca::store;
 
 use errors::*;
+use trace::Progress;
 
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -19,6 +24,60 @@ pub enum Depth {
 }
 
 
+struct FsckVisitor {
+    depth: Depth,
+    shallow: Shallow,
+    promised: Promised,
+    errors: Vec<Error>,
+}
+
+
+impl Visitor for FsckVisitor {
+    type Future = FutureResult<Visit, ::attaca::Error>;
+
+    fn enter(&mut self, hash: ObjectHash) -> bool {
+        // Objects left on a remote by a fetch restricted to some paths are not held locally.
+        !self.promised.contains(&hash)
+    }
+
+    fn visit(&mut self, hash: ObjectHash, object: Object) -> Self::Future {
+        let real_hash = marshal::hash(&object);
+
+        if hash != real_hash {
+            self.errors.push(Error::from_kind(ErrorKind::FsckFailure(hash, real_hash)));
+        }
+
+        let depth = &self.depth;
+        let follow = match object {
+            Object::Data(DataObject::Large(ref large_object)) if *depth >= Depth::Data => {
+                large_object.children.iter().map(|&(_, hash)| hash).collect()
+            }
+            Object::Subtree(ref subtree_object) if *depth >= Depth::Subtree => {
+                subtree_object
+                    .entries
+                    .values()
+                    .filter_map(|entry| match entry.unannotated() {
+                        SubtreeEntry::Subtree(hash) => Some(hash),
+                        _ if *depth >= Depth::Data => entry.data_hash(),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            Object::Commit(ref commit_object) if *depth >= Depth::Commit => {
+                let mut follow = self.shallow.parents(&hash, commit_object).to_vec();
+                if *depth >= Depth::Subtree {
+                    follow.push(commit_object.subtree);
+                }
+                follow
+            }
+            _ => Vec::new(),
+        };
+
+        future::ok(Visit::Follow(follow))
+    }
+}
+
+
 pub fn command() -> App<'static, 'static> {
     SubCommand::with_name("fsck")
         .about("Verify repository hashes.")
@@ -34,6 +93,56 @@ pub fn command() -> App<'static, 'static> {
                 .possible_values(&["commit", "subtree", "data"])
                 .default_value("commit"),
         )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .takes_value(true)
+                .value_name("NAME")
+                .help(
+                    "Check the objects the remote NAME is known to hold instead, as stored. Needs \
+                     no encryption key: sealed objects are checked against the digest in their \
+                     envelope, and the objects each refers to must be held too.",
+                ),
+        )
+}
+
+
+/// Check every object the catalog of `remote` lists, without unsealing any of them.
+fn check_remote(repository: &mut Repository, remote: &str) -> Result<Vec<Error>> {
+    let remote_catalog = repository.catalogs.get(Some(remote.to_owned()))?;
+    let hashes = remote_catalog.search(&[][..]);
+    let mut errors = Vec::new();
+
+    let ctx = repository.remote(remote, Progress::new(None))?;
+    for hash in hashes {
+        let checked = ctx.store()
+            .read_stored(hash)
+            .wait()
+            .and_then(|stored| if sealed::is_sealed(&stored) {
+                Ok(stored)
+            } else {
+                store::decompress_stored(stored)
+            })
+            .and_then(|stored| {
+                sealed::check_stored(hash, &stored)?;
+                sealed::stored_refs(&stored)
+            });
+
+        match checked {
+            Ok(refs) => {
+                for reference in refs {
+                    if remote_catalog.get(reference).is_none() {
+                        let message = format!("{} refers to {}, which is missing", hash, reference);
+                        errors.push(message.into());
+                    }
+                }
+            }
+            Err(error) => errors.push(format!("{}: {}", hash, error).into()),
+        }
+    }
+    ctx.close().wait()?;
+
+    Ok(errors)
 }
 
 
@@ -45,63 +154,21 @@ pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
         _ => panic!("clap verification failure!"),
     };
 
-    let errors = {
+    let errors = if let Some(remote) = matches.value_of("remote") {
+        check_remote(repository, remote)?
+    } else {
         let ctx = repository.local(())?;
-
-        let mut errors = Vec::new();
-        let mut hashes = ctx.refs.head().into_iter().collect::<Vec<_>>();
-        let mut visited = HashSet::new();
-
-        while !hashes.is_empty() {
-            let object_stream = {
-                let next_hashes = hashes.drain(..).filter(|&hash| visited.insert(hash));
-                stream::futures_unordered(next_hashes.map(|hash| {
-                    ctx.read_object(hash).map(move |object| (hash, object))
-                }))
-            };
-
-            object_stream
-                .from_err::<Error>()
-                .for_each(|(hash, object)| {
-                    let real_hash = marshal::hash(&object);
-
-                    if hash != real_hash {
-                        errors.push(Error::from_kind(ErrorKind::FsckFailure(hash, real_hash)));
-                    }
-
-                    match object {
-                        Object::Data(DataObject::Large(ref large_object))
-                            if depth >= Depth::Data => {
-                            hashes.extend(large_object.children.iter().map(|&(_, hash)| hash));
-                        }
-                        Object::Subtree(ref subtree_object) if depth >= Depth::Subtree => {
-                            hashes.extend(subtree_object.entries.iter().filter_map(
-                                |(_, entry)| match *entry {
-                                    SubtreeEntry::File(hash, _) if depth >= Depth::Data => Some(
-                                        hash,
-                                    ),
-                                    SubtreeEntry::Subtree(hash) => Some(hash),
-                                    _ => None,
-                                },
-                            ));
-                        }
-                        Object::Commit(ref commit_object) if depth >= Depth::Commit => {
-                            hashes.extend(commit_object.parents.iter().cloned());
-                            if depth >= Depth::Subtree {
-                                hashes.push(commit_object.subtree);
-                            }
-                        }
-                        _ => {}
-                    }
-
-                    Ok(())
-                })
-                .wait()?;
-        }
-
+        let roots = ctx.refs.head().into_iter().collect::<Vec<_>>();
+        let visitor = FsckVisitor {
+            depth,
+            shallow: ctx.shallow.clone(),
+            promised: ctx.promised.clone(),
+            errors: Vec::new(),
+        };
+        let visitor = graph::visit(ctx.store(), roots, visitor).wait()?;
         ctx.close().wait()?;
 
-        errors
+        visitor.errors
     };
 
     if errors.is_empty() {
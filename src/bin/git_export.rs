@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::export;
+use attaca::export::fast_import::FastImportOptions;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("git-export")
+        .about(
+            "Write the history of branches as a `git fast-import` stream, for mirroring into a \
+             git repository.",
+        )
+        .arg(Arg::with_name("BRANCH").index(1).multiple(true).help(
+            "The branches to export, each to the git branch of the same name. Defaults to every \
+             branch.",
+        ))
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the stream to FILE rather than to standard output."),
+        )
+        .arg(
+            Arg::with_name("inline-limit")
+                .long("inline-limit")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help(
+                    "Write files of at most BYTES inline with their commits, and larger files \
+                     as separate blobs. Defaults to 64 KB.",
+                ),
+        )
+        .arg(
+            Arg::with_name("lfs-threshold")
+                .long("lfs-threshold")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Write files larger than BYTES as git LFS pointers."),
+        )
+        .arg(
+            Arg::with_name("lfs-objects")
+                .long("lfs-objects")
+                .takes_value(true)
+                .value_name("DIR")
+                .requires("lfs-threshold")
+                .help(
+                    "Write the files behind LFS pointers into DIR, laid out as in \
+                     `.git/lfs/objects`.",
+                ),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let mut refs = Vec::new();
+    match matches.values_of("BRANCH") {
+        Some(branches) => {
+            for branch in branches {
+                let commit_hash = match repository.refs.branches.get(branch) {
+                    Some(&commit_hash) => commit_hash,
+                    None => bail!("no such branch `{}`", branch),
+                };
+                refs.push((format!("refs/heads/{}", branch), commit_hash));
+            }
+        }
+        None => {
+            for (branch, &commit_hash) in &repository.refs.branches {
+                refs.push((format!("refs/heads/{}", branch), commit_hash));
+            }
+            refs.sort();
+        }
+    }
+
+    if refs.is_empty() {
+        bail!("nothing to export; there are no branches yet");
+    }
+
+    let mut options = FastImportOptions::default();
+    if matches.is_present("inline-limit") {
+        options.inline_limit = value_t!(matches.value_of("inline-limit"), u64)?;
+    }
+    if matches.is_present("lfs-threshold") {
+        options.lfs_threshold = Some(value_t!(matches.value_of("lfs-threshold"), u64)?);
+    }
+    options.lfs_objects = matches.value_of("lfs-objects").map(PathBuf::from);
+
+    let writer: Box<Write + Send> = match matches.value_of("output") {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    let exported = {
+        let ctx = repository.local(())?;
+        let (mut writer, exported) = export::to_fast_import(ctx.store(), refs, options, writer)
+            .wait()?;
+        writer.write_all(b"done\n")?;
+        writer.flush()?;
+        ctx.close().wait()?;
+
+        exported
+    };
+
+    eprintln!("Exported {} commits.", exported.commits);
+    if exported.lfs_files > 0 {
+        eprintln!("Wrote {} files as LFS pointers.", exported.lfs_files);
+    }
+
+    Ok(())
+}
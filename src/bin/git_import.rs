@@ -0,0 +1,158 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("git-import")
+        .about(
+            "Import the history of a git repository, creating a branch for each of its branches.",
+        )
+        .arg(
+            Arg::with_name("GIT_REPOSITORY")
+                .index(1)
+                .required(true)
+                .help("The git repository to import, bare or not."),
+        )
+        .arg(
+            Arg::with_name("branch")
+                .short("b")
+                .long("branch")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Import only the given git branch. May be given more than once."),
+        )
+        .arg(
+            Arg::with_name("map")
+                .long("map")
+                .takes_value(true)
+                .value_name("FILE")
+                .help(
+                    "Keep the map from git commits to the commits made of them in FILE, as \
+                     `<git commit> <commit>` lines. Commits already in FILE are not imported \
+                     again, so later imports only convert what is new.",
+                ),
+        )
+        .arg(Arg::with_name("force").short("f").long("force").help(
+            "Move branches which already exist to the imported commits, rather than skipping them.",
+        ))
+}
+
+
+#[cfg(feature = "git")]
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use futures::prelude::*;
+    use git2::{self, BranchType};
+
+    use attaca::git::{self, Importer};
+    use attaca::repository::Head;
+
+    let git = git2::Repository::open(matches.value_of("GIT_REPOSITORY").unwrap())?;
+
+    let mut tips = Vec::new();
+    match matches.values_of("branch") {
+        Some(names) => {
+            for name in names {
+                let branch = git.find_branch(name, BranchType::Local)?;
+                match branch.get().target() {
+                    Some(oid) => tips.push((name.to_owned(), oid)),
+                    None => bail!("the git branch `{}` is not a commit", name),
+                }
+            }
+        }
+        None => {
+            for branch_res in git.branches(Some(BranchType::Local))? {
+                let (branch, _) = branch_res?;
+                if let (Some(name), Some(oid)) = (branch.name()?, branch.get().target()) {
+                    tips.push((name.to_owned(), oid));
+                }
+            }
+        }
+    }
+
+    let map_path = matches.value_of("map").map(Path::new);
+    let known = match map_path {
+        Some(map_path) if map_path.is_file() => git::read_map(map_path)?,
+        _ => HashMap::new(),
+    };
+    let known_count = known.len();
+
+    let chunkers = repository.chunkers.clone();
+    let (commits, imported, missing_lfs_objects) = {
+        let ctx = repository.local(())?;
+        let mut importer = Importer::new(&git, ctx.marshaller(), chunkers).with_commits(known);
+
+        let mut imported = Vec::new();
+        for (name, oid) in tips {
+            let commit_hash = importer.import(oid)?;
+            imported.push((name, commit_hash));
+        }
+
+        let commits = importer.commits().clone();
+        let missing_lfs_objects = importer.missing_lfs_objects();
+
+        // The importer holds on to a marshaller, which must be gone before the context closes.
+        drop(importer);
+        ctx.close().wait()?;
+
+        (commits, imported, missing_lfs_objects)
+    };
+
+    eprintln!("Imported {} commits.", commits.len() - known_count);
+    if missing_lfs_objects > 0 {
+        eprintln!(
+            "Warning: {} LFS objects were missing, and their pointer files were imported \
+             instead. Run `git lfs fetch --all` in the git repository before importing to \
+             bring them in.",
+            missing_lfs_objects
+        );
+    }
+
+    if let Some(map_path) = map_path {
+        git::write_map(map_path, &commits)?;
+    }
+
+    for (name, commit_hash) in imported {
+        let existing = repository.refs.branches.get(&name).cloned();
+        match existing {
+            Some(existing) if existing == commit_hash => {}
+            Some(existing) if !matches.is_present("force") => {
+                eprintln!(
+                    "Warning: not moving branch {}, which is at {} rather than {}; pass \
+                     `--force` to move it.",
+                    name,
+                    existing,
+                    commit_hash
+                );
+                continue;
+            }
+            _ => repository.compare_and_swap_branch(&name, existing, commit_hash)?,
+        }
+
+        println!("{} {}", commit_hash, name);
+    }
+
+    // A fresh repository follows the branch git was on.
+    if let Head::Root = repository.refs.head {
+        let git_head = git.head().ok().and_then(|head| head.shorthand().map(ToOwned::to_owned));
+        if let Some(branch) = git_head {
+            if repository.refs.branches.contains_key(&branch) {
+                repository.refs.head = Head::LocalRef(branch);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(not(feature = "git"))]
+pub fn go(_repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
+    bail!(::attaca::ErrorKind::BackendNotCompiled("git".to_owned(), "git".to_owned()))
+}
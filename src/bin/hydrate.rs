@@ -0,0 +1,116 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::fs;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+use futures::stream;
+use globset::{Glob, GlobSetBuilder};
+
+use attaca::checkout;
+use attaca::evict;
+use attaca::repository::Repository;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("hydrate")
+        .about(
+            "Fetch the contents of placeholder files written by a lazy checkout.",
+        )
+        .arg(Arg::with_name("PATH").index(1).multiple(true).help(
+            "Patterns matching the placeholders to hydrate. If none are given, every \
+             placeholder is hydrated.",
+        ))
+        .arg(
+            Arg::with_name("open-files")
+                .short("j")
+                .long("open-files")
+                .takes_value(true)
+                .value_name("N")
+                .help("The maximum number of files to write in parallel."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let pattern_opt = if let Some(paths) = matches.values_of("PATH") {
+        let mut builder = GlobSetBuilder::new();
+        for path in paths {
+            builder.add(Glob::new(path)?);
+        }
+        Some(builder.build()?)
+    } else {
+        None
+    };
+
+    let open_files = if matches.is_present("open-files") {
+        value_t!(matches.value_of("open-files"), usize)?
+    } else {
+        checkout::CheckoutOptions::default().open_files
+    };
+    let chunk_workers = checkout::CheckoutOptions::default().chunk_workers;
+
+    let selected = repository
+        .index
+        .placeholders()
+        .iter()
+        .filter(|&(path, _, _)| {
+            pattern_opt.as_ref().map_or(true, |pattern| pattern.is_match(path))
+        })
+        .map(|(path, object_hash, _)| (path.to_owned(), object_hash))
+        .collect::<Vec<_>>();
+
+    {
+        let ctx = repository.local(())?;
+        let writes = selected
+            .iter()
+            .map(|&(ref path, object_hash)| match ctx.filters.for_path(path) {
+                Some(filter) => {
+                    checkout::write_file_filtered(
+                        ctx.store(),
+                        ctx.paths.base.join(path),
+                        object_hash,
+                        filter,
+                    )
+                }
+                None => {
+                    checkout::write_file_parallel(
+                        ctx.store(),
+                        ctx.paths.base.join(path),
+                        object_hash,
+                        chunk_workers,
+                    )
+                }
+            })
+            .collect::<Vec<_>>();
+
+        stream::iter_ok(writes)
+            .buffer_unordered(cmp::max(open_files, 1))
+            .for_each(|()| Ok(()))
+            .wait()
+            .chain_err(|| "While trying to hydrate placeholders")?;
+
+        ctx.close().wait()?;
+    }
+
+    for &(ref path, object_hash) in &selected {
+        let metadata = fs::symlink_metadata(repository.paths.base.join(path))?;
+        repository.index.placeholders_mut().remove(path);
+        repository.index.materialized_mut().insert(path, object_hash, &metadata);
+    }
+
+    // Make room for the files just hydrated by evicting others, if limits are configured.
+    let hydrated = selected.into_iter().map(|(path, _)| path).collect::<HashSet<_>>();
+    let evicted = evict::enforce(repository, &hydrated)?;
+    if !evicted.paths.is_empty() {
+        println!(
+            "Evicted {} hydrated files ({} bytes) to stay within the configured limits.",
+            evicted.paths.len(),
+            evicted.bytes
+        );
+    }
+
+    Ok(())
+}
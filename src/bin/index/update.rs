@@ -1,6 +1,7 @@
 use clap::{App, SubCommand, ArgMatches};
 
 use attaca::Repository;
+use attaca::daemon;
 
 use errors::*;
 
@@ -11,7 +12,7 @@ pub fn command() -> App<'static, 'static> {
 
 
 pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
-    repository.index.update()?;
+    daemon::update_index(repository)?;
 
     Ok(())
 }
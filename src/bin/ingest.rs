@@ -0,0 +1,135 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::ingest::{self, IngestOptions, Snapshot};
+use attaca::repository::Head;
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Commit each of a series of directories, such as nightly backup snapshots, in the order given. Each
+directory becomes a commit whose parent is the commit of the directory before it, and the first is
+committed on top of the HEAD. The branch is only moved once every directory has been committed.
+
+Ingesting many directories at once is much cheaper than committing them one by one: hard links to
+files already ingested are never read again, and each distinct chunk is only sent to the store
+once, however many directories hold it.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("ingest")
+        .about("Commit a series of similar directories, one commit each, sharing the work.")
+        .after_help(HELP_STR)
+        .arg(
+            Arg::with_name("DIR")
+                .index(1)
+                .required(true)
+                .multiple(true)
+                .help("The directories to commit, oldest first."),
+        )
+        .arg(
+            Arg::with_name("message")
+                .short("m")
+                .long("message")
+                .takes_value(true)
+                .help(
+                    "The message of each commit, with `{}` replaced by the directory it was made \
+                     of. Defaults to `Snapshot {}`.",
+                ),
+        )
+        .arg(
+            Arg::with_name("trust-mtimes")
+                .long("trust-mtimes")
+                .help(
+                    "Take files with the same size and mtime as the same path in the previous \
+                     directory to be unchanged, without reading them.",
+                ),
+        )
+        .arg(
+            Arg::with_name("mtime-timestamps")
+                .long("mtime-timestamps")
+                .help("Timestamp each commit with the mtime of its directory, rather than now."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let template = matches.value_of("message").unwrap_or("Snapshot {}");
+    let mtime_timestamps = matches.is_present("mtime-timestamps");
+
+    let mut snapshots = Vec::new();
+    for dir in matches.values_of("DIR").unwrap() {
+        let timestamp = if mtime_timestamps {
+            let metadata = fs::metadata(dir)?;
+            Utc.timestamp(metadata.mtime(), metadata.mtime_nsec() as u32)
+        } else {
+            Utc::now()
+        };
+
+        snapshots.push(Snapshot {
+            path: PathBuf::from(dir),
+            message: template.replace("{}", dir),
+            timestamp,
+        });
+    }
+
+    let options = IngestOptions {
+        trust_mtimes: matches.is_present("trust-mtimes"),
+        metadata: repository.config.metadata,
+        author: repository.config.author(),
+        committer: repository.config.committer(),
+    };
+    let chunkers = repository.chunkers.clone();
+    let head_opt = repository.refs.head();
+
+    let ingested = {
+        let ctx = repository.local(())?;
+        let marshaller = ctx.deduplicating_marshaller();
+        let ingested = ingest::ingest(&marshaller, &chunkers, &options, head_opt, snapshots)
+            .wait()?;
+
+        // The marshaller must be dropped for the context to finish writing.
+        drop(marshaller);
+        ctx.close().wait()?;
+
+        ingested
+    };
+
+    for (dir, snapshot) in matches.values_of("DIR").unwrap().zip(&ingested) {
+        println!(
+            "{} {} ({} files, {} bytes; {} files, {} bytes not read again)",
+            snapshot.commit,
+            dir,
+            snapshot.files,
+            snapshot.bytes,
+            snapshot.reused_files,
+            snapshot.reused_bytes
+        );
+    }
+
+    let commit_hash = match ingested.last() {
+        Some(snapshot) => snapshot.commit,
+        None => return Ok(()),
+    };
+
+    // Committing on a branch advances it; otherwise, the HEAD is detached at the last commit.
+    let branch_opt = match repository.refs.head {
+        Head::LocalRef(ref branch) => Some(branch.clone()),
+        _ => None,
+    };
+
+    match branch_opt {
+        Some(ref branch) => repository.compare_and_swap_branch(branch, head_opt, commit_hash)?,
+        None => repository.refs.head = Head::Detached(commit_hash),
+    }
+
+    Ok(())
+}
@@ -5,7 +5,7 @@ use clap::{App, Arg, SubCommand, ArgMatches};
 
 use errors::*;
 
-use attaca::repository::Repository;
+use attaca::repository;
 
 
 pub fn command() -> App<'static, 'static> {
@@ -27,9 +27,9 @@ pub fn go(matches: &ArgMatches) -> Result<()> {
         wd,
     );
 
-    let repository = Repository::init(path);
+    let paths = repository::init(path)?;
 
-    println!("Initialized repository: {:?}", repository);
+    println!("Initialized repository in {}", paths.metadata.display());
 
     Ok(())
 }
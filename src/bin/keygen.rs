@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::marshal::sealed::EncryptionKey;
+use attaca::sign::SigningKey;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("keygen")
+        .about(
+            "Generate an Ed25519 key to sign new commits with, and trust commits signed by it.",
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("PATH")
+                .help(
+                    "Where to write the key, relative to the repository root. Defaults to \
+                     `.attaca/signing.key`, or `.attaca/encryption.key` with --encryption.",
+                ),
+        )
+        .arg(Arg::with_name("force").long("force").help(
+            "Overwrite an existing key file.",
+        ))
+        .arg(Arg::with_name("encryption").long("encryption").help(
+            "Generate a key to seal objects sent to remotes with instead, to be given to \
+             `remote add --encryption-key`. Everyone sharing a remote must use the same one.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let default_path = if matches.is_present("encryption") {
+        ".attaca/encryption.key"
+    } else {
+        ".attaca/signing.key"
+    };
+    let key_path = matches
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(default_path));
+    let absolute_path = repository.paths.base.join(&key_path);
+
+    if absolute_path.exists() && !matches.is_present("force") {
+        bail!(
+            "a key already exists at {}; use --force to overwrite it",
+            absolute_path.display()
+        );
+    }
+
+    if matches.is_present("encryption") {
+        EncryptionKey::generate()?.save(&absolute_path)?;
+        println!("Wrote an encryption key to {}.", key_path.display());
+
+        return Ok(());
+    }
+
+    let key = SigningKey::generate()?;
+    key.save(&absolute_path)?;
+
+    let public_key = key.public_key();
+    if !repository.config.trusted_keys.contains(&public_key.0) {
+        repository.config.trusted_keys.push(public_key.0.clone());
+    }
+    repository.config.signing_key = Some(key_path);
+
+    println!("{}", public_key);
+
+    Ok(())
+}
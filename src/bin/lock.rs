@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::path_locks;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("lock")
+        .about(
+            "Lock paths, so that pushes changing them are refused unless made by you. Locks are \
+             listed if no path is given.",
+        )
+        .arg(Arg::with_name("PATH").index(1).multiple(true).help(
+            "The paths to lock, relative to the root of the repository.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let paths = match matches.values_of("PATH") {
+        Some(values) => values.map(PathBuf::from).collect::<Vec<_>>(),
+        None => {
+            for (path, lock) in path_locks::get(repository)?.paths {
+                println!(
+                    "{}\t{}\t{}",
+                    path.display(),
+                    lock.holder,
+                    lock.locked_at.to_rfc3339()
+                );
+            }
+
+            return Ok(());
+        }
+    };
+
+    path_locks::lock(repository, &paths)?;
+    println!(
+        "Locked {} paths. Push `{}` to share the locks.",
+        paths.len(),
+        path_locks::LOCKS_BRANCH
+    );
+
+    Ok(())
+}
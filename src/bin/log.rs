@@ -2,11 +2,14 @@ use std::cmp::Ordering;
 use std::collections::{BinaryHeap, HashSet};
 use std::fmt::Write;
 
-use clap::{App, ArgMatches, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use futures::prelude::*;
 use futures::stream;
 
+use attaca::daemon::DaemonClient;
 use attaca::marshal::{CommitObject, ObjectHash};
+use attaca::search::{self, SearchIndex};
+use attaca::sign::{self, Verification};
 use attaca::Repository;
 
 use errors::*;
@@ -41,12 +44,111 @@ impl Ord for TimeOrdered {
 
 
 pub fn command() -> App<'static, 'static> {
-    SubCommand::with_name("log").about("View repository commit history.")
+    SubCommand::with_name("log")
+        .about("View repository commit history.")
+        .arg(Arg::with_name("verify").long("verify").help(
+            "Fail unless every commit carries a valid signature by a trusted key.",
+        ))
+        .arg(
+            Arg::with_name("grep")
+                .long("grep")
+                .takes_value(true)
+                .value_name("PATTERN")
+                .help(
+                    "Only show commits whose messages contain PATTERN, ignoring case. Uses the \
+                     search index, if it is enabled.",
+                ),
+        )
+        .arg(Arg::with_name("porcelain").long("porcelain").help(
+            "Print one tab-separated line per commit, newest first: its hash, its parents' \
+             hashes separated by spaces, its RFC 3339 timestamp, its author (empty if none), and \
+             the first line of its message.",
+        ))
 }
 
 
-pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
-    let mut commits = {
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commits = match matches.value_of("grep") {
+        Some(pattern) => grep(repository, pattern)?,
+        None => {
+            match DaemonClient::connect(&repository.paths) {
+                Some(mut client) => client.log()?,
+                None => read_history(repository)?,
+            }
+        }
+    };
+
+    let verify = matches.is_present("verify");
+    let porcelain = matches.is_present("porcelain");
+    let mut buf = String::new();
+
+    for (i, (hash, commit)) in commits.into_iter().enumerate() {
+        let (trusted, signature) = match sign::verify(&commit)? {
+            Verification::Unsigned => (false, None),
+            Verification::Valid(ref key) if repository.config.trusted_keys.contains(&key.0) => {
+                (true, Some(format!("good, by {}", key)))
+            }
+            Verification::Valid(key) => (false, Some(format!("good, by untrusted key {}", key))),
+            Verification::Invalid => (false, Some("BAD".to_owned())),
+        };
+
+        if porcelain {
+            if verify && !trusted {
+                print!("{}", buf);
+                bail!(ErrorKind::UntrustedCommit(hash));
+            }
+
+            let parents = commit
+                .parents
+                .iter()
+                .map(ObjectHash::to_string)
+                .collect::<Vec<_>>();
+            write!(
+                buf,
+                "{}\t{}\t{}\t{}\t{}\n",
+                hash,
+                parents.join(" "),
+                commit.timestamp.to_rfc3339(),
+                commit.author.as_ref().map(ToString::to_string).unwrap_or_default(),
+                commit.message.lines().next().unwrap_or("")
+            )?;
+
+            continue;
+        }
+
+        if i > 0 {
+            buf.push('\n');
+        }
+
+        write!(buf, "commit {}\n", hash)?;
+        if let Some(signature) = signature {
+            write!(buf, "Signature: {}\n", signature)?;
+        }
+
+        if verify && !trusted {
+            print!("{}", buf);
+            bail!(ErrorKind::UntrustedCommit(hash));
+        }
+
+        if let Some(ref author) = commit.author {
+            write!(buf, "Author: {}\n", author)?;
+        }
+        if commit.committer.is_some() && commit.committer != commit.author {
+            write!(buf, "Commit: {}\n", commit.committer.as_ref().unwrap())?;
+        }
+
+        write!(buf, "Date: {}\n\t{}\n", commit.timestamp, commit.message)?;
+    }
+
+    print!("{}", buf);
+
+    Ok(())
+}
+
+
+/// Read every commit reachable from the HEAD, newest first.
+fn read_history(repository: &mut Repository) -> Result<Vec<(ObjectHash, CommitObject)>> {
+    let commits = {
         let ctx = repository.local(())?;
 
         let mut commits = BinaryHeap::new();
@@ -63,7 +165,7 @@ pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
 
             commit_stream
                 .for_each(|(hash, commit)| {
-                    hashes.extend(commit.parents.iter().cloned());
+                    hashes.extend(ctx.shallow.parents(&hash, &commit).iter().cloned());
                     commits.push(TimeOrdered { hash, commit });
 
                     Ok(())
@@ -76,29 +178,44 @@ pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
         commits.into_sorted_vec()
     };
 
-    let mut buf = String::new();
+    let history = commits
+        .into_iter()
+        .rev()
+        .map(|TimeOrdered { hash, commit }| (hash, commit))
+        .collect();
 
-    if let Some(TimeOrdered { hash, commit }) = commits.pop() {
-        write!(
-            buf,
-            "commit {} \nDate: {}\n\t{}\n",
-            hash,
-            commit.timestamp,
-            commit.message
-        )?;
-    }
+    Ok(history)
+}
 
-    for TimeOrdered { hash, commit } in commits.into_iter().rev() {
-        write!(
-            buf,
-            "\ncommit {}\nDate: {}\n\t{}\n",
-            hash,
-            commit.timestamp,
-            commit.message
-        )?;
+
+/// Read every commit reachable from the HEAD whose message contains `pattern`, newest first.
+/// Only the matching commits are read if the search index covers the HEAD.
+fn grep(repository: &mut Repository, pattern: &str) -> Result<Vec<(ObjectHash, CommitObject)>> {
+    let head = match repository.refs.head() {
+        Some(head) => head,
+        None => return Ok(Vec::new()),
+    };
+
+    if search::refresh(repository, &[])?.is_some() {
+        let index = SearchIndex::open(&repository.paths)?;
+
+        if let Some(hashes) = index.grep(head, pattern) {
+            let ctx = repository.local(())?;
+            let mut commits = Vec::new();
+            for hash in hashes {
+                commits.push((hash, ctx.read_commit(hash).wait()?));
+            }
+            ctx.close().wait()?;
+
+            return Ok(commits);
+        }
     }
 
-    print!("{}", buf);
+    let pattern = pattern.to_lowercase();
+    let commits = read_history(repository)?
+        .into_iter()
+        .filter(|&(_, ref commit)| commit.message.to_lowercase().contains(&pattern))
+        .collect();
 
-    Ok(())
+    Ok(commits)
 }
@@ -6,35 +6,88 @@ extern crate clap;
 extern crate error_chain;
 extern crate futures;
 extern crate futures_cpupool;
+#[cfg(feature = "git")]
+extern crate git2;
 extern crate globset;
 extern crate histogram;
 extern crate indicatif;
 extern crate itertools;
 extern crate memmap;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate sha3;
 
+mod archive;
+mod backup;
+mod bench;
+mod bisect;
+mod blame;
+mod branch;
+mod cache_daemon;
+mod cat_file;
 mod catalog;
 mod checkout;
 mod commit;
+mod daemon;
 mod debug;
+mod dedup;
+mod diff;
+mod doctor;
+mod du;
 mod errors;
+mod evict;
+mod fetch;
+mod find;
 mod fsck;
+mod git_export;
+mod git_import;
+mod hydrate;
 mod index;
+mod ingest;
 mod init;
+mod keygen;
+mod lock;
 mod log;
+mod maintenance;
+mod merge;
+mod mirror_pull;
+mod notes;
+mod publish;
+mod publish_dir;
+mod pull;
+mod push;
+mod rebase;
+mod recover;
+mod refs_to;
 mod remote;
+mod repair;
+mod stash;
+mod stats;
 mod status;
+mod subrepo;
+mod subtree;
+mod sync_to;
+mod tag;
 mod test;
 mod trace;
 mod track;
+mod transfer;
+mod unlock;
 mod untrack;
+mod whoami;
+mod worktree;
 
 use std::env;
 use std::ffi::OsString;
+use std::time::Instant;
 
-use clap::{App, ArgMatches};
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches};
 
 use attaca::Repository;
+use attaca::{profile, progress, telemetry, warning};
 
 use errors::*;
 
@@ -47,58 +100,210 @@ fn command() -> App<'static, 'static> {
         .author(crate_authors!("\n"))
         .about(crate_description!())
         .version(crate_version!())
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("FILE")
+                .help(
+                    "Record how long each store operation and each bit of hashing took into FILE, \
+                     as a Chrome trace which chrome://tracing or Perfetto can open.",
+                ),
+        )
+        .subcommand(archive::command())
+        .subcommand(backup::command())
+        .subcommand(bench::command())
+        .subcommand(bisect::command())
+        .subcommand(blame::command())
+        .subcommand(branch::command())
+        .subcommand(cache_daemon::command())
+        .subcommand(cat_file::command())
         .subcommand(catalog::command())
         .subcommand(checkout::command())
         .subcommand(commit::command())
+        .subcommand(daemon::command())
         .subcommand(debug::command())
+        .subcommand(dedup::command())
+        .subcommand(diff::command())
+        .subcommand(doctor::command())
+        .subcommand(du::command())
+        .subcommand(evict::command())
+        .subcommand(fetch::command())
+        .subcommand(find::command())
         .subcommand(fsck::command())
+        .subcommand(git_export::command())
+        .subcommand(git_import::command())
+        .subcommand(hydrate::command())
+        .subcommand(lock::command())
         .subcommand(log::command())
+        .subcommand(maintenance::command())
+        .subcommand(merge::command())
         .subcommand(index::command())
+        .subcommand(ingest::command())
         .subcommand(init::command())
+        .subcommand(keygen::command())
+        .subcommand(mirror_pull::command())
+        .subcommand(notes::command())
+        .subcommand(publish::command())
+        .subcommand(publish_dir::command())
+        .subcommand(pull::command())
+        .subcommand(push::command())
+        .subcommand(rebase::command())
+        .subcommand(recover::command())
+        .subcommand(refs_to::command())
         .subcommand(remote::command())
+        .subcommand(repair::command())
+        .subcommand(stash::command())
+        .subcommand(stats::command())
         .subcommand(status::command())
+        .subcommand(subrepo::command())
+        .subcommand(subtree::command())
+        .subcommand(sync_to::command())
+        .subcommand(tag::command())
         .subcommand(test::command())
         .subcommand(track::command())
+        .subcommand(unlock::command())
         .subcommand(untrack::command())
+        .subcommand(whoami::command())
+        .subcommand(worktree::command())
 }
 
 
 fn go(matches: &ArgMatches) -> Result<()> {
     match matches.subcommand() {
         // First match commands which don't need a loaded repository.
+        ("bench", Some(sub_m)) => bench::go(sub_m),
+        ("cache-daemon", Some(sub_m)) => cache_daemon::go(sub_m),
+        ("daemon", Some(sub_m)) => daemon::go(sub_m),
         ("init", Some(sub_m)) => init::go(sub_m),
+        ("recover", Some(sub_m)) => recover::go(sub_m),
 
         // Other commands need a repository to act on.
         other => {
-            let mut repository = Repository::load(env::current_dir()?)?;
+            let mut repository = Repository::find(env::current_dir()?)?;
+            let started = Utc::now();
+            let timer = Instant::now();
+
+            if let Err(error) = progress::install(&command_name(matches)) {
+                eprintln!("Warning: could not report progress: {}", error);
+            }
 
             let result = match other {
+                ("archive", Some(sub_m)) => archive::go(&mut repository, sub_m),
+                ("backup", Some(sub_m)) => backup::go(&mut repository, sub_m),
+                ("bisect", Some(sub_m)) => bisect::go(&mut repository, sub_m),
+                ("blame", Some(sub_m)) => blame::go(&mut repository, sub_m),
+                ("branch", Some(sub_m)) => branch::go(&mut repository, sub_m),
+                ("cat-file", Some(sub_m)) => cat_file::go(&mut repository, sub_m),
                 ("catalog", Some(sub_m)) => catalog::go(&mut repository, sub_m),
                 ("checkout", Some(sub_m)) => checkout::go(&mut repository, sub_m),
                 ("commit", Some(sub_m)) => commit::go(&mut repository, sub_m),
                 ("debug", Some(sub_m)) => debug::go(&mut repository, sub_m),
+                ("dedup", Some(sub_m)) => dedup::go(&mut repository, sub_m),
+                ("diff", Some(sub_m)) => diff::go(&mut repository, sub_m),
+                ("doctor", Some(sub_m)) => doctor::go(&mut repository, sub_m),
+                ("du", Some(sub_m)) => du::go(&mut repository, sub_m),
+                ("evict", Some(sub_m)) => evict::go(&mut repository, sub_m),
+                ("fetch", Some(sub_m)) => fetch::go(&mut repository, sub_m),
+                ("find", Some(sub_m)) => find::go(&mut repository, sub_m),
                 ("fsck", Some(sub_m)) => fsck::go(&mut repository, sub_m),
+                ("git-export", Some(sub_m)) => git_export::go(&mut repository, sub_m),
+                ("git-import", Some(sub_m)) => git_import::go(&mut repository, sub_m),
+                ("hydrate", Some(sub_m)) => hydrate::go(&mut repository, sub_m),
+                ("lock", Some(sub_m)) => lock::go(&mut repository, sub_m),
                 ("log", Some(sub_m)) => log::go(&mut repository, sub_m),
+                ("maintenance", Some(sub_m)) => maintenance::go(&mut repository, sub_m),
+                ("merge", Some(sub_m)) => merge::go(&mut repository, sub_m),
                 ("index", Some(sub_m)) => index::go(&mut repository, sub_m),
+                ("ingest", Some(sub_m)) => ingest::go(&mut repository, sub_m),
+                ("keygen", Some(sub_m)) => keygen::go(&mut repository, sub_m),
+                ("mirror-pull", Some(sub_m)) => mirror_pull::go(&mut repository, sub_m),
+                ("notes", Some(sub_m)) => notes::go(&mut repository, sub_m),
+                ("publish", Some(sub_m)) => publish::go(&mut repository, sub_m),
+                ("publish-dir", Some(sub_m)) => publish_dir::go(&mut repository, sub_m),
+                ("pull", Some(sub_m)) => pull::go(&mut repository, sub_m),
+                ("push", Some(sub_m)) => push::go(&mut repository, sub_m),
+                ("rebase", Some(sub_m)) => rebase::go(&mut repository, sub_m),
+                ("refs-to", Some(sub_m)) => refs_to::go(&mut repository, sub_m),
                 ("remote", Some(sub_m)) => remote::go(&mut repository, sub_m),
+                ("repair", Some(sub_m)) => repair::go(&mut repository, sub_m),
+                ("stash", Some(sub_m)) => stash::go(&mut repository, sub_m),
+                ("stats", Some(sub_m)) => stats::go(&mut repository, sub_m),
                 ("status", Some(sub_m)) => status::go(&mut repository, sub_m),
+                ("subrepo", Some(sub_m)) => subrepo::go(&mut repository, sub_m),
+                ("subtree", Some(sub_m)) => subtree::go(&mut repository, sub_m),
+                ("sync-to", Some(sub_m)) => sync_to::go(&mut repository, sub_m),
+                ("tag", Some(sub_m)) => tag::go(&mut repository, sub_m),
                 ("test", Some(sub_m)) => test::go(&mut repository, sub_m),
+                ("unlock", Some(sub_m)) => unlock::go(&mut repository, sub_m),
                 ("untrack", Some(sub_m)) => untrack::go(&mut repository, sub_m),
                 ("track", Some(sub_m)) => track::go(&mut repository, sub_m),
+                ("whoami", Some(sub_m)) => whoami::go(&mut repository, sub_m),
+                ("worktree", Some(sub_m)) => worktree::go(&mut repository, sub_m),
                 _ => Err(Error::from_kind(ErrorKind::InvalidUsage)),
             };
 
-            repository.cleanup()?;
+            if repository.config.telemetry {
+                let elapsed = timer.elapsed();
+                let event = telemetry::Event {
+                    command: command_name(matches),
+                    started,
+                    duration_ms: elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64,
+                    measurements: telemetry::COUNTERS.snapshot(),
+                    error: result.as_ref().err().map(|error| error.to_string()),
+                };
+
+                if let Err(error) = telemetry::record(&repository.paths, &event) {
+                    eprintln!("Warning: could not record telemetry: {}", error);
+                }
+            }
+
+            let cleanup = repository.cleanup();
+
+            if let Some(reporter) = progress::installed() {
+                let error = result.as_ref().err().or(cleanup.as_ref().err());
+                reporter.finish(error.map(|error| error.to_string()));
+            }
+
+            cleanup?;
             result
         }
     }
 }
 
 
+/// The full name of the subcommand being run, including any nested subcommands.
+fn command_name(matches: &ArgMatches) -> String {
+    let mut names = Vec::new();
+    let mut current = matches;
+
+    while let (name, Some(sub_m)) = current.subcommand() {
+        names.push(name);
+        current = sub_m;
+    }
+
+    names.join(" ")
+}
+
+
 fn run() -> Result<()> {
+    warning::set_handler(|warning| eprintln!("Warning: {}", warning));
+
     let matches = command().get_matches();
+
+    let profile_path = matches.value_of("profile");
+    if profile_path.is_some() {
+        profile::enable();
+    }
+
     let result = go(&matches);
 
+    if let Some(profile_path) = profile_path {
+        if let Err(error) = profile::write(profile_path) {
+            eprintln!("Warning: could not write the profile: {}", error);
+        }
+    }
+
     if let Err(Error(ErrorKind::InvalidUsage, _)) = result {
         eprintln!("Invalid usage:\n{}", matches.usage());
     }
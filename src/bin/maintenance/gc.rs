@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use clap::{App, SubCommand, Arg, ArgMatches};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::refcount;
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Delete objects from the local store which nothing refers to: no other object, ref, stash or index
+entry. Requires `refcounts = true` in config.toml; objects written before it was set are only
+counted once the counts are rebuilt with `--rebuild`, and nothing is deleted until then.
+
+Each run deletes at most `--max-objects` objects, so that it can be run often and never takes
+long. Objects are only deleted once they have been unreferenced for the grace period, which should
+be longer than any commit or fetch takes; with a grace period of zero, which is only safe while
+nothing else uses the repository, everything collectible is collected in a single run.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("gc")
+        .about("Incrementally collect garbage from the local store, using reference counts.")
+        .after_help(HELP_STR)
+        .arg(Arg::with_name("rebuild").long("rebuild").help(
+            "Recount the references of every object in the local store first.",
+        ))
+        .arg(
+            Arg::with_name("max-objects")
+                .long("max-objects")
+                .takes_value(true)
+                .value_name("N")
+                .help("The most objects to delete in this run. Defaults to 10000."),
+        )
+        .arg(
+            Arg::with_name("grace-hours")
+                .long("grace-hours")
+                .takes_value(true)
+                .value_name("HOURS")
+                .help("How long an object must have been unreferenced for. Defaults to 24."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let refcounts = match repository.refcounts {
+        Some(ref refcounts) => refcounts.clone(),
+        None => bail!(::attaca::ErrorKind::RefcountsDisabled),
+    };
+
+    if matches.is_present("rebuild") {
+        refcounts.rebuild(&repository.paths)?;
+        refcounts.save()?;
+        eprintln!("Recounted references in the local store.");
+    }
+
+    let max_objects = if matches.is_present("max-objects") {
+        value_t!(matches.value_of("max-objects"), usize)?
+    } else {
+        10_000
+    };
+    let grace_hours = if matches.is_present("grace-hours") {
+        value_t!(matches.value_of("grace-hours"), u64)?
+    } else {
+        24
+    };
+
+    let roots = refcount::roots(repository)?;
+    let collected = {
+        let ctx = repository.local(())?;
+        let collected = refcount::collect(
+            ctx.store(),
+            &roots,
+            Duration::from_secs(grace_hours * 3600),
+            max_objects,
+        )?;
+        ctx.close().wait()?;
+
+        collected
+    };
+    refcounts.save()?;
+
+    if collected.incomplete {
+        println!(
+            "Deleted {} objects; more garbage is left for the next run.",
+            collected.deleted
+        );
+    } else {
+        println!("Deleted {} objects.", collected.deleted);
+    }
+
+    Ok(())
+}
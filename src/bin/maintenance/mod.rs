@@ -0,0 +1,27 @@
+use clap::{App, SubCommand, ArgMatches};
+
+use attaca::Repository;
+
+use errors::*;
+
+mod gc;
+mod repack;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("maintenance")
+        .about("Keep the local object store in shape.")
+        .subcommand(gc::command())
+        .subcommand(repack::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("gc", Some(sub_m)) => gc::go(repository, sub_m),
+        ("repack", Some(sub_m)) => repack::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
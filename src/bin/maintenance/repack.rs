@@ -0,0 +1,77 @@
+use clap::{App, SubCommand, Arg, ArgMatches};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::store::{RepackableStore, RepackOptions};
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Move every object in the local store into a single pack in .attaca/packs, compressed and stored as
+deltas against similar objects where that saves space, and then remove the loose copies and any
+older packs. Objects deleted by garbage collection since the last repack are left out. Reads of
+packed objects are slower than reads of loose ones, so this is best run once the objects in
+question are no longer being worked on.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("repack")
+        .about("Compact the local store into a compressed, delta-encoded pack.")
+        .after_help(HELP_STR)
+        .arg(
+            Arg::with_name("window")
+                .long("window")
+                .takes_value(true)
+                .value_name("N")
+                .help("How many objects to try each object as a delta against. Defaults to 10."),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .value_name("N")
+                .help("The longest chain of deltas to allow. Defaults to 16."),
+        )
+        .arg(
+            Arg::with_name("level")
+                .long("level")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .help("The zstd compression level. Defaults to 3."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let mut options = RepackOptions::default();
+    if matches.is_present("window") {
+        options.window = value_t!(matches.value_of("window"), usize)?;
+    }
+    if matches.is_present("depth") {
+        options.max_depth = value_t!(matches.value_of("depth"), usize)?;
+    }
+    if matches.is_present("level") {
+        options.level = value_t!(matches.value_of("level"), i32)?;
+    }
+
+    let repacked = {
+        let ctx = repository.local(())?;
+        let repacked = ctx.store().repack(&options)?;
+        ctx.close().wait()?;
+
+        repacked
+    };
+
+    println!(
+        "Packed {} objects ({} as deltas), removing {} loose objects and {} old packs.",
+        repacked.objects,
+        repacked.deltas,
+        repacked.loose_removed,
+        repacked.packs_removed
+    );
+    println!("{} bytes before, {} bytes after.", repacked.bytes_before, repacked.bytes_after);
+
+    Ok(())
+}
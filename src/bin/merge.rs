@@ -0,0 +1,458 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::checkout::{self, CheckoutOptions};
+use attaca::history::{self, merge};
+use attaca::history::conflict::{ConflictState, Operation, Resolution, Side};
+use attaca::history::merge::{Merge, Sides};
+use attaca::marshal::{CommitObject, Object, ObjectHash, SubtreeEntry};
+use attaca::repository::Head;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("merge")
+        .about(
+            "Merge a branch or commit into the HEAD, stopping at conflicting paths for them to be \
+             resolved one at a time.",
+        )
+        .arg(
+            Arg::with_name("REV")
+                .index(1)
+                .required_unless_one(&["list", "ours", "theirs", "resolved", "continue", "abort"])
+                .conflicts_with_all(&["list", "ours", "theirs", "resolved", "continue", "abort"])
+                .help("The branch or commit to merge into the HEAD."),
+        )
+        .arg(
+            Arg::with_name("message")
+                .short("m")
+                .long("message")
+                .takes_value(true)
+                .value_name("MESSAGE")
+                .requires("REV")
+                .help("The message of the merge commit."),
+        )
+        .arg(Arg::with_name("list").long("list").help(
+            "List the conflicting paths of the merge or rebase in progress, and how each was \
+             resolved.",
+        ))
+        .arg(
+            Arg::with_name("ours")
+                .long("ours")
+                .takes_value(true)
+                .value_name("PATH")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Resolve PATH by checking out the HEAD's version of it (when rebasing, the \
+                     version it is being rebased onto). May be given more than once.",
+                ),
+        )
+        .arg(
+            Arg::with_name("theirs")
+                .long("theirs")
+                .takes_value(true)
+                .value_name("PATH")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Resolve PATH by checking out the merged commit's version of it (when \
+                     rebasing, the replayed commit's). May be given more than once.",
+                ),
+        )
+        .arg(
+            Arg::with_name("resolved")
+                .long("resolved")
+                .takes_value(true)
+                .value_name("PATH")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Resolve PATH by keeping what is in the worktree, once it has been fixed up \
+                     by hand. May be given more than once.",
+                ),
+        )
+        .arg(
+            Arg::with_name("continue")
+                .long("continue")
+                .conflicts_with("abort")
+                .help("Commit the merge once every conflicting path has been resolved."),
+        )
+        .arg(Arg::with_name("abort").long("abort").help(
+            "Give up on the merge in progress, returning to the commit merged into.",
+        ))
+}
+
+
+fn check_out(repository: &mut Repository, subtree: ObjectHash) -> Result<()> {
+    let mut options = CheckoutOptions::default();
+    options.filter = repository.index.sparse().map(|sparse| sparse.globset().clone());
+
+    let ctx = repository.local(())?;
+    let base = ctx.paths.base.clone();
+    ctx.checkout(subtree, base, &options).wait()?;
+    ctx.close().wait()?;
+
+    Ok(())
+}
+
+
+fn check_out_commit(repository: &mut Repository, commit_hash: ObjectHash) -> Result<()> {
+    let subtree = {
+        let ctx = repository.local(())?;
+        let subtree = ctx.read_commit(commit_hash).wait()?.subtree;
+        ctx.close().wait()?;
+
+        subtree
+    };
+
+    check_out(repository, subtree)
+}
+
+
+/// Replace whatever is in the worktree at `path` with `entry`, or remove it if `entry` is `None`.
+fn write_side(
+    repository: &mut Repository,
+    path: &Path,
+    entry: Option<SubtreeEntry>,
+) -> Result<()> {
+    let full_path = repository.paths.base.join(path);
+
+    if let Ok(metadata) = full_path.symlink_metadata() {
+        if metadata.is_dir() {
+            fs::remove_dir_all(&full_path)?;
+        } else {
+            fs::remove_file(&full_path)?;
+        }
+    }
+
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let options = CheckoutOptions::default();
+    let ctx = repository.local(())?;
+    match entry.unannotated() {
+        SubtreeEntry::Subtree(subtree) => {
+            ctx.checkout(subtree, full_path, &options).wait()?;
+        }
+        SubtreeEntry::Subrepository(..) => {
+            bail!("{} is a subrepository, which cannot be checked out", path.display())
+        }
+        _ => {
+            checkout::write_entry(
+                ctx.store(),
+                full_path,
+                entry.clone(),
+                false,
+                options.chunk_workers,
+                ctx.filters.for_path(path),
+            ).wait()?;
+        }
+    }
+    ctx.close().wait()?;
+
+    Ok(())
+}
+
+
+/// Merge the trees of the commits `ours` and `theirs`, which have diverged since `base`.
+pub fn three_way(
+    repository: &mut Repository,
+    ours: ObjectHash,
+    theirs: ObjectHash,
+    base: Option<ObjectHash>,
+) -> Result<Merge> {
+    let version = repository.object_version;
+
+    let ctx = repository.local(())?;
+    let base_opt = match base {
+        Some(base) => Some(ctx.read_commit(base).wait()?.subtree),
+        None => None,
+    };
+    let our_subtree = ctx.read_commit(ours).wait()?.subtree;
+    let their_subtree = ctx.read_commit(theirs).wait()?.subtree;
+    let merged = merge::merge(
+        ctx.store(),
+        version,
+        &ctx.merge_drivers,
+        base_opt,
+        our_subtree,
+        their_subtree,
+    ).wait()?;
+    ctx.close().wait()?;
+
+    Ok(merged)
+}
+
+
+/// Write a commit of the cleanly merged tree `subtree` with the given parents.
+pub fn commit_merge(
+    repository: &mut Repository,
+    subtree: ObjectHash,
+    parents: Vec<ObjectHash>,
+    message: String,
+) -> Result<ObjectHash> {
+    let version = repository.object_version;
+    let commit = CommitObject {
+        subtree,
+        parents,
+        message,
+        timestamp: Utc::now(),
+        signature: None,
+        author: repository.config.author(),
+        committer: repository.config.committer(),
+    };
+
+    let ctx = repository.local(())?;
+    let commit_hash = merge::write(ctx.store(), Object::Commit(commit), version).wait()?;
+    ctx.close().wait()?;
+
+    Ok(commit_hash)
+}
+
+
+/// Move `branch` from `from` to `to`, or detach the HEAD at `to` if there is no branch.
+fn advance(
+    repository: &mut Repository,
+    branch: Option<&String>,
+    from: ObjectHash,
+    to: ObjectHash,
+) -> Result<()> {
+    match branch {
+        Some(branch) => repository.compare_and_swap_branch(branch, Some(from), to)?,
+        None => repository.refs.head = Head::Detached(to),
+    }
+
+    Ok(())
+}
+
+
+fn describe(sides: &Sides) -> &'static str {
+    match (&sides.base, &sides.ours, &sides.theirs) {
+        (&None, &Some(_), &Some(_)) => "added by both",
+        (_, &None, _) => "deleted by us",
+        (_, _, &None) => "deleted by them",
+        _ => "changed by both",
+    }
+}
+
+
+fn print_conflicts(state: &ConflictState) {
+    for (path, conflict) in &state.paths {
+        let resolution = match conflict.resolution {
+            None => "unresolved",
+            Some(Resolution::Accepted(Side::Ours)) => "ours",
+            Some(Resolution::Accepted(Side::Theirs)) => "theirs",
+            Some(Resolution::Worktree) => "resolved",
+        };
+
+        println!("\t{:<10} {} ({})", resolution, path.display(), describe(&conflict.sides));
+    }
+}
+
+
+/// Stop `operation` at the conflicts of `merged`: check out the merged tree, in which conflicting
+/// paths hold their version, and save the conflicts to be resolved.
+pub fn stop(repository: &mut Repository, operation: Operation, merged: &Merge) -> Result<()> {
+    check_out(repository, merged.subtree)?;
+
+    let state = ConflictState::new(operation, merged);
+    state.save(&repository.paths)?;
+
+    println!("Conflicting paths:");
+    print_conflicts(&state);
+
+    Ok(())
+}
+
+
+fn open(repository: &Repository) -> Result<ConflictState> {
+    match ConflictState::open(&repository.paths)? {
+        Some(state) => Ok(state),
+        None => bail!(::attaca::ErrorKind::NoConflicts),
+    }
+}
+
+
+fn start(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if ConflictState::open(&repository.paths)?.is_some() {
+        bail!(
+            "conflicts from an earlier merge or rebase are unresolved; see `attaca merge --list`"
+        );
+    }
+
+    let rev = matches.value_of("REV").unwrap();
+    let theirs = repository.refs.resolve(rev)?;
+    let (branch, ours) = match repository.refs.head {
+        Head::LocalRef(ref branch) => {
+            match repository.refs.branches.get(branch) {
+                Some(&ours) => (Some(branch.clone()), ours),
+                None => bail!("`{}` has no commits to merge into", branch),
+            }
+        }
+        Head::Detached(ours) => (None, ours),
+        _ => bail!("the HEAD is not on a local branch or a commit"),
+    };
+
+    let base = {
+        let shallow = repository.shallow.clone();
+        let ctx = repository.local(())?;
+        let base = history::merge_base(ctx.store(), ours, theirs, &shallow).wait()?;
+        ctx.close().wait()?;
+
+        base
+    };
+
+    if base == Some(theirs) {
+        println!("Already up to date with {}.", rev);
+        return Ok(());
+    }
+
+    if base == Some(ours) {
+        advance(repository, branch.as_ref(), ours, theirs)?;
+        check_out_commit(repository, theirs)?;
+        println!("Fast-forwarded to {}.", theirs);
+        return Ok(());
+    }
+
+    let message = match matches.value_of("message") {
+        Some(message) => message.to_owned(),
+        None => format!("Merge {} into {}", rev, branch.as_ref().map_or("HEAD", String::as_str)),
+    };
+
+    let merged = three_way(repository, ours, theirs, base)?;
+    if !merged.is_clean() {
+        let operation = Operation::Merge {
+            branch,
+            ours,
+            theirs,
+            message,
+        };
+        stop(repository, operation, &merged)?;
+        println!(
+            "Resolve them with --ours, --theirs or --resolved, and then run \
+             `attaca merge --continue`; or give up with `attaca merge --abort`."
+        );
+
+        return Ok(());
+    }
+
+    let commit_hash = commit_merge(repository, merged.subtree, vec![ours, theirs], message)?;
+    advance(repository, branch.as_ref(), ours, commit_hash)?;
+    check_out(repository, merged.subtree)?;
+    println!("Merged {} as {}.", rev, commit_hash);
+
+    Ok(())
+}
+
+
+fn resolve(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let mut state = open(repository)?;
+
+    let sides = [("ours", Side::Ours), ("theirs", Side::Theirs)];
+    for &(arg, side) in &sides {
+        for path in matches.values_of(arg).into_iter().flat_map(|paths| paths) {
+            let path = PathBuf::from(path);
+            let entry = state.accept(&path, side)?;
+            write_side(repository, &path, entry)?;
+            state.save(&repository.paths)?;
+        }
+    }
+
+    for path in matches.values_of("resolved").into_iter().flat_map(|paths| paths) {
+        state.mark_resolved(Path::new(path))?;
+    }
+    state.save(&repository.paths)?;
+
+    match state.unresolved().len() {
+        0 => println!("Every conflicting path has been resolved."),
+        n => println!("{} conflicting paths are left to resolve.", n),
+    }
+
+    Ok(())
+}
+
+
+fn resume(repository: &mut Repository) -> Result<()> {
+    let state = open(repository)?;
+    let (branch, ours, theirs, message) = match state.operation {
+        Operation::Merge {
+            ref branch,
+            ours,
+            theirs,
+            ref message,
+        } => (branch.clone(), ours, theirs, message.clone()),
+        Operation::Rebase => {
+            bail!("these conflicts are a rebase's; run `attaca rebase --continue`")
+        }
+    };
+
+    state.check_resolved()?;
+    repository.index.update()?;
+
+    let author = repository.config.author();
+    let committer = repository.config.committer();
+    let commit_hash = {
+        let ctx = repository.local(())?;
+        let commit_hash = ctx.write_commit(
+            None,
+            None,
+            vec![ours, theirs],
+            message,
+            Utc::now(),
+            author,
+            committer,
+        ).wait()?;
+        ctx.close().wait()?;
+
+        commit_hash
+    };
+
+    advance(repository, branch.as_ref(), ours, commit_hash)?;
+    ConflictState::clear(&repository.paths)?;
+    println!("Merged as {}.", commit_hash);
+
+    Ok(())
+}
+
+
+fn abort(repository: &mut Repository) -> Result<()> {
+    let state = open(repository)?;
+    let ours = match state.operation {
+        Operation::Merge { ours, .. } => ours,
+        Operation::Rebase => bail!("these conflicts are a rebase's; run `attaca rebase --abort`"),
+    };
+
+    check_out_commit(repository, ours)?;
+    ConflictState::clear(&repository.paths)?;
+
+    Ok(())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("list") {
+        print_conflicts(&open(repository)?);
+        Ok(())
+    } else if matches.is_present("abort") {
+        abort(repository)
+    } else if matches.is_present("continue") {
+        resume(repository)
+    } else if ["ours", "theirs", "resolved"].iter().any(|arg| matches.is_present(arg)) {
+        resolve(repository, matches)
+    } else {
+        start(repository, matches)
+    }
+}
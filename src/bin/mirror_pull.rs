@@ -0,0 +1,117 @@
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::lock::LockFile;
+use attaca::mirror::{self, MirrorState};
+use attaca::sync::{self, FetchOptions};
+
+use errors::*;
+use trace::Progress;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("mirror-pull")
+        .about(
+            "Refresh a read-only mirror: fetch the history behind each branch of an upstream \
+             repository which changed since the last refresh, and point the mirror's branches \
+             at the upstream's.",
+        )
+        .arg(
+            Arg::with_name("REMOTE")
+                .index(1)
+                .help("The remote to fetch objects from. Defaults to the default pull remote."),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream")
+                .takes_value(true)
+                .required(true)
+                .value_name("PATH")
+                .help("The upstream repository whose branches are mirrored."),
+        )
+        .arg(Arg::with_name("dry-run").long("dry-run").help(
+            "Only report which branches changed, without fetching anything.",
+        ))
+}
+
+
+fn describe(hash_opt: Option<::attaca::marshal::ObjectHash>) -> String {
+    hash_opt.map_or_else(|| "(none)".to_owned(), |hash| hash.to_string())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let remote = repository.config.pull_remote(matches.value_of("REMOTE"))?;
+    let upstream = matches.value_of("upstream").unwrap();
+
+    // A refresh still running from the last cron tick is left to finish.
+    let _lock = LockFile::acquire(&repository.paths.mirror_lock)?;
+
+    let mut state = MirrorState::open(&repository.paths)?;
+    let upstream_branches = mirror::upstream_branches(upstream)?;
+    let changes = state.changes(&upstream_branches);
+
+    for change in &changes {
+        println!(
+            "{}: {} -> {}",
+            change.branch,
+            describe(change.old),
+            describe(change.new)
+        );
+    }
+
+    if changes.is_empty() {
+        println!("Mirror is up to date.");
+    }
+
+    if matches.is_present("dry-run") {
+        return Ok(());
+    }
+
+    let heads = changes
+        .iter()
+        .filter_map(|change| change.new)
+        .collect::<Vec<_>>();
+    if !heads.is_empty() {
+        let local_catalog = repository.catalogs.get(None)?;
+        let shallow = repository.shallow.clone();
+        let promised = repository.promised.clone();
+
+        let ctx = repository.remote(&remote, Progress::new(None))?;
+        let fetched = sync::fetch(
+            ctx.store(),
+            &local_catalog,
+            &shallow,
+            &promised,
+            heads,
+            &FetchOptions::default(),
+        ).wait()?;
+        ctx.close().wait()?;
+
+        fetched.shallow.save(&repository.paths)?;
+        fetched.promised.save(&repository.paths)?;
+        repository.shallow = fetched.shallow;
+        repository.promised = fetched.promised;
+
+        println!("Fetched {} objects.", fetched.objects);
+    }
+
+    for change in changes {
+        match change.new {
+            Some(new) => {
+                repository.refs.branches.insert(change.branch, new);
+            }
+            None => {
+                repository.refs.branches.remove(&change.branch);
+            }
+        }
+    }
+
+    state.branches = upstream_branches;
+    state.last_refresh = Some(Utc::now());
+    state.save(&repository.paths)?;
+
+    Ok(())
+}
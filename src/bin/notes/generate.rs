@@ -0,0 +1,34 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::notes;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("generate")
+        .about("Run the configured generators for a commit, replacing its notes.")
+        .arg(Arg::with_name("REV").index(1).help(
+            "The commit to generate notes for. Defaults to the HEAD.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap_or("HEAD"))?;
+
+    if repository.config.generators.is_empty() {
+        bail!("no generators are configured");
+    }
+
+    let generated = notes::generate(repository, commit_hash)?;
+    for name in &generated.attached {
+        println!("Attached {} to {}.", name, commit_hash);
+    }
+    for (name, error) in generated.failed {
+        eprintln!("Generator `{}` failed: {}", name, error);
+    }
+
+    Ok(())
+}
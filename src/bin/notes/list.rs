@@ -0,0 +1,36 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::notes::{self, NOTES_BRANCH};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("list")
+        .about("List the notes attached to a commit.")
+        .arg(Arg::with_name("REV").index(1).help(
+            "The commit whose notes to list. Defaults to the HEAD.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap_or("HEAD"))?;
+    let notes_head = repository.refs.branches.get(NOTES_BRANCH).cloned();
+
+    let notes = {
+        let ctx = repository.local(())?;
+        let notes = notes::read(ctx.store(), notes_head, commit_hash).wait()?;
+        ctx.close().wait()?;
+
+        notes
+    };
+
+    for (name, note) in notes {
+        println!("{} {} {} bytes", name, note.hash, note.size);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+pub mod generate;
+pub mod list;
+pub mod show;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("notes")
+        .about("Inspect or regenerate the derived metadata attached to commits.")
+        .subcommand(generate::command())
+        .subcommand(list::command())
+        .subcommand(show::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("generate", Some(sub_m)) => generate::go(repository, sub_m),
+        ("list", Some(sub_m)) => list::go(repository, sub_m),
+        ("show", Some(sub_m)) => show::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::{self, Write};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::checkout;
+use attaca::notes::{self, NOTES_BRANCH};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("show")
+        .about("Write the contents of a note to standard output.")
+        .arg(
+            Arg::with_name("NAME")
+                .index(1)
+                .required(true)
+                .help("The name of the generator whose output to show."),
+        )
+        .arg(Arg::with_name("REV").index(2).help(
+            "The commit the note is attached to. Defaults to the HEAD.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let name = matches.value_of("NAME").unwrap();
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap_or("HEAD"))?;
+    let notes_head = repository.refs.branches.get(NOTES_BRANCH).cloned();
+
+    let ctx = repository.local(())?;
+    let note = match notes::read(ctx.store(), notes_head, commit_hash).wait()?.remove(name) {
+        Some(note) => note,
+        None => bail!("no note `{}` is attached to {}", name, commit_hash),
+    };
+
+    let stdout = io::stdout();
+    let mut stdout_lock = stdout.lock();
+    for chunk_res in checkout::data_chunks(ctx.store(), note.hash).wait() {
+        stdout_lock.write_all(&chunk_res?)?;
+    }
+    ctx.close().wait()?;
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+use serde_json;
+
+use attaca::Repository;
+use attaca::marshal::ObjectHash;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("publish")
+        .about(
+            "Point a branch at a commit if and only if it currently points at an expected commit. \
+             Intended for automation: the result is always printed as JSON, and nothing is \
+             ever forced.",
+        )
+        .arg(
+            Arg::with_name("branch")
+                .long("branch")
+                .takes_value(true)
+                .required(true)
+                .help("The branch to update."),
+        )
+        .arg(
+            Arg::with_name("expect")
+                .long("expect")
+                .takes_value(true)
+                .required(true)
+                .value_name("OLD_HASH")
+                .help(
+                    "The commit the branch must currently point at, or `none` if the branch must \
+                     not exist yet.",
+                ),
+        )
+        .arg(
+            Arg::with_name("commit")
+                .long("commit")
+                .takes_value(true)
+                .value_name("NEW_HASH")
+                .help("The commit to publish. Defaults to the HEAD."),
+        )
+}
+
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Outcome {
+    Published {
+        branch: String,
+        old: Option<String>,
+        new: String,
+    },
+    Conflict {
+        branch: String,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+    Locked { branch: String },
+}
+
+
+fn hash_string(hash_opt: Option<ObjectHash>) -> Option<String> {
+    hash_opt.map(|hash| hash.to_string())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let branch = matches.value_of("branch").unwrap().to_owned();
+    let expected = match matches.value_of("expect").unwrap() {
+        "none" => None,
+        hash_str => Some(hash_str.parse::<ObjectHash>()?),
+    };
+    let new = match matches.value_of("commit") {
+        Some(hash_str) => hash_str.parse::<ObjectHash>()?,
+        None => {
+            match repository.refs.head() {
+                Some(head) => head,
+                None => bail!("nothing to publish; there are no commits yet"),
+            }
+        }
+    };
+
+    // Refuse to publish anything which isn't a commit we actually have.
+    {
+        let ctx = repository.local(())?;
+        ctx.read_commit(new).wait()?;
+        ctx.close().wait()?;
+    }
+
+    let result = repository.compare_and_swap_branch(&branch, expected, new);
+    let outcome = match result {
+        Ok(()) => Outcome::Published {
+            branch: branch.clone(),
+            old: hash_string(expected),
+            new: new.to_string(),
+        },
+        Err(::attaca::Error(::attaca::ErrorKind::RefConflict(_, _, actual), _)) => {
+            Outcome::Conflict {
+                branch: branch.clone(),
+                expected: hash_string(expected),
+                actual: hash_string(actual),
+            }
+        }
+        Err(::attaca::Error(::attaca::ErrorKind::Locked(_), _)) => Outcome::Locked {
+            branch: branch.clone(),
+        },
+        Err(error) => return Err(error.into()),
+    };
+
+    println!("{}", serde_json::to_string(&outcome)?);
+
+    match outcome {
+        Outcome::Published { .. } => Ok(()),
+        _ => bail!(ErrorKind::PublishRejected(branch)),
+    }
+}
@@ -0,0 +1,84 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::export::directory::{self, PublishOptions, LATEST};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("publish-dir")
+        .about(
+            "Atomically publish a commit as plain files into a shared directory, behind a \
+             `latest` symlink.",
+        )
+        .arg(
+            Arg::with_name("REV")
+                .index(1)
+                .required(true)
+                .help("The revision to publish: `HEAD`, a branch, or a commit hash."),
+        )
+        .arg(
+            Arg::with_name("TARGET")
+                .index(2)
+                .required(true)
+                .help("The directory to publish into."),
+        )
+        .arg(
+            Arg::with_name("keep")
+                .long("keep")
+                .takes_value(true)
+                .value_name("N")
+                .help(
+                    "The number of snapshots to keep, including the new one. Keeping more than \
+                     one lets readers finish with a snapshot after `latest` moves on.",
+                ),
+        )
+        .arg(
+            Arg::with_name("open-files")
+                .short("j")
+                .long("open-files")
+                .takes_value(true)
+                .value_name("N")
+                .help("The maximum number of files to write in parallel."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap())?;
+    let target = matches.value_of("TARGET").unwrap();
+
+    let mut options = PublishOptions::default();
+    if matches.is_present("keep") {
+        options.keep = value_t!(matches.value_of("keep"), usize)?;
+    }
+    if matches.is_present("open-files") {
+        options.open_files = value_t!(matches.value_of("open-files"), usize)?;
+    }
+
+    let snapshot = {
+        let ctx = repository.local(())?;
+        let commit_object = ctx.read_commit(commit_hash).wait()?;
+        let snapshot = directory::publish(
+            ctx.store(),
+            commit_hash,
+            commit_object.subtree,
+            target,
+            &options,
+        ).wait()?;
+        ctx.close().wait()?;
+
+        snapshot
+    };
+
+    eprintln!(
+        "Published {} to {}; {} now points at it.",
+        commit_hash,
+        snapshot.display(),
+        LATEST
+    );
+
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::checkout::CheckoutOptions;
+use attaca::history;
+use attaca::history::conflict::{ConflictState, Operation};
+use attaca::repository::{Head, RefName};
+use attaca::search;
+use attaca::sync::{self, FetchOptions};
+
+use checkout;
+use errors::*;
+use fetch;
+use merge;
+use trace::Progress;
+use transfer;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("pull")
+        .about(
+            "Fetch a branch from a remote and bring the local branch up to date with it, \
+             merging if they have diverged.",
+        )
+        .arg(Arg::with_name("REMOTE").index(1).help(
+            "The remote to pull from. Defaults to the configured default pull remote.",
+        ))
+        .arg(
+            Arg::with_name("branch")
+                .long("branch")
+                .takes_value(true)
+                .value_name("BRANCH")
+                .help("The branch to pull. Defaults to the branch the HEAD is on."),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream")
+                .takes_value(true)
+                .value_name("PATH")
+                .help(
+                    "Update the remote-tracking branches from the repository at PATH, which \
+                     pushes to the remote, before pulling. Otherwise the branch is pulled from \
+                     where it was last recorded for the remote.",
+                ),
+        )
+        .args(&transfer::args())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let remote = repository.config.pull_remote(matches.value_of("REMOTE"))?;
+    let branch = match (matches.value_of("branch"), &repository.refs.head) {
+        (Some(branch), _) => branch.to_owned(),
+        (None, &Head::LocalRef(ref branch)) => branch.clone(),
+        (None, _) => bail!("not on a branch; pass --branch to choose one to pull"),
+    };
+
+    if ConflictState::open(&repository.paths)?.is_some() {
+        bail!("a merge or rebase is stopped at conflicts; finish it or abort it first");
+    }
+
+    if let Some(path) = matches.value_of("upstream") {
+        fetch::track_upstream(repository, &remote, path)?;
+    }
+
+    let tracking = RefName::RemoteBranch(remote.clone(), branch.clone());
+    let upstream = match repository.refs.get(&tracking) {
+        Some(upstream) => upstream,
+        None => bail!(::attaca::ErrorKind::RevisionNotFound(tracking.to_string())),
+    };
+    let local = repository.refs.branches.get(&branch).cloned();
+
+    if local == Some(upstream) {
+        println!("{} is already up to date with {}/{}.", branch, remote, branch);
+        return Ok(());
+    }
+
+    let local_catalog = repository.catalogs.get(None)?;
+    let shallow = repository.shallow.clone();
+    let promised = repository.promised.clone();
+
+    let fetched = {
+        let ctx = repository.remote(&remote, Progress::new(None))?;
+        let fetched = sync::fetch(
+            ctx.store(),
+            &local_catalog,
+            &shallow,
+            &promised,
+            vec![upstream],
+            &FetchOptions {
+                transfer: transfer::options(matches)?,
+                ..FetchOptions::default()
+            },
+        ).wait()?;
+        ctx.close().wait()?;
+
+        fetched
+    };
+
+    fetched.shallow.save(&repository.paths)?;
+    fetched.promised.save(&repository.paths)?;
+    repository.shallow = fetched.shallow;
+    repository.promised = fetched.promised;
+    println!("Fetched {} objects.", fetched.objects);
+
+    // The local branch is fast-forwarded if it is behind the remote one, and otherwise merged
+    // with it.
+    let mut new_head = upstream;
+    if let Some(local) = local {
+        let shallow = repository.shallow.clone();
+        let base = {
+            let ctx = repository.local(())?;
+            let base = history::merge_base(ctx.store(), local, upstream, &shallow).wait()?;
+            ctx.close().wait()?;
+
+            base
+        };
+
+        if base == Some(upstream) {
+            println!("{} is ahead of {}/{}; there is nothing to pull.", branch, remote, branch);
+            return Ok(());
+        }
+
+        if base != Some(local) {
+            let message = format!("Merge {} into {}", tracking, branch);
+            let merged = merge::three_way(repository, local, upstream, base)?;
+
+            if !merged.is_clean() {
+                if repository.refs.head != Head::LocalRef(branch.clone()) {
+                    bail!(
+                        "{} and {} conflict at {} paths; check out {} and pull again to resolve \
+                         them",
+                        branch,
+                        tracking,
+                        merged.conflicts.len(),
+                        branch
+                    );
+                }
+
+                let operation = Operation::Merge {
+                    branch: Some(branch.clone()),
+                    ours: local,
+                    theirs: upstream,
+                    message,
+                };
+                merge::stop(repository, operation, &merged)?;
+                println!(
+                    "Resolve them with `attaca merge --ours`, `--theirs` or `--resolved`, and \
+                     then run `attaca merge --continue`; or give up with `attaca merge --abort`."
+                );
+
+                return Ok(());
+            }
+
+            let parents = vec![local, upstream];
+            new_head = merge::commit_merge(repository, merged.subtree, parents, message)?;
+        }
+    }
+
+    repository.compare_and_swap_branch(&branch, local, new_head)?;
+
+    if let Err(error) = search::refresh(repository, &[]) {
+        eprintln!("Warning: could not update the search index: {}", error);
+    }
+
+    if repository.refs.head == Head::LocalRef(branch.clone()) {
+        let sparse = repository.index.sparse().cloned();
+        let options = CheckoutOptions {
+            filter: sparse.as_ref().map(|sparse| sparse.globset().clone()),
+            ..CheckoutOptions::default()
+        };
+        checkout::check_out(repository, new_head, &options, sparse)?;
+    }
+
+    match local {
+        Some(_) if new_head != upstream => {
+            println!("Merged {}/{} into {} as {}.", remote, branch, branch, new_head)
+        }
+        Some(local) => println!("Fast-forwarded {} from {} to {}.", branch, local, upstream),
+        None => println!("Created {} at {}.", branch, upstream),
+    }
+
+    Ok(())
+}
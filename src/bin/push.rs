@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::hooks::{Hooks, PushEvent};
+use attaca::path_locks;
+use attaca::repository::Head;
+use attaca::sync::{self, PushPlan};
+
+use errors::*;
+use trace::Progress;
+use transfer;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("push")
+        .about("Send the objects reachable from a branch to a remote.")
+        .arg(
+            Arg::with_name("REMOTE")
+                .index(1)
+                .help("The remote to push to. Defaults to the default push remote."),
+        )
+        .arg(
+            Arg::with_name("branch")
+                .long("branch")
+                .takes_value(true)
+                .value_name("BRANCH")
+                .help("The branch to push. Defaults to the currently checked out branch."),
+        )
+        .arg(Arg::with_name("dry-run").long("dry-run").help(
+            "Only work out what would be sent, without sending anything.",
+        ))
+        .arg(Arg::with_name("stat").long("stat").help(
+            "Report how many objects and bytes are sent, and how long that takes at the \
+             remote's configured bandwidth.",
+        ))
+        .arg(
+            Arg::with_name("bandwidth")
+                .long("bandwidth")
+                .takes_value(true)
+                .value_name("BYTES_PER_SECOND")
+                .help("The bandwidth to estimate with, overriding the remote's configuration."),
+        )
+        .args(&transfer::args())
+}
+
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}h {:02}m {:02}s", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+
+fn print_stat(plan: &PushPlan, bandwidth: Option<u64>) {
+    println!(
+        "{} objects, {} ({} bytes)",
+        plan.objects.len(),
+        human_bytes(plan.bytes),
+        plan.bytes
+    );
+
+    match bandwidth {
+        Some(bandwidth) => {
+            println!(
+                "ETA {} at {}/s",
+                human_duration(plan.eta(bandwidth)),
+                human_bytes(bandwidth)
+            );
+        }
+        None => println!("No bandwidth configured for this remote; pass --bandwidth for an ETA."),
+    }
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let remote = repository.config.push_remote(matches.value_of("REMOTE"))?;
+    let remote_cfg = repository.config.remotes[&remote].clone();
+
+    let branch = match (matches.value_of("branch"), &repository.refs.head) {
+        (Some(branch), _) => branch.to_owned(),
+        (None, &Head::LocalRef(ref branch)) => branch.clone(),
+        (None, _) => bail!("not on a branch; pass --branch to choose one to push"),
+    };
+    let commit_hash = match repository.refs.branches.get(&branch) {
+        Some(&commit_hash) => commit_hash,
+        None => bail!("no such branch `{}`", branch),
+    };
+
+    let bandwidth = if matches.is_present("bandwidth") {
+        Some(value_t!(matches.value_of("bandwidth"), u64)?)
+    } else {
+        remote_cfg.bandwidth
+    };
+    let version = repository.object_version;
+
+    let plan = {
+        let remote_catalog = repository.catalogs.get(Some(remote.clone()))?;
+        let ctx = repository.local(())?;
+        let plan = sync::plan_push(ctx.store(), &remote_catalog, commit_hash, version).wait()?;
+        ctx.close().wait()?;
+
+        plan
+    };
+
+    if matches.is_present("dry-run") {
+        println!("Would push {} to {}/{}:", commit_hash, remote, branch);
+        print_stat(&plan, bandwidth);
+
+        return Ok(());
+    }
+
+    let previous = repository
+        .refs
+        .remotes
+        .get(&remote)
+        .and_then(|branches| branches.get(&branch))
+        .cloned();
+    path_locks::check_push(repository, &remote, &branch, previous, commit_hash)?;
+    repository.hooks.pre_push(&PushEvent {
+        remote: &remote,
+        branch: &branch,
+        commit_hash,
+        previous,
+    })?;
+
+    if !plan.is_empty() {
+        let transfer_options = transfer::options(matches)?;
+        let remote_catalog = repository.catalogs.get(Some(remote.clone()))?;
+        let ctx = repository.remote(&remote, Progress::new(None))?;
+        sync::push(
+            ctx.store(),
+            ctx.store(),
+            &remote_catalog,
+            &plan,
+            version,
+            &transfer_options,
+        ).wait()?;
+        ctx.close().wait()?;
+    }
+
+    repository
+        .refs
+        .remotes
+        .entry(remote.clone())
+        .or_insert_with(Default::default)
+        .insert(branch.clone(), commit_hash);
+
+    println!("Pushed {} to {}/{}.", commit_hash, remote, branch);
+    if matches.is_present("stat") {
+        print_stat(&plan, bandwidth);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::checkout::CheckoutOptions;
+use attaca::history::conflict::{ConflictState, Operation};
+use attaca::history::rebase::Rebase;
+use attaca::marshal::ObjectHash;
+use attaca::repository::Head;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("rebase")
+        .about("Replay a branch's commits on top of another commit, making its history linear.")
+        .arg(
+            Arg::with_name("ONTO")
+                .index(1)
+                .required_unless_one(&["continue", "abort"])
+                .help("The revision to replay the branch onto."),
+        )
+        .arg(
+            Arg::with_name("branch")
+                .long("branch")
+                .takes_value(true)
+                .value_name("BRANCH")
+                .conflicts_with_all(&["continue", "abort"])
+                .help("The branch to rebase. Defaults to the checked out branch."),
+        )
+        .arg(
+            Arg::with_name("continue")
+                .long("continue")
+                .conflicts_with("abort")
+                .help(
+                    "Record the worktree as the resolution of the conflicted commit and carry on.",
+                ),
+        )
+        .arg(Arg::with_name("abort").long("abort").help(
+            "Give up on the rebase in progress, returning to the original branch.",
+        ))
+}
+
+
+fn check_out(repository: &mut Repository, commit_hash: ObjectHash) -> Result<()> {
+    let mut options = CheckoutOptions::default();
+    options.filter = repository.index.sparse().map(|sparse| sparse.globset().clone());
+
+    let ctx = repository.local(())?;
+    let commit = ctx.read_commit(commit_hash).wait()?;
+    let base = ctx.paths.base.clone();
+    ctx.checkout(commit.subtree, base, &options).wait()?;
+    ctx.close().wait()?;
+
+    Ok(())
+}
+
+
+/// Swap the branch if the rebase is done, or stop at the conflicted commit if it is not.
+fn finish(repository: &mut Repository, rebase: Rebase) -> Result<()> {
+    match rebase.conflict {
+        Some(ref conflict) => {
+            repository.refs.head = Head::Detached(conflict.provisional);
+            check_out(repository, conflict.provisional)?;
+            rebase.save(&repository.paths)?;
+            ConflictState::from_sides(Operation::Rebase, conflict.sides.clone())
+                .save(&repository.paths)?;
+
+            println!(
+                "Could not replay {} cleanly ({} commits left). Conflicting paths:",
+                conflict.commit,
+                rebase.remaining()
+            );
+            for path in &conflict.paths {
+                println!("\t{}", path.display());
+            }
+            println!(
+                "Resolve them with `attaca merge --ours`, `--theirs` or `--resolved`, and then \
+                 run `attaca rebase --continue`; or give up with `attaca rebase --abort`."
+            );
+        }
+        None => {
+            repository.compare_and_swap_branch(
+                &rebase.branch,
+                Some(rebase.original),
+                rebase.head,
+            )?;
+            repository.refs.head = Head::LocalRef(rebase.branch.clone());
+            check_out(repository, rebase.head)?;
+            Rebase::clear(&repository.paths)?;
+            ConflictState::clear(&repository.paths)?;
+
+            println!("Rebased {} to {}.", rebase.branch, rebase.head);
+        }
+    }
+
+    Ok(())
+}
+
+
+fn start(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if Rebase::open(&repository.paths)?.is_some() {
+        bail!("a rebase is already in progress; use --continue or --abort");
+    }
+
+    if ConflictState::open(&repository.paths)?.is_some() {
+        bail!("a merge is stopped at conflicts; finish it or abort it first");
+    }
+
+    let branch = match matches.value_of("branch") {
+        Some(branch) => branch.to_owned(),
+        None => {
+            match repository.refs.head {
+                Head::LocalRef(ref branch) => branch.clone(),
+                _ => bail!("HEAD is not a local branch; name the branch with --branch"),
+            }
+        }
+    };
+    let original = match repository.refs.branches.get(&branch) {
+        Some(&original) => original,
+        None => bail!(::attaca::ErrorKind::RevisionNotFound(branch)),
+    };
+    let onto = repository.refs.resolve(matches.value_of("ONTO").unwrap())?;
+
+    let rebase = {
+        let ctx = repository.local(())?;
+        let rebase = Rebase::start(ctx.store(), branch, original, onto).wait()?;
+        let rebase = rebase.run(ctx.store(), ctx.object_version, &ctx.merge_drivers).wait()?;
+        ctx.close().wait()?;
+
+        rebase
+    };
+
+    finish(repository, rebase)
+}
+
+
+pub fn resume(repository: &mut Repository) -> Result<()> {
+    let rebase = match Rebase::open(&repository.paths)? {
+        Some(rebase) => rebase,
+        None => bail!("no rebase is in progress"),
+    };
+
+    if let Some(state) = ConflictState::open(&repository.paths)? {
+        state.check_resolved()?;
+    }
+
+    repository.index.update()?;
+
+    // The worktree is recorded through an ordinary commit, of which only the tree is kept. It
+    // must be closed, flushing its objects, before they can be read back.
+    let worktree = {
+        let ctx = repository.local(())?;
+        let worktree = ctx.write_commit(
+            None,
+            None,
+            Vec::new(),
+            String::new(),
+            Utc::now(),
+            None,
+            None,
+        ).wait()?;
+        ctx.close().wait()?;
+
+        worktree
+    };
+
+    let rebase = {
+        let ctx = repository.local(())?;
+        let subtree = ctx.read_commit(worktree).wait()?.subtree;
+        let rebase = rebase
+            .resolve(ctx.store(), ctx.object_version, &ctx.merge_drivers, subtree)
+            .wait()?;
+        ctx.close().wait()?;
+
+        rebase
+    };
+
+    finish(repository, rebase)
+}
+
+
+pub fn abort(repository: &mut Repository) -> Result<()> {
+    let rebase = match Rebase::open(&repository.paths)? {
+        Some(rebase) => rebase,
+        None => bail!("no rebase is in progress"),
+    };
+
+    repository.refs.head = Head::LocalRef(rebase.branch.clone());
+    check_out(repository, rebase.original)?;
+    Rebase::clear(&repository.paths)?;
+    ConflictState::clear(&repository.paths)?;
+
+    Ok(())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("abort") {
+        abort(repository)
+    } else if matches.is_present("continue") {
+        resume(repository)
+    } else {
+        start(repository, matches)
+    }
+}
@@ -0,0 +1,153 @@
+use std::env;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::recover::{self, Action, Interrupted};
+use attaca::repository::{self, Paths};
+
+use errors::*;
+use rebase;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("recover")
+        .about(
+            "Find what interrupted commands left behind - stale locks, partially written files, \
+             staged objects, rebases and bisections - and resume or roll them back.",
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .conflicts_with("rollback")
+                .help("Finish what the interrupted operations were doing."),
+        )
+        .arg(Arg::with_name("rollback").long("rollback").help(
+            "Undo what the interrupted operations had done.",
+        ))
+        .arg(
+            Arg::with_name("ITEM")
+                .index(1)
+                .multiple(true)
+                .help(
+                    "The numbers of the interrupted operations to act on, as listed. Defaults to \
+                     every one which the action applies to.",
+                ),
+        )
+}
+
+
+fn list(found: &[Interrupted]) {
+    if found.is_empty() {
+        println!("Nothing was left behind by interrupted operations.");
+        return;
+    }
+
+    for (i, interrupted) in found.iter().enumerate() {
+        println!("{}. {}.", i + 1, interrupted);
+
+        let actions = interrupted.actions();
+        if actions.is_empty() {
+            println!("   Nothing can be done until it finishes.");
+        }
+
+        for action in actions {
+            let flag = match action {
+                Action::Resume => "--resume",
+                Action::Rollback => "--rollback",
+            };
+            println!(
+                "   {:<10} {}",
+                flag,
+                interrupted.explain(action).unwrap_or_default()
+            );
+        }
+    }
+}
+
+
+pub fn go(matches: &ArgMatches) -> Result<()> {
+    // The repository is deliberately not loaded until the end: loading it cleans up abandoned
+    // staging areas, and stale locks would keep its refs from being written.
+    let root = repository::find(env::current_dir()?)?;
+    let paths = Paths::new(&root);
+    let found = recover::scan(&paths)?;
+
+    let action = if matches.is_present("resume") {
+        Action::Resume
+    } else if matches.is_present("rollback") {
+        Action::Rollback
+    } else {
+        list(&found);
+
+        if found.iter().any(|interrupted| !interrupted.actions().is_empty()) {
+            println!(
+                "Run `attaca recover --resume` or `attaca recover --rollback`, optionally with \
+                 the numbers of the operations to act on."
+            );
+        }
+
+        return Ok(());
+    };
+
+    let selected = match matches.values_of("ITEM") {
+        Some(items) => {
+            let mut selected = Vec::new();
+
+            for item in items {
+                let index = match item.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= found.len() => n - 1,
+                    _ => bail!("`{}` is not one of the operations listed", item),
+                };
+
+                if !found[index].actions().contains(&action) {
+                    bail!("cannot {} operation {}: {}", action, item, found[index]);
+                }
+
+                selected.push(index);
+            }
+
+            selected
+        }
+        None => {
+            (0..found.len())
+                .filter(|&i| found[i].actions().contains(&action))
+                .collect()
+        }
+    };
+
+    if selected.is_empty() {
+        println!("There is nothing to {}.", action);
+        return Ok(());
+    }
+
+    let mut rebasing = false;
+
+    for (i, interrupted) in found.into_iter().enumerate() {
+        if !selected.contains(&i) {
+            continue;
+        }
+
+        println!("{}. {}.", i + 1, interrupted);
+        println!("   Will {}.", interrupted.explain(action).unwrap_or_default());
+
+        match interrupted {
+            Interrupted::Rebase(_) => rebasing = true,
+            interrupted => interrupted.apply(action, &paths)?,
+        }
+    }
+
+    // Rebases check out commits, and so need the repository. They are dealt with last, once any
+    // stale locks are out of the way.
+    if rebasing {
+        let mut repository = Repository::load(&root)?;
+        let result = match action {
+            Action::Resume => rebase::resume(&mut repository),
+            Action::Rollback => rebase::abort(&mut repository),
+        };
+        repository.cleanup()?;
+        result?;
+    }
+
+    Ok(())
+}
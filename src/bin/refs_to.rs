@@ -0,0 +1,86 @@
+use std::collections::{HashSet, VecDeque};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::marshal::ObjectHash;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("refs-to")
+        .about(
+            "List the objects in the local store which refer to an object. Requires `backrefs` to \
+             be enabled in config.toml.",
+        )
+        .arg(
+            Arg::with_name("HASH")
+                .index(1)
+                .required_unless("rebuild")
+                .help("The hash of the object to look up."),
+        )
+        .arg(Arg::with_name("transitive").short("t").long("transitive").help(
+            "List every object the object is reachable from, not just its direct referrers, \
+             along with any branches among them.",
+        ))
+        .arg(Arg::with_name("rebuild").long("rebuild").help(
+            "Rebuild the index from every object in the local store first.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("rebuild") {
+        match repository.backrefs {
+            Some(ref backrefs) => {
+                backrefs.rebuild(&repository.paths)?;
+                backrefs.save()?;
+                eprintln!("Indexed references to {} objects.", backrefs.len());
+            }
+            None => bail!(::attaca::ErrorKind::BackrefsDisabled),
+        }
+    }
+
+    if !matches.is_present("HASH") {
+        return Ok(());
+    }
+
+    let object_hash = value_t!(matches.value_of("HASH"), ObjectHash)?;
+
+    if !matches.is_present("transitive") {
+        for referrer in repository.referrers(object_hash)? {
+            println!("{}", referrer);
+        }
+
+        return Ok(());
+    }
+
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(object_hash);
+
+    while let Some(hash) = queue.pop_front() {
+        for referrer in repository.referrers(hash)? {
+            if seen.insert(referrer) {
+                let branches = repository
+                    .refs
+                    .branches
+                    .iter()
+                    .filter(|&(_, &head)| head == referrer)
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>();
+
+                if branches.is_empty() {
+                    println!("{}", referrer);
+                } else {
+                    println!("{} ({})", referrer, branches.join(", "));
+                }
+
+                queue.push_back(referrer);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,10 +1,13 @@
 use std::collections::HashMap;
+use std::net::ToSocketAddrs;
 use std::path::PathBuf;
 
 use clap::{App, SubCommand, Arg, ArgGroup, ArgMatches};
 use itertools::Itertools;
 
-use attaca::repository::{RemoteCfg, ObjectStoreCfg, CephCfg, EtcdCfg, Repository};
+use attaca::repository::{self, RemoteCfg, ObjectStoreCfg, CephCfg, EtcdCfg, LevelDbCfg, S3Cfg,
+                         SshCfg, Compression, NegotiationCfg, Repository};
+use attaca::store::WritePolicy;
 
 use errors::*;
 
@@ -33,9 +36,33 @@ pub fn command() -> App<'static, 'static> {
                     "Declare a repository using a Ceph cluster as an object store.",
                 ),
         )
+        .arg(
+            Arg::with_name("leveldb")
+                .long("leveldb")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Declare a repository using a LevelDB database as an object store."),
+        )
+        .arg(
+            Arg::with_name("s3-bucket")
+                .long("s3-bucket")
+                .takes_value(true)
+                .value_name("BUCKET")
+                .help("Declare a repository using an S3 bucket as an object store."),
+        )
+        .arg(
+            Arg::with_name("ssh")
+                .long("ssh")
+                .takes_value(true)
+                .value_name("URL")
+                .help(
+                    "Declare a repository reached over SSH, given as `ssh://user@host[:port]` or \
+                     `user@host[:port]`.",
+                ),
+        )
         .group(
             ArgGroup::with_name("object-store")
-                .args(&["ceph"])
+                .args(&["ceph", "leveldb", "s3-bucket", "ssh"])
                 .required(true),
         )
         .arg(
@@ -60,6 +87,17 @@ pub fn command() -> App<'static, 'static> {
                 .default_value("rbd")
                 .help("The pool of the Ceph cluster to use for objects."),
         )
+        .arg(
+            Arg::with_name("ceph-metadata-pool")
+                .long("ceph-metadata-pool")
+                .takes_value(true)
+                .value_name("POOL")
+                .requires("ceph")
+                .help(
+                    "A separate pool of the Ceph cluster to keep subtrees, commits and \
+                     transaction journals in, apart from file data.",
+                ),
+        )
         .arg(
             Arg::with_name("ceph-user")
                 .long("ceph-user")
@@ -94,11 +132,69 @@ pub fn command() -> App<'static, 'static> {
                 "ceph-conf",
             ],
         ))
+        .arg(
+            Arg::with_name("s3-region")
+                .long("s3-region")
+                .takes_value(true)
+                .requires("s3-bucket")
+                .help("The region of the S3 bucket."),
+        )
+        .arg(
+            Arg::with_name("s3-prefix")
+                .long("s3-prefix")
+                .takes_value(true)
+                .requires("s3-bucket")
+                .help("A prefix for the keys of objects in the S3 bucket."),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .takes_value(true)
+                .possible_values(&["none", "zstd"])
+                .default_value("none")
+                .help("How to compress objects sent to the remote."),
+        )
+        .arg(
+            Arg::with_name("compression-level")
+                .long("compression-level")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .default_value("3")
+                .help("The zstd compression level, if compressing."),
+        )
+        .arg(
+            Arg::with_name("digest")
+                .long("digest")
+                .takes_value(true)
+                .default_value(repository::DIGEST)
+                .help("The digest the remote addresses objects by."),
+        )
+        .arg(
+            Arg::with_name("encryption-key")
+                .long("encryption-key")
+                .takes_value(true)
+                .value_name("PATH")
+                .help(
+                    "Seal objects sent to the remote with the key at PATH, relative to the \
+                     repository root. See `attaca keygen --encryption`.",
+                ),
+        )
+        .arg(Arg::with_name("negotiate").long("negotiate").help(
+            "Before sending an object the catalog does not know the remote to hold, ask the \
+             remote whether it has it already. Answers are remembered for a while, per remote.",
+        ))
+        .arg(Arg::with_name("default-push").long("default-push").help(
+            "Push to this remote when no remote is named.",
+        ))
+        .arg(Arg::with_name("default-pull").long("default-pull").help(
+            "Fetch from this remote when no remote is named.",
+        ))
 }
 
 
 fn parse_ceph_object_store(matches: &ArgMatches) -> Result<CephCfg> {
     let pool = matches.value_of("ceph-pool").unwrap().to_owned();
+    let metadata_pool = matches.value_of("ceph-metadata-pool").map(str::to_owned);
     let user = matches.value_of("ceph-user").unwrap().to_owned();
 
     let mut conf_options = HashMap::new();
@@ -118,14 +214,48 @@ fn parse_ceph_object_store(matches: &ArgMatches) -> Result<CephCfg> {
         conf_file,
         conf_options,
         pool,
+        metadata_pool,
         user,
     })
 }
 
 
-fn parse_object_store(matches: &ArgMatches) -> Result<CephCfg> {
+fn parse_ssh_object_store(url: &str) -> Result<SshCfg> {
+    let trimmed = url.trim_left_matches("ssh://");
+    let (username, host) = match trimmed.find('@') {
+        Some(at) => (&trimmed[..at], &trimmed[at + 1..]),
+        None => bail!("SSH remote `{}` must name a user, as in `user@host`", url),
+    };
+    let host_and_port = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{}:22", host)
+    };
+    let address = match host_and_port.to_socket_addrs()?.next() {
+        Some(address) => address,
+        None => bail!("could not resolve SSH host `{}`", host),
+    };
+
+    Ok(SshCfg {
+        address,
+        username: username.to_owned(),
+    })
+}
+
+
+fn parse_object_store(matches: &ArgMatches) -> Result<ObjectStoreCfg> {
     if matches.is_present("ceph") {
-        parse_ceph_object_store(matches)
+        parse_ceph_object_store(matches).map(ObjectStoreCfg::Ceph)
+    } else if let Some(path) = matches.value_of("leveldb") {
+        Ok(ObjectStoreCfg::LevelDb(LevelDbCfg { path: PathBuf::from(path) }))
+    } else if let Some(bucket) = matches.value_of("s3-bucket") {
+        Ok(ObjectStoreCfg::S3(S3Cfg {
+            bucket: bucket.to_owned(),
+            region: matches.value_of("s3-region").map(str::to_owned),
+            prefix: matches.value_of("s3-prefix").map(str::to_owned),
+        }))
+    } else if let Some(url) = matches.value_of("ssh") {
+        parse_ssh_object_store(url).map(ObjectStoreCfg::Ssh)
     } else {
         unreachable!("CLAP validation failure")
     }
@@ -140,15 +270,48 @@ pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
     }
 
     let object_store = parse_object_store(matches)?;
+    if let Some(error_kind) = object_store.unavailable() {
+        eprintln!("Warning: the remote is saved, but cannot be used: {}", error_kind);
+    }
+    let compression = match matches.value_of("compression").unwrap() {
+        "none" => Compression::None,
+        "zstd" => Compression::Zstd(value_t!(matches.value_of("compression-level"), i32)?),
+        _ => unreachable!("CLAP validation failure"),
+    };
+    let digest = matches.value_of("digest").unwrap().to_owned();
+    if digest != repository::DIGEST {
+        bail!(::attaca::ErrorKind::UnsupportedDigest(digest));
+    }
 
     repository.config.remotes.insert(
-        name,
+        name.clone(),
         RemoteCfg {
-            object_store: ObjectStoreCfg::Ceph(object_store),
+            bandwidth: None,
+            object_store,
             ref_store: EtcdCfg::default(),
+            replicas: Vec::new(),
+            write_policy: WritePolicy::default(),
+            simulate: None,
+            digest,
+            compression,
+            encryption_key: matches.value_of("encryption-key").map(PathBuf::from),
+            negotiation: if matches.is_present("negotiate") {
+                Some(NegotiationCfg::default())
+            } else {
+                None
+            },
+            timeouts: None,
+            retry: None,
         },
     );
 
+    if matches.is_present("default-push") {
+        repository.config.default_push = Some(name.clone());
+    }
+    if matches.is_present("default-pull") {
+        repository.config.default_pull = Some(name);
+    }
+
     // repository writes config on drop.
 
     Ok(())
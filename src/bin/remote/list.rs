@@ -2,7 +2,7 @@ use std::fmt::Write;
 
 use clap::{App, SubCommand, ArgMatches};
 
-use attaca::repository::{ObjectStoreCfg, Repository};
+use attaca::repository::{self, ObjectStoreCfg, Compression, Repository};
 
 use errors::*;
 
@@ -13,19 +13,36 @@ pub fn command() -> App<'static, 'static> {
 
 
 pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
-    for (name, remote) in repository.config.remotes.iter() {
+    let mut names = repository.config.remotes.keys().collect::<Vec<_>>();
+    names.sort();
+
+    for name in names {
+        let remote = &repository.config.remotes[name];
+        let mut out = String::new();
+
         match remote.object_store {
             ObjectStoreCfg::Ceph(ref ceph_cfg) => {
                 if let Some(ref ceph_conf) = ceph_cfg.conf_file.as_ref() {
-                    println!("{}: ceph.conf path `{}`", name, ceph_conf.display());
+                    write!(out, "{}: ceph.conf path `{}`", name, ceph_conf.display())?;
                 } else if let Some(ref hosts) = ceph_cfg.conf_options.get("mon_host") {
-                    println!("{}: from hosts `{}`", name, hosts);
+                    write!(out, "{}: from hosts `{}`", name, hosts)?;
                 } else {
-                    println!("{}: no `mon_host` or ceph.conf entry", name);
+                    write!(out, "{}: no `mon_host` or ceph.conf entry", name)?;
+                }
+            }
+            ObjectStoreCfg::LevelDb(ref leveldb_cfg) => {
+                write!(out, "{}: leveldb at `{}`", name, leveldb_cfg.path.display())?;
+            }
+            ObjectStoreCfg::S3(ref s3_cfg) => {
+                write!(out, "{}: s3://{}", name, s3_cfg.bucket)?;
+                if let Some(ref prefix) = s3_cfg.prefix {
+                    write!(out, "/{}", prefix)?;
+                }
+                if let Some(ref region) = s3_cfg.region {
+                    write!(out, " in {}", region)?;
                 }
             }
             ObjectStoreCfg::Ssh(ref ssh_cfg) => {
-                let mut out = String::new();
                 write!(
                     out,
                     "{}: {}@{}",
@@ -36,9 +53,34 @@ pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
                 if ssh_cfg.address.port() != 22 {
                     write!(out, ":[{}]", ssh_cfg.address.port())?;
                 }
-                println!("{}", out);
             }
         }
+
+        if !remote.replicas.is_empty() {
+            write!(
+                out,
+                " (+{} replicas, writes acknowledged by {})",
+                remote.replicas.len(),
+                format!("{:?}", remote.write_policy).to_lowercase()
+            )?;
+        }
+        if let Compression::Zstd(level) = remote.compression {
+            write!(out, " (zstd level {})", level)?;
+        }
+        if remote.digest != repository::DIGEST {
+            write!(out, " (digest {})", remote.digest)?;
+        }
+        if !remote.object_store.is_available() {
+            write!(out, " [unavailable in this build]")?;
+        }
+        if repository.config.default_push.as_ref() == Some(name) {
+            write!(out, " [default push]")?;
+        }
+        if repository.config.default_pull.as_ref() == Some(name) {
+            write!(out, " [default pull]")?;
+        }
+
+        println!("{}", out);
     }
 
     Ok(())
@@ -6,6 +6,7 @@ use errors::*;
 
 pub mod add;
 pub mod list;
+pub mod remove;
 
 
 pub fn command() -> App<'static, 'static> {
@@ -13,6 +14,7 @@ pub fn command() -> App<'static, 'static> {
         .about("Manipulate remote repositories.")
         .subcommand(add::command())
         .subcommand(list::command())
+        .subcommand(remove::command())
 }
 
 
@@ -20,6 +22,7 @@ pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
     match matches.subcommand() {
         ("add", Some(sub_m)) => add::go(repository, sub_m),
         ("list", Some(sub_m)) => list::go(repository, sub_m),
+        ("remove", Some(sub_m)) => remove::go(repository, sub_m),
         _ => {
             bail!(ErrorKind::InvalidUsage);
         }
@@ -0,0 +1,47 @@
+use std::fs;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("remove")
+        .about("Remove a remote from a repository, along with its refs and catalog.")
+        .arg(
+            Arg::with_name("NAME")
+                .help("The short name of the remote.")
+                .required(true)
+                .index(1),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let name = matches.value_of("NAME").unwrap().to_owned();
+
+    if repository.config.remotes.remove(&name).is_none() {
+        bail!(::attaca::ErrorKind::RemoteNotFound(name));
+    }
+
+    if repository.config.default_push.as_ref() == Some(&name) {
+        repository.config.default_push = None;
+    }
+    if repository.config.default_pull.as_ref() == Some(&name) {
+        repository.config.default_pull = None;
+    }
+
+    repository.refs.remotes.remove(&name);
+    repository.catalogs.remove(&name)?;
+
+    let negotiation_path = repository.paths.remote_catalogs.join(format!("{}.negotiation", name));
+    if negotiation_path.is_file() {
+        fs::remove_file(negotiation_path)?;
+    }
+
+    // repository writes config on drop.
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::integrity;
+use attaca::marshal::ObjectHash;
+use attaca::quarantine::{self, Outcome};
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Check each object given again, or by default each object `attaca doctor` reports as corrupt, and
+move those which are corrupt or missing into .attaca/quarantine. Good copies are then fetched
+from each configured remote in turn. For any object no remote has a good copy of, every commit
+and index entry it breaks is listed, down to the paths within each commit's tree.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("repair")
+        .about("Quarantine corrupt objects, and recover good copies from remotes.")
+        .after_help(HELP_STR)
+        .arg(
+            Arg::with_name("HASH")
+                .index(1)
+                .multiple(true)
+                .help("The objects to repair. Defaults to those `attaca doctor` reports."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let hashes = match matches.values_of("HASH") {
+        Some(hashes) => hashes.map(str::parse).collect::<::attaca::Result<Vec<ObjectHash>>>()?,
+        None => {
+            let mut hashes = Vec::new();
+            for record in integrity::records(&repository.paths)? {
+                if !hashes.contains(&record.expected) {
+                    hashes.push(record.expected);
+                }
+            }
+            hashes
+        }
+    };
+
+    if hashes.is_empty() {
+        println!("No corruption detected.");
+        return Ok(());
+    }
+
+    let repair = quarantine::repair(repository, &hashes)?;
+
+    let mut repaired = HashSet::new();
+    for &(hash, ref outcome) in &repair.outcomes {
+        match *outcome {
+            Outcome::Intact => println!("{} is intact.", hash),
+            Outcome::Recovered(ref remote) => println!("{} recovered from {}.", hash, remote),
+            Outcome::Lost => println!("{} is lost: no remote has a good copy.", hash),
+        }
+
+        if *outcome != Outcome::Lost {
+            repaired.insert(hash);
+        }
+    }
+    integrity::forget(&repository.paths, &repaired)?;
+
+    if !repair.affected_commits.is_empty() {
+        println!("Affected commits:");
+        for affected in &repair.affected_commits {
+            if affected.paths.is_empty() {
+                println!("\t{} (the commit itself)", affected.commit);
+            }
+            for path in &affected.paths {
+                if path.as_os_str().is_empty() {
+                    println!("\t{} (the whole tree)", affected.commit);
+                } else {
+                    println!("\t{} {}", affected.commit, path.display());
+                }
+            }
+        }
+    }
+
+    if !repair.affected_index.is_empty() {
+        println!("Affected index entries:");
+        for path in &repair.affected_index {
+            println!("\t{}", path.display());
+        }
+    }
+
+    Ok(())
+}
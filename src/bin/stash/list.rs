@@ -0,0 +1,21 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::history::stash;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("list").about("List stashed changes, most recent first.")
+}
+
+
+pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
+    for (index, (stash_hash, commit)) in stash::stash_list(repository)?.into_iter().enumerate() {
+        let summary = commit.message.lines().next().unwrap_or("");
+        println!("stash@{{{}}} {} {}", index, stash_hash, summary);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+pub mod list;
+pub mod pop;
+pub mod save;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("stash")
+        .about("Set uncommitted changes aside, returning the worktree to the HEAD.")
+        .subcommand(list::command())
+        .subcommand(pop::command())
+        .subcommand(save::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("list", Some(sub_m)) => list::go(repository, sub_m),
+        ("pop", Some(sub_m)) => pop::go(repository, sub_m),
+        ("save", Some(sub_m)) => save::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
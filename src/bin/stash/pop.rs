@@ -0,0 +1,27 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::history::stash;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("pop").about(
+        "Apply the most recent stash to the worktree, dropping it if it applies cleanly.",
+    )
+}
+
+
+pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
+    let merged = stash::stash_pop(repository)?;
+
+    if !merged.is_clean() {
+        println!("The stash conflicts with the HEAD and has been kept. Conflicting paths:");
+        for path in &merged.conflicts {
+            println!("\t{}", path.display());
+        }
+    }
+
+    Ok(())
+}
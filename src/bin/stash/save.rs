@@ -0,0 +1,30 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::history::stash;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("save")
+        .about("Stash the tracked files of the worktree and check out the HEAD again.")
+        .arg(Arg::with_name("MESSAGE").index(1).help(
+            "A description of the stashed changes.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let message = match matches.value_of("MESSAGE") {
+        Some(message) => message.to_owned(),
+        None => format!("WIP on {}", repository.refs.resolve("HEAD")?),
+    };
+
+    match stash::stash_save(repository, message)? {
+        Some(stash_hash) => println!("Stashed changes as {}.", stash_hash),
+        None => println!("No changes to stash."),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::repository::Head;
+use attaca::store::{self, Statistics};
+use attaca::telemetry;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("stats")
+        .about(
+            "Summarize the local telemetry journal, or with --store, the local object store. \
+             Commands are only journaled when `telemetry = true` is set in the repository's \
+             config.",
+        )
+        .arg(
+            Arg::with_name("command")
+                .long("command")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Only summarize runs of the given command."),
+        )
+        .arg(Arg::with_name("clear").long("clear").help(
+            "Delete the journal instead of summarizing it.",
+        ))
+        .arg(
+            Arg::with_name("store")
+                .long("store")
+                .conflicts_with_all(&["command", "clear"])
+                .help(
+                    "Report object counts and sizes for the local store instead of \
+                     summarizing the journal.",
+                ),
+        )
+        .arg(
+            Arg::with_name("branch")
+                .long("branch")
+                .takes_value(true)
+                .value_name("BRANCH")
+                .requires("store")
+                .help(
+                    "Also report the size and deduplication ratio of a branch. Defaults to the \
+                     currently checked out branch.",
+                ),
+        )
+}
+
+
+fn store_stats(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let branch_opt = match (matches.value_of("branch"), &repository.refs.head) {
+        (Some(branch), _) => Some(branch.to_owned()),
+        (None, &Head::LocalRef(ref branch)) => Some(branch.clone()),
+        (None, _) => None,
+    };
+    let commit_opt = match branch_opt {
+        Some(ref branch) => {
+            match repository.refs.branches.get(branch) {
+                Some(&commit_hash) => Some(commit_hash),
+                None => bail!("no such branch `{}`", branch),
+            }
+        }
+        None => None,
+    };
+    let version = repository.object_version;
+
+    let ctx = repository.local(())?;
+    let stats = ctx.store().stats()?;
+
+    println!("local store:");
+    println!("\tobjects: {}", stats.objects);
+    println!("\tstored bytes: {}", stats.stored_bytes);
+
+    if let (Some(branch), Some(commit_hash)) = (branch_opt, commit_opt) {
+        let branch_stats = store::branch_stats(ctx.store(), commit_hash, version).wait()?;
+
+        println!("branch `{}` ({}):", branch, commit_hash);
+        println!("\tobjects: {}", branch_stats.objects);
+        println!("\tstored bytes: {}", branch_stats.stored_bytes);
+        println!("\tlogical bytes: {}", branch_stats.logical_bytes);
+
+        if let Some(ratio) = branch_stats.dedup_ratio() {
+            println!("\tdeduplication ratio: {:.2}x", ratio);
+        }
+    }
+
+    ctx.close().wait()?;
+
+    Ok(())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("store") {
+        return store_stats(repository, matches);
+    }
+
+    if matches.is_present("clear") {
+        telemetry::clear(&repository.paths)?;
+        return Ok(());
+    }
+
+    if !repository.config.telemetry {
+        eprintln!("Telemetry is disabled; set `telemetry = true` in the config to record it.");
+    }
+
+    let events = telemetry::events(&repository.paths)?;
+    let filtered = events.iter().filter(|event| {
+        matches.value_of("command").map_or(
+            true,
+            |command| event.command == command,
+        )
+    });
+
+    for (command, summary) in telemetry::summarize(filtered) {
+        println!("{}:", command);
+        println!(
+            "\truns: {} ({} failed)",
+            summary.runs,
+            summary.failures
+        );
+        println!(
+            "\tduration: {} ms mean, {} ms max",
+            summary.mean_ms(),
+            summary.max_ms
+        );
+        println!(
+            "\tbytes: {} split, {} fetched, {} sent",
+            summary.totals.bytes_split,
+            summary.totals.bytes_fetched,
+            summary.totals.bytes_sent
+        );
+        println!("\tobjects written: {}", summary.totals.objects_written);
+
+        if let Some(rate) = summary.cache_hit_rate() {
+            println!(
+                "\tcache hit rate: {:.1}% of {} reads",
+                rate * 100.0,
+                summary.totals.cache_hits + summary.totals.cache_misses
+            );
+        }
+
+        if let Some(rate) = summary.hash_cache_hit_rate() {
+            println!(
+                "\thash cache hit rate: {:.1}% of {} files",
+                rate * 100.0,
+                summary.totals.hash_cache_hits + summary.totals.hash_cache_misses
+            );
+        }
+    }
+
+    Ok(())
+}
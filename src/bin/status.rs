@@ -1,25 +1,169 @@
-use clap::{App, SubCommand, ArgMatches};
+use clap::{App, Arg, SubCommand, ArgMatches};
+use futures::prelude::*;
 
 use attaca::Repository;
-use attaca::index::Cached;
+use attaca::daemon::DaemonClient;
+use attaca::history;
+use attaca::history::conflict::ConflictState;
+use attaca::index::{Cached, Hygiene};
+use attaca::marshal::ObjectHash;
+use attaca::repository::{Head, RefName};
 use errors::*;
 
 
 pub fn command() -> App<'static, 'static> {
-    SubCommand::with_name("status").about("Show repository status, including tracked/added files.")
+    SubCommand::with_name("status")
+        .about("Show repository status, including tracked/added files.")
+        .arg(Arg::with_name("porcelain").long("porcelain").help(
+            "Print one `<kind> <hygiene> <state> <path>` line per tracked or added file, where \
+             `<kind>` is `tracked` or `added`, `<hygiene>` is `clean`, `dodgy` or `dirty`, and \
+             `<state>` is a full hash, `unhashed` or `removed`. Nothing else is printed.",
+        ))
 }
 
 
-pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
-    repository.index.update()?;
+/// How far the commits `local` and `upstream` have diverged: the number of commits only behind
+/// `local`, and the number only behind `upstream`.
+pub fn divergence(
+    repository: &mut Repository,
+    local: ObjectHash,
+    upstream: ObjectHash,
+) -> Result<(usize, usize)> {
+    let shallow = repository.shallow.clone();
+    let ctx = repository.local(())?;
+    let divergence = history::divergence(ctx.store(), local, upstream, &shallow).wait()?;
+    ctx.close().wait()?;
+
+    Ok(divergence)
+}
+
+
+fn commits(n: usize) -> String {
+    if n == 1 {
+        "1 commit".to_owned()
+    } else {
+        format!("{} commits", n)
+    }
+}
+
+
+/// Print how the commit `local` stands relative to the remote-tracking branch `upstream`.
+fn print_divergence(
+    repository: &mut Repository,
+    local: ObjectHash,
+    upstream: &RefName,
+) -> Result<()> {
+    let upstream_hash = match repository.refs.get(upstream) {
+        Some(upstream_hash) => upstream_hash,
+        None => bail!(::attaca::ErrorKind::RevisionNotFound(upstream.to_string())),
+    };
+
+    match divergence(repository, local, upstream_hash)? {
+        (0, 0) => println!("Up to date with {}.", upstream),
+        (ahead, 0) => println!("Ahead of {} by {}.", upstream, commits(ahead)),
+        (0, behind) => {
+            println!("Behind {} by {}; pull to fast-forward.", upstream, commits(behind))
+        }
+        (ahead, behind) => {
+            println!(
+                "Diverged from {}: {} ahead and {} behind; pull to merge.",
+                upstream,
+                commits(ahead),
+                commits(behind)
+            )
+        }
+    }
+
+    Ok(())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let entries = match DaemonClient::connect(&repository.paths) {
+        Some(mut client) => client.status()?,
+        None => {
+            repository.index.update()?;
+            repository
+                .index
+                .iter()
+                .map(|(path, entry)| (path.to_owned(), *entry))
+                .collect()
+        }
+    };
+
+    if matches.is_present("porcelain") {
+        let mut entries = entries
+            .into_iter()
+            .filter(|&(_, ref entry)| entry.tracked || entry.added)
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+        for (path, entry) in entries {
+            let kind = if entry.tracked { "tracked" } else { "added" };
+            let hygiene = match entry.hygiene {
+                Hygiene::Clean => "clean",
+                Hygiene::Dodgy => "dodgy",
+                Hygiene::Dirty => "dirty",
+            };
+            let state = match entry.cached {
+                Cached::Hashed(hashed, _) => hashed.to_string(),
+                Cached::Unhashed => "unhashed".to_owned(),
+                Cached::Removed => "removed".to_owned(),
+            };
+
+            println!("{} {} {} {}", kind, hygiene, state, path.display());
+        }
+
+        return Ok(());
+    }
+
+    match repository.refs.head {
+        Head::LocalRef(ref branch) => println!("On branch {}.", branch),
+        Head::RemoteRef(ref remote, ref branch) => println!("On {}/{}.", remote, branch),
+        Head::Detached(hash) => println!("HEAD detached at {}.", hash),
+        Head::Root => println!("No commits yet."),
+    }
+
+    let tracking = match repository.refs.head {
+        Head::LocalRef(ref branch) => {
+            repository.upstream(branch).and_then(|upstream| {
+                repository.refs.branches.get(branch).map(|&local| (local, upstream))
+            })
+        }
+        _ => None,
+    };
+    if let Some((local, upstream)) = tracking {
+        if let Err(error) = print_divergence(repository, local, &upstream) {
+            println!("Could not compare with {}: {}", upstream, error);
+        }
+    }
+
+    if let Some(state) = ConflictState::open(&repository.paths)? {
+        let unresolved = state.unresolved();
+        if !unresolved.is_empty() {
+            println!("Unresolved conflicts (see `attaca merge --list`):");
+
+            for path in unresolved {
+                println!("\t{}", path.display());
+            }
+        }
+    }
 
     let catalog = repository.catalogs.get(None)?;
     println!("{} local objects.", catalog.len());
 
+    if let Some(sparse) = repository.index.sparse() {
+        println!("Sparse checkout of:");
+
+        for pattern in sparse.patterns() {
+            println!("\t{}", pattern);
+        }
+    }
+
     let mut added = Vec::new();
     let mut tracked = Vec::new();
 
-    for (path, entry) in repository.index.iter() {
+    for (path, entry) in entries {
         if entry.tracked {
             tracked.push((path, entry));
         } else if entry.added {
@@ -27,8 +171,8 @@ pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
         }
     }
 
-    added.sort_unstable_by_key(|&(path, _)| path);
-    tracked.sort_unstable_by_key(|&(path, _)| path);
+    added.sort_unstable_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+    tracked.sort_unstable_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
 
     println!("Tracked:");
 
@@ -0,0 +1,112 @@
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::repository::Head;
+use attaca::subrepository::{self, Subrepository};
+
+use errors::*;
+use subrepo::update;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("add")
+        .about(
+            "Nest a repository at a path, cloning it there if need be, and commit a record of \
+             the commit it is checked out at. Run again to record a new commit of it.",
+        )
+        .arg(
+            Arg::with_name("URL")
+                .index(1)
+                .required(true)
+                .help(
+                    "The repository to clone from: the path of its working directory, relative \
+                     to the root of this one.",
+                ),
+        )
+        .arg(
+            Arg::with_name("PATH")
+                .index(2)
+                .required(true)
+                .help("Where to nest the repository, relative to the root of this one."),
+        )
+        .arg(
+            Arg::with_name("rev")
+                .long("rev")
+                .takes_value(true)
+                .value_name("REV")
+                .help(
+                    "The branch or commit of the cloned repository to check out. Defaults to \
+                     the HEAD of the nested repository if it is already cloned, and to the HEAD \
+                     of the repository cloned from otherwise.",
+                ),
+        )
+        .arg(
+            Arg::with_name("message")
+                .short("m")
+                .long("message")
+                .takes_value(true)
+                .help("The message of the commit recording the nested repository."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let url = matches.value_of("URL").unwrap().to_owned();
+    let path = PathBuf::from(matches.value_of("PATH").unwrap());
+
+    let nested_head = subrepository::open(repository, &path)?.refs.head();
+    let commit_hash = match (matches.value_of("rev"), nested_head) {
+        (None, Some(nested_head)) => nested_head,
+        (rev_opt, _) => {
+            let source = subrepository::source_path(&repository.paths.base, &url);
+            let source = Repository::load(source)?;
+            source.refs.resolve(rev_opt.unwrap_or("HEAD"))?
+        }
+    };
+
+    let subrepository = Subrepository {
+        path,
+        url,
+        commit_hash,
+    };
+    update::update(repository, &subrepository, false)?;
+
+    let message = matches.value_of("message").map(ToOwned::to_owned).unwrap_or_else(|| {
+        format!(
+            "Nest {} at {}",
+            subrepository.url,
+            subrepository.path.display()
+        )
+    });
+
+    let head_opt = repository.refs.head();
+    let new_head = {
+        let ctx = repository.local(())?;
+        let new_head = subrepository::record(
+            ctx.store(),
+            &ctx.marshaller(),
+            head_opt,
+            subrepository,
+            message,
+            Utc::now(),
+        ).wait()?;
+        ctx.close().wait()?;
+
+        new_head
+    };
+
+    match repository.refs.head {
+        Head::LocalRef(ref branch) => {
+            repository.refs.branches.insert(branch.clone(), new_head);
+        }
+        _ => repository.refs.head = Head::Detached(new_head),
+    }
+
+    println!("Recorded as {}.", new_head);
+
+    Ok(())
+}
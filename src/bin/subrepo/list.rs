@@ -0,0 +1,41 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::subrepository;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("list")
+        .about("List the repositories nested in a commit.")
+        .arg(Arg::with_name("REV").index(1).help(
+            "The commit whose nested repositories to list. Defaults to the HEAD.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap_or("HEAD"))?;
+
+    let mut subrepositories = {
+        let ctx = repository.local(())?;
+        let subrepositories = subrepository::list(ctx.store(), commit_hash).wait()?;
+        ctx.close().wait()?;
+
+        subrepositories
+    };
+    subrepositories.sort_by(|a, b| a.path.cmp(&b.path));
+
+    for subrepository in subrepositories {
+        println!(
+            "{} {} {}",
+            subrepository.commit_hash,
+            subrepository.path.display(),
+            subrepository.url
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+pub mod add;
+pub mod list;
+pub mod update;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("subrepo")
+        .about("Nest other repositories inside this one, each checked out at a recorded commit.")
+        .subcommand(add::command())
+        .subcommand(list::command())
+        .subcommand(update::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("add", Some(sub_m)) => add::go(repository, sub_m),
+        ("list", Some(sub_m)) => list::go(repository, sub_m),
+        ("update", Some(sub_m)) => update::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
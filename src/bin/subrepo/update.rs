@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::checkout::CheckoutOptions;
+use attaca::repository::Head;
+use attaca::subrepository::{self, Subrepository};
+
+use checkout;
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("update")
+        .about(
+            "Clone any nested repository which is missing, and check each out at the commit \
+             recorded in the HEAD.",
+        )
+        .arg(Arg::with_name("PATH").index(1).multiple(true).help(
+            "Only update the repositories nested at these paths. Defaults to all of them.",
+        ))
+        .arg(Arg::with_name("recursive").long("recursive").help(
+            "Also update the repositories nested inside each nested repository, and so on.",
+        ))
+}
+
+
+/// Bring the repository nested at `subrepository.path` to the commit recorded for it, cloning it
+/// first if it is not there, and then do the same for its own nested repositories if
+/// `recursive` is given.
+pub fn update(
+    repository: &mut Repository,
+    subrepository: &Subrepository,
+    recursive: bool,
+) -> Result<()> {
+    let source = subrepository::source_path(&repository.paths.base, &subrepository.url);
+    let mut nested = subrepository::open(repository, &subrepository.path)?;
+
+    let copied = subrepository::fetch(&mut nested, &source, subrepository.commit_hash)?;
+    if copied > 0 {
+        println!(
+            "Copied {} objects into {}.",
+            copied,
+            subrepository.path.display()
+        );
+    }
+
+    if nested.refs.head() != Some(subrepository.commit_hash) {
+        let sparse = nested.index.sparse().cloned();
+        let mut options = CheckoutOptions::default();
+        options.filter = sparse.as_ref().map(|sparse| sparse.globset().clone());
+
+        checkout::check_out(&mut nested, subrepository.commit_hash, &options, sparse)?;
+        nested.refs.head = Head::Detached(subrepository.commit_hash);
+        println!(
+            "Checked out {} at {}.",
+            subrepository.path.display(),
+            subrepository.commit_hash
+        );
+    }
+
+    if recursive {
+        let inner = {
+            let ctx = nested.local(())?;
+            let inner = subrepository::list(ctx.store(), subrepository.commit_hash).wait()?;
+            ctx.close().wait()?;
+
+            inner
+        };
+
+        for inner_subrepository in inner {
+            update(&mut nested, &inner_subrepository, recursive).chain_err(|| {
+                format!(
+                    "While updating {}",
+                    subrepository.path.join(&inner_subrepository.path).display()
+                )
+            })?;
+        }
+    }
+
+    nested.cleanup()?;
+
+    Ok(())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let head = match repository.refs.head() {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+
+    let subrepositories = {
+        let ctx = repository.local(())?;
+        let subrepositories = subrepository::list(ctx.store(), head).wait()?;
+        ctx.close().wait()?;
+
+        subrepositories
+    };
+
+    let paths = matches.values_of("PATH").map(|paths| paths.map(Path::new).collect::<Vec<_>>());
+    let recursive = matches.is_present("recursive");
+
+    for subrepository in subrepositories {
+        let selected = paths.as_ref().map_or(true, |paths| {
+            paths.iter().any(|path| *path == subrepository.path)
+        });
+
+        if selected {
+            update(repository, &subrepository, recursive)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::history::subtree;
+use attaca::repository::Head;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("join")
+        .about(
+            "Merge another history into this one, placing its contents under a directory.",
+        )
+        .arg(
+            Arg::with_name("COMMIT")
+                .index(1)
+                .required(true)
+                .help("The commit (or branch) whose history is to be joined."),
+        )
+        .arg(
+            Arg::with_name("PATH")
+                .index(2)
+                .required(true)
+                .help("The directory to place the joined contents under."),
+        )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .value_name("REPOSITORY")
+                .help(
+                    "Read the commit and its history from another local repository, copying \
+                     its objects into this one.",
+                ),
+        )
+        .arg(
+            Arg::with_name("message")
+                .short("m")
+                .long("message")
+                .takes_value(true)
+                .help("The message of the merge commit."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_str = matches.value_of("COMMIT").unwrap();
+    let prefix = PathBuf::from(matches.value_of("PATH").unwrap());
+
+    let other = match matches.value_of("from") {
+        Some(from) => {
+            let mut source = Repository::load(from)?;
+            let other = match source.refs.branches.get(commit_str) {
+                Some(&hash) => hash,
+                None => commit_str.parse()?,
+            };
+
+            {
+                let source_ctx = source.local(())?;
+                let ctx = repository.local(())?;
+                let copied = subtree::copy_history(source_ctx.store(), ctx.store(), other)
+                    .wait()?;
+                println!("Copied {} objects.", copied);
+
+                ctx.close().wait()?;
+                source_ctx.close().wait()?;
+            }
+
+            source.cleanup()?;
+            other
+        }
+        None => {
+            match repository.refs.branches.get(commit_str) {
+                Some(&hash) => hash,
+                None => commit_str.parse()?,
+            }
+        }
+    };
+
+    let message = matches.value_of("message").map(ToOwned::to_owned).unwrap_or_else(|| {
+        format!("Join {} into {}", other, prefix.display())
+    });
+
+    let head_opt = repository.refs.head();
+    let commit_hash = {
+        let ctx = repository.local(())?;
+        let commit_hash = subtree::join(
+            ctx.store(),
+            &ctx.marshaller(),
+            head_opt,
+            other,
+            prefix,
+            message,
+            Utc::now(),
+        ).wait()?;
+        ctx.close().wait()?;
+
+        commit_hash
+    };
+
+    match repository.refs.head {
+        Head::LocalRef(ref branch) => {
+            repository.refs.branches.insert(branch.clone(), commit_hash);
+        }
+        _ => repository.refs.head = Head::Detached(commit_hash),
+    }
+
+    println!("Joined as {}.", commit_hash);
+
+    Ok(())
+}
@@ -0,0 +1,27 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+pub mod join;
+pub mod split;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("subtree")
+        .about("Split a directory's history out, or join another history in under a directory.")
+        .subcommand(join::command())
+        .subcommand(split::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("join", Some(sub_m)) => join::go(repository, sub_m),
+        ("split", Some(sub_m)) => split::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
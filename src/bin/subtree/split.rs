@@ -0,0 +1,61 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::history::subtree;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("split")
+        .about(
+            "Extract the history of a directory into its own branch, with the directory as root.",
+        )
+        .arg(
+            Arg::with_name("PATH")
+                .index(1)
+                .required(true)
+                .help("The directory to split out."),
+        )
+        .arg(
+            Arg::with_name("into")
+                .long("into")
+                .takes_value(true)
+                .required(true)
+                .value_name("BRANCH")
+                .help("The branch to create or update with the split history."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let prefix = PathBuf::from(matches.value_of("PATH").unwrap());
+    let branch = matches.value_of("into").unwrap().to_owned();
+
+    let head = match repository.refs.head() {
+        Some(head) => head,
+        None => bail!("nothing to split; there are no commits yet"),
+    };
+
+    let split_opt = {
+        let ctx = repository.local(())?;
+        let split_opt = subtree::split(ctx.store(), &ctx.marshaller(), head, prefix.clone())
+            .wait()?;
+        ctx.close().wait()?;
+
+        split_opt
+    };
+
+    match split_opt {
+        Some(split_hash) => {
+            repository.refs.branches.insert(branch.clone(), split_hash);
+            println!("{} -> {}", branch, split_hash);
+
+            Ok(())
+        }
+        None => bail!("{} does not exist in any commit", prefix.display()),
+    }
+}
@@ -0,0 +1,145 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use attaca::Repository;
+use attaca::export::sync_to::{self, DirectoryTarget, Synced};
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Bring a directory up to date with a commit, writing only the files which changed and sending only
+the chunks the directory does not already hold. The directory records the commit it holds in
+`.attaca-sync`, so that the next sync knows what is there; it should not be changed by anything but
+`sync-to`.
+
+The target is either a local directory or `[USER@]HOST:PATH`, which is synced over SSH,
+authenticating with the SSH agent. The remote host needs nothing but a POSIX shell.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("sync-to")
+        .about("Sync a commit to a directory, here or over SSH, sending only changed chunks.")
+        .after_help(HELP_STR)
+        .arg(
+            Arg::with_name("REV")
+                .index(1)
+                .required(true)
+                .help("The revision to sync: `HEAD`, a branch, or a commit hash."),
+        )
+        .arg(
+            Arg::with_name("TARGET")
+                .index(2)
+                .required(true)
+                .help("The directory to sync into: a local path, or `[USER@]HOST:PATH`."),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .takes_value(true)
+                .help("The port to connect to SSH on. Defaults to 22."),
+        )
+}
+
+
+/// Split an SSH target into its user, host and path, if it is one. A target is taken to be a
+/// local path if a `/` comes before any `:`.
+fn parse_ssh_target(target: &str) -> Option<(Option<&str>, &str, &str)> {
+    let colon = match target.find(':') {
+        Some(colon) => colon,
+        None => return None,
+    };
+    if target[..colon].contains('/') {
+        return None;
+    }
+
+    let (user_host, path) = (&target[..colon], &target[colon + 1..]);
+    match user_host.find('@') {
+        Some(at) => Some((Some(&user_host[..at]), &user_host[at + 1..], path)),
+        None => Some((None, user_host, path)),
+    }
+}
+
+
+#[cfg(feature = "ssh")]
+fn sync_over_ssh<F>(
+    user: Option<&str>,
+    host: &str,
+    path: &str,
+    matches: &ArgMatches,
+    sync: F,
+) -> Result<Synced>
+where
+    F: FnOnce(&mut sync_to::SshTarget) -> Result<Synced>,
+{
+    use std::env;
+
+    let port = if matches.is_present("port") {
+        value_t!(matches.value_of("port"), u16)?
+    } else {
+        22
+    };
+    let user = match user {
+        Some(user) => user.to_owned(),
+        None => env::var("USER").chain_err(|| "no user given, and $USER is not set")?,
+    };
+    let path = if path.is_empty() { "." } else { path };
+
+    let mut target = sync_to::SshTarget::connect(host, port, &user, path)?;
+    sync(&mut target)
+}
+
+
+#[cfg(not(feature = "ssh"))]
+fn sync_over_ssh<F>(
+    _user: Option<&str>,
+    _host: &str,
+    _path: &str,
+    _matches: &ArgMatches,
+    _sync: F,
+) -> Result<Synced> {
+    bail!(::attaca::ErrorKind::BackendNotCompiled("ssh".to_owned(), "ssh".to_owned()))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap())?;
+    let target = matches.value_of("TARGET").unwrap();
+
+    let synced = {
+        let ctx = repository.local(())?;
+        let subtree_hash = ctx.read_commit(commit_hash).wait()?.subtree;
+
+        let synced = match parse_ssh_target(target) {
+            Some((user, host, path)) => {
+                sync_over_ssh(user, host, path, matches, |ssh_target| {
+                    Ok(sync_to::sync_to(ctx.store(), commit_hash, subtree_hash, ssh_target)?)
+                })?
+            }
+            None => {
+                let mut directory_target = DirectoryTarget::new(target)?;
+                sync_to::sync_to(ctx.store(), commit_hash, subtree_hash, &mut directory_target)?
+            }
+        };
+        ctx.close().wait()?;
+
+        synced
+    };
+
+    eprintln!(
+        "Synced {} to {}: {} files written, {} unchanged, {} removed; {} bytes sent, {} bytes \
+         reused from files already there.",
+        commit_hash,
+        target,
+        synced.written,
+        synced.unchanged,
+        synced.removed,
+        synced.bytes_sent,
+        synced.bytes_reused
+    );
+
+    Ok(())
+}
+
@@ -0,0 +1,61 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("tag")
+        .about("List, create, or delete tags: names for commits which never move.")
+        .arg(Arg::with_name("NAME").index(1).help(
+            "The tag to create or delete. Tags are listed if no name is given.",
+        ))
+        .arg(Arg::with_name("REV").index(2).requires("NAME").help(
+            "The branch or commit to tag. Defaults to the HEAD.",
+        ))
+        .arg(
+            Arg::with_name("delete")
+                .short("d")
+                .long("delete")
+                .requires("NAME")
+                .conflicts_with("REV")
+                .help("Delete the tag NAME instead."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let name = match matches.value_of("NAME") {
+        Some(name) => name,
+        None => {
+            let mut tags = repository.refs.tags.iter().collect::<Vec<_>>();
+            tags.sort_by(|a, b| a.0.cmp(b.0));
+
+            for (tag, hash) in tags {
+                println!("{} {}", tag, hash);
+            }
+
+            return Ok(());
+        }
+    };
+
+    if matches.is_present("delete") {
+        match repository.refs.tags.remove(name) {
+            Some(hash) => println!("Deleted tag {} (was {}).", name, hash),
+            None => bail!("no such tag `{}`", name),
+        }
+
+        return Ok(());
+    }
+
+    if repository.refs.tags.contains_key(name) {
+        bail!("tag `{}` already exists; delete it first to move it", name);
+    }
+
+    let commit_hash = repository.refs.resolve(matches.value_of("REV").unwrap_or("HEAD"))?;
+    repository.refs.tags.insert(name.to_owned(), commit_hash);
+    println!("Created tag {} at {}.", name, commit_hash);
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use clap::{App, SubCommand, Arg, ArgMatches};
+
+use attaca::dataset::{self, DatasetSpec};
+
+use errors::*;
+
+
+const HELP_STR: &'static str = r#"
+Generate a synthetic dataset, for benchmarks and round-trip tests to run against. The same options
+always generate the same dataset, byte for byte, so a result can be reproduced by anyone given the
+command line which generated its input.
+
+Sizes are given in bytes, optionally suffixed with K, M, G or T. A size distribution is a single
+size (`1M`), a uniform range (`4K-16M`), or a log-normal distribution given by its median and shape
+(`lognormal:64K:2`), which has many small files and a long tail of large ones, as most real
+datasets do.
+"#;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("gen-dataset")
+        .about("Generate a reproducible synthetic dataset.")
+        .after_help(HELP_STR)
+        .arg(
+            Arg::with_name("DIR")
+                .index(1)
+                .required(true)
+                .help("The directory to generate the dataset in. Created if it does not exist."),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("The seed every choice is drawn from. Defaults to 0."),
+        )
+        .arg(
+            Arg::with_name("files")
+                .short("n")
+                .long("files")
+                .takes_value(true)
+                .help("The number of files to generate. Defaults to 1000."),
+        )
+        .arg(
+            Arg::with_name("sizes")
+                .long("sizes")
+                .takes_value(true)
+                .value_name("DISTRIBUTION")
+                .help("How file sizes are distributed. Defaults to `lognormal:64K:2`."),
+        )
+        .arg(
+            Arg::with_name("max-size")
+                .long("max-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("The size no file is made larger than. Defaults to 256M."),
+        )
+        .arg(
+            Arg::with_name("duplicates")
+                .long("duplicates")
+                .takes_value(true)
+                .value_name("FRACTION")
+                .help("The fraction of files which copy an earlier file. Defaults to 0.1."),
+        )
+        .arg(
+            Arg::with_name("near-duplicates")
+                .long("near-duplicates")
+                .takes_value(true)
+                .value_name("FRACTION")
+                .help(
+                    "The fraction of files which copy an earlier file with a small region \
+                     rewritten. Defaults to 0.1.",
+                ),
+        )
+        .arg(
+            Arg::with_name("text")
+                .long("text")
+                .takes_value(true)
+                .value_name("FRACTION")
+                .help("The fraction of files which are text. Defaults to 0.2."),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .help("How many levels of directories to spread files over. Defaults to 2."),
+        )
+        .arg(
+            Arg::with_name("fanout")
+                .long("fanout")
+                .takes_value(true)
+                .help("How many subdirectories each directory has. Defaults to 8."),
+        )
+}
+
+
+pub fn go(matches: &ArgMatches) -> Result<()> {
+    let mut spec = DatasetSpec::default();
+
+    if matches.is_present("seed") {
+        spec.seed = value_t!(matches.value_of("seed"), u64)?;
+    }
+    if matches.is_present("files") {
+        spec.files = value_t!(matches.value_of("files"), usize)?;
+    }
+    if let Some(sizes) = matches.value_of("sizes") {
+        spec.sizes = sizes.parse()?;
+    }
+    if let Some(max_size) = matches.value_of("max-size") {
+        spec.max_size = dataset::parse_size(max_size)?;
+    }
+    if matches.is_present("duplicates") {
+        spec.duplicates = value_t!(matches.value_of("duplicates"), f64)?;
+    }
+    if matches.is_present("near-duplicates") {
+        spec.near_duplicates = value_t!(matches.value_of("near-duplicates"), f64)?;
+    }
+    if matches.is_present("text") {
+        spec.text = value_t!(matches.value_of("text"), f64)?;
+    }
+    if matches.is_present("depth") {
+        spec.depth = value_t!(matches.value_of("depth"), usize)?;
+    }
+    if matches.is_present("fanout") {
+        spec.fanout = value_t!(matches.value_of("fanout"), usize)?;
+    }
+
+    if spec.duplicates + spec.near_duplicates > 1.0 {
+        bail!("at most all of the files may be duplicates");
+    }
+
+    let generated = dataset::generate(&spec, matches.value_of("DIR").unwrap())?;
+
+    println!(
+        "Generated {} files, {} bytes, of which {} bytes are exact duplicates and {} bytes near \
+         duplicates.",
+        generated.files,
+        generated.bytes,
+        generated.duplicate_bytes,
+        generated.near_duplicate_bytes
+    );
+    println!(
+        "Spec: --seed {} --files {} --sizes {} --max-size {} --duplicates {} --near-duplicates {} \
+         --text {} --depth {} --fanout {}",
+        spec.seed,
+        spec.files,
+        spec.sizes,
+        spec.max_size,
+        spec.duplicates,
+        spec.near_duplicates,
+        spec.text,
+        spec.depth,
+        spec.fanout
+    );
+
+    Ok(())
+}
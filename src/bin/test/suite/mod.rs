@@ -1,3 +1,5 @@
+mod gen_dataset;
+mod report;
 mod write_all;
 
 use std::env;
@@ -11,6 +13,8 @@ use attaca::Repository;
 
 use errors::*;
 
+use self::report::Report;
+
 
 const HELP_STR: &'static str = r#"
 Run a full test with a remote.
@@ -51,10 +55,16 @@ pub fn command() -> App<'static, 'static> {
         .arg(Arg::with_name("no-takedown")
              .long("no-takedown")
              .help("Do not run the 'stop.sh' script, leaving a RADOS container running."))
+        .arg(Arg::with_name("report")
+             .long("report")
+             .takes_value(true)
+             .value_name("REPORT_PATH")
+             .help("Write a machine-readable JSON report of the suite run to the given path."))
         .subcommand(SubCommand::with_name("noop").about(
             "Test the test suite infrastructure. I.S.M.E.T.A.",
         ))
         .subcommand(write_all::command())
+        .subcommand(gen_dataset::command())
 }
 
 
@@ -146,48 +156,85 @@ fn run_takedown<P: AsRef<Path>>(ir_path: P) -> Result<()> {
 
 
 pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    // Generating a dataset needs no cluster, so there is nothing to set up or take down.
+    if let ("gen-dataset", Some(sub_m)) = matches.subcommand() {
+        return gen_dataset::go(sub_m);
+    }
+
     if let (subcmd, Some(sub_m)) = matches.subcommand() {
         let ir_path = matches
             .value_of("fetch-tools")
             .map(PathBuf::from)
             .or_else(|| matches.value_of("with-tools").map(PathBuf::from))
             .unwrap_or_else(|| env::current_dir().unwrap().join("insta-rados"));
-
-        if matches.is_present("fetch-tools") {
-            fetch_tools(&ir_path)?;
-        }
-
-        if !matches.is_present("no-setup") {
-            println!("Running setup...");
-
-            run_setup(&ir_path)?;
-
-            println!("Setup finished.");
-        }
-
         let ceph_path = ir_path.join("ceph");
 
-        println!(
-            "Running test suite: '{}', with ceph directory '{}'.",
-            subcmd,
-            ceph_path.display()
-        );
-
-        let result = match subcmd {
-            "noop" => Ok(()),
-            "write_all" => write_all::go(repository, &ceph_path, sub_m),
-            _ => unreachable!("Invalid subcommand {}", subcmd),
-        }.chain_err(|| format!("run test suite '{}'", subcmd));
+        let mut report = Report::new(subcmd);
+
+        let setup_result = {
+            let fetch_result = if matches.is_present("fetch-tools") {
+                report.stage("fetch", || {
+                    fetch_tools(&ir_path)?;
+                    Ok(vec![ir_path.clone()])
+                })
+            } else {
+                report.skip("fetch");
+                Ok(())
+            };
+
+            if fetch_result.is_err() {
+                report.skip("setup");
+                fetch_result
+            } else if !matches.is_present("no-setup") {
+                report.stage("setup", || {
+                    run_setup(&ir_path)?;
+                    Ok(vec![ceph_path.clone()])
+                })
+            } else {
+                report.skip("setup");
+                Ok(())
+            }
+        };
+
+        if setup_result.is_ok() {
+            println!(
+                "Running test suite: '{}', with ceph directory '{}'.",
+                subcmd,
+                ceph_path.display()
+            );
+
+            let _ = report.stage("test", || match subcmd {
+                "noop" => Ok(Vec::new()),
+                "write_all" => {
+                    write_all::go(repository, &ceph_path, sub_m)
+                        .map(|()| vec![ceph_path.join("ceph.conf")])
+                }
+                _ => unreachable!("Invalid subcommand {}", subcmd),
+            });
+        } else {
+            report.skip("test");
+        }
 
         if !matches.is_present("no-takedown") {
-            println!("Running takedown...");
+            let _ = report.stage("takedown", || {
+                run_takedown(&ir_path)?;
+                Ok(Vec::new())
+            });
+        } else {
+            report.skip("takedown");
+        }
 
-            run_takedown(&ir_path)?;
+        report.print_summary();
 
-            println!("Takedown finished.");
+        if let Some(report_path) = matches.value_of("report") {
+            report.write_json(report_path)?;
         }
 
-        result
+        if report.passed {
+            Ok(())
+        } else {
+            bail!(ErrorKind::SuiteFailed(subcmd.to_owned()));
+        }
     } else {
         bail!(ErrorKind::InvalidUsage);
     }
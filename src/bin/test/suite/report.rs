@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+use serde_json;
+
+use errors::*;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+
+/// The outcome of a single stage of a suite run (setup, the test itself, or takedown.)
+#[derive(Debug, Clone, Serialize)]
+pub struct StageReport {
+    pub name: String,
+    pub status: Status,
+    pub duration_ms: u64,
+
+    /// The full chain of error messages, if the stage failed.
+    pub error: Option<String>,
+
+    /// Paths to any files or directories produced or used by the stage.
+    pub artifacts: Vec<PathBuf>,
+}
+
+
+/// A machine-readable report of a full suite run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub suite: String,
+    pub passed: bool,
+    pub duration_ms: u64,
+    pub stages: Vec<StageReport>,
+}
+
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+
+impl Report {
+    pub fn new<S: Into<String>>(suite: S) -> Self {
+        Report {
+            suite: suite.into(),
+            passed: true,
+            duration_ms: 0,
+            stages: Vec::new(),
+        }
+    }
+
+    /// Run a stage, recording its duration, any artifacts it produces, and whether or not it
+    /// succeeded. The result of the stage is passed through so that the caller may decide
+    /// whether or not to continue.
+    pub fn stage<F>(&mut self, name: &str, run: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Vec<PathBuf>>,
+    {
+        println!("Running stage '{}'...", name);
+
+        let start = Instant::now();
+        let result = run();
+        let duration_ms = millis(start.elapsed());
+
+        let stage_report = match result {
+            Ok(ref artifacts) => StageReport {
+                name: name.to_owned(),
+                status: Status::Passed,
+                duration_ms,
+                error: None,
+                artifacts: artifacts.clone(),
+            },
+            Err(ref error) => {
+                self.passed = false;
+
+                StageReport {
+                    name: name.to_owned(),
+                    status: Status::Failed,
+                    duration_ms,
+                    error: Some(error.iter().map(ToString::to_string).join(": ")),
+                    artifacts: Vec::new(),
+                }
+            }
+        };
+
+        println!(
+            "Stage '{}' {:?} in {} ms.",
+            name,
+            stage_report.status,
+            stage_report.duration_ms
+        );
+
+        self.duration_ms += duration_ms;
+        self.stages.push(stage_report);
+
+        result.map(|_| ())
+    }
+
+    /// Record that a stage was skipped.
+    pub fn skip(&mut self, name: &str) {
+        self.stages.push(StageReport {
+            name: name.to_owned(),
+            status: Status::Skipped,
+            duration_ms: 0,
+            error: None,
+            artifacts: Vec::new(),
+        });
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "Suite '{}': {} ({} ms)",
+            self.suite,
+            if self.passed { "PASSED" } else { "FAILED" },
+            self.duration_ms
+        );
+
+        for stage in &self.stages {
+            println!("\t{:<10} {:?} ({} ms)", stage.name, stage.status, stage.duration_ms);
+
+            if let Some(ref error) = stage.error {
+                println!("\t\t{}", error);
+            }
+
+            for artifact in &stage.artifacts {
+                println!("\t\tartifact: {}", artifact.display());
+            }
+        }
+    }
+
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = File::create(path.as_ref()).chain_err(|| {
+            format!("unable to create report file '{}'", path.as_ref().display())
+        })?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+
+        Ok(())
+    }
+}
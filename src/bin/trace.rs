@@ -5,6 +5,7 @@ use std::thread::{self, JoinHandle};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
 use attaca::marshal::ObjectHash;
+use attaca::progress::{self, ProgressReporter};
 use attaca::trace::Trace;
 
 
@@ -16,6 +17,9 @@ pub struct ProgressInner {
     written: u64,
     fresh: u64,
 
+    /// Blocklisted objects which were written anyways.
+    flagged: Vec<ObjectHash>,
+
     remote: Option<String>,
 
     split_progress: ProgressBar,
@@ -55,6 +59,17 @@ impl Drop for ProgressInner {
         self.split_progress.finish();
         self.write_progress.finish();
 
+        if !self.flagged.is_empty() {
+            eprintln!(
+                "Warning: {} blocklisted object(s) were written:",
+                self.flagged.len()
+            );
+
+            for object_hash in &self.flagged {
+                eprintln!("\t{}", object_hash);
+            }
+        }
+
         self.join_handle.take().map(
             |jh| { jh.join().unwrap().unwrap(); },
         );
@@ -65,6 +80,9 @@ impl Drop for ProgressInner {
 #[derive(Clone)]
 pub struct Progress {
     inner: Arc<Mutex<ProgressInner>>,
+
+    /// Where to report progress to the program running us, if anywhere.
+    reporter: Option<ProgressReporter>,
 }
 
 
@@ -95,6 +113,8 @@ impl Progress {
                 written: 0,
                 fresh: 0,
 
+                flagged: Vec::new(),
+
                 remote,
 
                 split_progress,
@@ -102,6 +122,7 @@ impl Progress {
 
                 join_handle,
             })),
+            reporter: progress::installed(),
         }
     }
 }
@@ -109,34 +130,54 @@ impl Progress {
 
 impl Trace for Progress {
     fn on_split_begin(&self, size: u64) {
+        if let Some(ref reporter) = self.reporter {
+            reporter.on_split_begin(size);
+        }
+
         let mut inner = self.inner.lock().unwrap();
 
         inner.to_split += size;
         inner.update_split_progress();
     }
 
-    fn on_split_chunk(&self, _offset: u64, chunk: &[u8]) {
+    fn on_split_chunk(&self, offset: u64, chunk: &[u8]) {
+        if let Some(ref reporter) = self.reporter {
+            reporter.on_split_chunk(offset, chunk);
+        }
+
         let mut inner = self.inner.lock().unwrap();
 
         inner.split += chunk.len() as u64;
         inner.update_split_progress();
     }
 
-    fn on_marshal_process(&self, _object_hash: &ObjectHash) {
+    fn on_marshal_process(&self, object_hash: &ObjectHash) {
+        if let Some(ref reporter) = self.reporter {
+            reporter.on_marshal_process(object_hash);
+        }
+
         let mut inner = self.inner.lock().unwrap();
 
         inner.object_count += 1;
         inner.update_write_progress();
     }
 
-    fn on_write_object_start(&self, _object_hash: &ObjectHash) {
+    fn on_write_object_start(&self, object_hash: &ObjectHash) {
+        if let Some(ref reporter) = self.reporter {
+            reporter.on_write_object_start(object_hash);
+        }
+
         let mut inner = self.inner.lock().unwrap();
 
         inner.in_flight += 1;
         inner.update_write_progress();
     }
 
-    fn on_write_object_finish(&self, _object_hash: &ObjectHash, fresh: bool) {
+    fn on_write_object_finish(&self, object_hash: &ObjectHash, fresh: bool) {
+        if let Some(ref reporter) = self.reporter {
+            reporter.on_write_object_finish(object_hash, fresh);
+        }
+
         let mut inner = self.inner.lock().unwrap();
 
         inner.in_flight -= 1;
@@ -146,4 +187,8 @@ impl Trace for Progress {
         inner.written += 1;
         inner.update_write_progress();
     }
+
+    fn on_blocked_object(&self, object_hash: &ObjectHash) {
+        self.inner.lock().unwrap().flagged.push(*object_hash);
+    }
 }
@@ -8,6 +8,7 @@ use errors::*;
 
 pub fn command() -> App<'static, 'static> {
     SubCommand::with_name("track")
+        .alias("add")
         .help("Begin tracking changes to files.")
         .arg(Arg::with_name("PATH").index(1).multiple(true))
 }
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches};
+
+use attaca::cancel::CancelToken;
+use attaca::dataset;
+use attaca::sync::{TransferOptions, DEFAULT_MAX_IN_FLIGHT};
+
+use errors::*;
+
+
+/// The arguments shared by every command which moves objects to or from a remote.
+pub fn args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("limit-rate")
+            .long("limit-rate")
+            .takes_value(true)
+            .value_name("RATE")
+            .help(
+                "Send or receive at most RATE bytes per second, optionally suffixed with K, M \
+                 or G.",
+            ),
+        Arg::with_name("max-in-flight")
+            .long("max-in-flight")
+            .takes_value(true)
+            .value_name("N")
+            .help("Send or receive at most N objects at once. Defaults to 16."),
+        Arg::with_name("timeout")
+            .long("timeout")
+            .takes_value(true)
+            .value_name("SECS")
+            .help(
+                "Give up if the transfer has not finished within SECS seconds. Trying again \
+                 carries on from where it stopped.",
+            ),
+    ]
+}
+
+
+pub fn options(matches: &ArgMatches) -> Result<TransferOptions> {
+    let limit_rate = match matches.value_of("limit-rate") {
+        Some(rate) => Some(dataset::parse_size(rate)?),
+        None => None,
+    };
+    let max_in_flight = if matches.is_present("max-in-flight") {
+        value_t!(matches.value_of("max-in-flight"), usize)?
+    } else {
+        DEFAULT_MAX_IN_FLIGHT
+    };
+    let cancel = if matches.is_present("timeout") {
+        let token = CancelToken::new();
+        token.cancel_after(Duration::from_secs(value_t!(matches.value_of("timeout"), u64)?));
+        Some(token)
+    } else {
+        None
+    };
+
+    Ok(TransferOptions {
+        limit_rate,
+        max_in_flight,
+        cancel,
+        ..TransferOptions::default()
+    })
+}
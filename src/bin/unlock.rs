@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::path_locks;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("unlock")
+        .about("Release locks on paths.")
+        .arg(
+            Arg::with_name("PATH")
+                .index(1)
+                .multiple(true)
+                .required(true)
+                .help("The paths to unlock, relative to the root of the repository."),
+        )
+        .arg(Arg::with_name("force").long("force").help(
+            "Release the locks even if they are held by someone else.",
+        ))
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let paths = matches
+        .values_of("PATH")
+        .unwrap()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+
+    path_locks::unlock(repository, &paths, matches.is_present("force"))?;
+    println!(
+        "Unlocked {} paths. Push `{}` to share the change.",
+        paths.len(),
+        path_locks::LOCKS_BRANCH
+    );
+
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::identity::{self, Role};
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("whoami").about(
+        "Show the identity and signing key new commits are made with, and where each comes from.",
+    )
+}
+
+
+pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
+    for &(label, role) in &[("Author", Role::Author), ("Committer", Role::Committer)] {
+        match identity::resolve(&repository.config, role) {
+            Some(resolved) => println!("{}: {} ({})", label, resolved.value, resolved.source),
+            None => println!("{}: none configured", label),
+        }
+    }
+
+    match identity::resolve_signing_key(&repository.config, &repository.paths) {
+        Some(resolved) => {
+            println!(
+                "Signing key: {} ({})",
+                resolved.value.display(),
+                resolved.source
+            );
+            if let Some(ref signing_key) = repository.signing_key {
+                println!("Public key: {}", signing_key.public_key());
+            }
+        }
+        None => println!("Signing key: none configured"),
+    }
+
+    Ok(())
+}
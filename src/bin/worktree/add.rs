@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::checkout::CheckoutOptions;
+use attaca::repository::Head;
+use attaca::worktree;
+
+use checkout;
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("add")
+        .about("Create a new working directory and check a commit out into it.")
+        .arg(
+            Arg::with_name("PATH")
+                .index(1)
+                .required(true)
+                .help("Where to create the new working directory."),
+        )
+        .arg(Arg::with_name("REV").index(2).help(
+            "The branch or commit to check out. Defaults to the HEAD.",
+        ))
+        .arg(
+            Arg::with_name("branch")
+                .short("b")
+                .long("branch")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Create a new branch NAME at REV, and check it out."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let path = Path::new(matches.value_of("PATH").unwrap());
+    let rev = matches.value_of("REV").unwrap_or("HEAD");
+    let commit_hash = repository.refs.resolve(rev)?;
+    let head = match matches.value_of("branch") {
+        Some(branch) => {
+            repository.compare_and_swap_branch(branch, None, commit_hash)?;
+            Head::LocalRef(branch.to_owned())
+        }
+        None if repository.refs.branches.contains_key(rev) => Head::LocalRef(rev.to_owned()),
+        None => Head::Detached(commit_hash),
+    };
+
+    let base = worktree::add(&repository.paths, path, head)?;
+
+    let mut linked = Repository::load(&base)?;
+    checkout::check_out(&mut linked, commit_hash, &CheckoutOptions::default(), None)?;
+    linked.cleanup()?;
+
+    println!("Checked out {} at {}.", commit_hash, base.display());
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::repository::Head;
+use attaca::worktree;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("list").about(
+        "List the working directories of this repository, and what each has checked out.",
+    )
+}
+
+
+pub fn go(repository: &mut Repository, _matches: &ArgMatches) -> Result<()> {
+    for worktree in worktree::list(&repository.paths)? {
+        let checked_out = match worktree.head {
+            Some(Head::LocalRef(branch)) => format!("[{}]", branch),
+            Some(Head::RemoteRef(remote, branch)) => format!("[{}/{}]", remote, branch),
+            Some(Head::Detached(hash)) => format!("{} (detached)", hash),
+            Some(Head::Root) => "(no commit)".to_owned(),
+            None => "(missing)".to_owned(),
+        };
+
+        println!("{} {}", worktree.path.display(), checked_out);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use clap::{App, ArgMatches, SubCommand};
+
+use attaca::Repository;
+
+use errors::*;
+
+pub mod add;
+pub mod list;
+pub mod remove;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("worktree")
+        .about("Check out more than one commit at once, in working directories sharing a store.")
+        .subcommand(add::command())
+        .subcommand(list::command())
+        .subcommand(remove::command())
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("add", Some(sub_m)) => add::go(repository, sub_m),
+        ("list", Some(sub_m)) => list::go(repository, sub_m),
+        ("remove", Some(sub_m)) => remove::go(repository, sub_m),
+        _ => {
+            bail!(ErrorKind::InvalidUsage);
+        }
+    }
+}
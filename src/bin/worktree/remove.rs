@@ -0,0 +1,35 @@
+use std::path::Path;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use attaca::Repository;
+use attaca::worktree;
+
+use errors::*;
+
+
+pub fn command() -> App<'static, 'static> {
+    SubCommand::with_name("remove")
+        .about(
+            "Detach a working directory from this repository. Its files are left in place, but \
+             anything added to its index and not committed is forgotten.",
+        )
+        .arg(
+            Arg::with_name("PATH")
+                .index(1)
+                .required(true)
+                .help("The working directory to detach."),
+        )
+}
+
+
+pub fn go(repository: &mut Repository, matches: &ArgMatches) -> Result<()> {
+    let path = Path::new(matches.value_of("PATH").unwrap());
+    if path.canonicalize().ok() == repository.paths.base.canonicalize().ok() {
+        bail!("cannot remove the working directory this command was run from");
+    }
+
+    worktree::remove(&repository.paths, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! # `blocklist` - refuse or flag known-bad objects.
+//!
+//! A repository may be configured with a blocklist: a file listing the hashes of chunks or files
+//! which are known to be bad (for example, the outputs of a faulty instrument.) Every object
+//! written through a `Context`, whether while committing locally or while sending objects to a
+//! remote, is checked against the blocklist. Depending on the configured `BlockAction`, a match
+//! either fails the write or is reported through `Trace::on_blocked_object`.
+//!
+//! The blocklist file contains one hex-encoded object hash per line. Blank lines and anything
+//! following a `#` are ignored.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use errors::*;
+use marshal::ObjectHash;
+
+
+/// What to do upon encountering a blocklisted object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockAction {
+    /// Fail the write with `ErrorKind::BlockedObject`.
+    Reject,
+
+    /// Write the object anyways, but report it.
+    Flag,
+}
+
+
+impl Default for BlockAction {
+    fn default() -> Self {
+        BlockAction::Reject
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    hashes: Arc<HashSet<ObjectHash>>,
+    action: BlockAction,
+}
+
+
+impl Blocklist {
+    /// Load a blocklist from a file.
+    pub fn open<P: AsRef<Path>>(path: P, action: BlockAction) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).chain_err(|| {
+            ErrorKind::BlocklistOpen(path.to_owned())
+        })?;
+
+        let mut hashes = HashSet::new();
+        for line_res in BufReader::new(file).lines() {
+            let line = line_res?;
+            let hash_str = line.split('#').next().unwrap().trim();
+
+            if !hash_str.is_empty() {
+                hashes.insert(hash_str.parse::<ObjectHash>().chain_err(|| {
+                    ErrorKind::BlocklistOpen(path.to_owned())
+                })?);
+            }
+        }
+
+        Ok(Blocklist {
+            hashes: Arc::new(hashes),
+            action,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn contains(&self, object_hash: &ObjectHash) -> bool {
+        self.hashes.contains(object_hash)
+    }
+
+    /// Check an object about to be written. Returns `Ok(true)` if the object is blocklisted but
+    /// should be written anyways, `Ok(false)` if it is not blocklisted, and an error if it is
+    /// blocklisted and should be rejected.
+    pub fn check(&self, object_hash: &ObjectHash) -> Result<bool> {
+        if !self.contains(object_hash) {
+            return Ok(false);
+        }
+
+        match self.action {
+            BlockAction::Reject => bail!(ErrorKind::BlockedObject(*object_hash)),
+            BlockAction::Flag => Ok(true),
+        }
+    }
+}
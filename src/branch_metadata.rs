@@ -0,0 +1,293 @@
+//! # `branch_metadata` - descriptions and other metadata attached to branches.
+//!
+//! A branch may be given a description, an owner, and any number of other fields, so that a
+//! repository holding many dataset branches can say what each of them is for. The metadata of a
+//! branch is kept as TOML, in a data object of its own.
+//!
+//! Like notes, branch metadata has a history of its own, on the branch `METADATA_BRANCH`. The
+//! subtree of each of its commits holds one file per described branch, named by the branch with
+//! any `/` escaped, so metadata is pushed and fetched along with the branches it describes and
+//! every earlier description is kept in its history.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use toml;
+
+use arc_slice;
+use checkout;
+use errors::*;
+use history::merge;
+use marshal::{ObjectHash, Object, CommitObject, DataObject, SmallObject, SubtreeObject,
+              SubtreeEntry};
+use marshal::canonical::Version;
+use repository::Repository;
+use store::ObjectStore;
+
+
+/// The branch holding the history of every branch's metadata.
+pub const METADATA_BRANCH: &str = "attaca-branch-metadata";
+
+
+/// What is known about a branch besides the commit it points at.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BranchMetadata {
+    /// What the branch is for, in as many lines as it takes.
+    #[serde(default)]
+    pub description: String,
+
+    /// Who to ask about the branch.
+    #[serde(default)]
+    pub owner: Option<String>,
+
+    /// Any other fields, such as where a dataset came from or which experiment it feeds.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+
+impl BranchMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.description.is_empty() && self.owner.is_none() && self.fields.is_empty()
+    }
+}
+
+
+/// The name of the file holding the metadata of `branch`. Branch names may contain `/`, which
+/// subtree entry names may not, so it is escaped as `%2F`, and `%` itself as `%25`.
+fn escape(branch: &str) -> String {
+    branch.replace('%', "%25").replace('/', "%2F")
+}
+
+
+/// The branch whose metadata is held in the file `name`, if `name` was made by `escape`.
+fn unescape(name: &str) -> Option<String> {
+    let mut branch = String::with_capacity(name.len());
+    let mut rest = name;
+
+    while let Some(i) = rest.find('%') {
+        branch.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 3) {
+            Some("25") => branch.push('%'),
+            Some("2F") => branch.push('/'),
+            _ => return None,
+        }
+        rest = &rest[i + 3..];
+    }
+    branch.push_str(rest);
+
+    Some(branch)
+}
+
+
+fn read_entries<S: ObjectStore>(
+    store: &S,
+    hash_opt: Option<ObjectHash>,
+) -> Box<Future<Item = BTreeMap<OsString, SubtreeEntry>, Error = Error> + Send> {
+    match hash_opt {
+        Some(hash) => {
+            Box::new(store.read_object(hash).and_then(move |object| match object {
+                Object::Subtree(subtree_object) => Ok(subtree_object.entries),
+                _ => bail!(ErrorKind::ObjectNotASubtree(hash)),
+            }))
+        }
+        None => Box::new(Ok(BTreeMap::new()).into_future()),
+    }
+}
+
+
+/// The subtree of the metadata commit `metadata_head`, if there is one.
+fn metadata_subtree<S: ObjectStore>(
+    store: &S,
+    metadata_head: Option<ObjectHash>,
+) -> Box<Future<Item = Option<ObjectHash>, Error = Error> + Send> {
+    match metadata_head {
+        Some(hash) => {
+            Box::new(store.read_object(hash).and_then(move |object| match object {
+                Object::Commit(commit_object) => Ok(Some(commit_object.subtree)),
+                _ => bail!(ErrorKind::ObjectNotACommit(hash)),
+            }))
+        }
+        None => Box::new(Ok(None).into_future()),
+    }
+}
+
+
+/// Read and parse the metadata held in the data object `hash`.
+fn read_metadata<S: ObjectStore>(
+    store: &S,
+    hash: ObjectHash,
+) -> Box<Future<Item = BranchMetadata, Error = Error> + Send> {
+    let folded = checkout::data_chunks(store, hash).fold(Vec::new(), |mut bytes, chunk| {
+        bytes.extend_from_slice(&chunk);
+        Ok::<_, Error>(bytes)
+    });
+    let parsed = folded.and_then(|bytes| -> Result<BranchMetadata> {
+        let text = String::from_utf8(bytes).chain_err(
+            || "branch metadata is not valid UTF-8",
+        )?;
+        Ok(toml::from_str(&text)?)
+    });
+
+    Box::new(parsed)
+}
+
+
+/// Read the metadata of `branch`, as of the metadata commit `metadata_head`.
+pub fn read<S: ObjectStore>(
+    store: &S,
+    metadata_head: Option<ObjectHash>,
+    branch: &str,
+) -> Box<Future<Item = Option<BranchMetadata>, Error = Error> + Send> {
+    let store = store.clone();
+    let name = OsString::from(escape(branch));
+
+    let result = {
+        async_block! {
+            let subtree_opt = await!(metadata_subtree(&store, metadata_head))?;
+            let entries = await!(read_entries(&store, subtree_opt))?;
+            let hash = match entries.get(&name) {
+                Some(&SubtreeEntry::File(hash, _)) => hash,
+                _ => return Ok(None),
+            };
+
+            Ok(Some(await!(read_metadata(&store, hash))?))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Read the metadata of every described branch, as of the metadata commit `metadata_head`.
+pub fn read_all<S: ObjectStore>(
+    store: &S,
+    metadata_head: Option<ObjectHash>,
+) -> Box<Future<Item = BTreeMap<String, BranchMetadata>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_opt = await!(metadata_subtree(&store, metadata_head))?;
+            let entries = await!(read_entries(&store, subtree_opt))?;
+            let mut all = BTreeMap::new();
+
+            for (name, entry) in entries {
+                let branch_opt = unescape(&name.to_string_lossy());
+                if let (Some(branch), SubtreeEntry::File(hash, _)) = (branch_opt, entry) {
+                    let metadata = await!(read_metadata(&store, hash))?;
+                    all.insert(branch, metadata);
+                }
+            }
+
+            Ok(all)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Replace the metadata of `branch`, removing it altogether if `metadata` is empty. Returns the
+/// new metadata commit, whose parent is `metadata_head`.
+pub fn write<S: ObjectStore>(
+    store: &S,
+    version: Version,
+    metadata_head: Option<ObjectHash>,
+    branch: &str,
+    metadata: BranchMetadata,
+) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+    let store = store.clone();
+    let name = OsString::from(escape(branch));
+    let branch = branch.to_owned();
+
+    let result = {
+        async_block! {
+            let subtree_opt = await!(metadata_subtree(&store, metadata_head))?;
+            let mut entries = await!(read_entries(&store, subtree_opt))?;
+
+            let message = if metadata.is_empty() {
+                entries.remove(&name);
+                format!("Remove the metadata of {}", branch)
+            } else {
+                let bytes = toml::to_vec(&metadata)?;
+                let size = bytes.len() as u64;
+                let small = SmallObject { chunk: arc_slice::owned(bytes) };
+                let data_object = Object::Data(DataObject::Small(small));
+                let hash = await!(merge::write(&store, data_object, version))?;
+                entries.insert(name, SubtreeEntry::File(hash, size));
+                format!("Update the metadata of {}", branch)
+            };
+
+            let root_object = Object::Subtree(SubtreeObject { entries });
+            let subtree = await!(merge::write(&store, root_object, version))?;
+
+            let metadata_commit = CommitObject {
+                subtree,
+                parents: metadata_head.into_iter().collect(),
+                message,
+                timestamp: Utc::now(),
+                signature: None,
+                author: None,
+                committer: None,
+            };
+
+            await!(merge::write(&store, Object::Commit(metadata_commit), version))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// The metadata of `branch` in `repository`, if it has any.
+pub fn get(repository: &mut Repository, branch: &str) -> Result<Option<BranchMetadata>> {
+    let metadata_head = match repository.refs.branches.get(METADATA_BRANCH) {
+        Some(&metadata_head) => metadata_head,
+        None => return Ok(None),
+    };
+
+    let ctx = repository.local(())?;
+    let metadata = read(ctx.store(), Some(metadata_head), branch).wait()?;
+    ctx.close().wait()?;
+
+    Ok(metadata)
+}
+
+
+/// Replace the metadata of `branch` in `repository`, advancing `METADATA_BRANCH` to record the
+/// change. Empty metadata removes the branch's metadata altogether.
+pub fn set(repository: &mut Repository, branch: &str, metadata: BranchMetadata) -> Result<()> {
+    let metadata_head = repository.refs.branches.get(METADATA_BRANCH).cloned();
+    let version = repository.object_version;
+
+    let new_head = {
+        let ctx = repository.local(())?;
+        let new_head = write(ctx.store(), version, metadata_head, branch, metadata).wait()?;
+        ctx.close().wait()?;
+
+        new_head
+    };
+
+    repository.compare_and_swap_branch(METADATA_BRANCH, metadata_head, new_head)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for branch in &["master", "datasets/imagenet", "100%", "a%2Fb"] {
+            let name = escape(branch);
+            assert!(!name.contains('/'));
+            assert_eq!(unescape(&name).as_ref().map(String::as_str), Some(*branch));
+        }
+
+        assert_eq!(unescape("50%"), None);
+        assert_eq!(unescape("%41"), None);
+    }
+}
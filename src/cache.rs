@@ -0,0 +1,351 @@
+//! # `cache` - a per-user object cache shared between attaca processes.
+//!
+//! When many attaca processes run at once on a single machine (for example, parallel steps of a
+//! data pipeline) they will often load the same objects and ask remotes about the same hashes.
+//! The cache daemon keeps recently loaded objects and remote-existence answers in memory and
+//! serves them over a Unix socket, so that only the first process has to do the work.
+//!
+//! The cache is strictly best-effort: if the daemon is not running or a request fails, clients
+//! behave as though they had a cache miss.
+//!
+//! The socket lives in a directory which only its user may enter, so that nobody else can bind
+//! it first and answer in the daemon's place. Both the daemon and its clients refuse a socket
+//! whose directory is owned by someone else or open to anyone else.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use libc;
+
+use errors::*;
+use ipc;
+use marshal::ObjectHash;
+use telemetry::COUNTERS;
+
+
+/// The default capacity of the daemon's object cache, in bytes.
+pub const DEFAULT_CAPACITY: u64 = 1 << 30;
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    /// Fetch the encoded bytes of an object, if cached.
+    Get(ObjectHash),
+
+    /// Offer the encoded bytes of an object to the cache.
+    Put(ObjectHash, Vec<u8>),
+
+    /// Ask whether or not a given remote is known to contain an object.
+    Contains(String, ObjectHash),
+
+    /// Record that a given remote is known to contain an object.
+    Insert(String, ObjectHash),
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Object(Option<Vec<u8>>),
+    Contains(bool),
+    Done,
+}
+
+
+/// The default location of the cache daemon's socket, in a directory of its own beneath the
+/// system's temporary directory. This may be overridden by setting the `ATTACA_CACHE_SOCKET`
+/// environment variable.
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("ATTACA_CACHE_SOCKET") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let uid = unsafe { libc::getuid() };
+            env::temp_dir().join(format!("attaca-cache-{}", uid)).join("cache.sock")
+        }
+    }
+}
+
+
+/// The directory holding the socket at `socket_path`.
+fn socket_dir(socket_path: &Path) -> &Path {
+    match socket_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+
+/// Check that the directory holding the socket at `socket_path` belongs to this user and that
+/// nobody else may enter it. A symlink is refused, even to such a directory.
+fn check_private(socket_path: &Path) -> Result<()> {
+    let dir = socket_dir(socket_path);
+    let metadata = fs::symlink_metadata(dir)?;
+    let uid = unsafe { libc::getuid() };
+
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        bail!(ErrorKind::CacheDirNotPrivate(dir.to_owned()));
+    }
+
+    Ok(())
+}
+
+
+/// What a remote existence answer counts for against the daemon's capacity: the name of the
+/// remote and the hash, and something for the bookkeeping around them.
+fn known_cost(remote: &str) -> u64 {
+    remote.len() as u64 + 64
+}
+
+
+/// An entry in the cache, in order of use.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Entry {
+    Object(ObjectHash),
+    Known(String, ObjectHash),
+}
+
+
+#[derive(Debug)]
+struct CacheState {
+    capacity: u64,
+    size: u64,
+    tick: u64,
+
+    objects: HashMap<ObjectHash, (u64, Vec<u8>)>,
+    known: HashMap<(String, ObjectHash), u64>,
+    recency: BTreeMap<u64, Entry>,
+}
+
+
+impl CacheState {
+    fn new(capacity: u64) -> Self {
+        CacheState {
+            capacity,
+            size: 0,
+            tick: 0,
+
+            objects: HashMap::new(),
+            known: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn touch(&mut self, hash: ObjectHash) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        match self.objects.get_mut(&hash) {
+            Some(&mut (ref mut last_used, ref bytes)) => {
+                self.recency.remove(last_used);
+                self.recency.insert(tick, Entry::Object(hash));
+                *last_used = tick;
+
+                Some(bytes.clone())
+            }
+            None => None,
+        }
+    }
+
+    fn touch_known(&mut self, remote: String, hash: ObjectHash) -> bool {
+        self.tick += 1;
+        let tick = self.tick;
+        let key = (remote, hash);
+
+        match self.known.get_mut(&key) {
+            Some(last_used) => {
+                self.recency.remove(last_used);
+                self.recency.insert(tick, Entry::Known(key.0.clone(), hash));
+                *last_used = tick;
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Evict the least recently used entries until the cache fits its capacity again.
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+
+            match self.recency.remove(&oldest).unwrap() {
+                Entry::Object(hash) => {
+                    let (_, bytes) = self.objects.remove(&hash).unwrap();
+                    self.size -= bytes.len() as u64;
+                }
+                Entry::Known(remote, hash) => {
+                    self.size -= known_cost(&remote);
+                    self.known.remove(&(remote, hash));
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, hash: ObjectHash, bytes: Vec<u8>) {
+        if self.objects.contains_key(&hash) || bytes.len() as u64 > self.capacity {
+            return;
+        }
+
+        self.tick += 1;
+        self.size += bytes.len() as u64;
+        self.recency.insert(self.tick, Entry::Object(hash));
+        self.objects.insert(hash, (self.tick, bytes));
+
+        self.evict();
+    }
+
+    fn insert_known(&mut self, remote: String, hash: ObjectHash) {
+        if self.touch_known(remote.clone(), hash) || known_cost(&remote) > self.capacity {
+            return;
+        }
+
+        self.tick += 1;
+        self.size += known_cost(&remote);
+        self.recency.insert(self.tick, Entry::Known(remote.clone(), hash));
+        self.known.insert((remote, hash), self.tick);
+
+        self.evict();
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        match request {
+            Request::Get(hash) => {
+                let object = self.touch(hash);
+                if object.is_some() {
+                    COUNTERS.add_cache_hit();
+                } else {
+                    COUNTERS.add_cache_miss();
+                }
+                Response::Object(object)
+            }
+            Request::Put(hash, bytes) => {
+                self.insert(hash, bytes);
+                Response::Done
+            }
+            Request::Contains(remote, hash) => Response::Contains(self.touch_known(remote, hash)),
+            Request::Insert(remote, hash) => {
+                self.insert_known(remote, hash);
+                Response::Done
+            }
+        }
+    }
+}
+
+
+fn serve_connection(state: Arc<Mutex<CacheState>>, mut stream: UnixStream) -> Result<()> {
+    while let Some(request) = ipc::read_message(&mut stream)? {
+        let response = state.lock().unwrap().handle(request);
+        ipc::write_message(&mut stream, &response)?;
+    }
+
+    Ok(())
+}
+
+
+/// Run the cache daemon in the foreground, listening on the given socket path. Each connection is
+/// served on its own thread. This function only returns if the listener fails.
+pub fn serve<P: AsRef<Path>>(socket_path: P, capacity: u64) -> Result<()> {
+    let socket_path = socket_path.as_ref();
+
+    let dir = socket_dir(socket_path);
+    if !dir.exists() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    check_private(socket_path)?;
+
+    // A stale socket left behind by a dead daemon would prevent us from binding.
+    if socket_path.exists() && UnixStream::connect(socket_path).is_err() {
+        fs::remove_file(socket_path)?;
+    }
+
+    let listener = UnixListener::bind(socket_path).chain_err(|| {
+        ErrorKind::CacheBind(socket_path.to_owned())
+    })?;
+    let state = Arc::new(Mutex::new(CacheState::new(capacity)));
+
+    for stream_res in listener.incoming() {
+        let stream = stream_res?;
+        let state = state.clone();
+
+        thread::spawn(move || { let _ = serve_connection(state, stream); });
+    }
+
+    Ok(())
+}
+
+
+/// A handle to a (possibly absent) cache daemon. Cloning a `CacheClient` shares its connection.
+#[derive(Debug, Clone)]
+pub struct CacheClient {
+    socket_path: Arc<PathBuf>,
+    conn: Arc<Mutex<Option<UnixStream>>>,
+}
+
+
+impl CacheClient {
+    pub fn new<P: AsRef<Path>>(socket_path: P) -> Self {
+        CacheClient {
+            socket_path: Arc::new(socket_path.as_ref().to_owned()),
+            conn: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn request(&self, request: &Request) -> Option<Response> {
+        let mut conn = self.conn.lock().unwrap();
+
+        if conn.is_none() && check_private(&self.socket_path).is_ok() {
+            *conn = UnixStream::connect(&*self.socket_path).ok();
+        }
+
+        let response = match *conn {
+            Some(ref mut stream) => {
+                ipc::write_message(stream, request)
+                    .and_then(|()| ipc::read_message(stream))
+                    .ok()
+                    .and_then(|response_opt| response_opt)
+            }
+            None => None,
+        };
+
+        // Drop broken connections so that the next request attempts to reconnect.
+        if response.is_none() {
+            *conn = None;
+        }
+
+        response
+    }
+
+    /// Fetch the encoded bytes of an object from the cache, if present.
+    pub fn get(&self, hash: ObjectHash) -> Option<Vec<u8>> {
+        match self.request(&Request::Get(hash)) {
+            Some(Response::Object(bytes_opt)) => bytes_opt,
+            _ => None,
+        }
+    }
+
+    /// Offer the encoded bytes of an object to the cache.
+    pub fn put(&self, hash: ObjectHash, bytes: Vec<u8>) {
+        let _ = self.request(&Request::Put(hash, bytes));
+    }
+
+    /// Ask whether the named remote is known to contain an object.
+    pub fn contains(&self, remote: &str, hash: ObjectHash) -> bool {
+        match self.request(&Request::Contains(remote.to_owned(), hash)) {
+            Some(Response::Contains(contains)) => contains,
+            _ => false,
+        }
+    }
+
+    /// Record that the named remote contains an object.
+    pub fn insert(&self, remote: &str, hash: ObjectHash) {
+        let _ = self.request(&Request::Insert(remote.to_owned(), hash));
+    }
+}
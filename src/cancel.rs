@@ -0,0 +1,311 @@
+//! # `cancel` - cancel store operations, and bound how long each may take.
+//!
+//! A `CancelToken` is shared between whoever may want to stop an operation and the operation
+//! itself. Once it is cancelled, every future guarded by it fails with `ErrorKind::Cancelled` the
+//! next time it is polled, and futures already waiting are woken to notice. `guard` also bounds a
+//! future in time: if it has not finished once its timeout has passed, it fails with
+//! `ErrorKind::TimedOut`. Either way the guarded future is dropped, so whatever it was doing stops
+//! at its next await point rather than running on unobserved.
+//!
+//! Timeouts are served by a single timer thread, started the first time one is needed, which
+//! wakes each waiting future as its deadline passes.
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::task::{self, AtomicTask, Task};
+
+use errors::*;
+
+
+/// Distinguishes the futures waiting on a single token.
+static NEXT_WAITER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+struct TokenInner {
+    cancelled: AtomicBool,
+    waiters: Mutex<HashMap<usize, Task>>,
+}
+
+
+/// A flag which, once set, fails every future guarded by it. Clones share the flag.
+#[derive(Clone)]
+pub struct CancelToken {
+    inner: Arc<TokenInner>,
+}
+
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken {
+            inner: Arc::new(TokenInner {
+                cancelled: AtomicBool::new(false),
+                waiters: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Cancel every future guarded by this token, now and from now on.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        for (_, waiter) in self.inner.waiters.lock().unwrap().drain() {
+            waiter.notify();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancel this token once `duration` has passed, bounding a whole operation rather than each
+    /// of its parts.
+    pub fn cancel_after(&self, duration: Duration) {
+        let token = self.clone();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            token.cancel();
+        });
+    }
+}
+
+
+struct DeadlineInner {
+    fired: AtomicBool,
+    task: AtomicTask,
+}
+
+
+/// A deadline waiting in the timer's queue. The queue is a max-heap, so deadlines are ordered
+/// latest first.
+struct Entry {
+    at: Instant,
+    deadline: Arc<DeadlineInner>,
+}
+
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at
+    }
+}
+
+
+impl Eq for Entry {}
+
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.at.cmp(&self.at)
+    }
+}
+
+
+struct Timer {
+    queue: Mutex<BinaryHeap<Entry>>,
+    condvar: Condvar,
+}
+
+
+impl Timer {
+    fn run(&self) {
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            let now = Instant::now();
+            let wait = match queue.peek() {
+                Some(entry) if entry.at <= now => None,
+                Some(entry) => Some(entry.at - now),
+                None => Some(Duration::from_secs(3600)),
+            };
+
+            match wait {
+                Some(wait) => queue = self.condvar.wait_timeout(queue, wait).unwrap().0,
+                None => {
+                    let entry = queue.pop().unwrap();
+                    entry.deadline.fired.store(true, Ordering::SeqCst);
+                    entry.deadline.task.notify();
+                }
+            }
+        }
+    }
+}
+
+
+lazy_static! {
+    static ref TIMER: Arc<Timer> = {
+        let timer = Arc::new(Timer {
+            queue: Mutex::new(BinaryHeap::new()),
+            condvar: Condvar::new(),
+        });
+
+        let runner = timer.clone();
+        thread::Builder::new()
+            .name("attaca-timer".to_owned())
+            .spawn(move || runner.run())
+            .expect("could not start the timer thread");
+
+        timer
+    };
+}
+
+
+/// A future which finishes once a given amount of time has passed.
+pub struct Deadline {
+    deadline: Arc<DeadlineInner>,
+}
+
+
+impl Deadline {
+    pub fn after(duration: Duration) -> Self {
+        let deadline = Arc::new(DeadlineInner {
+            fired: AtomicBool::new(false),
+            task: AtomicTask::new(),
+        });
+
+        TIMER.queue.lock().unwrap().push(Entry {
+            at: Instant::now() + duration,
+            deadline: deadline.clone(),
+        });
+        TIMER.condvar.notify_one();
+
+        Deadline { deadline }
+    }
+}
+
+
+impl Future for Deadline {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.deadline.task.register();
+
+        if self.deadline.fired.load(Ordering::SeqCst) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+
+/// A future which fails once its token is cancelled or its timeout passes. See `guard`.
+pub struct Guarded<F> {
+    inner: F,
+    token: Option<CancelToken>,
+    waiter: usize,
+    deadline: Option<(Deadline, Duration)>,
+}
+
+
+impl<F: Future<Error = Error>> Future for Guarded<F> {
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<F::Item, Error> {
+        if let Some(ref token) = self.token {
+            if token.is_cancelled() {
+                bail!(ErrorKind::Cancelled);
+            }
+
+            token.inner.waiters.lock().unwrap().insert(
+                self.waiter,
+                task::current(),
+            );
+        }
+
+        if let Async::Ready(item) = self.inner.poll()? {
+            return Ok(Async::Ready(item));
+        }
+
+        if let Some((ref mut deadline, timeout)) = self.deadline {
+            if let Async::Ready(()) = deadline.poll()? {
+                let ms = timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos()) / 1_000_000;
+                bail!(ErrorKind::TimedOut(ms));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+
+impl<F> Drop for Guarded<F> {
+    fn drop(&mut self) {
+        if let Some(ref token) = self.token {
+            token.inner.waiters.lock().unwrap().remove(&self.waiter);
+        }
+    }
+}
+
+
+/// Fail `future` with `ErrorKind::Cancelled` once `token` is cancelled, and with
+/// `ErrorKind::TimedOut` if it has not finished within `timeout`.
+pub fn guard<F: Future<Error = Error>>(
+    future: F,
+    token: Option<CancelToken>,
+    timeout: Option<Duration>,
+) -> Guarded<F> {
+    Guarded {
+        inner: future,
+        token,
+        waiter: NEXT_WAITER.fetch_add(1, Ordering::SeqCst),
+        deadline: timeout.map(|timeout| (Deadline::after(timeout), timeout)),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use futures::future;
+
+    use super::*;
+
+    #[test]
+    fn cancelled_and_timed_out_futures_fail() {
+        let token = CancelToken::new();
+        let ready = future::ok::<_, Error>(1);
+        assert_eq!(guard(ready, Some(token.clone()), None).wait().ok(), Some(1));
+
+        let pending = future::empty::<(), Error>();
+        match *guard(pending, None, Some(Duration::from_millis(10))).wait().unwrap_err().kind() {
+            ErrorKind::TimedOut(10) => {}
+            ref other => panic!("expected a timeout, got {:?}", other),
+        }
+
+        token.cancel_after(Duration::from_millis(10));
+        match *guard(future::empty::<(), Error>(), Some(token), None).wait().unwrap_err().kind() {
+            ErrorKind::Cancelled => {}
+            ref other => panic!("expected cancellation, got {:?}", other),
+        }
+    }
+}
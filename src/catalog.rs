@@ -1,6 +1,6 @@
 use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
 use std::iter;
 use std::mem;
 use std::path::PathBuf;
@@ -8,20 +8,26 @@ use std::result::Result as StdResult;
 use std::sync::{Arc, Weak, Mutex};
 use std::sync::atomic::{Ordering, AtomicUsize};
 use std::thread;
+use std::time::Duration;
 
 use bincode;
 use futures::prelude::*;
 use futures::task::AtomicTask;
 use qp_trie::{Entry, Trie};
 
+use LOCK_TIMEOUT_MS;
 use errors::*;
+use fault;
+use lock::LockFile;
 use marshal::ObjectHash;
 use repository::{Config, Paths};
+use warning;
 
 
 const OK_NOT_READY: usize = 0;
 const OK_READY: usize = 1;
 const ERR: usize = 2;
+const ABANDONED: usize = 3;
 
 
 #[derive(Debug)]
@@ -37,6 +43,7 @@ pub struct CatalogLock {
     inner: Weak<CatalogLockInner>,
     hash: ObjectHash,
     catalog: Catalog,
+    released: bool,
 }
 
 
@@ -51,6 +58,7 @@ impl CatalogLock {
             inner: Arc::downgrade(&inner),
             hash,
             catalog: catalog.clone(),
+            released: false,
         };
         let future = CatalogFuture { inner };
 
@@ -58,7 +66,12 @@ impl CatalogLock {
     }
 
 
-    pub fn release(self) {}
+    /// Mark the object as present. A lock dropped without being released - because the write it
+    /// guarded failed, timed out, or was cancelled - leaves the object absent from the catalog
+    /// instead, so that the next attempt to write it does so.
+    pub fn release(mut self) {
+        self.released = true;
+    }
 }
 
 
@@ -67,6 +80,18 @@ impl Drop for CatalogLock {
         if thread::panicking() {
             let inner = Weak::upgrade(&self.inner).unwrap();
             inner.locked.store(ERR, Ordering::SeqCst);
+        } else if !self.released {
+            {
+                let mut catalog_inner = self.catalog.inner.lock().unwrap();
+                if let Some(&CatalogEntry::Locked(_)) = catalog_inner.objects.get(&self.hash) {
+                    catalog_inner.objects.remove(&self.hash);
+                }
+            }
+
+            if let Some(inner) = Weak::upgrade(&self.inner) {
+                inner.locked.compare_and_swap(OK_NOT_READY, ABANDONED, Ordering::SeqCst);
+                inner.task.notify();
+            }
         } else {
             let inner = Weak::upgrade(&self.inner).unwrap();
             let previous_state = inner.locked.compare_and_swap(
@@ -103,6 +128,7 @@ impl Future for CatalogFuture {
             }
             OK_READY => Ok(Async::Ready(())),
             ERR => bail!(ErrorKind::CatalogPoisoned),
+            ABANDONED => bail!(ErrorKind::CatalogLockAbandoned),
             _ => unreachable!("Invalid CatalogLock state!"),
         }
     }
@@ -181,6 +207,47 @@ impl CatalogTrie {
 struct CatalogInner {
     catalog_path: PathBuf,
     objects: Trie<ObjectHash, CatalogEntry>,
+
+    /// Hashes removed since the catalog was loaded, which must not be brought back from the file
+    /// when merging with it.
+    removed: HashSet<ObjectHash>,
+
+    /// Whether the file is to be replaced outright rather than merged with, as when the catalog
+    /// was rebuilt from scratch or cleared.
+    replace: bool,
+}
+
+
+impl CatalogInner {
+    /// Write the catalog back. Other processes may have added to the file since it was loaded - a
+    /// fetch running alongside a commit, say - so unless the catalog is to replace the file, every
+    /// hash in the file which was not removed here is kept.
+    fn save(&mut self) -> Result<()> {
+        let lock_path = self.catalog_path.with_extension("catalog.lock");
+        let _lock = LockFile::acquire_timeout(&lock_path, Duration::from_millis(LOCK_TIMEOUT_MS))?;
+
+        if !self.replace && self.catalog_path.is_file() {
+            let on_disk: Trie<ObjectHash, CatalogEntry> =
+                bincode::deserialize_from(&mut File::open(&self.catalog_path)?, bincode::Infinite)
+                    .chain_err(|| ErrorKind::CatalogDeserialize(self.catalog_path.clone()))?;
+
+            for (hash, _) in on_disk {
+                if !self.removed.contains(&hash) && self.objects.get(&hash).is_none() {
+                    self.objects.insert(hash, CatalogEntry::Finished);
+                }
+            }
+        }
+
+        let temp_path = self.catalog_path.with_extension("catalog.tmp");
+        {
+            let mut file = File::create(&temp_path)?;
+            bincode::serialize_into(&mut file, &self.objects, bincode::Infinite)?;
+        }
+        fault::point("catalog.write");
+        fs::rename(&temp_path, &self.catalog_path)?;
+
+        Ok(())
+    }
 }
 
 
@@ -198,6 +265,8 @@ impl Catalog {
             inner: Arc::new(Mutex::new(CatalogInner {
                 catalog_path,
                 objects: catalog_trie.objects,
+                removed: HashSet::new(),
+                replace: true,
             })),
         })
     }
@@ -219,6 +288,8 @@ impl Catalog {
             inner: Arc::new(Mutex::new(CatalogInner {
                 catalog_path,
                 objects,
+                removed: HashSet::new(),
+                replace: false,
             })),
         })
     }
@@ -242,6 +313,14 @@ impl Catalog {
         })
     }
 
+    /// Forget a hash, so that its object is written again if it is needed. Used to roll back
+    /// objects which were written and then discarded.
+    pub fn remove(&self, hash: ObjectHash) {
+        let mut inner_lock = self.inner.lock().unwrap();
+        inner_lock.objects.remove(&hash);
+        inner_lock.removed.insert(hash);
+    }
+
     pub fn search<K: Borrow<[u8]>>(&self, bytes: K) -> Vec<ObjectHash> {
         self.inner
             .lock()
@@ -264,6 +343,7 @@ impl Catalog {
     pub fn clear(&self) -> Result<()> {
         let mut inner_lock = self.inner.lock().unwrap();
         let objects = mem::replace(&mut inner_lock.objects, Trie::new());
+        inner_lock.replace = true;
 
         for (_, value) in objects {
             if let CatalogEntry::Locked(future) = value {
@@ -282,8 +362,13 @@ impl Catalog {
 
 impl Drop for CatalogInner {
     fn drop(&mut self) {
-        let mut file = File::create(&self.catalog_path).unwrap();
-        bincode::serialize_into(&mut file, &self.objects, bincode::Infinite).unwrap();
+        if let Err(err) = self.save() {
+            warning::warn(format!(
+                "could not save the catalog at {}: {}",
+                self.catalog_path.display(),
+                err
+            ));
+        }
     }
 }
 
@@ -331,6 +416,18 @@ impl Registry {
         Ok(catalog)
     }
 
+    /// Forget the catalog of a remote, deleting it from disk.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        self.catalogs.remove(&Some(name.to_owned()));
+
+        let catalog_path = self.paths.remote_catalogs.join(format!("{}.catalog", name));
+        if catalog_path.exists() {
+            fs::remove_file(catalog_path)?;
+        }
+
+        Ok(())
+    }
+
     pub fn clear(&mut self) -> Result<()> {
         let catalog_names = self.catalogs.keys().cloned().collect::<Vec<_>>();
 
@@ -0,0 +1,566 @@
+//! # `checkout` - materialize a subtree from an object store onto the filesystem.
+//!
+//! Checking out a subtree happens in two phases. First, the subtree is walked, collecting every
+//! directory and file it contains; all directories are created up-front. Then, files are written
+//! in parallel. Each file is written by streaming the chunks of its data object in order, and at
+//! most `CheckoutOptions::open_files` files are ever open for writing at once.
+//!
+//! A single large file would be written one chunk at a time, so that checking out one huge file
+//! is bound by the latency of fetching each chunk rather than by bandwidth. Instead, large files
+//! are preallocated and up to `CheckoutOptions::chunk_workers` of their chunks are fetched at
+//! once, each written directly at its offset as computed from the sizes recorded in the large
+//! object.
+//!
+//! Both phases read through a `Prefetch` store, so that the subtrees and chunks which will be
+//! needed next are fetched while the current ones are being processed; see
+//! `CheckoutOptions::prefetch_window`.
+//!
+//! Files matched by one of `CheckoutOptions::filters` are read whole and smudged before being
+//! written; see the `filter` module.
+
+use std::cmp;
+use std::collections::BTreeSet;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{self as unix_fs, FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures::prelude::*;
+use futures::stream;
+use globset::GlobSet;
+use libc;
+
+use {CHECKOUT_OPEN_FILES, CHECKOUT_CHUNK_WORKERS, PREFETCH_WINDOW};
+use arc_slice::{self, ArcSlice};
+use errors::*;
+use filter::{Filter, FilterSet};
+use marshal::{ObjectHash, Object, DataObject, SubtreeEntry, EntryMetadata};
+use marshal::names;
+use store::{ObjectStore, Prefetch};
+
+
+/// Options controlling how a subtree is checked out.
+#[derive(Debug, Clone)]
+pub struct CheckoutOptions {
+    /// The maximum number of files which may be open for writing at any given time. Zero is
+    /// taken as one.
+    pub open_files: usize,
+
+    /// The maximum number of chunks of any one large file which may be fetched and written at
+    /// once. With a single worker, chunks are streamed in order; zero is taken as one.
+    pub chunk_workers: usize,
+
+    /// The maximum number of objects which may be read ahead of the checkout at once. Zero
+    /// disables reading ahead.
+    pub prefetch_window: usize,
+
+    /// If set, files are written as placeholders of the correct size, without fetching any of
+    /// their contents. See the `lazy` module.
+    pub lazy: bool,
+
+    /// If present, only files whose paths (relative to the root of the subtree) match are checked
+    /// out, along with the directories containing them.
+    pub filter: Option<GlobSet>,
+
+    /// The content filters files are smudged with as they are written, chosen by their paths
+    /// relative to the root of the subtree. `Context::checkout` uses the repository's.
+    pub filters: FilterSet,
+}
+
+
+impl Default for CheckoutOptions {
+    fn default() -> Self {
+        CheckoutOptions {
+            open_files: CHECKOUT_OPEN_FILES,
+            chunk_workers: CHECKOUT_CHUNK_WORKERS,
+            prefetch_window: PREFETCH_WINDOW,
+            lazy: false,
+            filter: None,
+            filters: FilterSet::default(),
+        }
+    }
+}
+
+
+/// The flattened contents of a subtree: every directory it contains, every nested repository
+/// along with where to clone it from and the commit of it recorded, and every other non-subtree
+/// entry along with its path. All paths are relative to the root of the subtree.
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    pub directories: Vec<PathBuf>,
+    pub files: Vec<(PathBuf, SubtreeEntry)>,
+    pub subrepositories: Vec<(PathBuf, String, ObjectHash)>,
+}
+
+
+impl Listing {
+    /// Restrict the listing to files and nested repositories matching a pattern, and to the
+    /// directories containing them.
+    pub fn filter(self, pattern: &GlobSet) -> Listing {
+        let files = self.files
+            .into_iter()
+            .filter(|&(ref path, _)| pattern.is_match(path))
+            .collect::<Vec<_>>();
+        let subrepositories = self.subrepositories
+            .into_iter()
+            .filter(|&(ref path, _, _)| pattern.is_match(path))
+            .collect::<Vec<_>>();
+
+        let mut needed = BTreeSet::new();
+        let paths = files.iter().map(|&(ref path, _)| path).chain(
+            subrepositories.iter().map(|&(ref path, _, _)| path),
+        );
+        for path in paths {
+            let mut parent = path.parent();
+            while let Some(directory) = parent {
+                needed.insert(directory.to_owned());
+                parent = directory.parent();
+            }
+        }
+
+        let directories = self.directories
+            .into_iter()
+            .filter(|directory| needed.contains(directory))
+            .collect();
+
+        Listing {
+            directories,
+            files,
+            subrepositories,
+        }
+    }
+}
+
+
+/// Stream the chunks of a data object, in order.
+pub fn data_chunks<S: ObjectStore>(
+    store: &S,
+    object_hash: ObjectHash,
+) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
+    let chunks = stream::unfold((store.clone(), vec![object_hash]), |(store, mut stack)| {
+        stack.pop().map(move |next| {
+            store.read_object(next).and_then(move |object| match object {
+                Object::Data(DataObject::Small(small_object)) => {
+                    Ok((Some(small_object.chunk), (store, stack)))
+                }
+                Object::Data(DataObject::Large(large_object)) => {
+                    // Children are pushed in reverse so that they are popped in order.
+                    stack.extend(large_object.children.into_iter().rev().map(|(_, hash)| hash));
+                    Ok((None, (store, stack)))
+                }
+                _ => bail!(ErrorKind::ObjectNotData(next)),
+            })
+        })
+    });
+
+    Box::new(chunks.filter_map(|chunk_opt| chunk_opt))
+}
+
+
+/// Recursively read a subtree, producing a `Listing` of its contents. Subtrees are read ahead of
+/// the walk; see `Prefetch`.
+pub fn walk<S: ObjectStore>(
+    store: &S,
+    subtree_hash: ObjectHash,
+) -> Box<Future<Item = Listing, Error = Error> + Send> {
+    walk_store(Prefetch::new(store, PREFETCH_WINDOW), subtree_hash)
+}
+
+
+fn walk_store<S: ObjectStore>(
+    store: S,
+    subtree_hash: ObjectHash,
+) -> Box<Future<Item = Listing, Error = Error> + Send> {
+    let result = {
+        async_block! {
+            let mut listing = Listing::default();
+            let mut stack = vec![(PathBuf::new(), subtree_hash)];
+
+            while let Some((path, hash)) = stack.pop() {
+                let subtree_object = match await!(store.read_object(hash))? {
+                    Object::Subtree(subtree_object) => subtree_object,
+                    _ => bail!(ErrorKind::ObjectNotASubtree(hash)),
+                };
+
+                for (component, entry) in subtree_object.entries {
+                    let joined = path.join(component);
+
+                    match entry {
+                        SubtreeEntry::Subtree(child_hash) => {
+                            listing.directories.push(joined.clone());
+                            stack.push((joined, child_hash));
+                        }
+                        SubtreeEntry::Subrepository(url, commit_hash) => {
+                            listing.subrepositories.push((joined, url, commit_hash));
+                        }
+                        other => listing.files.push((joined, other)),
+                    }
+                }
+            }
+
+            Ok(listing)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Write a single data object to the given path, streaming its chunks in order. The file is not
+/// opened until the returned future is first polled.
+pub fn write_file<S: ObjectStore>(
+    store: &S,
+    path: PathBuf,
+    object_hash: ObjectHash,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let chunks = data_chunks(store, object_hash);
+
+    let result = {
+        async_block! {
+            let file = File::create(&path).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            let file = await!(chunks.fold(file, |mut file, chunk| {
+                file.write_all(&chunk).map(|_| file)
+            })).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            file.sync_data()?;
+
+            Ok(())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Write a single data object to the given path, smudged by `filter`. The whole object is read
+/// into memory first, since a filter needs all of it at once.
+pub fn write_file_filtered<S: ObjectStore>(
+    store: &S,
+    path: PathBuf,
+    object_hash: ObjectHash,
+    filter: Arc<Filter>,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let bytes = data_chunks(store, object_hash).fold(Vec::new(), |mut bytes, chunk| {
+        bytes.extend_from_slice(&chunk);
+        Ok::<_, Error>(bytes)
+    });
+
+    let result = {
+        async_block! {
+            let smudged = filter.smudge(arc_slice::owned(await!(bytes)?))?;
+            let mut file =
+                File::create(&path).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            file.write_all(&smudged).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            file.sync_data()?;
+
+            Ok(())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Write the whole of `buf` to `file` at `offset`.
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match file.write_at(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Write a single data object to the given path. The chunks of a large object are fetched by up
+/// to `workers` tasks at once, each writing directly at its offset into the preallocated file;
+/// small objects, or any object with a single worker or none, are streamed as by `write_file`.
+pub fn write_file_parallel<S: ObjectStore>(
+    store: &S,
+    path: PathBuf,
+    object_hash: ObjectHash,
+    workers: usize,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    if workers <= 1 {
+        return write_file(store, path, object_hash);
+    }
+
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let large_object = match await!(store.read_object(object_hash))? {
+                Object::Data(DataObject::Large(large_object)) => large_object,
+                Object::Data(DataObject::Small(_)) => {
+                    return await!(write_file(&store, path, object_hash));
+                }
+                _ => bail!(ErrorKind::ObjectNotData(object_hash)),
+            };
+
+            let file = File::create(&path).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            file.set_len(large_object.size).chain_err(|| {
+                ErrorKind::CheckoutWrite(path.clone())
+            })?;
+            let file = Arc::new(file);
+
+            let mut offset = 0;
+            let mut writes = Vec::with_capacity(large_object.children.len());
+            for (size, child_hash) in large_object.children {
+                let file = file.clone();
+                let write = data_chunks(&store, child_hash).fold(offset, move |offset, chunk| {
+                    write_all_at(&file, &chunk, offset)?;
+                    Ok::<_, Error>(offset + chunk.len() as u64)
+                });
+
+                writes.push(write);
+                offset += size;
+            }
+
+            await!(stream::iter_ok(writes).buffer_unordered(workers).for_each(|_| Ok(())))
+                .chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            file.sync_data()?;
+
+            Ok(())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Write an empty placeholder file of the given size.
+fn write_placeholder(path: PathBuf, size: u64) -> Box<Future<Item = (), Error = Error> + Send> {
+    let result = {
+        async_block! {
+            let file = File::create(&path).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            file.set_len(size).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+
+            Ok(())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Set or clear the executable bits of a file. Execute permission is granted to exactly those who
+/// may read the file.
+fn set_executable(path: &Path, executable: bool) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+
+    if executable {
+        permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    } else {
+        permissions.set_mode(mode & !0o111);
+    }
+
+    fs::set_permissions(path, permissions)?;
+
+    Ok(())
+}
+
+
+/// Restore recorded metadata to a freshly written file without following symlinks. Ownership can
+/// usually only be changed by the superuser, so failing to change it is not an error.
+fn restore_metadata(path: &Path, metadata: &EntryMetadata) -> io::Result<()> {
+    let path_c_string = CString::new(path.as_os_str().as_bytes())?;
+
+    if let Some((uid, gid)) = metadata.owner {
+        if unsafe { libc::lchown(path_c_string.as_ptr(), uid, gid) } != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EPERM) {
+                return Err(error);
+            }
+        }
+    }
+
+    let mtime = libc::timespec {
+        tv_sec: metadata.mtime as libc::time_t,
+        tv_nsec: 0,
+    };
+    let times = [mtime, mtime];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path_c_string.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+
+/// Write a single non-subtree entry to the given path, replacing whatever is there. If `lazy` is
+/// set, files are written as placeholders; symlinks are always written in full. Files are smudged
+/// by `filter` if there is one; otherwise, large files are written by up to `chunk_workers` tasks
+/// at once; see `write_file_parallel`.
+pub fn write_entry<S: ObjectStore>(
+    store: &S,
+    path: PathBuf,
+    entry: SubtreeEntry,
+    lazy: bool,
+    chunk_workers: usize,
+    filter: Option<Arc<Filter>>,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            // Never write through a symlink, and never try to create a symlink over a file.
+            let existing_is_symlink = path.symlink_metadata()
+                .map(|metadata| metadata.file_type().is_symlink())
+                .unwrap_or(false);
+            let is_symlink = match entry.unannotated() {
+                SubtreeEntry::Symlink(_) => true,
+                _ => false,
+            };
+            if existing_is_symlink || (is_symlink && path.symlink_metadata().is_ok()) {
+                fs::remove_file(&path).chain_err(|| ErrorKind::CheckoutWrite(path.clone()))?;
+            }
+
+            match entry {
+                SubtreeEntry::File(object_hash, size) |
+                SubtreeEntry::Executable(object_hash, size) => {
+                    match filter {
+                        _ if lazy => await!(write_placeholder(path.clone(), size))?,
+                        Some(filter) => {
+                            await!(write_file_filtered(&store, path.clone(), object_hash, filter))?
+                        }
+                        None => {
+                            await!(write_file_parallel(
+                                &store,
+                                path.clone(),
+                                object_hash,
+                                chunk_workers,
+                            ))?
+                        }
+                    }
+
+                    let executable = match entry {
+                        SubtreeEntry::Executable(..) => true,
+                        _ => false,
+                    };
+                    set_executable(&path, executable).chain_err(|| {
+                        ErrorKind::CheckoutWrite(path.clone())
+                    })?;
+                }
+                SubtreeEntry::Symlink(object_hash) => {
+                    let target = await!(data_chunks(&store, object_hash).fold(Vec::new(), |mut target, chunk| {
+                        target.extend_from_slice(&chunk);
+                        Ok::<_, Error>(target)
+                    }))?;
+                    unix_fs::symlink(OsString::from_vec(target), &path).chain_err(|| {
+                        ErrorKind::CheckoutWrite(path.clone())
+                    })?;
+                }
+                SubtreeEntry::Annotated(inner, metadata) => {
+                    await!(write_entry(
+                        &store,
+                        path.clone(),
+                        SubtreeEntry::from(inner),
+                        lazy,
+                        chunk_workers,
+                        filter,
+                    ))?;
+                    restore_metadata(&path, &metadata).chain_err(|| {
+                        ErrorKind::CheckoutWrite(path.clone())
+                    })?;
+                }
+                SubtreeEntry::Subtree(_) |
+                SubtreeEntry::Subrepository(..) => {
+                    unreachable!("subtrees and subrepositories are never listed as files")
+                }
+            }
+
+            Ok(())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Check out the subtree with the given hash into the `target` directory, creating it if it does
+/// not exist. Existing files which are also present in the subtree are overwritten; files which
+/// are not present in the subtree (or which are excluded by `CheckoutOptions::filter`) are left
+/// untouched. The listing of everything which was checked out is returned.
+pub fn checkout<S: ObjectStore, P: AsRef<Path>>(
+    store: &S,
+    subtree_hash: ObjectHash,
+    target: P,
+    options: &CheckoutOptions,
+) -> Box<Future<Item = Listing, Error = Error> + Send> {
+    let store = Prefetch::new(store, options.prefetch_window);
+    let target = target.as_ref().to_owned();
+    let open_files = options.open_files;
+    let lazy = options.lazy;
+    let chunk_workers = options.chunk_workers;
+    let filter = options.filter.clone();
+    let filters = options.filters.clone();
+
+    let result = {
+        async_block! {
+            let mut listing = await!(walk_store(store.clone(), subtree_hash))?;
+            if let Some(ref pattern) = filter {
+                listing = listing.filter(pattern);
+            }
+
+            {
+                let directories = listing.directories.iter().map(PathBuf::as_path);
+                let files = listing.files.iter().map(|&(ref path, _)| path.as_path());
+                let subrepositories = listing.subrepositories.iter().map(
+                    |&(ref path, _, _)| path.as_path(),
+                );
+                names::check_checkout(directories.chain(files).chain(subrepositories))?;
+            }
+
+            // Nested repositories are left as empty directories, to be cloned into by
+            // `subrepository::update`.
+            fs::create_dir_all(&target).chain_err(|| ErrorKind::CheckoutWrite(target.clone()))?;
+            let subrepositories = listing.subrepositories.iter().map(|&(ref path, _, _)| path);
+            for directory in listing.directories.iter().chain(subrepositories) {
+                let absolute_path = target.join(directory);
+                fs::create_dir_all(&absolute_path).chain_err(|| {
+                    ErrorKind::CheckoutWrite(absolute_path.clone())
+                })?;
+            }
+
+            let writes = listing
+                .files
+                .iter()
+                .map(|&(ref path, ref entry)| {
+                    write_entry(
+                        &store,
+                        target.join(path),
+                        entry.clone(),
+                        lazy,
+                        chunk_workers,
+                        filters.for_path(path),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            // No file would ever be written with no room for any to be open.
+            let open_files = cmp::max(open_files, 1);
+            await!(stream::iter_ok(writes).buffer_unordered(open_files).for_each(|()| Ok(())))?;
+
+            Ok(listing)
+        }
+    };
+
+    Box::new(result)
+}
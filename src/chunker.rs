@@ -0,0 +1,301 @@
+//! # `chunker` - choosing where files are split into chunks.
+//!
+//! How well two versions of a file deduplicate depends entirely on where they are split: a chunk
+//! boundary which moves whenever a byte is inserted before it makes every chunk after it new. The
+//! built-in `Rolling` chunker places boundaries by content, which works well for arbitrary data,
+//! but a chunker which knows a format - one which splits FASTQ files between records, say, or
+//! Parquet files between row groups - can do much better on it.
+//!
+//! A `Chunker` turns the contents of a file into a stream of chunks. Every repository has a
+//! `ChunkerSet`, which picks a chunker for each file by its path: downstream crates may register
+//! chunkers of their own for the files they know about, and every other file is split by the
+//! default chunker.
+//!
+//! Besides `Rolling` and `Fixed`, the `Tar` and `Zip` chunkers place a boundary at the start of
+//! every member of an archive, and split the members themselves as `Rolling` would. An archive
+//! repacked with one member changed then shares every chunk but that member's with the original.
+//! Both are registered by default for files named `*.tar` and `*.zip`; anything which does not
+//! parse as an archive of the right kind is split by `Rolling` as a whole.
+//!
+//! Chunkers must be deterministic. The same contents must always produce the same chunks, or
+//! unchanged files will be stored anew on every commit.
+
+use std::cmp;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use futures::stream;
+use globset::{Glob, GlobMatcher};
+
+use arc_slice::ArcSlice;
+use archive::{tar, zip};
+use errors::*;
+use split::SliceChunker;
+
+
+/// Splits the contents of a file into chunks.
+pub trait Chunker: fmt::Debug + Send + Sync {
+    /// Split `slice` into chunks. Concatenated in order, the chunks must be exactly `slice`.
+    fn chunk(&self, slice: ArcSlice) -> Box<Stream<Item = ArcSlice, Error = Error> + Send>;
+}
+
+
+/// The built-in content-defined chunker, which places boundaries where a rolling checksum of
+/// the data hits a fixed value. Produces chunks of around 3 MB.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rolling;
+
+
+impl Chunker for Rolling {
+    fn chunk(&self, slice: ArcSlice) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
+        Box::new(stream::iter_ok(SliceChunker::new(slice)))
+    }
+}
+
+
+/// Splits data into chunks of a fixed size, with a shorter last chunk. Useful for formats made
+/// of fixed-size records, which are never shifted by insertions.
+#[derive(Debug, Clone, Copy)]
+pub struct Fixed {
+    pub size: usize,
+}
+
+
+impl Chunker for Fixed {
+    fn chunk(&self, slice: ArcSlice) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
+        let size = cmp::max(self.size, 1);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+
+        while start < slice.len() {
+            let end = cmp::min(start + size, slice.len());
+            chunks.push(slice.clone().map(|bytes| &bytes[start..end]));
+            start = end;
+        }
+
+        Box::new(stream::iter_ok(chunks))
+    }
+}
+
+
+/// Split `slice` at each of `boundaries`, and each resulting span as `Rolling` would. Boundaries
+/// need not be sorted or distinct; any outside of the slice are ignored.
+fn split_at(
+    slice: ArcSlice,
+    mut boundaries: Vec<usize>,
+) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
+    boundaries.retain(|&boundary| boundary > 0 && boundary < slice.len());
+    boundaries.push(slice.len());
+    boundaries.sort();
+    boundaries.dedup();
+
+    let mut spans = Vec::new();
+    let mut start = 0;
+    for end in boundaries {
+        spans.push(slice.clone().map(|bytes| &bytes[start..end]));
+        start = end;
+    }
+
+    Box::new(stream::iter_ok(spans.into_iter().flat_map(SliceChunker::new)))
+}
+
+
+/// Splits tar archives between members. See the module docs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tar;
+
+
+impl Tar {
+    /// The offset of every member header in `bytes`, and of the blocks ending the archive, or
+    /// `None` if it is not a tar archive. Extended headers (pax and GNU long names) are counted as
+    /// members of their own.
+    fn members(bytes: &[u8]) -> Option<Vec<usize>> {
+        let mut entries = tar::entries(bytes);
+        let mut offsets = entries
+            .by_ref()
+            .map(|entry| entry.map(|entry| entry.offset))
+            .collect::<Result<Vec<_>>>()
+            .ok()?;
+
+        if entries.position() + tar::BLOCK <= bytes.len() {
+            offsets.push(entries.position());
+        }
+
+        if offsets.is_empty() { None } else { Some(offsets) }
+    }
+}
+
+
+impl Chunker for Tar {
+    fn chunk(&self, slice: ArcSlice) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
+        let members = Tar::members(&slice).unwrap_or_default();
+        split_at(slice, members)
+    }
+}
+
+
+/// Splits zip archives between members. See the module docs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Zip;
+
+
+impl Zip {
+    /// The offset of every local header in `bytes` and of the central directory, as listed by
+    /// the central directory, or `None` if it is not a zip archive.
+    fn members(bytes: &[u8]) -> Option<Vec<usize>> {
+        let directory = zip::directory(bytes).ok()?;
+
+        let mut offsets = vec![directory.offset];
+        for entry in &directory.entries {
+            if zip::u32_at(bytes, entry.local as usize)? != zip::LOCAL_HEADER {
+                return None;
+            }
+            offsets.push(entry.local as usize);
+        }
+
+        Some(offsets)
+    }
+}
+
+
+impl Chunker for Zip {
+    fn chunk(&self, slice: ArcSlice) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
+        let members = Zip::members(&slice).unwrap_or_default();
+        split_at(slice, members)
+    }
+}
+
+
+/// The chunkers of a repository, chosen by path.
+#[derive(Clone)]
+pub struct ChunkerSet {
+    default: Arc<Chunker>,
+    by_pattern: Vec<(GlobMatcher, Arc<Chunker>)>,
+}
+
+
+impl Default for ChunkerSet {
+    fn default() -> Self {
+        let mut chunkers = ChunkerSet {
+            default: Arc::new(Rolling),
+            by_pattern: Vec::new(),
+        };
+
+        chunkers.register(Glob::new("*.tar").unwrap(), Tar);
+        chunkers.register(Glob::new("*.zip").unwrap(), Zip);
+
+        chunkers
+    }
+}
+
+
+impl fmt::Debug for ChunkerSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChunkerSet {{ {} patterns }}", self.by_pattern.len())
+    }
+}
+
+
+impl ChunkerSet {
+    /// Split files matching `pattern`, relative to the root of the worktree, with `chunker`. Of
+    /// the patterns matching a file, the last registered wins.
+    pub fn register<C: Chunker + 'static>(&mut self, pattern: Glob, chunker: C) {
+        self.by_pattern.push((pattern.compile_matcher(), Arc::new(chunker)));
+    }
+
+    /// Split files which match no registered pattern with `chunker`.
+    pub fn set_default<C: Chunker + 'static>(&mut self, chunker: C) {
+        self.default = Arc::new(chunker);
+    }
+
+    /// The chunker for the file at `path`, relative to the root of the worktree.
+    pub fn for_path<P: AsRef<Path>>(&self, path: P) -> Arc<Chunker> {
+        self.by_pattern
+            .iter()
+            .rev()
+            .find(|&&(ref matcher, _)| matcher.is_match(path.as_ref()))
+            .map(|&(_, ref chunker)| chunker.clone())
+            .unwrap_or_else(|| self.default.clone())
+    }
+
+    /// The chunker for data which does not come from a file.
+    pub fn default_chunker(&self) -> Arc<Chunker> {
+        self.default.clone()
+    }
+
+    /// Which chunker splits which files. Whenever the way any file would be split changes, so
+    /// does the description.
+    pub fn describe(&self) -> String {
+        let mut description = format!("{:?}", self.default);
+
+        for &(ref matcher, ref chunker) in &self.by_pattern {
+            description.push_str(&format!(" {}={:?}", matcher.glob(), chunker));
+        }
+
+        description
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use arc_slice;
+
+    #[test]
+    fn chooses_last_matching_pattern() {
+        let mut chunkers = ChunkerSet::default();
+        chunkers.register(Glob::new("*.fastq").unwrap(), Fixed { size: 4 });
+        chunkers.register(Glob::new("reads/*.fastq").unwrap(), Fixed { size: 3 });
+
+        let bytes = arc_slice::owned(b"0123456789".to_vec());
+        let lengths = |path: &str| {
+            chunkers
+                .for_path(path)
+                .chunk(bytes.clone())
+                .wait()
+                .map(|chunk| chunk.unwrap().len())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(lengths("a.fastq"), vec![4, 4, 2]);
+        assert_eq!(lengths("reads/b.fastq"), vec![3, 3, 3, 1]);
+        assert_eq!(lengths("a.txt"), vec![10]);
+    }
+
+    /// A tar header for a regular file of the given size.
+    fn tar_header(name: &str, size: usize) -> Vec<u8> {
+        let mut header = vec![0; tar::BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+
+        let checksum = tar::checksum(&header);
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+
+        header
+    }
+
+    #[test]
+    fn splits_tar_between_members() {
+        let mut archive = Vec::new();
+        for &(name, size) in &[("a", 700), ("b", 0), ("c", 10)] {
+            archive.extend(tar_header(name, size));
+            archive.extend(vec![b'x'; size + tar::padding(size as u64)]);
+        }
+        archive.extend(vec![0; 2 * tar::BLOCK]);
+
+        let lengths = Tar.chunk(arc_slice::owned(archive))
+            .wait()
+            .map(|chunk| chunk.unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(lengths, vec![3 * tar::BLOCK, tar::BLOCK, 2 * tar::BLOCK, 2 * tar::BLOCK]);
+
+        let garbage = arc_slice::owned(vec![1; 3 * tar::BLOCK]);
+        assert_eq!(Tar.chunk(garbage).wait().count(), 1);
+    }
+}
@@ -0,0 +1,156 @@
+//! # `concurrency` - tests of a fetch running alongside local work in the same repository.
+//!
+//! A background fetch updates remote-tracking refs and catalogs while the user goes on staging
+//! and committing. Each of the two is a process of its own, holding a `Repository` loaded before
+//! the other finished. Here two `Repository`s loaded from the same directory stand in for them,
+//! and each test checks that neither undoes what the other wrote.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use bench::{hash_of, Scratch};
+use catalog::Catalog;
+use errors::*;
+use repository::{self, Repository};
+
+
+/// A scratch directory holding a freshly initialized repository.
+fn temp_repository(name: &str) -> Scratch {
+    let scratch = Scratch::new(&format!("attaca-{}", name)).unwrap();
+    repository::init(scratch.path()).unwrap();
+    scratch
+}
+
+
+/// Run the cleanup of both repositories at once, each on a thread of its own.
+fn cleanup_together(first: Repository, second: Repository) -> (Result<()>, Result<()>) {
+    let barrier = Arc::new(Barrier::new(2));
+
+    let first_barrier = barrier.clone();
+    let first_thread = thread::spawn(move || {
+        first_barrier.wait();
+        first.cleanup()
+    });
+    barrier.wait();
+    let second_result = second.cleanup();
+
+    (first_thread.join().unwrap(), second_result)
+}
+
+
+#[test]
+fn fetch_and_commit_both_update_refs() {
+    let scratch = temp_repository("concurrent-refs");
+    let root = scratch.path();
+
+    let mut fetch = Repository::load(&root).unwrap();
+    let mut commit = Repository::load(&root).unwrap();
+
+    fetch
+        .refs
+        .remotes
+        .entry("origin".to_owned())
+        .or_insert_with(HashMap::new)
+        .insert("master".to_owned(), hash_of(1));
+    commit.compare_and_swap_branch("master", None, hash_of(2)).unwrap();
+
+    let (fetch_result, commit_result) = cleanup_together(fetch, commit);
+    fetch_result.unwrap();
+    commit_result.unwrap();
+
+    let reloaded = Repository::load(&root).unwrap();
+    assert_eq!(reloaded.refs.branches.get("master"), Some(&hash_of(2)));
+    assert_eq!(reloaded.refs.remotes["origin"].get("master"), Some(&hash_of(1)));
+}
+
+
+#[test]
+fn fetch_leaves_index_and_config_alone() {
+    let scratch = temp_repository("concurrent-index");
+    let root = scratch.path();
+    File::create(root.join("staged")).unwrap();
+
+    let fetch = Repository::load(&root).unwrap();
+    let mut commit = Repository::load(&root).unwrap();
+
+    commit.index.insert_hashed("staged", hash_of(1), 0).unwrap();
+    commit.config.telemetry = true;
+    commit.cleanup().unwrap();
+
+    // The fetch loaded the index and config before the commit wrote them, but changed neither,
+    // so it must not write them back.
+    fetch.cleanup().unwrap();
+
+    let reloaded = Repository::load(&root).unwrap();
+    assert!(reloaded.config.telemetry);
+    assert!(reloaded.index.iter().any(|(path, _)| path == Path::new("staged")));
+}
+
+
+#[test]
+fn stale_index_is_not_written() {
+    let scratch = temp_repository("concurrent-stale");
+    let root = scratch.path();
+    File::create(root.join("first")).unwrap();
+    File::create(root.join("second")).unwrap();
+
+    let mut first = Repository::load(&root).unwrap();
+    let mut second = Repository::load(&root).unwrap();
+
+    first.index.insert_hashed("first", hash_of(1), 0).unwrap();
+    second.index.insert_hashed("second", hash_of(2), 0).unwrap();
+    first.cleanup().unwrap();
+
+    match second.cleanup() {
+        Err(Error(ErrorKind::IndexConflict, _)) => {}
+        other => panic!("expected an index conflict, found {:?}", other),
+    }
+
+    let reloaded = Repository::load(&root).unwrap();
+    assert!(reloaded.index.iter().any(|(path, _)| path == Path::new("first")));
+}
+
+
+#[test]
+fn catalog_saves_are_merged() {
+    const WRITERS: u64 = 8;
+    const HASHES_PER_WRITER: u64 = 64;
+
+    let scratch = temp_repository("concurrent-catalog");
+    let root = scratch.path();
+    let catalog_path = root.join("test.catalog");
+    let barrier = Arc::new(Barrier::new(WRITERS as usize));
+
+    let writers = (0..WRITERS)
+        .map(|writer| {
+            let catalog_path = catalog_path.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                let catalog = Catalog::load(catalog_path).unwrap();
+                barrier.wait();
+
+                for i in 0..HASHES_PER_WRITER {
+                    catalog.try_lock(hash_of(writer * HASHES_PER_WRITER + i)).unwrap().release();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let catalog = Catalog::load(catalog_path.clone()).unwrap();
+    assert_eq!(catalog.len() as u64, WRITERS * HASHES_PER_WRITER);
+
+    // A removal is kept, even though the file still has the hash when the catalog is saved.
+    catalog.remove(hash_of(0));
+    drop(catalog);
+    let catalog = Catalog::load(catalog_path).unwrap();
+    assert!(catalog.get(hash_of(0)).is_none());
+    assert_eq!(catalog.len() as u64, WRITERS * HASHES_PER_WRITER - 1);
+}
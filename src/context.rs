@@ -1,9 +1,15 @@
 //! # `context` - manage a valid repository.
 
+use std::collections::HashSet;
 use std::ops::{Deref, DerefMut};
 use std::fmt;
+use std::fs;
 use std::iter::FromIterator;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use chrono::prelude::*;
 use futures::future::{self, Either};
@@ -16,13 +22,16 @@ use memmap::{Mmap, Protection};
 
 use {BATCH_FUTURE_BUFFER_SIZE, WRITE_FUTURE_BUFFER_SIZE};
 use arc_slice::{self, ArcSlice};
+use catalog::Catalog;
+use checkout::{self, CheckoutOptions, Listing};
 use errors::*;
+use hash_cache::StatKey;
 use index::Cached;
-use marshal::{ObjectHash, Marshaller, Hashed, Object, SubtreeEntry, CommitObject, Tree,
+use marshal::{ObjectHash, Marshaller, Hashed, Object, SubtreeEntry, CommitObject, Identity, Tree,
               BackedTree, TreeOp};
 use repository::Repository;
-use split::SliceChunker;
 use store::ObjectStore;
+use telemetry::{self, COUNTERS};
 use trace::Trace;
 
 
@@ -42,8 +51,8 @@ pub struct Context<'a, T: Trace, S: ObjectStore> {
     marshal_tx: Sender<Hashed>,
     writes: Box<Future<Item = (), Error = Error> + Send>,
 
-    index_tx: Sender<(PathBuf, ObjectHash)>,
-    index_rx: Receiver<(PathBuf, ObjectHash)>,
+    index_tx: Sender<(PathBuf, ObjectHash, u64, StatKey)>,
+    index_rx: Receiver<(PathBuf, ObjectHash, u64, StatKey)>,
 }
 
 
@@ -85,22 +94,37 @@ impl<'a, T: Trace, S: ObjectStore> Context<'a, T, S> {
         marshal_pool: &CpuPool,
         io_pool: &CpuPool,
     ) -> Self {
+        repository.renormalize();
+
         let (marshal_tx, marshal_rx) = mpsc::channel(BATCH_FUTURE_BUFFER_SIZE);
         let (index_tx, index_rx) = mpsc::channel(BATCH_FUTURE_BUFFER_SIZE);
 
         let writes = {
             let trace = trace.clone();
             let store = store.clone();
+            let blocklist = repository.blocklist.clone();
             let writes_unboxed = marshal_rx
                 .map_err(|()| unreachable!("mpsc receivers never error"))
                 .map(move |hashed: Hashed| {
                     let hash = *hashed.as_hash();
                     let trace = trace.clone();
 
-                    trace.on_write_object_start(&hash);
-                    store.write_object(hashed).map(move |fresh| {
-                        trace.on_write_object_finish(&hash, fresh);
-                    })
+                    match blocklist.check(&hash) {
+                        Ok(flagged) => {
+                            if flagged {
+                                trace.on_blocked_object(&hash);
+                            }
+
+                            trace.on_write_object_start(&hash);
+                            Either::A(store.write_object(hashed).map(move |fresh| {
+                                if fresh {
+                                    COUNTERS.add_object_written();
+                                }
+                                trace.on_write_object_finish(&hash, fresh);
+                            }))
+                        }
+                        Err(error) => Either::B(future::err(error)),
+                    }
                 })
                 .buffer_unordered(WRITE_FUTURE_BUFFER_SIZE)
                 .for_each(|_| Ok(()));
@@ -129,26 +153,50 @@ impl<'a, T: Trace, S: ObjectStore> Context<'a, T, S> {
         path: P,
     ) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
         let trace = self.trace.clone();
+        let (chunker, filter) = {
+            let path = path.as_ref();
+            let relative_path = path.strip_prefix(&self.paths.base).unwrap_or(path);
+            (self.chunkers.for_path(relative_path), self.filters.for_path(relative_path))
+        };
         let slice_res = Mmap::open_path(path, Protection::Read).map(|mmap| {
             trace.on_split_begin(mmap.len() as u64);
+            COUNTERS.add_bytes_split(mmap.len() as u64);
             arc_slice::mapped(mmap)
         });
 
         let stream_future = {
             async_block! {
+                let mut slice = slice_res?;
+                if let Some(filter) = filter {
+                    slice = filter.clean(slice)?;
+                }
+
                 let mut offset = 0u64;
-                let slices = SliceChunker::new(slice_res?).inspect(move |chunk| {
+                let chunks = telemetry::chunking(chunker.chunk(slice));
+                let slices = chunks.inspect(move |chunk| {
                     trace.on_split_chunk(offset, chunk);
                     offset += chunk.len() as u64;
                 });
 
-                Ok(stream::iter_ok(slices))
+                Ok(slices)
             }
         };
 
         Box::new(stream_future.flatten_stream())
     }
 
+    /// Produce the target of a symlink as a single chunk, without following the link.
+    pub fn read_symlink<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<Stream<Item = ArcSlice, Error = Error> + Send> {
+        let target_res = fs::read_link(path).map(|target| {
+            arc_slice::owned(target.into_os_string().into_vec())
+        });
+
+        Box::new(target_res.into_future().from_err().into_stream())
+    }
+
     pub fn read_object(
         &self,
         object_hash: ObjectHash,
@@ -184,11 +232,24 @@ impl<'a, T: Trace, S: ObjectStore> Context<'a, T, S> {
         U: Stream<Item = ArcSlice, Error = Error> + Send + 'static,
     {
         let marshal_tx = self.marshal_tx.clone();
-        let marshaller = Marshaller::with_trace(marshal_tx, self.trace.clone());
+        let marshaller = Marshaller::with_trace(marshal_tx, self.trace.clone())
+            .with_version(self.object_version)
+            .with_layout(self.config.data_layout.unwrap_or_default())
+            .with_known(self.known_objects());
 
         Box::new(self.marshal_pool.spawn(marshaller.process_chunks(stream)))
     }
 
+    /// Split an in-memory buffer into chunks and write it as a data object.
+    pub fn write_bytes(
+        &self,
+        bytes: Vec<u8>,
+    ) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+        let chunks = self.chunkers.default_chunker().chunk(arc_slice::owned(bytes));
+
+        self.write_file(chunks)
+    }
+
     pub fn write_subtree<U>(
         &self,
         stream: U,
@@ -197,7 +258,11 @@ impl<'a, T: Trace, S: ObjectStore> Context<'a, T, S> {
         U: Stream<Item = (PathBuf, SubtreeEntry), Error = Error> + Send + 'static,
     {
         let marshal_tx = self.marshal_tx.clone();
-        let marshaller = Marshaller::with_trace(marshal_tx, self.trace.clone());
+        let marshaller = Marshaller::with_trace(marshal_tx, self.trace.clone())
+            .with_version(self.object_version)
+            .with_non_utf8_names(self.config.non_utf8_names)
+            .with_layout(self.config.data_layout.unwrap_or_default())
+            .with_known(self.known_objects());
         let hash_future = stream.collect().and_then(move |entries| {
             marshaller.process_tree(Tree::from_iter(entries))
         });
@@ -212,8 +277,19 @@ impl<'a, T: Trace, S: ObjectStore> Context<'a, T, S> {
         parents: Vec<ObjectHash>,
         message: String,
         timestamp: DateTime<Utc>,
+        author: Option<Identity>,
+        committer: Option<Identity>,
     ) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
-        let marshaller = Marshaller::with_trace(self.marshal_tx.clone(), self.trace.clone());
+        let marshaller = Marshaller::with_trace(self.marshal_tx.clone(), self.trace.clone())
+            .with_version(self.object_version)
+            .with_non_utf8_names(self.config.non_utf8_names)
+            .with_layout(self.config.data_layout.unwrap_or_default())
+            .with_known(self.known_objects());
+        let metadata_mode = self.config.metadata;
+        let version_byte = self.object_version.to_byte().unwrap_or(0);
+
+        // A cached hash is only used if its object is still in the local store.
+        let local_catalog = self.catalogs.get(None).ok();
 
         let subtree_future = {
             let entries_iter = self.index.iter()
@@ -225,26 +301,75 @@ impl<'a, T: Trace, S: ObjectStore> Context<'a, T, S> {
                         .map(|exclude| exclude.is_match(path))
                         .unwrap_or(false);
 
-                    (is_included || entry.added || entry.tracked) && !is_excluded
+                    // Placeholders from a lazy checkout hold no data, so committing them would
+                    // clobber the real contents; they are left as they are in the parent commit.
+                    // One which has been written to since is committed like any other file.
+                    let is_placeholder =
+                        self.index.placeholders().is_unmodified(&self.paths.base, path);
+
+                    (is_included || entry.added || entry.tracked) && !is_excluded && !is_placeholder
                 })
                 .map(|(path, entry)| {
                     match entry.get() {
-                        Some(Cached::Hashed(object_hash, size)) => Either::A(future::ok(TreeOp::Insert(path.to_owned(), SubtreeEntry::File(object_hash, size)))),
+                        Some(Cached::Hashed(object_hash, size)) => {
+                            let subtree_entry = entry.annotate(entry.to_subtree_entry(object_hash, size), metadata_mode);
+                            Either::A(future::ok(TreeOp::Insert(path.to_owned(), subtree_entry)))
+                        }
                         Some(Cached::Removed) => Either::A(future::ok(TreeOp::Remove(path.to_owned()))),
 
                         // If the file has no hash in the cache *or* has an invalid cache entry, we must
                         // split and hash it.
                         Some(Cached::Unhashed) | None => {
                             let path = path.to_owned();
-                            let size = path.symlink_metadata().into_future().map(|m| m.len());
-                            let chunk_stream = self.split_file(&path);
+                            let metadata_res = path.symlink_metadata();
+
+                            // Unless the file is unchanged since its hash was last cached.
+                            let cached_hash = metadata_res.as_ref().ok().and_then(|metadata| {
+                                self.index.hash_cache().get(&path, &StatKey::new(metadata), version_byte)
+                            }).and_then(|(object_hash, size)| match local_catalog {
+                                Some(ref catalog) if self.reuse(catalog, object_hash) => Some((object_hash, size)),
+                                _ => None,
+                            });
+
+                            let hash_future = match cached_hash {
+                                Some(cached) => {
+                                    COUNTERS.add_hash_cache_hit();
+                                    Either::A(future::ok(cached))
+                                }
+                                None => {
+                                    COUNTERS.add_hash_cache_miss();
+                                    let chunk_stream = match metadata_res {
+                                        Ok(ref metadata) if metadata.file_type().is_symlink() => self.read_symlink(&path),
+                                        _ => self.split_file(&path),
+                                    };
+
+                                    // The entry records the size of the cleaned contents, which a
+                                    // clean filter may have made differ from the worktree file.
+                                    let counter = Arc::new(AtomicUsize::new(0));
+                                    let counted = {
+                                        let counter = counter.clone();
+                                        chunk_stream.inspect(move |chunk| {
+                                            counter.fetch_add(chunk.len(), Ordering::Relaxed);
+                                        })
+                                    };
+                                    Either::B(self.write_file(counted).map(move |object_hash| {
+                                        (object_hash, counter.load(Ordering::Relaxed) as u64)
+                                    }))
+                                }
+                            };
                             let index_tx = self.index_tx.clone();
-                            let hash_future = self.write_file(chunk_stream);
 
-                            Either::B(hash_future.join(size.from_err()).and_then(|(object_hash, size)| {
+                            Either::B(hash_future.join(metadata_res.into_future().from_err()).and_then(|((object_hash, size), metadata)| {
+                                let subtree_entry = metadata_mode.annotate(
+                                    SubtreeEntry::from_mode(object_hash, size, metadata.mode()),
+                                    metadata.mtime(),
+                                    metadata.uid(),
+                                    metadata.gid(),
+                                );
+
                                 index_tx
-                                    .send((path.clone(), object_hash))
-                                    .map(move |_| TreeOp::Insert(path, SubtreeEntry::File(object_hash, size)))
+                                    .send((path.clone(), object_hash, size, StatKey::new(&metadata)))
+                                    .map(move |_| TreeOp::Insert(path, subtree_entry))
                                     .map_err(|_| Error::from_kind(ErrorKind::Absurd))
                             }))
                         }
@@ -267,27 +392,128 @@ impl<'a, T: Trace, S: ObjectStore> Context<'a, T, S> {
             }
         };
 
+        let signing_key = self.signing_key.clone();
         let commit_future = subtree_future.and_then(move |subtree| {
-            marshaller.process(CommitObject {
+            let mut commit_object = CommitObject {
                 subtree,
                 parents,
                 message,
                 timestamp,
-            })
+                signature: None,
+                author,
+                committer,
+            };
+
+            let signed = match signing_key {
+                Some(ref key) => key.sign(&mut commit_object),
+                None => Ok(()),
+            };
+
+            signed.into_future().and_then(
+                move |()| marshaller.process(commit_object),
+            )
         });
 
         Box::new(self.marshal_pool.spawn(commit_future))
     }
 
+    /// Check out the subtree with the given hash into the `target` directory, smudging files with
+    /// the repository's content filters in place of any in `options`.
+    pub fn checkout<P: AsRef<Path>>(
+        &self,
+        subtree_hash: ObjectHash,
+        target: P,
+        options: &CheckoutOptions,
+    ) -> Box<Future<Item = Listing, Error = Error> + Send> {
+        let mut options = options.clone();
+        options.filters = self.filters.clone();
+
+        checkout::checkout(&self.store, subtree_hash, target, &options)
+    }
+
     pub fn store(&self) -> &S {
         &self.store
     }
 
+    /// Whether an object in the local store may be reused without being written again, restarting
+    /// its grace period if nothing refers to it yet. See `Refcounts::reused`. One which another
+    /// process has collected since is forgotten, so that it is written again; should recording
+    /// the reuse fail, it is written again too, which reports the error.
+    fn reuse(&self, catalog: &Catalog, object_hash: ObjectHash) -> bool {
+        if catalog.get(object_hash).is_none() {
+            return false;
+        }
+
+        match self.repository.refcounts {
+            Some(ref refcounts) => {
+                match refcounts.reused(object_hash) {
+                    Ok(true) => true,
+                    Ok(false) => {
+                        catalog.remove(object_hash);
+                        false
+                    }
+                    Err(_) => false,
+                }
+            }
+            None => true,
+        }
+    }
+
+    /// Whether this context's store is known to contain an object already, so that marshallers
+    /// need neither encode nor send it. Blocklisted objects are always sent, so that they are
+    /// checked against the blocklist however often they are marshalled.
+    fn known_objects(&self) -> Arc<Fn(ObjectHash) -> bool + Send + Sync> {
+        let store = self.store.clone();
+        let blocklist = self.repository.blocklist.clone();
+
+        Arc::new(move |object_hash| {
+            !blocklist.contains(&object_hash) && store.is_known(object_hash)
+        })
+    }
+
+    /// A marshaller which writes objects to this context's store. Objects written this way are
+    /// flushed when the context is closed.
+    pub fn marshaller(&self) -> Marshaller<T> {
+        Marshaller::with_trace(self.marshal_tx.clone(), self.trace.clone())
+            .with_version(self.object_version)
+            .with_non_utf8_names(self.config.non_utf8_names)
+            .with_layout(self.config.data_layout.unwrap_or_default())
+            .with_known(self.known_objects())
+    }
+
+    /// A marshaller which writes objects to this context's store like `marshaller`, but sends each
+    /// distinct object to the store only once, however often it is marshalled. Every object
+    /// written is remembered until the context is closed, so this is meant for bulk ingestion of
+    /// content which repeats itself, where it saves the store from being asked again and again
+    /// whether it already has an object.
+    pub fn deduplicating_marshaller(&self) -> Marshaller<T> {
+        let (tx, rx) = mpsc::channel(BATCH_FUTURE_BUFFER_SIZE);
+        let mut seen = HashSet::new();
+        let forward = rx.map_err(|()| unreachable!("mpsc receivers never error"))
+            .filter(move |hashed: &Hashed| seen.insert(*hashed.as_hash()))
+            .forward(self.marshal_tx.clone().sink_map_err(
+                |_| Error::from_kind(ErrorKind::Absurd),
+            ))
+            .map(|_| ());
+
+        // The forwarding task ends once every clone of the marshaller is dropped; `close` waits
+        // for it along with every other write, as it holds a sender of its own.
+        self.marshal_pool.spawn(forward).forget();
+
+        Marshaller::with_trace(tx, self.trace.clone())
+            .with_version(self.object_version)
+            .with_non_utf8_names(self.config.non_utf8_names)
+            .with_layout(self.config.data_layout.unwrap_or_default())
+            .with_known(self.known_objects())
+    }
+
     pub fn close(self) -> Box<Future<Item = (), Error = Error> + Send + 'a> {
+        let version_byte = self.repository.object_version.to_byte().unwrap_or(0);
         let repository = self.repository;
         let close_future = self.writes.join(
-            self.index_rx.map_err(|_| Error::from_kind(ErrorKind::Absurd)).for_each(move |(path, object_hash)| {
-                repository.index.clean(path, object_hash)
+            self.index_rx.map_err(|_| Error::from_kind(ErrorKind::Absurd)).for_each(move |(path, object_hash, size, key)| {
+                repository.index.hash_cache_mut().insert(path.clone(), key, object_hash, size, version_byte);
+                repository.index.clean(path, object_hash, size)
             }),
         ).map(|((), ())| ());
 
@@ -0,0 +1,402 @@
+//! # `daemon` - a per-worktree daemon answering queries from warm state.
+//!
+//! On large worktrees, most of the time spent by `status`, `log`, and `diff` goes into loading the
+//! index, re-reading history, and flattening the tree of the HEAD commit - work which gives the
+//! same answer from one invocation to the next. The worktree daemon keeps all of this in memory
+//! and serves queries over a Unix socket at `.attaca/daemon.sock`, so that the CLI only has to
+//! wait for the parts which actually changed.
+//!
+//! Started with `--watch`, the daemon also watches the worktree for changes, and only stats the
+//! files which may have changed when asked for the status. It answers the same question for the
+//! CLI, so that `commit` and `status` need not rescan the whole worktree even when run without
+//! the daemon's help; see `update_index`.
+//!
+//! The daemon never writes to the repository. It notices changes made by other processes by
+//! re-reading the refs on every request and reloading the index whenever its file is modified.
+//! Like the shared cache, it is strictly optional: if no daemon is listening, the CLI does the
+//! work itself.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use chrono::prelude::*;
+use futures::prelude::*;
+
+use checkout::{self, Listing};
+use errors::*;
+use index::{Cached, Index, IndexEntry};
+use ipc;
+use marshal::{ObjectHash, CommitObject};
+use repository::{Paths, Refs, Repository};
+#[cfg(feature = "watch")]
+use watch::Watcher;
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    /// Every tracked or added entry of the index, freshly updated against the worktree.
+    Status,
+
+    /// Every commit reachable from the HEAD.
+    Log,
+
+    /// Every tracked file which differs from the HEAD.
+    Diff,
+
+    /// Every path which may have changed at or after the given instant, if the worktree is being
+    /// watched.
+    Changed(DateTime<Utc>),
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Status(Vec<(PathBuf, IndexEntry)>),
+    Log(Vec<(ObjectHash, CommitObject)>),
+    Diff(Vec<Change>),
+
+    /// `None` if the worktree is not being watched, or changes may have been missed.
+    Changed(Option<Vec<PathBuf>>),
+
+    /// The request could not be answered; the message describes why.
+    Failed(String),
+}
+
+
+/// A difference between the worktree and the HEAD commit.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Change {
+    Added(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+}
+
+
+/// Compare the tracked entries of an index against the files of a commit's tree. Files which
+/// have been modified but not yet hashed are reported as modified.
+pub fn changes(index: &Index, head: &Listing) -> Vec<Change> {
+    let head_files = head.files
+        .iter()
+        .map(|&(ref path, ref entry)| (path.as_path(), entry))
+        .collect::<HashMap<_, _>>();
+
+    let mut changes = Vec::new();
+    for (path, entry) in index.iter().filter(|&(_, entry)| entry.tracked) {
+        let head_entry = head_files.get(path);
+
+        let change = match (entry.cached, head_entry) {
+            (Cached::Removed, Some(_)) => Change::Removed(path.to_owned()),
+            (Cached::Removed, None) => continue,
+            (_, None) => Change::Added(path.to_owned()),
+            (Cached::Hashed(hash, _), Some(head_entry)) => {
+                if hash == head_entry.hash() {
+                    continue;
+                }
+                Change::Modified(path.to_owned())
+            }
+            (Cached::Unhashed, Some(_)) => Change::Modified(path.to_owned()),
+        };
+
+        changes.push(change);
+    }
+
+    changes.sort();
+    changes
+}
+
+
+/// The state a daemon keeps warm.
+struct Worktree {
+    repository: Repository,
+
+    /// When the index file was last modified, as of when we loaded it.
+    index_modified: Option<SystemTime>,
+
+    /// Every commit we have read so far. Commits are immutable, so these never go stale.
+    commits: HashMap<ObjectHash, CommitObject>,
+
+    /// The flattened tree of the last HEAD commit asked about, keyed by its subtree hash.
+    head_listing: Option<(ObjectHash, Listing)>,
+
+    #[cfg(feature = "watch")]
+    watcher: Option<Watcher>,
+}
+
+
+impl Worktree {
+    #[cfg(feature = "watch")]
+    fn new(repository: Repository, watch: bool) -> Result<Self> {
+        let index_modified = modified(&repository.paths);
+        let watcher = if watch {
+            Some(Watcher::start(&repository.paths)?)
+        } else {
+            None
+        };
+
+        Ok(Worktree {
+            repository,
+            index_modified,
+            commits: HashMap::new(),
+            head_listing: None,
+            watcher,
+        })
+    }
+
+    #[cfg(not(feature = "watch"))]
+    fn new(repository: Repository, watch: bool) -> Result<Self> {
+        if watch {
+            bail!(ErrorKind::WatchNotCompiled);
+        }
+
+        let index_modified = modified(&repository.paths);
+
+        Ok(Worktree {
+            repository,
+            index_modified,
+            commits: HashMap::new(),
+            head_listing: None,
+        })
+    }
+
+    #[cfg(feature = "watch")]
+    fn changed_since(&self, since: &DateTime<Utc>) -> Option<Vec<PathBuf>> {
+        self.watcher.as_ref().and_then(|watcher| watcher.changed_since(since))
+    }
+
+    #[cfg(not(feature = "watch"))]
+    fn changed_since(&self, _since: &DateTime<Utc>) -> Option<Vec<PathBuf>> {
+        None
+    }
+
+    /// Update our copy of the index, statting only the files which may have changed if we can.
+    fn update_index(&mut self) -> Result<()> {
+        match self.changed_since(self.repository.index.timestamp()) {
+            Some(changed) => self.repository.index.update_paths(changed),
+            None => self.repository.index.update(),
+        }
+    }
+
+    /// Pick up changes made by other processes since the last request.
+    fn refresh(&mut self) -> Result<()> {
+        self.repository.refs = Refs::open(&self.repository.paths)?;
+
+        let index_modified = modified(&self.repository.paths);
+        if index_modified != self.index_modified {
+            self.repository.index = Index::open(&self.repository.paths)?;
+            self.index_modified = index_modified;
+        }
+
+        Ok(())
+    }
+
+    fn status(&mut self) -> Result<Vec<(PathBuf, IndexEntry)>> {
+        self.update_index()?;
+
+        let entries = self.repository
+            .index
+            .iter()
+            .filter(|&(_, entry)| entry.tracked || entry.added)
+            .map(|(path, entry)| (path.to_owned(), *entry))
+            .collect();
+
+        Ok(entries)
+    }
+
+    /// Read every commit reachable from the HEAD which we have not seen before.
+    fn load_history(&mut self) -> Result<()> {
+        let head = match self.repository.refs.head() {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+
+        let ctx = self.repository.local(())?;
+        let mut hashes = vec![head];
+
+        while let Some(hash) = hashes.pop() {
+            if self.commits.contains_key(&hash) {
+                continue;
+            }
+
+            let commit = ctx.read_commit(hash).wait()?;
+            hashes.extend(ctx.shallow.parents(&hash, &commit).iter().cloned());
+            self.commits.insert(hash, commit);
+        }
+
+        ctx.close().wait()?;
+
+        Ok(())
+    }
+
+    /// Every commit reachable from the HEAD, newest first.
+    fn log(&mut self) -> Result<Vec<(ObjectHash, CommitObject)>> {
+        self.load_history()?;
+
+        let mut reachable = Vec::new();
+        let mut visited = HashSet::new();
+        let mut hashes = self.repository.refs.head().into_iter().collect::<Vec<_>>();
+
+        while let Some(hash) = hashes.pop() {
+            if visited.insert(hash) {
+                let commit = &self.commits[&hash];
+                hashes.extend(self.repository.shallow.parents(&hash, commit).iter().cloned());
+                reachable.push((hash, commit.clone()));
+            }
+        }
+
+        reachable.sort_by(|&(_, ref a), &(_, ref b)| b.timestamp.cmp(&a.timestamp));
+
+        Ok(reachable)
+    }
+
+    fn diff(&mut self) -> Result<Vec<Change>> {
+        self.update_index()?;
+        self.load_history()?;
+
+        let subtree_hash = match self.repository.refs.head() {
+            Some(head) => self.commits[&head].subtree,
+            None => return Ok(changes(&self.repository.index, &Listing::default())),
+        };
+
+        let stale = match self.head_listing {
+            Some((hash, _)) => hash != subtree_hash,
+            None => true,
+        };
+
+        if stale {
+            let ctx = self.repository.local(())?;
+            let listing = checkout::walk(ctx.store(), subtree_hash).wait()?;
+            ctx.close().wait()?;
+
+            self.head_listing = Some((subtree_hash, listing));
+        }
+
+        let listing = &self.head_listing.as_ref().unwrap().1;
+
+        Ok(changes(&self.repository.index, listing))
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        let result = self.refresh().and_then(|()| match request {
+            Request::Status => self.status().map(Response::Status),
+            Request::Log => self.log().map(Response::Log),
+            Request::Diff => self.diff().map(Response::Diff),
+            Request::Changed(since) => Ok(Response::Changed(self.changed_since(&since))),
+        });
+
+        result.unwrap_or_else(|error| Response::Failed(error.to_string()))
+    }
+}
+
+
+fn modified(paths: &Paths) -> Option<SystemTime> {
+    fs::metadata(&paths.index).and_then(|metadata| metadata.modified()).ok()
+}
+
+
+fn serve_connection(worktree: Arc<Mutex<Worktree>>, mut stream: UnixStream) -> Result<()> {
+    while let Some(request) = ipc::read_message(&mut stream)? {
+        let response = worktree.lock().unwrap().handle(request);
+        ipc::write_message(&mut stream, &response)?;
+    }
+
+    Ok(())
+}
+
+
+/// Run the worktree daemon for a repository in the foreground, watching the worktree for changes
+/// if `watch` is set. Each connection is served on its own thread, but requests are answered one
+/// at a time. This function only returns if the listener fails.
+pub fn serve(repository: Repository, watch: bool) -> Result<()> {
+    let socket_path = repository.paths.daemon_socket.clone();
+
+    // A stale socket left behind by a dead daemon would prevent us from binding.
+    if socket_path.exists() && UnixStream::connect(&socket_path).is_err() {
+        fs::remove_file(&socket_path)?;
+    }
+
+    let listener = UnixListener::bind(&socket_path).chain_err(|| {
+        ErrorKind::DaemonBind(socket_path.clone())
+    })?;
+    let worktree = Arc::new(Mutex::new(Worktree::new(repository, watch)?));
+
+    for stream_res in listener.incoming() {
+        let stream = stream_res?;
+        let worktree = worktree.clone();
+
+        thread::spawn(move || { let _ = serve_connection(worktree, stream); });
+    }
+
+    Ok(())
+}
+
+
+/// A connection to the worktree daemon of a repository.
+#[derive(Debug)]
+pub struct DaemonClient {
+    stream: UnixStream,
+}
+
+
+impl DaemonClient {
+    /// Connect to the daemon serving a repository, if one is running.
+    pub fn connect(paths: &Paths) -> Option<Self> {
+        UnixStream::connect(&paths.daemon_socket).ok().map(|stream| DaemonClient { stream })
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        ipc::write_message(&mut self.stream, request)?;
+
+        match ipc::read_message(&mut self.stream)? {
+            Some(Response::Failed(message)) => bail!(ErrorKind::DaemonFailed(message)),
+            Some(response) => Ok(response),
+            None => bail!(ErrorKind::DaemonFailed("connection closed".to_owned())),
+        }
+    }
+
+    pub fn status(&mut self) -> Result<Vec<(PathBuf, IndexEntry)>> {
+        match self.request(&Request::Status)? {
+            Response::Status(entries) => Ok(entries),
+            _ => bail!(ErrorKind::DaemonFailed("unexpected response".to_owned())),
+        }
+    }
+
+    pub fn log(&mut self) -> Result<Vec<(ObjectHash, CommitObject)>> {
+        match self.request(&Request::Log)? {
+            Response::Log(commits) => Ok(commits),
+            _ => bail!(ErrorKind::DaemonFailed("unexpected response".to_owned())),
+        }
+    }
+
+    pub fn diff(&mut self) -> Result<Vec<Change>> {
+        match self.request(&Request::Diff)? {
+            Response::Diff(changes) => Ok(changes),
+            _ => bail!(ErrorKind::DaemonFailed("unexpected response".to_owned())),
+        }
+    }
+
+    pub fn changed_since(&mut self, since: &DateTime<Utc>) -> Result<Option<Vec<PathBuf>>> {
+        match self.request(&Request::Changed(*since))? {
+            Response::Changed(changed) => Ok(changed),
+            _ => bail!(ErrorKind::DaemonFailed("unexpected response".to_owned())),
+        }
+    }
+}
+
+
+/// Update the index of a repository. If its worktree daemon is watching for changes, only the
+/// files which it says may have changed are statted; otherwise, every tracked file is.
+pub fn update_index(repository: &mut Repository) -> Result<()> {
+    let changed = DaemonClient::connect(&repository.paths).and_then(|mut client| {
+        client.changed_since(repository.index.timestamp()).ok().and_then(|changed| changed)
+    });
+
+    match changed {
+        Some(changed) => repository.index.update_paths(changed),
+        None => repository.index.update(),
+    }
+}
@@ -0,0 +1,409 @@
+//! # `dataset` - generate synthetic datasets for benchmarks and tests.
+//!
+//! A `DatasetSpec` describes the shape of a dataset - how many files, how their sizes are
+//! distributed, how much of it is duplicated, how deep and wide its directories are and how much
+//! of it is text - and `generate` writes a dataset of that shape into a directory. Everything is
+//! drawn from a generator seeded by the spec, so the same spec always produces the same bytes, and
+//! a benchmark can be reproduced from its spec alone.
+//!
+//! Duplication comes in two kinds. An exact duplicate is a copy of an earlier file, which
+//! deduplicates entirely. A near duplicate is a copy with a small region rewritten, as an edited
+//! or appended-to file would be, which deduplicates only as well as chunking does.
+
+use std::cmp;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+use rand::distributions::{IndependentSample, LogNormal};
+
+use errors::*;
+
+
+/// Files are written in blocks of this many bytes.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+
+/// The largest region rewritten in a near duplicate.
+const MAX_EDIT_SIZE: u64 = 16 * 1024;
+
+
+/// Words text files are made of. Text is compressible and chunks differently from random bytes,
+/// which is all that matters here.
+const WORDS: &[&str] = &[
+    "sample", "label", "frame", "sensor", "value", "train", "test", "batch", "epoch", "weight",
+    "image", "record", "index", "shard", "region", "offset", "delta", "time", "mean", "error",
+];
+
+
+/// Parse a size in bytes, optionally suffixed with `K`, `M`, `G` or `T` for powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') | Some('t') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+
+    Ok(digits.parse::<u64>()? * multiplier)
+}
+
+
+/// How the sizes of generated files are distributed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeDistribution {
+    /// Every file is the same size. Written as a single size, such as `1M`.
+    Fixed(u64),
+
+    /// Sizes are drawn uniformly between two bounds, inclusive. Written as `MIN-MAX`, such as
+    /// `4K-16M`.
+    Uniform(u64, u64),
+
+    /// Sizes are drawn from a log-normal distribution with the given median and shape, as the
+    /// sizes of files in real datasets tend to be: many small files and a long tail of large
+    /// ones. Written as `lognormal:MEDIAN:SIGMA`, such as `lognormal:64K:2`.
+    LogNormal(u64, f64),
+}
+
+
+impl Default for SizeDistribution {
+    fn default() -> Self {
+        SizeDistribution::LogNormal(64 * 1024, 2.0)
+    }
+}
+
+
+impl FromStr for SizeDistribution {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("lognormal:") {
+            let mut params = s["lognormal:".len()..].splitn(2, ':');
+            match (params.next(), params.next()) {
+                (Some(median), Some(sigma)) => {
+                    let sigma = sigma.parse::<f64>().chain_err(
+                        || format!("invalid shape in `{}`", s),
+                    )?;
+                    return Ok(SizeDistribution::LogNormal(parse_size(median)?, sigma));
+                }
+                _ => bail!("expected `lognormal:MEDIAN:SIGMA`, found `{}`", s),
+            }
+        }
+
+        match s.find('-') {
+            Some(i) => {
+                let (min, max) = (parse_size(&s[..i])?, parse_size(&s[i + 1..])?);
+                ensure!(min <= max, "the bounds of `{}` are the wrong way around", s);
+                Ok(SizeDistribution::Uniform(min, max))
+            }
+            None => Ok(SizeDistribution::Fixed(parse_size(s)?)),
+        }
+    }
+}
+
+
+impl fmt::Display for SizeDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SizeDistribution::Fixed(size) => write!(f, "{}", size),
+            SizeDistribution::Uniform(min, max) => write!(f, "{}-{}", min, max),
+            SizeDistribution::LogNormal(median, sigma) => {
+                write!(f, "lognormal:{}:{}", median, sigma)
+            }
+        }
+    }
+}
+
+
+/// The shape of a dataset to generate. See the module documentation.
+#[derive(Debug, Clone)]
+pub struct DatasetSpec {
+    /// Seeds every choice made, so that the same spec always generates the same dataset.
+    pub seed: u64,
+
+    /// The number of files to generate.
+    pub files: usize,
+
+    pub sizes: SizeDistribution,
+
+    /// No file is made larger than this, whatever `sizes` draws.
+    pub max_size: u64,
+
+    /// The fraction of files which are exact copies of an earlier file.
+    pub duplicates: f64,
+
+    /// The fraction of files which are copies of an earlier file with a small region rewritten.
+    pub near_duplicates: f64,
+
+    /// The fraction of files which are text rather than random bytes.
+    pub text: f64,
+
+    /// How many levels of directories files are spread over, beneath the root.
+    pub depth: usize,
+
+    /// How many subdirectories each directory has.
+    pub fanout: usize,
+}
+
+
+impl Default for DatasetSpec {
+    fn default() -> Self {
+        DatasetSpec {
+            seed: 0,
+            files: 1000,
+            sizes: SizeDistribution::default(),
+            max_size: 256 << 20,
+            duplicates: 0.1,
+            near_duplicates: 0.1,
+            text: 0.2,
+            depth: 2,
+            fanout: 8,
+        }
+    }
+}
+
+
+/// What was written by `generate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Generated {
+    pub files: u64,
+    pub bytes: u64,
+
+    /// The bytes of exact duplicates, which a store should not need to keep twice.
+    pub duplicate_bytes: u64,
+
+    /// The bytes of near duplicates, of which a store should only need to keep the edits.
+    pub near_duplicate_bytes: u64,
+}
+
+
+fn rng_for(seed: u64) -> XorShiftRng {
+    // `XorShiftRng` refuses an all-zero seed, so mix in constants.
+    XorShiftRng::from_seed([
+        seed as u32 ^ 0x193a_6754,
+        (seed >> 32) as u32 ^ 0xa8a7_d469,
+        0x9783_0e05,
+        0x113b_a7bb,
+    ])
+}
+
+
+fn sample_size<R: Rng>(rng: &mut R, sizes: SizeDistribution, max_size: u64) -> u64 {
+    let size = match sizes {
+        SizeDistribution::Fixed(size) => size,
+        SizeDistribution::Uniform(min, max) => rng.gen_range(min, max + 1),
+        SizeDistribution::LogNormal(median, sigma) => {
+            let mean = (cmp::max(median, 1) as f64).ln();
+            let sample = LogNormal::new(mean, sigma.abs()).ind_sample(rng);
+
+            // Casting a float too large for the integer is not defined, so clamp first.
+            if sample >= max_size as f64 {
+                max_size
+            } else {
+                sample as u64
+            }
+        }
+    };
+
+    cmp::min(size, max_size)
+}
+
+
+/// A relative directory at depth `depth`, chosen uniformly among the leaves of the tree.
+fn sample_dir<R: Rng>(rng: &mut R, depth: usize, fanout: usize) -> PathBuf {
+    let mut dir = PathBuf::new();
+
+    if fanout > 0 {
+        for level in 0..depth {
+            dir.push(format!("d{}-{:02}", level, rng.gen_range(0, fanout)));
+        }
+    }
+
+    dir
+}
+
+
+fn write_random<R: Rng, W: Write>(rng: &mut R, writer: &mut W, size: u64) -> Result<()> {
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut remaining = size;
+
+    while remaining > 0 {
+        let len = cmp::min(remaining, BLOCK_SIZE as u64) as usize;
+        rng.fill_bytes(&mut block[..len]);
+        writer.write_all(&block[..len])?;
+        remaining -= len as u64;
+    }
+
+    Ok(())
+}
+
+
+fn write_text<R: Rng, W: Write>(rng: &mut R, writer: &mut W, size: u64) -> Result<()> {
+    let mut line = String::new();
+    let mut remaining = size;
+
+    while remaining > 0 {
+        line.clear();
+        for i in 0..rng.gen_range(4, 16) {
+            if i > 0 {
+                line.push(' ');
+            }
+            line.push_str(rng.choose(WORDS).unwrap());
+            if rng.gen_weighted_bool(4) {
+                line.push_str(&format!(" {}", rng.gen_range(0, 100_000)));
+            }
+        }
+        line.push('\n');
+
+        let len = cmp::min(remaining, line.len() as u64) as usize;
+        writer.write_all(&line.as_bytes()[..len])?;
+        remaining -= len as u64;
+    }
+
+    Ok(())
+}
+
+
+/// Generate the dataset described by `spec` in the directory `root`, which is created if it does
+/// not exist.
+pub fn generate<P: AsRef<Path>>(spec: &DatasetSpec, root: P) -> Result<Generated> {
+    let root = root.as_ref();
+    let mut rng = rng_for(spec.seed);
+    let mut written: Vec<(PathBuf, u64)> = Vec::with_capacity(spec.files);
+    let mut generated = Generated::default();
+
+    fs::create_dir_all(root)?;
+
+    for i in 0..spec.files {
+        let dir = root.join(sample_dir(&mut rng, spec.depth, spec.fanout));
+        fs::create_dir_all(&dir)?;
+
+        let roll = rng.gen::<f64>();
+        let original = if !written.is_empty() && roll < spec.duplicates + spec.near_duplicates {
+            Some(written[rng.gen_range(0, written.len())].clone())
+        } else {
+            None
+        };
+
+        let (path, size) = match original {
+            Some((original_path, size)) => {
+                let extension = original_path.extension().unwrap_or_default().to_owned();
+                let path = dir.join(format!("{:06}", i)).with_extension(extension);
+                fs::copy(&original_path, &path)?;
+
+                if roll < spec.duplicates {
+                    generated.duplicate_bytes += size;
+                } else {
+                    if size > 0 {
+                        let edit_size = rng.gen_range(1, cmp::min(size, MAX_EDIT_SIZE) + 1);
+                        let offset = rng.gen_range(0, size - edit_size + 1);
+                        let mut file = OpenOptions::new().write(true).open(&path)?;
+                        file.seek(SeekFrom::Start(offset))?;
+                        write_random(&mut rng, &mut file, edit_size)?;
+                    }
+                    generated.near_duplicate_bytes += size;
+                }
+
+                (path, size)
+            }
+            None => {
+                let size = sample_size(&mut rng, spec.sizes, spec.max_size);
+                let is_text = rng.gen::<f64>() < spec.text;
+                let extension = if is_text { "txt" } else { "bin" };
+                let path = dir.join(format!("{:06}.{}", i, extension));
+
+                let mut writer = BufWriter::new(File::create(&path)?);
+                if is_text {
+                    write_text(&mut rng, &mut writer, size)?;
+                } else {
+                    write_random(&mut rng, &mut writer, size)?;
+                }
+                writer.flush()?;
+
+                (path, size)
+            }
+        };
+
+        generated.files += 1;
+        generated.bytes += size;
+        written.push((path, size));
+    }
+
+    Ok(generated)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+    use bench::Scratch;
+
+    fn contents(root: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut found = Vec::new();
+        let mut stack = vec![root.to_owned()];
+
+        while let Some(dir) = stack.pop() {
+            for entry_res in fs::read_dir(&dir).unwrap() {
+                let path = entry_res.unwrap().path();
+                if path.is_dir() {
+                    stack.push(path);
+                } else {
+                    let mut bytes = Vec::new();
+                    File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+                    found.push((path.strip_prefix(root).unwrap().to_owned(), bytes));
+                }
+            }
+        }
+
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn same_spec_same_dataset() {
+        let scratch = Scratch::new("attaca-dataset").unwrap();
+        let root = scratch.path();
+        let spec = DatasetSpec {
+            seed: 42,
+            files: 50,
+            sizes: "0-100K".parse().unwrap(),
+            duplicates: 0.2,
+            near_duplicates: 0.2,
+            text: 0.5,
+            ..DatasetSpec::default()
+        };
+
+        let first = generate(&spec, root.join("first")).unwrap();
+        let second = generate(&spec, root.join("second")).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.files, 50);
+        assert_eq!(contents(&root.join("first")), contents(&root.join("second")));
+
+        let total = contents(&root.join("first"))
+            .iter()
+            .map(|&(_, ref bytes)| bytes.len() as u64)
+            .sum::<u64>();
+        assert_eq!(total, first.bytes);
+    }
+
+    #[test]
+    fn size_distributions() {
+        assert_eq!("1M".parse::<SizeDistribution>().unwrap(), SizeDistribution::Fixed(1 << 20));
+        assert_eq!(
+            "4K-16M".parse::<SizeDistribution>().unwrap(),
+            SizeDistribution::Uniform(4 << 10, 16 << 20)
+        );
+        assert_eq!(
+            "lognormal:64K:2".parse::<SizeDistribution>().unwrap(),
+            SizeDistribution::LogNormal(64 << 10, 2.0)
+        );
+        assert!("16M-4K".parse::<SizeDistribution>().is_err());
+    }
+}
@@ -1,23 +1,28 @@
 //! # `errors` - error-chain generated `Error` types.
 
+use std::ffi::OsString;
 use std::path::PathBuf;
 
-use marshal::ObjectHash;
+use marshal::{Identity, ObjectHash};
 
 
 error_chain! {
     types { Error, ErrorKind, ResultExt, Result; }
 
     links {
-        Rados(::rad::Error, ::rad::ErrorKind);
+        Rados(::rad::Error, ::rad::ErrorKind) #[cfg(feature = "rados")];
     }
 
     foreign_links {
         Bincode(::bincode::Error);
+        Git(::git2::Error) #[cfg(feature = "git")];
+        GlobSet(::globset::Error);
         Io(::std::io::Error);
+        Json(::serde_json::Error);
+        Notify(::notify::Error) #[cfg(feature = "watch")];
         Nul(::std::ffi::NulError);
         ParseInt(::std::num::ParseIntError);
-        Ssh2(::ssh2::Error);
+        Ssh2(::ssh2::Error) #[cfg(feature = "ssh")];
         TomlSer(::toml::ser::Error);
         TomlDe(::toml::de::Error);
     }
@@ -28,6 +33,63 @@ error_chain! {
             display("this is absurd and should never happen")
         }
 
+        ArchiveEntryNotFound(path: PathBuf) {
+            description("no such file in the archive")
+            display("{} is not in the archive", path.display())
+        }
+
+        BackendNotCompiled(backend: String, feature: String) {
+            description("a remote backend was not compiled into this build")
+            display("the {} backend was not compiled into this build of attaca; rebuild with `--features {}`", backend, feature)
+        }
+
+        BackrefsDisabled {
+            description("the reverse reference index is disabled")
+            display("the reverse reference index is disabled; set `backrefs = true` in config.toml")
+        }
+
+        BlockedObject(hash: ObjectHash) {
+            description("refused to write a blocklisted object")
+            display("refused to write the blocklisted object {}", hash)
+        }
+
+        BlocklistOpen(path: PathBuf) {
+            description("could not read the blocklist")
+            display("could not read the blocklist at {}", path.display())
+        }
+
+        BranchCheckedOut(branch: String, path: PathBuf) {
+            description("a branch is already checked out in another worktree")
+            display("branch `{}` is already checked out at {}", branch, path.display())
+        }
+
+        CacheBind(path: PathBuf) {
+            description("could not bind the shared cache socket")
+            display("could not bind the shared cache socket at {}", path.display())
+        }
+
+        CacheDirNotPrivate(path: PathBuf) {
+            description("the shared cache socket's directory is open to other users")
+            display(
+                "{} must be a directory owned by this user and closed to everyone else",
+                path.display()
+            )
+        }
+
+        Cancelled {
+            description("operation cancelled")
+            display("operation cancelled")
+        }
+
+        CaseCollision(a: PathBuf, b: PathBuf) {
+            description("two paths differ only in case")
+            display(
+                "{} and {} differ only in case and cannot both be checked out here",
+                a.display(),
+                b.display()
+            )
+        }
+
         CatalogDeserialize(path: PathBuf) {
             description("could not deserialize catalog")
             display("could not deserialize catalog at path {}", path.display())
@@ -56,6 +118,16 @@ error_chain! {
             display("an error occurred while filling a catalog entry")
         }
 
+        CatalogLockAbandoned {
+            description("a concurrent write of the same object failed")
+            display("a concurrent write of the same object failed")
+        }
+
+        CheckoutWrite(path: PathBuf) {
+            description("could not write a checked out file or directory")
+            display("could not write checked out file or directory at {}", path.display())
+        }
+
         CloseRefs(path: PathBuf) {
             description("error writing refs to filesystem")
             display("error writing refs to filesystem at path {}", path.display())
@@ -71,16 +143,61 @@ error_chain! {
             display("file {} modified while hashing/updating the index, or racily modified before hashing/updating", path.display())
         }
 
+        DaemonBind(path: PathBuf) {
+            description("could not bind the worktree daemon socket")
+            display("could not bind the worktree daemon socket at {}", path.display())
+        }
+
+        DaemonFailed(message: String) {
+            description("the worktree daemon could not answer a request")
+            display("the worktree daemon could not answer a request: {}", message)
+        }
+
+        DeleteObject(hash: ObjectHash) {
+            description("could not delete an object from the local store")
+            display("could not delete {} from the local store", hash)
+        }
+
         DirTreeDelta {
             description("failure to build a subtree hierarchy")
             display("failure to build a subtree hierarchy")
         }
 
+        EmptyStash {
+            description("there are no stashed changes")
+            display("there are no stashed changes")
+        }
+
         EmptyStore {
             description("attempted to write or read an object to/from the empty store")
             display("Attempted to write or read an object to/from the empty store! The empty store always errors when operated upon.")
         }
 
+        EntrySizeMismatch(path: PathBuf, actual: u64, expected: u64) {
+            description("a file holds a different number of bytes than its subtree entry says")
+            display(
+                "{} holds {} bytes, but its subtree entry says {}",
+                path.display(),
+                actual,
+                expected
+            )
+        }
+
+        GitNotACommit(oid: String) {
+            description("a git object is not a commit")
+            display("{} is not a commit", oid)
+        }
+
+        GitParentNotImported(parent: String, commit: String) {
+            description("a parent of a git commit was not imported before it")
+            display("the parent {} of {} was not imported", parent, commit)
+        }
+
+        IndexConflict {
+            description("the index was changed by another process")
+            display("the index was changed by another process in the meantime; try again")
+        }
+
         IndexOpen {
             description("an error occurred while opening the index file")
             display("an error occurred while opening the index file")
@@ -106,26 +223,120 @@ error_chain! {
             display("attempted to update the index entry for an untracked file")
         }
 
+        IpcMessageTooLarge(len: u64) {
+            description("received an impossibly large IPC message")
+            display("received an impossibly large IPC message of {} bytes", len)
+        }
+
+        InvalidEntryName(name: OsString) {
+            description("invalid name for a subtree entry")
+            display("{:?} is not a valid name for a subtree entry", name)
+        }
+
         InvalidHashLength(len: usize) {
             description("expected a string of 64 hex digits")
             display("expected a string of 64 hex digits, found a string of length {}", len)
         }
 
+        InvalidIdentity(s: String) {
+            description("invalid identity")
+            display("invalid identity `{}`, expected `Name <email>`", s)
+        }
+
         InvalidHashString(s: String) {
             description("could not parse string into hash")
             display("could not parse string `{}` into hash", s)
         }
 
+        InvalidRefName(name: String) {
+            description("invalid ref name")
+            display(
+                "invalid ref name `{}`, expected `heads/<branch>`, `tags/<tag>` or \
+                 `remotes/<remote>/<branch>`",
+                name
+            )
+        }
+
         LocalLoad {
             description("could not load local store")
             display("could not load local store")
         }
 
+        MalformedArchive(reason: String) {
+            description("malformed archive")
+            display("malformed archive: {}", reason)
+        }
+
+        MalformedCommitMap(line: String) {
+            description("malformed line in the git commit map")
+            display("malformed line in the git commit map: `{}`", line)
+        }
+
+        MarshalAbandoned {
+            description("marshalling stopped before every object in a batch was hashed")
+            display("marshalling stopped before every object in a batch was hashed")
+        }
+
+        MetricsBind(addr: String) {
+            description("could not bind the metrics address")
+            display("could not serve metrics at {}", addr)
+        }
+
+        NegotiationCacheCorrupt(path: PathBuf) {
+            description("the negotiation cache is corrupt")
+            display("the negotiation cache {} is corrupt", path.display())
+        }
+
+        NoIdentity {
+            description("no identity is configured")
+            display("no identity is configured; see `attaca whoami`")
+        }
+
+        NoRebaseConflict {
+            description("the rebase in progress is not stopped at a conflict")
+            display("the rebase in progress is not stopped at a conflict")
+        }
+
+        NoConflicts {
+            description("no merge or rebase is stopped at conflicts")
+            display("no merge or rebase is stopped at conflicts")
+        }
+
+        PathNotConflicted(path: PathBuf) {
+            description("a path is not in conflict")
+            display("{} is not in conflict", path.display())
+        }
+
+        NoSuchFile(path: PathBuf, commit: ObjectHash) {
+            description("no such file in commit")
+            display("{} is not a file in commit {}", path.display(), commit)
+        }
+
+        NotSealed {
+            description("object is not sealed")
+            display("object is not sealed")
+        }
+
+        NotInBisection(hash: ObjectHash) {
+            description("commit is not a candidate in the bisection")
+            display("commit {} is not a candidate in the bisection in progress", hash)
+        }
+
+        ObjectMissing(hash: ObjectHash) {
+            description("no such object in the store")
+            display("no object {} in the store", hash)
+        }
+
         ObjectNotACommit(hash: ObjectHash) {
             description("expected a commit, but got a different kind of object")
             display("expected {} to be a commit object, but got a different kind of object", hash)
         }
 
+        ObjectNotData(hash: ObjectHash) {
+            description("expected a data object, but got a different kind of object")
+            display("expected {} to be a data object, but got a different kind of object", hash)
+        }
+
         ObjectNotASubtree(hash: ObjectHash) {
             description("expected a subtree, but got a different kind of object")
             display("expected {} to be a subtree object, but got a different kind of object", hash)
@@ -141,11 +352,61 @@ error_chain! {
             display("error opening serialized refs at path {}", path.display())
         }
 
+        Locked(path: PathBuf) {
+            description("a lock is held by another process")
+            display("the lock at {} is held by another process", path.display())
+        }
+
+        MalformedPack(path: PathBuf, reason: String) {
+            description("malformed pack")
+            display("malformed pack {}: {}", path.display(), reason)
+        }
+
         MalformedSubtree(parent_hash: Option<ObjectHash>, child_hash: ObjectHash) {
             description("subtree contained a non-data, non-subtree object in its entries")
             display("subtree object with hash {:?} contained a non-data, non-subtree object {} in its entries", parent_hash.as_ref().map(ToString::to_string), child_hash)
         }
 
+        ParentNotWritten(parent: ObjectHash, commit: ObjectHash) {
+            description("a parent of a commit was not written before it")
+            display("the parent {} of {} was not written", parent, commit)
+        }
+
+        PathLocked(path: PathBuf, holder: Identity) {
+            description("the path is locked by someone else")
+            display("{} is locked by {}", path.display(), holder)
+        }
+
+        PathNotLocked(path: PathBuf) {
+            description("the path is not locked")
+            display("{} is not locked", path.display())
+        }
+
+        PlaceholdersParse(path: PathBuf) {
+            description("could not parse the lazy checkout placeholders")
+            display("could not parse the lazy checkout placeholders at {}", path.display())
+        }
+
+        RefConflict(branch: String, expected: Option<ObjectHash>, actual: Option<ObjectHash>) {
+            description("a branch did not point at the expected commit")
+            display(
+                "branch `{}` was expected to be at {} but is at {}",
+                branch,
+                expected.map(|hash| hash.to_string()).unwrap_or_else(|| "nothing".to_owned()),
+                actual.map(|hash| hash.to_string()).unwrap_or_else(|| "nothing".to_owned())
+            )
+        }
+
+        RefcountsDisabled {
+            description("reference counting is disabled")
+            display("reference counting is disabled; set `refcounts = true` in config.toml")
+        }
+
+        RefcountsIncomplete {
+            description("reference counts are incomplete")
+            display("reference counts are incomplete, as objects were written before counting was enabled; rebuild them with `attaca maintenance gc --rebuild`")
+        }
+
         RemoteConnect {
             description("could not connect to remote store")
             display("could not connect to remote store")
@@ -176,9 +437,204 @@ error_chain! {
             display("no such remote `{}`", name)
         }
 
+        NoDefaultRemote(direction: &'static str) {
+            description("no remote was named, and there is no default")
+            display("no remote was named, and there is no default {} remote", direction)
+        }
+
+        UnsupportedRemoteBackend(kind: String) {
+            description("this kind of remote object store is not supported yet")
+            display("remote object stores of kind `{}` are not supported yet", kind)
+        }
+
+        UnresolvedConflicts(count: usize) {
+            description("conflicting paths have not all been resolved")
+            display("{} conflicting paths have yet to be resolved", count)
+        }
+
+        UnsupportedDigest(digest: String) {
+            description("a remote addresses objects by an unsupported digest")
+            display("a remote addresses objects by `{}`, but only `sha3-256` is supported", digest)
+        }
+
+        RemoveFile(path: PathBuf) {
+            description("could not remove a file")
+            display("could not remove {}", path.display())
+        }
+
         RepositoryNotFound(path: PathBuf) {
             description("repository not found")
             display("no repository found in {} or in any parent directory", path.display())
         }
+
+        RetriesExhausted(attempts: u32) {
+            description("an operation kept failing after being retried")
+            display("gave up after {} attempts", attempts)
+        }
+
+        RevisionNotFound(rev: String) {
+            description("revision not found")
+            display("`{}` is not a branch, tag, remote branch, or commit hash", rev)
+        }
+
+        SigningKeyOpen(path: PathBuf) {
+            description("could not read a signing key")
+            display("could not read a signing key from {}", path.display())
+        }
+
+        SparseOpen(path: PathBuf) {
+            description("could not open the sparse checkout patterns")
+            display("could not open the sparse checkout patterns at {}", path.display())
+        }
+
+        SshHostKeyMismatch(host: String) {
+            description("an SSH host's key does not match the one in known_hosts")
+            display("the key {} presented does not match the one in known_hosts", host)
+        }
+
+        SshHostUnknown(host: String) {
+            description("an SSH host is not in known_hosts")
+            display("{} is not in known_hosts; connect to it with ssh once to add it", host)
+        }
+
+        SshSession {
+            description("could not start an SSH session")
+            display("could not start an SSH session")
+        }
+
+        SubtreeJoinOccupied(path: PathBuf) {
+            description("cannot join a subtree at a path which already exists")
+            display("cannot join a subtree at {}, which already exists", path.display())
+        }
+
+        SyncApply(status: i32, stderr: String) {
+            description("applying a sync failed")
+            display("applying the sync failed with status {}: {}", status, stderr)
+        }
+
+        SyncFileNotBegun {
+            description("a synced file was written to before it was begun")
+            display("a synced file was written to before it was begun")
+        }
+
+        SyncSourceTruncated(path: PathBuf) {
+            description("a synced file is shorter than its sync manifest says")
+            display("{} is shorter than its sync manifest says", path.display())
+        }
+
+        UnrepresentableName(path: PathBuf) {
+            description("path cannot be created on this platform")
+            display("{} cannot be created on this platform", path.display())
+        }
+
+        SealedObject {
+            description("an object is sealed, and no key to open it was configured")
+            display("an object is sealed, and no key to open it was configured; set the remote's `encryption_key`")
+        }
+
+        SealFailed(hash: ObjectHash) {
+            description("could not seal an object")
+            display("could not seal object {}", hash)
+        }
+
+        UnsealFailed(hash: ObjectHash) {
+            description("could not open a sealed object")
+            display("could not open sealed object {}: the key is wrong, or the envelope was tampered with", hash)
+        }
+
+        UnsealedObject(hash: ObjectHash) {
+            description("an object on an encrypted remote is not sealed")
+            display("object {} is not sealed, but the remote it was read from is encrypted", hash)
+        }
+
+        CorruptEnvelope(hash: ObjectHash) {
+            description("a stored object is corrupt")
+            display("stored object {} is corrupt", hash)
+        }
+
+        EncryptionKeyOpen(path: PathBuf) {
+            description("could not open encryption key")
+            display("could not open encryption key {}", path.display())
+        }
+
+        GeneratorFailed(name: String, status: String) {
+            description("a metadata generator failed")
+            display("metadata generator `{}` failed: {}", name, status)
+        }
+
+        InvalidGeneratorName(name: String) {
+            description("invalid metadata generator name")
+            display("`{}` is not a valid generator name: names must be non-empty and may not contain `/`", name)
+        }
+
+        UnsupportedObjectVersion(version: u8) {
+            description("an object is encoded with an unsupported format version")
+            display("an object is encoded with format version {}, which this version of attaca does not support; upgrading may help", version)
+        }
+
+        HookFailed(event: String, command: String, status: String) {
+            description("a hook failed")
+            display("{} hook `{}` failed: {}", event, command, status)
+        }
+
+        FilterFailed(direction: String, command: String, reason: String) {
+            description("a content filter failed")
+            display("{} filter `{}` failed: {}", direction, command, reason)
+        }
+
+        SearchIndexDisabled {
+            description("the search index is disabled")
+            display("the search index is disabled; set `search_index = true` in config.toml")
+        }
+
+        WatchNotCompiled {
+            description("filesystem watching was not compiled into this build")
+            display("filesystem watching was not compiled into this build of attaca; rebuild with `--features watch`")
+        }
+
+        MaterializedParse(path: PathBuf) {
+            description("could not parse the record of hydrated files")
+            display("could not parse the record of hydrated files at {}", path.display())
+        }
+
+        NoEvictionPolicy {
+            description("no eviction limits are set")
+            display("no eviction limits are set; pass `--max-bytes` or `--max-idle-days`, or set them under `[eviction]` in config.toml")
+        }
+
+        ReplicationFailed(hash: ObjectHash, acked: usize, required: usize) {
+            description("too few replicas acknowledged a write")
+            display("only {} of the {} replicas needed acknowledged writing {}", acked, required, hash)
+        }
+
+        RecoveryUnavailable(action: String, what: String) {
+            description("an interrupted operation cannot be recovered that way")
+            display("cannot {} {}", action, what)
+        }
+
+        TimedOut(ms: u64) {
+            description("a store operation timed out")
+            display("a store operation did not finish within {}ms", ms)
+        }
+
+        TruncatedObject(hash: ObjectHash) {
+            description("an object on the remote ended early")
+            display("unexpected end of object {} on the remote", hash)
+        }
+
+        UnsupportedArchiveMember(path: PathBuf, reason: String) {
+            description("an archive member cannot be imported")
+            display("{} cannot be imported: {}", path.display(), reason)
+        }
+
+        WorktreeNotFound(path: PathBuf) {
+            description("not a linked worktree of this repository")
+            display("{} is not a linked worktree of this repository", path.display())
+        }
+
+        WriteAbandoned {
+            description("a write stopped before it finished")
+            display("a write stopped before it finished")
+        }
     }
 }
@@ -0,0 +1,296 @@
+//! # `evict` - turning hydrated files back into placeholders.
+//!
+//! A lazy checkout only takes up disk space for the files which are actually hydrated, but over
+//! time the hydrated files of a large checkout can fill the disk all the same. Eviction turns
+//! hydrated files back into placeholders, least recently used first, until those remaining fit
+//! within the given limits:
+//!
+//! * `max_bytes` - the most space hydrated files may take up in total.
+//! * `max_idle_days` - the longest a hydrated file may go unused.
+//!
+//! Only files which are exactly as they were hydrated are ever evicted. Once a hydrated file is
+//! modified, its contents are the user's, and it is no longer considered for eviction. Evicted
+//! files may be hydrated again at any time. Limits set under `[eviction]` in `config.toml` are
+//! enforced automatically whenever files are hydrated.
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Duration;
+use chrono::prelude::*;
+use globset::GlobSet;
+
+use errors::*;
+use index::Index;
+use repository::{Config, EvictionCfg, Repository};
+
+
+/// Limits on the files hydrated from placeholders.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvictionPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_idle: Option<Duration>,
+}
+
+
+impl<'a> From<&'a EvictionCfg> for EvictionPolicy {
+    fn from(cfg: &'a EvictionCfg) -> Self {
+        EvictionPolicy {
+            max_bytes: cfg.max_bytes,
+            max_idle: cfg.max_idle_days.map(|days| Duration::days(days as i64)),
+        }
+    }
+}
+
+
+impl EvictionPolicy {
+    /// The limits configured for a repository, if any.
+    pub fn configured(config: &Config) -> Option<Self> {
+        config.eviction.as_ref().map(EvictionPolicy::from).and_then(|policy| {
+            if policy.is_unlimited() {
+                None
+            } else {
+                Some(policy)
+            }
+        })
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_idle.is_none()
+    }
+}
+
+
+/// A hydrated file which may be evicted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_used: DateTime<Utc>,
+}
+
+
+/// The hydrated files to evict, and those which are no longer eligible for eviction at all.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    /// Files to evict, least recently used first.
+    pub evict: Vec<Candidate>,
+
+    /// Hydrated files which have since been modified or removed, and so should be forgotten.
+    pub forget: Vec<PathBuf>,
+
+    /// The bytes taken up by hydrated files which will remain once the plan is carried out.
+    pub retained_bytes: u64,
+}
+
+
+impl Plan {
+    pub fn evicted_bytes(&self) -> u64 {
+        self.evict.iter().map(|candidate| candidate.size).sum()
+    }
+}
+
+
+/// Every hydrated file which is unchanged since it was hydrated, least recently used first,
+/// along with those which are no longer eligible for eviction.
+fn survey(index: &Index, base: &Path) -> Result<(Vec<Candidate>, Vec<PathBuf>)> {
+    let mut candidates = Vec::new();
+    let mut forget = Vec::new();
+
+    for (path, file) in index.materialized().iter() {
+        let metadata = match base.join(path).symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                forget.push(path.to_owned());
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        if file.is_unmodified(&metadata) {
+            candidates.push(Candidate {
+                path: path.to_owned(),
+                size: file.size,
+                last_used: file.last_used(&metadata),
+            });
+        } else {
+            forget.push(path.to_owned());
+        }
+    }
+
+    candidates.sort_by(|a, b| a.last_used.cmp(&b.last_used));
+
+    Ok((candidates, forget))
+}
+
+
+/// Choose which hydrated files to evict so that those remaining satisfy `policy` as of `now`.
+/// Files in `protected` are never chosen, even if that leaves the limits unsatisfied.
+pub fn plan(
+    index: &Index,
+    base: &Path,
+    policy: &EvictionPolicy,
+    protected: &HashSet<PathBuf>,
+    now: DateTime<Utc>,
+) -> Result<Plan> {
+    let (candidates, forget) = survey(index, base)?;
+    let mut retained_bytes = candidates.iter().map(|candidate| candidate.size).sum::<u64>();
+    let mut evict = Vec::new();
+
+    for candidate in candidates {
+        let idle = policy.max_idle.map_or(false, |max_idle| {
+            now.signed_duration_since(candidate.last_used) > max_idle
+        });
+        let over_budget = policy.max_bytes.map_or(false, |max_bytes| retained_bytes > max_bytes);
+
+        if (idle || over_budget) && !protected.contains(&candidate.path) {
+            retained_bytes -= candidate.size;
+            evict.push(candidate);
+        }
+    }
+
+    Ok(Plan {
+        evict,
+        forget,
+        retained_bytes,
+    })
+}
+
+
+/// Choose every hydrated file matching `pattern` for eviction, regardless of any limits.
+pub fn plan_matching(index: &Index, base: &Path, pattern: &GlobSet) -> Result<Plan> {
+    let (candidates, forget) = survey(index, base)?;
+    let (evict, retained) = candidates.into_iter().partition::<Vec<_>, _>(|candidate| {
+        pattern.is_match(&candidate.path)
+    });
+
+    Ok(Plan {
+        evict,
+        forget,
+        retained_bytes: retained.iter().map(|candidate| candidate.size).sum(),
+    })
+}
+
+
+/// What carrying out a plan actually evicted.
+#[derive(Debug, Clone, Default)]
+pub struct Evicted {
+    pub paths: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+
+/// Turn the files chosen by `plan` back into placeholders, and forget those it found to be no
+/// longer eligible. Files modified since the plan was made are forgotten rather than evicted.
+pub fn apply(index: &mut Index, base: &Path, plan: &Plan) -> Result<Evicted> {
+    let mut evicted = Evicted::default();
+
+    for path in &plan.forget {
+        index.materialized_mut().remove(path);
+    }
+
+    for candidate in &plan.evict {
+        let file = match index.materialized_mut().remove(&candidate.path) {
+            Some(file) => file,
+            None => continue,
+        };
+
+        let absolute_path = base.join(&candidate.path);
+        let unmodified = absolute_path.symlink_metadata().map(
+            |metadata| file.is_unmodified(&metadata),
+        );
+        if !unmodified.unwrap_or(false) {
+            continue;
+        }
+
+        // Truncating the file frees its blocks; extending it again leaves a sparse placeholder
+        // of the right size.
+        let handle = OpenOptions::new().write(true).open(&absolute_path).chain_err(|| {
+            ErrorKind::CheckoutWrite(absolute_path.clone())
+        })?;
+        handle.set_len(0).and_then(|()| handle.set_len(file.size)).chain_err(|| {
+            ErrorKind::CheckoutWrite(absolute_path.clone())
+        })?;
+
+        let metadata = absolute_path.symlink_metadata().chain_err(|| {
+            ErrorKind::CheckoutWrite(absolute_path.clone())
+        })?;
+        index.placeholders_mut().insert(&candidate.path, file.object_hash, &metadata);
+        evicted.paths.push(candidate.path.clone());
+        evicted.bytes += file.size;
+    }
+
+    Ok(evicted)
+}
+
+
+/// Enforce the eviction limits configured for a repository, if any, never evicting the files in
+/// `protected`.
+pub fn enforce(repository: &mut Repository, protected: &HashSet<PathBuf>) -> Result<Evicted> {
+    let policy = match EvictionPolicy::configured(&repository.config) {
+        Some(policy) => policy,
+        None => return Ok(Evicted::default()),
+    };
+
+    let plan = plan(
+        &repository.index,
+        &repository.paths.base,
+        &policy,
+        protected,
+        Utc::now(),
+    )?;
+
+    apply(&mut repository.index, &repository.paths.base, &plan)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use bench::Scratch;
+    use marshal::ObjectHash;
+
+    #[test]
+    fn evict_within_budget() {
+        let scratch = Scratch::new("attaca-evict").unwrap();
+        let root = scratch.path();
+        Repository::init(&root).unwrap();
+        let mut repository = Repository::load(&root).unwrap();
+
+        for &(name, contents) in &[("a", &b"hello"[..]), ("b", &b"hey"[..])] {
+            File::create(root.join(name)).unwrap().write_all(contents).unwrap();
+            let metadata = fs::metadata(root.join(name)).unwrap();
+            repository.index.materialized_mut().insert(name, ObjectHash::zero(), &metadata);
+        }
+
+        let policy = EvictionPolicy {
+            max_bytes: Some(5),
+            max_idle: None,
+        };
+        let protected = vec![PathBuf::from("a")].into_iter().collect();
+        let chosen = plan(&repository.index, &root, &policy, &protected, Utc::now()).unwrap();
+        assert_eq!(chosen.evicted_bytes(), 3);
+        assert_eq!(chosen.retained_bytes, 5);
+
+        let evicted = apply(&mut repository.index, &root, &chosen).unwrap();
+        assert_eq!(evicted.paths, vec![PathBuf::from("b")]);
+        assert!(repository.index.placeholders().contains("b"));
+        assert!(repository.index.materialized().get("b").is_none());
+        assert_eq!(fs::metadata(root.join("b")).unwrap().len(), 3);
+
+        // Two days from now, the remaining file will have gone unused for more than a day.
+        let policy = EvictionPolicy {
+            max_bytes: None,
+            max_idle: Some(Duration::days(1)),
+        };
+        let later = Utc::now() + Duration::days(2);
+        let chosen = plan(&repository.index, &root, &policy, &HashSet::new(), later).unwrap();
+        assert_eq!(chosen.evicted_bytes(), 5);
+    }
+}
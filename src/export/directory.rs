@@ -0,0 +1,171 @@
+//! # `directory` - publish commits into a shared directory, atomically.
+//!
+//! A publish directory holds one fully materialized snapshot per published commit, named by the
+//! commit's hash, along with a `latest` symlink pointing at the most recently published one:
+//!
+//! ```text
+//! target
+//! +-- latest -> 3f2a...
+//! +-- 3f2a... (the newest snapshot)
+//! +-- 91c0... (an older snapshot, kept for readers still using it)
+//! ```
+//!
+//! Snapshots are checked out into a hidden temporary directory, synced to disk, and renamed into
+//! place; only then is `latest` swapped over by renaming a new symlink on top of it. Tools which
+//! read through `latest` therefore only ever see a complete snapshot, and need know nothing about
+//! attaca.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use futures::prelude::*;
+use libc;
+
+use checkout::{self, CheckoutOptions, Listing};
+use errors::*;
+use marshal::{ObjectHash, SubtreeEntry};
+use store::ObjectStore;
+use CHECKOUT_OPEN_FILES;
+
+
+/// The name of the symlink pointing at the most recently published snapshot.
+pub const LATEST: &str = "latest";
+
+
+/// Options controlling how a commit is published.
+#[derive(Debug, Clone)]
+pub struct PublishOptions {
+    /// The maximum number of files which may be open for writing at any given time.
+    pub open_files: usize,
+
+    /// The number of snapshots to keep, including the one just published. Older snapshots are
+    /// deleted. At least one is always kept.
+    pub keep: usize,
+}
+
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        PublishOptions {
+            open_files: CHECKOUT_OPEN_FILES,
+            keep: 2,
+        }
+    }
+}
+
+
+fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+
+/// Flush every file and directory of a freshly checked out snapshot to disk.
+fn sync_listing(root: &Path, listing: &Listing) -> Result<()> {
+    for &(ref path, ref entry) in &listing.files {
+        if let SubtreeEntry::Symlink(_) = entry.unannotated() {
+            continue;
+        }
+
+        File::open(root.join(path))?.sync_all()?;
+    }
+
+    for directory in &listing.directories {
+        sync_dir(&root.join(directory))?;
+    }
+
+    sync_dir(root)
+}
+
+
+fn is_snapshot_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| (b as char).is_digit(16))
+}
+
+
+/// Delete all but the `keep` most recently modified snapshots, never deleting `current`.
+fn prune(target: &Path, current: &str, keep: usize) -> Result<()> {
+    let mut snapshots = Vec::new();
+
+    for entry_res in fs::read_dir(target)? {
+        let entry = entry_res?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        if name == current || !is_snapshot_name(&name) || !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        snapshots.push((entry.metadata()?.modified()?, entry.path()));
+    }
+
+    snapshots.sort_by(|a, b| b.0.cmp(&a.0));
+
+    for &(_, ref path) in snapshots.iter().skip(cmp::max(keep, 1) - 1) {
+        fs::remove_dir_all(path)?;
+    }
+
+    Ok(())
+}
+
+
+/// Publish a commit's subtree into the `target` directory, creating it if it does not exist, and
+/// point `latest` at it. Publishing a commit which is already present only swaps the symlink.
+/// Returns the path of the published snapshot.
+pub fn publish<S: ObjectStore, P: AsRef<Path>>(
+    store: &S,
+    commit_hash: ObjectHash,
+    subtree_hash: ObjectHash,
+    target: P,
+    options: &PublishOptions,
+) -> Box<Future<Item = PathBuf, Error = Error> + Send> {
+    let store = store.clone();
+    let target = target.as_ref().to_owned();
+    let options = options.clone();
+
+    let result = {
+        async_block! {
+            let name = commit_hash.to_string();
+            let snapshot = target.join(&name);
+            let pid = unsafe { libc::getpid() };
+
+            fs::create_dir_all(&target).chain_err(|| ErrorKind::CheckoutWrite(target.clone()))?;
+
+            if !snapshot.exists() {
+                let temp = target.join(format!(".{}.tmp-{}", name, pid));
+                if temp.exists() {
+                    fs::remove_dir_all(&temp)?;
+                }
+
+                let checkout_options = CheckoutOptions {
+                    open_files: options.open_files,
+                    ..CheckoutOptions::default()
+                };
+                let listing =
+                    await!(checkout::checkout(&store, subtree_hash, &temp, &checkout_options))?;
+                sync_listing(&temp, &listing)?;
+
+                fs::rename(&temp, &snapshot)?;
+                sync_dir(&target)?;
+            }
+
+            let temp_link = target.join(format!(".{}.tmp-{}", LATEST, pid));
+            if fs::symlink_metadata(&temp_link).is_ok() {
+                fs::remove_file(&temp_link)?;
+            }
+            symlink(&name, &temp_link)?;
+            fs::rename(&temp_link, target.join(LATEST))?;
+            sync_dir(&target)?;
+
+            prune(&target, &name, options.keep)?;
+
+            Ok(snapshot)
+        }
+    };
+
+    Box::new(result)
+}
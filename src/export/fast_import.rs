@@ -0,0 +1,472 @@
+//! `git fast-import` streams.
+//!
+//! Commit history is written as a stream which `git fast-import` reads into a git repository, so
+//! that snapshots can be mirrored into existing git hosting for browsing. Commits are written
+//! oldest first, each keeping its parents, message, author, committer and timestamp, and each
+//! listing only the files changed since its first parent.
+//!
+//! Small files are written inline with the commits which change them. Larger files are written
+//! once each as separate blobs, however many commits and paths they appear under. Optionally,
+//! files above a size threshold are written as git LFS pointers instead, with a `.gitattributes`
+//! at the root of each commit routing them through LFS; the objects the pointers stand for may be
+//! written into a directory laid out like `.git/lfs/objects`, to be copied there.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use futures::prelude::*;
+use sha2::{Digest, Sha256};
+
+use checkout;
+use errors::*;
+use history;
+use marshal::{CommitObject, Identity, Object, ObjectHash, SubtreeEntry};
+use store::ObjectStore;
+
+
+/// By default, files of up to 64 KB are written inline.
+pub const DEFAULT_INLINE_LIMIT: u64 = 64 * 1024;
+
+
+#[derive(Debug, Clone)]
+pub struct FastImportOptions {
+    /// Files of at most this many bytes are written inline with their commits; larger ones are
+    /// written as separate blobs, once each.
+    pub inline_limit: u64,
+
+    /// Files larger than this many bytes are written as git LFS pointers, if set.
+    pub lfs_threshold: Option<u64>,
+
+    /// Where to write the objects LFS pointers stand for, if anywhere.
+    pub lfs_objects: Option<PathBuf>,
+}
+
+
+impl Default for FastImportOptions {
+    fn default() -> Self {
+        FastImportOptions {
+            inline_limit: DEFAULT_INLINE_LIMIT,
+            lfs_threshold: None,
+            lfs_objects: None,
+        }
+    }
+}
+
+
+/// The outcome of an export.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Exported {
+    /// The number of commits written.
+    pub commits: u64,
+
+    /// The number of files written as LFS pointers, counting each distinct file once.
+    pub lfs_files: u64,
+}
+
+
+/// How the contents of a file are given in the stream.
+#[derive(Debug, Clone)]
+enum Contents {
+    /// A blob already written, by its mark.
+    Mark(u64),
+
+    /// Bytes to be written inline.
+    Inline(Vec<u8>),
+}
+
+
+/// Quote a path as `git fast-import` wants, should it need quoting.
+fn quote_path(path: &Path) -> Vec<u8> {
+    let bytes = path.as_os_str().as_bytes();
+    let needs_quoting = bytes.starts_with(b"\"") ||
+        bytes.iter().any(|&byte| byte == b'\n' || byte == b'\\');
+    if !needs_quoting {
+        return bytes.to_vec();
+    }
+
+    let mut quoted = vec![b'"'];
+    for &byte in bytes {
+        match byte {
+            b'"' => quoted.extend_from_slice(b"\\\""),
+            b'\\' => quoted.extend_from_slice(b"\\\\"),
+            b'\n' => quoted.extend_from_slice(b"\\n"),
+            _ => quoted.push(byte),
+        }
+    }
+    quoted.push(b'"');
+
+    quoted
+}
+
+
+/// Escape a path for use as a `.gitattributes` pattern, as `git lfs track` does.
+fn attributes_pattern(path: &Path) -> Vec<u8> {
+    let mut pattern = Vec::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b' ' => pattern.extend_from_slice(b"[[:space:]]"),
+            b'*' | b'?' | b'[' | b']' | b'\\' | b'#' | b'!' => {
+                pattern.push(b'\\');
+                pattern.push(byte);
+            }
+            _ => pattern.push(byte),
+        }
+    }
+
+    pattern
+}
+
+
+/// The attributes which route a file through LFS, as `git lfs track` writes them.
+const LFS_ATTRIBUTES: &[u8] = b" filter=lfs diff=lfs merge=lfs -text\n";
+
+
+fn lfs_pointer(sha256: &str, size: u64) -> Vec<u8> {
+    format!(
+        "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize {}\n",
+        sha256,
+        size
+    ).into_bytes()
+}
+
+
+/// An author or committer line.
+fn person(role: &str, identity: Option<&Identity>, commit_object: &CommitObject) -> Vec<u8> {
+    let (name, email) = match identity {
+        Some(identity) => (identity.name.as_str(), identity.email.as_str()),
+        None => ("Unknown", "unknown"),
+    };
+
+    format!(
+        "{} {} <{}> {} +0000\n",
+        role,
+        name,
+        email,
+        commit_object.timestamp.timestamp()
+    ).into_bytes()
+}
+
+
+fn data<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    write!(writer, "data {}\n", bytes.len())?;
+    writer.write_all(bytes)?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+
+/// Read a whole data object into memory.
+fn read_data<S: ObjectStore>(
+    store: &S,
+    object_hash: ObjectHash,
+) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+    let folded = checkout::data_chunks(store, object_hash).fold(Vec::new(), |mut bytes, chunk| {
+        bytes.extend_from_slice(&chunk);
+        Ok::<_, Error>(bytes)
+    });
+
+    Box::new(folded)
+}
+
+
+/// Hash a data object as LFS does, writing it into `lfs_objects` along the way if given.
+fn lfs_object<S: ObjectStore>(
+    store: &S,
+    object_hash: ObjectHash,
+    lfs_objects: Option<PathBuf>,
+) -> Box<Future<Item = String, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let mut hasher = Sha256::default();
+            let mut file_opt = match lfs_objects {
+                Some(ref lfs_objects) => {
+                    fs::create_dir_all(lfs_objects)?;
+                    let temp_path = lfs_objects.join(format!("{}.tmp", object_hash));
+                    Some((File::create(&temp_path)?, temp_path))
+                }
+                None => None,
+            };
+
+            #[async]
+            for chunk in checkout::data_chunks(&store, object_hash) {
+                hasher.input(&chunk);
+                if let Some((ref mut file, _)) = file_opt {
+                    file.write_all(&chunk)?;
+                }
+            }
+
+            let sha256 = hasher
+                .result()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>();
+
+            if let (Some(lfs_objects), Some((_, temp_path))) = (lfs_objects, file_opt) {
+                let dir = lfs_objects.join(&sha256[..2]).join(&sha256[2..4]);
+                fs::create_dir_all(&dir)?;
+                fs::rename(temp_path, dir.join(&sha256))?;
+            }
+
+            Ok(sha256)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// The files of a commit, by path.
+fn commit_files<S: ObjectStore>(
+    store: &S,
+    commit_hash: ObjectHash,
+) -> Box<Future<Item = BTreeMap<PathBuf, SubtreeEntry>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_hash = match await!(store.read_object(commit_hash))? {
+                Object::Commit(commit_object) => commit_object.subtree,
+                _ => bail!(ErrorKind::ObjectNotACommit(commit_hash)),
+            };
+            let listing = await!(checkout::walk(&store, subtree_hash))?;
+
+            Ok(listing.files.into_iter().collect())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Write the history of each of `refs`, given as git ref names along with the commits they
+/// should point to, to `writer` as a `git fast-import` stream. History shared between refs is
+/// only written once. Writes to `writer` block, so it should not be driven on an event loop.
+pub fn write<S: ObjectStore, W: Write + Send + 'static>(
+    store: &S,
+    refs: Vec<(String, ObjectHash)>,
+    options: FastImportOptions,
+    mut writer: W,
+) -> Box<Future<Item = (W, Exported), Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let mut exported = Exported::default();
+            let mut next_mark = 1u64;
+            let mut commit_marks = HashMap::<ObjectHash, u64>::new();
+            let mut blob_marks = HashMap::<ObjectHash, u64>::new();
+            let mut lfs_shas = HashMap::<ObjectHash, String>::new();
+
+            let lfs_threshold = options.lfs_threshold;
+            let is_lfs = move |entry: &SubtreeEntry| match (entry.unannotated(), lfs_threshold) {
+                (SubtreeEntry::File(_, size), Some(threshold)) |
+                (SubtreeEntry::Executable(_, size), Some(threshold)) => size > threshold,
+                _ => false,
+            };
+
+            // The files of the commit written last, which is usually the first parent of the
+            // next, so that its tree need not be walked again.
+            let mut previous: Option<(ObjectHash, BTreeMap<PathBuf, SubtreeEntry>)> = None;
+
+            for (git_ref, head) in refs {
+                for (commit_hash, commit_object) in await!(history::ancestry(&store, head))? {
+                    if commit_marks.contains_key(&commit_hash) {
+                        continue;
+                    }
+
+                    let files = await!(commit_files(&store, commit_hash))?;
+
+                    let first_parent = commit_object.parents.first().cloned();
+                    let follows_previous = match (first_parent, previous.as_ref()) {
+                        (Some(parent), Some(&(previous_hash, _))) => parent == previous_hash,
+                        _ => false,
+                    };
+                    let parent_files = match first_parent {
+                        Some(_) if follows_previous => previous.take().unwrap().1,
+                        Some(parent) => await!(commit_files(&store, parent))?,
+                        None => BTreeMap::new(),
+                    };
+
+                    let entries = files
+                        .iter()
+                        .map(|(path, entry)| (path.clone(), entry.clone()))
+                        .collect::<Vec<_>>();
+                    let mut modified = Vec::new();
+                    let mut lfs_paths = Vec::new();
+
+                    for (path, entry) in entries {
+                        let unchanged = parent_files
+                            .get(&path)
+                            .map_or(false, |parent| parent.unannotated() == entry.unannotated());
+                        let lfs = is_lfs(&entry);
+                        if lfs {
+                            lfs_paths.push(path.clone());
+                        }
+                        if unchanged {
+                            continue;
+                        }
+
+                        let (mode, object_hash, size) = match entry.unannotated() {
+                            SubtreeEntry::File(hash, size) => ("100644", hash, size),
+                            SubtreeEntry::Executable(hash, size) => ("100755", hash, size),
+                            SubtreeEntry::Symlink(hash) => {
+                                let target = await!(read_data(&store, hash))?;
+                                modified.push((path, "120000", Contents::Inline(target)));
+                                continue;
+                            }
+                            _ => unreachable!("subtrees are never listed as files"),
+                        };
+
+                        let contents = if lfs {
+                            if !lfs_shas.contains_key(&object_hash) {
+                                let lfs_objects = options.lfs_objects.clone();
+                                let sha256 = await!(lfs_object(&store, object_hash, lfs_objects))?;
+                                lfs_shas.insert(object_hash, sha256);
+                                exported.lfs_files += 1;
+                            }
+
+                            Contents::Inline(lfs_pointer(&lfs_shas[&object_hash], size))
+                        } else if size <= options.inline_limit {
+                            Contents::Inline(await!(read_data(&store, object_hash))?)
+                        } else {
+                            if !blob_marks.contains_key(&object_hash) {
+                                let blob_mark = next_mark;
+                                next_mark += 1;
+
+                                write!(writer, "blob\nmark :{}\ndata {}\n", blob_mark, size)?;
+                                #[async]
+                                for chunk in checkout::data_chunks(&store, object_hash) {
+                                    writer.write_all(&chunk)?;
+                                }
+                                writer.write_all(b"\n")?;
+
+                                blob_marks.insert(object_hash, blob_mark);
+                            }
+
+                            Contents::Mark(blob_marks[&object_hash])
+                        };
+
+                        modified.push((path, mode, contents));
+                    }
+
+                    // Files sent through LFS need a `.gitattributes` saying so. It is written
+                    // whenever the first parent's was, too, so that it never goes stale.
+                    let parent_has_lfs = parent_files.values().any(|entry| is_lfs(entry));
+                    let attributes_opt = if !lfs_paths.is_empty() || parent_has_lfs {
+                        let own_hash = match files.get(Path::new(".gitattributes")) {
+                            Some(entry) => Some(entry.hash()),
+                            None => None,
+                        };
+                        let mut attributes = match own_hash {
+                            Some(own_hash) => await!(read_data(&store, own_hash))?,
+                            None => Vec::new(),
+                        };
+
+                        if !attributes.is_empty() && !attributes.ends_with(b"\n") {
+                            attributes.push(b'\n');
+                        }
+                        for path in &lfs_paths {
+                            attributes.push(b'/');
+                            attributes.extend(attributes_pattern(path));
+                            attributes.extend_from_slice(LFS_ATTRIBUTES);
+                        }
+
+                        Some(attributes)
+                    } else {
+                        None
+                    };
+
+                    let mark = next_mark;
+                    next_mark += 1;
+
+                    let author = person("author", commit_object.author.as_ref(), &commit_object);
+                    let committer =
+                        person("committer", commit_object.committer.as_ref(), &commit_object);
+                    write!(writer, "commit {}\nmark :{}\n", git_ref, mark)?;
+                    writer.write_all(&author)?;
+                    writer.write_all(&committer)?;
+                    data(&mut writer, commit_object.message.as_bytes())?;
+
+                    for (index, parent) in commit_object.parents.iter().enumerate() {
+                        let command = if index == 0 { "from" } else { "merge" };
+                        let parent_mark = match commit_marks.get(parent) {
+                            Some(&parent_mark) => parent_mark,
+                            None => bail!(ErrorKind::ParentNotWritten(*parent, commit_hash)),
+                        };
+                        write!(writer, "{} :{}\n", command, parent_mark)?;
+                    }
+
+                    for path in parent_files.keys() {
+                        if !files.contains_key(path) {
+                            writer.write_all(b"D ")?;
+                            writer.write_all(&quote_path(path))?;
+                            writer.write_all(b"\n")?;
+                        }
+                    }
+
+                    for (path, mode, contents) in modified {
+                        match contents {
+                            Contents::Mark(blob_mark) => {
+                                write!(writer, "M {} :{} ", mode, blob_mark)?
+                            }
+                            Contents::Inline(_) => write!(writer, "M {} inline ", mode)?,
+                        }
+                        writer.write_all(&quote_path(&path))?;
+                        writer.write_all(b"\n")?;
+
+                        if let Contents::Inline(bytes) = contents {
+                            data(&mut writer, &bytes)?;
+                        }
+                    }
+
+                    match attributes_opt {
+                        Some(ref attributes) if attributes.is_empty() => {
+                            writer.write_all(b"D .gitattributes\n")?;
+                        }
+                        Some(ref attributes) => {
+                            writer.write_all(b"M 100644 inline .gitattributes\n")?;
+                            data(&mut writer, attributes)?;
+                        }
+                        None => {}
+                    }
+
+                    writer.write_all(b"\n")?;
+
+                    commit_marks.insert(commit_hash, mark);
+                    exported.commits += 1;
+                    previous = Some((commit_hash, files));
+                }
+
+                // Point the ref at its head even if every commit was written for an earlier ref.
+                write!(writer, "reset {}\nfrom :{}\n\n", git_ref, commit_marks[&head])?;
+            }
+
+            Ok((writer, exported))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths_are_quoted() {
+        assert_eq!(quote_path(Path::new("a b/c")), b"a b/c".to_vec());
+        assert_eq!(quote_path(Path::new("a\nb")), b"\"a\\nb\"".to_vec());
+        assert_eq!(quote_path(Path::new("\"a\"")), b"\"\\\"a\\\"\"".to_vec());
+        assert_eq!(
+            attributes_pattern(Path::new("big file[1].bin")),
+            b"big[[:space:]]file\\[1\\].bin".to_vec()
+        );
+    }
+}
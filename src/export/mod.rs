@@ -0,0 +1,18 @@
+//! # `export` - write the contents of a subtree out of the repository.
+//!
+//! Archives are written straight from the object store, without checking anything out onto the
+//! filesystem first. Publish directories hold plain checked out snapshots for tools which know
+//! nothing about attaca, and synced directories are brought up to date with a commit by sending
+//! only the chunks they lack, locally or over SSH. Whole histories can be written as
+//! `git fast-import` streams, for mirroring into git.
+
+pub mod directory;
+pub mod fast_import;
+pub mod seekable;
+pub mod sync_to;
+pub mod tar;
+pub mod zip;
+
+pub use self::fast_import::write as to_fast_import;
+pub use self::tar::write as to_tar;
+pub use self::zip::write as to_zip;
@@ -0,0 +1,434 @@
+//! Seekable zstd archives.
+//!
+//! An archive is a sequence of independent zstd frames followed by a seek table, in the format
+//! described by the zstd project's [seekable format specification][spec]. Any zstd decoder can
+//! decompress the whole archive as a single stream, but a reader which understands the seek table
+//! can decompress any range of the stream by decompressing only the frames overlapping it.
+//!
+//! The decompressed stream consists of the contents of every file in the subtree, one after the
+//! other, followed by an index and then the length of the index as a little-endian `u64`. Every
+//! file begins on a frame boundary, so extracting a single file never decompresses any data
+//! belonging to another file. The index is JSON, so that it may be read without this crate; it
+//! maps every path to the offset and size of its contents in the decompressed stream.
+//!
+//! [spec]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use futures::prelude::*;
+use serde_json;
+use zstd;
+
+use checkout;
+use errors::*;
+use marshal::{ObjectHash, SubtreeEntry};
+use store::ObjectStore;
+
+
+/// The magic number of the skippable frame holding the seek table.
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+
+
+/// The magic number ending the seek table.
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+
+
+/// The size of the seek table footer: the number of frames, the descriptor, and the magic number.
+const FOOTER_SIZE: u64 = 9;
+
+
+/// The version of the index format written.
+pub const INDEX_VERSION: u32 = 1;
+
+
+/// Options controlling how a seekable archive is written.
+#[derive(Debug, Clone, Copy)]
+pub struct SeekableOptions {
+    /// The maximum number of decompressed bytes in a single frame. Smaller frames make reads of
+    /// small ranges cheaper at the cost of compression ratio.
+    pub frame_size: usize,
+
+    /// The zstd compression level.
+    pub level: i32,
+}
+
+
+impl Default for SeekableOptions {
+    fn default() -> Self {
+        SeekableOptions {
+            frame_size: 1 << 20,
+            level: 3,
+        }
+    }
+}
+
+
+/// What kind of filesystem entry an archived file came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Executable,
+    Symlink,
+}
+
+
+/// The location of a single file's contents in the decompressed stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub kind: EntryKind,
+    pub offset: u64,
+    pub size: u64,
+}
+
+
+/// The index embedded at the end of a seekable archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Index {
+    pub version: u32,
+    pub entries: BTreeMap<PathBuf, IndexEntry>,
+}
+
+
+/// Accumulates decompressed bytes and writes them out as frames, keeping track of the seek table.
+struct FrameWriter<W: Write> {
+    writer: W,
+    options: SeekableOptions,
+    buffer: Vec<u8>,
+    frames: Vec<(u32, u32)>,
+    decompressed: u64,
+}
+
+
+impl<W: Write> FrameWriter<W> {
+    fn new(writer: W, options: SeekableOptions) -> Self {
+        FrameWriter {
+            writer,
+            options,
+            buffer: Vec::with_capacity(options.frame_size),
+            frames: Vec::new(),
+            decompressed: 0,
+        }
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) -> Result<()> {
+        while !bytes.is_empty() {
+            let n = cmp::min(self.options.frame_size - self.buffer.len(), bytes.len());
+            self.buffer.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+
+            if self.buffer.len() == self.options.frame_size {
+                self.finish_frame()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compress and write out whatever is buffered as a single frame, so that the next byte
+    /// written begins a new frame.
+    fn finish_frame(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let compressed = zstd::stream::encode_all(&self.buffer[..], self.options.level)?;
+        self.writer.write_all(&compressed)?;
+        self.frames.push((compressed.len() as u32, self.buffer.len() as u32));
+        self.decompressed += self.buffer.len() as u64;
+        self.buffer.clear();
+
+        Ok(())
+    }
+
+    /// The offset in the decompressed stream of the next byte written.
+    fn position(&self) -> u64 {
+        self.decompressed + self.buffer.len() as u64
+    }
+
+    /// Flush the last frame and write the seek table, returning the underlying writer.
+    fn finish(mut self) -> Result<W> {
+        self.finish_frame()?;
+
+        let table_size = self.frames.len() as u64 * 8 + FOOTER_SIZE;
+        let mut table = Vec::with_capacity(8 + table_size as usize);
+        table.extend_from_slice(&u32_to_le(SKIPPABLE_MAGIC));
+        table.extend_from_slice(&u32_to_le(table_size as u32));
+        for &(compressed, decompressed) in &self.frames {
+            table.extend_from_slice(&u32_to_le(compressed));
+            table.extend_from_slice(&u32_to_le(decompressed));
+        }
+        table.extend_from_slice(&u32_to_le(self.frames.len() as u32));
+        // The descriptor: no checksums, and all reserved bits unset.
+        table.push(0);
+        table.extend_from_slice(&u32_to_le(SEEKABLE_MAGIC));
+
+        self.writer.write_all(&table)?;
+
+        Ok(self.writer)
+    }
+}
+
+
+/// Write the subtree with the given hash to `writer` as a seekable zstd archive, returning the
+/// writer and the archive's index once done. Writes to `writer` block, so it should not be
+/// driven on an event loop.
+pub fn write<S: ObjectStore, W: Write + Send + 'static>(
+    store: &S,
+    subtree_hash: ObjectHash,
+    writer: W,
+    options: SeekableOptions,
+) -> Box<Future<Item = (W, Index), Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let mut listing = await!(checkout::walk(&store, subtree_hash))?;
+            listing.files.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let mut frames = FrameWriter::new(writer, options);
+            let mut entries = BTreeMap::new();
+
+            for (path, entry) in listing.files {
+                let (object_hash, kind) = match entry.unannotated() {
+                    SubtreeEntry::File(hash, _) => (hash, EntryKind::File),
+                    SubtreeEntry::Executable(hash, _) => (hash, EntryKind::Executable),
+                    SubtreeEntry::Symlink(hash) => (hash, EntryKind::Symlink),
+                    SubtreeEntry::Subtree(_) |
+                    SubtreeEntry::Annotated(..) |
+                    SubtreeEntry::Subrepository(..) => {
+                        unreachable!("subtrees and subrepositories are never listed as files")
+                    }
+                };
+
+                let offset = frames.position();
+
+                #[async]
+                for chunk in checkout::data_chunks(&store, object_hash) {
+                    frames.write(&chunk)?;
+                }
+
+                frames.finish_frame()?;
+                let size = frames.position() - offset;
+                entries.insert(path, IndexEntry { kind, offset, size });
+            }
+
+            let index = Index {
+                version: INDEX_VERSION,
+                entries,
+            };
+            let index_bytes = serde_json::to_vec(&index)?;
+            frames.write(&index_bytes)?;
+            frames.write(&u64_to_le(index_bytes.len() as u64))?;
+
+            Ok((frames.finish()?, index))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// A reader for seekable zstd archives, which decompresses only those frames it needs.
+#[derive(Debug)]
+pub struct SeekableArchive<R: Read + Seek> {
+    reader: R,
+
+    /// For each frame, its offset in the compressed file, its offset in the decompressed stream,
+    /// and its compressed and decompressed sizes.
+    frames: Vec<(u64, u64, u32, u32)>,
+
+    /// The total size of the decompressed stream.
+    size: u64,
+}
+
+
+impl<R: Read + Seek> SeekableArchive<R> {
+    /// Read the seek table of an archive.
+    pub fn open(mut reader: R) -> Result<Self> {
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        if file_size < FOOTER_SIZE {
+            bail!(ErrorKind::MalformedArchive("too small to hold a seek table".to_owned()));
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        reader.read_exact(&mut footer)?;
+
+        if u32_from_le(&footer[5..9]) != SEEKABLE_MAGIC {
+            bail!(ErrorKind::MalformedArchive("missing seek table".to_owned()));
+        }
+
+        let frame_count = u32_from_le(&footer[0..4]) as u64;
+        let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+        let table_size = 8 + frame_count * entry_size + FOOTER_SIZE;
+        if table_size > file_size {
+            bail!(ErrorKind::MalformedArchive("truncated seek table".to_owned()));
+        }
+
+        let mut table = vec![0u8; (frame_count * entry_size) as usize];
+        reader.seek(SeekFrom::End(-((frame_count * entry_size + FOOTER_SIZE) as i64)))?;
+        reader.read_exact(&mut table)?;
+
+        let mut frames = Vec::with_capacity(frame_count as usize);
+        let mut compressed_offset = 0u64;
+        let mut decompressed_offset = 0u64;
+        for entry in table.chunks(entry_size as usize) {
+            let compressed = u32_from_le(&entry[0..4]);
+            let decompressed = u32_from_le(&entry[4..8]);
+            frames.push((compressed_offset, decompressed_offset, compressed, decompressed));
+            compressed_offset += compressed as u64;
+            decompressed_offset += decompressed as u64;
+        }
+
+        if compressed_offset + table_size > file_size {
+            bail!(ErrorKind::MalformedArchive(
+                "seek table describes more data than the file holds".to_owned(),
+            ));
+        }
+
+        Ok(SeekableArchive {
+            reader,
+            frames,
+            size: decompressed_offset,
+        })
+    }
+
+    /// The total size of the decompressed stream.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Decompress the range `[offset, offset + len)` of the stream into `writer`.
+    pub fn copy_range<W: Write>(&mut self, offset: u64, len: u64, writer: &mut W) -> Result<()> {
+        let end = offset + len;
+        if end > self.size {
+            bail!(ErrorKind::MalformedArchive("read past the end of the archive".to_owned()));
+        }
+
+        // The first frame whose end lies past the start of the range.
+        let first = match self.frames.binary_search_by(|&(_, start, _, size)| {
+            if start + size as u64 <= offset {
+                cmp::Ordering::Less
+            } else {
+                cmp::Ordering::Greater
+            }
+        }) {
+            Ok(i) | Err(i) => i,
+        };
+
+        for i in first..self.frames.len() {
+            let (compressed_offset, start, compressed, _) = self.frames[i];
+            if start >= end {
+                break;
+            }
+
+            let mut buf = vec![0u8; compressed as usize];
+            self.reader.seek(SeekFrom::Start(compressed_offset))?;
+            self.reader.read_exact(&mut buf)?;
+            let frame = zstd::stream::decode_all(&buf[..])?;
+
+            let lo = (cmp::max(start, offset) - start) as usize;
+            let hi = (cmp::min(start + frame.len() as u64, end) - start) as usize;
+            writer.write_all(&frame[lo..hi])?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the index embedded at the end of the stream.
+    pub fn index(&mut self) -> Result<Index> {
+        if self.size < 8 {
+            bail!(ErrorKind::MalformedArchive("missing index".to_owned()));
+        }
+
+        let mut len_bytes = Vec::with_capacity(8);
+        let size = self.size;
+        self.copy_range(size - 8, 8, &mut len_bytes)?;
+        let index_len = u64_from_le(&len_bytes);
+        if index_len > size - 8 {
+            bail!(ErrorKind::MalformedArchive("index is larger than the archive".to_owned()));
+        }
+
+        let mut index_bytes = Vec::with_capacity(index_len as usize);
+        self.copy_range(size - 8 - index_len, index_len, &mut index_bytes)?;
+
+        serde_json::from_slice(&index_bytes).chain_err(|| {
+            ErrorKind::MalformedArchive("unreadable index".to_owned())
+        })
+    }
+
+    /// Decompress a single file from the archive into `writer`.
+    pub fn extract<P: AsRef<Path>, W: Write>(
+        &mut self,
+        index: &Index,
+        path: P,
+        writer: &mut W,
+    ) -> Result<IndexEntry> {
+        let entry = match index.entries.get(path.as_ref()) {
+            Some(&entry) => entry,
+            None => bail!(ErrorKind::ArchiveEntryNotFound(path.as_ref().to_owned())),
+        };
+
+        self.copy_range(entry.offset, entry.size, writer)?;
+
+        Ok(entry)
+    }
+}
+
+
+fn u32_to_le(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+
+fn u64_to_le(n: u64) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (n >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+
+fn u32_from_le(bytes: &[u8]) -> u32 {
+    bytes[..4].iter().rev().fold(0, |n, &byte| (n << 8) | byte as u32)
+}
+
+
+fn u64_from_le(bytes: &[u8]) -> u64 {
+    bytes[..8].iter().rev().fold(0, |n, &byte| (n << 8) | byte as u64)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    quickcheck! {
+        fn range_roundtrip(data: Vec<u8>, frame_size: u8, start: usize, len: usize) -> bool {
+            let options = SeekableOptions {
+                frame_size: frame_size as usize + 1,
+                level: 1,
+            };
+
+            let mut frames = FrameWriter::new(Vec::new(), options);
+            frames.write(&data).unwrap();
+            let bytes = frames.finish().unwrap();
+
+            let mut archive = SeekableArchive::open(Cursor::new(bytes)).unwrap();
+            let start = if data.is_empty() { 0 } else { start % data.len() };
+            let len = if data.len() == start { 0 } else { len % (data.len() - start) };
+
+            let mut buf = Vec::new();
+            archive.copy_range(start as u64, len as u64, &mut buf).unwrap();
+
+            archive.size() == data.len() as u64 && buf[..] == data[start..start + len]
+        }
+    }
+}
@@ -0,0 +1,776 @@
+//! # `sync_to` - bring a plain directory up to date with a commit, sending only what changed.
+//!
+//! A synced directory records the snapshot it holds in a manifest, `MANIFEST`, kept at its root:
+//! the commit, and every file along with the chunks it is made of. Syncing a new commit compares
+//! the commit against the manifest and rewrites only the files which differ. Each rewritten file
+//! is assembled from chunks the directory already holds, in any of its files, wherever they can be
+//! found, and only the remaining chunks are sent. This is what makes syncing a large dataset to a
+//! compute node after a small change cheap, even when the target is on the other end of an SSH
+//! connection.
+//!
+//! Rewritten files are written beside the files they replace and moved into place at the end, so
+//! that chunks are always copied out of the old versions. Files changed on the target behind the
+//! manifest's back are not noticed; syncing is meant for directories which only ever receive
+//! snapshots this way. Of the metadata a commit may record, only the executable bit is kept.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use bincode;
+use futures::prelude::*;
+
+use checkout;
+use errors::*;
+use marshal::{ObjectHash, Object, DataObject, SubtreeEntry};
+use store::ObjectStore;
+use warning;
+
+
+/// The name of the manifest recording the snapshot a synced directory holds.
+pub const MANIFEST: &str = ".attaca-sync";
+
+
+/// Appended to the names of files written beside the files they are to replace.
+const TEMP_SUFFIX: &str = ".attaca-sync-tmp";
+
+
+/// A file as recorded in a manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedFile {
+    pub entry: SubtreeEntry,
+
+    /// The sizes and hashes of the chunks the file is made of, in order. Empty for symlinks.
+    pub chunks: Vec<(u64, ObjectHash)>,
+}
+
+
+/// The snapshot a synced directory holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub commit: ObjectHash,
+    pub directories: Vec<PathBuf>,
+    pub files: BTreeMap<PathBuf, SyncedFile>,
+}
+
+
+/// What a sync did.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Synced {
+    pub written: u64,
+    pub unchanged: u64,
+    pub removed: u64,
+
+    /// Bytes of chunks which had to be sent.
+    pub bytes_sent: u64,
+
+    /// Bytes of chunks copied from files the target already held.
+    pub bytes_reused: u64,
+}
+
+
+/// A directory which can be synced to. Files are assembled from pieces, each either copied out of
+/// a file the directory already holds or sent. Nothing written becomes visible until `commit`,
+/// which also carries out removals and records the new manifest.
+pub trait SyncTarget {
+    /// The manifest the directory holds, if it has been synced to before.
+    fn read_manifest(&mut self) -> Result<Option<Vec<u8>>>;
+
+    /// Create a directory, along with any missing parents.
+    fn create_dir(&mut self, path: &Path) -> Result<()>;
+
+    /// Begin writing a new version of the file at `path`.
+    fn begin_file(&mut self, path: &Path) -> Result<()>;
+
+    /// Append `len` bytes from `offset` in the current version of the file `from`.
+    fn copy(&mut self, from: &Path, offset: u64, len: u64) -> Result<()>;
+
+    /// Append `bytes`, which the directory does not hold.
+    fn data(&mut self, bytes: &[u8]) -> Result<()>;
+
+    /// Finish the file begun by `begin_file`.
+    fn finish_file(&mut self, executable: bool) -> Result<()>;
+
+    /// Write a new symlink at `path`, pointing at `target`.
+    fn symlink(&mut self, path: &Path, target: &[u8]) -> Result<()>;
+
+    fn remove_file(&mut self, path: &Path) -> Result<()>;
+
+    /// Remove a directory, if it is empty once the sync is done.
+    fn remove_dir(&mut self, path: &Path) -> Result<()>;
+
+    /// Move every written file into place, carry out removals, and record `manifest`.
+    fn commit(&mut self, manifest: &[u8]) -> Result<()>;
+}
+
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(TEMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+
+/// The chunks of a data object, in order, as `(size, hash)` pairs.
+fn leaves<S: ObjectStore>(
+    store: &S,
+    object_hash: ObjectHash,
+    size: u64,
+) -> Result<Vec<(u64, ObjectHash)>> {
+    let mut leaves = Vec::new();
+    let mut stack = vec![(size, object_hash)];
+
+    while let Some((size, hash)) = stack.pop() {
+        match store.read_object(hash).wait()? {
+            Object::Data(DataObject::Small(_)) => leaves.push((size, hash)),
+            Object::Data(DataObject::Large(large_object)) => {
+                // Children are pushed in reverse so that they are popped in order.
+                stack.extend(large_object.children.into_iter().rev());
+            }
+            _ => bail!(ErrorKind::ObjectNotData(hash)),
+        }
+    }
+
+    Ok(leaves)
+}
+
+
+fn read_chunk<S: ObjectStore>(store: &S, hash: ObjectHash) -> Result<Vec<u8>> {
+    match store.read_object(hash).wait()? {
+        Object::Data(DataObject::Small(small_object)) => Ok(small_object.chunk.to_vec()),
+        _ => bail!(ErrorKind::ObjectNotData(hash)),
+    }
+}
+
+
+/// Bring `target` up to date with the commit `commit_hash`, whose subtree is `subtree_hash`.
+pub fn sync_to<S: ObjectStore, T: SyncTarget>(
+    store: &S,
+    commit_hash: ObjectHash,
+    subtree_hash: ObjectHash,
+    target: &mut T,
+) -> Result<Synced> {
+    let old = match target.read_manifest()? {
+        Some(bytes) => Some(bincode::deserialize::<Manifest>(&bytes).chain_err(
+            || "the target's sync manifest is corrupt",
+        )?),
+        None => None,
+    };
+    let (old_directories, old_files) = match old {
+        Some(manifest) => (manifest.directories, manifest.files),
+        None => (Vec::new(), BTreeMap::new()),
+    };
+
+    // Where each chunk the target holds can be found.
+    let mut held = HashMap::new();
+    for (path, synced) in &old_files {
+        let mut offset = 0;
+        for &(size, hash) in &synced.chunks {
+            held.entry(hash).or_insert((path.clone(), offset));
+            offset += size;
+        }
+    }
+
+    let listing = checkout::walk(store, subtree_hash).wait()?;
+    let mut synced = Synced::default();
+    let mut files = BTreeMap::new();
+
+    for directory in &listing.directories {
+        target.create_dir(directory)?;
+    }
+
+    for (path, entry) in listing.files {
+        if let Some(old_file) = old_files.get(&path) {
+            if old_file.entry == entry {
+                synced.unchanged += 1;
+                files.insert(path, old_file.clone());
+                continue;
+            }
+        }
+
+        synced.written += 1;
+
+        let chunks = match entry.unannotated() {
+            SubtreeEntry::Symlink(hash) => {
+                let mut link_target = Vec::new();
+                for chunk in checkout::data_chunks(store, hash).wait() {
+                    link_target.extend_from_slice(&chunk?);
+                }
+                target.symlink(&path, &link_target)?;
+
+                Vec::new()
+            }
+            SubtreeEntry::File(hash, size) |
+            SubtreeEntry::Executable(hash, size) => {
+                let chunks = leaves(store, hash, size)?;
+                let executable = match entry.unannotated() {
+                    SubtreeEntry::Executable(..) => true,
+                    _ => false,
+                };
+
+                target.begin_file(&path)?;
+                for &(size, hash) in &chunks {
+                    match held.get(&hash) {
+                        Some(&(ref from, offset)) => {
+                            target.copy(from, offset, size)?;
+                            synced.bytes_reused += size;
+                        }
+                        None => {
+                            target.data(&read_chunk(store, hash)?)?;
+                            synced.bytes_sent += size;
+                        }
+                    }
+                }
+                target.finish_file(executable)?;
+
+                chunks
+            }
+            _ => unreachable!("subtrees are never listed as files"),
+        };
+
+        files.insert(path, SyncedFile { entry, chunks });
+    }
+
+    for path in old_files.keys() {
+        if !files.contains_key(path) {
+            target.remove_file(path)?;
+            synced.removed += 1;
+        }
+    }
+
+    let mut stale_directories = old_directories
+        .into_iter()
+        .filter(|directory| !listing.directories.contains(directory))
+        .collect::<Vec<_>>();
+    // Deepest first, so that directories are emptied before their parents are removed.
+    stale_directories.sort_by(|a, b| b.components().count().cmp(&a.components().count()));
+    for directory in &stale_directories {
+        target.remove_dir(directory)?;
+    }
+
+    let manifest = Manifest {
+        commit: commit_hash,
+        directories: listing.directories,
+        files,
+    };
+    target.commit(&bincode::serialize(&manifest, bincode::Infinite)?)?;
+
+    Ok(synced)
+}
+
+
+/// A directory on the local filesystem, or on a filesystem mounted on it.
+#[derive(Debug)]
+pub struct DirectoryTarget {
+    root: PathBuf,
+    current: Option<(PathBuf, BufWriter<File>)>,
+    written: Vec<PathBuf>,
+    removed_files: Vec<PathBuf>,
+    removed_dirs: Vec<PathBuf>,
+}
+
+
+impl DirectoryTarget {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        fs::create_dir_all(root.as_ref())?;
+
+        Ok(DirectoryTarget {
+            root: root.as_ref().to_owned(),
+            current: None,
+            written: Vec::new(),
+            removed_files: Vec::new(),
+            removed_dirs: Vec::new(),
+        })
+    }
+}
+
+
+impl SyncTarget for DirectoryTarget {
+    fn read_manifest(&mut self) -> Result<Option<Vec<u8>>> {
+        match File::open(self.root.join(MANIFEST)) {
+            Ok(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                Ok(Some(bytes))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn create_dir(&mut self, path: &Path) -> Result<()> {
+        fs::create_dir_all(self.root.join(path))?;
+        Ok(())
+    }
+
+    fn begin_file(&mut self, path: &Path) -> Result<()> {
+        let file = File::create(temp_path(&self.root.join(path)))?;
+        self.current = Some((path.to_owned(), BufWriter::new(file)));
+        Ok(())
+    }
+
+    fn copy(&mut self, from: &Path, offset: u64, len: u64) -> Result<()> {
+        let writer = match self.current {
+            Some((_, ref mut writer)) => writer,
+            None => bail!(ErrorKind::SyncFileNotBegun),
+        };
+
+        let mut source = File::open(self.root.join(from))?;
+        source.seek(SeekFrom::Start(offset))?;
+        let copied = io::copy(&mut source.take(len), writer)?;
+        ensure!(copied == len, ErrorKind::SyncSourceTruncated(from.to_owned()));
+
+        Ok(())
+    }
+
+    fn data(&mut self, bytes: &[u8]) -> Result<()> {
+        match self.current {
+            Some((_, ref mut writer)) => writer.write_all(bytes)?,
+            None => bail!(ErrorKind::SyncFileNotBegun),
+        }
+
+        Ok(())
+    }
+
+    fn finish_file(&mut self, executable: bool) -> Result<()> {
+        let (path, mut writer) = match self.current.take() {
+            Some(current) => current,
+            None => bail!(ErrorKind::SyncFileNotBegun),
+        };
+
+        writer.flush()?;
+        let mode = if executable { 0o755 } else { 0o644 };
+        writer.get_ref().set_permissions(fs::Permissions::from_mode(mode))?;
+        self.written.push(path);
+
+        Ok(())
+    }
+
+    fn symlink(&mut self, path: &Path, target: &[u8]) -> Result<()> {
+        let link_path = temp_path(&self.root.join(path));
+        if link_path.symlink_metadata().is_ok() {
+            fs::remove_file(&link_path)?;
+        }
+        symlink(OsStr::from_bytes(target), &link_path)?;
+        self.written.push(path.to_owned());
+
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.removed_files.push(path.to_owned());
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<()> {
+        self.removed_dirs.push(path.to_owned());
+        Ok(())
+    }
+
+    fn commit(&mut self, manifest: &[u8]) -> Result<()> {
+        for path in self.written.drain(..) {
+            let full_path = self.root.join(path);
+            fs::rename(temp_path(&full_path), &full_path)?;
+        }
+
+        for path in self.removed_files.drain(..) {
+            match fs::remove_file(self.root.join(&path)) {
+                Err(ref err) if err.kind() != io::ErrorKind::NotFound => {
+                    warning::warn(format!("could not remove {}: {}", path.display(), err));
+                }
+                _ => {}
+            }
+        }
+
+        for path in self.removed_dirs.drain(..) {
+            // A directory holding files which were never synced is left alone.
+            let _ = fs::remove_dir(self.root.join(path));
+        }
+
+        let manifest_path = self.root.join(MANIFEST);
+        let temp_manifest_path = temp_path(&manifest_path);
+        File::create(&temp_manifest_path)?.write_all(manifest)?;
+        fs::rename(temp_manifest_path, manifest_path)?;
+
+        Ok(())
+    }
+}
+
+
+/// Quote `bytes` for a POSIX shell.
+#[cfg(feature = "ssh")]
+fn quote(bytes: &[u8]) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in bytes {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+
+    quoted
+}
+
+
+#[cfg(feature = "ssh")]
+fn quote_path(path: &Path) -> Vec<u8> {
+    quote(path.as_os_str().as_bytes())
+}
+
+
+/// The name, beside the manifest, of the file holding every chunk sent in a sync over SSH.
+#[cfg(feature = "ssh")]
+const DELTA: &str = ".attaca-sync-delta";
+
+
+/// The name, beside the manifest, of the script applying a sync over SSH.
+#[cfg(feature = "ssh")]
+const SCRIPT: &str = ".attaca-sync-script";
+
+
+/// Where the bytes of a run of pieces come from.
+#[cfg(feature = "ssh")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    File(PathBuf),
+    Delta,
+}
+
+
+/// A directory on another host, reached over SSH. Nothing but a POSIX shell is needed on the
+/// other end.
+///
+/// Chunks to be sent are gathered into a single delta file, and every operation is written into a
+/// shell script. `commit` uploads both over SFTP and runs the script, which assembles each file
+/// with `tail` and `head`, from the files already there and from the delta.
+#[cfg(feature = "ssh")]
+pub struct SshTarget {
+    // Declared before the connection, so that it is dropped first.
+    session: ::ssh2::Session,
+    _stream: ::std::net::TcpStream,
+    root: PathBuf,
+
+    script: Vec<u8>,
+    delta: BufWriter<File>,
+    delta_path: PathBuf,
+    delta_len: u64,
+
+    /// The run of bytes waiting to be appended to the current file. Adjacent pieces are merged,
+    /// so that the script needs fewer commands.
+    pending: Option<(Source, u64, u64)>,
+    current: Option<PathBuf>,
+
+    written: Vec<PathBuf>,
+    removed_files: Vec<PathBuf>,
+    removed_dirs: Vec<PathBuf>,
+}
+
+
+/// Check the key the server on the other end of `session` presented against the user's
+/// `~/.ssh/known_hosts`, as `ssh` would, before anything is sent to it.
+#[cfg(feature = "ssh")]
+fn check_host_key(session: &::ssh2::Session, host: &str, port: u16) -> Result<()> {
+    use std::env;
+
+    use ssh2::{CheckResult, KnownHostFileKind};
+
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(home) = env::home_dir() {
+        let path = home.join(".ssh").join("known_hosts");
+        if path.is_file() {
+            known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)?;
+        }
+    }
+
+    let key = match session.host_key() {
+        Some((key, _)) => key,
+        None => bail!(ErrorKind::SshHostUnknown(host.to_owned())),
+    };
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => bail!(ErrorKind::SshHostKeyMismatch(host.to_owned())),
+        CheckResult::NotFound | CheckResult::Failure => {
+            bail!(ErrorKind::SshHostUnknown(host.to_owned()))
+        }
+    }
+}
+
+
+#[cfg(feature = "ssh")]
+impl SshTarget {
+    /// Connect to `host` on `port` as `user`, authenticating with the SSH agent, to sync into the
+    /// directory `root` there. A relative `root` is taken from the user's home directory. The
+    /// host's key must already be in the user's `~/.ssh/known_hosts`.
+    pub fn connect<P: AsRef<Path>>(host: &str, port: u16, user: &str, root: P) -> Result<Self> {
+        use std::env;
+        use std::net::TcpStream;
+
+        use rand;
+        use ssh2::Session;
+
+        let stream = TcpStream::connect((host, port))?;
+        let mut session = match Session::new() {
+            Some(session) => session,
+            None => bail!(ErrorKind::SshSession),
+        };
+        session.handshake(&stream)?;
+        check_host_key(&session, host, port)?;
+        session.userauth_agent(user)?;
+
+        let delta_path =
+            env::temp_dir().join(format!("attaca-sync-delta-{}", rand::random::<u64>()));
+        let delta = BufWriter::new(File::create(&delta_path)?);
+
+        let mut script = b"set -e\ncd ".to_vec();
+        script.extend(quote_path(root.as_ref()));
+        script.push(b'\n');
+
+        Ok(SshTarget {
+            session,
+            _stream: stream,
+            root: root.as_ref().to_owned(),
+
+            script,
+            delta,
+            delta_path,
+            delta_len: 0,
+
+            pending: None,
+            current: None,
+
+            written: Vec::new(),
+            removed_files: Vec::new(),
+            removed_dirs: Vec::new(),
+        })
+    }
+
+    fn line(&mut self, parts: &[&[u8]]) {
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                self.script.push(b' ');
+            }
+            self.script.extend_from_slice(part);
+        }
+        self.script.push(b'\n');
+    }
+
+    /// Append `len` bytes from `offset` in `source` to the current file.
+    fn append(&mut self, source: Source, offset: u64, len: u64) {
+        if let Some((ref pending_source, pending_offset, ref mut pending_len)) = self.pending {
+            if *pending_source == source && pending_offset + *pending_len == offset {
+                *pending_len += len;
+                return;
+            }
+        }
+
+        self.flush_pending();
+        self.pending = Some((source, offset, len));
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some((source, offset, len)) = self.pending.take() {
+            let source_path = match source {
+                Source::File(path) => quote_path(&path),
+                Source::Delta => quote(DELTA.as_bytes()),
+            };
+            let start = format!("+{}", offset + 1);
+            let len = len.to_string();
+
+            self.line(
+                &[b"tail -c", start.as_bytes(), &source_path, b"| head -c", len.as_bytes()],
+            );
+        }
+    }
+
+    fn upload(&self, name: &str, source: &Path) -> Result<()> {
+        let sftp = self.session.sftp()?;
+        let mut remote = sftp.create(&self.root.join(name))?;
+        io::copy(&mut File::open(source)?, &mut remote)?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(feature = "ssh")]
+impl SyncTarget for SshTarget {
+    fn read_manifest(&mut self) -> Result<Option<Vec<u8>>> {
+        let sftp = self.session.sftp()?;
+        let manifest_path = self.root.join(MANIFEST);
+
+        // SFTP does not say why a stat failed; take any failure to mean there is no manifest,
+        // and let a real problem surface when the sync is applied.
+        if sftp.stat(&manifest_path).is_err() {
+            return Ok(None);
+        }
+
+        let mut bytes = Vec::new();
+        sftp.open(&manifest_path)?.read_to_end(&mut bytes)?;
+
+        Ok(Some(bytes))
+    }
+
+    fn create_dir(&mut self, path: &Path) -> Result<()> {
+        let quoted = quote_path(path);
+        self.line(&[b"mkdir -p", &quoted]);
+        Ok(())
+    }
+
+    fn begin_file(&mut self, path: &Path) -> Result<()> {
+        self.current = Some(path.to_owned());
+        self.line(&[b"{ :"]);
+        Ok(())
+    }
+
+    fn copy(&mut self, from: &Path, offset: u64, len: u64) -> Result<()> {
+        self.append(Source::File(from.to_owned()), offset, len);
+        Ok(())
+    }
+
+    fn data(&mut self, bytes: &[u8]) -> Result<()> {
+        let offset = self.delta_len;
+        self.delta.write_all(bytes)?;
+        self.delta_len += bytes.len() as u64;
+        self.append(Source::Delta, offset, bytes.len() as u64);
+
+        Ok(())
+    }
+
+    fn finish_file(&mut self, executable: bool) -> Result<()> {
+        let path = match self.current.take() {
+            Some(path) => path,
+            None => bail!(ErrorKind::SyncFileNotBegun),
+        };
+
+        self.flush_pending();
+        let temp = quote_path(&temp_path(&path));
+        let mode: &[u8] = if executable { b"755" } else { b"644" };
+        self.line(&[b"} >", &temp]);
+        self.line(&[b"chmod", mode, &temp]);
+        self.written.push(path);
+
+        Ok(())
+    }
+
+    fn symlink(&mut self, path: &Path, target: &[u8]) -> Result<()> {
+        let temp = quote_path(&temp_path(path));
+        self.line(&[b"rm -f", &temp]);
+        self.line(&[b"ln -s", &quote(target), &temp]);
+        self.written.push(path.to_owned());
+
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.removed_files.push(path.to_owned());
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<()> {
+        self.removed_dirs.push(path.to_owned());
+        Ok(())
+    }
+
+    fn commit(&mut self, manifest: &[u8]) -> Result<()> {
+        for path in self.written.drain(..).collect::<Vec<_>>() {
+            let (temp, path) = (quote_path(&temp_path(&path)), quote_path(&path));
+            self.line(&[b"mv -f", &temp, &path]);
+        }
+        for path in self.removed_files.drain(..).collect::<Vec<_>>() {
+            let path = quote_path(&path);
+            self.line(&[b"rm -f", &path]);
+        }
+        for path in self.removed_dirs.drain(..).collect::<Vec<_>>() {
+            let path = quote_path(&path);
+            self.line(&[b"rmdir", &path, b"2>/dev/null || true"]);
+        }
+
+        // The manifest goes in after the sync's delta, and is moved into place last of all, so
+        // that a sync cut short leaves the old one in place to be synced against again.
+        let manifest_temp = format!("{}{}", MANIFEST, TEMP_SUFFIX);
+        self.delta.write_all(manifest)?;
+        self.line(&[
+            b"tail -c",
+            format!("+{}", self.delta_len + 1).as_bytes(),
+            &quote(DELTA.as_bytes()),
+            b">",
+            &quote(manifest_temp.as_bytes()),
+        ]);
+        self.line(&[b"mv -f", &quote(manifest_temp.as_bytes()), &quote(MANIFEST.as_bytes())]);
+        self.line(&[b"rm -f", &quote(DELTA.as_bytes()), &quote(SCRIPT.as_bytes())]);
+        self.delta.flush()?;
+
+        let script_path = self.delta_path.with_extension("sh");
+        File::create(&script_path)?.write_all(&self.script)?;
+        let delta_path = self.delta_path.clone();
+        self.upload(DELTA, &delta_path)?;
+        self.upload(SCRIPT, &script_path)?;
+        fs::remove_file(&script_path)?;
+
+        let mut channel = self.session.channel_session()?;
+        let mut command = b"sh ".to_vec();
+        command.extend(quote_path(&self.root.join(SCRIPT)));
+        channel.exec(&String::from_utf8_lossy(&command))?;
+
+        let mut stderr = String::new();
+        channel.stderr().read_to_string(&mut stderr)?;
+        channel.wait_close()?;
+        let status = channel.exit_status()?;
+        if status != 0 {
+            bail!(ErrorKind::SyncApply(status, stderr.trim().to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(feature = "ssh")]
+impl Drop for SshTarget {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.delta_path);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bench::Scratch;
+
+    #[test]
+    fn temp_paths_sit_beside_their_files() {
+        assert_eq!(
+            temp_path(Path::new("a/b.bin")),
+            PathBuf::from(format!("a/b.bin{}", TEMP_SUFFIX))
+        );
+    }
+
+    #[test]
+    fn directory_target_assembles_from_old_files() {
+        let scratch = Scratch::new("attaca-sync-to").unwrap();
+        let root = scratch.path();
+        let mut target = DirectoryTarget::new(&root).unwrap();
+        File::create(root.join("old")).unwrap().write_all(b"hello, world").unwrap();
+
+        target.begin_file(Path::new("new")).unwrap();
+        target.copy(Path::new("old"), 7, 5).unwrap();
+        target.data(b"!").unwrap();
+        target.finish_file(false).unwrap();
+        target.remove_file(Path::new("old")).unwrap();
+        target.commit(b"manifest").unwrap();
+
+        let mut contents = String::new();
+        File::open(root.join("new")).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "world!");
+        assert!(!root.join("old").exists());
+        assert_eq!(target.read_manifest().unwrap(), Some(b"manifest".to_vec()));
+    }
+}
@@ -0,0 +1,243 @@
+//! Tar archives.
+//!
+//! Archives are written in the POSIX ustar format, with pax extended headers for paths and link
+//! targets too long for a ustar header and for files of 8 GB or more, so that any modern `tar`
+//! can extract them. Every directory is archived ahead of its contents. Files are archived with
+//! mode 644, executables and directories with mode 755, and symlinks as symlinks; the recorded
+//! modification times and owners of annotated entries are kept.
+
+use std::cmp;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+
+use futures::prelude::*;
+
+use archive::tar::{self, octal, pax_record, BLOCK, MAX_USTAR_SIZE};
+use checkout;
+use errors::*;
+use marshal::{EntryMetadata, ObjectHash, SubtreeEntry};
+use store::ObjectStore;
+
+
+/// The header of a single member.
+struct Header<'a> {
+    path: &'a [u8],
+    kind: u8,
+    mode: u32,
+    size: u64,
+    link: &'a [u8],
+    metadata: Option<&'a EntryMetadata>,
+}
+
+
+impl<'a> Header<'a> {
+    fn ustar(&self, path: &[u8], link: &[u8], kind: u8, size: u64) -> Vec<u8> {
+        let mut header = vec![0u8; BLOCK];
+        header[..path.len()].copy_from_slice(path);
+        octal(&mut header[100..108], self.mode as u64);
+
+        let (uid, gid) = self.metadata.and_then(|metadata| metadata.owner).unwrap_or((0, 0));
+        octal(&mut header[108..116], uid as u64);
+        octal(&mut header[116..124], gid as u64);
+        octal(&mut header[124..136], size);
+
+        let mtime = self.metadata.map(|metadata| metadata.mtime).unwrap_or(0);
+        octal(&mut header[136..148], if mtime < 0 { 0 } else { mtime as u64 });
+
+        header[156] = kind;
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        let checksum = tar::checksum(&header);
+        octal(&mut header[148..155], checksum);
+        header[155] = b' ';
+
+        header
+    }
+
+    /// The header blocks of this member, including a pax extended header if one is needed.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut pax = Vec::new();
+        if self.path.len() > 100 {
+            pax.extend(pax_record("path", self.path));
+        }
+        if self.link.len() > 100 {
+            pax.extend(pax_record("linkpath", self.link));
+        }
+        if self.size > MAX_USTAR_SIZE {
+            pax.extend(pax_record("size", self.size.to_string().as_bytes()));
+        }
+
+        let mut bytes = Vec::new();
+        if !pax.is_empty() {
+            bytes.extend(self.ustar(b"././@PaxHeader", b"", b'x', pax.len() as u64));
+            bytes.extend_from_slice(&pax);
+            bytes.extend(vec![0u8; tar::padding(pax.len() as u64)]);
+        }
+
+        // Whatever did not fit is left truncated here; readers take it from the pax header.
+        let path = &self.path[..cmp::min(self.path.len(), 100)];
+        let link = &self.link[..cmp::min(self.link.len(), 100)];
+        bytes.extend(self.ustar(path, link, self.kind, cmp::min(self.size, MAX_USTAR_SIZE)));
+
+        bytes
+    }
+}
+
+
+/// Write the subtree with the given hash to `writer` as a tar archive, returning the writer and
+/// the number of members written once done. Writes to `writer` block, so it should not be driven
+/// on an event loop.
+pub fn write<S: ObjectStore, W: Write + Send + 'static>(
+    store: &S,
+    subtree_hash: ObjectHash,
+    mut writer: W,
+) -> Box<Future<Item = (W, u64), Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let mut listing = await!(checkout::walk(&store, subtree_hash))?;
+            listing.directories.sort();
+            listing.files.sort_by(|a, b| a.0.cmp(&b.0));
+
+            for directory in &listing.directories {
+                let mut path = directory.as_os_str().as_bytes().to_vec();
+                path.push(b'/');
+
+                let header = Header {
+                    path: &path,
+                    kind: b'5',
+                    mode: 0o755,
+                    size: 0,
+                    link: b"",
+                    metadata: None,
+                };
+                writer.write_all(&header.to_bytes())?;
+            }
+
+            let members = (listing.directories.len() + listing.files.len()) as u64;
+
+            for (path, entry) in listing.files {
+                let path_bytes = path.as_os_str().as_bytes().to_vec();
+                let metadata = entry.metadata().cloned();
+                let unannotated = entry.unannotated();
+
+                let (object_hash, mode, size) = match unannotated {
+                    SubtreeEntry::File(hash, size) => (hash, 0o644, size),
+                    SubtreeEntry::Executable(hash, size) => (hash, 0o755, size),
+                    SubtreeEntry::Symlink(hash) => {
+                        let target = await!(checkout::data_chunks(&store, hash).fold(
+                            Vec::new(),
+                            |mut target, chunk| {
+                                target.extend_from_slice(&chunk);
+                                Ok::<_, Error>(target)
+                            },
+                        ))?;
+                        let header = Header {
+                            path: &path_bytes,
+                            kind: b'2',
+                            mode: 0o777,
+                            size: 0,
+                            link: &target,
+                            metadata: metadata.as_ref(),
+                        };
+                        writer.write_all(&header.to_bytes())?;
+
+                        continue;
+                    }
+                    SubtreeEntry::Subtree(_) |
+                    SubtreeEntry::Annotated(..) |
+                    SubtreeEntry::Subrepository(..) => {
+                        unreachable!("subtrees and subrepositories are never listed as files")
+                    }
+                };
+
+                let header = Header {
+                    path: &path_bytes,
+                    kind: b'0',
+                    mode,
+                    size,
+                    link: b"",
+                    metadata: metadata.as_ref(),
+                };
+                writer.write_all(&header.to_bytes())?;
+
+                let mut written = 0u64;
+
+                #[async]
+                for chunk in checkout::data_chunks(&store, object_hash) {
+                    writer.write_all(&chunk)?;
+                    written += chunk.len() as u64;
+                }
+
+                if written != size {
+                    bail!(ErrorKind::EntrySizeMismatch(path, written, size));
+                }
+
+                writer.write_all(&vec![0u8; tar::padding(size)])?;
+            }
+
+            // Two zero blocks end the archive.
+            writer.write_all(&[0u8; 2 * BLOCK])?;
+
+            Ok((writer, members))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+
+    /// Every path a tar archive written by `write` would hold, for tests.
+    fn member_paths(archive: &[u8]) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let mut long_path = None;
+
+        for entry in tar::entries(archive) {
+            let entry = entry.unwrap();
+            if entry.kind() == b'x' {
+                let records = tar::pax_records(&archive[entry.data]).unwrap();
+                long_path = records.get("path").map(|path| PathBuf::from(OsStr::from_bytes(path)));
+            } else {
+                let name = PathBuf::from(OsStr::from_bytes(tar::text(&entry.header[..100])));
+                paths.push(long_path.take().unwrap_or(name));
+            }
+        }
+
+        paths
+    }
+
+    #[test]
+    fn long_paths_use_pax_headers() {
+        let long = vec![b'a'; 150];
+        let mut archive = Vec::new();
+        for path in &[&b"short"[..], &long[..]] {
+            let header = Header {
+                path,
+                kind: b'0',
+                mode: 0o644,
+                size: 0,
+                link: b"",
+                metadata: None,
+            };
+            archive.extend(header.to_bytes());
+        }
+
+        assert_eq!(
+            member_paths(&archive),
+            vec![
+                PathBuf::from("short"),
+                PathBuf::from(String::from_utf8(long).unwrap()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! Zip archives.
+//!
+//! Files are stored without compression, since the data attaca versions is rarely worth
+//! compressing twice, and each is followed by a data descriptor so that it can be streamed
+//! straight from the store without knowing its checksum up front. Zip64 records are used for
+//! files and archives too large for plain zip. Every directory is archived ahead of its contents.
+//! Unix modes are recorded as `tar` would record them (see the `tar` module), and the recorded
+//! modification times of annotated entries are kept.
+
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+
+use futures::prelude::*;
+
+use archive::zip::{self, crc32, dos_time, field_u32, put_u16, put_u32, put_u64};
+use checkout;
+use errors::*;
+use marshal::{ObjectHash, SubtreeEntry};
+use store::ObjectStore;
+
+
+/// General purpose flags: sizes and checksum follow the data, and names are UTF-8.
+const FLAGS: u16 = 0x0808;
+
+
+/// What the central directory records of a member once it has been written.
+struct Member {
+    name: Vec<u8>,
+    mode: u32,
+    mtime: i64,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+
+impl Member {
+    fn zip64(&self) -> bool {
+        self.size >= 0xffff_ffff || self.offset >= 0xffff_ffff
+    }
+
+    fn version(&self) -> u16 {
+        if self.zip64() { 45 } else { 20 }
+    }
+
+    fn local_header(&self) -> Vec<u8> {
+        let (dos_time, dos_date) = dos_time(self.mtime);
+
+        let mut header = Vec::new();
+        put_u32(&mut header, zip::LOCAL_HEADER);
+        put_u16(&mut header, self.version());
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, dos_time);
+        put_u16(&mut header, dos_date);
+
+        // The checksum and sizes are in the data descriptor, or the zip64 extra field.
+        put_u32(&mut header, 0);
+        let sizes = if self.zip64() { 0xffff_ffff } else { 0 };
+        put_u32(&mut header, sizes);
+        put_u32(&mut header, sizes);
+
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(&mut header, if self.zip64() { 20 } else { 0 });
+        header.extend_from_slice(&self.name);
+
+        if self.zip64() {
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        }
+
+        header
+    }
+
+    fn data_descriptor(&self) -> Vec<u8> {
+        let mut descriptor = Vec::new();
+        put_u32(&mut descriptor, zip::DATA_DESCRIPTOR);
+        put_u32(&mut descriptor, self.crc);
+
+        if self.zip64() {
+            put_u64(&mut descriptor, self.size);
+            put_u64(&mut descriptor, self.size);
+        } else {
+            put_u32(&mut descriptor, self.size as u32);
+            put_u32(&mut descriptor, self.size as u32);
+        }
+
+        descriptor
+    }
+
+    fn central_header(&self) -> Vec<u8> {
+        let (dos_time, dos_date) = dos_time(self.mtime);
+
+        // Only the fields which overflowed are given in the zip64 extra field, in this order.
+        let mut extra = Vec::new();
+        if self.size >= 0xffff_ffff {
+            put_u64(&mut extra, self.size);
+            put_u64(&mut extra, self.size);
+        }
+        if self.offset >= 0xffff_ffff {
+            put_u64(&mut extra, self.offset);
+        }
+
+        // MS-DOS readers look for the directory attribute rather than the Unix mode.
+        let dos_attributes = if self.mode & 0o170000 == 0o040000 { 0x10 } else { 0 };
+
+        let mut header = Vec::new();
+        put_u32(&mut header, zip::CENTRAL_HEADER);
+        put_u16(&mut header, zip::MADE_BY_UNIX | self.version());
+        put_u16(&mut header, self.version());
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, dos_time);
+        put_u16(&mut header, dos_date);
+        put_u32(&mut header, self.crc);
+        put_u32(&mut header, field_u32(self.size));
+        put_u32(&mut header, field_u32(self.size));
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(&mut header, if extra.is_empty() { 0 } else { extra.len() as u16 + 4 });
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u32(&mut header, (self.mode << 16) | dos_attributes);
+        put_u32(&mut header, field_u32(self.offset));
+        header.extend_from_slice(&self.name);
+
+        if !extra.is_empty() {
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, extra.len() as u16);
+            header.extend_from_slice(&extra);
+        }
+
+        header
+    }
+}
+
+
+/// Write the central directory and the records ending the archive, given the offset at which the
+/// central directory begins.
+fn central_directory(members: &[Member], offset: u64) -> Vec<u8> {
+    let mut directory = Vec::new();
+    for member in members {
+        directory.extend(member.central_header());
+    }
+
+    let size = directory.len() as u64;
+    let entries = members.len() as u64;
+
+    if entries >= 0xffff || size >= 0xffff_ffff || offset >= 0xffff_ffff {
+        let end_offset = offset + size;
+
+        put_u32(&mut directory, zip::ZIP64_END_OF_CENTRAL_DIRECTORY);
+        put_u64(&mut directory, 44);
+        put_u16(&mut directory, zip::MADE_BY_UNIX | 45);
+        put_u16(&mut directory, 45);
+        put_u32(&mut directory, 0);
+        put_u32(&mut directory, 0);
+        put_u64(&mut directory, entries);
+        put_u64(&mut directory, entries);
+        put_u64(&mut directory, size);
+        put_u64(&mut directory, offset);
+
+        put_u32(&mut directory, zip::ZIP64_END_LOCATOR);
+        put_u32(&mut directory, 0);
+        put_u64(&mut directory, end_offset);
+        put_u32(&mut directory, 1);
+    }
+
+    let entries_u16 = if entries >= 0xffff { 0xffff } else { entries as u16 };
+    put_u32(&mut directory, zip::END_OF_CENTRAL_DIRECTORY);
+    put_u16(&mut directory, 0);
+    put_u16(&mut directory, 0);
+    put_u16(&mut directory, entries_u16);
+    put_u16(&mut directory, entries_u16);
+    put_u32(&mut directory, field_u32(size));
+    put_u32(&mut directory, field_u32(offset));
+    put_u16(&mut directory, 0);
+
+    directory
+}
+
+
+/// Write the subtree with the given hash to `writer` as a zip archive, returning the writer and
+/// the number of members written once done. Writes to `writer` block, so it should not be driven
+/// on an event loop.
+pub fn write<S: ObjectStore, W: Write + Send + 'static>(
+    store: &S,
+    subtree_hash: ObjectHash,
+    mut writer: W,
+) -> Box<Future<Item = (W, u64), Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let mut listing = await!(checkout::walk(&store, subtree_hash))?;
+            listing.directories.sort();
+            listing.files.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let mut members = Vec::new();
+            let mut offset = 0u64;
+
+            for directory in listing.directories {
+                let mut name = directory.as_os_str().as_bytes().to_vec();
+                name.push(b'/');
+
+                let member = Member {
+                    name,
+                    mode: 0o040755,
+                    mtime: 0,
+                    crc: 0,
+                    size: 0,
+                    offset,
+                };
+
+                let local_header = member.local_header();
+                let data_descriptor = member.data_descriptor();
+                writer.write_all(&local_header)?;
+                writer.write_all(&data_descriptor)?;
+                offset += (local_header.len() + data_descriptor.len()) as u64;
+
+                members.push(member);
+            }
+
+            for (path, entry) in listing.files {
+                let mtime = entry.metadata().map(|metadata| metadata.mtime);
+                let unannotated = entry.unannotated();
+
+                let (object_hash, mode, size) = match unannotated {
+                    SubtreeEntry::File(hash, size) => (hash, 0o100644, Some(size)),
+                    SubtreeEntry::Executable(hash, size) => (hash, 0o100755, Some(size)),
+                    SubtreeEntry::Symlink(hash) => (hash, 0o120777, None),
+                    SubtreeEntry::Subtree(_) |
+                    SubtreeEntry::Annotated(..) |
+                    SubtreeEntry::Subrepository(..) => {
+                        unreachable!("subtrees and subrepositories are never listed as files")
+                    }
+                };
+
+                // Symlink targets are small, and their sizes are not recorded in the subtree.
+                let target = match size {
+                    Some(_) => None,
+                    None => {
+                        Some(await!(checkout::data_chunks(&store, object_hash).fold(
+                            Vec::new(),
+                            |mut target, chunk| {
+                                target.extend_from_slice(&chunk);
+                                Ok::<_, Error>(target)
+                            },
+                        ))?)
+                    }
+                };
+
+                let mut member = Member {
+                    name: path.as_os_str().as_bytes().to_vec(),
+                    mode,
+                    mtime: mtime.unwrap_or(0),
+                    crc: 0,
+                    size: size.unwrap_or_else(|| target.as_ref().unwrap().len() as u64),
+                    offset,
+                };
+
+                let local_header = member.local_header();
+                writer.write_all(&local_header)?;
+                offset += local_header.len() as u64;
+
+                let mut written = 0u64;
+                match target {
+                    Some(target) => {
+                        writer.write_all(&target)?;
+                        member.crc = crc32(0, &target);
+                        written = target.len() as u64;
+                    }
+                    None => {
+                        #[async]
+                        for chunk in checkout::data_chunks(&store, object_hash) {
+                            writer.write_all(&chunk)?;
+                            member.crc = crc32(member.crc, &chunk);
+                            written += chunk.len() as u64;
+                        }
+                    }
+                }
+
+                if written != member.size {
+                    bail!(ErrorKind::EntrySizeMismatch(path, written, member.size));
+                }
+                offset += written;
+
+                let data_descriptor = member.data_descriptor();
+                writer.write_all(&data_descriptor)?;
+                offset += data_descriptor.len() as u64;
+
+                members.push(member);
+            }
+
+            writer.write_all(&central_directory(&members, offset))?;
+
+            Ok((writer, members.len() as u64))
+        }
+    };
+
+    Box::new(result)
+}
+
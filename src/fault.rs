@@ -0,0 +1,272 @@
+//! # `fault` - deliberate crashes at chosen points, for crash-consistency testing.
+//!
+//! The places where a crash would leave the repository halfway between two states - staged
+//! objects marked but not yet promoted, a refs file written but not yet moved into place, and so
+//! on - are each marked with a named fault point. In builds with the `chaos` feature, a fault
+//! point can be armed, and the process exits on the spot when it is reached: no destructors run
+//! and nothing is flushed, just as if it had been killed. Without the feature, fault points
+//! compile to nothing.
+//!
+//! Fault points are armed by `arm`, or by setting `ATTACA_FAULT` to either the name of a point,
+//! optionally followed by `:N` to crash on the Nth time it is reached, or to `random:P` to crash
+//! at each point reached with probability P. The chaos test in this module runs commits in forked
+//! processes which crash at random points, checking after each that the repository reopens with
+//! every branch pointing at objects which are all present.
+
+#[cfg(feature = "chaos")]
+use std::collections::HashMap;
+#[cfg(feature = "chaos")]
+use std::env;
+#[cfg(feature = "chaos")]
+use std::sync::Mutex;
+
+#[cfg(feature = "chaos")]
+use libc;
+#[cfg(feature = "chaos")]
+use rand;
+
+#[cfg(feature = "chaos")]
+use warning;
+
+
+/// Every fault point, by name.
+pub const POINTS: &[&str] = &[
+    "catalog.write",
+    "cleanup.refs-written",
+    "index.write",
+    "local.write-object",
+    "refs.write",
+    "staging.mark",
+    "staging.promote",
+];
+
+
+/// The exit status of a process which crashed at a fault point.
+pub const FAULT_EXIT_STATUS: i32 = 86;
+
+
+/// The environment variable fault points are armed from.
+pub const FAULT_VAR: &'static str = "ATTACA_FAULT";
+
+
+/// Which fault points crash the process.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Plan {
+    Never,
+
+    /// Crash the `n`th time the named point is reached, counting from one.
+    At(String, u64),
+
+    /// Crash at each point reached with the given probability.
+    Random(f64),
+}
+
+
+impl Plan {
+    /// Parse a plan as given in `ATTACA_FAULT`.
+    pub fn parse(spec: &str) -> Option<Plan> {
+        let mut split = spec.splitn(2, ':');
+
+        match (split.next(), split.next()) {
+            (Some("random"), Some(probability)) => probability.parse().ok().map(Plan::Random),
+            (Some(name), None) if POINTS.contains(&name) => Some(Plan::At(name.to_owned(), 1)),
+            (Some(name), Some(n)) if POINTS.contains(&name) => {
+                n.parse().ok().map(|n| Plan::At(name.to_owned(), n))
+            }
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(feature = "chaos")]
+#[derive(Debug)]
+struct Faults {
+    plan: Plan,
+    hits: HashMap<&'static str, u64>,
+}
+
+
+#[cfg(feature = "chaos")]
+lazy_static! {
+    static ref FAULTS: Mutex<Faults> = {
+        let plan = match env::var(FAULT_VAR) {
+            Ok(spec) => {
+                Plan::parse(&spec).unwrap_or_else(|| {
+                    warning::warn(format!("ignoring unrecognized {}={}", FAULT_VAR, spec));
+                    Plan::Never
+                })
+            }
+            Err(_) => Plan::Never,
+        };
+
+        Mutex::new(Faults {
+            plan,
+            hits: HashMap::new(),
+        })
+    };
+}
+
+
+/// Arm fault points according to `plan`, replacing whatever was armed before.
+#[cfg(feature = "chaos")]
+pub fn arm(plan: Plan) {
+    let mut faults = FAULTS.lock().unwrap();
+    faults.plan = plan;
+    faults.hits.clear();
+}
+
+
+/// Mark a fault point. If it is armed, the process exits immediately.
+#[cfg(feature = "chaos")]
+pub fn point(name: &'static str) {
+    debug_assert!(POINTS.contains(&name), "unknown fault point `{}`", name);
+
+    let crash = {
+        let mut faults = FAULTS.lock().unwrap();
+        let hits = {
+            let hits = faults.hits.entry(name).or_insert(0);
+            *hits += 1;
+            *hits
+        };
+
+        match faults.plan {
+            Plan::Never => false,
+            Plan::At(ref target, n) => target == name && hits == n,
+            Plan::Random(probability) => rand::random::<f64>() < probability,
+        }
+    };
+
+    if crash {
+        eprintln!("Crashing at fault point `{}`.", name);
+        unsafe {
+            libc::_exit(FAULT_EXIT_STATUS);
+        }
+    }
+}
+
+
+/// Mark a fault point. Fault points do nothing without the `chaos` feature.
+#[cfg(not(feature = "chaos"))]
+#[inline(always)]
+pub fn point(_name: &'static str) {}
+
+
+#[cfg(all(test, feature = "chaos"))]
+mod test {
+    use super::*;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use chrono::prelude::*;
+    use futures::future::{self, FutureResult};
+    use futures::prelude::*;
+    use globset::{Glob, GlobSetBuilder};
+
+    use bench::Scratch;
+    use errors::*;
+    use graph::{self, Visit, Visitor};
+    use marshal::{Object, ObjectHash};
+    use repository::Repository;
+
+    /// The number of crashed commits to recover from.
+    const ITERATIONS: usize = 32;
+
+    /// Visits everything, failing if anything is missing.
+    struct Reachable;
+
+    impl Visitor for Reachable {
+        type Future = FutureResult<Visit, Error>;
+
+        fn visit(&mut self, _hash: ObjectHash, _object: Object) -> Self::Future {
+            future::ok(Visit::Descend)
+        }
+    }
+
+    fn commit(root: &Path) -> Result<()> {
+        let mut repository = Repository::load(root)?;
+
+        let mut builder = GlobSetBuilder::new();
+        builder.add(Glob::new("*")?);
+        repository.index.register(&builder.build()?)?;
+        repository.index.update()?;
+
+        repository.begin_staging()?;
+        let commit_hash = {
+            let ctx = repository.local(())?;
+            let parents = ctx.refs.head().into_iter().collect();
+            let commit_hash = ctx.write_commit(
+                None,
+                None,
+                parents,
+                "chaos".to_owned(),
+                Utc::now(),
+                None,
+                None,
+            ).wait()?;
+            ctx.close().wait()?;
+
+            commit_hash
+        };
+        repository.finish_staging();
+
+        let expected = repository.refs.branches.get("master").cloned();
+        repository.compare_and_swap_branch("master", expected, commit_hash)?;
+
+        repository.cleanup()
+    }
+
+    /// Reopen the repository and check that every branch can be read in full.
+    fn check(root: &Path) {
+        let mut repository = Repository::load(root).unwrap();
+        let heads = repository.refs.branches.values().cloned().collect::<Vec<_>>();
+
+        let ctx = repository.local(()).unwrap();
+        graph::visit(ctx.store(), heads, Reachable).wait().unwrap();
+        ctx.close().wait().unwrap();
+
+        repository.cleanup().unwrap();
+    }
+
+    #[test]
+    fn commits_survive_crashes() {
+        let scratch = Scratch::new("attaca-chaos").unwrap();
+        let root = scratch.path();
+        Repository::init(&root).unwrap();
+
+        for iteration in 0..ITERATIONS {
+            for name in &["a", "b"] {
+                let mut file = File::create(root.join(name)).unwrap();
+                write!(file, "{} {}", iteration, rand::random::<u64>()).unwrap();
+            }
+
+            let plan = if iteration % 2 == 0 {
+                Plan::At(POINTS[iteration / 2 % POINTS.len()].to_owned(), 1)
+            } else {
+                Plan::Random(0.2)
+            };
+
+            match unsafe { libc::fork() } {
+                0 => {
+                    arm(plan);
+                    let status = if commit(&root).is_ok() { 0 } else { 1 };
+                    unsafe { libc::_exit(status) };
+                }
+                pid => {
+                    assert!(pid > 0);
+
+                    let mut status = 0;
+                    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                    assert!(unsafe { libc::WIFEXITED(status) });
+
+                    let code = unsafe { libc::WEXITSTATUS(status) };
+                    assert!(code == 0 || code == FAULT_EXIT_STATUS, "commit failed: {}", code);
+                }
+            }
+
+            check(&root);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! # `filter` - transforming file contents between the worktree and the store.
+//!
+//! A content filter has two halves. When a file is committed, its contents are "cleaned" before
+//! being split into chunks, so that what is stored is the canonical form of the file; when it is
+//! checked out, the stored contents are "smudged" back into the form the worktree should hold.
+//! Filters can normalize line endings, decompress files which are stored more efficiently
+//! uncompressed - compressed data deduplicates very poorly - or anything else which maps the
+//! worktree's form of a file to a canonical one.
+//!
+//! Every repository has a `FilterSet`, which picks at most one filter for each file by its path.
+//! Filters are either commands, listed in `config.toml` with the patterns they apply to, or Rust
+//! filters registered by library users through the `Filter` trait. Commands are run in the root
+//! of the worktree, are handed the contents to filter on their standard input, and must write the
+//! filtered contents to their standard output; a command exiting with a nonzero status fails the
+//! commit or checkout.
+//!
+//! The built-in `Eol` filter normalizes line endings, so that text files are stored with LF
+//! endings whatever platform they were committed from, and checked out with the endings
+//! configured for them. Without it, a file saved with CRLF endings on one machine and LF on
+//! another differs on every line, and shares not a single chunk between the two versions.
+//!
+//! Cleaning a smudged file must give back exactly what was stored, or every checkout will leave
+//! files which appear modified.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+
+use globset::{Glob, GlobMatcher};
+
+use arc_slice::{self, ArcSlice};
+use errors::*;
+use repository::{EolCfg, FilterCfg, LineEnding, Paths, TextMode};
+
+
+/// Transforms the contents of files as they are committed and checked out.
+pub trait Filter: Send + Sync {
+    /// Turn the contents of a file in the worktree into the contents to store.
+    fn clean(&self, data: ArcSlice) -> Result<ArcSlice>;
+
+    /// Turn stored contents into the contents of the file to write to the worktree.
+    fn smudge(&self, data: ArcSlice) -> Result<ArcSlice>;
+}
+
+
+/// A filter configured in `config.toml`, run as an external process.
+#[derive(Debug, Clone)]
+pub struct CommandFilter {
+    base: PathBuf,
+
+    /// The program and arguments run to clean files. If empty, files are stored as they are.
+    clean: Vec<String>,
+
+    /// The program and arguments run to smudge files. If empty, files are checked out as they
+    /// are stored.
+    smudge: Vec<String>,
+}
+
+
+impl CommandFilter {
+    pub fn new(paths: &Paths, clean: Vec<String>, smudge: Vec<String>) -> Self {
+        Self {
+            base: paths.base.clone(),
+            clean,
+            smudge,
+        }
+    }
+
+    fn run(&self, direction: &str, command: &[String], data: ArcSlice) -> Result<ArcSlice> {
+        let (program, args) = match command.split_first() {
+            Some(split) => split,
+            None => return Ok(data),
+        };
+        let command_line = command.join(" ");
+        let failed = |reason: String| {
+            ErrorKind::FilterFailed(direction.to_owned(), command_line.clone(), reason)
+        };
+
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(&self.base)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .chain_err(|| failed("could not be started".to_owned()))?;
+
+        // The input is written from another thread, so that a filter which writes its output
+        // before it has read all of its input cannot deadlock against us.
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(&data));
+
+        let mut output = Vec::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_end(&mut output)
+            .chain_err(|| failed("its output could not be read".to_owned()))?;
+        let status = child.wait().chain_err(|| failed("could not be waited on".to_owned()))?;
+
+        if !status.success() {
+            bail!(failed(status.to_string()));
+        }
+
+        match writer.join() {
+            Ok(Ok(())) => {}
+            _ => bail!(failed("did not read all of its input".to_owned())),
+        }
+
+        Ok(arc_slice::owned(output))
+    }
+}
+
+
+impl Filter for CommandFilter {
+    fn clean(&self, data: ArcSlice) -> Result<ArcSlice> {
+        self.run("clean", &self.clean, data)
+    }
+
+    fn smudge(&self, data: ArcSlice) -> Result<ArcSlice> {
+        self.run("smudge", &self.smudge, data)
+    }
+}
+
+
+/// Normalizes line endings in text files: CRLF endings are stored as LF, and LF endings are
+/// checked out as `eol`. With `TextMode::Auto`, files containing a NUL byte are taken to be binary
+/// and left alone.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Eol {
+    pub text: TextMode,
+    pub eol: LineEnding,
+}
+
+
+impl Eol {
+    fn is_text(&self, data: &[u8]) -> bool {
+        match self.text {
+            TextMode::Auto => !data.iter().any(|&byte| byte == 0),
+            TextMode::Text => true,
+            TextMode::Binary => false,
+        }
+    }
+}
+
+
+impl Filter for Eol {
+    fn clean(&self, data: ArcSlice) -> Result<ArcSlice> {
+        if !self.is_text(&data) || !data.windows(2).any(|pair| pair == b"\r\n") {
+            return Ok(data);
+        }
+
+        let mut cleaned = Vec::with_capacity(data.len());
+        for (i, &byte) in data.iter().enumerate() {
+            if byte != b'\r' || data.get(i + 1) != Some(&b'\n') {
+                cleaned.push(byte);
+            }
+        }
+
+        Ok(arc_slice::owned(cleaned))
+    }
+
+    fn smudge(&self, data: ArcSlice) -> Result<ArcSlice> {
+        if self.eol == LineEnding::Lf || !self.is_text(&data) {
+            return Ok(data);
+        }
+
+        let mut smudged = Vec::with_capacity(data.len() + data.len() / 32);
+        for (i, &byte) in data.iter().enumerate() {
+            if byte == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+                smudged.push(b'\r');
+            }
+            smudged.push(byte);
+        }
+
+        Ok(arc_slice::owned(smudged))
+    }
+}
+
+
+/// The content filters of a repository, chosen by path. Files which match no pattern are stored
+/// and checked out as they are.
+#[derive(Clone, Default)]
+pub struct FilterSet {
+    by_pattern: Vec<(GlobMatcher, Arc<Filter>)>,
+}
+
+
+impl fmt::Debug for FilterSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FilterSet {{ {} patterns }}", self.by_pattern.len())
+    }
+}
+
+
+impl FilterSet {
+    /// The filters configured for the repository at `paths`: line ending normalization, and then
+    /// commands, which take precedence over it.
+    pub fn configured(paths: &Paths, eol: &[EolCfg], filters: &[FilterCfg]) -> Result<Self> {
+        let mut filter_set = Self::default();
+
+        for eol_cfg in eol {
+            let filter = Eol {
+                text: eol_cfg.text,
+                eol: eol_cfg.eol,
+            };
+            filter_set.register(Glob::new(&eol_cfg.pattern)?, filter);
+        }
+
+        for filter_cfg in filters {
+            let filter = CommandFilter::new(
+                paths,
+                filter_cfg.clean.clone(),
+                filter_cfg.smudge.clone(),
+            );
+            filter_set.register(Glob::new(&filter_cfg.pattern)?, filter);
+        }
+
+        Ok(filter_set)
+    }
+
+    /// Filter files matching `pattern`, relative to the root of the worktree, with `filter`. Of
+    /// the patterns matching a file, the last registered wins.
+    pub fn register<F: Filter + 'static>(&mut self, pattern: Glob, filter: F) {
+        self.by_pattern.push((pattern.compile_matcher(), Arc::new(filter)));
+    }
+
+    /// The filter for the file at `path`, relative to the root of the worktree, if any.
+    pub fn for_path<P: AsRef<Path>>(&self, path: P) -> Option<Arc<Filter>> {
+        self.by_pattern
+            .iter()
+            .rev()
+            .find(|&&(ref matcher, _)| matcher.is_match(path.as_ref()))
+            .map(|&(_, ref filter)| filter.clone())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_pattern.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn runs_configured_commands() {
+        let paths = Paths::new(env::temp_dir());
+        let filter_cfg = FilterCfg {
+            pattern: "*.txt".to_owned(),
+            clean: vec!["tr".to_owned(), "a-z".to_owned(), "A-Z".to_owned()],
+            smudge: Vec::new(),
+        };
+        let filters = FilterSet::configured(&paths, &[], &[filter_cfg]).unwrap();
+
+        assert!(filters.for_path("a.bin").is_none());
+
+        let filter = filters.for_path("notes/a.txt").unwrap();
+        let data = arc_slice::owned(b"hello\n".to_vec());
+        assert_eq!(&*filter.clean(data.clone()).unwrap(), b"HELLO\n");
+        assert_eq!(&*filter.smudge(data).unwrap(), b"hello\n");
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        let eol = Eol {
+            text: TextMode::Auto,
+            eol: LineEnding::Crlf,
+        };
+        let windows = arc_slice::owned(b"a\r\nb\nc\r".to_vec());
+        let stored = eol.clean(windows).unwrap();
+        assert_eq!(&*stored, b"a\nb\nc\r");
+        assert_eq!(&*eol.smudge(stored).unwrap(), b"a\r\nb\r\nc\r");
+
+        let binary = arc_slice::owned(b"\0\r\n".to_vec());
+        assert_eq!(&*eol.clean(binary.clone()).unwrap(), b"\0\r\n");
+        assert_eq!(&*eol.smudge(binary).unwrap(), b"\0\r\n");
+
+        let unix = Eol::default();
+        let stored = unix.clean(arc_slice::owned(b"a\r\n".to_vec())).unwrap();
+        assert_eq!(&*unix.smudge(stored).unwrap(), b"a\n");
+    }
+}
@@ -0,0 +1,335 @@
+//! # `git` - import the history of a git repository.
+//!
+//! Commits are converted oldest first, so that every parent is converted before its children,
+//! and each keeps its parents, message, author, committer and commit time. Blobs are split by the
+//! repository's chunkers, just as committed files are, and git trees become subtrees. Both are
+//! converted only once and remembered, so that a history of thousands of commits sharing most of
+//! their files reads each file only once.
+//!
+//! Files kept in git LFS are imported with their real contents, provided the LFS object is in
+//! the repository's `lfs/objects` directory - run `git lfs fetch --all` first. Otherwise the
+//! pointer file itself is imported, with a warning. Submodules have no counterpart in attaca and
+//! are skipped, also with a warning.
+//!
+//! Git and attaca hash objects differently, so the importer keeps a map from each git commit to
+//! the commit made of it. The map can be saved with `write_map`, and handing it back to a later
+//! import lets that import convert only the commits made since.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use futures::stream;
+use git2::{self, Oid, Repository as GitRepository, Signature};
+use memmap::{Mmap, Protection};
+
+use arc_slice;
+use chunker::{Chunker, ChunkerSet};
+use errors::*;
+use marshal::{CommitObject, Identity, Marshaller, ObjectHash, SubtreeEntry};
+use trace::Trace;
+use warning;
+
+
+/// The first line of every git LFS pointer file.
+const LFS_POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1\n";
+
+
+/// Pointer files are tiny; anything larger is a file which merely happens to look like one.
+const LFS_POINTER_MAX_LEN: usize = 1024;
+
+
+/// The SHA-256 of the object a git LFS pointer file stands in for, if `content` is one.
+fn lfs_pointer(content: &[u8]) -> Option<&str> {
+    if content.len() > LFS_POINTER_MAX_LEN {
+        return None;
+    }
+
+    let text = ::std::str::from_utf8(content).ok()?;
+    if !text.starts_with(LFS_POINTER_VERSION) {
+        return None;
+    }
+
+    text.lines()
+        .filter(|line| line.starts_with("oid sha256:"))
+        .map(|line| &line["oid sha256:".len()..])
+        .find(|oid| oid.len() == 64 && oid.chars().all(|c| c.is_digit(16)))
+}
+
+
+fn identity(signature: &Signature) -> Identity {
+    Identity {
+        name: String::from_utf8_lossy(signature.name_bytes()).into_owned(),
+        email: String::from_utf8_lossy(signature.email_bytes()).into_owned(),
+    }
+}
+
+
+/// Identifies the chunker a blob was split with, as chunkers are shared between the paths they
+/// are registered for.
+fn chunker_id(chunker: &Chunker) -> usize {
+    chunker as *const Chunker as *const u8 as usize
+}
+
+
+/// Converts the commits of a git repository, and everything they reference, into objects.
+pub struct Importer<'a, T: Trace> {
+    git: &'a GitRepository,
+    marshaller: Marshaller<T>,
+    chunkers: ChunkerSet,
+
+    /// Blobs already converted, keyed by the chunker they were split with, along with their sizes.
+    blobs: HashMap<(Oid, usize), (ObjectHash, u64)>,
+
+    /// Trees already converted, keyed by where they were found, since chunkers are chosen by
+    /// path.
+    trees: HashMap<(Oid, PathBuf), ObjectHash>,
+
+    commits: HashMap<Oid, ObjectHash>,
+    missing_lfs_objects: u64,
+}
+
+
+impl<'a, T: Trace> Importer<'a, T> {
+    pub fn new(git: &'a GitRepository, marshaller: Marshaller<T>, chunkers: ChunkerSet) -> Self {
+        Importer {
+            git,
+            marshaller,
+            chunkers,
+
+            blobs: HashMap::new(),
+            trees: HashMap::new(),
+
+            commits: HashMap::new(),
+            missing_lfs_objects: 0,
+        }
+    }
+
+    /// Take the commits in `commits` as already imported, as by an earlier import. See
+    /// `read_map`.
+    pub fn with_commits(mut self, commits: HashMap<Oid, ObjectHash>) -> Self {
+        self.commits = commits;
+        self
+    }
+
+    /// Every git commit imported so far, including those handed in through `with_commits`, and
+    /// the commits made of them.
+    pub fn commits(&self) -> &HashMap<Oid, ObjectHash> {
+        &self.commits
+    }
+
+    /// The number of LFS pointers imported as-is, since the objects they point to were missing.
+    pub fn missing_lfs_objects(&self) -> u64 {
+        self.missing_lfs_objects
+    }
+
+    /// Import `tip` and all of its history, returning the commit made of `tip`.
+    pub fn import(&mut self, tip: Oid) -> Result<ObjectHash> {
+        let mut revwalk = self.git.revwalk()?;
+        revwalk.set_sorting(git2::SORT_TOPOLOGICAL | git2::SORT_REVERSE);
+        revwalk.push(tip)?;
+
+        for oid_res in revwalk {
+            let oid = oid_res?;
+            if !self.commits.contains_key(&oid) {
+                self.import_commit(oid)?;
+            }
+        }
+
+        match self.commits.get(&tip) {
+            Some(&commit_hash) => Ok(commit_hash),
+            None => bail!(ErrorKind::GitNotACommit(tip.to_string())),
+        }
+    }
+
+    fn import_commit(&mut self, oid: Oid) -> Result<()> {
+        let git = self.git;
+        let commit = git.find_commit(oid)?;
+        let subtree = self.import_tree(commit.tree_id(), Path::new(""))?;
+
+        let mut parents = Vec::new();
+        for parent in commit.parent_ids() {
+            match self.commits.get(&parent) {
+                Some(&parent_hash) => parents.push(parent_hash),
+                None => {
+                    bail!(ErrorKind::GitParentNotImported(parent.to_string(), oid.to_string()))
+                }
+            }
+        }
+
+        let commit_object = CommitObject {
+            subtree,
+            parents,
+            message: String::from_utf8_lossy(commit.message_bytes()).into_owned(),
+            timestamp: Utc.timestamp(commit.time().seconds(), 0),
+            signature: None,
+            author: Some(identity(&commit.author())),
+            committer: Some(identity(&commit.committer())),
+        };
+
+        let commit_hash = self.marshaller.process(commit_object).wait()?;
+        self.commits.insert(oid, commit_hash);
+
+        Ok(())
+    }
+
+    fn import_tree(&mut self, oid: Oid, path: &Path) -> Result<ObjectHash> {
+        let key = (oid, path.to_owned());
+        if let Some(&subtree_hash) = self.trees.get(&key) {
+            return Ok(subtree_hash);
+        }
+
+        let git = self.git;
+        let tree = git.find_tree(oid)?;
+        let mut entries = BTreeMap::new();
+
+        for tree_entry in tree.iter() {
+            let name = OsStr::from_bytes(tree_entry.name_bytes()).to_owned();
+            let entry_path = path.join(&name);
+            let mode = tree_entry.filemode() as u32;
+
+            let entry = match mode & 0o170000 {
+                0o040000 => SubtreeEntry::Subtree(self.import_tree(tree_entry.id(), &entry_path)?),
+                0o160000 => {
+                    warning::warn(format!(
+                        "skipping the submodule at {}, as submodules cannot be imported.",
+                        entry_path.display()
+                    ));
+                    continue;
+                }
+                0o120000 => SubtreeEntry::Symlink(self.import_symlink(tree_entry.id())?),
+                _ => {
+                    let (object_hash, size) = self.import_blob(tree_entry.id(), &entry_path)?;
+                    SubtreeEntry::from_mode(object_hash, size, mode)
+                }
+            };
+
+            entries.insert(name, entry);
+        }
+
+        let subtree_hash = self.marshaller.process_tree(entries).wait()?;
+        self.trees.insert(key, subtree_hash);
+
+        Ok(subtree_hash)
+    }
+
+    fn import_blob(&mut self, oid: Oid, path: &Path) -> Result<(ObjectHash, u64)> {
+        let chunker = self.chunkers.for_path(path);
+        let key = (oid, chunker_id(&*chunker));
+        if let Some(&imported) = self.blobs.get(&key) {
+            return Ok(imported);
+        }
+
+        let git = self.git;
+        let blob = git.find_blob(oid)?;
+        let data = match lfs_pointer(blob.content()) {
+            Some(sha256) => {
+                let lfs_path = git.path()
+                    .join("lfs/objects")
+                    .join(&sha256[..2])
+                    .join(&sha256[2..4])
+                    .join(sha256);
+
+                if lfs_path.is_file() {
+                    arc_slice::mapped(Mmap::open_path(&lfs_path, Protection::Read)?)
+                } else {
+                    warning::warn(format!(
+                        "the LFS object for {} is missing; importing the pointer instead.",
+                        path.display()
+                    ));
+                    self.missing_lfs_objects += 1;
+                    arc_slice::owned(blob.content().to_vec())
+                }
+            }
+            None => arc_slice::owned(blob.content().to_vec()),
+        };
+
+        let size = data.len() as u64;
+        let object_hash = self.marshaller.process_chunks(chunker.chunk(data)).wait()?;
+        self.blobs.insert(key, (object_hash, size));
+
+        Ok((object_hash, size))
+    }
+
+    fn import_symlink(&mut self, oid: Oid) -> Result<ObjectHash> {
+        // Link targets are never chunked, so any key unused by a chunker will do.
+        let key = (oid, 0);
+        if let Some(&(object_hash, _)) = self.blobs.get(&key) {
+            return Ok(object_hash);
+        }
+
+        let git = self.git;
+        let target = git.find_blob(oid)?.content().to_vec();
+        let size = target.len() as u64;
+        let chunks = stream::once::<_, Error>(Ok(arc_slice::owned(target)));
+        let object_hash = self.marshaller.process_chunks(chunks).wait()?;
+        self.blobs.insert(key, (object_hash, size));
+
+        Ok(object_hash)
+    }
+}
+
+
+/// Read a map of imported commits, written by `write_map`, as `<git commit> <commit>` lines.
+pub fn read_map<P: AsRef<Path>>(path: P) -> Result<HashMap<Oid, ObjectHash>> {
+    let mut commits = HashMap::new();
+
+    for line_res in BufReader::new(File::open(path)?).lines() {
+        let line = line_res?;
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(oid), Some(commit_hash)) => {
+                commits.insert(Oid::from_str(oid)?, commit_hash.parse()?);
+            }
+            (None, _) => {}
+            _ => bail!(ErrorKind::MalformedCommitMap(line.clone())),
+        }
+    }
+
+    Ok(commits)
+}
+
+
+/// Write a map of imported commits, sorted by git commit, replacing the file at `path`.
+pub fn write_map<P: AsRef<Path>>(path: P, commits: &HashMap<Oid, ObjectHash>) -> Result<()> {
+    let path = path.as_ref();
+    let temp_path = path.with_extension("tmp");
+
+    {
+        let mut sorted = commits.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|&(oid, _)| *oid);
+
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for (oid, commit_hash) in sorted {
+            writeln!(writer, "{} {}", oid, commit_hash)?;
+        }
+        writer.flush()?;
+    }
+
+    fs::rename(temp_path, path)?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lfs_pointers() {
+        let oid = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+        let pointer = format!("{}oid sha256:{}\nsize 12345\n", LFS_POINTER_VERSION, oid);
+        assert_eq!(lfs_pointer(pointer.as_bytes()), Some(oid));
+
+        let truncated = format!("{}oid sha256:{}\nsize 12345\n", LFS_POINTER_VERSION, &oid[..63]);
+        assert_eq!(lfs_pointer(truncated.as_bytes()), None);
+
+        assert_eq!(lfs_pointer(b"oid sha256:0000\n"), None);
+    }
+}
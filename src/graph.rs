@@ -0,0 +1,368 @@
+//! # `graph` - breadth-first traversal of the object graph.
+//!
+//! `visit` walks every object reachable from a set of roots, reading each distinct object exactly
+//! once no matter how many times it is referred to. Which objects are read and which of their
+//! references are followed is up to a `Visitor`, as is which objects are put off until everything
+//! else reachable has been visited.
+//!
+//! Object graphs of large repositories can have far more objects than fit comfortably in memory,
+//! so the set of visited hashes is kept in a `VisitedSet`, which holds up to a fixed number of
+//! hashes in memory and spills the rest to a sorted file on disk.
+
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use futures::prelude::*;
+use futures::stream;
+use libc;
+
+use errors::*;
+use marshal::{ObjectHash, Object};
+use store::ObjectStore;
+
+
+/// The number of hashes a `VisitedSet` keeps in memory before spilling to disk, by default. At 32
+/// bytes per hash plus overhead, this is on the order of 64 MiB.
+pub const DEFAULT_MEMORY_BUDGET: usize = 1 << 20;
+
+
+/// The number of object reads `visit` keeps in flight at once.
+const VISIT_READ_BUFFER_SIZE: usize = 64;
+
+
+const HASH_SIZE: u64 = 32;
+
+
+static SPILL_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+fn read_hash_at(file: &File, buf: &mut [u8; HASH_SIZE as usize], offset: u64) -> Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64)? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            n => read += n,
+        }
+    }
+
+    Ok(())
+}
+
+
+/// The set of sorted hashes which have been spilled to disk.
+#[derive(Debug)]
+struct Spill {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+
+impl Spill {
+    fn contains(&self, hash: &ObjectHash) -> Result<bool> {
+        let mut buf = [0u8; HASH_SIZE as usize];
+        let (mut lo, mut hi) = (0, self.len);
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            read_hash_at(&self.file, &mut buf, mid * HASH_SIZE)?;
+
+            match buf[..].cmp(hash.as_slice()) {
+                cmp::Ordering::Less => lo = mid + 1,
+                cmp::Ordering::Greater => hi = mid,
+                cmp::Ordering::Equal => return Ok(true),
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+/// A set of object hashes which spills to disk once it grows past a memory budget.
+#[derive(Debug)]
+pub struct VisitedSet {
+    memory: HashSet<ObjectHash>,
+    budget: usize,
+    spill_dir: PathBuf,
+    spill: Option<Spill>,
+}
+
+
+impl Default for VisitedSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl VisitedSet {
+    /// Create a visited set with the default memory budget, spilling to the system temporary
+    /// directory.
+    pub fn new() -> Self {
+        Self::with_budget(DEFAULT_MEMORY_BUDGET, env::temp_dir())
+    }
+
+    /// Create a visited set which keeps at most `budget` hashes in memory, spilling the rest into
+    /// a file in `spill_dir`.
+    pub fn with_budget<P: AsRef<Path>>(budget: usize, spill_dir: P) -> Self {
+        VisitedSet {
+            memory: HashSet::new(),
+            budget: cmp::max(budget, 1),
+            spill_dir: spill_dir.as_ref().to_owned(),
+            spill: None,
+        }
+    }
+
+    /// The number of hashes in the set.
+    pub fn len(&self) -> u64 {
+        self.memory.len() as u64 + self.spill.as_ref().map(|spill| spill.len).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, hash: &ObjectHash) -> Result<bool> {
+        if self.memory.contains(hash) {
+            return Ok(true);
+        }
+
+        match self.spill {
+            Some(ref spill) => spill.contains(hash),
+            None => Ok(false),
+        }
+    }
+
+    /// Insert a hash, returning `true` if it was not already present.
+    pub fn insert(&mut self, hash: ObjectHash) -> Result<bool> {
+        if self.contains(&hash)? {
+            return Ok(false);
+        }
+
+        self.memory.insert(hash);
+
+        if self.memory.len() >= self.budget {
+            self.spill()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Merge the in-memory hashes into the sorted spill file, replacing it.
+    fn spill(&mut self) -> Result<()> {
+        let mut in_memory = self.memory.drain().collect::<Vec<_>>();
+        in_memory.sort();
+
+        let path = self.spill_dir.join(format!(
+            "attaca-visited-{}-{}",
+            unsafe { libc::getpid() },
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        let len = {
+            let mut writer = BufWriter::new(&file);
+            let mut len = 0;
+            let mut in_memory = in_memory.into_iter().peekable();
+
+            if let Some(ref spill) = self.spill {
+                let mut file = &spill.file;
+                file.seek(SeekFrom::Start(0))?;
+                let mut reader = BufReader::new(file);
+                let mut buf = [0u8; HASH_SIZE as usize];
+
+                for _ in 0..spill.len {
+                    reader.read_exact(&mut buf)?;
+
+                    while in_memory.peek().map(|hash| hash.as_slice() < &buf[..]) == Some(true) {
+                        writer.write_all(in_memory.next().unwrap().as_slice())?;
+                        len += 1;
+                    }
+
+                    writer.write_all(&buf)?;
+                    len += 1;
+                }
+            }
+
+            for hash in in_memory {
+                writer.write_all(hash.as_slice())?;
+                len += 1;
+            }
+
+            writer.flush()?;
+
+            len
+        };
+
+        // Replacing the old spill drops it, removing its file.
+        self.spill = Some(Spill { path, file, len });
+
+        Ok(())
+    }
+}
+
+
+/// What to do after visiting an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Visit {
+    /// Follow every reference out of the object.
+    Descend,
+
+    /// Follow only the given references.
+    Follow(Vec<ObjectHash>),
+
+    /// Follow none of the object's references.
+    Prune,
+}
+
+
+/// Decides which objects `visit` reads and which of their references it follows.
+pub trait Visitor: Send + 'static {
+    type Future: Future<Item = Visit, Error = Error> + Send + 'static;
+
+    /// Decide whether to read an object at all. Called once for each distinct hash before it is
+    /// read; objects which are not entered are neither visited nor descended into.
+    fn enter(&mut self, _hash: ObjectHash) -> bool {
+        true
+    }
+
+    /// Decide whether to put off reading an entered object until every object which is not put
+    /// off has been visited. Called once for each entered object.
+    fn defer(&mut self, _hash: ObjectHash) -> bool {
+        false
+    }
+
+    /// Visit an object. Objects are visited one at a time, in breadth-first order, deferred
+    /// objects after all others.
+    fn visit(&mut self, hash: ObjectHash, object: Object) -> Self::Future;
+}
+
+
+/// Walk the object graph breadth-first from `roots`, returning the visitor once every reachable
+/// object has been visited.
+pub fn visit<S, I, V>(store: &S, roots: I, visitor: V) -> Box<Future<Item = V, Error = Error> + Send>
+where
+    S: ObjectStore,
+    I: IntoIterator<Item = ObjectHash>,
+    V: Visitor,
+{
+    visit_with(store, roots, visitor, VisitedSet::new())
+}
+
+
+/// As `visit`, but tracking visited objects in the given set. Hashes already in the set are
+/// neither entered nor visited.
+pub fn visit_with<S, I, V>(
+    store: &S,
+    roots: I,
+    mut visitor: V,
+    mut visited: VisitedSet,
+) -> Box<Future<Item = V, Error = Error> + Send>
+where
+    S: ObjectStore,
+    I: IntoIterator<Item = ObjectHash>,
+    V: Visitor,
+{
+    let store = store.clone();
+    let roots = roots.into_iter().collect::<Vec<_>>();
+
+    let result = {
+        async_block! {
+            let mut frontier = VecDeque::new();
+            let mut deferred = VecDeque::new();
+            for hash in roots {
+                if visited.insert(hash)? && visitor.enter(hash) {
+                    if visitor.defer(hash) {
+                        deferred.push_back(hash);
+                    } else {
+                        frontier.push_back(hash);
+                    }
+                }
+            }
+
+            while !frontier.is_empty() || !deferred.is_empty() {
+                // Deferred objects are only read once nothing else is left, and whatever they
+                // refer to is then deferred or not afresh.
+                if frontier.is_empty() {
+                    mem::swap(&mut frontier, &mut deferred);
+                }
+
+                let reads = {
+                    let store = store.clone();
+                    stream::iter_ok::<_, Error>(frontier.drain(..).collect::<Vec<_>>())
+                        .map(move |hash| store.read_object(hash).map(move |object| (hash, object)))
+                        .buffered(VISIT_READ_BUFFER_SIZE)
+                };
+
+                #[async]
+                for (hash, object) in reads {
+                    let refs = object.refs();
+                    let follow = match await!(visitor.visit(hash, object))? {
+                        Visit::Descend => refs,
+                        Visit::Follow(follow) => follow,
+                        Visit::Prune => Vec::new(),
+                    };
+
+                    for next in follow {
+                        if visited.insert(next)? && visitor.enter(next) {
+                            if visitor.defer(next) {
+                                deferred.push_back(next);
+                            } else {
+                                frontier.push_back(next);
+                            }
+                        }
+                    }
+                }
+            }
+
+            Ok(visitor)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::hash_of;
+
+    quickcheck! {
+        fn visited_set_spills_losslessly(ns: Vec<u64>, absent: u64, budget: u8) -> bool {
+            let mut visited = VisitedSet::with_budget(budget as usize, env::temp_dir());
+            let mut reference = HashSet::new();
+
+            for &n in &ns {
+                let hash = hash_of(n);
+                if visited.insert(hash).unwrap() != reference.insert(hash) {
+                    return false;
+                }
+            }
+
+            visited.len() == reference.len() as u64
+                && ns.iter().all(|&n| visited.contains(&hash_of(n)).unwrap())
+                && visited.contains(&hash_of(absent)).unwrap() == ns.contains(&absent)
+        }
+    }
+}
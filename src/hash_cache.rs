@@ -0,0 +1,221 @@
+//! # `hash_cache` - remembered hashes of files in the worktree.
+//!
+//! The index only remembers the hash of a file for as long as the file stays tracked and clean.
+//! The hash cache remembers it for as long as the file itself is unchanged: it maps each path to
+//! the hash and size its contents had once cleaned, along with the size, mtime, and inode the file
+//! had at the time. If a file's stat information still matches when it is next committed, its
+//! hash is taken from the cache instead of splitting and hashing the file again; if not, the entry
+//! is stale and is replaced once the file has been rehashed.
+//!
+//! A file modified twice within the resolution of its mtime could change without its stat
+//! information changing. To rule this out, files whose mtimes are not older than the instant the
+//! cache was opened are never cached.
+
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use bincode;
+use chrono::prelude::*;
+
+use errors::*;
+use marshal::ObjectHash;
+use repository::Paths;
+
+
+/// The stat information a cached hash is valid for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatKey {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ino: u64,
+}
+
+
+impl StatKey {
+    pub fn new(metadata: &Metadata) -> Self {
+        StatKey {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            ino: metadata.ino(),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct CacheEntry {
+    key: StatKey,
+    hash: ObjectHash,
+
+    /// The size of the contents hashed, which a clean filter may have made differ from the size
+    /// of the file.
+    size: u64,
+
+    /// The header byte of the object format the hash was computed with, or `0` if unframed. The
+    /// same contents hash differently in different formats.
+    version: u8,
+}
+
+
+#[derive(Debug)]
+pub struct HashCache {
+    entries: HashMap<PathBuf, CacheEntry>,
+
+    /// The normalization settings the entries were hashed under. See
+    /// `Repository::normalization`.
+    config: u64,
+
+    /// When we were opened, in seconds since the epoch.
+    opened: i64,
+
+    /// Whether anything has been inserted since we were opened.
+    modified: bool,
+}
+
+
+impl HashCache {
+    pub fn open(paths: &Paths) -> Result<Self> {
+        let opened = Utc::now().timestamp();
+
+        let (config, entries) = if paths.hash_cache.exists() {
+            let mut bytes = Vec::new();
+            File::open(&paths.hash_cache)?.read_to_end(&mut bytes)?;
+
+            // The cache is only an optimization, so a damaged one is simply started afresh.
+            bincode::deserialize(&bytes).unwrap_or_default()
+        } else {
+            (0, HashMap::new())
+        };
+
+        Ok(HashCache {
+            entries,
+            config,
+            opened,
+            modified: false,
+        })
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+
+        let bytes = bincode::serialize(&(self.config, &self.entries), bincode::Infinite)?;
+        let temp_path = paths.hash_cache.with_extension("bin.tmp");
+        File::create(&temp_path)?.write_all(&bytes)?;
+        fs::rename(temp_path, &paths.hash_cache)?;
+
+        Ok(())
+    }
+
+    /// The normalization settings the cached hashes were computed under.
+    pub fn config(&self) -> u64 {
+        self.config
+    }
+
+    /// Forget every cached hash, as the normalization settings have changed to `config`.
+    pub fn reset(&mut self, config: u64) {
+        self.entries.clear();
+        self.config = config;
+        self.modified = true;
+    }
+
+    /// The hash and size of the cleaned contents of the file at `path`, if they were hashed in the
+    /// object format `version` when the file had the stat information `key`.
+    pub fn get<P: AsRef<Path>>(
+        &self,
+        path: P,
+        key: &StatKey,
+        version: u8,
+    ) -> Option<(ObjectHash, u64)> {
+        self.entries.get(path.as_ref()).and_then(|entry| {
+            if &entry.key == key && entry.version == version {
+                Some((entry.hash, entry.size))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Record that the file at `path`, with the stat information `key`, cleans to `size` bytes
+    /// which hash to `hash` in the object format `version`. Any stale entry for the path is
+    /// dropped, even if the new one is too recent to be cached.
+    pub fn insert(
+        &mut self,
+        path: PathBuf,
+        key: StatKey,
+        hash: ObjectHash,
+        size: u64,
+        version: u8,
+    ) {
+        if self.get(&path, &key, version) == Some((hash, size)) {
+            return;
+        }
+
+        self.modified = true;
+
+        if key.mtime < self.opened {
+            self.entries.insert(
+                path,
+                CacheEntry {
+                    key,
+                    hash,
+                    size,
+                    version,
+                },
+            );
+        } else {
+            self.entries.remove(&path);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::Scratch;
+
+    #[test]
+    fn stale_and_recent_entries_miss() {
+        let scratch = Scratch::new("attaca-hash-cache").unwrap();
+        let dir = scratch.path();
+        let path = dir.join("file");
+        File::create(&path).unwrap().write_all(b"hello").unwrap();
+
+        let paths = Paths::new(&dir);
+        fs::create_dir_all(&paths.metadata).unwrap();
+        let mut cache = HashCache::open(&paths).unwrap();
+        let key = StatKey::new(&fs::metadata(&path).unwrap());
+
+        // The file was written just now, so it is too recent to cache.
+        cache.insert(path.clone(), key, ObjectHash::zero(), 4, 1);
+        assert_eq!(cache.get(&path, &key, 1), None);
+
+        cache.opened = key.mtime + 1;
+        cache.insert(path.clone(), key, ObjectHash::zero(), 4, 1);
+        assert_eq!(cache.get(&path, &key, 1), Some((ObjectHash::zero(), 4)));
+        assert_eq!(cache.get(&path, &key, 0), None);
+
+        cache.save(&paths).unwrap();
+        let reopened = HashCache::open(&paths).unwrap();
+        assert_eq!(reopened.get(&path, &key, 1), Some((ObjectHash::zero(), 4)));
+
+        let changed = StatKey { size: 6, ..key };
+        assert_eq!(reopened.get(&path, &changed, 1), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! # `bisect` - search history for the commit which introduced a change.
+//!
+//! A `Bisector` starts from a bad commit and one or more good ones. The candidates for the first
+//! bad commit are those reachable from the bad commit but from none of the good ones. Each step
+//! suggests the candidate which splits the candidates most evenly into those which are its
+//! ancestors and those which are not, so that whichever way it is marked, about half of them are
+//! ruled out.
+//!
+//! The ancestry of the bad commit is read once, when bisection starts, and kept in the
+//! `Bisector`, so marking commits and choosing the next one never touches the store. Bisection
+//! state is saved between commands in `.attaca/bisect.bin`.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+use bincode;
+use futures::prelude::*;
+
+use errors::*;
+use history::ancestry;
+use marshal::ObjectHash;
+use repository::Paths;
+use store::ObjectStore;
+
+
+/// What to do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Test the given commit and mark it good or bad. The number of remaining candidates is
+    /// included to give an idea of how many steps are left.
+    Test(ObjectHash, usize),
+
+    /// The given commit is the first bad commit.
+    Found(ObjectHash),
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bisector {
+    /// The parents of every commit reachable from the original bad commit.
+    parents: HashMap<ObjectHash, Vec<ObjectHash>>,
+
+    /// The oldest commit known to be bad.
+    bad: ObjectHash,
+
+    /// Every commit marked good.
+    good: Vec<ObjectHash>,
+}
+
+
+impl Bisector {
+    /// Start bisecting between a bad commit and some good ones.
+    pub fn start<S: ObjectStore>(
+        store: &S,
+        bad: ObjectHash,
+        good: Vec<ObjectHash>,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        Box::new(ancestry(store, bad).map(move |commits| {
+            let parents = commits
+                .into_iter()
+                .map(|(hash, commit_object)| (hash, commit_object.parents))
+                .collect();
+
+            Bisector { parents, bad, good }
+        }))
+    }
+
+    /// Every commit reachable from `hash` (including itself) within the bisected history.
+    fn ancestors(&self, hash: ObjectHash) -> HashSet<ObjectHash> {
+        let mut ancestors = HashSet::new();
+        let mut stack = vec![hash];
+
+        while let Some(next) = stack.pop() {
+            if ancestors.insert(next) {
+                if let Some(parents) = self.parents.get(&next) {
+                    stack.extend(parents.iter().cloned());
+                }
+            }
+        }
+
+        ancestors
+    }
+
+    /// Every commit which might still be the first bad commit.
+    pub fn candidates(&self) -> HashSet<ObjectHash> {
+        let mut candidates = self.ancestors(self.bad);
+
+        for &good in &self.good {
+            for ancestor in self.ancestors(good) {
+                candidates.remove(&ancestor);
+            }
+        }
+
+        candidates
+    }
+
+    pub fn mark_good(&mut self, hash: ObjectHash) -> Result<()> {
+        if !self.parents.contains_key(&hash) {
+            bail!(ErrorKind::NotInBisection(hash));
+        }
+
+        self.good.push(hash);
+
+        Ok(())
+    }
+
+    pub fn mark_bad(&mut self, hash: ObjectHash) -> Result<()> {
+        if !self.candidates().contains(&hash) {
+            bail!(ErrorKind::NotInBisection(hash));
+        }
+
+        self.bad = hash;
+
+        Ok(())
+    }
+
+    /// Choose the next commit to test, or report the first bad commit if there is only one
+    /// candidate left.
+    pub fn next(&self) -> Step {
+        let candidates = self.candidates();
+        let total = candidates.len();
+
+        if total <= 1 {
+            return Step::Found(self.bad);
+        }
+
+        // The bad commit is already known to be bad, so testing it would tell us nothing. Ties
+        // are broken by hash, so that the suggestion is deterministic.
+        let best = candidates
+            .iter()
+            .filter(|&&hash| hash != self.bad)
+            .map(|&hash| {
+                let below = self.ancestors(hash)
+                    .iter()
+                    .filter(|ancestor| candidates.contains(ancestor))
+                    .count();
+                let balance = if below < total - below { below } else { total - below };
+
+                (balance, hash)
+            })
+            .max()
+            .map(|(_, hash)| hash)
+            .unwrap();
+
+        Step::Test(best, total)
+    }
+
+    /// Load the bisection in progress, if there is one.
+    pub fn open(paths: &Paths) -> Result<Option<Self>> {
+        if !paths.bisect.exists() {
+            return Ok(None);
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&paths.bisect)?.read_to_end(&mut bytes)?;
+
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        let bytes = bincode::serialize(self, bincode::Infinite)?;
+        File::create(&paths.bisect)?.write_all(&bytes)?;
+
+        Ok(())
+    }
+
+    /// Abandon the bisection in progress, if there is one.
+    pub fn reset(paths: &Paths) -> Result<()> {
+        if paths.bisect.exists() {
+            fs::remove_file(&paths.bisect)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::hash_of;
+
+    fn linear(len: u64, good: u64, bad: u64) -> Bisector {
+        let parents = (0..len)
+            .map(|i| {
+                let parents = if i == 0 { vec![] } else { vec![hash_of(i - 1)] };
+                (hash_of(i), parents)
+            })
+            .collect();
+
+        Bisector {
+            parents,
+            bad: hash_of(bad),
+            good: vec![hash_of(good)],
+        }
+    }
+
+    #[test]
+    fn finds_first_bad_commit() {
+        for first_bad in 1..32 {
+            let mut bisector = linear(32, 0, 31);
+            let mut steps = 0;
+
+            let found = loop {
+                match bisector.next() {
+                    Step::Found(hash) => break hash,
+                    Step::Test(hash, _) => {
+                        let n = (0..32).find(|&n| hash_of(n) == hash).unwrap();
+                        if n >= first_bad {
+                            bisector.mark_bad(hash).unwrap();
+                        } else {
+                            bisector.mark_good(hash).unwrap();
+                        }
+                        steps += 1;
+                    }
+                }
+            };
+
+            assert_eq!(found, hash_of(first_bad));
+            assert!(steps <= 5);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! # `blame` - attribute the contents of a file to the commits which introduced them.
+//!
+//! Files are blamed at chunk granularity. History is walked from the oldest commit forwards, and
+//! every commit records which commit introduced each node of its version of the file's chunk
+//! tree. A node which one of a commit's parents has already attributed is inherited whole,
+//! without being read, so unchanged regions of a file cost a single lookup no matter how large
+//! they are; only new parts of the tree are ever read from the store.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::prelude::*;
+
+use errors::*;
+use history::{ancestry, resolve};
+use marshal::{ObjectHash, Object, DataObject, CommitObject, SubtreeEntry};
+use store::ObjectStore;
+
+
+/// A range of a file, attributed to the commit which introduced it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlameRange {
+    pub offset: u64,
+    pub size: u64,
+    pub commit: ObjectHash,
+}
+
+
+/// The blame of a single file.
+#[derive(Debug, Clone)]
+pub struct Blame {
+    /// Ranges covering the whole file in order, with adjacent ranges from the same commit merged.
+    pub ranges: Vec<BlameRange>,
+
+    /// Every commit which some range is attributed to.
+    pub commits: HashMap<ObjectHash, CommitObject>,
+}
+
+
+/// Which commit introduced each node of a version of a file's chunk tree. Nodes only appear if
+/// the whole of their range was introduced by the same commit.
+type Attribution = HashMap<ObjectHash, ObjectHash>;
+
+
+enum Node {
+    /// A node whose range starts at the given offset and has the given size.
+    Enter(ObjectHash, u64, u64),
+
+    /// The end of a large node, whose children's ranges start at the given index.
+    Exit(ObjectHash, usize),
+}
+
+
+/// Blame the file at `path` as of the commit `head`.
+pub fn blame<S: ObjectStore, P: AsRef<Path>>(
+    store: &S,
+    head: ObjectHash,
+    path: P,
+) -> Box<Future<Item = Blame, Error = Error> + Send> {
+    let store = store.clone();
+    let path = path.as_ref().to_owned();
+
+    let result = {
+        async_block! {
+            let mut commits = HashMap::new();
+            let mut attributions = HashMap::<ObjectHash, Arc<Attribution>>::new();
+            let mut ranges = Vec::new();
+
+            for (commit_hash, commit_object) in await!(ancestry(&store, head))? {
+                let subtree_hash = commit_object.subtree;
+                let parents = commit_object.parents.clone();
+                commits.insert(commit_hash, commit_object);
+
+                let entry_opt = await!(resolve(&store, subtree_hash, &path))?;
+                let file_opt = entry_opt.as_ref().map(SubtreeEntry::unannotated);
+                let (data_hash, data_size) = match file_opt {
+                    Some(&SubtreeEntry::File(hash, size)) |
+                    Some(&SubtreeEntry::Executable(hash, size)) => (hash, size),
+                    _ if commit_hash == head => bail!(ErrorKind::NoSuchFile(path.clone(), head)),
+                    _ => {
+                        attributions.insert(commit_hash, Arc::new(Attribution::new()));
+                        continue;
+                    }
+                };
+
+                let inherited = parents
+                    .iter()
+                    .filter_map(|parent| attributions.get(parent).cloned())
+                    .collect::<Vec<_>>();
+
+                // A file left unchanged by a parent shares that parent's attribution outright.
+                let unchanged = inherited.iter().find(|attribution| {
+                    attribution.contains_key(&data_hash)
+                }).cloned();
+                if let Some(attribution) = unchanged {
+                    if commit_hash == head {
+                        ranges = vec![BlameRange {
+                            offset: 0,
+                            size: data_size,
+                            commit: attribution[&data_hash],
+                        }];
+                    }
+                    attributions.insert(commit_hash, attribution);
+                    continue;
+                }
+
+                let mut attribution = Attribution::new();
+                let mut commit_ranges = Vec::<BlameRange>::new();
+                let mut stack = vec![Node::Enter(data_hash, 0, data_size)];
+
+                while let Some(node) = stack.pop() {
+                    match node {
+                        Node::Enter(hash, offset, size) => {
+                            let owner_opt = inherited
+                                .iter()
+                                .filter_map(|attribution| attribution.get(&hash))
+                                .next()
+                                .cloned();
+
+                            if let Some(owner) = owner_opt {
+                                attribution.insert(hash, owner);
+                                commit_ranges.push(BlameRange { offset, size, commit: owner });
+                                continue;
+                            }
+
+                            match await!(store.read_object(hash))? {
+                                Object::Data(DataObject::Small(_)) => {
+                                    attribution.insert(hash, commit_hash);
+                                    commit_ranges.push(BlameRange {
+                                        offset,
+                                        size,
+                                        commit: commit_hash,
+                                    });
+                                }
+                                Object::Data(DataObject::Large(large_object)) => {
+                                    stack.push(Node::Exit(hash, commit_ranges.len()));
+
+                                    let mut children = Vec::new();
+                                    let mut child_offset = offset;
+                                    for (child_size, child_hash) in large_object.children {
+                                        children.push(
+                                            Node::Enter(child_hash, child_offset, child_size),
+                                        );
+                                        child_offset += child_size;
+                                    }
+                                    stack.extend(children.into_iter().rev());
+                                }
+                                _ => bail!(ErrorKind::ObjectNotData(hash)),
+                            }
+                        }
+                        Node::Exit(hash, start) => {
+                            let owner_opt = commit_ranges.get(start).map(|range| range.commit);
+                            if let Some(owner) = owner_opt {
+                                let uniform = commit_ranges[start..].iter().all(|range| {
+                                    range.commit == owner
+                                });
+                                if uniform {
+                                    attribution.insert(hash, owner);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if commit_hash == head {
+                    ranges = commit_ranges;
+                }
+                attributions.insert(commit_hash, Arc::new(attribution));
+            }
+
+            let mut merged = Vec::<BlameRange>::new();
+            for range in ranges {
+                if let Some(last) = merged.last_mut() {
+                    if last.commit == range.commit {
+                        last.size += range.size;
+                        continue;
+                    }
+                }
+                merged.push(range);
+            }
+
+            let blamed = merged.iter().map(|range| range.commit).collect::<HashSet<_>>();
+            let commits = commits
+                .into_iter()
+                .filter(|&(hash, _)| blamed.contains(&hash))
+                .collect();
+
+            Ok(Blame {
+                ranges: merged,
+                commits,
+            })
+        }
+    };
+
+    Box::new(result)
+}
@@ -0,0 +1,227 @@
+//! # `conflict` - resolving the paths a merge or rebase stopped at, one at a time.
+//!
+//! When a merge cannot reconcile a path, the entries the merge base and each side had there are
+//! recorded in a `ConflictState`, along with the operation which stopped, and saved between
+//! commands in `.attaca/conflicts.bin`. Each path is then resolved on its own: by accepting our or
+//! their version, which the caller checks out in place of what is in the worktree, or by fixing the
+//! file up by hand and marking it resolved, in which case the worktree's version is kept. Once no
+//! path is left unresolved, the operation may be finished; a merge is finished by committing the
+//! worktree with both sides as parents, and a rebase by carrying on replaying commits.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use bincode;
+
+use errors::*;
+use history::merge::{Merge, Sides};
+use marshal::{ObjectHash, SubtreeEntry};
+use repository::Paths;
+
+
+/// One side of a merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+
+/// How a conflicting path was resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Resolution {
+    /// One side's version was taken as it is.
+    Accepted(Side),
+
+    /// Whatever is in the worktree at the path is the resolution.
+    Worktree,
+}
+
+
+/// A path changed differently by both sides of a merge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathConflict {
+    pub sides: Sides,
+    pub resolution: Option<Resolution>,
+}
+
+
+impl PathConflict {
+    /// The entry one side had at the path, if any.
+    pub fn side(&self, side: Side) -> Option<&SubtreeEntry> {
+        match side {
+            Side::Ours => self.sides.ours.as_ref(),
+            Side::Theirs => self.sides.theirs.as_ref(),
+        }
+    }
+}
+
+
+/// The operation which stopped at conflicts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    /// A merge of the commit `theirs` into the commit `ours`, which is where `branch` pointed, or
+    /// where the HEAD was detached if `branch` is `None`.
+    Merge {
+        branch: Option<String>,
+        ours: ObjectHash,
+        theirs: ObjectHash,
+        message: String,
+    },
+
+    /// A rebase, whose own state is kept by the `rebase` module.
+    Rebase,
+}
+
+
+/// The conflicts a merge or rebase stopped at, and how far they have been resolved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictState {
+    pub operation: Operation,
+    pub paths: BTreeMap<PathBuf, PathConflict>,
+}
+
+
+impl ConflictState {
+    /// Record the conflicts of `merge`, none of which have yet been resolved.
+    pub fn new(operation: Operation, merge: &Merge) -> Self {
+        Self::from_sides(operation, merge.sides.clone())
+    }
+
+    pub fn from_sides(operation: Operation, sides: BTreeMap<PathBuf, Sides>) -> Self {
+        let paths = sides
+            .into_iter()
+            .map(|(path, sides)| {
+                let conflict = PathConflict {
+                    sides,
+                    resolution: None,
+                };
+
+                (path, conflict)
+            })
+            .collect();
+
+        ConflictState { operation, paths }
+    }
+
+    /// Every path yet to be resolved, in order.
+    pub fn unresolved(&self) -> Vec<&Path> {
+        self.paths
+            .iter()
+            .filter(|&(_, conflict)| conflict.resolution.is_none())
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.paths.values().all(|conflict| conflict.resolution.is_some())
+    }
+
+    /// Fail unless every path has been resolved.
+    pub fn check_resolved(&self) -> Result<()> {
+        let unresolved = self.unresolved().len();
+        if unresolved > 0 {
+            bail!(ErrorKind::UnresolvedConflicts(unresolved));
+        }
+
+        Ok(())
+    }
+
+    fn get_mut(&mut self, path: &Path) -> Result<&mut PathConflict> {
+        match self.paths.get_mut(path) {
+            Some(conflict) => Ok(conflict),
+            None => bail!(ErrorKind::PathNotConflicted(path.to_owned())),
+        }
+    }
+
+    /// Resolve `path` by taking one side's version, returning the entry that side had there, which
+    /// the caller must check out in place of what is in the worktree. `None` means that the side
+    /// deleted the path.
+    pub fn accept(&mut self, path: &Path, side: Side) -> Result<Option<SubtreeEntry>> {
+        let conflict = self.get_mut(path)?;
+        conflict.resolution = Some(Resolution::Accepted(side));
+
+        Ok(conflict.side(side).cloned())
+    }
+
+    /// Resolve `path` by keeping whatever is in the worktree there.
+    pub fn mark_resolved(&mut self, path: &Path) -> Result<()> {
+        self.get_mut(path)?.resolution = Some(Resolution::Worktree);
+
+        Ok(())
+    }
+
+    /// Load the conflicts in progress, if there are any.
+    pub fn open(paths: &Paths) -> Result<Option<Self>> {
+        if !paths.conflicts.exists() {
+            return Ok(None);
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&paths.conflicts)?.read_to_end(&mut bytes)?;
+
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        let bytes = bincode::serialize(self, bincode::Infinite)?;
+        File::create(&paths.conflicts)?.write_all(&bytes)?;
+
+        Ok(())
+    }
+
+    /// Forget the conflicts in progress, if there are any.
+    pub fn clear(paths: &Paths) -> Result<()> {
+        if paths.conflicts.exists() {
+            fs::remove_file(&paths.conflicts)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_each_path() {
+        let file = |byte: u8| {
+            let hash = format!("{:064x}", byte).parse().unwrap();
+            Some(SubtreeEntry::File(hash, byte as u64))
+        };
+        let mut sides = BTreeMap::new();
+        sides.insert(
+            PathBuf::from("a"),
+            Sides {
+                base: file(0),
+                ours: file(1),
+                theirs: file(2),
+            },
+        );
+        sides.insert(
+            PathBuf::from("b"),
+            Sides {
+                base: file(0),
+                ours: None,
+                theirs: file(3),
+            },
+        );
+        let mut state = ConflictState::from_sides(Operation::Rebase, sides);
+
+        assert_eq!(state.unresolved(), vec![Path::new("a"), Path::new("b")]);
+        assert!(state.check_resolved().is_err());
+
+        assert_eq!(state.accept(Path::new("a"), Side::Theirs).unwrap(), file(2));
+        assert_eq!(state.accept(Path::new("b"), Side::Ours).unwrap(), None);
+        assert!(state.accept(Path::new("c"), Side::Ours).is_err());
+        assert!(state.is_resolved());
+
+        state.mark_resolved(Path::new("a")).unwrap();
+        assert_eq!(state.paths[Path::new("a")].resolution, Some(Resolution::Worktree));
+        state.check_resolved().unwrap();
+    }
+}
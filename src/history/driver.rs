@@ -0,0 +1,445 @@
+//! # `driver` - merging the contents of files both sides of a merge changed.
+//!
+//! A `MergeDriver` is handed the base's and each side's version of a file and either merges them
+//! or reports a conflict. Every repository has a `MergeDriverSet`, which picks a driver for each
+//! file by its path, just as the `ChunkerSet` picks chunkers: downstream crates may register
+//! drivers of their own for the formats they know, and every other file is merged by the default
+//! driver.
+//!
+//! The default, `Text`, is a line-based three-way merge: lines changed by only one side are taken
+//! from that side, and lines changed differently by both are a conflict. Anything containing a NUL
+//! byte is taken to be binary and always conflicts, as does anything registered to the `Binary`
+//! driver. `Json` merges objects key by key, recursing into objects changed on both sides, and is
+//! registered by default for files named `*.json`; `Csv` merges rows keyed by their first field,
+//! and is registered for `*.csv`. Both conflict on anything which does not parse.
+//!
+//! Drivers must not guess. Returning `None` leaves the path to be resolved by hand, which is
+//! always better than a merge which silently loses one side's changes.
+
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use globset::{Glob, GlobMatcher};
+use serde_json::{self, Map, Value};
+
+
+/// Files larger than this are never handed to a driver, and conflict whenever both sides change
+/// them.
+pub const MAX_DRIVER_SIZE: u64 = 16 * 1024 * 1024;
+
+
+/// The most cells of the table `Text` fills in to match lines, after any common prefix and suffix
+/// are trimmed. Larger changes conflict instead of taking quadratic time and space.
+const MAX_TABLE: usize = 1 << 24;
+
+
+/// Merges the contents of a file changed by both sides of a merge.
+pub trait MergeDriver: Send + Sync {
+    /// Merge `ours` and `theirs`, which were both derived from `base`, or return `None` if they
+    /// conflict. A file added by both sides has an empty base.
+    fn merge(&self, base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>>;
+}
+
+
+/// Of three versions of something, the one a merge takes: whichever side changed it, or either if
+/// both made the same change. `None` if both changed it differently.
+fn take<T: PartialEq>(base: T, ours: T, theirs: T) -> Option<T> {
+    if ours == theirs || base == theirs {
+        Some(ours)
+    } else if base == ours {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
+
+/// Whether `bytes` look like binary data rather than text.
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(8000).any(|&byte| byte == 0)
+}
+
+
+/// Split `bytes` into lines, each keeping its terminator.
+fn lines(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        if byte == b'\n' {
+            lines.push(&bytes[start..i + 1]);
+            start = i + 1;
+        }
+    }
+
+    if start < bytes.len() {
+        lines.push(&bytes[start..]);
+    }
+
+    lines
+}
+
+
+/// For each line of `a`, the line of `b` it is matched with by a longest common subsequence, if
+/// any. `None` if the lines which differ are too many to match.
+fn matches(a: &[&[u8]], b: &[&[u8]]) -> Option<Vec<Option<usize>>> {
+    // A common prefix and suffix are matched directly, which keeps the table small for the usual
+    // case of a few lines changed in a long file.
+    let prefix = a.iter().zip(b).take_while(|&(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|&(x, y)| x == y)
+        .count();
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+
+    if a_mid.len().saturating_mul(b_mid.len()) > MAX_TABLE {
+        return None;
+    }
+
+    // `lengths[i * width + j]` is the length of the longest common subsequence of `a_mid[i..]` and
+    // `b_mid[j..]`.
+    let width = b_mid.len() + 1;
+    let mut lengths = vec![0u32; (a_mid.len() + 1) * width];
+    for i in (0..a_mid.len()).rev() {
+        for j in (0..b_mid.len()).rev() {
+            lengths[i * width + j] = if a_mid[i] == b_mid[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                cmp::max(lengths[(i + 1) * width + j], lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut matched = vec![None; a.len()];
+    for i in 0..prefix {
+        matched[i] = Some(i);
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < a_mid.len() && j < b_mid.len() {
+        if a_mid[i] == b_mid[j] {
+            matched[prefix + i] = Some(prefix + j);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    for k in 0..suffix {
+        matched[a.len() - suffix + k] = Some(b.len() - suffix + k);
+    }
+
+    Some(matched)
+}
+
+
+/// Merges text line by line. See the module docs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Text;
+
+
+impl MergeDriver for Text {
+    fn merge(&self, base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+        if is_binary(base) || is_binary(ours) || is_binary(theirs) {
+            return None;
+        }
+
+        let (base, ours, theirs) = (lines(base), lines(ours), lines(theirs));
+        let to_ours = matches(&base, &ours)?;
+        let to_theirs = matches(&base, &theirs)?;
+
+        let mut merged = Vec::new();
+        let (mut i, mut j, mut k) = (0, 0, 0);
+        loop {
+            // Between two base lines both sides kept, each side's lines are its change to the
+            // base's lines, and the usual rule decides which change to take.
+            let stable = (i..base.len()).find(|&b| to_ours[b].is_some() && to_theirs[b].is_some());
+            let (i_end, j_end, k_end) = match stable {
+                Some(b) => (b, to_ours[b].unwrap(), to_theirs[b].unwrap()),
+                None => (base.len(), ours.len(), theirs.len()),
+            };
+
+            let chunk = take(&base[i..i_end], &ours[j..j_end], &theirs[k..k_end])?;
+            for line in chunk {
+                merged.extend_from_slice(line);
+            }
+
+            match stable {
+                Some(b) => {
+                    merged.extend_from_slice(base[b]);
+                    i = i_end + 1;
+                    j = j_end + 1;
+                    k = k_end + 1;
+                }
+                None => return Some(merged),
+            }
+        }
+    }
+}
+
+
+/// Never merges anything, for files which only a person can reconcile.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Binary;
+
+
+impl MergeDriver for Binary {
+    fn merge(&self, _base: &[u8], _ours: &[u8], _theirs: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+
+/// Merges JSON documents, key by key within objects. The result is pretty-printed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+
+impl Json {
+    /// Merge three versions of a value, `None` meaning that there was nothing there. The outer
+    /// `None` is a conflict.
+    fn merge_values(
+        base: Option<&Value>,
+        ours: Option<&Value>,
+        theirs: Option<&Value>,
+    ) -> Option<Option<Value>> {
+        if let Some(merged) = take(base, ours, theirs) {
+            return Some(merged.cloned());
+        }
+
+        match (ours, theirs) {
+            (Some(&Value::Object(ref ours)), Some(&Value::Object(ref theirs))) => {
+                let empty = Map::new();
+                let base = match base {
+                    Some(&Value::Object(ref base)) => base,
+                    _ => &empty,
+                };
+
+                let keys = base.keys()
+                    .chain(ours.keys())
+                    .chain(theirs.keys())
+                    .collect::<BTreeSet<_>>();
+                let mut merged = Map::new();
+                for key in keys {
+                    let value = Json::merge_values(base.get(key), ours.get(key), theirs.get(key))?;
+                    if let Some(value) = value {
+                        merged.insert(key.clone(), value);
+                    }
+                }
+
+                Some(Some(Value::Object(merged)))
+            }
+            _ => None,
+        }
+    }
+}
+
+
+impl MergeDriver for Json {
+    fn merge(&self, base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+        let base = if base.is_empty() {
+            None
+        } else {
+            Some(serde_json::from_slice::<Value>(base).ok()?)
+        };
+        let ours = serde_json::from_slice::<Value>(ours).ok()?;
+        let theirs = serde_json::from_slice::<Value>(theirs).ok()?;
+
+        let merged = Json::merge_values(base.as_ref(), Some(&ours), Some(&theirs))??;
+        let mut bytes = serde_json::to_vec_pretty(&merged).ok()?;
+        bytes.push(b'\n');
+
+        Some(bytes)
+    }
+}
+
+
+/// Merges CSV files row by row, identifying rows by their first field. The header is merged like
+/// any other row, and merged rows keep our order, followed by any rows only they added. Files
+/// with quoted line breaks or duplicate keys conflict.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Csv;
+
+
+impl Csv {
+    /// The first field of a row, with any quotes left on.
+    fn key(row: &[u8]) -> &[u8] {
+        let mut quoted = false;
+        for (i, &byte) in row.iter().enumerate() {
+            match byte {
+                b'"' => quoted = !quoted,
+                b',' | b'\r' | b'\n' if !quoted => return &row[..i],
+                _ => {}
+            }
+        }
+
+        row
+    }
+
+    /// The header and rows of a file, each row along with its key.
+    fn rows(bytes: &[u8]) -> Option<(Option<&[u8]>, Vec<(&[u8], &[u8])>)> {
+        if is_binary(bytes) {
+            return None;
+        }
+
+        let mut lines = lines(bytes).into_iter();
+        let header = lines.next();
+        let mut keys = BTreeSet::new();
+        let mut rows = Vec::new();
+        for row in lines {
+            let quotes = row.iter().filter(|&&byte| byte == b'"').count();
+            if quotes % 2 != 0 || !keys.insert(Csv::key(row)) {
+                return None;
+            }
+
+            rows.push((Csv::key(row), row));
+        }
+
+        Some((header, rows))
+    }
+}
+
+
+impl MergeDriver for Csv {
+    fn merge(&self, base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+        let (base_header, base_rows) = Csv::rows(base)?;
+        let (our_header, our_rows) = Csv::rows(ours)?;
+        let (their_header, their_rows) = Csv::rows(theirs)?;
+
+        let base_map = base_rows.iter().cloned().collect::<HashMap<_, _>>();
+        let our_map = our_rows.iter().cloned().collect::<HashMap<_, _>>();
+        let their_map = their_rows.iter().cloned().collect::<HashMap<_, _>>();
+
+        let mut merged_rows = Vec::new();
+        merged_rows.extend(take(base_header, our_header, their_header)?);
+
+        let keys = our_rows.iter().chain(their_rows.iter().filter(|&&(key, _)| {
+            !our_map.contains_key(key)
+        }));
+        for &(key, _) in keys {
+            let row = take(base_map.get(key), our_map.get(key), their_map.get(key))?;
+            merged_rows.extend(row.cloned());
+        }
+
+        let mut merged = Vec::new();
+        for row in merged_rows {
+            // A last row without a line break may no longer be last.
+            if !merged.is_empty() && merged.last() != Some(&b'\n') {
+                merged.push(b'\n');
+            }
+            merged.extend_from_slice(row);
+        }
+
+        Some(merged)
+    }
+}
+
+
+/// The merge drivers of a repository, chosen by path.
+#[derive(Clone)]
+pub struct MergeDriverSet {
+    default: Arc<MergeDriver>,
+    by_pattern: Vec<(GlobMatcher, Arc<MergeDriver>)>,
+}
+
+
+impl Default for MergeDriverSet {
+    fn default() -> Self {
+        let mut drivers = MergeDriverSet {
+            default: Arc::new(Text),
+            by_pattern: Vec::new(),
+        };
+
+        drivers.register(Glob::new("*.json").unwrap(), Json);
+        drivers.register(Glob::new("*.csv").unwrap(), Csv);
+
+        drivers
+    }
+}
+
+
+impl fmt::Debug for MergeDriverSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MergeDriverSet {{ {} patterns }}", self.by_pattern.len())
+    }
+}
+
+
+impl MergeDriverSet {
+    /// Merge files matching `pattern`, relative to the root of the worktree, with `driver`. Of the
+    /// patterns matching a file, the last registered wins.
+    pub fn register<D: MergeDriver + 'static>(&mut self, pattern: Glob, driver: D) {
+        self.by_pattern.push((pattern.compile_matcher(), Arc::new(driver)));
+    }
+
+    /// Merge files which match no registered pattern with `driver`.
+    pub fn set_default<D: MergeDriver + 'static>(&mut self, driver: D) {
+        self.default = Arc::new(driver);
+    }
+
+    /// The driver for the file at `path`, relative to the root of the worktree.
+    pub fn for_path<P: AsRef<Path>>(&self, path: P) -> Arc<MergeDriver> {
+        self.by_pattern
+            .iter()
+            .rev()
+            .find(|&&(ref matcher, _)| matcher.is_match(path.as_ref()))
+            .map(|&(_, ref driver)| driver.clone())
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn merge(path: &str, base: &str, ours: &str, theirs: &str) -> Option<String> {
+        let driver = MergeDriverSet::default().for_path(path);
+        let merged = driver.merge(base.as_bytes(), ours.as_bytes(), theirs.as_bytes())?;
+
+        Some(String::from_utf8(merged).unwrap())
+    }
+
+    #[test]
+    fn merges_by_file_type() {
+        let base = "a\nb\nc\nd\n";
+        assert_eq!(
+            merge("notes.txt", base, "A\nb\nc\nd\n", "a\nb\nc\nD\ne\n").unwrap(),
+            "A\nb\nc\nD\ne\n"
+        );
+        assert_eq!(merge("notes.txt", base, "a\nB\nc\nd\n", "a\nX\nc\nd\n"), None);
+        assert_eq!(merge("notes.txt", "", "a\0", "b\0"), None);
+
+        let merged = merge(
+            "config.json",
+            r#"{"a": 1, "b": {"c": 2, "d": 3}}"#,
+            r#"{"a": 2, "b": {"c": 2, "d": 3}}"#,
+            r#"{"a": 1, "b": {"c": 2, "d": 4}, "e": 5}"#,
+        ).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&merged).unwrap(),
+            serde_json::from_str::<Value>(r#"{"a": 2, "b": {"c": 2, "d": 4}, "e": 5}"#).unwrap()
+        );
+        assert_eq!(merge("config.json", r#"{"a": 1}"#, r#"{"a": 2}"#, r#"{"a": 3}"#), None);
+
+        assert_eq!(
+            merge(
+                "samples.csv",
+                "id,value\n1,a\n2,b\n3,c\n",
+                "id,value\n1,A\n3,c\n",
+                "id,value\n1,a\n2,b\n3,C\n4,d\n",
+            ).unwrap(),
+            "id,value\n1,A\n3,C\n4,d\n"
+        );
+        assert_eq!(merge("samples.csv", "id\n1,a\n", "id\n1,b\n", "id\n1,c\n"), None);
+    }
+}
@@ -0,0 +1,305 @@
+//! # `merge` - three-way merges of subtrees.
+//!
+//! Merging works entry by entry: where only one side changed an entry relative to the base, that
+//! side's version is taken; where both sides made the same change, it is taken once. Where both
+//! sides are directories, the merge recurses into them. Where both sides changed a file, its
+//! contents are handed to the merge driver for its path, which may merge them; see the `driver`
+//! module. Anything else is a conflict.
+//!
+//! Conflicting paths take "their" version in the merged tree, and are reported along with what the
+//! base and each side had there, so that the caller can ask for them to be resolved; see the
+//! `conflict` module. Merged subtrees are written straight to the store, so that
+//! they can be read back as soon as the merge finishes.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use futures::prelude::*;
+
+use arc_slice;
+use checkout;
+use errors::*;
+use history::driver::{MAX_DRIVER_SIZE, MergeDriverSet};
+use marshal::{ObjectHash, Object, DataObject, SmallObject, SubtreeEntry, SubtreeObject,
+              serialize_and_hash_with};
+use marshal::canonical::Version;
+use store::ObjectStore;
+
+
+/// What the base and each side of a merge had at a path. `None` means that there was nothing at
+/// the path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sides {
+    pub base: Option<SubtreeEntry>,
+    pub ours: Option<SubtreeEntry>,
+    pub theirs: Option<SubtreeEntry>,
+}
+
+
+/// The result of a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merge {
+    /// The merged subtree, with conflicting paths taking their version.
+    pub subtree: ObjectHash,
+
+    /// Every path which both sides changed differently, in order.
+    pub conflicts: Vec<PathBuf>,
+
+    /// What the base and each side had at each conflicting path.
+    pub sides: BTreeMap<PathBuf, Sides>,
+}
+
+
+impl Merge {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+
+/// Merge the subtrees `ours` and `theirs`, which were both derived from `base_opt`, merging files
+/// changed on both sides with `drivers`. A missing base is treated as an empty subtree.
+pub fn merge<S: ObjectStore>(
+    store: &S,
+    version: Version,
+    drivers: &MergeDriverSet,
+    base_opt: Option<ObjectHash>,
+    ours: ObjectHash,
+    theirs: ObjectHash,
+) -> Box<Future<Item = Merge, Error = Error> + Send> {
+    let result = merge_subtrees(
+        store.clone(),
+        version,
+        drivers.clone(),
+        PathBuf::new(),
+        base_opt,
+        ours,
+        theirs,
+    ).map(|(subtree, conflicted)| {
+        let conflicts = conflicted.iter().map(|&(ref path, _)| path.clone()).collect();
+        let sides = conflicted.into_iter().collect();
+
+        Merge {
+            subtree,
+            conflicts,
+            sides,
+        }
+    });
+
+    Box::new(result)
+}
+
+
+/// Write an object to a store, returning its hash once it has been written.
+pub fn write<S: ObjectStore>(
+    store: &S,
+    object: Object,
+    version: Version,
+) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+    let hashed = serialize_and_hash_with(&object, version);
+    let hash = *hashed.as_hash();
+
+    Box::new(store.write_object(hashed).map(move |_| hash))
+}
+
+
+fn read_entries<S: ObjectStore>(
+    store: &S,
+    hash_opt: Option<ObjectHash>,
+) -> Box<Future<Item = BTreeMap<OsString, SubtreeEntry>, Error = Error> + Send> {
+    match hash_opt {
+        Some(hash) => {
+            Box::new(store.read_object(hash).and_then(move |object| match object {
+                Object::Subtree(subtree_object) => Ok(subtree_object.entries),
+                _ => bail!(ErrorKind::ObjectNotASubtree(hash)),
+            }))
+        }
+        None => Box::new(Ok(BTreeMap::new()).into_future()),
+    }
+}
+
+
+/// Read the whole contents of a file.
+fn read_file<S: ObjectStore>(
+    store: &S,
+    hash: ObjectHash,
+) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+    let bytes = checkout::data_chunks(store, hash).fold(Vec::new(), |mut bytes, chunk| {
+        bytes.extend_from_slice(&chunk);
+        Ok::<_, Error>(bytes)
+    });
+
+    Box::new(bytes)
+}
+
+
+/// Merge the contents of a file changed by both sides with the driver for its path, writing the
+/// result to the store. `None` if the entries are not all files, if any is too large, or if the
+/// driver finds a conflict. The result is executable if our version is.
+fn merge_files<S: ObjectStore>(
+    store: &S,
+    version: Version,
+    drivers: &MergeDriverSet,
+    path: &Path,
+    base: Option<&SubtreeEntry>,
+    ours: Option<&SubtreeEntry>,
+    theirs: Option<&SubtreeEntry>,
+) -> Box<Future<Item = Option<SubtreeEntry>, Error = Error> + Send> {
+    let file = |entry: &SubtreeEntry| match entry.unannotated() {
+        SubtreeEntry::File(hash, size) |
+        SubtreeEntry::Executable(hash, size) if size <= MAX_DRIVER_SIZE => Some(hash),
+        _ => None,
+    };
+
+    let (our_hash, their_hash) = match (ours.and_then(&file), theirs.and_then(&file)) {
+        (Some(our_hash), Some(their_hash)) => (our_hash, their_hash),
+        _ => return Box::new(Ok(None).into_future()),
+    };
+    let base_hash = match base {
+        Some(base) => {
+            match file(base) {
+                Some(base_hash) => Some(base_hash),
+                None => return Box::new(Ok(None).into_future()),
+            }
+        }
+        None => None,
+    };
+    let executable = match ours.map(SubtreeEntry::unannotated) {
+        Some(&SubtreeEntry::Executable(..)) => true,
+        _ => false,
+    };
+
+    let store = store.clone();
+    let driver = drivers.for_path(path);
+
+    let result = {
+        async_block! {
+            let base_bytes = match base_hash {
+                Some(base_hash) => await!(read_file(&store, base_hash))?,
+                None => Vec::new(),
+            };
+            let our_bytes = await!(read_file(&store, our_hash))?;
+            let their_bytes = await!(read_file(&store, their_hash))?;
+
+            let merged = match driver.merge(&base_bytes, &our_bytes, &their_bytes) {
+                Some(merged) => merged,
+                None => return Ok(None),
+            };
+
+            let size = merged.len() as u64;
+            let small = SmallObject { chunk: arc_slice::owned(merged) };
+            let hash = await!(write(&store, Object::Data(DataObject::Small(small)), version))?;
+
+            if executable {
+                Ok(Some(SubtreeEntry::Executable(hash, size)))
+            } else {
+                Ok(Some(SubtreeEntry::File(hash, size)))
+            }
+        }
+    };
+
+    Box::new(result)
+}
+
+
+// Boxed due to recursion.
+fn merge_subtrees<S: ObjectStore>(
+    store: S,
+    version: Version,
+    drivers: MergeDriverSet,
+    path: PathBuf,
+    base_opt: Option<ObjectHash>,
+    ours: ObjectHash,
+    theirs: ObjectHash,
+) -> Box<Future<Item = (ObjectHash, Vec<(PathBuf, Sides)>), Error = Error> + Send> {
+    let result = {
+        async_block! {
+            let mut base_entries = await!(read_entries(&store, base_opt))?;
+            let mut our_entries = await!(read_entries(&store, Some(ours)))?;
+            let mut their_entries = await!(read_entries(&store, Some(theirs)))?;
+
+            let names = base_entries
+                .keys()
+                .chain(our_entries.keys())
+                .chain(their_entries.keys())
+                .cloned()
+                .collect::<BTreeSet<_>>();
+
+            let mut entries = BTreeMap::new();
+            let mut conflicts = Vec::new();
+
+            for name in names {
+                let base = base_entries.remove(&name);
+                let ours = our_entries.remove(&name);
+                let theirs = their_entries.remove(&name);
+                let child_path = path.join(&name);
+
+                let merged = if ours == theirs || base == theirs {
+                    ours
+                } else if base == ours {
+                    theirs
+                } else {
+                    use marshal::SubtreeEntry::Subtree;
+
+                    match (ours, theirs) {
+                        (Some(Subtree(ours)), Some(Subtree(theirs))) => {
+                            let base_subtree = match base {
+                                Some(SubtreeEntry::Subtree(base)) => Some(base),
+                                _ => None,
+                            };
+                            let (subtree, child_conflicts) = await!(merge_subtrees(
+                                store.clone(),
+                                version,
+                                drivers.clone(),
+                                child_path,
+                                base_subtree,
+                                ours,
+                                theirs,
+                            ))?;
+                            conflicts.extend(child_conflicts);
+
+                            Some(SubtreeEntry::Subtree(subtree))
+                        }
+                        (ours, theirs) => {
+                            let merged_file = await!(merge_files(
+                                &store,
+                                version,
+                                &drivers,
+                                &child_path,
+                                base.as_ref(),
+                                ours.as_ref(),
+                                theirs.as_ref(),
+                            ))?;
+
+                            match merged_file {
+                                Some(entry) => Some(entry),
+                                None => {
+                                    let sides = Sides {
+                                        base,
+                                        ours,
+                                        theirs: theirs.clone(),
+                                    };
+                                    conflicts.push((child_path, sides));
+
+                                    theirs
+                                }
+                            }
+                        }
+                    }
+                };
+
+                if let Some(entry) = merged {
+                    entries.insert(name, entry);
+                }
+            }
+
+            let subtree_object = Object::Subtree(SubtreeObject { entries });
+            let subtree = await!(write(&store, subtree_object, version))?;
+
+            Ok((subtree, conflicts))
+        }
+    };
+
+    Box::new(result)
+}
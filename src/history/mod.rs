@@ -0,0 +1,199 @@
+//! # `history` - walking and rewriting commit history.
+
+pub mod bisect;
+pub mod blame;
+pub mod conflict;
+pub mod driver;
+pub mod merge;
+pub mod rebase;
+pub mod stash;
+pub mod subtree;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::Path;
+
+use futures::prelude::*;
+
+use errors::*;
+use marshal::{ObjectHash, Object, CommitObject, SubtreeEntry};
+use shallow::Shallow;
+use store::ObjectStore;
+
+
+/// Read every commit reachable from `head`, returning them in topological order: every commit
+/// appears after all of its parents.
+pub fn ancestry<S: ObjectStore>(
+    store: &S,
+    head: ObjectHash,
+) -> Box<Future<Item = Vec<(ObjectHash, CommitObject)>, Error = Error> + Send> {
+    shallow_ancestry(store, head, &Shallow::default())
+}
+
+
+/// As `ancestry`, but treating the commits of a shallow boundary as having no parents, since
+/// their parents are not held locally.
+pub fn shallow_ancestry<S: ObjectStore>(
+    store: &S,
+    head: ObjectHash,
+    shallow: &Shallow,
+) -> Box<Future<Item = Vec<(ObjectHash, CommitObject)>, Error = Error> + Send> {
+    let store = store.clone();
+    let shallow = shallow.clone();
+
+    let result = {
+        async_block! {
+            let mut commits = HashMap::new();
+            let mut order = Vec::new();
+
+            // Each commit is pushed twice: once to be read, and once more (with the flag set) to
+            // be emitted after all of its parents have been.
+            let mut stack = vec![(head, false)];
+
+            while let Some((hash, expanded)) = stack.pop() {
+                if expanded {
+                    order.push(hash);
+                    continue;
+                }
+
+                if commits.contains_key(&hash) {
+                    continue;
+                }
+
+                let commit_object = match await!(store.read_object(hash))? {
+                    Object::Commit(commit_object) => commit_object,
+                    _ => bail!(ErrorKind::ObjectNotACommit(hash)),
+                };
+
+                stack.push((hash, true));
+                for &parent in shallow.parents(&hash, &commit_object).iter().rev() {
+                    if !commits.contains_key(&parent) {
+                        stack.push((parent, false));
+                    }
+                }
+
+                commits.insert(hash, commit_object);
+            }
+
+            let ordered = order
+                .into_iter()
+                .map(|hash| {
+                    let commit_object = commits.remove(&hash).unwrap();
+                    (hash, commit_object)
+                })
+                .collect();
+
+            Ok(ordered)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// The most recent commit which both `a` and `b` descend from, counting each as descending from
+/// itself, or `None` if their histories have no commit in common. Where more than one such commit
+/// has no common descendant, the one which comes last in `a`'s topological order is taken.
+pub fn merge_base<S: ObjectStore>(
+    store: &S,
+    a: ObjectHash,
+    b: ObjectHash,
+    shallow: &Shallow,
+) -> Box<Future<Item = Option<ObjectHash>, Error = Error> + Send> {
+    let result = both_ancestries(store, a, b, shallow).map(|(a_hashes, b_hashes)| {
+        let b_hashes = b_hashes.into_iter().collect::<HashSet<_>>();
+        a_hashes.into_iter().rev().find(|hash| b_hashes.contains(hash))
+    });
+
+    Box::new(result)
+}
+
+
+/// How far `a` and `b` have diverged since their merge base: the number of commits reachable from
+/// `a` but not from `b`, and the number reachable from `b` but not from `a`. A branch which is
+/// merely behind another is `(0, n)` from it, and one merely ahead `(n, 0)`.
+pub fn divergence<S: ObjectStore>(
+    store: &S,
+    a: ObjectHash,
+    b: ObjectHash,
+    shallow: &Shallow,
+) -> Box<Future<Item = (usize, usize), Error = Error> + Send> {
+    let result = both_ancestries(store, a, b, shallow).map(|(a_hashes, b_hashes)| {
+        let a_hashes = a_hashes.into_iter().collect::<HashSet<_>>();
+        let b_hashes = b_hashes.into_iter().collect::<HashSet<_>>();
+
+        (
+            a_hashes.difference(&b_hashes).count(),
+            b_hashes.difference(&a_hashes).count(),
+        )
+    });
+
+    Box::new(result)
+}
+
+
+/// The hashes of the commits reachable from `a` and from `b`, each in topological order.
+fn both_ancestries<S: ObjectStore>(
+    store: &S,
+    a: ObjectHash,
+    b: ObjectHash,
+    shallow: &Shallow,
+) -> Box<Future<Item = (Vec<ObjectHash>, Vec<ObjectHash>), Error = Error> + Send> {
+    let hashes = |commits: Vec<(ObjectHash, CommitObject)>| {
+        commits.into_iter().map(|(hash, _)| hash).collect::<Vec<_>>()
+    };
+    let result = shallow_ancestry(store, a, shallow)
+        .join(shallow_ancestry(store, b, shallow))
+        .map(move |(a_commits, b_commits)| (hashes(a_commits), hashes(b_commits)));
+
+    Box::new(result)
+}
+
+
+/// Find the entry at `path` within the subtree with the given hash, if there is one. The empty
+/// path resolves to the subtree itself.
+pub fn resolve<S: ObjectStore, P: AsRef<Path>>(
+    store: &S,
+    subtree_hash: ObjectHash,
+    path: P,
+) -> Box<Future<Item = Option<SubtreeEntry>, Error = Error> + Send> {
+    let store = store.clone();
+    let components = path.as_ref()
+        .iter()
+        .map(OsStr::to_owned)
+        .collect::<Vec<_>>();
+
+    let result = {
+        async_block! {
+            let mut current = SubtreeEntry::Subtree(subtree_hash);
+
+            for component in components {
+                let hash = match current {
+                    SubtreeEntry::Subtree(hash) => hash,
+                    _ => return Ok(None),
+                };
+
+                let mut subtree_object = match await!(store.read_object(hash))? {
+                    Object::Subtree(subtree_object) => subtree_object,
+                    _ => bail!(ErrorKind::ObjectNotASubtree(hash)),
+                };
+
+                current = match subtree_object.entries.remove(&component) {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+            }
+
+            Ok(Some(current))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Remove duplicate hashes from a list of parents, preserving the order of first appearance.
+fn dedup_parents(parents: Vec<ObjectHash>) -> Vec<ObjectHash> {
+    let mut seen = HashSet::new();
+    parents.into_iter().filter(|hash| seen.insert(*hash)).collect()
+}
@@ -0,0 +1,268 @@
+//! # `rebase` - replay a branch's commits onto a new base.
+//!
+//! Rebasing finds every commit reachable from the branch but not from the new base, and replays
+//! each one's changes - the difference between it and its first parent - on top of the new base
+//! in turn, using a three-way merge. Merge commits are replayed like any other, against their
+//! first parent, so the rewritten history is linear. Commits whose changes are already present
+//! are dropped.
+//!
+//! The branch itself is not touched until every commit has been replayed, at which point the
+//! caller swaps it to the rewritten head. If a commit cannot be replayed cleanly, rebasing stops
+//! with a `Conflict`: a provisional commit holding the merged tree, in which conflicting paths
+//! take the replayed commit's version. Once those paths have been fixed up, `resolve` records the
+//! fixed tree in place of the conflicted commit and rebasing carries on. The state of a rebase in
+//! progress is saved between commands in `.attaca/rebase.bin`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use bincode;
+use futures::prelude::*;
+
+use errors::*;
+use history::ancestry;
+use history::driver::MergeDriverSet;
+use history::merge::{self, Sides};
+use marshal::{ObjectHash, Object, CommitObject};
+use marshal::canonical::Version;
+use repository::Paths;
+use store::ObjectStore;
+
+
+/// A commit which could not be replayed cleanly.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    /// The original commit being replayed.
+    pub commit: ObjectHash,
+
+    /// A commit on top of the rewritten history holding the merged tree.
+    pub provisional: ObjectHash,
+
+    /// Paths changed differently by the new base and by the commit.
+    pub paths: Vec<PathBuf>,
+
+    /// What the base, the new base ("ours") and the commit ("theirs") had at each of `paths`.
+    pub sides: BTreeMap<PathBuf, Sides>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rebase {
+    /// The branch being rebased.
+    pub branch: String,
+
+    /// Where the branch pointed when rebasing started.
+    pub original: ObjectHash,
+
+    /// The head of the rewritten history so far.
+    pub head: ObjectHash,
+
+    /// Commits still to be replayed, oldest last.
+    remaining: Vec<ObjectHash>,
+
+    /// The commit rebasing is stopped at, if any.
+    pub conflict: Option<Conflict>,
+}
+
+
+impl Rebase {
+    /// Plan a rebase of `branch`, currently at `original`, onto the commit `onto`. Nothing is
+    /// replayed until `run` is called.
+    pub fn start<S: ObjectStore>(
+        store: &S,
+        branch: String,
+        original: ObjectHash,
+        onto: ObjectHash,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let result = ancestry(store, original).join(ancestry(store, onto)).map(
+            move |(branch_commits, onto_commits)| {
+                let upstream = onto_commits
+                    .into_iter()
+                    .map(|(hash, _)| hash)
+                    .collect::<HashSet<_>>();
+                let remaining = branch_commits
+                    .into_iter()
+                    .rev()
+                    .map(|(hash, _)| hash)
+                    .filter(|hash| !upstream.contains(hash))
+                    .collect();
+
+                Rebase {
+                    branch,
+                    original,
+                    head: onto,
+                    remaining,
+                    conflict: None,
+                }
+            },
+        );
+
+        Box::new(result)
+    }
+
+    /// Whether every commit has been replayed.
+    pub fn is_done(&self) -> bool {
+        self.remaining.is_empty() && self.conflict.is_none()
+    }
+
+    /// The number of commits which have yet to be replayed, including any conflicted one.
+    pub fn remaining(&self) -> usize {
+        self.remaining.len() + self.conflict.iter().count()
+    }
+
+    /// Replay commits until they have all been replayed or one conflicts, merging files changed
+    /// on both sides with `drivers`.
+    pub fn run<S: ObjectStore>(
+        mut self,
+        store: &S,
+        version: Version,
+        drivers: &MergeDriverSet,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let store = store.clone();
+        let drivers = drivers.clone();
+
+        let result = {
+            async_block! {
+                if self.conflict.is_some() {
+                    return Ok(self);
+                }
+
+                while let Some(hash) = self.remaining.pop() {
+                    let commit = await!(read_commit(&store, hash))?;
+                    let head = await!(read_commit(&store, self.head))?;
+                    let base_opt = match commit.parents.first() {
+                        Some(&parent) => Some(await!(read_commit(&store, parent))?.subtree),
+                        None => None,
+                    };
+
+                    let merged = await!(merge::merge(
+                        &store,
+                        version,
+                        &drivers,
+                        base_opt,
+                        head.subtree,
+                        commit.subtree,
+                    ))?;
+
+                    if !merged.is_clean() {
+                        let provisional = await!(replay(
+                            &store,
+                            version,
+                            commit,
+                            self.head,
+                            merged.subtree,
+                        ))?;
+
+                        self.conflict = Some(Conflict {
+                            commit: hash,
+                            provisional,
+                            paths: merged.conflicts,
+                            sides: merged.sides,
+                        });
+
+                        break;
+                    }
+
+                    // Commits whose changes are already upstream are dropped.
+                    if merged.subtree != head.subtree {
+                        self.head =
+                            await!(replay(&store, version, commit, self.head, merged.subtree))?;
+                    }
+                }
+
+                Ok(self)
+            }
+        };
+
+        Box::new(result)
+    }
+
+    /// Record `subtree` as the resolution of the conflicted commit, and carry on replaying.
+    pub fn resolve<S: ObjectStore>(
+        mut self,
+        store: &S,
+        version: Version,
+        drivers: &MergeDriverSet,
+        subtree: ObjectHash,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let store = store.clone();
+        let drivers = drivers.clone();
+
+        let result = {
+            async_block! {
+                let conflict = match self.conflict.take() {
+                    Some(conflict) => conflict,
+                    None => bail!(ErrorKind::NoRebaseConflict),
+                };
+
+                let commit = await!(read_commit(&store, conflict.commit))?;
+                self.head = await!(replay(&store, version, commit, self.head, subtree))?;
+
+                await!(self.run(&store, version, &drivers))
+            }
+        };
+
+        Box::new(result)
+    }
+
+    /// Load the rebase in progress, if there is one.
+    pub fn open(paths: &Paths) -> Result<Option<Self>> {
+        if !paths.rebase.exists() {
+            return Ok(None);
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&paths.rebase)?.read_to_end(&mut bytes)?;
+
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        let bytes = bincode::serialize(self, bincode::Infinite)?;
+        File::create(&paths.rebase)?.write_all(&bytes)?;
+
+        Ok(())
+    }
+
+    /// Forget the rebase in progress, if there is one.
+    pub fn clear(paths: &Paths) -> Result<()> {
+        if paths.rebase.exists() {
+            fs::remove_file(&paths.rebase)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+fn read_commit<S: ObjectStore>(
+    store: &S,
+    hash: ObjectHash,
+) -> Box<Future<Item = CommitObject, Error = Error> + Send> {
+    Box::new(store.read_object(hash).and_then(move |object| match object {
+        Object::Commit(commit_object) => Ok(commit_object),
+        _ => bail!(ErrorKind::ObjectNotACommit(hash)),
+    }))
+}
+
+
+/// Write a copy of `commit` with the given subtree on top of `parent`.
+fn replay<S: ObjectStore>(
+    store: &S,
+    version: Version,
+    commit: CommitObject,
+    parent: ObjectHash,
+    subtree: ObjectHash,
+) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+    let replayed = CommitObject {
+        subtree,
+        parents: vec![parent],
+        // Rewritten commits no longer match what was signed.
+        signature: None,
+        ..commit
+    };
+
+    merge::write(store, Object::Commit(replayed), version)
+}
@@ -0,0 +1,232 @@
+//! # `stash` - set uncommitted changes aside, and bring them back later.
+//!
+//! Saving a stash records the tracked files of the worktree in an ordinary commit whose only
+//! parent is the HEAD, pushes that commit onto a stack kept in `.attaca/stash.bin`, and then puts
+//! the worktree back as the HEAD has it: files the stash changed are checked out again, and files
+//! only the stash has are removed.
+//!
+//! Popping a stash applies its changes - the difference between the stash commit and its parent -
+//! to whatever the HEAD is now, using the same three-way merge as rebasing, and checks out the
+//! result. A stash which does not apply cleanly is left on the stack, and its conflicting paths
+//! take the stashed version. Changes made to the worktree since the HEAD and also touched by the
+//! stash are overwritten.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use bincode;
+use chrono::prelude::*;
+use futures::prelude::*;
+
+use checkout::{self, CheckoutOptions};
+use errors::*;
+use history::merge::{self, Merge};
+use marshal::{ObjectHash, CommitObject};
+use repository::{Paths, Repository};
+use store::ObjectStore;
+
+
+/// The saved stashes, oldest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StashStack {
+    entries: Vec<ObjectHash>,
+}
+
+
+impl StashStack {
+    pub fn open(paths: &Paths) -> Result<Self> {
+        if !paths.stash.exists() {
+            return Ok(StashStack::default());
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&paths.stash)?.read_to_end(&mut bytes)?;
+
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        if self.entries.is_empty() {
+            if paths.stash.exists() {
+                fs::remove_file(&paths.stash)?;
+            }
+
+            return Ok(());
+        }
+
+        let bytes = bincode::serialize(self, bincode::Infinite)?;
+        File::create(&paths.stash)?.write_all(&bytes)?;
+
+        Ok(())
+    }
+
+    /// The most recently saved stash, if any.
+    pub fn top(&self) -> Option<ObjectHash> {
+        self.entries.last().cloned()
+    }
+
+    /// The saved stashes, most recent first.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = ObjectHash> + 'a {
+        self.entries.iter().rev().cloned()
+    }
+
+    pub fn push(&mut self, commit_hash: ObjectHash) {
+        self.entries.push(commit_hash);
+    }
+
+    pub fn pop(&mut self) -> Option<ObjectHash> {
+        self.entries.pop()
+    }
+}
+
+
+/// Replace the files of the subtree `from`, which is checked out at `target`, with those of the
+/// subtree `to`. Files which `to` does not have are removed.
+fn switch<S: ObjectStore>(
+    store: &S,
+    from: ObjectHash,
+    to: ObjectHash,
+    target: &Path,
+    options: &CheckoutOptions,
+) -> Result<()> {
+    let to_listing = checkout::checkout(store, to, target, options).wait()?;
+    let mut from_listing = checkout::walk(store, from).wait()?;
+    if let Some(ref pattern) = options.filter {
+        from_listing = from_listing.filter(pattern);
+    }
+
+    let kept = to_listing
+        .files
+        .iter()
+        .map(|&(ref path, _)| path.as_path())
+        .collect::<HashSet<_>>();
+    for &(ref path, _) in &from_listing.files {
+        let absolute_path = target.join(path);
+        if !kept.contains(path.as_path()) && absolute_path.symlink_metadata().is_ok() {
+            fs::remove_file(absolute_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Stash the tracked files of the worktree and return it to the HEAD. Returns the hash of the
+/// stash commit, or `None` if there was nothing to stash.
+pub fn stash_save(repository: &mut Repository, message: String) -> Result<Option<ObjectHash>> {
+    let head = repository.refs.resolve("HEAD")?;
+    let mut options = CheckoutOptions::default();
+    options.filter = repository.index.sparse().map(|sparse| sparse.globset().clone());
+    options.filters = repository.filters.clone();
+
+    repository.index.update()?;
+
+    // The stash commit must be flushed to the store before it can be read back.
+    let stash_hash = {
+        let ctx = repository.local(())?;
+        let author = ctx.config.author();
+        let committer = ctx.config.committer();
+        let stash_hash = ctx.write_commit(
+            None,
+            None,
+            vec![head],
+            message,
+            Utc::now(),
+            author,
+            committer,
+        ).wait()?;
+        ctx.close().wait()?;
+
+        stash_hash
+    };
+
+    {
+        let ctx = repository.local(())?;
+        let stash_subtree = ctx.read_commit(stash_hash).wait()?.subtree;
+        let head_subtree = ctx.read_commit(head).wait()?.subtree;
+
+        if stash_subtree == head_subtree {
+            ctx.close().wait()?;
+            return Ok(None);
+        }
+
+        let base = ctx.paths.base.clone();
+        switch(ctx.store(), stash_subtree, head_subtree, &base, &options)?;
+        ctx.close().wait()?;
+    }
+
+    let mut stack = StashStack::open(&repository.paths)?;
+    stack.push(stash_hash);
+    stack.save(&repository.paths)?;
+
+    Ok(Some(stash_hash))
+}
+
+
+/// Apply the most recent stash to the HEAD and check out the result, dropping the stash if it
+/// applied cleanly.
+pub fn stash_pop(repository: &mut Repository) -> Result<Merge> {
+    let mut stack = StashStack::open(&repository.paths)?;
+    let stash_hash = match stack.top() {
+        Some(stash_hash) => stash_hash,
+        None => bail!(ErrorKind::EmptyStash),
+    };
+    let head = repository.refs.resolve("HEAD")?;
+    let version = repository.object_version;
+    let mut options = CheckoutOptions::default();
+    options.filter = repository.index.sparse().map(|sparse| sparse.globset().clone());
+    options.filters = repository.filters.clone();
+
+    let merged = {
+        let ctx = repository.local(())?;
+        let stash_commit = ctx.read_commit(stash_hash).wait()?;
+        let base_opt = match stash_commit.parents.first() {
+            Some(&parent) => Some(ctx.read_commit(parent).wait()?.subtree),
+            None => None,
+        };
+        let head_subtree = ctx.read_commit(head).wait()?.subtree;
+
+        let merged = merge::merge(
+            ctx.store(),
+            version,
+            &ctx.merge_drivers,
+            base_opt,
+            head_subtree,
+            stash_commit.subtree,
+        ).wait()?;
+
+        let base = ctx.paths.base.clone();
+        switch(ctx.store(), head_subtree, merged.subtree, &base, &options)?;
+        ctx.close().wait()?;
+
+        merged
+    };
+
+    if merged.is_clean() {
+        stack.pop();
+        stack.save(&repository.paths)?;
+    }
+
+    Ok(merged)
+}
+
+
+/// Every saved stash along with its commit, most recent first.
+pub fn stash_list(repository: &mut Repository) -> Result<Vec<(ObjectHash, CommitObject)>> {
+    let stack = StashStack::open(&repository.paths)?;
+
+    let ctx = repository.local(())?;
+    let stashes = stack
+        .iter()
+        .map(|stash_hash| {
+            ctx.read_commit(stash_hash).wait().map(
+                |commit| (stash_hash, commit),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ctx.close().wait()?;
+
+    Ok(stashes)
+}
@@ -0,0 +1,206 @@
+//! # `subtree` - split a path's history out into its own history, or join one history into
+//! another under a path.
+//!
+//! Splitting rewrites every commit reachable from a head so that its subtree is the subtree found
+//! at a given path. Commits in which the path does not exist, and commits which do not change it,
+//! are dropped. Since the rewritten commits keep their messages and timestamps, splitting the same
+//! path of the same history twice produces identical commits, so a split branch may be re-split
+//! and updated incrementally.
+//!
+//! Joining grafts the subtree of another commit in at a path and records a merge commit whose
+//! parents are the current head and the joined commit, so that the joined history is preserved.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+
+use errors::*;
+use graph::{self, Visit, Visitor};
+use history::{self, dedup_parents};
+use marshal::{ObjectHash, Object, CommitObject, SubtreeEntry, Marshaller, BackedTree,
+              serialize_and_hash};
+use store::ObjectStore;
+use trace::Trace;
+
+
+/// Rewrite the history of `head` to contain only the contents of `prefix`. Returns `None` if
+/// `prefix` never existed as a directory in any commit reachable from `head`.
+pub fn split<S: ObjectStore, T: Trace>(
+    store: &S,
+    marshaller: &Marshaller<T>,
+    head: ObjectHash,
+    prefix: PathBuf,
+) -> Box<Future<Item = Option<ObjectHash>, Error = Error> + Send> {
+    let store = store.clone();
+    let marshaller = marshaller.clone();
+
+    let result = {
+        async_block! {
+            let commits = await!(history::ancestry(&store, head))?;
+
+            // Maps original commits to their rewritten counterparts, if any, and rewritten commits
+            // to their subtrees.
+            let mut rewritten: HashMap<ObjectHash, Option<ObjectHash>> = HashMap::new();
+            let mut subtrees: HashMap<ObjectHash, ObjectHash> = HashMap::new();
+
+            for (hash, commit_object) in commits {
+                let parents = dedup_parents(
+                    commit_object
+                        .parents
+                        .iter()
+                        .filter_map(|parent| rewritten[parent])
+                        .collect(),
+                );
+
+                let subtree_opt = match await!(history::resolve(&store, commit_object.subtree, prefix.clone()))? {
+                    Some(SubtreeEntry::Subtree(subtree_hash)) => Some(subtree_hash),
+                    _ => None,
+                };
+
+                let new_hash_opt = match subtree_opt {
+                    // If the path doesn't exist here, then this commit is dropped in favor of its
+                    // first rewritten parent, if it has one.
+                    None => parents.first().cloned(),
+
+                    // Commits which don't touch the path are dropped as well.
+                    Some(subtree_hash) if parents.len() == 1 &&
+                                          subtrees[&parents[0]] == subtree_hash => {
+                        Some(parents[0])
+                    }
+
+                    Some(subtree_hash) => {
+                        let new_hash = await!(marshaller.process(CommitObject {
+                            subtree: subtree_hash,
+                            parents,
+                            message: commit_object.message,
+                            timestamp: commit_object.timestamp,
+                            // Rewritten commits no longer match what was signed.
+                            signature: None,
+                            author: commit_object.author,
+                            committer: commit_object.committer,
+                        }))?;
+                        subtrees.insert(new_hash, subtree_hash);
+
+                        Some(new_hash)
+                    }
+                };
+
+                rewritten.insert(hash, new_hash_opt);
+            }
+
+            Ok(rewritten[&head])
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Graft the subtree of the commit `other` into the subtree of `head_opt` at `prefix`, and write
+/// a merge commit with both as parents. The path must not already exist in `head_opt`.
+pub fn join<S: ObjectStore, T: Trace>(
+    store: &S,
+    marshaller: &Marshaller<T>,
+    head_opt: Option<ObjectHash>,
+    other: ObjectHash,
+    prefix: PathBuf,
+    message: String,
+    timestamp: DateTime<Utc>,
+) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+    let store = store.clone();
+    let marshaller = marshaller.clone();
+
+    let result = {
+        async_block! {
+            let other_commit = match await!(store.read_object(other))? {
+                Object::Commit(commit_object) => commit_object,
+                _ => bail!(ErrorKind::ObjectNotACommit(other)),
+            };
+
+            let subtree_hash = match head_opt {
+                Some(head) => {
+                    let head_commit = match await!(store.read_object(head))? {
+                        Object::Commit(commit_object) => commit_object,
+                        _ => bail!(ErrorKind::ObjectNotACommit(head)),
+                    };
+
+                    if await!(history::resolve(&store, head_commit.subtree, prefix.clone()))?.is_some() {
+                        bail!(ErrorKind::SubtreeJoinOccupied(prefix));
+                    }
+
+                    let components = prefix.iter().map(ToOwned::to_owned).collect::<Vec<_>>();
+                    let tree = BackedTree::new(store.clone(), SubtreeEntry::Subtree(head_commit.subtree));
+                    let tree = await!(tree.insert(components, SubtreeEntry::Subtree(other_commit.subtree)))?;
+
+                    await!(tree.marshal(marshaller.clone()))?
+                }
+                None => {
+                    let entries = vec![(prefix, SubtreeEntry::Subtree(other_commit.subtree))];
+                    await!(marshaller.process_tree(entries.into_iter().collect::<::marshal::Tree>()))?
+                }
+            };
+
+            let parents = head_opt.into_iter().chain(Some(other)).collect();
+            let commit_hash = await!(marshaller.process(CommitObject {
+                subtree: subtree_hash,
+                parents,
+                message,
+                timestamp,
+                signature: None,
+                author: None,
+                committer: None,
+            }))?;
+
+            Ok(commit_hash)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+struct CopyVisitor<D: ObjectStore> {
+    destination: D,
+    copied: Arc<AtomicUsize>,
+}
+
+
+impl<D: ObjectStore> Visitor for CopyVisitor<D> {
+    type Future = Box<Future<Item = Visit, Error = Error> + Send>;
+
+    fn visit(&mut self, _hash: ObjectHash, object: Object) -> Self::Future {
+        let copied = self.copied.clone();
+
+        Box::new(self.destination.write_object(serialize_and_hash(&object)).map(
+            move |fresh| if fresh {
+                copied.fetch_add(1, Ordering::Relaxed);
+                Visit::Descend
+            } else {
+                Visit::Prune
+            },
+        ))
+    }
+}
+
+
+/// Copy every object reachable from a commit (including its full history) from one store into
+/// another, returning the number of objects copied. Objects already present in the destination
+/// are assumed to have all of their descendants present as well.
+pub fn copy_history<S: ObjectStore, D: ObjectStore>(
+    source: &S,
+    destination: &D,
+    commit_hash: ObjectHash,
+) -> Box<Future<Item = u64, Error = Error> + Send> {
+    let visitor = CopyVisitor {
+        destination: destination.clone(),
+        copied: Arc::new(AtomicUsize::new(0)),
+    };
+
+    Box::new(graph::visit(source, Some(commit_hash), visitor).map(|visitor| {
+        visitor.copied.load(Ordering::Relaxed) as u64
+    }))
+}
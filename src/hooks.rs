@@ -0,0 +1,277 @@
+//! # `hooks` - checks and actions run around commits and pushes.
+//!
+//! Hooks are run by the `commit` and `push` commands at three points:
+//!
+//! * `pre-commit` - once the new commit has been written, but before any branch points at it. If
+//!   a pre-commit hook fails, the commit is abandoned and the objects written for it discarded.
+//! * `post-commit` - once the branch points at the new commit. The commit has landed by then, so
+//!   a post-commit hook failing is only reported.
+//! * `pre-push` - before any objects are sent to the remote. If a pre-push hook fails, nothing is
+//!   sent.
+//!
+//! Hooks are either commands, listed in `config.toml`, or Rust callbacks registered by library
+//! users through the `Hooks` trait. Commands are run in the root of the repository, with what is
+//! being committed or pushed described by their environment:
+//!
+//! * `ATTACA_COMMIT` - the hash of the commit being committed or pushed.
+//! * `ATTACA_SUBTREE`, `ATTACA_PARENTS` - the hash of the commit's subtree and the hashes of its
+//!   parents, separated by spaces. Commit hooks only.
+//! * `ATTACA_BRANCH` - the branch being committed to or pushed, or empty for a detached HEAD.
+//! * `ATTACA_REMOTE`, `ATTACA_REMOTE_COMMIT` - the remote being pushed to, and where the branch
+//!   was last known to point on it, or empty if it was not. Pre-push hooks only.
+//!
+//! A command exiting with a nonzero status fails the hook.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use errors::*;
+use marshal::{ObjectHash, CommitObject};
+use repository::{HookCfg, HookEvent, Paths};
+
+
+/// A commit, as seen by commit hooks.
+#[derive(Debug, Clone, Copy)]
+pub struct CommitEvent<'a> {
+    pub commit_hash: ObjectHash,
+    pub commit: &'a CommitObject,
+
+    /// The branch being committed to, or `None` if the HEAD is detached.
+    pub branch: Option<&'a str>,
+}
+
+
+/// A push, as seen by pre-push hooks.
+#[derive(Debug, Clone, Copy)]
+pub struct PushEvent<'a> {
+    pub remote: &'a str,
+    pub branch: &'a str,
+    pub commit_hash: ObjectHash,
+
+    /// Where the branch was last known to point on the remote, if anywhere.
+    pub previous: Option<ObjectHash>,
+}
+
+
+/// Callbacks run around commits and pushes. Every method does nothing by default; returning an
+/// error from a `pre_` method aborts the operation.
+pub trait Hooks: Send + Sync {
+    fn pre_commit(&self, _event: &CommitEvent) -> Result<()> {
+        Ok(())
+    }
+
+    fn post_commit(&self, _event: &CommitEvent) -> Result<()> {
+        Ok(())
+    }
+
+    fn pre_push(&self, _event: &PushEvent) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+/// The commands configured as hooks in `config.toml`.
+#[derive(Debug, Clone)]
+pub struct CommandHooks {
+    base: PathBuf,
+    hooks: Vec<HookCfg>,
+}
+
+
+impl CommandHooks {
+    pub fn new(paths: &Paths, hooks: Vec<HookCfg>) -> Self {
+        Self {
+            base: paths.base.clone(),
+            hooks,
+        }
+    }
+
+    fn run(&self, event: HookEvent, env: &[(&str, String)]) -> Result<()> {
+        for hook in self.hooks.iter().filter(|hook| hook.event == event) {
+            let command_line = hook.command.join(" ");
+            let (program, args) = match hook.command.split_first() {
+                Some(split) => split,
+                None => {
+                    bail!(ErrorKind::HookFailed(
+                        event.as_str().to_owned(),
+                        command_line,
+                        "no command is configured".to_owned(),
+                    ))
+                }
+            };
+
+            let status = Command::new(program)
+                .args(args)
+                .current_dir(&self.base)
+                .envs(env.iter().map(|&(key, ref value)| (key, value)))
+                .stdin(Stdio::null())
+                .status()
+                .chain_err(|| {
+                    ErrorKind::HookFailed(
+                        event.as_str().to_owned(),
+                        command_line.clone(),
+                        "could not be started".to_owned(),
+                    )
+                })?;
+
+            if !status.success() {
+                bail!(ErrorKind::HookFailed(
+                    event.as_str().to_owned(),
+                    command_line,
+                    status.to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+fn commit_env(event: &CommitEvent) -> Vec<(&'static str, String)> {
+    let parents = event
+        .commit
+        .parents
+        .iter()
+        .map(ObjectHash::to_string)
+        .collect::<Vec<_>>();
+
+    vec![
+        ("ATTACA_COMMIT", event.commit_hash.to_string()),
+        ("ATTACA_SUBTREE", event.commit.subtree.to_string()),
+        ("ATTACA_PARENTS", parents.join(" ")),
+        ("ATTACA_BRANCH", event.branch.unwrap_or("").to_owned()),
+    ]
+}
+
+
+impl Hooks for CommandHooks {
+    fn pre_commit(&self, event: &CommitEvent) -> Result<()> {
+        self.run(HookEvent::PreCommit, &commit_env(event))
+    }
+
+    fn post_commit(&self, event: &CommitEvent) -> Result<()> {
+        self.run(HookEvent::PostCommit, &commit_env(event))
+    }
+
+    fn pre_push(&self, event: &PushEvent) -> Result<()> {
+        let previous = event.previous.map(|hash| hash.to_string()).unwrap_or_default();
+
+        self.run(
+            HookEvent::PrePush,
+            &[
+                ("ATTACA_COMMIT", event.commit_hash.to_string()),
+                ("ATTACA_BRANCH", event.branch.to_owned()),
+                ("ATTACA_REMOTE", event.remote.to_owned()),
+                ("ATTACA_REMOTE_COMMIT", previous),
+            ],
+        )
+    }
+}
+
+
+/// Every hook registered with a repository, run in the order they were registered. The first to
+/// fail stops the rest from running.
+#[derive(Clone, Default)]
+pub struct HookSet {
+    hooks: Vec<Arc<Hooks>>,
+}
+
+
+impl fmt::Debug for HookSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HookSet {{ {} hooks }}", self.hooks.len())
+    }
+}
+
+
+impl HookSet {
+    /// The hooks configured for the repository at `paths`.
+    pub fn configured(paths: &Paths, hooks: &[HookCfg]) -> Self {
+        let mut hook_set = Self::default();
+
+        if !hooks.is_empty() {
+            hook_set.register(CommandHooks::new(paths, hooks.to_vec()));
+        }
+
+        hook_set
+    }
+
+    /// Run `hooks` after every hook registered so far.
+    pub fn register<H: Hooks + 'static>(&mut self, hooks: H) {
+        self.hooks.push(Arc::new(hooks));
+    }
+}
+
+
+impl Hooks for HookSet {
+    fn pre_commit(&self, event: &CommitEvent) -> Result<()> {
+        for hooks in &self.hooks {
+            hooks.pre_commit(event)?;
+        }
+
+        Ok(())
+    }
+
+    fn post_commit(&self, event: &CommitEvent) -> Result<()> {
+        for hooks in &self.hooks {
+            hooks.post_commit(event)?;
+        }
+
+        Ok(())
+    }
+
+    fn pre_push(&self, event: &PushEvent) -> Result<()> {
+        for hooks in &self.hooks {
+            hooks.pre_push(event)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Reject;
+
+    impl Hooks for Reject {
+        fn pre_push(&self, event: &PushEvent) -> Result<()> {
+            bail!("pushes to {} are not allowed", event.remote)
+        }
+    }
+
+    struct Count(Arc<AtomicUsize>);
+
+    impl Hooks for Count {
+        fn pre_push(&self, _event: &PushEvent) -> Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn first_failure_stops_the_rest() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut hook_set = HookSet::default();
+        hook_set.register(Count(count.clone()));
+        hook_set.register(Reject);
+        hook_set.register(Count(count.clone()));
+
+        let event = PushEvent {
+            remote: "origin",
+            branch: "master",
+            commit_hash: ObjectHash::zero(),
+            previous: None,
+        };
+
+        assert!(hook_set.pre_push(&event).is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,201 @@
+//! # `identity` - who is making changes, and the key they sign them with.
+//!
+//! Identities and signing keys are each resolved from the first of these which has one:
+//!
+//! 1. The environment. `ATTACA_AUTHOR_NAME` and `ATTACA_AUTHOR_EMAIL` (or `ATTACA_COMMITTER_*` for
+//!    the committer) take precedence over `ATTACA_NAME` and `ATTACA_EMAIL`, which apply to both;
+//!    a name is only used along with an email. `ATTACA_SIGNING_KEY` names a signing key.
+//! 2. The repository's `config.toml`.
+//! 3. The global config, at `$ATTACA_GLOBAL_CONFIG` if set and otherwise
+//!    `$XDG_CONFIG_HOME/attaca/config.toml` or `~/.config/attaca/config.toml`. Paths in it are
+//!    relative to the directory it is in.
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use toml;
+
+use errors::*;
+use marshal::Identity;
+use repository::{Config, Paths};
+
+
+/// The global configuration, shared by every repository of a user.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GlobalConfig {
+    /// The identity used by repositories which do not configure their own.
+    #[serde(default)]
+    pub identity: Option<Identity>,
+
+    /// The signing key used by repositories which do not configure their own.
+    #[serde(default)]
+    pub signing_key: Option<PathBuf>,
+}
+
+
+impl GlobalConfig {
+    /// The location of the global config, if one can be determined.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("ATTACA_GLOBAL_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::home_dir().map(|home| home.join(".config")))
+            .map(|config_home| config_home.join("attaca").join("config.toml"))
+    }
+
+    /// Load the global config. It is not an error for there to be none.
+    pub fn open() -> Result<Self> {
+        let path = match GlobalConfig::path() {
+            Some(ref path) if path.is_file() => path.clone(),
+            _ => return Ok(GlobalConfig::default()),
+        };
+
+        let mut config_string = String::new();
+        File::open(&path)?.read_to_string(&mut config_string)?;
+
+        Ok(toml::from_str(&config_string).chain_err(|| {
+            format!("error reading global config {}", path.display())
+        })?)
+    }
+}
+
+
+/// Where a setting was resolved from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Environment,
+    Repository,
+    Global,
+}
+
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Environment => write!(f, "environment"),
+            Source::Repository => write!(f, "repository config"),
+            Source::Global => write!(f, "global config"),
+        }
+    }
+}
+
+
+/// A resolved setting, along with where it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+
+/// The part an identity plays in a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+
+impl Role {
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Role::Author => "ATTACA_AUTHOR",
+            Role::Committer => "ATTACA_COMMITTER",
+        }
+    }
+}
+
+
+fn env_identity(prefix: &str) -> Option<Identity> {
+    match (
+        env::var(format!("{}_NAME", prefix)),
+        env::var(format!("{}_EMAIL", prefix)),
+    ) {
+        (Ok(name), Ok(email)) => Some(Identity { name, email }),
+        _ => None,
+    }
+}
+
+
+/// Resolve the identity playing `role` in new commits.
+pub fn resolve(config: &Config, role: Role) -> Option<Resolved<Identity>> {
+    let candidates = vec![
+        (
+            env_identity(role.env_prefix()).or_else(|| env_identity("ATTACA")),
+            Source::Environment,
+        ),
+        (config.identity.clone(), Source::Repository),
+        (config.global.identity.clone(), Source::Global),
+    ];
+
+    candidates.into_iter().filter_map(|(identity_opt, source)| {
+        identity_opt.map(|value| Resolved { value, source })
+    }).next()
+}
+
+
+/// Resolve the path of the key new commits are signed with.
+pub fn resolve_signing_key(config: &Config, paths: &Paths) -> Option<Resolved<PathBuf>> {
+    if let Some(path) = env::var_os("ATTACA_SIGNING_KEY") {
+        return Some(Resolved {
+            value: PathBuf::from(path),
+            source: Source::Environment,
+        });
+    }
+
+    if let Some(ref path) = config.signing_key {
+        return Some(Resolved {
+            value: paths.common.join(path),
+            source: Source::Repository,
+        });
+    }
+
+    match (&config.global.signing_key, GlobalConfig::path()) {
+        (&Some(ref path), Some(global_path)) => {
+            let global_dir = global_path.parent().map(PathBuf::from).unwrap_or_default();
+            Some(Resolved {
+                value: global_dir.join(path),
+                source: Source::Global,
+            })
+        }
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn identity(name: &str) -> Identity {
+        Identity {
+            name: name.to_owned(),
+            email: format!("{}@example.com", name),
+        }
+    }
+
+    #[test]
+    fn repository_overrides_global() {
+        let mut config = Config::default();
+        config.global.identity = Some(identity("global"));
+        assert_eq!(
+            resolve(&config, Role::Author).map(|resolved| resolved.source),
+            Some(Source::Global)
+        );
+
+        config.identity = Some(identity("repository"));
+        assert_eq!(
+            resolve(&config, Role::Committer),
+            Some(Resolved {
+                value: identity("repository"),
+                source: Source::Repository,
+            })
+        );
+    }
+}
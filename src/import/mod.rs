@@ -0,0 +1,179 @@
+//! # `import` - read the contents of an archive into the repository.
+//!
+//! Archives are read straight into the store, without unpacking anything onto the filesystem
+//! first: the archive is mapped into memory, and the contents of each member are split by the
+//! repository's chunkers and marshalled from there. The result is a subtree, which may be
+//! committed like any other.
+//!
+//! Directories are implied by the files beneath them, so empty directories are not kept, just as
+//! when committing. Devices, FIFOs and other special files are skipped with a warning. Should the
+//! same path appear twice, the later member wins, as it would when extracting.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use futures::prelude::*;
+use futures::stream;
+
+use arc_slice::{self, ArcSlice};
+use chunker::ChunkerSet;
+use errors::*;
+use marshal::{Marshaller, ObjectHash, SubtreeEntry, Tree};
+use repository::MetadataMode;
+use trace::Trace;
+
+pub mod tar;
+pub mod zip;
+
+pub use self::tar::read as from_tar;
+pub use self::zip::read as from_zip;
+
+
+/// The outcome of an import.
+#[derive(Debug, Clone, Copy)]
+pub struct Imported {
+    /// The subtree holding the contents of the archive.
+    pub subtree: ObjectHash,
+
+    /// The number of files and symlinks imported.
+    pub files: u64,
+
+    /// The total size of the files imported.
+    pub bytes: u64,
+}
+
+
+/// What a member of an archive holds.
+enum Contents {
+    File(ArcSlice),
+    Symlink(Vec<u8>),
+
+    /// A hard link to a member earlier in the archive.
+    Link(PathBuf),
+}
+
+
+/// A member of an archive, as read by `tar` or `zip`.
+struct Member {
+    path: PathBuf,
+    contents: Contents,
+    mode: u32,
+    mtime: i64,
+    uid: u32,
+    gid: u32,
+}
+
+
+/// Turn the path of a member into a path relative to the root of the archive, or `None` if it
+/// names the root itself. Paths leading outside of the archive are refused.
+fn member_path(raw: &[u8]) -> Result<Option<PathBuf>> {
+    let mut path = PathBuf::new();
+    for component in Path::new(OsStr::from_bytes(raw)).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir | Component::RootDir => {}
+            _ => {
+                bail!(ErrorKind::MalformedArchive(
+                    format!("{} leads outside of the archive", String::from_utf8_lossy(raw)),
+                ))
+            }
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(path))
+    }
+}
+
+
+/// Marshal `members` in order, and then the subtree holding them.
+fn import<T: Trace>(
+    marshaller: &Marshaller<T>,
+    chunkers: &ChunkerSet,
+    metadata: MetadataMode,
+    members: Vec<Member>,
+) -> Box<Future<Item = Imported, Error = Error> + Send> {
+    let marshaller = marshaller.clone();
+    let chunkers = chunkers.clone();
+
+    let result = {
+        async_block! {
+            let mut entries = BTreeMap::new();
+            let mut bytes = 0;
+
+            for member in members {
+                let Member { path, contents, mode, mtime, uid, gid } = member;
+
+                let entry = match contents {
+                    Contents::File(data) => {
+                        let size = data.len() as u64;
+                        let chunks = chunkers.for_path(&path).chunk(data);
+                        let object_hash = await!(marshaller.process_chunks(chunks))?;
+                        bytes += size;
+
+                        let entry = SubtreeEntry::from_mode(object_hash, size, mode & 0o7777);
+                        metadata.annotate(entry, mtime, uid, gid)
+                    }
+                    Contents::Symlink(target) => {
+                        let chunks = stream::once::<_, Error>(Ok(arc_slice::owned(target)));
+                        let object_hash = await!(marshaller.process_chunks(chunks))?;
+
+                        metadata.annotate(SubtreeEntry::Symlink(object_hash), mtime, uid, gid)
+                    }
+                    Contents::Link(target) => {
+                        match entries.get(&target) {
+                            Some(entry) => SubtreeEntry::clone(entry),
+                            None => {
+                                bail!(
+                                    "{} links to {}, which does not come before it in the archive",
+                                    path.display(),
+                                    target.display()
+                                );
+                            }
+                        }
+                    }
+                };
+
+                entries.insert(path, entry);
+            }
+
+            for path in entries.keys() {
+                let mut parent = path.parent();
+                while let Some(dir) = parent {
+                    if entries.contains_key(dir) {
+                        bail!(ErrorKind::MalformedArchive(format!(
+                            "{} is both a file and a directory in the archive",
+                            dir.display()
+                        )));
+                    }
+                    parent = dir.parent();
+                }
+            }
+
+            let files = entries.len() as u64;
+            let subtree = await!(marshaller.process_tree(entries.into_iter().collect::<Tree>()))?;
+
+            Ok(Imported { subtree, files, bytes })
+        }
+    };
+
+    Box::new(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn member_paths_stay_inside_the_archive() {
+        assert_eq!(member_path(b"./a/b").unwrap(), Some(PathBuf::from("a/b")));
+        assert_eq!(member_path(b"/a/./b/").unwrap(), Some(PathBuf::from("a/b")));
+        assert_eq!(member_path(b"./").unwrap(), None);
+        assert!(member_path(b"a/../../b").is_err());
+    }
+}
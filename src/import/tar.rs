@@ -0,0 +1,229 @@
+//! Tar archives.
+//!
+//! Both ustar and GNU archives are understood, along with pax extended headers and GNU long
+//! names, so that archives written by any modern `tar` - and by `export::tar` - can be imported.
+//! Hard links become a second entry for the same object.
+
+use std::collections::HashMap;
+
+use futures::future;
+use futures::prelude::*;
+
+use arc_slice::ArcSlice;
+use archive::tar::{self, number, pax_number, pax_records, text};
+use chunker::ChunkerSet;
+use errors::*;
+use import::{self, Contents, Imported, Member};
+use marshal::Marshaller;
+use repository::MetadataMode;
+use trace::Trace;
+use warning;
+
+
+/// Look up a pax record, in the extended header of the member at hand or failing that in the
+/// global one.
+fn pax_value(
+    extended: &HashMap<String, Vec<u8>>,
+    global: &HashMap<String, Vec<u8>>,
+    key: &str,
+) -> Option<Vec<u8>> {
+    extended.get(key).or_else(|| global.get(key)).cloned()
+}
+
+
+/// Read every member of a tar archive, in order.
+fn members(archive: &ArcSlice) -> Result<Vec<Member>> {
+    let mut members = Vec::new();
+
+    let mut global = HashMap::new();
+    let mut extended = HashMap::new();
+    let mut long_path = None;
+    let mut long_link = None;
+
+    for entry in tar::entries(archive) {
+        let entry = entry?;
+        let header = entry.header;
+        let (start, end) = (entry.data.start, entry.data.end);
+        let data = &archive[start..end];
+
+        match entry.kind() {
+            b'x' => {
+                extended = pax_records(data)?;
+                continue;
+            }
+            b'g' => {
+                global.extend(pax_records(data)?);
+                continue;
+            }
+            b'L' => {
+                long_path = Some(text(data).to_vec());
+                continue;
+            }
+            b'K' => {
+                long_link = Some(text(data).to_vec());
+                continue;
+            }
+            _ => {}
+        }
+
+        let raw_path = pax_value(&extended, &global, "path")
+            .or_else(|| long_path.take())
+            .unwrap_or_else(|| {
+                let name = text(&header[..100]);
+                let prefix = text(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    let mut path = prefix.to_vec();
+                    path.push(b'/');
+                    path.extend_from_slice(name);
+                    path
+                } else {
+                    name.to_vec()
+                }
+            });
+        let link = pax_value(&extended, &global, "linkpath")
+            .or_else(|| long_link.take())
+            .unwrap_or_else(|| text(&header[157..257]).to_vec());
+
+        let mode = number(&header[100..108]).unwrap_or(0o644) as u32;
+        let mtime = pax_value(&extended, &global, "mtime")
+            .and_then(|mtime| pax_number(&mtime))
+            .unwrap_or_else(|| number(&header[136..148]).unwrap_or(0) as i64);
+        let uid = pax_value(&extended, &global, "uid")
+            .and_then(|uid| pax_number(&uid))
+            .unwrap_or_else(|| number(&header[108..116]).unwrap_or(0) as i64);
+        let gid = pax_value(&extended, &global, "gid")
+            .and_then(|gid| pax_number(&gid))
+            .unwrap_or_else(|| number(&header[116..124]).unwrap_or(0) as i64);
+
+        extended.clear();
+        long_path = None;
+        long_link = None;
+
+        let path = match import::member_path(&raw_path)? {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let contents = match entry.kind() {
+            0 | b'0' | b'7' => Contents::File(archive.clone().map(|bytes| &bytes[start..end])),
+            b'2' => Contents::Symlink(link),
+            b'1' => {
+                match import::member_path(&link)? {
+                    Some(target) => Contents::Link(target),
+                    None => {
+                        bail!(ErrorKind::MalformedArchive(
+                            format!("{} is a hard link to the root of the archive", path.display()),
+                        ))
+                    }
+                }
+            }
+            b'5' => continue,
+            kind => {
+                warning::warn(format!(
+                    "skipping {}, which is of unsupported type `{}`.",
+                    path.display(),
+                    kind as char
+                ));
+                continue;
+            }
+        };
+
+        members.push(Member {
+            path,
+            contents,
+            mode,
+            mtime,
+            uid: uid as u32,
+            gid: gid as u32,
+        });
+    }
+
+    Ok(members)
+}
+
+
+/// Import a tar archive, already mapped into memory, as a subtree.
+pub fn read<T: Trace>(
+    marshaller: &Marshaller<T>,
+    chunkers: &ChunkerSet,
+    metadata: MetadataMode,
+    archive: ArcSlice,
+) -> Box<Future<Item = Imported, Error = Error> + Send> {
+    match members(&archive) {
+        Ok(members) => import::import(marshaller, chunkers, metadata, members),
+        Err(error) => Box::new(future::err(error)),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use arc_slice;
+
+    /// A header block of the given type and size, with a valid checksum.
+    fn header(name: &[u8], kind: u8, size: usize, link: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; tar::BLOCK];
+        header[..name.len()].copy_from_slice(name);
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[257..263].copy_from_slice(b"ustar\0");
+
+        let checksum = tar::checksum(&header);
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        header[155] = b' ';
+
+        header
+    }
+
+    fn member(archive: &mut Vec<u8>, name: &[u8], kind: u8, data: &[u8], link: &[u8]) {
+        archive.extend(header(name, kind, data.len(), link));
+        archive.extend_from_slice(data);
+        archive.extend(vec![0u8; tar::padding(data.len() as u64)]);
+    }
+
+    #[test]
+    fn reads_long_names_and_links() {
+        let long_name = vec![b'a'; 150];
+        let mut pax = b"160 path=".to_vec();
+        pax.extend_from_slice(&long_name);
+        pax.push(b'\n');
+
+        let mut archive = Vec::new();
+        member(&mut archive, b"./", b'5', b"", b"");
+        member(&mut archive, b"././@PaxHeader", b'x', &pax, b"");
+        member(&mut archive, b"truncated", b'0', b"hello", b"");
+        member(&mut archive, b"./dir/link", b'1', b"", b"./dir/file");
+        member(&mut archive, b"dir/symlink", b'2', b"", b"../target");
+        archive.extend(vec![0u8; 2 * tar::BLOCK]);
+
+        let members = members(&arc_slice::owned(archive)).unwrap();
+        let paths = members.iter().map(|member| member.path.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from(String::from_utf8(long_name).unwrap()),
+                PathBuf::from("dir/link"),
+                PathBuf::from("dir/symlink"),
+            ]
+        );
+
+        match members[0].contents {
+            Contents::File(ref data) => assert_eq!(&data[..], b"hello"),
+            _ => panic!("expected a file"),
+        }
+        match members[1].contents {
+            Contents::Link(ref target) => assert_eq!(target, &PathBuf::from("dir/file")),
+            _ => panic!("expected a hard link"),
+        }
+        match members[2].contents {
+            Contents::Symlink(ref target) => assert_eq!(target, b"../target"),
+            _ => panic!("expected a symlink"),
+        }
+    }
+}
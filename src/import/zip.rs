@@ -0,0 +1,94 @@
+//! Zip archives.
+//!
+//! Members are found through the central directory, and zip64 archives are understood, so that
+//! archives written by `export::zip` can be imported. Only members stored without compression
+//! can be read; an archive with compressed members must be repacked with `zip -0` first. Unix
+//! modes are kept where the archive records them, and MS-DOS modification times are read as UTC.
+
+use futures::future;
+use futures::prelude::*;
+
+use arc_slice::ArcSlice;
+use archive::zip;
+use chunker::ChunkerSet;
+use errors::*;
+use import::{self, Contents, Imported, Member};
+use marshal::Marshaller;
+use repository::MetadataMode;
+use trace::Trace;
+use warning;
+
+
+/// Read every member of a zip archive, in the order of its central directory.
+fn members(archive: &ArcSlice) -> Result<Vec<Member>> {
+    let bytes = &archive[..];
+    let mut members = Vec::new();
+
+    for entry in zip::directory(bytes)?.entries {
+        let path = match import::member_path(entry.name)? {
+            Some(path) => path,
+            None => continue,
+        };
+
+        if entry.flags & 1 != 0 {
+            bail!(ErrorKind::UnsupportedArchiveMember(path, "it is encrypted".to_owned()));
+        }
+        if entry.method != 0 {
+            bail!(ErrorKind::UnsupportedArchiveMember(
+                path,
+                "it is compressed; only stored members can be imported".to_owned(),
+            ));
+        }
+
+        let span = entry.data(bytes).ok_or_else(zip::malformed)?;
+        let data = &bytes[span.clone()];
+
+        if zip::crc32(0, data) != entry.crc {
+            bail!(ErrorKind::MalformedArchive(
+                format!("{} is corrupt: its checksum does not match", path.display()),
+            ));
+        }
+
+        let mode = if entry.made_by & 0xff00 == zip::MADE_BY_UNIX {
+            entry.external >> 16
+        } else {
+            0
+        };
+        let contents = match mode & 0o170000 {
+            _ if entry.name.ends_with(b"/") => continue,
+            0o040000 => continue,
+            0o120000 => Contents::Symlink(data.to_vec()),
+            0 | 0o100000 => Contents::File(archive.clone().map(|bytes| &bytes[span])),
+            _ => {
+                warning::warn(format!("skipping {}, which is not a regular file.", path.display()));
+                continue;
+            }
+        };
+
+        members.push(Member {
+            path,
+            contents,
+            mode: if mode == 0 { 0o644 } else { mode },
+            mtime: zip::unix_time(entry.dos_time, entry.dos_date),
+            uid: 0,
+            gid: 0,
+        });
+    }
+
+    Ok(members)
+}
+
+
+/// Import a zip archive, already mapped into memory, as a subtree.
+pub fn read<T: Trace>(
+    marshaller: &Marshaller<T>,
+    chunkers: &ChunkerSet,
+    metadata: MetadataMode,
+    archive: ArcSlice,
+) -> Box<Future<Item = Imported, Error = Error> + Send> {
+    match members(&archive) {
+        Ok(members) => import::import(marshaller, chunkers, metadata, members),
+        Err(error) => Box::new(future::err(error)),
+    }
+}
+
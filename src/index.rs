@@ -1,10 +1,13 @@
+use std::collections::HashSet;
 use std::collections::hash_map::{HashMap, Entry};
 use std::ffi::CString;
-use std::fs::File;
-use std::io::Error as IoError;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Error as IoError, Read};
 use std::mem;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 use std::os::unix::ffi::OsStrExt;
@@ -13,11 +16,17 @@ use bincode;
 use chrono::prelude::*;
 use globset::GlobSet;
 use libc;
+use seahash;
 
-use DEFAULT_IGNORES;
+use {DEFAULT_IGNORES, LOCK_TIMEOUT_MS, METADATA_PATH};
 use errors::*;
-use marshal::ObjectHash;
-use repository::Paths;
+use fault;
+use hash_cache::HashCache;
+use lock::LockFile;
+use marshal::{ObjectHash, SubtreeEntry};
+use lazy::{Materialized, Placeholders};
+use repository::{MetadataMode, Paths};
+use sparse::Sparse;
 
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
@@ -165,6 +174,7 @@ impl IndexEntry {
         fresh: &IndexMetadata,
         timestamp: &DateTime<Utc>,
         object_hash: ObjectHash,
+        size: u64,
     ) -> Result<()> {
         self.update(&fresh, &timestamp);
         // We check to ensure the self does not seem to have been modified since its last
@@ -173,11 +183,27 @@ impl IndexEntry {
             self.hygiene == Hygiene::Clean,
             ErrorKind::ConcurrentlyModifiedEntry
         );
-        self.cached = Cached::Hashed(object_hash, fresh.size as u64);
+        self.cached = Cached::Hashed(object_hash, size);
 
         Ok(())
     }
 
+    /// Build the subtree entry for this file given the hash and size of its contents, preserving
+    /// whether it is a symlink or executable.
+    pub fn to_subtree_entry(&self, object_hash: ObjectHash, size: u64) -> SubtreeEntry {
+        SubtreeEntry::from_mode(object_hash, size, self.metadata.mode)
+    }
+
+    /// Annotate a subtree entry for this file with the metadata recorded by the given mode.
+    pub fn annotate(&self, entry: SubtreeEntry, mode: MetadataMode) -> SubtreeEntry {
+        mode.annotate(
+            entry,
+            self.metadata.mtime.timestamp(),
+            self.metadata.uid,
+            self.metadata.gid,
+        )
+    }
+
     pub fn get(&self) -> Option<Cached> {
         if self.hygiene == Hygiene::Clean {
             Some(self.cached)
@@ -205,26 +231,139 @@ impl IndexData {
 }
 
 
+/// A hash of index data which does not depend on the order its entries happen to be kept in, so
+/// that data read and left alone hashes the same when it is written back.
+fn fingerprint(data: &IndexData) -> Result<u64> {
+    let mut entries = data.entries.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    let bytes = bincode::serialize(&(&data.timestamp, entries), bincode::Infinite)?;
+
+    Ok(seahash::hash(&bytes))
+}
+
+
+/// Identifies a version of the index file. The file is only ever replaced by renaming a new one
+/// over it, so every write gives it a new inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexStamp {
+    inode: u64,
+    len: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+
+impl IndexStamp {
+    fn new(metadata: &Metadata) -> Self {
+        IndexStamp {
+            inode: metadata.ino(),
+            len: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        }
+    }
+
+    /// The stamp of the index file at `path`, or `None` if there is none.
+    fn of(path: &Path) -> Result<Option<Self>> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(Self::new(&metadata))),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+
+/// Whether `path` or any of its parents is in `paths`.
+fn within(paths: &HashSet<PathBuf>, path: &Path) -> bool {
+    let mut current = Some(path);
+
+    while let Some(path) = current {
+        if paths.contains(path) {
+            return true;
+        }
+
+        current = path.parent();
+    }
+
+    false
+}
+
+
+/// Bring a tracked entry up to date with the file it describes. Returns `None` if the entry
+/// should be dropped from the index.
+fn refresh_entry(
+    base: &Path,
+    timestamp: &DateTime<Utc>,
+    sparse: &Option<Sparse>,
+    relative_path: PathBuf,
+    mut entry: IndexEntry,
+) -> Result<Option<(PathBuf, IndexEntry)>> {
+    let absolute_path = base.join(&relative_path);
+    if absolute_path.exists() {
+        let file_type = absolute_path.symlink_metadata()?.file_type();
+        if file_type.is_file() || file_type.is_symlink() {
+            let fresh = IndexMetadata::load(absolute_path)?;
+            entry.update(&fresh, timestamp);
+            if entry.hygiene != Hygiene::Dirty {
+                return Ok(Some((relative_path, entry)));
+            }
+        }
+    } else if sparse.as_ref().map_or(true, |sparse| sparse.is_match(&relative_path)) {
+        entry.cached = Cached::Removed;
+        return Ok(Some((relative_path, entry)));
+    } else {
+        // Files outside of a sparse checkout are absent, not removed.
+        return Ok(Some((relative_path, entry)));
+    }
+
+    Ok(None)
+}
+
+
 #[derive(Debug)]
 pub struct Index {
     data: IndexData,
     paths: Arc<Paths>,
+    sparse: Option<Sparse>,
+    placeholders: Placeholders,
+    materialized: Materialized,
+    hash_cache: HashCache,
+
+    /// The file the index was read from, and the fingerprint of what was read.
+    loaded: Option<(IndexStamp, u64)>,
 }
 
 
 impl Index {
     pub fn open(paths: &Arc<Paths>) -> Result<Index> {
-        let data = if paths.index.exists() {
+        let (data, loaded) = if paths.index.exists() {
             let mut index_file = File::open(&paths.index).chain_err(|| ErrorKind::IndexOpen)?;
-            bincode::deserialize_from(&mut index_file, bincode::Infinite)
-                .chain_err(|| ErrorKind::IndexParse)?
+            let stamp = IndexStamp::new(&index_file.metadata()?);
+            let mut bytes = Vec::new();
+            index_file.read_to_end(&mut bytes).chain_err(
+                || ErrorKind::IndexOpen,
+            )?;
+            let data = bincode::deserialize(&bytes).chain_err(|| ErrorKind::IndexParse)?;
+            let loaded_fingerprint = fingerprint(&data)?;
+
+            (data, Some((stamp, loaded_fingerprint)))
         } else {
-            IndexData::new()
+            (IndexData::new(), None)
         };
+        let sparse = Sparse::open(paths)?;
+        let placeholders = Placeholders::open(paths)?;
+        let materialized = Materialized::open(paths)?;
+        let hash_cache = HashCache::open(paths)?;
 
         let index = Index {
             data,
             paths: paths.clone(),
+            sparse,
+            placeholders,
+            materialized,
+            hash_cache,
+            loaded,
         };
 
         Ok(index)
@@ -237,26 +376,12 @@ impl Index {
         self.data.entries = {
             let base_ref = &self.paths.base;
             let timestamp_ref = &self.data.timestamp;
+            let sparse_ref = &self.sparse;
 
             mem::replace(&mut self.data.entries, HashMap::new())
                 .into_iter()
-                .map(|(relative_path, mut entry)| if entry.tracked {
-                    let absolute_path = base_ref.join(&relative_path);
-                    if absolute_path.exists() {
-                        let file_type = absolute_path.symlink_metadata()?.file_type();
-                        if file_type.is_file() || file_type.is_symlink() {
-                            let fresh = IndexMetadata::load(absolute_path)?;
-                            entry.update(&fresh, timestamp_ref);
-                            if entry.hygiene != Hygiene::Dirty {
-                                return Ok(Some((relative_path, entry)));
-                            }
-                        }
-                    } else {
-                        entry.cached = Cached::Removed;
-                        return Ok(Some((relative_path, entry)));
-                    }
-
-                    Ok(None)
+                .map(|(relative_path, entry)| if entry.tracked {
+                    refresh_entry(base_ref, timestamp_ref, sparse_ref, relative_path, entry)
                 } else {
                     Ok(Some((relative_path, entry)))
                 })
@@ -275,7 +400,56 @@ impl Index {
         Ok(())
     }
 
-    pub fn clean<P: AsRef<Path>>(&mut self, path: P, object_hash: ObjectHash) -> Result<()> {
+    /// Update the index as `update` does, but only stat the tracked files which may have changed
+    /// since the index was last updated: those at or beneath one of the `changed` paths, and
+    /// those which are dodgy. Every other entry is left as it is, which is only correct if
+    /// `changed` really does contain every path modified since `timestamp()`.
+    pub fn update_paths<I: IntoIterator<Item = PathBuf>>(&mut self, changed: I) -> Result<()> {
+        let fresh_timestamp = Utc::now().with_nanosecond(0).unwrap();
+        let changed = changed.into_iter().collect::<HashSet<_>>();
+
+        let stale = self.data
+            .entries
+            .iter()
+            .filter(|&(path, entry)| {
+                entry.tracked && (entry.hygiene == Hygiene::Dodgy || within(&changed, path))
+            })
+            .map(|(path, _)| path.to_owned())
+            .collect::<Vec<_>>();
+
+        for relative_path in stale {
+            let entry = self.data.entries.remove(&relative_path).unwrap();
+            let refreshed = refresh_entry(
+                &self.paths.base,
+                &self.data.timestamp,
+                &self.sparse,
+                relative_path,
+                entry,
+            )?;
+
+            if let Some((relative_path, entry)) = refreshed {
+                self.data.entries.insert(relative_path, entry);
+            }
+        }
+
+        self.data.timestamp = fresh_timestamp;
+
+        Ok(())
+    }
+
+    /// The instant at which the index was last updated.
+    pub fn timestamp(&self) -> &DateTime<Utc> {
+        &self.data.timestamp
+    }
+
+    /// Record that the file at `path` has been hashed, its contents cleaning to `size` bytes which
+    /// hash to `object_hash`.
+    pub fn clean<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        object_hash: ObjectHash,
+        size: u64,
+    ) -> Result<()> {
         match self.data.entries.get_mut(path.as_ref()) {
             Some(entry) => {
                 entry
@@ -283,6 +457,7 @@ impl Index {
                         &IndexMetadata::load(self.paths.base.join(&path))?,
                         &self.data.timestamp,
                         object_hash,
+                        size,
                     )
                     .chain_err(|| {
                         ErrorKind::ConcurrentlyModifiedFile(path.as_ref().to_owned())
@@ -293,6 +468,37 @@ impl Index {
         }
     }
 
+    /// Record that the file at `path` has just been written with the contents of the given
+    /// object, of `size` bytes before any smudge filter, so that it need not be rehashed. Files
+    /// whose mtimes were restored to a time before the last index update are immediately clean;
+    /// freshly written files remain dodgy until the next update, exactly as if they had been
+    /// hashed.
+    pub fn insert_hashed<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        object_hash: ObjectHash,
+        size: u64,
+    ) -> Result<()> {
+        let fresh = IndexMetadata::load(self.paths.base.join(&path))?;
+        let cached = Cached::Hashed(object_hash, size);
+        let timestamp = self.data.timestamp;
+
+        match self.data.entries.entry(path.as_ref().to_owned()) {
+            Entry::Occupied(mut occupied) => {
+                let entry = occupied.get_mut();
+                entry.metadata = fresh;
+                entry.hygiene = Hygiene::Clean;
+                entry.cached = cached;
+                entry.update(&fresh, &timestamp);
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(IndexEntry::fresh(fresh, cached)).update(&fresh, &timestamp);
+            }
+        }
+
+        Ok(())
+    }
+
     // TODO: Take an iterator of string slices instead of a `GlobSet`, and attempt to parse those
     // string slices into `Glob`s of their own. Then, only visit subdirectories which we know might
     // contain the files we're looking for.
@@ -315,8 +521,13 @@ impl Index {
                 }
 
                 if absolute_path.symlink_metadata()?.is_dir() {
+                    // Nested repositories track their own files. See the `subrepository` module.
+                    if absolute_path.join(&*METADATA_PATH).is_dir() {
+                        continue;
+                    }
+
                     stack.push(absolute_path.read_dir()?);
-                } else if pattern.is_match(&relative_path) {
+                } else if pattern.is_match(&relative_path) && self.in_view(&relative_path) {
                     let fresh = IndexMetadata::load(absolute_path)?;
 
                     match self.data.entries.entry(relative_path) {
@@ -334,6 +545,64 @@ impl Index {
         Ok(())
     }
 
+    /// The sparse checkout patterns of the working directory, if it is a sparse checkout.
+    pub fn sparse(&self) -> Option<&Sparse> {
+        self.sparse.as_ref()
+    }
+
+    pub fn set_sparse(&mut self, sparse: Option<Sparse>) {
+        self.sparse = sparse;
+    }
+
+    /// Check whether a path is inside the working directory's sparse view. If the working
+    /// directory is not a sparse checkout, every path is in view.
+    pub fn in_view<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.sparse.as_ref().map_or(true, |sparse| sparse.is_match(path))
+    }
+
+    /// Placeholder files left by a lazy checkout.
+    pub fn placeholders(&self) -> &Placeholders {
+        &self.placeholders
+    }
+
+    pub fn placeholders_mut(&mut self) -> &mut Placeholders {
+        &mut self.placeholders
+    }
+
+    /// Files hydrated from placeholders, which may be evicted back into placeholders.
+    pub fn materialized(&self) -> &Materialized {
+        &self.materialized
+    }
+
+    pub fn materialized_mut(&mut self) -> &mut Materialized {
+        &mut self.materialized
+    }
+
+    /// Hashes of worktree files, kept for as long as the files are unchanged.
+    pub fn hash_cache(&self) -> &HashCache {
+        &self.hash_cache
+    }
+
+    pub fn hash_cache_mut(&mut self) -> &mut HashCache {
+        &mut self.hash_cache
+    }
+
+    /// Mark every hashed file unhashed and empty the hash cache, unless the hashes were computed
+    /// under the normalization settings `config`. See `Repository::normalization`.
+    pub fn renormalize(&mut self, config: u64) {
+        if self.hash_cache.config() == config {
+            return;
+        }
+
+        for entry in self.data.entries.values_mut() {
+            if let Cached::Hashed(..) = entry.cached {
+                entry.cached = Cached::Unhashed;
+            }
+        }
+
+        self.hash_cache.reset(config);
+    }
+
     pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a Path, &'a IndexEntry)> {
         self.data.entries.iter().map(|(path, entry)| {
             (path.as_ref(), entry)
@@ -352,9 +621,39 @@ impl Index {
         );
     }
 
+    /// Write the index back, if it was changed. Another process may have written the index since
+    /// it was read - a commit finishing while a fetch runs, say - in which case an unchanged index
+    /// is left alone, and a changed one fails with `IndexConflict` rather than undo that write.
     pub fn cleanup(self) -> Result<()> {
-        let mut file = File::create(&self.paths.index)?;
-        bincode::serialize_into(&mut file, &self.data, bincode::Infinite)?;
+        let _lock = LockFile::acquire_timeout(
+            &self.paths.index_lock,
+            Duration::from_millis(LOCK_TIMEOUT_MS),
+        )?;
+
+        let changed = match self.loaded {
+            Some((_, loaded_fingerprint)) => fingerprint(&self.data)? != loaded_fingerprint,
+            None => true,
+        };
+
+        if changed {
+            if IndexStamp::of(&self.paths.index)? != self.loaded.map(|(stamp, _)| stamp) {
+                bail!(ErrorKind::IndexConflict);
+            }
+
+            // Written aside and moved into place, so that a crash never leaves a torn index.
+            let temp_path = self.paths.index.with_extension("bin.tmp");
+            {
+                let mut file = File::create(&temp_path)?;
+                bincode::serialize_into(&mut file, &self.data, bincode::Infinite)?;
+            }
+            fault::point("index.write");
+            fs::rename(&temp_path, &self.paths.index)?;
+        }
+
+        Sparse::save(self.sparse.as_ref(), &self.paths)?;
+        self.placeholders.save(&self.paths)?;
+        self.materialized.save(&self.paths)?;
+        self.hash_cache.save(&self.paths)?;
 
         Ok(())
     }
@@ -0,0 +1,256 @@
+//! # `ingest` - commit many similar directories at once, such as a series of nightly backups.
+//!
+//! Each directory becomes a commit of its own, whose parent is the commit of the directory before
+//! it, so a series of snapshots becomes a history. What makes ingesting them together cheaper
+//! than committing them one at a time is that the work is shared between them:
+//!
+//! * A file which is a hard link to a file already ingested, as backup tools make of files which
+//!   did not change between snapshots, is never read again.
+//! * With `IngestOptions::trust_mtimes`, neither is a file with the same size and mtime as the
+//!   file at the same path in the snapshot before it, just as `rsync` decides which files to skip.
+//! * Given the marshaller of `Context::deduplicating_marshaller`, each distinct chunk is sent to
+//!   the store once, however many snapshots hold it.
+//!
+//! As when committing, directories are implied by the files beneath them, so empty directories
+//! are not kept. Devices, FIFOs and other special files are skipped with a warning.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use futures::stream;
+use memmap::{Mmap, Protection};
+
+use arc_slice::{self, ArcSlice};
+use chunker::ChunkerSet;
+use errors::*;
+use marshal::{Marshaller, ObjectHash, CommitObject, Identity, SubtreeEntry, Tree};
+use repository::MetadataMode;
+use trace::Trace;
+use warning;
+
+
+/// A directory to ingest, and the commit to make of it.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct IngestOptions {
+    /// Take a file to be unchanged if the file at the same path in the previous snapshot had the
+    /// same size and mtime, without reading it.
+    pub trust_mtimes: bool,
+
+    pub metadata: MetadataMode,
+    pub author: Option<Identity>,
+    pub committer: Option<Identity>,
+}
+
+
+/// The outcome of ingesting a single snapshot.
+#[derive(Debug, Clone, Copy)]
+pub struct Ingested {
+    pub commit: ObjectHash,
+
+    /// The number of files and symlinks in the snapshot.
+    pub files: u64,
+
+    /// The total size of the files in the snapshot.
+    pub bytes: u64,
+
+    /// The number of files whose hashes were taken from an earlier snapshot, and their total
+    /// size.
+    pub reused_files: u64,
+    pub reused_bytes: u64,
+}
+
+
+/// What a file's hash was computed for: the file must still have the same size and mtime, and,
+/// for a hard link, the same ctime, to be taken to have the same contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stat {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+
+impl Stat {
+    fn new(metadata: &Metadata) -> Self {
+        Stat {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        }
+    }
+}
+
+
+/// Hashes already computed during an ingest, shared between its snapshots.
+#[derive(Debug, Default)]
+struct Seen {
+    /// By device and inode, along with the ctime the inode had.
+    inodes: HashMap<(u64, u64), (Stat, i64, i64, ObjectHash)>,
+
+    /// By path, relative to the root of the previous snapshot.
+    paths: HashMap<PathBuf, (Stat, ObjectHash)>,
+}
+
+
+/// Every file and symlink beneath `root`, relative to it, with its metadata.
+fn walk(root: &Path) -> Result<Vec<(PathBuf, Metadata)>> {
+    let mut files = Vec::new();
+    let mut stack = vec![PathBuf::new()];
+
+    while let Some(dir) = stack.pop() {
+        let full_dir = root.join(&dir);
+        for entry_res in fs::read_dir(&full_dir)
+            .chain_err(|| format!("could not read {}", full_dir.display()))?
+        {
+            let entry = entry_res?;
+            let path = dir.join(entry.file_name());
+            let metadata = entry.path().symlink_metadata()?;
+            let file_type = metadata.file_type();
+
+            if file_type.is_dir() {
+                stack.push(path);
+            } else if file_type.is_file() || file_type.is_symlink() {
+                files.push((path, metadata));
+            } else {
+                warning::warn(format!("skipping {}, which is not a regular file", path.display()));
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(files)
+}
+
+
+/// The contents of the file at `path`: its data, or the target of a symlink.
+fn read_contents(path: &Path, metadata: &Metadata) -> Result<ArcSlice> {
+    if metadata.file_type().is_symlink() {
+        Ok(arc_slice::owned(fs::read_link(path)?.into_os_string().into_vec()))
+    } else if metadata.len() == 0 {
+        // An empty file cannot be mapped.
+        Ok(arc_slice::owned(Vec::new()))
+    } else {
+        Ok(arc_slice::mapped(Mmap::open_path(path, Protection::Read)?))
+    }
+}
+
+
+/// Commit each of `snapshots` in turn, the first on top of `parent`. Returns what became of each
+/// snapshot, in order; the commit of the last is the head of the new history.
+pub fn ingest<T: Trace>(
+    marshaller: &Marshaller<T>,
+    chunkers: &ChunkerSet,
+    options: &IngestOptions,
+    parent: Option<ObjectHash>,
+    snapshots: Vec<Snapshot>,
+) -> Box<Future<Item = Vec<Ingested>, Error = Error> + Send> {
+    let marshaller = marshaller.clone();
+    let chunkers = chunkers.clone();
+    let options = options.clone();
+
+    let result = {
+        async_block! {
+            let mut seen = Seen::default();
+            let mut parent = parent;
+            let mut ingested = Vec::new();
+
+            for snapshot in snapshots {
+                let Snapshot { path: root, message, timestamp } = snapshot;
+                let files = walk(&root)?;
+
+                let mut paths = HashMap::new();
+                let mut entries = BTreeMap::new();
+                let (mut bytes, mut reused_files, mut reused_bytes) = (0, 0, 0);
+
+                for (path, metadata) in files {
+                    let stat = Stat::new(&metadata);
+                    let inode = (metadata.dev(), metadata.ino());
+                    let ctime = (metadata.ctime(), metadata.ctime_nsec());
+
+                    let by_inode = match seen.inodes.get(&inode) {
+                        Some(&(seen_stat, ctime_sec, ctime_nsec, object_hash))
+                            if seen_stat == stat && (ctime_sec, ctime_nsec) == ctime => {
+                            Some(object_hash)
+                        }
+                        _ => None,
+                    };
+                    let by_path = match seen.paths.get(&path) {
+                        Some(&(seen_stat, object_hash))
+                            if options.trust_mtimes && seen_stat == stat => Some(object_hash),
+                        _ => None,
+                    };
+
+                    let object_hash = match by_inode.or(by_path) {
+                        Some(object_hash) => {
+                            reused_files += 1;
+                            reused_bytes += stat.size;
+                            object_hash
+                        }
+                        None => {
+                            let contents = read_contents(&root.join(&path), &metadata)?;
+                            let chunks: Box<Stream<Item = ArcSlice, Error = Error> + Send> =
+                                if metadata.file_type().is_symlink() {
+                                    Box::new(stream::once(Ok(contents)))
+                                } else {
+                                    chunkers.for_path(&path).chunk(contents)
+                                };
+
+                            await!(marshaller.process_chunks(chunks))?
+                        }
+                    };
+
+                    bytes += stat.size;
+                    seen.inodes.insert(inode, (stat, ctime.0, ctime.1, object_hash));
+                    paths.insert(path.clone(), (stat, object_hash));
+
+                    let entry = SubtreeEntry::from_mode(object_hash, stat.size, metadata.mode());
+                    let entry = options.metadata.annotate(
+                        entry,
+                        metadata.mtime(),
+                        metadata.uid(),
+                        metadata.gid(),
+                    );
+                    entries.insert(path, entry);
+                }
+
+                // Only the snapshot just before is compared against by path.
+                seen.paths = paths;
+
+                let files = entries.len() as u64;
+                let tree = entries.into_iter().collect::<Tree>();
+                let subtree = await!(marshaller.process_tree(tree))?;
+                let commit_object = CommitObject {
+                    subtree,
+                    parents: parent.into_iter().collect(),
+                    message,
+                    timestamp,
+                    signature: None,
+                    author: options.author.clone(),
+                    committer: options.committer.clone(),
+                };
+                let commit = await!(marshaller.process(commit_object))?;
+
+                parent = Some(commit);
+                ingested.push(Ingested { commit, files, bytes, reused_files, reused_bytes });
+            }
+
+            Ok(ingested)
+        }
+    };
+
+    Box::new(result)
+}
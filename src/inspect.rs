@@ -0,0 +1,214 @@
+//! # `inspect` - describe stored objects, for debugging the contents of stores.
+//!
+//! `describe` takes an object exactly as a store holds it - possibly compressed or sealed, if it
+//! came from a remote - and reports how it is encoded, what kind of object it is, and which
+//! objects it refers to. Sealed objects cannot be decoded without their key, so only their
+//! references, which are kept in the clear, are reported.
+
+use std::fmt::{self, Write};
+
+use arc_slice;
+use errors::*;
+use marshal::{ObjectHash, Object, DataObject, SubtreeEntry};
+use marshal::canonical::{self, Version};
+use marshal::sealed;
+use store;
+
+
+/// How an object is encoded in a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Encoded with the given format version, and compressed if `compressed` is set.
+    Canonical { version: Version, compressed: bool },
+
+    /// Sealed in an encrypted envelope. See `marshal::sealed`.
+    Sealed,
+}
+
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Encoding::Canonical { version, compressed } => {
+                write!(f, "{:?}", version)?;
+                if compressed {
+                    write!(f, ", zstd-compressed")?;
+                }
+                Ok(())
+            }
+            Encoding::Sealed => write!(f, "sealed"),
+        }
+    }
+}
+
+
+/// A description of a stored object.
+#[derive(Debug, Clone)]
+pub struct Description {
+    pub hash: ObjectHash,
+    pub encoding: Encoding,
+
+    /// The number of bytes the store holds for the object.
+    pub stored_size: u64,
+
+    /// The hashes of the objects this object refers to directly.
+    pub refs: Vec<ObjectHash>,
+
+    /// The decoded object, unless it is sealed.
+    pub object: Option<Object>,
+}
+
+
+impl Description {
+    /// The kind of the object, or `"sealed"` if that cannot be known.
+    pub fn kind(&self) -> &'static str {
+        match self.object {
+            Some(Object::Data(DataObject::Small(_))) => "small data",
+            Some(Object::Data(DataObject::Large(_))) => "large data",
+            Some(Object::Subtree(_)) => "subtree",
+            Some(Object::Commit(_)) => "commit",
+            None => "sealed",
+        }
+    }
+
+    /// The number of bytes of file data the object holds, if it is a data object.
+    pub fn data_size(&self) -> Option<u64> {
+        match self.object {
+            Some(Object::Data(DataObject::Small(ref small_object))) => Some(small_object.size()),
+            Some(Object::Data(DataObject::Large(ref large_object))) => Some(large_object.size()),
+            _ => None,
+        }
+    }
+}
+
+
+/// Describe an object from the bytes a store holds for it.
+pub fn describe(hash: ObjectHash, stored: &[u8]) -> Result<Description> {
+    if sealed::is_sealed(stored) {
+        return Ok(Description {
+            hash,
+            encoding: Encoding::Sealed,
+            stored_size: stored.len() as u64,
+            refs: sealed::stored_refs(stored)?,
+            object: None,
+        });
+    }
+
+    let bytes = store::decompress_stored(stored.to_vec())?;
+    let compressed = bytes[..] != stored[..];
+    let (version, _) = canonical::decode(&bytes)?;
+    let object = Object::from_bytes(arc_slice::owned(bytes))?;
+
+    Ok(Description {
+        hash,
+        encoding: Encoding::Canonical { version, compressed },
+        stored_size: stored.len() as u64,
+        refs: object.refs(),
+        object: Some(object),
+    })
+}
+
+
+fn write_entry<W: Write>(out: &mut W, entry: &SubtreeEntry) -> fmt::Result {
+    match *entry {
+        SubtreeEntry::File(hash, size) => write!(out, "file       {} {:>12}", hash, size),
+        SubtreeEntry::Executable(hash, size) => write!(out, "executable {} {:>12}", hash, size),
+        SubtreeEntry::Symlink(hash) => write!(out, "symlink    {} {:>12}", hash, ""),
+        SubtreeEntry::Subtree(hash) => write!(out, "subtree    {} {:>12}", hash, ""),
+        SubtreeEntry::Subrepository(ref url, hash) => {
+            write!(out, "subrepo    {} {:>12} {}", hash, "", url)
+        }
+        SubtreeEntry::Annotated(entry, ref metadata) => {
+            write_entry(out, &SubtreeEntry::from(entry))?;
+            write!(out, " mtime={}", metadata.mtime)?;
+            if let Some((uid, gid)) = metadata.owner {
+                write!(out, " owner={}:{}", uid, gid)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+
+/// Pretty-print the structure of an object: the entries of a subtree, the fields of a commit, or
+/// the children of a large data object. Small data objects have no structure to print.
+pub fn pretty<W: Write>(out: &mut W, object: &Object) -> fmt::Result {
+    match *object {
+        Object::Data(DataObject::Small(_)) => Ok(()),
+        Object::Data(DataObject::Large(ref large_object)) => {
+            for &(size, hash) in &large_object.children {
+                writeln!(out, "chunk {} {:>12}", hash, size)?;
+            }
+            Ok(())
+        }
+        Object::Subtree(ref subtree_object) => {
+            for (name, entry) in &subtree_object.entries {
+                write_entry(out, entry)?;
+                writeln!(out, "\t{}", name.to_string_lossy())?;
+            }
+            Ok(())
+        }
+        Object::Commit(ref commit_object) => {
+            writeln!(out, "subtree   {}", commit_object.subtree)?;
+            for parent in &commit_object.parents {
+                writeln!(out, "parent    {}", parent)?;
+            }
+            if let Some(ref author) = commit_object.author {
+                writeln!(out, "author    {}", author)?;
+            }
+            if let Some(ref committer) = commit_object.committer {
+                writeln!(out, "committer {}", committer)?;
+            }
+            writeln!(out, "timestamp {}", commit_object.timestamp.to_rfc3339())?;
+            writeln!(
+                out,
+                "signature {}",
+                if commit_object.signature.is_some() { "present" } else { "none" }
+            )?;
+            writeln!(out, "")?;
+            for line in commit_object.message.lines() {
+                writeln!(out, "    {}", line)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use chrono::prelude::*;
+
+    use marshal::{self, CommitObject, SubtreeObject};
+
+    #[test]
+    fn describe_commit() {
+        let subtree = Object::Subtree(SubtreeObject { entries: BTreeMap::new() });
+        let subtree_hash = marshal::hash(&subtree);
+        let commit = Object::Commit(CommitObject {
+            subtree: subtree_hash,
+            parents: Vec::new(),
+            message: "Initial commit".to_owned(),
+            timestamp: Utc.timestamp(0, 0),
+            signature: None,
+            author: None,
+            committer: None,
+        });
+        let (hash, bytes_opt) = marshal::serialize_and_hash_with(&commit, Version::CURRENT)
+            .into_components();
+        let bytes = bytes_opt.unwrap();
+
+        let description = describe(hash, &bytes).unwrap();
+        assert_eq!(description.kind(), "commit");
+        assert_eq!(description.refs, vec![subtree_hash]);
+        assert_eq!(description.stored_size, bytes.len() as u64);
+
+        let mut out = String::new();
+        pretty(&mut out, description.object.as_ref().unwrap()).unwrap();
+        assert!(out.contains("Initial commit"));
+    }
+}
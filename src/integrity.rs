@@ -0,0 +1,129 @@
+//! # `integrity` - continuous, sampled verification of stored objects.
+//!
+//! When a repository's config sets `integrity_sample_percent`, that percentage of the objects
+//! read from the local store have their hashes recomputed and compared against the hash they
+//! were read under. This is far cheaper than a full `fsck`, but since it happens during ordinary
+//! use, silent corruption of the store is eventually noticed without ever scheduling one.
+//!
+//! Mismatches are not treated as errors by the read itself; instead they are appended to
+//! `.attaca/corruption.jsonl`, one JSON object per line, and reported by `attaca doctor`.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+use chrono::prelude::*;
+use rand;
+use serde_json;
+
+use errors::*;
+use marshal::{self, Object, ObjectHash};
+use repository::Paths;
+
+
+/// A single object found to be corrupt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorruptionRecord {
+    /// The hash the object was read under.
+    pub expected: ObjectHash,
+
+    /// The hash of what was actually read.
+    pub actual: ObjectHash,
+
+    /// When the corruption was detected.
+    pub detected: DateTime<Utc>,
+}
+
+
+/// Decide whether to verify a read, given the percentage of reads to verify.
+pub fn should_sample(percent: f64) -> bool {
+    percent > 0.0 && (percent >= 100.0 || rand::random::<f64>() * 100.0 < percent)
+}
+
+
+/// Verify an object read under `expected`, logging it if it is corrupt. Returns whether the
+/// object was intact.
+pub fn verify(paths: &Paths, expected: ObjectHash, object: &Object) -> Result<bool> {
+    let actual = marshal::hash(object);
+
+    if actual == expected {
+        return Ok(true);
+    }
+
+    record(
+        paths,
+        &CorruptionRecord {
+            expected,
+            actual,
+            detected: Utc::now(),
+        },
+    )?;
+
+    Ok(false)
+}
+
+
+/// Append a record to a repository's corruption log.
+pub fn record(paths: &Paths, record: &CorruptionRecord) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(
+        &paths.corruption,
+    )?;
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+
+    Ok(())
+}
+
+
+/// Read every record in a repository's corruption log. Lines which cannot be parsed are skipped.
+pub fn records(paths: &Paths) -> Result<Vec<CorruptionRecord>> {
+    if !paths.corruption.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut records = Vec::new();
+    for line_res in BufReader::new(File::open(&paths.corruption)?).lines() {
+        if let Ok(record) = serde_json::from_str(&line_res?) {
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+
+/// Remove the records of the given objects from a repository's corruption log, once they have
+/// been repaired.
+pub fn forget(paths: &Paths, repaired: &HashSet<ObjectHash>) -> Result<()> {
+    let remaining = records(paths)?
+        .into_iter()
+        .filter(|record| !repaired.contains(&record.expected))
+        .collect::<Vec<_>>();
+    if remaining.is_empty() {
+        return clear(paths);
+    }
+
+    let temp_path = paths.corruption.with_extension("jsonl.tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        for record in &remaining {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+    }
+    fs::rename(&temp_path, &paths.corruption)?;
+
+    Ok(())
+}
+
+
+/// Delete a repository's corruption log.
+pub fn clear(paths: &Paths) -> Result<()> {
+    if paths.corruption.exists() {
+        fs::remove_file(&paths.corruption)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! # `ipc` - length-prefixed message framing for local inter-process communication.
+//!
+//! Messages are serialized with `bincode` and prefixed with their length as a little-endian
+//! `u64`. This is used for communicating with long-running local helper processes over Unix
+//! sockets.
+
+use std::io::{self, Read, Write};
+
+use bincode;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use errors::*;
+
+
+/// The largest message we are willing to read. Anything larger is assumed to be garbage.
+const MAX_MESSAGE_SIZE: u64 = 1 << 28;
+
+
+/// Serialize and write a single message.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let bytes = bincode::serialize(message, bincode::Infinite)?;
+    let len = bytes.len() as u64;
+    let mut len_bytes = [0u8; 8];
+
+    for (i, byte) in len_bytes.iter_mut().enumerate() {
+        *byte = (len >> (i * 8)) as u8;
+    }
+
+    writer.write_all(&len_bytes)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+
+/// Read and deserialize a single message. Returns `None` if the stream was closed cleanly before
+/// the start of a message.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len_bytes = [0u8; 8];
+
+    match reader.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => bail!(err),
+    }
+
+    let len = len_bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    ensure!(len <= MAX_MESSAGE_SIZE, ErrorKind::IpcMessageTooLarge(len));
+
+    // The buffer only grows as the message arrives, so a peer cannot make us allocate the whole
+    // length it claims without sending it.
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        bail!(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated IPC message"));
+    }
+
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
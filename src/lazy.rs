@@ -0,0 +1,568 @@
+//! # `lazy` - on-demand access to the contents of data objects.
+//!
+//! A lazy checkout writes placeholder files in place of file contents: each placeholder has the
+//! correct size but no data, and the hash of the data object it stands in for is recorded in
+//! `.attaca/placeholders.bin`, along with its modification time. A placeholder which has since
+//! been written to is no longer taken for one, and is committed like any other file.
+//!
+//! The contents of a placeholder may later be "hydrated" in full, or read piecemeal through a
+//! `ChunkedReader`, which only fetches those chunks which overlap the regions actually read.
+//! Hydrated files are remembered in `.attaca/materialized.bin`, so that they can be turned back
+//! into placeholders to save space; see the `evict` module.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use bincode;
+use chrono::prelude::*;
+use futures::prelude::*;
+
+use errors::*;
+use marshal::{ObjectHash, Object, DataObject, SmallObject, LargeObject};
+use repository::Paths;
+use store::{ObjectStore, RangeStore};
+
+
+/// Read the bytes of a data object in the range `[offset, offset + len)`, fetching only those
+/// chunks which overlap the range. If the range extends past the end of the object, the result is
+/// truncated.
+pub fn read_range<S: ObjectStore>(
+    store: &S,
+    object_hash: ObjectHash,
+    offset: u64,
+    len: u64,
+) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let range_end = offset + len;
+            let mut buf = vec![0u8; len as usize];
+            let mut filled = 0u64;
+
+            // Each element of the stack is an object hash along with the offset of its first
+            // byte within the root object.
+            let mut stack = vec![(object_hash, 0u64)];
+
+            while let Some((hash, base)) = stack.pop() {
+                match await!(store.read_object(hash))? {
+                    Object::Data(DataObject::Small(small_object)) => {
+                        let chunk_end = base + small_object.size();
+                        let start = cmp::max(base, offset);
+                        let end = cmp::min(chunk_end, range_end);
+
+                        if start < end {
+                            let dst = &mut buf[(start - offset) as usize..(end - offset) as usize];
+                            dst.copy_from_slice(
+                                &small_object.chunk[(start - base) as usize..(end - base) as usize],
+                            );
+                            filled = cmp::max(filled, end - offset);
+                        }
+                    }
+                    Object::Data(DataObject::Large(large_object)) => {
+                        let mut child_base = base;
+
+                        for (size, child_hash) in large_object.children {
+                            if child_base < range_end && child_base + size > offset {
+                                stack.push((child_hash, child_base));
+                            }
+
+                            child_base += size;
+                        }
+                    }
+                    _ => bail!(ErrorKind::ObjectNotData(hash)),
+                }
+            }
+
+            buf.truncate(filled as usize);
+
+            Ok(buf)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// A read-only, seekable view of a data object which fetches chunks from its store only when the
+/// regions containing them are read.
+///
+/// Large objects read on the way down to a chunk are kept, so that finding the chunk at any
+/// offset costs at most one fetch per level of the object's tree the first time and none after.
+/// The chunk most recently read is kept too, so that reading sequentially in small pieces fetches
+/// each chunk only once.
+#[derive(Debug)]
+pub struct ChunkedReader<S: ObjectStore> {
+    store: S,
+    object_hash: ObjectHash,
+    size: u64,
+    position: u64,
+    large_objects: HashMap<ObjectHash, LargeObject>,
+
+    /// The chunk most recently read, along with the offset of its first byte.
+    current: Option<(u64, SmallObject)>,
+}
+
+
+impl<S: ObjectStore> ChunkedReader<S> {
+    /// A reader over the data object `object_hash`, which is `size` bytes long. Nothing is
+    /// fetched until the first read.
+    pub fn new(store: &S, object_hash: ObjectHash, size: u64) -> Self {
+        ChunkedReader {
+            store: store.clone(),
+            object_hash,
+            size,
+            position: 0,
+            large_objects: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// A reader over the data object `object_hash`, which has already been read as
+    /// `data_object`.
+    pub fn from_object(store: &S, object_hash: ObjectHash, data_object: DataObject) -> Self {
+        let mut reader = Self::new(store, object_hash, data_object.size());
+
+        match data_object {
+            DataObject::Small(small_object) => reader.current = Some((0, small_object)),
+            DataObject::Large(large_object) => {
+                reader.large_objects.insert(object_hash, large_object);
+            }
+        }
+
+        reader
+    }
+
+    /// A reader over the data object `object_hash`, fetching it to find its size.
+    pub fn open(store: &S, object_hash: ObjectHash) -> Result<Self> {
+        match store.read_object(object_hash).wait()? {
+            Object::Data(data_object) => Ok(Self::from_object(store, object_hash, data_object)),
+            _ => bail!(ErrorKind::ObjectNotData(object_hash)),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+
+impl<S: RangeStore> ChunkedReader<S> {
+    /// Fill `buf` with the bytes starting at `offset`, without changing the current position.
+    /// Returns the number of bytes read, which is less than the length of `buf` only at the end
+    /// of the object.
+    ///
+    /// Where only part of a chunk is needed, the store is first asked for just that part; chunks
+    /// read this way are not kept.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let end = cmp::min(offset.saturating_add(buf.len() as u64), self.size);
+        let mut position = offset;
+
+        while position < end {
+            if let Some((base, ref chunk)) = self.current {
+                if base <= position && position < base + chunk.size() {
+                    let chunk_end = cmp::min(base + chunk.size(), end);
+                    let src = &chunk.chunk[(position - base) as usize..(chunk_end - base) as usize];
+                    buf[(position - offset) as usize..(chunk_end - offset) as usize]
+                        .copy_from_slice(src);
+                    position = chunk_end;
+                    continue;
+                }
+            }
+
+            let (base, size, hash) = self.node_at(position)?;
+            let node_end = cmp::min(base + size, end);
+
+            // The node may yet turn out to be a large object, in which case the store finds as
+            // much and it is read whole below.
+            if position > base || node_end < base + size {
+                let range = self.store
+                    .read_range(hash, position - base, node_end - position)
+                    .wait()?;
+
+                if let Some(bytes) = range {
+                    if bytes.len() as u64 == node_end - position {
+                        buf[(position - offset) as usize..(node_end - offset) as usize]
+                            .copy_from_slice(&bytes);
+                        position = node_end;
+                        continue;
+                    }
+                }
+            }
+
+            match self.store.read_object(hash).wait()? {
+                Object::Data(DataObject::Small(small_object)) => {
+                    if small_object.size() != size {
+                        bail!(ErrorKind::TruncatedObject(hash));
+                    }
+
+                    self.current = Some((base, small_object));
+                }
+                Object::Data(DataObject::Large(large_object)) => {
+                    self.large_objects.insert(hash, large_object);
+                }
+                _ => bail!(ErrorKind::ObjectNotData(hash)),
+            }
+        }
+
+        Ok(position.saturating_sub(offset) as usize)
+    }
+
+    /// The deepest node of the object's tree containing the byte at `offset` which has not yet
+    /// been read, along with the offset of its first byte and its size.
+    fn node_at(&self, offset: u64) -> Result<(u64, u64, ObjectHash)> {
+        let mut hash = self.object_hash;
+        let mut base = 0;
+        let mut size = self.size;
+
+        while let Some(large_object) = self.large_objects.get(&hash) {
+            let mut child_base = base;
+            let mut child = None;
+
+            for &(child_size, child_hash) in &large_object.children {
+                if offset < child_base + child_size {
+                    child = Some((child_base, child_size, child_hash));
+                    break;
+                }
+
+                child_base += child_size;
+            }
+
+            match child {
+                Some((child_base, child_size, child_hash)) => {
+                    base = child_base;
+                    size = child_size;
+                    hash = child_hash;
+                }
+                None => bail!(ErrorKind::TruncatedObject(hash)),
+            }
+        }
+
+        Ok((base, size, hash))
+    }
+}
+
+
+impl<S: RangeStore> Read for ChunkedReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        let read = self.read_at(position, buf).map_err(|err| {
+            io::Error::new(io::ErrorKind::Other, err.to_string())
+        })?;
+
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+
+impl<S: ObjectStore> Seek for ChunkedReader<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.size as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot seek before the start of a file",
+            ));
+        }
+
+        self.position = position as u64;
+
+        Ok(self.position)
+    }
+}
+
+
+/// A placeholder file, as it was when written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlaceholderFile {
+    pub object_hash: ObjectHash,
+    pub size: u64,
+
+    /// The modification time of the placeholder, in seconds and nanoseconds. Placeholders
+    /// recorded before modification times were have none, and are judged by their size alone.
+    mtime: Option<(i64, i64)>,
+}
+
+
+impl PlaceholderFile {
+    /// Whether a file with the given metadata is still the placeholder as it was written, rather
+    /// than a file which has since been written to.
+    pub fn is_unmodified(&self, metadata: &fs::Metadata) -> bool {
+        let mtime_matches = match self.mtime {
+            Some((mtime, mtime_nsec)) => {
+                metadata.mtime() == mtime && metadata.mtime_nsec() == mtime_nsec
+            }
+            None => true,
+        };
+
+        metadata.is_file() && metadata.size() == self.size && mtime_matches
+    }
+}
+
+
+/// The set of placeholder files in a working directory, keyed by path relative to the repository
+/// root, along with the data objects they stand in for.
+#[derive(Debug, Clone, Default)]
+pub struct Placeholders {
+    entries: BTreeMap<PathBuf, PlaceholderFile>,
+}
+
+
+impl Placeholders {
+    pub fn open(paths: &Paths) -> Result<Self> {
+        if !paths.placeholders.exists() {
+            return Ok(Placeholders::default());
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&paths.placeholders)?.read_to_end(&mut bytes)?;
+        let entries = match bincode::deserialize(&bytes) {
+            Ok(entries) => entries,
+            Err(_) => {
+                let entries: BTreeMap<PathBuf, (ObjectHash, u64)> = bincode::deserialize(&bytes)
+                    .chain_err(|| ErrorKind::PlaceholdersParse(paths.placeholders.clone()))?;
+
+                entries
+                    .into_iter()
+                    .map(|(path, (object_hash, size))| {
+                        let placeholder = PlaceholderFile {
+                            object_hash,
+                            size,
+                            mtime: None,
+                        };
+                        (path, placeholder)
+                    })
+                    .collect()
+            }
+        };
+
+        Ok(Placeholders { entries })
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        if self.entries.is_empty() {
+            if paths.placeholders.exists() {
+                fs::remove_file(&paths.placeholders)?;
+            }
+        } else {
+            let mut file = File::create(&paths.placeholders)?;
+            bincode::serialize_into(&mut file, &self.entries, bincode::Infinite)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record that the file at `path` has just been written as a placeholder for the given data
+    /// object. `metadata` is that of the placeholder once written.
+    pub fn insert<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        object_hash: ObjectHash,
+        metadata: &fs::Metadata,
+    ) {
+        self.entries.insert(
+            path.as_ref().to_owned(),
+            PlaceholderFile {
+                object_hash,
+                size: metadata.size(),
+                mtime: Some((metadata.mtime(), metadata.mtime_nsec())),
+            },
+        );
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<PlaceholderFile> {
+        self.entries.get(path.as_ref()).cloned()
+    }
+
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.entries.contains_key(path.as_ref())
+    }
+
+    /// Whether the file at `path`, relative to the repository root `base`, is a placeholder which
+    /// has not been written to since it was recorded.
+    pub fn is_unmodified<P: AsRef<Path>>(&self, base: &Path, path: P) -> bool {
+        match self.get(&path) {
+            Some(placeholder) => {
+                base.join(path)
+                    .symlink_metadata()
+                    .map(|metadata| placeholder.is_unmodified(&metadata))
+                    .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    /// Forget a placeholder, for example because it has been hydrated or overwritten.
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> Option<PlaceholderFile> {
+        self.entries.remove(path.as_ref())
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a Path, ObjectHash, u64)> + 'a {
+        self.entries.iter().map(|(path, placeholder)| {
+            (path.as_ref(), placeholder.object_hash, placeholder.size)
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+
+/// A file hydrated from a placeholder, as it was when hydrated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaterializedFile {
+    pub object_hash: ObjectHash,
+    pub size: u64,
+
+    /// When the file was hydrated.
+    pub hydrated: DateTime<Utc>,
+
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+
+impl MaterializedFile {
+    /// Whether a file with the given metadata is still exactly as it was hydrated, and so can be
+    /// turned back into a placeholder without losing anything.
+    pub fn is_unmodified(&self, metadata: &fs::Metadata) -> bool {
+        metadata.is_file() && metadata.size() == self.size && metadata.mtime() == self.mtime &&
+            metadata.mtime_nsec() == self.mtime_nsec
+    }
+
+    /// When the file was last used, judging by its access time. Filesystems mounted with
+    /// `noatime` never update access times, and those mounted with `relatime` (the default on
+    /// Linux) update them at most daily, so this is only a rough guide.
+    pub fn last_used(&self, metadata: &fs::Metadata) -> DateTime<Utc> {
+        let accessed = Utc.timestamp(metadata.atime(), metadata.atime_nsec() as u32);
+
+        if accessed > self.hydrated {
+            accessed
+        } else {
+            self.hydrated
+        }
+    }
+}
+
+
+/// The set of files in a working directory hydrated from placeholders, keyed by path relative to
+/// the repository root.
+#[derive(Debug, Clone, Default)]
+pub struct Materialized {
+    entries: BTreeMap<PathBuf, MaterializedFile>,
+}
+
+
+impl Materialized {
+    pub fn open(paths: &Paths) -> Result<Self> {
+        if !paths.materialized.exists() {
+            return Ok(Materialized::default());
+        }
+
+        let mut file = File::open(&paths.materialized)?;
+        let entries = bincode::deserialize_from(&mut file, bincode::Infinite)
+            .chain_err(|| ErrorKind::MaterializedParse(paths.materialized.clone()))?;
+
+        Ok(Materialized { entries })
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        if self.entries.is_empty() {
+            if paths.materialized.exists() {
+                fs::remove_file(&paths.materialized)?;
+            }
+        } else {
+            let mut file = File::create(&paths.materialized)?;
+            bincode::serialize_into(&mut file, &self.entries, bincode::Infinite)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record that the file at `path` has just been hydrated with the contents of the given data
+    /// object. `metadata` is that of the file once written.
+    pub fn insert<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        object_hash: ObjectHash,
+        metadata: &fs::Metadata,
+    ) {
+        self.entries.insert(
+            path.as_ref().to_owned(),
+            MaterializedFile {
+                object_hash,
+                size: metadata.size(),
+                hydrated: Utc::now(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+            },
+        );
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<MaterializedFile> {
+        self.entries.get(path.as_ref()).cloned()
+    }
+
+    /// Forget a hydrated file, for example because it has been evicted or overwritten.
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> Option<MaterializedFile> {
+        self.entries.remove(path.as_ref())
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a Path, &'a MaterializedFile)> + 'a {
+        self.entries.iter().map(|(path, file)| (path.as_ref(), file))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::Scratch;
+    use marshal;
+
+    #[test]
+    fn written_placeholders_are_modified() {
+        use std::fs::OpenOptions;
+        use std::io::Write;
+
+        let scratch = Scratch::new("attaca-placeholder").unwrap();
+        let base = scratch.path();
+        let name = "placeholder";
+        let file = File::create(base.join(&name)).unwrap();
+        file.set_len(16).unwrap();
+
+        let object_hash = marshal::hash(&Object::Data(DataObject::Large(LargeObject {
+            size: 16,
+            children: Vec::new(),
+        })));
+        let mut placeholders = Placeholders::default();
+        let metadata = fs::symlink_metadata(base.join(&name)).unwrap();
+        placeholders.insert(&name, object_hash, &metadata);
+        assert!(placeholders.is_unmodified(&base, &name));
+
+        let mut file = OpenOptions::new().append(true).open(base.join(&name)).unwrap();
+        file.write_all(b"edited").unwrap();
+        assert!(!placeholders.is_unmodified(&base, &name));
+    }
+}
@@ -11,9 +11,6 @@ compile_error!(
 #[cfg(test)]
 extern crate histogram;
 
-#[cfg(test)]
-extern crate rand;
-
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
@@ -21,41 +18,108 @@ extern crate quickcheck;
 extern crate bincode;
 extern crate chrono;
 extern crate digest_writer;
+extern crate ed25519_dalek;
 #[macro_use]
 extern crate error_chain;
 extern crate futures_await as futures;
 extern crate futures_bufio;
 extern crate futures_cpupool;
 extern crate generic_array;
+#[cfg(feature = "git")]
+extern crate git2;
 extern crate globset;
 extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
 extern crate libc;
 extern crate memmap;
+#[cfg(feature = "watch")]
+extern crate notify;
 extern crate owning_ref;
 extern crate qp_trie;
+#[cfg(feature = "rados")]
 extern crate rad;
+extern crate rand;
+extern crate ring;
 extern crate seahash;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate sequence_trie;
+extern crate sha2;
 extern crate sha3;
+#[cfg(feature = "ssh")]
 extern crate ssh2;
 extern crate stable_deref_trait;
 extern crate toml;
 extern crate typenum;
+extern crate unicode_normalization;
+extern crate zstd;
 
 pub mod arc_slice;
+pub mod archive;
+pub mod backup;
+pub mod backrefs;
+pub mod bench;
+pub mod blocklist;
+pub mod branch_metadata;
+pub mod cache;
+pub mod cancel;
 pub mod catalog;
+pub mod checkout;
+pub mod chunker;
+#[cfg(test)]
+mod concurrency;
 pub mod context;
+pub mod daemon;
+pub mod dataset;
 pub mod errors;
+pub mod evict;
+pub mod export;
+pub mod fault;
+pub mod filter;
+#[cfg(feature = "git")]
+pub mod git;
+pub mod graph;
+pub mod hash_cache;
+pub mod history;
+pub mod hooks;
+pub mod identity;
+pub mod import;
 pub mod index;
+pub mod ingest;
+pub mod inspect;
+pub mod integrity;
+pub mod ipc;
+pub mod lazy;
+pub mod lock;
 pub mod marshal;
+pub mod mirror;
+pub mod negotiation;
+pub mod notes;
+pub mod path_locks;
+pub mod profile;
+pub mod progress;
+pub mod promised;
+pub mod quarantine;
+pub mod recover;
+pub mod refcount;
 pub mod repository;
+pub mod search;
+pub mod shallow;
+pub mod sign;
+pub mod sparse;
 pub mod split;
 pub mod store;
+pub mod subrepository;
+pub mod sync;
+pub mod telemetry;
 pub mod trace;
+pub mod warning;
+#[cfg(feature = "watch")]
+pub mod watch;
+pub mod worktree;
 
 pub use errors::*;
 pub use repository::Repository;
@@ -73,6 +137,23 @@ const BATCH_FUTURE_BUFFER_SIZE: usize = 64;
 const WRITE_FUTURE_BUFFER_SIZE: usize = 64;
 
 
+/// Controls how long to wait for another process to release a lock on the refs, the index, the
+/// config or a catalog, in milliseconds.
+const LOCK_TIMEOUT_MS: u64 = 10_000;
+
+
+/// Controls the default number of files which may be open for writing at once during checkout.
+const CHECKOUT_OPEN_FILES: usize = 16;
+
+
+/// Controls the default number of chunks of a single large file fetched at once during checkout.
+const CHECKOUT_CHUNK_WORKERS: usize = 8;
+
+
+/// Controls the default number of objects read ahead while walking a subtree or checking it out.
+const PREFETCH_WINDOW: usize = 32;
+
+
 lazy_static! {
     /// Controls the name of the "hidden" `.attaca` repository metadata directory.
     static ref METADATA_PATH: &'static Path = Path::new(".attaca");
@@ -82,6 +163,10 @@ lazy_static! {
     static ref CONFIG_PATH: PathBuf = METADATA_PATH.join("config.toml");
 
 
+    /// The location of the lock guarding updates to the config file.
+    static ref CONFIG_LOCK_PATH: PathBuf = METADATA_PATH.join("config.lock");
+
+
     /// The relative path of the blob directory within a repository.
     static ref BLOBS_PATH: PathBuf = METADATA_PATH.join("blobs");
 
@@ -98,10 +183,116 @@ lazy_static! {
     static ref INDEX_PATH: PathBuf = METADATA_PATH.join("index.bin");
 
 
+    /// The location of the lock guarding updates to the index file.
+    static ref INDEX_LOCK_PATH: PathBuf = METADATA_PATH.join("index.lock");
+
+
     /// The location of the HEAD file.
     static ref REFS_PATH: PathBuf = METADATA_PATH.join("refs.bin");
 
 
+    /// The location of the lock guarding updates to the refs file.
+    static ref REFS_LOCK_PATH: PathBuf = METADATA_PATH.join("refs.lock");
+
+
+    /// The location of the branch heads a mirror last refreshed to.
+    static ref MIRROR_PATH: PathBuf = METADATA_PATH.join("mirror.bin");
+
+
+    /// The location of the lock preventing overlapping mirror refreshes.
+    static ref MIRROR_LOCK_PATH: PathBuf = METADATA_PATH.join("mirror.lock");
+
+
+    /// The location of the lazy checkout placeholders file.
+    static ref PLACEHOLDERS_PATH: PathBuf = METADATA_PATH.join("placeholders.bin");
+
+    /// The location of the record of files hydrated from placeholders.
+    static ref MATERIALIZED_PATH: PathBuf = METADATA_PATH.join("materialized.bin");
+
+
+    /// The location of the list of objects skipped by a fetch restricted to some paths.
+    static ref PROMISED_PATH: PathBuf = METADATA_PATH.join("promised");
+
+
+    /// The location of the list of commits at which locally held history stops.
+    static ref SHALLOW_PATH: PathBuf = METADATA_PATH.join("shallow");
+
+
+    /// The location of the sparse checkout patterns file.
+    static ref SPARSE_PATH: PathBuf = METADATA_PATH.join("sparse");
+
+
+    /// The location of the opt-in telemetry journal.
+    static ref TELEMETRY_PATH: PathBuf = METADATA_PATH.join("telemetry.jsonl");
+
+
+    /// The location of the reverse reference index.
+    static ref BACKREFS_PATH: PathBuf = METADATA_PATH.join("backrefs.bin");
+
+
+    /// The location of the counts of references to each object.
+    static ref REFCOUNTS_PATH: PathBuf = METADATA_PATH.join("refcounts.bin");
+
+
+    /// The location of the lock taken while saving the reference counts.
+    static ref REFCOUNTS_LOCK_PATH: PathBuf = METADATA_PATH.join("refcounts.lock");
+
+
+    /// The location of the directory of packed objects.
+    static ref PACKS_PATH: PathBuf = METADATA_PATH.join("packs");
+
+
+    /// The location of objects set aside as corrupt.
+    static ref QUARANTINE_PATH: PathBuf = METADATA_PATH.join("quarantine");
+
+
+    /// The location of the worktree daemon's socket.
+    static ref DAEMON_SOCKET_PATH: PathBuf = METADATA_PATH.join("daemon.sock");
+
+
+    /// The location of the state of a rebase in progress.
+    static ref REBASE_PATH: PathBuf = METADATA_PATH.join("rebase.bin");
+
+
+    /// The location of the conflicts a merge or rebase stopped at.
+    static ref CONFLICTS_PATH: PathBuf = METADATA_PATH.join("conflicts.bin");
+
+
+    /// The location of objects written by commands which have not yet finished.
+    static ref STAGING_PATH: PathBuf = METADATA_PATH.join("staging");
+
+
+    /// The location of the search index over commit messages and paths.
+    static ref SEARCH_PATH: PathBuf = METADATA_PATH.join("search.bin");
+
+    /// The location of the cache of hashes of worktree files.
+    static ref HASH_CACHE_PATH: PathBuf = METADATA_PATH.join("hash-cache.bin");
+
+
+    /// The location of the stack of stashed changes.
+    static ref STASH_PATH: PathBuf = METADATA_PATH.join("stash.bin");
+
+
+    /// The location of the state of a bisection in progress.
+    static ref BISECT_PATH: PathBuf = METADATA_PATH.join("bisect.bin");
+
+
+    /// The location of the log of corrupt objects found by integrity sampling.
+    static ref CORRUPTION_PATH: PathBuf = METADATA_PATH.join("corruption.jsonl");
+
+
+    /// The location, in a linked worktree, of the path of the repository it belongs to.
+    static ref COMMONDIR_PATH: PathBuf = METADATA_PATH.join("commondir");
+
+
+    /// The location of a linked worktree's own HEAD.
+    static ref HEAD_PATH: PathBuf = METADATA_PATH.join("head.bin");
+
+
+    /// The location of the list of a repository's linked worktrees.
+    static ref WORKTREES_PATH: PathBuf = METADATA_PATH.join("worktrees.toml");
+
+
     /// Default paths to ignore.
     static ref DEFAULT_IGNORES: HashSet<PathBuf> = {
         let mut set = HashSet::new();
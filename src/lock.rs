@@ -0,0 +1,97 @@
+//! # `lock` - advisory lock files.
+//!
+//! A lock file is created exclusively, so that only one process may hold it at once, and is
+//! removed when the `LockFile` is dropped. The ID of the holding process is written into the file
+//! to aid in diagnosing stale locks.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use errors::*;
+
+
+/// How long to wait between attempts to acquire a contended lock.
+const LOCK_RETRY_INTERVAL_MS: u64 = 10;
+
+
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+}
+
+
+impl LockFile {
+    /// Attempt to acquire the lock at `path`, failing immediately if it is held.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                write!(file, "{}", unsafe { libc::getpid() })?;
+                Ok(LockFile { path: path.to_owned() })
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                bail!(ErrorKind::Locked(path.to_owned()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Attempt to acquire the lock at `path`, retrying until `timeout` has elapsed.
+    pub fn acquire_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self> {
+        let start = Instant::now();
+
+        loop {
+            match Self::acquire(path.as_ref()) {
+                Err(Error(ErrorKind::Locked(_), _)) if start.elapsed() < timeout => {
+                    thread::sleep(Duration::from_millis(LOCK_RETRY_INTERVAL_MS));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The ID of the process which holds the lock at `path`, if it is held and the ID was
+    /// written. A lock whose holder is no longer running was left behind by a crashed process.
+    pub fn holder<P: AsRef<Path>>(path: P) -> Result<Option<libc::pid_t>> {
+        let mut contents = String::new();
+
+        match File::open(path.as_ref()) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)?;
+                Ok(contents.trim().parse().ok())
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Release the lock. This is equivalent to dropping it.
+    pub fn release(self) {}
+}
+
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+/// Whether the process with the given ID is still running.
+pub fn is_alive(pid: libc::pid_t) -> bool {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
@@ -42,7 +42,7 @@ where
                 let blocking_hash = blocked.object_hash();
                 let entries = match await!(store.read_object(blocking_hash))? {
                     Object::Subtree(subtree_object) => subtree_object.entries,
-                    _ => bail!("Expected a subtree!"),
+                    _ => bail!(ErrorKind::ObjectNotASubtree(blocking_hash)),
                 };
 
                 entry_res = blocked.unblock(entries.into());
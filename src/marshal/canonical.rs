@@ -0,0 +1,369 @@
+//! `canonical` - versioned framing of encoded objects.
+//!
+//! Every object written to a store is framed with a two-byte header: the magic byte `0xA7`
+//! followed by a format version. Decoders dispatch on the version, and refuse versions they do not
+//! know with `ErrorKind::UnsupportedObjectVersion` rather than misinterpreting the payload.
+//!
+//! Objects written before framing was introduced have no header at all; they are decoded as
+//! `Version::Unframed`. The two are never confused, since an unframed object begins with the
+//! little-endian variant index of a `RawObject`, whose first byte is always small.
+//!
+//! The header is *not* part of an object's identity: an object's hash is always the hash of its
+//! unframed payload, so that the same object hashes identically whichever version it is framed
+//! with, and old objects keep their hashes.
+//!
+//! ## Versions
+//!
+//! * `Unframed` - the bare bincode encoding of a `RawObject`. `RawObject` has only ever gained
+//!   variants at its end, and its `Commit` variant still holds the original commit layout, so
+//!   objects written before framing decode with the schema they were written with.
+//! * `V1` - the bincode encoding of a `RawObject`, behind a header.
+//!
+//! The header byte `0x80` is reserved for objects sealed for storage on a remote, which must be
+//! opened before they can be decoded; see the `sealed` module.
+//!
+//! New versions must be appended to `Version`, given a byte in `Version::from_byte`/`to_byte`, and
+//! listed here along with what they change.
+
+use std::cmp;
+use std::io::{self, Write};
+
+use bincode;
+
+use errors::*;
+use marshal::{self, ObjectHash, RawObject};
+use marshal::sealed::SEALED;
+
+
+/// The first byte of every framed object.
+pub const MAGIC: u8 = 0xA7;
+
+
+/// A format version for encoded objects. Versions are ordered from oldest to newest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    Unframed,
+    V1,
+}
+
+
+/// Every version this build can decode, oldest first.
+pub const SUPPORTED: &[Version] = &[Version::Unframed, Version::V1];
+
+
+impl Version {
+    /// The version written unless configured otherwise.
+    pub const CURRENT: Version = Version::V1;
+
+    /// Look up a framed version by its header byte.
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            1 => Ok(Version::V1),
+            _ => bail!(ErrorKind::UnsupportedObjectVersion(byte)),
+        }
+    }
+
+    /// The header byte of this version, or `None` if it is written without a header.
+    pub fn to_byte(self) -> Option<u8> {
+        match self {
+            Version::Unframed => None,
+            Version::V1 => Some(1),
+        }
+    }
+
+    /// Choose the version to write when every reader is known to support at most `max`. Readers
+    /// which support newer versions can always read older ones, so this is simply the older of
+    /// the two.
+    pub fn negotiate(self, max: Version) -> Version {
+        cmp::min(self, max)
+    }
+
+    /// Choose the version to write when every reader is known to support versions up to the one
+    /// with the given header byte, where `0` stands for `Unframed`. Bytes newer than any version
+    /// this build knows of do not restrict it.
+    pub fn negotiate_byte(self, max: u8) -> Version {
+        match max {
+            0 => Version::Unframed,
+            _ => Version::from_byte(max).map(|max| self.negotiate(max)).unwrap_or(self),
+        }
+    }
+
+    /// The size of the header this version writes.
+    pub fn header_size(self) -> u64 {
+        match self.to_byte() {
+            Some(_) => 2,
+            None => 0,
+        }
+    }
+}
+
+
+/// Write the header for the given version.
+pub fn write_header<W: Write>(version: Version, writer: &mut W) -> Result<()> {
+    if let Some(byte) = version.to_byte() {
+        writer.write_all(&[MAGIC, byte])?;
+    }
+
+    Ok(())
+}
+
+
+/// Encode an object with the given version.
+pub fn encode(raw_object: &RawObject, version: Version) -> Result<Vec<u8>> {
+    let size = version.header_size() + bincode::serialized_size(raw_object);
+    let mut buf = Vec::with_capacity(size as usize);
+    write_header(version, &mut buf)?;
+    bincode::serialize_into(&mut buf, raw_object, bincode::Infinite)?;
+
+    Ok(buf)
+}
+
+
+/// Decode an object of any supported version, borrowing from `bytes`.
+pub fn decode(bytes: &[u8]) -> Result<(Version, RawObject)> {
+    let (version, payload) = match bytes.first() {
+        Some(&MAGIC) if bytes.len() >= 2 && bytes[1] == SEALED => bail!(ErrorKind::SealedObject),
+        Some(&MAGIC) if bytes.len() >= 2 => (Version::from_byte(bytes[1])?, &bytes[2..]),
+        Some(&MAGIC) => bail!(ErrorKind::UnsupportedObjectVersion(MAGIC)),
+        _ => (Version::Unframed, bytes),
+    };
+
+    match version {
+        Version::Unframed | Version::V1 => {
+            let raw_object = bincode::deserialize(payload)?;
+            Ok((version, raw_object))
+        }
+    }
+}
+
+
+/// Decode an object, if it decodes at all and hashes to `hash`. Whatever a store hands back under
+/// a hash is only to be trusted once it does.
+pub fn decode_verified(bytes: &[u8], hash: ObjectHash) -> Option<RawObject> {
+    let (_, raw_object) = decode(bytes).ok()?;
+
+    match marshal::serialize_into_and_hash(&raw_object, &mut io::sink()) {
+        Ok(actual) if actual == hash => Some(raw_object),
+        _ => None,
+    }
+}
+
+
+/// The number of bytes from the start of an encoded object which `small_chunk_span` needs.
+pub const SMALL_PREFIX_SIZE: usize = 18;
+
+
+/// Split the header from an encoded object of a supported version, returning the size of the
+/// header and the payload; `None` if the object is sealed or of an unknown version.
+fn split_header(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    match bytes.first() {
+        Some(&MAGIC) if bytes.len() >= 2 => {
+            match Version::from_byte(bytes[1]) {
+                Ok(version) => Some((version.header_size(), &bytes[2..])),
+                Err(_) => None,
+            }
+        }
+        Some(&MAGIC) => None,
+        _ => Some((0, bytes)),
+    }
+}
+
+
+/// Whether an encoded object is a data object, judging by its variant index alone. Sealed
+/// objects and objects of unknown versions are not.
+pub fn is_data(bytes: &[u8]) -> bool {
+    match split_header(bytes) {
+        Some((_, payload)) => payload.len() >= 4 && payload[..4] == [0, 0, 0, 0],
+        None => false,
+    }
+}
+
+
+/// Where the chunk of an encoded small data object begins, and how long it is, given at least
+/// its first `SMALL_PREFIX_SIZE` bytes; `None` if they are not the start of a small data object
+/// in a supported version.
+///
+/// A small data object is encoded as the variant indices of `RawObject::Data` and
+/// `RawDataObject::Small`, both zero, followed by the length of the chunk and then the chunk
+/// itself, so that a store which can read part of an object can read part of a chunk without
+/// fetching the rest of it.
+pub fn small_chunk_span(prefix: &[u8]) -> Option<(u64, u64)> {
+    let (header_size, payload) = split_header(prefix)?;
+
+    if payload.len() < 16 || payload[..8].iter().any(|&byte| byte != 0) {
+        return None;
+    }
+
+    let chunk_len = payload[8..16].iter().rev().fold(
+        0u64,
+        |len, &byte| (len << 8) | byte as u64,
+    );
+
+    Some((header_size + 16, chunk_len))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::borrow::Cow;
+
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use arc_slice;
+    use marshal::{AnnotatedEntry, CommitObject, EntryMetadata, Identity, LargeObject, Object,
+                  ObjectHash, SubtreeEntry};
+    use marshal::object::{RawDataObject, RawSmallObject};
+
+    /// A commit as it was encoded before commits could be signed or attributed.
+    #[derive(Serialize)]
+    struct BaselineCommit {
+        subtree: ObjectHash,
+        parents: Vec<ObjectHash>,
+        message: String,
+        timestamp: DateTime<Utc>,
+    }
+
+    /// `RawObject` as it was before commits could be signed or attributed; only the variant index
+    /// of the commit matters here.
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    enum BaselineRawObject {
+        Data(()),
+        Subtree(()),
+        Commit(BaselineCommit),
+    }
+
+    /// `SubtreeEntry` as it was when an annotation boxed the entry it annotated, which let any
+    /// entry be annotated.
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    enum BoxedSubtreeEntry {
+        File(ObjectHash, u64),
+        Subtree(ObjectHash),
+        Symlink(ObjectHash),
+        Executable(ObjectHash, u64),
+        Annotated(Box<BoxedSubtreeEntry>, EntryMetadata),
+    }
+
+    fn baseline_commit() -> (CommitObject, Vec<u8>) {
+        let subtree = "11".repeat(32).parse().unwrap();
+        let parents = vec!["22".repeat(32).parse().unwrap()];
+        let message = "a commit from before signatures".to_owned();
+        let timestamp = Utc.timestamp(1_500_000_000, 0);
+
+        let commit = CommitObject {
+            subtree,
+            parents: parents.clone(),
+            message: message.clone(),
+            timestamp,
+            signature: None,
+            author: None,
+            committer: None,
+        };
+        let baseline = BaselineRawObject::Commit(BaselineCommit {
+            subtree,
+            parents,
+            message,
+            timestamp,
+        });
+
+        (commit, bincode::serialize(&baseline, bincode::Infinite).unwrap())
+    }
+
+    #[test]
+    fn roundtrip_every_version() {
+        let large = LargeObject {
+            size: 0,
+            children: Vec::new(),
+        };
+        let raw_object = RawObject::Data(RawDataObject::Large(Cow::Owned(large)));
+
+        for &version in SUPPORTED {
+            let bytes = encode(&raw_object, version).unwrap();
+            let (decoded_version, decoded) = decode(&bytes).unwrap();
+
+            assert_eq!(decoded_version, version);
+            assert_eq!(decoded, raw_object);
+        }
+    }
+
+    #[test]
+    fn unsigned_commits_keep_the_baseline_encoding() {
+        let (mut commit, baseline) = baseline_commit();
+        let object = Object::Commit(commit.clone());
+        assert_eq!(object.as_raw().to_bytes().unwrap(), baseline);
+
+        commit.author = Some("A. U. Thor <author@example.com>".parse::<Identity>().unwrap());
+        let object = Object::Commit(commit);
+        assert!(object.as_raw().to_bytes().unwrap()[..4] != baseline[..4]);
+    }
+
+    #[test]
+    fn decode_baseline_commit() {
+        let (commit, baseline) = baseline_commit();
+
+        let (version, raw_object) = decode(&baseline).unwrap();
+        assert_eq!(version, Version::Unframed);
+        assert_eq!(raw_object, Object::Commit(commit.clone()).as_raw());
+
+        match Object::from_bytes(arc_slice::owned(baseline)).unwrap() {
+            Object::Commit(decoded) => assert_eq!(decoded, commit),
+            other => panic!("unexpected object {:?}", other),
+        }
+    }
+
+    #[test]
+    fn annotated_entries_keep_the_boxed_encoding() {
+        let hash = "33".repeat(32).parse().unwrap();
+        let metadata = EntryMetadata {
+            mtime: 1_500_000_000,
+            owner: Some((1000, 1000)),
+        };
+
+        let executable = Box::new(BoxedSubtreeEntry::Executable(hash, 7));
+        let boxed = BoxedSubtreeEntry::Annotated(executable, metadata);
+        let bytes = bincode::serialize(&boxed, bincode::Infinite).unwrap();
+        let entry = SubtreeEntry::Annotated(AnnotatedEntry::Executable(hash, 7), metadata);
+        assert_eq!(bincode::serialize(&entry, bincode::Infinite).unwrap(), bytes);
+        assert_eq!(bincode::deserialize::<SubtreeEntry>(&bytes).unwrap(), entry);
+
+        // Neither subtrees nor annotated entries can carry metadata of their own.
+        for inner in vec![BoxedSubtreeEntry::Subtree(hash), boxed] {
+            let nested = BoxedSubtreeEntry::Annotated(Box::new(inner), metadata);
+            let bytes = bincode::serialize(&nested, bincode::Infinite).unwrap();
+            assert!(bincode::deserialize::<SubtreeEntry>(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        match decode(&[MAGIC, 0xFF, 0, 0, 0, 0]) {
+            Err(Error(ErrorKind::UnsupportedObjectVersion(0xFF), _)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn find_small_chunk() {
+        let chunk = b"a chunk of a small object";
+        let raw_object = RawObject::Data(RawDataObject::Small(RawSmallObject { chunk }));
+
+        for &version in SUPPORTED {
+            let bytes = encode(&raw_object, version).unwrap();
+            let (start, len) = small_chunk_span(&bytes[..SMALL_PREFIX_SIZE]).unwrap();
+
+            assert_eq!(&bytes[start as usize..(start + len) as usize], &chunk[..]);
+        }
+
+        let large = LargeObject {
+            size: 0,
+            children: Vec::new(),
+        };
+        let raw_object = RawObject::Data(RawDataObject::Large(Cow::Owned(large)));
+        let bytes = encode(&raw_object, Version::CURRENT).unwrap();
+        assert_eq!(small_chunk_span(&bytes), None);
+        assert!(is_data(&bytes));
+    }
+}
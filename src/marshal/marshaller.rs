@@ -1,23 +1,30 @@
 use std::borrow::Borrow;
+use std::cmp;
 use std::fmt;
 use std::io::{self, BufWriter, Write};
 use std::mem;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use bincode;
 use digest_writer::{FixedOutput, Writer};
 use futures::prelude::*;
+use futures::stream;
 use futures::sync::mpsc::Sender;
 use generic_array::GenericArray;
 use sha3::{Sha3_256, Digest};
 use typenum::consts;
 
+use arc_slice;
 use errors::*;
-use marshal::{RawObject, Object, LargeObject, Record, SmallRecord};
+use marshal::{RawObject, Object, SmallObject, LargeObject, SubtreeObject, Record, SmallRecord};
+use marshal::canonical::{self, Version};
+use marshal::names::{self, NonUtf8Names};
 use marshal::tree::Tree;
+use profile;
 use split::GenericSplitter;
 use trace::Trace;
 
@@ -54,6 +61,20 @@ impl ObjectHash {
 
         buf.into()
     }
+
+    /// The hash of the object whose file is at `path`, which ends with the three components
+    /// `to_path` splits the hash into.
+    pub fn from_blob_path(path: &Path) -> Result<Self> {
+        let mut components = path.components().rev().take(3).collect::<Vec<_>>();
+        components.reverse();
+
+        let hash_string = components
+            .into_iter()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect::<String>();
+
+        hash_string.parse()
+    }
 }
 
 
@@ -151,6 +172,7 @@ impl<L: Write, R: Write> Write for Fork<L, R> {
 }
 
 
+/// Serialize an object's unframed payload into `writer`, returning its hash.
 pub fn serialize_into_and_hash<W: Write>(
     raw_object: &RawObject,
     writer: &mut W,
@@ -173,10 +195,19 @@ pub fn hash(object: &Object) -> ObjectHash {
 }
 
 
+/// Encode an object for storage with the current format version, and hash it.
 pub fn serialize_and_hash(object: &Object) -> Hashed {
+    serialize_and_hash_with(object, Version::CURRENT)
+}
+
+
+/// Encode an object for storage with the given format version, and hash it. The hash does not
+/// depend on the version.
+pub fn serialize_and_hash_with(object: &Object, version: Version) -> Hashed {
     let raw_object = object.as_raw();
-    let size = bincode::serialized_size(&raw_object);
+    let size = version.header_size() + bincode::serialized_size(&raw_object);
     let mut buf = Vec::with_capacity(size as usize);
+    canonical::write_header(version, &mut buf).expect("Vec should never error!");
     let hash = serialize_into_and_hash(&raw_object, &mut buf).expect(
         "Vec should never error, Digest should never error!",
     );
@@ -188,7 +219,7 @@ pub fn serialize_and_hash(object: &Object) -> Hashed {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Hashed {
     hash: ObjectHash,
     bytes: Option<Vec<u8>>,
@@ -217,10 +248,58 @@ impl Hashed {
 }
 
 
-#[derive(Debug, Clone)]
+/// How the chunks of a data object are arranged into small and large objects.
+///
+/// Changing either parameter changes the hashes of data objects marshalled afterwards, so copies
+/// of a file marshalled before and after the change share chunks but not large objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DataLayout {
+    /// Data of at most this many bytes is stored as a single small object, however many chunks it
+    /// was split into, saving a large object for every small file. `0` keeps every chunk.
+    pub small_threshold: u64,
+
+    /// The most children a large object may have. The chunks of data split into more are grouped
+    /// into a tree of large objects, each level cut at boundaries defined by the hashes of the
+    /// level below and never wider than this, so that no single object grows with the size of
+    /// the data. Must be at least `2`.
+    pub fanout: usize,
+}
+
+
+impl Default for DataLayout {
+    fn default() -> Self {
+        DataLayout {
+            small_threshold: 0,
+            fanout: 1024,
+        }
+    }
+}
+
+
+#[derive(Clone)]
 pub struct Marshaller<T: Trace> {
     output: Sender<Hashed>,
     trace: T,
+    version: Version,
+    non_utf8_names: NonUtf8Names,
+    layout: DataLayout,
+
+    /// Whether the store objects are sent to is known to contain an object already.
+    known: Option<Arc<Fn(ObjectHash) -> bool + Send + Sync>>,
+}
+
+
+impl<T: Trace + fmt::Debug> fmt::Debug for Marshaller<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Marshaller")
+            .field("output", &self.output)
+            .field("trace", &self.trace)
+            .field("version", &self.version)
+            .field("non_utf8_names", &self.non_utf8_names)
+            .field("layout", &self.layout)
+            .finish()
+    }
 }
 
 
@@ -239,7 +318,41 @@ type LeafSplitter<A, B, C, D, E> = GenericSplitter<
 
 impl<T: Trace> Marshaller<T> {
     pub fn with_trace(output: Sender<Hashed>, trace: T) -> Self {
-        Self { output, trace }
+        Self {
+            output,
+            trace,
+            version: Version::CURRENT,
+            non_utf8_names: NonUtf8Names::default(),
+            layout: DataLayout::default(),
+            known: None,
+        }
+    }
+
+    /// Encode objects with the given format version rather than the current one.
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Handle subtree entry names which are not valid UTF-8 according to the given policy. See
+    /// the `names` module.
+    pub fn with_non_utf8_names(mut self, non_utf8_names: NonUtf8Names) -> Self {
+        self.non_utf8_names = non_utf8_names;
+        self
+    }
+
+    /// Arrange the chunks of data objects according to `layout`.
+    pub fn with_layout(mut self, layout: DataLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Hash each object before encoding it, and neither encode nor send on any object for which
+    /// `known` returns `true`, as the store it would be sent to already has it. Re-marshalling
+    /// content which has not changed then costs little more than hashing it.
+    pub fn with_known(mut self, known: Arc<Fn(ObjectHash) -> bool + Send + Sync>) -> Self {
+        self.known = Some(known);
+        self
     }
 
     pub fn process<R: Into<Record>>(
@@ -248,13 +361,42 @@ impl<T: Trace> Marshaller<T> {
     ) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
         let trace = self.trace.clone();
         let output = self.output.clone();
+        let version = self.version;
+        let non_utf8_names = self.non_utf8_names;
+        let known = self.known.clone();
         let record = object.into();
 
         let async = {
             async_block! {
-                let hashed = match record.to_deep() {
-                    Ok(object) => serialize_and_hash(&object),
-                    Err(hash) => Hashed::from_hash(hash),
+                let object = match record.to_deep() {
+                    // Subtree keys are part of the hash, so they are put into canonical form
+                    // before anything else sees them.
+                    Ok(Object::Subtree(subtree_object)) => {
+                        let entries =
+                            names::canonicalize_entries(subtree_object.entries, non_utf8_names)?;
+                        Ok(Object::Subtree(SubtreeObject { entries }))
+                    }
+                    other => other,
+                };
+
+                let hashed = match (object, known) {
+                    (Ok(object), Some(known)) => {
+                        let hash = profile::sync(profile::CPU, "hash", || self::hash(&object));
+                        if known(hash) {
+                            return Ok(hash);
+                        }
+
+                        let bytes = profile::sync(profile::CPU, "encode", || {
+                            canonical::encode(&object.as_raw(), version)
+                        })?;
+                        Hashed { hash, bytes: Some(bytes) }
+                    }
+                    (Ok(object), None) => {
+                        profile::sync(profile::CPU, "hash", || {
+                            serialize_and_hash_with(&object, version)
+                        })
+                    }
+                    (Err(hash), _) => Hashed::from_hash(hash),
                 };
                 let hash = *hashed.as_hash();
                 trace.on_marshal_process(&hash);
@@ -266,6 +408,8 @@ impl<T: Trace> Marshaller<T> {
         Box::new(async)
     }
 
+    /// Marshal a stream of chunks into a data object, returning the hash of the object
+    /// representing all of them. See `DataLayout` for how the chunks are arranged.
     pub fn process_chunks<S, C>(
         &self,
         stream: S,
@@ -278,9 +422,36 @@ impl<T: Trace> Marshaller<T> {
         let marshaller = self.clone();
         let result = {
             async_block! {
+                let DataLayout { small_threshold, fanout } = marshaller.layout;
+                let fanout = cmp::max(fanout, 2);
+
+                // Hold back the first few chunks until there are too many bytes of them to be
+                // stored as a single small object.
+                let mut chunks: Box<Stream<Item = SmallRecord, Error = Error> + Send> =
+                    Box::new(stream.map(Into::into));
+                let mut head = Vec::new();
+                let mut head_size = 0;
+                while small_threshold > 0 && head_size <= small_threshold {
+                    let (next, rest) = await!(chunks.into_future()).map_err(|(error, _)| error)?;
+                    chunks = rest;
+
+                    match next {
+                        Some(small_record) => {
+                            head_size += small_record.size();
+                            head.push(small_record);
+                        }
+                        None => {
+                            if let Some(small_object) = concatenate(&head) {
+                                return await!(marshaller.process(small_object));
+                            }
+
+                            break;
+                        }
+                    }
+                }
+
                 let record_marshaller = marshaller.clone();
-                let records = stream.and_then(move |chunk| {
-                    let small_record = chunk.into();
+                let records = stream::iter_ok(head).chain(chunks).and_then(move |small_record| {
                     let size = small_record.size();
                     record_marshaller.process(small_record).map(
                         move |hash| (size, hash),
@@ -289,17 +460,20 @@ impl<T: Trace> Marshaller<T> {
 
                 let mut leaves = await!(records.collect())?;
 
-                while leaves.len() > 1024 {
+                while leaves.len() > fanout {
                     let old_leaves = mem::replace(&mut leaves, Vec::new());
                     let splitter =
                         LeafSplitter::new(old_leaves.into_iter(), |(sz, hash)| (hash, (sz, hash)));
 
-                    for (_, children) in splitter {
-                        let size = children.iter().map(|&(sz, _)| sz).sum();
-                        let object = LargeObject { size, children };
-                        let object_hash = await!(marshaller.process(object))?;
+                    for (_, group) in splitter {
+                        for children in group.chunks(fanout) {
+                            let children = children.to_vec();
+                            let size = children.iter().map(|&(sz, _)| sz).sum();
+                            let object = LargeObject { size, children };
+                            let object_hash = await!(marshaller.process(object))?;
 
-                        leaves.push((size, object_hash));
+                            leaves.push((size, object_hash));
+                        }
                     }
                 }
 
@@ -330,6 +504,25 @@ impl<T: Trace> Marshaller<T> {
 }
 
 
+/// Join the contents of two or more chunks into a single small object, if all of them are
+/// present in memory.
+fn concatenate(small_records: &[SmallRecord]) -> Option<SmallObject> {
+    if small_records.len() < 2 {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    for small_record in small_records {
+        match *small_record {
+            SmallRecord::Deep(ref small_object) => bytes.extend_from_slice(&small_object.chunk),
+            SmallRecord::Shallow(..) => return None,
+        }
+    }
+
+    Some(SmallObject { chunk: arc_slice::owned(bytes) })
+}
+
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -341,6 +534,7 @@ mod test {
     use quickcheck::TestResult;
 
     use arc_slice;
+    use marshal::DataObject;
 
     quickcheck! {
         #[test]
@@ -429,4 +623,123 @@ mod test {
 
         assert_eq!(hashes.len(), 8220);
     }
+
+    #[test]
+    fn small_threshold_concatenates() {
+        const CHUNK_QUANTITY: usize = 4;
+        const CHUNK_SIZE: usize = 64;
+
+        let pool = CpuPool::new_num_cpus();
+        let bytes = (0..CHUNK_QUANTITY * CHUNK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let chunks = bytes
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| arc_slice::owned(chunk.to_vec()))
+            .collect::<Vec<_>>();
+
+        let layout = DataLayout {
+            small_threshold: 1024,
+            ..DataLayout::default()
+        };
+        let (tx, rx) = mpsc::channel(64);
+        let hasher = Marshaller::with_trace(tx, ()).with_layout(layout);
+        let marshal_future = pool.spawn(hasher.process_chunks(stream::iter_ok(chunks)));
+        mem::drop(hasher);
+        let joined = pool.spawn(rx.collect())
+            .map_err(|_| Error::from_kind(ErrorKind::Absurd))
+            .join(marshal_future);
+
+        let (hashes, object_hash) = joined.wait().unwrap();
+        let small_object = SmallObject { chunk: arc_slice::owned(bytes) };
+
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(object_hash, hash(&Object::Data(DataObject::Small(small_object))));
+    }
+
+    #[test]
+    fn fanout_bounds_large_objects() {
+        const CHUNK_QUANTITY: usize = 8192;
+        const CHUNK_SIZE: usize = 64;
+        const FANOUT: usize = 16;
+
+        let pool = CpuPool::new_num_cpus();
+        let chunks = (0..CHUNK_QUANTITY)
+            .map(|i| {
+                arc_slice::owned(
+                    XorShiftRng::from_seed([i as u32, 2, 3, 7])
+                        .gen_iter()
+                        .take(CHUNK_SIZE)
+                        .collect(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let layout = DataLayout {
+            fanout: FANOUT,
+            ..DataLayout::default()
+        };
+        let (tx, rx) = mpsc::channel(64);
+        let hasher = Marshaller::with_trace(tx, ()).with_layout(layout);
+        let marshal_future = pool.spawn(hasher.process_chunks(stream::iter_ok(chunks)));
+        mem::drop(hasher);
+        let joined = pool.spawn(rx.collect())
+            .map_err(|_| Error::from_kind(ErrorKind::Absurd))
+            .join(marshal_future);
+
+        let (hashes, _marshal_success) = joined.wait().unwrap();
+
+        let mut large_objects = 0;
+        for hashed in hashes {
+            let bytes = arc_slice::owned(hashed.as_bytes().unwrap().to_vec());
+            let object = Object::from_bytes(bytes).unwrap();
+
+            if let Object::Data(DataObject::Large(large_object)) = object {
+                assert!(large_object.children.len() <= FANOUT);
+                large_objects += 1;
+            }
+        }
+
+        assert!(large_objects > CHUNK_QUANTITY / FANOUT);
+    }
+
+    #[test]
+    fn known_objects_are_not_sent() {
+        let pool = CpuPool::new_num_cpus();
+        let chunks = vec![b"known".to_vec(), b"not known".to_vec(), b"nor this".to_vec()]
+            .into_iter()
+            .map(arc_slice::owned)
+            .collect::<Vec<_>>();
+        let known_hash = hash(&Object::Data(DataObject::Small(SmallObject {
+            chunk: chunks[0].clone(),
+        })));
+
+        let (tx, rx) = mpsc::channel(64);
+        let hasher = Marshaller::with_trace(tx, ())
+            .with_known(Arc::new(move |object_hash| object_hash == known_hash));
+        let marshal_future = pool.spawn(hasher.process_chunks(stream::iter_ok(chunks.clone())));
+        mem::drop(hasher);
+        let joined = pool.spawn(rx.collect())
+            .map_err(|_| Error::from_kind(ErrorKind::Absurd))
+            .join(marshal_future);
+
+        let (hashes, object_hash) = joined.wait().unwrap();
+
+        // The two unknown chunks and the large object joining all three.
+        assert_eq!(hashes.len(), 3);
+        for hashed in hashes {
+            assert!(*hashed.as_hash() != known_hash);
+
+            let bytes = arc_slice::owned(hashed.as_bytes().unwrap().to_vec());
+            assert_eq!(hash(&Object::from_bytes(bytes).unwrap()), *hashed.as_hash());
+        }
+
+        let (tx, rx) = mpsc::channel(64);
+        let hasher = Marshaller::with_trace(tx, ());
+        let marshal_future = pool.spawn(hasher.process_chunks(stream::iter_ok(chunks)));
+        mem::drop(hasher);
+        let joined = pool.spawn(rx.collect())
+            .map_err(|_| Error::from_kind(ErrorKind::Absurd))
+            .join(marshal_future);
+
+        assert_eq!(joined.wait().unwrap().1, object_hash);
+    }
 }
@@ -8,16 +8,20 @@
 
 //pub mod data_tree;
 pub mod backed;
+pub mod canonical;
 pub mod marshaller;
+pub mod names;
 pub mod object;
 pub mod record;
+pub mod sealed;
 pub mod tree;
 
 
-pub use self::marshaller::{hash, serialize_and_hash, serialize_into_and_hash, ObjectHash,
-                           Marshaller, Hashed};
+pub use self::marshaller::{hash, serialize_and_hash, serialize_and_hash_with, serialize_into_and_hash, ObjectHash,
+                           Marshaller, Hashed, DataLayout};
 pub use self::object::{RawObject, ShallowObject, Object, SmallObject, LargeObject, DataObject,
-                       SubtreeObject, SubtreeEntry, CommitObject};
+                       SubtreeObject, SubtreeEntry, AnnotatedEntry, EntryMetadata, CommitObject,
+                       LegacyCommitObject, Identity};
 pub use self::record::{Record, DataRecord, MetaRecord, SmallRecord};
 pub use self::tree::Tree;
 pub use self::backed::{Tree as BackedTree, TreeOp};
@@ -0,0 +1,278 @@
+//! `names` - the canonical encoding of names in subtree objects.
+//!
+//! Subtree keys are part of a tree's hash, so the same directory must produce the same keys
+//! whichever platform it was committed from. Every name written into a subtree is therefore put
+//! into canonical form first:
+//!
+//! * Names must be non-empty, must not be `.` or `..`, and must not contain `/` or NUL.
+//! * Names are UTF-8, normalized to NFC. This means a file committed from macOS (whose filesystems
+//!   often hand out decomposed names) hashes the same as one committed from Linux.
+//! * Names which are not valid UTF-8 are handled according to the repository's
+//!   `non_utf8_names` setting: rejected (the default), preserved as raw bytes (only usable on
+//!   Unix), or decoded lossily.
+//!
+//! Keys are encoded exactly as a Unix `OsString` would be, so that trees written before
+//! canonicalization keep their hashes, but are decoded as UTF-8 on platforms which cannot hold
+//! raw bytes.
+//!
+//! Names valid in a tree may still be impossible to create on some platforms: Windows forbids a
+//! number of characters and device names, and both Windows and macOS usually fold case. These are
+//! checked when checking out, rather than when committing, so that a repository can still be used
+//! on the platforms which can represent it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de::Error as DeError;
+use serde::ser::Error as SerError;
+use unicode_normalization::UnicodeNormalization;
+
+use errors::*;
+use marshal::SubtreeEntry;
+
+
+/// What to do with names which are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonUtf8Names {
+    /// Refuse to commit them.
+    Reject,
+
+    /// Store their raw bytes. Trees containing them cannot be checked out on Windows.
+    Preserve,
+
+    /// Replace invalid sequences with U+FFFD. This is lossy; distinct names may collide.
+    Replace,
+}
+
+
+impl Default for NonUtf8Names {
+    fn default() -> Self {
+        NonUtf8Names::Reject
+    }
+}
+
+
+/// Put a single name into canonical form.
+pub fn canonicalize(name: &OsStr, non_utf8: NonUtf8Names) -> Result<OsString> {
+    let lossy = name.to_string_lossy();
+
+    if lossy.is_empty() || lossy == "." || lossy == ".." || lossy.contains('/') ||
+        lossy.contains('\0')
+    {
+        bail!(ErrorKind::InvalidEntryName(name.to_owned()));
+    }
+
+    match name.to_str() {
+        Some(utf8) => Ok(OsString::from(utf8.nfc().collect::<String>())),
+        None => {
+            match non_utf8 {
+                NonUtf8Names::Reject => bail!(ErrorKind::InvalidEntryName(name.to_owned())),
+                NonUtf8Names::Preserve => Ok(name.to_owned()),
+                NonUtf8Names::Replace => Ok(OsString::from(lossy.nfc().collect::<String>())),
+            }
+        }
+    }
+}
+
+
+/// Put every key of a subtree into canonical form, failing if two keys become the same.
+pub fn canonicalize_entries(
+    entries: BTreeMap<OsString, SubtreeEntry>,
+    non_utf8: NonUtf8Names,
+) -> Result<BTreeMap<OsString, SubtreeEntry>> {
+    let mut canonical = BTreeMap::new();
+
+    for (name, entry) in entries {
+        let canonical_name = canonicalize(&name, non_utf8)?;
+
+        if canonical.insert(canonical_name, entry).is_some() {
+            bail!(ErrorKind::InvalidEntryName(name));
+        }
+    }
+
+    Ok(canonical)
+}
+
+
+/// Windows device names, which cannot be used as file names with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+
+/// Whether a name can be created on Windows.
+fn is_windows_portable(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or("");
+
+    !name.chars().any(|c| c < ' ' || "<>:\"\\|?*".contains(c)) && !name.ends_with('.') &&
+        !name.ends_with(' ') &&
+        !RESERVED_NAMES.contains(&&*stem.to_uppercase())
+}
+
+
+/// Check that every name in a set of paths can be created on this platform, and that no two of
+/// them collide on filesystems which fold case.
+pub fn check_checkout<'a, I>(paths: I) -> Result<()>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let windows = cfg!(windows);
+    let case_insensitive = cfg!(any(windows, target_os = "macos"));
+    let mut folded = HashMap::new();
+
+    for path in paths {
+        for component in path.iter() {
+            if windows {
+                match component.to_str() {
+                    Some(name) if is_windows_portable(name) => {}
+                    _ => bail!(ErrorKind::UnrepresentableName(path.to_owned())),
+                }
+            }
+        }
+
+        if case_insensitive {
+            let key = path.to_string_lossy().to_lowercase();
+
+            match folded.insert(key, path) {
+                Some(other) if other != path => {
+                    bail!(ErrorKind::CaseCollision(other.to_owned(), path.to_owned()))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Names as they are encoded in subtree objects. The variant names and indices match serde's
+/// encoding of `OsString`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename = "OsString")]
+enum EncodedName {
+    Unix(Vec<u8>),
+    Windows(Vec<u16>),
+}
+
+
+impl EncodedName {
+    #[cfg(unix)]
+    fn encode(name: &OsStr) -> Option<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        Some(EncodedName::Unix(name.as_bytes().to_owned()))
+    }
+
+    #[cfg(not(unix))]
+    fn encode(name: &OsStr) -> Option<Self> {
+        name.to_str().map(|utf8| EncodedName::Unix(utf8.as_bytes().to_owned()))
+    }
+
+    #[cfg(unix)]
+    fn decode(self) -> Option<OsString> {
+        use std::os::unix::ffi::OsStringExt;
+
+        match self {
+            EncodedName::Unix(bytes) => Some(OsString::from_vec(bytes)),
+            EncodedName::Windows(wide) => String::from_utf16(&wide).ok().map(OsString::from),
+        }
+    }
+
+    #[cfg(windows)]
+    fn decode(self) -> Option<OsString> {
+        use std::os::windows::ffi::OsStringExt;
+
+        match self {
+            EncodedName::Unix(bytes) => String::from_utf8(bytes).ok().map(OsString::from),
+            EncodedName::Windows(wide) => Some(OsString::from_wide(&wide)),
+        }
+    }
+}
+
+
+/// Serialize the entries of a subtree. Used through `#[serde(serialize_with)]`.
+pub fn serialize_entries<S: Serializer>(
+    entries: &BTreeMap<OsString, SubtreeEntry>,
+    serializer: S,
+) -> ::std::result::Result<S::Ok, S::Error> {
+    let mut encoded = Vec::with_capacity(entries.len());
+
+    for (name, entry) in entries {
+        let encoded_name = EncodedName::encode(name).ok_or_else(|| {
+            S::Error::custom(format!("{:?} cannot be encoded as a subtree key", name))
+        })?;
+        encoded.push((encoded_name, entry));
+    }
+
+    serializer.collect_map(encoded)
+}
+
+
+/// Deserialize the entries of a subtree. Used through `#[serde(deserialize_with)]`.
+pub fn deserialize_entries<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> ::std::result::Result<BTreeMap<OsString, SubtreeEntry>, D::Error> {
+    let encoded = HashMap::<EncodedName, SubtreeEntry>::deserialize(deserializer)?;
+    let mut entries = BTreeMap::new();
+
+    for (encoded_name, entry) in encoded {
+        let name = encoded_name.clone().decode().ok_or_else(|| {
+            D::Error::custom(format!(
+                "{:?} cannot be represented on this platform",
+                encoded_name
+            ))
+        })?;
+        entries.insert(name, entry);
+    }
+
+    Ok(entries)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn composes_unicode() {
+        let decomposed = OsStr::new("cafe\u{301}");
+        let composed = canonicalize(decomposed, NonUtf8Names::Reject).unwrap();
+
+        assert_eq!(composed, OsStr::new("caf\u{e9}"));
+    }
+
+    #[test]
+    fn rejects_special_names() {
+        for name in &["", ".", "..", "a/b", "a\0b"] {
+            assert!(canonicalize(OsStr::new(name), NonUtf8Names::Preserve).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_policy() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"a\xffb");
+
+        assert!(canonicalize(name, NonUtf8Names::Reject).is_err());
+        assert_eq!(canonicalize(name, NonUtf8Names::Preserve).unwrap(), name);
+        assert_eq!(
+            canonicalize(name, NonUtf8Names::Replace).unwrap(),
+            OsStr::new("a\u{fffd}b")
+        );
+    }
+
+    #[test]
+    fn windows_portability() {
+        assert!(is_windows_portable("report.txt"));
+        assert!(!is_windows_portable("con.txt"));
+        assert!(!is_windows_portable("what?"));
+        assert!(!is_windows_portable("trailing."));
+    }
+}
@@ -3,13 +3,20 @@
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::ffi::OsString;
+use std::fmt;
+use std::str::FromStr;
 
 use bincode;
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as DeError;
 
 use arc_slice::ArcSlice;
-use errors::Result;
+use errors::*;
 use marshal::ObjectHash;
+use marshal::canonical;
+use marshal::names;
+use sign::CommitSignature;
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -79,18 +86,153 @@ impl LargeObject {
 }
 
 
+// New variants must only ever be appended, as the variant index is part of the encoding.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum SubtreeEntry {
     File(ObjectHash, u64),
     Subtree(ObjectHash),
+
+    /// A symbolic link. The hash is of a data object containing the raw bytes of the link target.
+    Symlink(ObjectHash),
+
+    /// A file with its executable bit set.
+    Executable(ObjectHash, u64),
+
+    /// A file, symlink or executable, along with filesystem metadata to be restored on checkout.
+    Annotated(AnnotatedEntry, EntryMetadata),
+
+    /// Another repository nested at this path, by where to clone it from and the commit of it to
+    /// check out. The commit's objects are in the nested repository's store, not this one, so
+    /// nothing which walks the object graph follows this entry. See the `subrepository` module.
+    Subrepository(String, ObjectHash),
+}
+
+
+/// The entries which may carry metadata. Encoded exactly as the `SubtreeEntry` of the same name,
+/// so that an annotated entry holds the bytes of the entry it annotates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnnotatedEntry {
+    File(ObjectHash, u64),
+    Symlink(ObjectHash),
+    Executable(ObjectHash, u64),
+}
+
+
+impl From<AnnotatedEntry> for SubtreeEntry {
+    fn from(entry: AnnotatedEntry) -> Self {
+        match entry {
+            AnnotatedEntry::File(hash, size) => SubtreeEntry::File(hash, size),
+            AnnotatedEntry::Symlink(hash) => SubtreeEntry::Symlink(hash),
+            AnnotatedEntry::Executable(hash, size) => SubtreeEntry::Executable(hash, size),
+        }
+    }
+}
+
+
+impl Serialize for AnnotatedEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        SubtreeEntry::from(*self).serialize(serializer)
+    }
+}
+
+
+impl<'de> Deserialize<'de> for AnnotatedEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        match SubtreeEntry::deserialize(deserializer)? {
+            SubtreeEntry::File(hash, size) => Ok(AnnotatedEntry::File(hash, size)),
+            SubtreeEntry::Symlink(hash) => Ok(AnnotatedEntry::Symlink(hash)),
+            SubtreeEntry::Executable(hash, size) => Ok(AnnotatedEntry::Executable(hash, size)),
+            other => Err(D::Error::custom(format!("{:?} cannot carry metadata", other))),
+        }
+    }
+}
+
+
+/// Filesystem metadata optionally recorded alongside subtree entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    /// The modification time, in seconds since the Unix epoch.
+    pub mtime: i64,
+
+    /// The owning user and group IDs, if recorded.
+    pub owner: Option<(u32, u32)>,
 }
 
 
 impl SubtreeEntry {
+    /// Build the entry for a non-directory with the given contents, using its Unix mode to
+    /// determine whether it is a symlink, an executable, or a regular file.
+    pub fn from_mode(object_hash: ObjectHash, size: u64, mode: u32) -> SubtreeEntry {
+        const S_IFMT: u32 = 0o170000;
+        const S_IFLNK: u32 = 0o120000;
+
+        if mode & S_IFMT == S_IFLNK {
+            SubtreeEntry::Symlink(object_hash)
+        } else if mode & 0o111 != 0 {
+            SubtreeEntry::Executable(object_hash, size)
+        } else {
+            SubtreeEntry::File(object_hash, size)
+        }
+    }
+
     pub fn hash(&self) -> ObjectHash {
         match *self {
             SubtreeEntry::File(hash, _) => hash,
             SubtreeEntry::Subtree(hash) => hash,
+            SubtreeEntry::Symlink(hash) => hash,
+            SubtreeEntry::Executable(hash, _) => hash,
+            SubtreeEntry::Annotated(entry, _) => SubtreeEntry::from(entry).hash(),
+            SubtreeEntry::Subrepository(_, hash) => hash,
+        }
+    }
+
+    /// Whether the entry is a nested repository, whose commit is not in this store.
+    pub fn is_subrepository(&self) -> bool {
+        match *self {
+            SubtreeEntry::Subrepository(..) => true,
+            _ => false,
+        }
+    }
+
+    /// The entry with any metadata annotation removed.
+    pub fn unannotated(&self) -> SubtreeEntry {
+        match *self {
+            SubtreeEntry::Annotated(entry, _) => SubtreeEntry::from(entry),
+            ref other => other.clone(),
+        }
+    }
+
+    /// The entry with `metadata` recorded alongside it, replacing any recorded before. Subtrees
+    /// and subrepositories carry no metadata, and are returned as they are.
+    pub fn annotated(self, metadata: EntryMetadata) -> SubtreeEntry {
+        let entry = match self {
+            SubtreeEntry::File(hash, size) => AnnotatedEntry::File(hash, size),
+            SubtreeEntry::Symlink(hash) => AnnotatedEntry::Symlink(hash),
+            SubtreeEntry::Executable(hash, size) => AnnotatedEntry::Executable(hash, size),
+            SubtreeEntry::Annotated(entry, _) => entry,
+            other => return other,
+        };
+
+        SubtreeEntry::Annotated(entry, metadata)
+    }
+
+    /// The recorded filesystem metadata of this entry, if any.
+    pub fn metadata(&self) -> Option<&EntryMetadata> {
+        match *self {
+            SubtreeEntry::Annotated(_, ref metadata) => Some(metadata),
+            _ => None,
+        }
+    }
+
+    /// The hash of the data object holding this entry's contents, if it is not a subtree.
+    pub fn data_hash(&self) -> Option<ObjectHash> {
+        match *self {
+            SubtreeEntry::File(hash, _) |
+            SubtreeEntry::Symlink(hash) |
+            SubtreeEntry::Executable(hash, _) => Some(hash),
+            SubtreeEntry::Subtree(_) |
+            SubtreeEntry::Subrepository(..) => None,
+            SubtreeEntry::Annotated(entry, _) => SubtreeEntry::from(entry).data_hash(),
         }
     }
 }
@@ -99,6 +241,8 @@ impl SubtreeEntry {
 /// The marshaled, deserialized representation of a subtree.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SubtreeObject {
+    #[serde(serialize_with = "names::serialize_entries",
+            deserialize_with = "names::deserialize_entries")]
     pub entries: BTreeMap<OsString, SubtreeEntry>,
 }
 
@@ -117,6 +261,105 @@ pub struct CommitObject {
 
     /// The commit timestamp, denoting when the commit was made locally.
     pub timestamp: DateTime<Utc>,
+
+    /// A signature over the rest of the commit, if it has been signed. See the `sign` module.
+    pub signature: Option<CommitSignature>,
+
+    /// The person who wrote the changes in the commit, if known.
+    pub author: Option<Identity>,
+
+    /// The person who made the commit, if known.
+    pub committer: Option<Identity>,
+}
+
+
+impl CommitObject {
+    /// Whether the commit has nothing which `LegacyCommitObject` cannot hold, and so is encoded
+    /// as one.
+    pub fn is_legacy(&self) -> bool {
+        self.signature.is_none() && self.author.is_none() && self.committer.is_none()
+    }
+}
+
+
+/// The encoding of a commit object from before commits carried signatures or author and
+/// committer identities. Commits with none of them are still encoded this way, so that their
+/// hashes are unchanged and commits written before then still decode.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LegacyCommitObject {
+    pub subtree: ObjectHash,
+    pub parents: Vec<ObjectHash>,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+
+impl From<LegacyCommitObject> for CommitObject {
+    fn from(legacy: LegacyCommitObject) -> Self {
+        CommitObject {
+            subtree: legacy.subtree,
+            parents: legacy.parents,
+            message: legacy.message,
+            timestamp: legacy.timestamp,
+            signature: None,
+            author: None,
+            committer: None,
+        }
+    }
+}
+
+
+impl<'a> From<&'a CommitObject> for LegacyCommitObject {
+    fn from(commit: &'a CommitObject) -> Self {
+        LegacyCommitObject {
+            subtree: commit.subtree,
+            parents: commit.parents.clone(),
+            message: commit.message.clone(),
+            timestamp: commit.timestamp,
+        }
+    }
+}
+
+
+/// The name and email address of a commit's author or committer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Identity {
+    pub name: String,
+    pub email: String,
+}
+
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
+
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    /// Parse an identity of the form `Name <email>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        match (s.find('<'), s.ends_with('>')) {
+            (Some(open), true) => {
+                let name = s[..open].trim();
+                let email = s[open + 1..s.len() - 1].trim();
+
+                if name.is_empty() || email.is_empty() {
+                    bail!(ErrorKind::InvalidIdentity(s.to_owned()));
+                }
+
+                Ok(Identity {
+                    name: name.to_owned(),
+                    email: email.to_owned(),
+                })
+            }
+            _ => bail!(ErrorKind::InvalidIdentity(s.to_owned())),
+        }
+    }
 }
 
 
@@ -191,28 +434,65 @@ pub enum RawObject<'a> {
 
     /// A commit is a pointer to a subtree representing the current state of the repository, as
     /// well as a list of parent commits.
-    Commit(Cow<'a, CommitObject>),
+    Commit(Cow<'a, LegacyCommitObject>),
+
+    /// A commit which is signed, or records its author or committer.
+    ExtendedCommit(Cow<'a, CommitObject>),
 }
 
 
 impl<'a> RawObject<'a> {
-    /// Deserialize and borrow an `Object` from a byte slice.
+    /// Deserialize and borrow an `Object` from its unframed payload. To decode an object as
+    /// stored, use `canonical::decode`.
     pub fn from_bytes(slice: &'a [u8]) -> Result<Self> {
         bincode::deserialize(slice).map_err(Into::into)
     }
 
 
-    /// Serialize an `Object` into a byte vector.
+    /// Serialize an `Object` into its unframed payload, the bytes its hash is computed over. To
+    /// encode an object for storage, use `canonical::encode`.
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         bincode::serialize(self, bincode::Infinite).map_err(Into::into)
     }
 
 
+    /// The hashes of every object this object refers to directly. See `Object::refs`.
+    pub fn refs(&self) -> Vec<ObjectHash> {
+        match *self {
+            RawObject::Data(RawDataObject::Small(_)) => Vec::new(),
+            RawObject::Data(RawDataObject::Large(ref large_object)) => {
+                large_object.children.iter().map(|&(_, hash)| hash).collect()
+            }
+            RawObject::Subtree(ref subtree_object) => {
+                subtree_object
+                    .entries
+                    .values()
+                    .filter(|entry| !entry.is_subrepository())
+                    .map(SubtreeEntry::hash)
+                    .collect()
+            }
+            RawObject::Commit(ref commit_object) => {
+                let mut refs = Vec::with_capacity(commit_object.parents.len() + 1);
+                refs.push(commit_object.subtree);
+                refs.extend(commit_object.parents.iter().cloned());
+                refs
+            }
+            RawObject::ExtendedCommit(ref commit_object) => {
+                let mut refs = Vec::with_capacity(commit_object.parents.len() + 1);
+                refs.push(commit_object.subtree);
+                refs.extend(commit_object.parents.iter().cloned());
+                refs
+            }
+        }
+    }
+
+
     unsafe fn into_object(self, slice: ArcSlice) -> Object {
         match self {
             RawObject::Data(data) => Object::Data(data.into_object(slice)),
             RawObject::Subtree(subtree) => Object::Subtree(subtree.into_owned()),
-            RawObject::Commit(commit) => Object::Commit(commit.into_owned()),
+            RawObject::Commit(commit) => Object::Commit(commit.into_owned().into()),
+            RawObject::ExtendedCommit(commit) => Object::Commit(commit.into_owned()),
         }
     }
 }
@@ -232,8 +512,9 @@ pub enum Object {
 
 
 impl Object {
+    /// Decode an object as stored, in any supported format version.
     pub fn from_bytes(slice: ArcSlice) -> Result<Object> {
-        let object = RawObject::from_bytes(&slice)?;
+        let (_, object) = canonical::decode(&slice)?;
         Ok(unsafe { object.into_object(slice.clone()) })
     }
 
@@ -242,7 +523,10 @@ impl Object {
         match *self {
             Object::Data(ref data) => RawObject::Data(data.as_raw()),
             Object::Subtree(ref subtree) => RawObject::Subtree(Cow::Borrowed(subtree)),
-            Object::Commit(ref commit) => RawObject::Commit(Cow::Borrowed(commit)),
+            Object::Commit(ref commit) if commit.is_legacy() => {
+                RawObject::Commit(Cow::Owned(LegacyCommitObject::from(commit)))
+            }
+            Object::Commit(ref commit) => RawObject::ExtendedCommit(Cow::Borrowed(commit)),
         }
     }
 
@@ -251,4 +535,29 @@ impl Object {
         let raw_object = self.as_raw();
         bincode::serialized_size(&raw_object)
     }
+
+
+    /// The hashes of every object this object refers to directly.
+    pub fn refs(&self) -> Vec<ObjectHash> {
+        match *self {
+            Object::Data(DataObject::Small(_)) => Vec::new(),
+            Object::Data(DataObject::Large(ref large_object)) => {
+                large_object.children.iter().map(|&(_, hash)| hash).collect()
+            }
+            Object::Subtree(ref subtree_object) => {
+                subtree_object
+                    .entries
+                    .values()
+                    .filter(|entry| !entry.is_subrepository())
+                    .map(SubtreeEntry::hash)
+                    .collect()
+            }
+            Object::Commit(ref commit_object) => {
+                let mut refs = Vec::with_capacity(commit_object.parents.len() + 1);
+                refs.push(commit_object.subtree);
+                refs.extend(commit_object.parents.iter().cloned());
+                refs
+            }
+        }
+    }
 }
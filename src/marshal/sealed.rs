@@ -0,0 +1,308 @@
+//! `sealed` - encrypted envelopes for objects stored on untrusted remotes.
+//!
+//! A sealed object is framed like any other (see `canonical`), but with the header byte `SEALED`
+//! in place of a format version. The frame holds an `Envelope`: the hashes of the objects it
+//! refers to, in the clear, followed by its stored bytes - already framed, and possibly compressed
+//! - encrypted with ChaCha20-Poly1305. The hashes referred to are bound to the ciphertext as
+//! associated data, along with the object's own hash, so that a holder of the key will notice if
+//! a server rewrites them.
+//!
+//! Each object is encrypted under its own key, and with its own nonce, both derived from a
+//! repository secret and the object's hash. Sealing is therefore deterministic: every holder of
+//! the same secret seals the same object to the same bytes, so objects stay deduplicated on the
+//! remote and the catalog negotiation in `sync` works unchanged. Objects keep their hashes, which
+//! are the hashes of their plaintext.
+//!
+//! ## What works without the key
+//!
+//! A server holding only sealed objects can still:
+//!
+//! * Walk the object graph, since references are in the clear, and so garbage-collect, answer
+//!   negotiation, and maintain a reverse reference index.
+//! * Replicate or mirror objects, by copying envelopes verbatim.
+//! * Detect corruption of stored envelopes, using the digest of the ciphertext the envelope
+//!   carries (see `check_stored`). It cannot tell whether the plaintext matches the object's
+//!   hash; only a holder of the key can, and `fsck` of a local store does so as usual.
+//!
+//! It cannot read any contents, verify object hashes, or re-chunk or repack data. It does learn
+//! the shape of the object graph, the hashes of objects, and their approximate sizes.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::iter;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use bincode;
+use rand::{OsRng, Rng};
+use ring::aead::{self, CHACHA20_POLY1305, OpeningKey, SealingKey};
+use sha3::{Sha3_256, Digest};
+
+use errors::*;
+use marshal::ObjectHash;
+use marshal::canonical::{self, MAGIC};
+
+
+/// The header byte, following `MAGIC`, of a sealed object.
+pub const SEALED: u8 = 0x80;
+
+
+/// The length of an encryption key, in bytes.
+const KEY_LEN: usize = 32;
+
+
+/// The length of a ChaCha20-Poly1305 nonce, in bytes.
+const NONCE_LEN: usize = 12;
+
+
+/// The readable part of a sealed object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// The hashes of the objects the sealed object refers to.
+    pub refs: Vec<ObjectHash>,
+
+    /// The SHA3-256 digest of `ciphertext`, so that corruption can be found without the key.
+    pub digest: [u8; 32],
+
+    /// The stored bytes of the object, encrypted and followed by their authentication tag.
+    pub ciphertext: Vec<u8>,
+}
+
+
+fn sha3(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    for part in parts {
+        hasher.input(part);
+    }
+
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&hasher.result());
+    digest
+}
+
+
+/// The data authenticated along with an object's ciphertext.
+fn additional_data(hash: ObjectHash, refs: &[ObjectHash]) -> Vec<u8> {
+    let mut ad = hash.as_slice().to_vec();
+    for reference in refs {
+        ad.extend_from_slice(reference.as_slice());
+    }
+    ad
+}
+
+
+/// A repository secret from which the key of every sealed object is derived.
+pub struct EncryptionKey {
+    secret: [u8; KEY_LEN],
+}
+
+
+impl EncryptionKey {
+    /// Generate a fresh secret from the operating system's random number generator.
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0; KEY_LEN];
+        OsRng::new()?.fill_bytes(&mut secret);
+
+        Ok(EncryptionKey { secret })
+    }
+
+    /// Load a secret from a file, which must hold exactly its 32 bytes.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .chain_err(|| ErrorKind::EncryptionKeyOpen(path.to_owned()))?;
+
+        if bytes.len() != KEY_LEN {
+            bail!(ErrorKind::EncryptionKeyOpen(path.to_owned()));
+        }
+
+        let mut secret = [0; KEY_LEN];
+        secret.copy_from_slice(&bytes);
+
+        Ok(EncryptionKey { secret })
+    }
+
+    /// Write this secret to a file, truncating it if it exists. Whether or not it did, and
+    /// whatever the umask, the file is made readable and writable only by its owner before the
+    /// secret is written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(&self.secret)?;
+
+        Ok(())
+    }
+
+    /// The key and nonce an object is sealed with.
+    fn derive(&self, hash: ObjectHash) -> ([u8; KEY_LEN], [u8; NONCE_LEN]) {
+        let key = sha3(&[&self.secret, b"attaca object key", hash.as_slice()]);
+        let nonce_digest = sha3(&[&self.secret, b"attaca object nonce", hash.as_slice()]);
+
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&nonce_digest[..NONCE_LEN]);
+
+        (key, nonce)
+    }
+
+    /// Seal the stored bytes of the object `hash`, which refers to `refs`.
+    pub fn seal(&self, hash: ObjectHash, refs: Vec<ObjectHash>, bytes: &[u8]) -> Result<Vec<u8>> {
+        let (key, nonce) = self.derive(hash);
+        let sealing_key = SealingKey::new(&CHACHA20_POLY1305, &key).map_err(|_| {
+            Error::from_kind(ErrorKind::SealFailed(hash))
+        })?;
+        let tag_len = CHACHA20_POLY1305.tag_len();
+
+        let mut ciphertext = bytes.to_vec();
+        ciphertext.extend(iter::repeat(0).take(tag_len));
+        let sealed_len = aead::seal_in_place(
+            &sealing_key,
+            &nonce,
+            &additional_data(hash, &refs),
+            &mut ciphertext,
+            tag_len,
+        ).map_err(|_| Error::from_kind(ErrorKind::SealFailed(hash)))?;
+        ciphertext.truncate(sealed_len);
+
+        let envelope = Envelope {
+            refs,
+            digest: sha3(&[&ciphertext]),
+            ciphertext,
+        };
+
+        let mut sealed = vec![MAGIC, SEALED];
+        bincode::serialize_into(&mut sealed, &envelope, bincode::Infinite)?;
+
+        Ok(sealed)
+    }
+
+    /// Recover the stored bytes of the object `hash` from what the remote holds. Objects which
+    /// were not sealed are refused: anyone able to write to the remote could otherwise substitute
+    /// plaintext of their choosing for an object.
+    pub fn open_stored(&self, hash: ObjectHash, stored: Vec<u8>) -> Result<Vec<u8>> {
+        if !is_sealed(&stored) {
+            bail!(ErrorKind::UnsealedObject(hash));
+        }
+
+        let envelope = envelope(&stored)?;
+        if sha3(&[&envelope.ciphertext]) != envelope.digest {
+            bail!(ErrorKind::CorruptEnvelope(hash));
+        }
+
+        let (key, nonce) = self.derive(hash);
+        let opening_key = OpeningKey::new(&CHACHA20_POLY1305, &key).map_err(|_| {
+            Error::from_kind(ErrorKind::UnsealFailed(hash))
+        })?;
+        let additional_data = additional_data(hash, &envelope.refs);
+
+        let mut bytes = envelope.ciphertext;
+        let opened_len = aead::open_in_place(&opening_key, &nonce, &additional_data, 0, &mut bytes)
+            .map_err(|_| Error::from_kind(ErrorKind::UnsealFailed(hash)))?
+            .len();
+        bytes.truncate(opened_len);
+
+        Ok(bytes)
+    }
+}
+
+
+/// Whether stored bytes are a sealed object.
+pub fn is_sealed(stored: &[u8]) -> bool {
+    stored.len() >= 2 && stored[0] == MAGIC && stored[1] == SEALED
+}
+
+
+/// Parse the envelope of a sealed object.
+pub fn envelope(stored: &[u8]) -> Result<Envelope> {
+    if !is_sealed(stored) {
+        bail!(ErrorKind::NotSealed);
+    }
+
+    Ok(bincode::deserialize(&stored[2..])?)
+}
+
+
+/// The hashes of the objects which stored bytes refer to, whether they are sealed or not. Objects
+/// which are not sealed must already have been decompressed.
+pub fn stored_refs(stored: &[u8]) -> Result<Vec<ObjectHash>> {
+    if is_sealed(stored) {
+        Ok(envelope(stored)?.refs)
+    } else {
+        Ok(canonical::decode(stored)?.1.refs())
+    }
+}
+
+
+/// Check stored bytes for corruption without a key. An object which is not sealed must hash to
+/// `hash`, once decompressed; a sealed object's ciphertext must match the digest in its envelope.
+pub fn check_stored(hash: ObjectHash, stored: &[u8]) -> Result<()> {
+    if is_sealed(stored) {
+        let envelope = envelope(stored)?;
+        if sha3(&[&envelope.ciphertext]) != envelope.digest {
+            bail!(ErrorKind::CorruptEnvelope(hash));
+        }
+    } else {
+        let payload = match canonical::decode(stored)?.0.header_size() {
+            0 => stored,
+            header_size => &stored[header_size as usize..],
+        };
+        if sha3(&[payload]) != *hash.as_slice() {
+            bail!(ErrorKind::CorruptEnvelope(hash));
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::hash_of;
+
+    #[test]
+    fn seal_is_deterministic_and_opens() {
+        let key = EncryptionKey::generate().unwrap();
+        let refs = vec![hash_of(2), hash_of(3)];
+        let bytes = b"some stored bytes".to_vec();
+
+        let sealed = key.seal(hash_of(1), refs.clone(), &bytes).unwrap();
+        assert_eq!(sealed, key.seal(hash_of(1), refs.clone(), &bytes).unwrap());
+        assert_eq!(stored_refs(&sealed).unwrap(), refs);
+        check_stored(hash_of(1), &sealed).unwrap();
+
+        assert_eq!(key.open_stored(hash_of(1), sealed.clone()).unwrap(), bytes);
+        assert!(EncryptionKey::generate().unwrap().open_stored(hash_of(1), sealed).is_err());
+    }
+
+    #[test]
+    fn rewritten_refs_are_refused() {
+        let key = EncryptionKey::generate().unwrap();
+        let sealed = key.seal(hash_of(1), vec![hash_of(2)], b"bytes").unwrap();
+
+        let mut envelope = envelope(&sealed).unwrap();
+        envelope.refs = vec![hash_of(4)];
+        let mut rewritten = vec![MAGIC, SEALED];
+        bincode::serialize_into(&mut rewritten, &envelope, bincode::Infinite).unwrap();
+
+        check_stored(hash_of(1), &rewritten).unwrap();
+        assert!(key.open_stored(hash_of(1), rewritten).is_err());
+    }
+
+    #[test]
+    fn unsealed_objects_are_refused() {
+        let key = EncryptionKey::generate().unwrap();
+
+        match key.open_stored(hash_of(1), b"plaintext".to_vec()) {
+            Err(Error(ErrorKind::UnsealedObject(hash), _)) => assert_eq!(hash, hash_of(1)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
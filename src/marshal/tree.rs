@@ -440,6 +440,8 @@ impl Tree {
         Box::new(async_block! {
             let node = this.lock().unwrap()[node_id].take().unwrap();
             match node {
+                // A nested repository's commit is in another store, so there is nothing to write.
+                Node::Opaque(ref subtree_entry) if subtree_entry.is_subrepository() => Ok(subtree_entry.clone()),
                 Node::Opaque(subtree_entry) => await!(marshaller.process(subtree_entry.hash()).map(|_| subtree_entry)),
                 Node::Transparent(entries) => {
                     let captured_marshaller = marshaller.clone();
@@ -0,0 +1,159 @@
+//! # `mirror` - keep a read-only mirror's branches in step with an upstream repository.
+//!
+//! A mirror serves the branches of an upstream repository, whose objects it fetches from a remote
+//! the upstream pushes to. There is no ref store to ask which branches have moved, so the upstream
+//! repository's refs file is read directly, typically over a shared filesystem; it is replaced
+//! atomically whenever it changes, so a partially written one is never seen.
+//!
+//! The branch heads seen at the last successful refresh are kept in `.attaca/mirror.bin`. A
+//! refresh only fetches the history behind branches whose heads differ from those, so that a
+//! refresh run on a cron cadence costs no more than reading one file when nothing has changed.
+//! Overlapping refreshes are prevented by `.attaca/mirror.lock`.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use bincode;
+use chrono::prelude::*;
+
+use errors::*;
+use marshal::ObjectHash;
+use repository::{Paths, Refs};
+
+
+/// The branch heads a mirror last refreshed to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MirrorState {
+    pub branches: HashMap<String, ObjectHash>,
+    pub last_refresh: Option<DateTime<Utc>>,
+}
+
+
+impl MirrorState {
+    pub fn open(paths: &Paths) -> Result<Self> {
+        if !paths.mirror.exists() {
+            return Ok(MirrorState::default());
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&paths.mirror)?.read_to_end(&mut bytes)?;
+
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        let bytes = bincode::serialize(self, bincode::Infinite)?;
+        let temp_path = paths.mirror.with_extension("bin.tmp");
+        File::create(&temp_path)?.write_all(&bytes)?;
+        fs::rename(temp_path, &paths.mirror)?;
+
+        Ok(())
+    }
+
+    /// How the branches `upstream` differ from those last refreshed to, sorted by branch name.
+    pub fn changes(&self, upstream: &HashMap<String, ObjectHash>) -> Vec<BranchChange> {
+        diff_branches(&self.branches, upstream)
+    }
+}
+
+
+/// How the branches `new` differ from the branches `old`, sorted by branch name.
+pub fn diff_branches(
+    old: &HashMap<String, ObjectHash>,
+    new: &HashMap<String, ObjectHash>,
+) -> Vec<BranchChange> {
+    let mut changes = new.iter()
+        .filter(|&(branch, &head)| old.get(branch) != Some(&head))
+        .map(|(branch, &head)| {
+            BranchChange {
+                branch: branch.clone(),
+                old: old.get(branch).cloned(),
+                new: Some(head),
+            }
+        })
+        .chain(old.iter().filter(|&(branch, _)| !new.contains_key(branch)).map(
+            |(branch, &head)| {
+                BranchChange {
+                    branch: branch.clone(),
+                    old: Some(head),
+                    new: None,
+                }
+            },
+        ))
+        .collect::<Vec<_>>();
+    changes.sort_by(|a, b| a.branch.cmp(&b.branch));
+
+    changes
+}
+
+
+/// A branch which was created, moved, or deleted upstream since the last refresh, or a
+/// remote-tracking branch which moved when a remote's branches were last read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchChange {
+    pub branch: String,
+    pub old: Option<ObjectHash>,
+    pub new: Option<ObjectHash>,
+}
+
+
+/// Read the branches of the upstream repository at `upstream`.
+pub fn upstream_branches<P: AsRef<Path>>(upstream: P) -> Result<HashMap<String, ObjectHash>> {
+    let upstream_paths = Paths::new(upstream);
+
+    if !upstream_paths.metadata.is_dir() {
+        bail!(ErrorKind::RepositoryNotFound(upstream_paths.base.clone()));
+    }
+
+    Ok(Refs::open(&upstream_paths)?.branches)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hash(byte: u8) -> ObjectHash {
+        let mut hash_string = String::new();
+        for _ in 0..32 {
+            hash_string.push_str(&format!("{:02x}", byte));
+        }
+        hash_string.parse().unwrap()
+    }
+
+    #[test]
+    fn changes_are_created_moved_and_deleted_branches() {
+        let mut state = MirrorState::default();
+        state.branches.insert("kept".to_owned(), hash(1));
+        state.branches.insert("moved".to_owned(), hash(2));
+        state.branches.insert("deleted".to_owned(), hash(3));
+
+        let mut upstream = HashMap::new();
+        upstream.insert("kept".to_owned(), hash(1));
+        upstream.insert("moved".to_owned(), hash(4));
+        upstream.insert("created".to_owned(), hash(5));
+
+        assert_eq!(
+            state.changes(&upstream),
+            vec![
+                BranchChange {
+                    branch: "created".to_owned(),
+                    old: None,
+                    new: Some(hash(5)),
+                },
+                BranchChange {
+                    branch: "deleted".to_owned(),
+                    old: Some(hash(3)),
+                    new: None,
+                },
+                BranchChange {
+                    branch: "moved".to_owned(),
+                    old: Some(hash(2)),
+                    new: Some(hash(4)),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! # `negotiation` - remembering what a remote has said it holds.
+//!
+//! A remote's catalog records the objects it is known to hold, and anything recorded there is
+//! never sent again. Anything not recorded there - everything, for a fresh clone or a scripted
+//! ingest running from a scratch repository - may still be on the remote already, pushed from
+//! elsewhere. A remote configured to negotiate asks whether it holds each such object before
+//! sending it, and skips those it already has.
+//!
+//! Each of those questions costs a round trip, and an ingest pushing every few minutes would ask
+//! about the same hundreds of thousands of objects every time. A `Negotiation` keeps the answers,
+//! in a file per remote beside its catalog, so that back-to-back pushes, even from separate
+//! processes, only ask about what is new. Answers go stale - an object the remote lacked a minute
+//! ago may have been pushed since, and one it held may since have been collected - so each is
+//! trusted only for the freshness window configured for the remote, and asked again after.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bincode;
+
+use errors::*;
+use marshal::ObjectHash;
+use repository::NegotiationCfg;
+use warning;
+
+
+/// The number of seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+
+/// What a remote said about an object, and when.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Answer {
+    present: bool,
+    at: u64,
+}
+
+
+/// Whether an answer given at `answer.at` may still be trusted at `now`.
+fn is_fresh(cfg: &NegotiationCfg, answer: &Answer, now: u64) -> bool {
+    let ttl = if answer.present {
+        cfg.present_ttl
+    } else {
+        cfg.absent_ttl
+    };
+
+    answer.at <= now && now - answer.at < ttl
+}
+
+
+#[derive(Debug)]
+struct NegotiationInner {
+    path: PathBuf,
+    cfg: NegotiationCfg,
+    answers: HashMap<ObjectHash, Answer>,
+}
+
+
+/// The remembered answers of a single remote. See the module docs.
+#[derive(Debug, Clone)]
+pub struct Negotiation {
+    inner: Arc<Mutex<NegotiationInner>>,
+}
+
+
+impl Negotiation {
+    /// Load the answers kept at `path`, dropping any which have gone stale. The file is written
+    /// back when the last clone is dropped.
+    pub fn load(path: PathBuf, cfg: &NegotiationCfg) -> Result<Self> {
+        let mut answers: HashMap<ObjectHash, Answer> = if path.is_file() {
+            bincode::deserialize_from(&mut File::open(&path)?, bincode::Infinite)
+                .chain_err(|| ErrorKind::NegotiationCacheCorrupt(path.clone()))?
+        } else {
+            HashMap::new()
+        };
+
+        let now = now();
+        answers.retain(|_, answer| is_fresh(cfg, answer, now));
+
+        Ok(Negotiation {
+            inner: Arc::new(Mutex::new(NegotiationInner {
+                path,
+                cfg: *cfg,
+                answers,
+            })),
+        })
+    }
+
+    /// Whether the remote holds an object, if it has said so recently enough to be trusted.
+    pub fn get(&self, object_hash: ObjectHash) -> Option<bool> {
+        let inner = self.inner.lock().unwrap();
+        let now = now();
+
+        match inner.answers.get(&object_hash) {
+            Some(answer) if is_fresh(&inner.cfg, answer, now) => Some(answer.present),
+            _ => None,
+        }
+    }
+
+    /// Remember what the remote just said about an object.
+    pub fn record(&self, object_hash: ObjectHash, present: bool) {
+        let answer = Answer { present, at: now() };
+        self.inner.lock().unwrap().answers.insert(object_hash, answer);
+    }
+
+    /// Forget every answer, so that each object is asked about again.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().answers.clear();
+    }
+
+    /// The number of answers remembered, fresh or not.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().answers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+impl Drop for NegotiationInner {
+    fn drop(&mut self) {
+        // The answers only save round trips, so failing to keep them is not worth failing over.
+        let temp_path = self.path.with_extension("negotiation.tmp");
+        let saved = File::create(&temp_path)
+            .map_err(Error::from)
+            .and_then(|mut file| {
+                bincode::serialize_into(&mut file, &self.answers, bincode::Infinite)
+                    .map_err(Error::from)
+            })
+            .and_then(|()| fs::rename(&temp_path, &self.path).map_err(Error::from));
+
+        if let Err(error) = saved {
+            warning::warn(format!(
+                "could not save the negotiation cache {}: {}",
+                self.path.display(),
+                error
+            ));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::Scratch;
+
+    #[test]
+    fn answers_go_stale() {
+        let scratch = Scratch::new("attaca-negotiation").unwrap();
+        let path = scratch.path().join("negotiation");
+        let cfg = NegotiationCfg {
+            present_ttl: 60,
+            absent_ttl: 0,
+        };
+        let present = "01".repeat(32).parse::<ObjectHash>().unwrap();
+        let absent = "02".repeat(32).parse::<ObjectHash>().unwrap();
+
+        {
+            let negotiation = Negotiation::load(path.clone(), &cfg).unwrap();
+            negotiation.record(present, true);
+            negotiation.record(absent, false);
+
+            assert_eq!(negotiation.get(present), Some(true));
+            assert_eq!(negotiation.get(absent), None);
+        }
+
+        let negotiation = Negotiation::load(path.clone(), &cfg).unwrap();
+        assert_eq!(negotiation.get(present), Some(true));
+        assert_eq!(negotiation.len(), 1);
+    }
+}
@@ -0,0 +1,280 @@
+//! # `notes` - derived metadata attached to commits.
+//!
+//! Generators are commands listed in `config.toml` which are run for each new commit to produce
+//! metadata about it, such as a checksum manifest, thumbnails, or a schema summary. Each is run in
+//! the root of the repository, with the commit described by its environment:
+//!
+//! * `ATTACA_COMMIT` - the hash of the commit.
+//! * `ATTACA_SUBTREE` - the hash of the commit's subtree.
+//! * `ATTACA_PARENTS` - the hashes of the commit's parents, separated by spaces.
+//!
+//! Whatever a generator writes to its standard output is stored as a data object, and attached to
+//! the commit under the generator's name.
+//!
+//! Notes have a history of their own, on the branch `NOTES_BRANCH`. The subtree of each notes
+//! commit holds one directory per annotated commit, named by the commit's hash, which holds one
+//! file per generator. Notes are therefore pushed and fetched like any other branch, and
+//! regenerating a note keeps its earlier versions in the notes history.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::process::{Command, Stdio};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+
+use errors::*;
+use history::merge;
+use marshal::{ObjectHash, Object, CommitObject, SubtreeObject, SubtreeEntry};
+use marshal::canonical::Version;
+use repository::{GeneratorCfg, Paths, Repository};
+use store::ObjectStore;
+
+
+/// The branch holding the history of every commit's notes.
+pub const NOTES_BRANCH: &str = "attaca-notes";
+
+
+/// The output of a generator, as attached to a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub hash: ObjectHash,
+    pub size: u64,
+}
+
+
+/// The outcome of running the configured generators for a commit.
+#[derive(Debug, Default)]
+pub struct Generated {
+    /// The names of the generators whose outputs were attached.
+    pub attached: Vec<String>,
+
+    /// The generators which failed, and were not required to succeed.
+    pub failed: Vec<(String, Error)>,
+}
+
+
+/// Run a generator for a commit, returning what it wrote to its standard output.
+pub fn run(
+    generator: &GeneratorCfg,
+    paths: &Paths,
+    commit_hash: ObjectHash,
+    commit: &CommitObject,
+) -> Result<Vec<u8>> {
+    if generator.name.is_empty() || generator.name.contains('/') {
+        bail!(ErrorKind::InvalidGeneratorName(generator.name.clone()));
+    }
+
+    let (program, args) = match generator.command.split_first() {
+        Some(split) => split,
+        None => {
+            bail!(ErrorKind::GeneratorFailed(
+                generator.name.clone(),
+                "no command is configured".to_owned(),
+            ))
+        }
+    };
+    let parents = commit
+        .parents
+        .iter()
+        .map(ObjectHash::to_string)
+        .collect::<Vec<_>>();
+
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(&paths.base)
+        .env("ATTACA_COMMIT", commit_hash.to_string())
+        .env("ATTACA_SUBTREE", commit.subtree.to_string())
+        .env("ATTACA_PARENTS", parents.join(" "))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .chain_err(|| {
+            ErrorKind::GeneratorFailed(generator.name.clone(), "could not be started".to_owned())
+        })?;
+
+    if !output.status.success() {
+        bail!(ErrorKind::GeneratorFailed(
+            generator.name.clone(),
+            output.status.to_string(),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+
+fn read_entries<S: ObjectStore>(
+    store: &S,
+    hash_opt: Option<ObjectHash>,
+) -> Box<Future<Item = BTreeMap<OsString, SubtreeEntry>, Error = Error> + Send> {
+    match hash_opt {
+        Some(hash) => {
+            Box::new(store.read_object(hash).and_then(move |object| match object {
+                Object::Subtree(subtree_object) => Ok(subtree_object.entries),
+                _ => bail!(ErrorKind::ObjectNotASubtree(hash)),
+            }))
+        }
+        None => Box::new(Ok(BTreeMap::new()).into_future()),
+    }
+}
+
+
+/// The subtree of the notes commit `notes_head`, if there is one.
+fn notes_subtree<S: ObjectStore>(
+    store: &S,
+    notes_head: Option<ObjectHash>,
+) -> Box<Future<Item = Option<ObjectHash>, Error = Error> + Send> {
+    match notes_head {
+        Some(hash) => {
+            Box::new(store.read_object(hash).and_then(move |object| match object {
+                Object::Commit(commit_object) => Ok(Some(commit_object.subtree)),
+                _ => bail!(ErrorKind::ObjectNotACommit(hash)),
+            }))
+        }
+        None => Box::new(Ok(None).into_future()),
+    }
+}
+
+
+/// Read the notes attached to a commit, as of the notes commit `notes_head`.
+pub fn read<S: ObjectStore>(
+    store: &S,
+    notes_head: Option<ObjectHash>,
+    commit_hash: ObjectHash,
+) -> Box<Future<Item = BTreeMap<String, Note>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_opt = await!(notes_subtree(&store, notes_head))?;
+            let root_entries = await!(read_entries(&store, subtree_opt))?;
+            let commit_subtree = match root_entries.get(&OsString::from(commit_hash.to_string())) {
+                Some(&SubtreeEntry::Subtree(hash)) => Some(hash),
+                _ => None,
+            };
+
+            let notes = await!(read_entries(&store, commit_subtree))?
+                .into_iter()
+                .filter_map(|(name, entry)| match entry {
+                    SubtreeEntry::File(hash, size) => {
+                        Some((name.to_string_lossy().into_owned(), Note { hash, size }))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            Ok(notes)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Attach notes to a commit, replacing any it already has under the same names. Returns the new
+/// notes commit, whose parent is `notes_head`.
+pub fn attach<S: ObjectStore>(
+    store: &S,
+    version: Version,
+    notes_head: Option<ObjectHash>,
+    commit_hash: ObjectHash,
+    notes: BTreeMap<String, Note>,
+) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let commit_name = OsString::from(commit_hash.to_string());
+            let subtree_opt = await!(notes_subtree(&store, notes_head))?;
+            let mut root_entries = await!(read_entries(&store, subtree_opt))?;
+            let commit_subtree = match root_entries.get(&commit_name) {
+                Some(&SubtreeEntry::Subtree(hash)) => Some(hash),
+                _ => None,
+            };
+
+            let mut entries = await!(read_entries(&store, commit_subtree))?;
+            for (name, note) in notes {
+                entries.insert(OsString::from(name), SubtreeEntry::File(note.hash, note.size));
+            }
+
+            let commit_subtree =
+                await!(merge::write(&store, Object::Subtree(SubtreeObject { entries }), version))?;
+            root_entries.insert(commit_name, SubtreeEntry::Subtree(commit_subtree));
+
+            let root_object = Object::Subtree(SubtreeObject { entries: root_entries });
+            let subtree = await!(merge::write(&store, root_object, version))?;
+
+            let notes_commit = CommitObject {
+                subtree,
+                parents: notes_head.into_iter().collect(),
+                message: format!("Notes for {}", commit_hash),
+                timestamp: Utc::now(),
+                signature: None,
+                author: None,
+                committer: None,
+            };
+
+            await!(merge::write(&store, Object::Commit(notes_commit), version))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Run every configured generator for a commit, attach their outputs to it, and advance
+/// `NOTES_BRANCH` to the resulting notes commit. A generator which is required to succeed fails
+/// the whole operation if it fails; any other is only reported.
+pub fn generate(repository: &mut Repository, commit_hash: ObjectHash) -> Result<Generated> {
+    let generators = repository.config.generators.clone();
+    let mut generated = Generated::default();
+
+    if generators.is_empty() {
+        return Ok(generated);
+    }
+
+    let notes_head = repository.refs.branches.get(NOTES_BRANCH).cloned();
+    let version = repository.object_version;
+
+    let new_head = {
+        let ctx = repository.local(())?;
+        let commit = ctx.read_commit(commit_hash).wait()?;
+        let mut notes = BTreeMap::new();
+
+        for generator in &generators {
+            let output = match run(generator, &ctx.paths, commit_hash, &commit) {
+                Ok(output) => output,
+                Err(error) => {
+                    if generator.required {
+                        return Err(error);
+                    }
+
+                    generated.failed.push((generator.name.clone(), error));
+                    continue;
+                }
+            };
+
+            let size = output.len() as u64;
+            let hash = ctx.write_bytes(output).wait()?;
+            notes.insert(generator.name.clone(), Note { hash, size });
+            generated.attached.push(generator.name.clone());
+        }
+
+        let new_head = if notes.is_empty() {
+            None
+        } else {
+            Some(attach(ctx.store(), version, notes_head, commit_hash, notes).wait()?)
+        };
+
+        ctx.close().wait()?;
+
+        new_head
+    };
+
+    if let Some(new_head) = new_head {
+        repository.compare_and_swap_branch(NOTES_BRANCH, notes_head, new_head)?;
+    }
+
+    Ok(generated)
+}
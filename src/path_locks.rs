@@ -0,0 +1,430 @@
+//! # `path_locks` - exclusive locks on paths, for files which cannot be merged.
+//!
+//! Two people changing the same image or model checkpoint at once leaves one of them to throw
+//! their work away, since there is no merging binary files. Locking a path first tells everyone
+//! else that it is being changed, and a push which changes a path locked by someone else is
+//! refused.
+//!
+//! Like notes and branch metadata, locks have a history of their own, on the branch
+//! `LOCKS_BRANCH`. The subtree of each of its commits holds a single TOML file listing every lock
+//! held, so locks are pushed and fetched like any other branch. A push is checked against the
+//! locks held here and those last fetched from the remote being pushed to, so a lock taken
+//! elsewhere is respected as soon as `LOCKS_BRANCH` has been fetched from the remote.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use toml;
+
+use arc_slice;
+use checkout;
+use errors::*;
+use history::merge;
+use marshal::{ObjectHash, Object, CommitObject, DataObject, SmallObject, SubtreeObject,
+              SubtreeEntry, Identity};
+use marshal::canonical::Version;
+use repository::Repository;
+use store::ObjectStore;
+
+
+/// The branch holding the history of every lock.
+pub const LOCKS_BRANCH: &str = "attaca-locks";
+
+
+/// The file, in the subtree of each locks commit, listing every lock held.
+const LOCKS_FILE: &str = "locks.toml";
+
+
+/// Who holds a lock on a path, and since when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathLock {
+    // TOML requires plain values to come before tables, so `holder` must be last.
+    pub locked_at: DateTime<Utc>,
+    pub holder: Identity,
+}
+
+
+/// Every lock held as of some locks commit, by the path it is held on. Paths are relative to the
+/// root of the repository.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathLocks {
+    #[serde(default)]
+    pub paths: BTreeMap<PathBuf, PathLock>,
+}
+
+
+impl PathLocks {
+    /// The locks on any of `paths` held by anyone but `identity`. With no identity, every lock is
+    /// held by someone else.
+    pub fn conflicts<'a>(
+        &'a self,
+        identity: Option<&Identity>,
+        paths: &'a [PathBuf],
+    ) -> Vec<(&'a Path, &'a PathLock)> {
+        paths
+            .iter()
+            .filter_map(|path| self.paths.get(path).map(|lock| (path.as_path(), lock)))
+            .filter(|&(_, lock)| Some(&lock.holder) != identity)
+            .collect()
+    }
+}
+
+
+/// The subtree of the locks commit `locks_head`, if there is one.
+fn locks_subtree<S: ObjectStore>(
+    store: &S,
+    locks_head: Option<ObjectHash>,
+) -> Box<Future<Item = Option<ObjectHash>, Error = Error> + Send> {
+    match locks_head {
+        Some(hash) => {
+            Box::new(store.read_object(hash).and_then(move |object| match object {
+                Object::Commit(commit_object) => Ok(Some(commit_object.subtree)),
+                _ => bail!(ErrorKind::ObjectNotACommit(hash)),
+            }))
+        }
+        None => Box::new(Ok(None).into_future()),
+    }
+}
+
+
+/// Read every lock held as of the locks commit `locks_head`.
+pub fn read<S: ObjectStore>(
+    store: &S,
+    locks_head: Option<ObjectHash>,
+) -> Box<Future<Item = PathLocks, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_hash = match await!(locks_subtree(&store, locks_head))? {
+                Some(subtree_hash) => subtree_hash,
+                None => return Ok(PathLocks::default()),
+            };
+            let hash = match await!(store.read_object(subtree_hash))? {
+                Object::Subtree(subtree_object) => {
+                    match subtree_object.entries.get(&OsString::from(LOCKS_FILE)) {
+                        Some(&SubtreeEntry::File(hash, _)) => hash,
+                        _ => return Ok(PathLocks::default()),
+                    }
+                }
+                _ => bail!(ErrorKind::ObjectNotASubtree(subtree_hash)),
+            };
+
+            let bytes = await!(checkout::data_chunks(&store, hash).fold(
+                Vec::new(),
+                |mut bytes, chunk| {
+                    bytes.extend_from_slice(&chunk);
+                    Ok::<_, Error>(bytes)
+                },
+            ))?;
+            let text = String::from_utf8(bytes).chain_err(|| "locks are not valid UTF-8")?;
+
+            Ok(toml::from_str(&text)?)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Record `locks` as every lock held, returning the new locks commit, whose parent is
+/// `locks_head`.
+pub fn write<S: ObjectStore>(
+    store: &S,
+    version: Version,
+    locks_head: Option<ObjectHash>,
+    locks: &PathLocks,
+    message: String,
+) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+    let store = store.clone();
+    let locks = locks.clone();
+
+    let result = {
+        async_block! {
+            let bytes = toml::to_vec(&locks)?;
+            let size = bytes.len() as u64;
+            let small = SmallObject { chunk: arc_slice::owned(bytes) };
+            let data_object = Object::Data(DataObject::Small(small));
+            let hash = await!(merge::write(&store, data_object, version))?;
+
+            let mut entries = BTreeMap::new();
+            entries.insert(OsString::from(LOCKS_FILE), SubtreeEntry::File(hash, size));
+            let root_object = Object::Subtree(SubtreeObject { entries });
+            let subtree = await!(merge::write(&store, root_object, version))?;
+
+            let locks_commit = CommitObject {
+                subtree,
+                parents: locks_head.into_iter().collect(),
+                message,
+                timestamp: Utc::now(),
+                signature: None,
+                author: None,
+                committer: None,
+            };
+
+            await!(merge::write(&store, Object::Commit(locks_commit), version))
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// The files of the commit `commit_hash`, by path.
+fn files<S: ObjectStore>(
+    store: &S,
+    commit_hash: ObjectHash,
+) -> Box<Future<Item = HashMap<PathBuf, SubtreeEntry>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_hash = match await!(store.read_object(commit_hash))? {
+                Object::Commit(commit_object) => commit_object.subtree,
+                _ => bail!(ErrorKind::ObjectNotACommit(commit_hash)),
+            };
+            let listing = await!(checkout::walk(&store, subtree_hash))?;
+
+            Ok(listing.files.into_iter().collect())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// The paths of files added, changed or removed between the commits `from` and `to`. With no
+/// `from`, every file in `to` is new.
+pub fn changed_paths<S: ObjectStore>(
+    store: &S,
+    from: Option<ObjectHash>,
+    to: ObjectHash,
+) -> Box<Future<Item = Vec<PathBuf>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let mut before = match from {
+                Some(from) => await!(files(&store, from))?,
+                None => HashMap::new(),
+            };
+            let after = await!(files(&store, to))?;
+
+            let mut changed = Vec::new();
+            for (path, entry) in after {
+                if before.remove(&path).as_ref() != Some(&entry) {
+                    changed.push(path);
+                }
+            }
+            changed.extend(before.into_iter().map(|(path, _)| path));
+            changed.sort();
+
+            Ok(changed)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// The locks held as of the local `LOCKS_BRANCH`, followed by those last fetched from each of
+/// `remotes`.
+fn views(repository: &mut Repository, remotes: &[String]) -> Result<Vec<PathLocks>> {
+    let mut heads = vec![repository.refs.branches.get(LOCKS_BRANCH).cloned()];
+    for remote in remotes {
+        let remote_head = repository.refs.remotes.get(remote).and_then(|branches| {
+            branches.get(LOCKS_BRANCH).cloned()
+        });
+        if remote_head.is_some() {
+            heads.push(remote_head);
+        }
+    }
+
+    let ctx = repository.local(())?;
+    let mut views = Vec::new();
+    for head in heads {
+        views.push(read(ctx.store(), head).wait()?);
+    }
+    ctx.close().wait()?;
+
+    Ok(views)
+}
+
+
+/// Every lock held, as of the local `LOCKS_BRANCH`.
+pub fn get(repository: &mut Repository) -> Result<PathLocks> {
+    Ok(views(repository, &[])?.remove(0))
+}
+
+
+/// Replace every lock held with `locks`, advancing `LOCKS_BRANCH` to record the change.
+fn set(repository: &mut Repository, locks: &PathLocks, message: String) -> Result<()> {
+    let locks_head = repository.refs.branches.get(LOCKS_BRANCH).cloned();
+    let version = repository.object_version;
+
+    let new_head = {
+        let ctx = repository.local(())?;
+        let new_head = write(ctx.store(), version, locks_head, locks, message).wait()?;
+        ctx.close().wait()?;
+
+        new_head
+    };
+
+    repository.compare_and_swap_branch(LOCKS_BRANCH, locks_head, new_head)
+}
+
+
+/// Lock `paths` as the configured author. Fails, locking nothing, if any of them is already
+/// locked by someone else, whether here or as last fetched from any remote.
+pub fn lock(repository: &mut Repository, paths: &[PathBuf]) -> Result<()> {
+    let holder = match repository.config.author() {
+        Some(holder) => holder,
+        None => bail!(ErrorKind::NoIdentity),
+    };
+
+    let remotes = repository.config.remotes.keys().cloned().collect::<Vec<_>>();
+    let mut held = views(repository, &remotes)?;
+    for view in &held {
+        if let Some(&(path, lock)) = view.conflicts(Some(&holder), paths).first() {
+            bail!(ErrorKind::PathLocked(path.to_owned(), lock.holder.clone()));
+        }
+    }
+
+    let mut locks = held.remove(0);
+    let locked_at = Utc::now();
+    for path in paths {
+        locks.paths.entry(path.clone()).or_insert_with(|| {
+            PathLock {
+                locked_at,
+                holder: holder.clone(),
+            }
+        });
+    }
+
+    let message = format!("{} locked {} paths", holder, paths.len());
+    set(repository, &locks, message)
+}
+
+
+/// Release the locks on `paths`. Unless `force` is given, a lock held by someone else is not
+/// released, and nothing is.
+pub fn unlock(repository: &mut Repository, paths: &[PathBuf], force: bool) -> Result<()> {
+    let identity = repository.config.author();
+    let mut locks = get(repository)?;
+
+    for path in paths {
+        match locks.paths.get(path) {
+            Some(lock) if !force && Some(&lock.holder) != identity.as_ref() => {
+                bail!(ErrorKind::PathLocked(path.clone(), lock.holder.clone()))
+            }
+            Some(_) => {}
+            None => bail!(ErrorKind::PathNotLocked(path.clone())),
+        }
+    }
+
+    for path in paths {
+        locks.paths.remove(path);
+    }
+
+    let message = match identity {
+        Some(identity) => format!("{} unlocked {} paths", identity, paths.len()),
+        None => format!("Unlock {} paths", paths.len()),
+    };
+    set(repository, &locks, message)
+}
+
+
+/// Refuse to push `commit_hash` to `remote`, where it replaces `previous`, if it changes a path
+/// locked by anyone but the configured author. `LOCKS_BRANCH` itself may always be pushed, or no
+/// lock could ever be shared.
+pub fn check_push(
+    repository: &mut Repository,
+    remote: &str,
+    branch: &str,
+    previous: Option<ObjectHash>,
+    commit_hash: ObjectHash,
+) -> Result<()> {
+    if branch == LOCKS_BRANCH {
+        return Ok(());
+    }
+
+    let views = views(repository, &[remote.to_owned()])?;
+    if views.iter().all(|view| view.paths.is_empty()) {
+        return Ok(());
+    }
+
+    let changed = {
+        let ctx = repository.local(())?;
+        let changed = changed_paths(ctx.store(), previous, commit_hash).wait()?;
+        ctx.close().wait()?;
+
+        changed
+    };
+
+    let identity = repository.config.author();
+    for view in &views {
+        if let Some(&(path, lock)) = view.conflicts(identity.as_ref(), &changed).first() {
+            bail!(ErrorKind::PathLocked(path.to_owned(), lock.holder.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn identity(name: &str) -> Identity {
+        Identity {
+            name: name.to_owned(),
+            email: format!("{}@example.com", name),
+        }
+    }
+
+    #[test]
+    fn conflicts_are_locks_held_by_others() {
+        let mut locks = PathLocks::default();
+        for &(path, holder) in &[("a.psd", "alice"), ("b.psd", "bob")] {
+            locks.paths.insert(
+                PathBuf::from(path),
+                PathLock {
+                    locked_at: Utc::now(),
+                    holder: identity(holder),
+                },
+            );
+        }
+
+        let paths = ["a.psd", "b.psd", "c.psd"]
+            .iter()
+            .map(|path| PathBuf::from(*path))
+            .collect::<Vec<_>>();
+        let alice = identity("alice");
+
+        let conflicts = locks.conflicts(Some(&alice), &paths);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, Path::new("b.psd"));
+
+        assert_eq!(locks.conflicts(None, &paths).len(), 2);
+    }
+
+    #[test]
+    fn locks_round_trip_through_toml() {
+        let mut locks = PathLocks::default();
+        locks.paths.insert(
+            PathBuf::from("assets/logo.psd"),
+            PathLock {
+                locked_at: Utc::now(),
+                holder: identity("alice"),
+            },
+        );
+
+        let text = toml::to_string(&locks).unwrap();
+        assert_eq!(toml::from_str::<PathLocks>(&text).unwrap(), locks);
+        assert_eq!(toml::from_str::<PathLocks>("").unwrap(), PathLocks::default());
+    }
+}
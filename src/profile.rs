@@ -0,0 +1,270 @@
+//! # `profile` - record what a command spends its time on, for viewing as a flame chart.
+//!
+//! When enabled, the library records a span for every object a store reads or writes and for
+//! every piece of CPU-bound work it does while marshalling, and `write` saves them in the Chrome
+//! trace format, which both `chrome://tracing` and Perfetto (<https://ui.perfetto.dev>) open.
+//!
+//! Store operations are futures, and run interleaved on a handful of threads, so each is recorded
+//! as an async span reaching from when the operation was started to when it finished. Its
+//! arguments split that time into `work_us`, spent actually polling the operation, and `wait_us`,
+//! spent waiting on it to be woken - by the disk, by the network, or behind other operations. A
+//! slow push whose writes are almost all `wait_us` is network bound; one whose `cpu` spans fill
+//! the marshalling threads is bound by hashing.
+//!
+//! Recording is off unless `enable` is called, and costs next to nothing while it is.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use libc;
+use serde_json;
+
+use errors::*;
+
+
+/// Spans which have to do with a store on the local filesystem.
+pub const DISK: &'static str = "disk";
+
+
+/// Spans which have to do with a remote store.
+pub const NETWORK: &'static str = "network";
+
+
+/// Spans of CPU-bound work, such as hashing and chunking.
+pub const CPU: &'static str = "cpu";
+
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+    static ref EVENTS: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+}
+
+
+thread_local! {
+    /// A small number standing in for the current thread, as the trace format wants.
+    static THREAD_ID: usize = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64
+}
+
+
+fn since_start(instant: Instant) -> u64 {
+    if instant > *START {
+        micros(instant.duration_since(*START))
+    } else {
+        0
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Args {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    work_us: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wait_us: Option<u64>,
+}
+
+
+/// A single event, as laid out by the Chrome trace format.
+#[derive(Debug, Clone, Serialize)]
+struct Event {
+    name: &'static str,
+    cat: &'static str,
+    ph: &'static str,
+    ts: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<usize>,
+
+    pid: u32,
+    tid: usize,
+    args: Args,
+}
+
+
+impl Event {
+    fn new(name: &'static str, cat: &'static str, ph: &'static str, ts: u64, args: Args) -> Self {
+        Event {
+            name,
+            cat,
+            ph,
+            ts,
+            dur: None,
+            id: None,
+            pid: unsafe { libc::getpid() } as u32,
+            tid: THREAD_ID.with(|&tid| tid),
+            args,
+        }
+    }
+}
+
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceFile<'a> {
+    trace_events: &'a [Event],
+    display_time_unit: &'static str,
+}
+
+
+/// Start recording spans. Spans are kept in memory until `write` is called.
+pub fn enable() {
+    // Make sure timestamps are measured from no later than now.
+    let _ = *START;
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+
+/// Run `f` as a span of synchronous work, named `name`, on the current thread.
+pub fn sync<R, F: FnOnce() -> R>(category: &'static str, name: &'static str, f: F) -> R {
+    if !is_enabled() {
+        return f();
+    }
+
+    let started = Instant::now();
+    let result = f();
+    let elapsed = micros(started.elapsed());
+
+    let args = Args {
+        size: None,
+        work_us: Some(elapsed),
+        wait_us: None,
+    };
+    let mut event = Event::new(name, category, "X", since_start(started), args);
+    event.dur = Some(elapsed);
+    EVENTS.lock().unwrap().push(event);
+
+    result
+}
+
+
+/// Record `future` as an async span named `name`, from now until it resolves. `size` is the
+/// number of bytes the operation moves, if known up front.
+pub fn timed<F: Future>(
+    category: &'static str,
+    name: &'static str,
+    size: Option<u64>,
+    future: F,
+) -> Timed<F> {
+    let span = if is_enabled() {
+        Some(Span {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            started: Instant::now(),
+            work: Duration::new(0, 0),
+        })
+    } else {
+        None
+    };
+
+    Timed {
+        category,
+        name,
+        size,
+        future,
+        span,
+    }
+}
+
+
+struct Span {
+    id: usize,
+    started: Instant,
+    work: Duration,
+}
+
+
+/// A future recorded as a span. See `timed`.
+pub struct Timed<F> {
+    category: &'static str,
+    name: &'static str,
+    size: Option<u64>,
+    future: F,
+    span: Option<Span>,
+}
+
+
+impl<F: Future> Future for Timed<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let polled = Instant::now();
+        let result = self.future.poll();
+
+        if let Some(mut span) = self.span.take() {
+            let finished = Instant::now();
+            span.work += finished.duration_since(polled);
+
+            match result {
+                Ok(Async::NotReady) => self.span = Some(span),
+                _ => {
+                    let total = finished.duration_since(span.started);
+                    let args = Args {
+                        size: self.size,
+                        work_us: Some(micros(span.work)),
+                        wait_us: Some(micros(total) - micros(span.work)),
+                    };
+                    let empty = Args {
+                        size: None,
+                        work_us: None,
+                        wait_us: None,
+                    };
+
+                    let mut begin =
+                        Event::new(self.name, self.category, "b", since_start(span.started), args);
+                    begin.id = Some(span.id);
+                    let mut end =
+                        Event::new(self.name, self.category, "e", since_start(finished), empty);
+                    end.id = Some(span.id);
+
+                    let mut events = EVENTS.lock().unwrap();
+                    events.push(begin);
+                    events.push(end);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+
+/// Write every span recorded so far to `path`, as a Chrome trace.
+pub fn write<P: AsRef<Path>>(path: P) -> Result<()> {
+    let events = EVENTS.lock().unwrap();
+    let trace_file = TraceFile {
+        trace_events: &events,
+        display_time_unit: "ms",
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, &trace_file)?;
+    writer.flush()?;
+
+    Ok(())
+}
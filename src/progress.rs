@@ -0,0 +1,374 @@
+//! # `progress` - machine-readable progress reports for the programs which run attaca.
+//!
+//! Orchestrators and GUIs which spawn attaca for long transfers can follow along by listening on
+//! a Unix socket and passing its path in `ATTACA_PROGRESS_SOCKET`. attaca connects to the socket
+//! when it starts, and writes one JSON `Report` per line: one when the command starts, then as
+//! work progresses (at most every `REPORT_INTERVAL_MS` milliseconds), and a final one once the
+//! command has finished, failed, or been cancelled.
+//!
+//! The other end of the socket may send control messages, also one JSON object per line. The
+//! only one understood is `{"type": "cancel"}`, which interrupts attaca exactly as if it had
+//! received `SIGINT`; as after any interruption, `attaca recover` cleans up whatever was left
+//! behind. Connecting to the socket is best-effort: if it fails, attaca runs as usual.
+
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+use serde_json;
+
+use errors::*;
+use marshal::ObjectHash;
+use telemetry::{COUNTERS, Measurements};
+use trace::Trace;
+
+
+/// The environment variable naming the socket to report progress to.
+pub const PROGRESS_SOCKET_VAR: &'static str = "ATTACA_PROGRESS_SOCKET";
+
+
+/// The least time between two reports sent while work progresses, in milliseconds.
+pub const REPORT_INTERVAL_MS: u64 = 250;
+
+
+lazy_static! {
+    static ref INSTALLED: Mutex<Option<ProgressReporter>> = Mutex::new(None);
+}
+
+
+/// The state of the command being reported on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+
+/// A single progress report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// The subcommand being run, e.g. `push` or `remote add`.
+    pub command: String,
+
+    pub state: State,
+
+    /// What the command is currently doing: `start`, `split` while files are being hashed, and
+    /// `write` while objects are being written, or whatever the command has set.
+    pub stage: String,
+
+    pub bytes_to_split: u64,
+    pub bytes_split: u64,
+    pub objects_total: u64,
+    pub objects_written: u64,
+
+    /// The process-wide counters, including bytes sent to and fetched from remotes.
+    pub measurements: Measurements,
+
+    pub elapsed_ms: u64,
+
+    /// An estimate of how long the current stage will take to finish, if one can be made.
+    pub eta_ms: Option<u64>,
+
+    /// Why the command failed, if it did.
+    pub error: Option<String>,
+}
+
+
+/// A message sent to attaca by the other end of the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Control {
+    Cancel,
+}
+
+
+#[derive(Debug)]
+struct ReporterInner {
+    stream: Option<UnixStream>,
+
+    command: String,
+    state: State,
+    stage: String,
+
+    bytes_to_split: u64,
+    bytes_split: u64,
+    objects_total: u64,
+    objects_written: u64,
+
+    started: Instant,
+    stage_started: Instant,
+    last_sent: Option<Instant>,
+}
+
+
+impl ReporterInner {
+    fn enter_stage(&mut self, stage: &str) {
+        if self.stage != stage {
+            self.stage = stage.to_owned();
+            self.stage_started = Instant::now();
+            self.last_sent = None;
+        }
+    }
+
+    fn eta_ms(&self) -> Option<u64> {
+        let (done, total) = match self.stage.as_str() {
+            "split" => (self.bytes_split, self.bytes_to_split),
+            "write" => (self.objects_written, self.objects_total),
+            _ => return None,
+        };
+
+        if done == 0 || total < done {
+            return None;
+        }
+
+        let stage_ms = millis(self.stage_started.elapsed());
+        Some(stage_ms * (total - done) / done)
+    }
+
+    fn report(&self, error: Option<String>) -> Report {
+        Report {
+            command: self.command.clone(),
+            state: self.state,
+            stage: self.stage.clone(),
+            bytes_to_split: self.bytes_to_split,
+            bytes_split: self.bytes_split,
+            objects_total: self.objects_total,
+            objects_written: self.objects_written,
+            measurements: COUNTERS.snapshot(),
+            elapsed_ms: millis(self.started.elapsed()),
+            eta_ms: self.eta_ms(),
+            error,
+        }
+    }
+
+    /// Send a report, unless one was sent too recently and `force` is not set. If the other end
+    /// has gone away, stop reporting.
+    fn send(&mut self, force: bool, error: Option<String>) {
+        let interval = Duration::from_millis(REPORT_INTERVAL_MS);
+        if !force && self.last_sent.map_or(false, |sent| sent.elapsed() < interval) {
+            return;
+        }
+
+        let mut line = match serde_json::to_vec(&self.report(error)) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+
+        let failed = match self.stream {
+            Some(ref mut stream) => stream.write_all(&line).is_err(),
+            None => return,
+        };
+
+        if failed {
+            self.stream = None;
+        } else {
+            self.last_sent = Some(Instant::now());
+        }
+    }
+
+    /// Stop reporting. The reader of control messages holds its own handle to the socket, so the
+    /// other end only sees it close once it has been shut down.
+    fn close(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+    }
+}
+
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+
+/// Reports the progress of a command over a Unix socket. As a trace object, it follows splitting
+/// and writing; cloning it shares the connection.
+#[derive(Debug, Clone)]
+pub struct ProgressReporter {
+    inner: Arc<Mutex<ReporterInner>>,
+}
+
+
+impl ProgressReporter {
+    /// Connect to the socket at `path` and report that `command` has started. Control messages
+    /// are read from the socket on a background thread.
+    pub fn connect<P: AsRef<Path>>(path: P, command: &str) -> Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let control = stream.try_clone()?;
+        let now = Instant::now();
+
+        let reporter = ProgressReporter {
+            inner: Arc::new(Mutex::new(ReporterInner {
+                stream: Some(stream),
+
+                command: command.to_owned(),
+                state: State::Running,
+                stage: "start".to_owned(),
+
+                bytes_to_split: 0,
+                bytes_split: 0,
+                objects_total: 0,
+                objects_written: 0,
+
+                started: now,
+                stage_started: now,
+                last_sent: None,
+            })),
+        };
+
+        reporter.inner.lock().unwrap().send(true, None);
+
+        {
+            let reporter = reporter.clone();
+            thread::spawn(move || for line_res in BufReader::new(control).lines() {
+                let line = match line_res {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+
+                if let Ok(Control::Cancel) = serde_json::from_str(&line) {
+                    reporter.cancel();
+                }
+            });
+        }
+
+        Ok(reporter)
+    }
+
+    /// Set the stage reported, for commands whose work is not just splitting and writing.
+    pub fn set_stage(&self, stage: &str) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.enter_stage(stage);
+        inner.send(true, None);
+    }
+
+    /// Send the final report for a command which has finished, or failed with `error`.
+    pub fn finish(&self, error: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state == State::Running {
+            inner.state = if error.is_some() {
+                State::Failed
+            } else {
+                State::Finished
+            };
+        }
+
+        inner.send(true, error);
+        inner.close();
+    }
+
+    fn cancel(&self) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.state = State::Cancelled;
+            inner.send(true, None);
+            inner.close();
+        }
+
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGINT);
+        }
+    }
+
+    fn update<F: FnOnce(&mut ReporterInner)>(&self, f: F) {
+        let mut inner = self.inner.lock().unwrap();
+
+        f(&mut inner);
+        inner.send(false, None);
+    }
+}
+
+
+impl Trace for ProgressReporter {
+    fn on_split_begin(&self, size: u64) {
+        self.update(|inner| {
+            inner.enter_stage("split");
+            inner.bytes_to_split += size;
+        });
+    }
+
+    fn on_split_chunk(&self, _offset: u64, chunk: &[u8]) {
+        self.update(|inner| inner.bytes_split += chunk.len() as u64);
+    }
+
+    fn on_marshal_process(&self, _object_hash: &ObjectHash) {
+        self.update(|inner| inner.objects_total += 1);
+    }
+
+    fn on_write_object_start(&self, _object_hash: &ObjectHash) {
+        self.update(|inner| inner.enter_stage("write"));
+    }
+
+    fn on_write_object_finish(&self, _object_hash: &ObjectHash, _fresh: bool) {
+        self.update(|inner| inner.objects_written += 1);
+    }
+}
+
+
+/// Connect to the socket named by `ATTACA_PROGRESS_SOCKET`, if it is set, and report progress on
+/// `command` to it for the rest of the process. See `installed`.
+pub fn install(command: &str) -> Result<()> {
+    let path = match env::var_os(PROGRESS_SOCKET_VAR) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let reporter = ProgressReporter::connect(&path, command).chain_err(|| {
+        format!("could not connect to the progress socket {}", Path::new(&path).display())
+    })?;
+    *INSTALLED.lock().unwrap() = Some(reporter);
+
+    Ok(())
+}
+
+
+/// The reporter installed for this process, if any.
+pub fn installed() -> Option<ProgressReporter> {
+    INSTALLED.lock().unwrap().clone()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+
+    use rand;
+
+    #[test]
+    fn reports_start_and_finish() {
+        let path = env::temp_dir().join(format!("attaca-progress-{}.sock", rand::random::<u64>()));
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let reporter = ProgressReporter::connect(&path, "push").unwrap();
+        reporter.on_split_begin(10);
+        reporter.finish(None);
+
+        let (stream, _) = listener.accept().unwrap();
+        let reports = BufReader::new(stream)
+            .lines()
+            .map(|line| serde_json::from_str::<Report>(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(reports.first().unwrap().stage, "start");
+        let last = reports.last().unwrap();
+        assert_eq!(last.command, "push");
+        assert_eq!(last.state, State::Finished);
+        assert_eq!(last.bytes_to_split, 10);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+//! # `promised` - record objects deliberately left on a remote.
+//!
+//! A fetch restricted to some paths skips every entry of the tree outside of them. The hashes of
+//! the skipped entries are listed in `.attaca/promised`, one per line along with the path at
+//! which they were skipped, so that their absence from the local store is known to be deliberate
+//! rather than a sign of corruption, and so that they can be fetched later by path.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use errors::*;
+use marshal::ObjectHash;
+use repository::Paths;
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Promised {
+    objects: HashMap<ObjectHash, PathBuf>,
+}
+
+
+impl Promised {
+    pub fn open(paths: &Paths) -> Result<Self> {
+        if !paths.promised.exists() {
+            return Ok(Promised::default());
+        }
+
+        let mut objects = HashMap::new();
+        for line_res in BufReader::new(File::open(&paths.promised)?).lines() {
+            let line = line_res?;
+            let mut split = line.splitn(2, ' ');
+
+            if let (Some(hash), Some(path)) = (split.next(), split.next()) {
+                objects.insert(hash.parse()?, PathBuf::from(path));
+            }
+        }
+
+        Ok(Promised { objects })
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        if self.objects.is_empty() {
+            if paths.promised.exists() {
+                fs::remove_file(&paths.promised)?;
+            }
+
+            return Ok(());
+        }
+
+        let mut objects = self.objects.iter().collect::<Vec<_>>();
+        objects.sort_by(|a, b| a.1.cmp(b.1));
+
+        let mut file = File::create(&paths.promised)?;
+        for (hash, path) in objects {
+            writeln!(file, "{} {}", hash, path.display())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn contains(&self, hash: &ObjectHash) -> bool {
+        self.objects.contains_key(hash)
+    }
+
+    pub fn insert(&mut self, hash: ObjectHash, path: PathBuf) {
+        self.objects.insert(hash, path);
+    }
+
+    pub fn remove(&mut self, hash: &ObjectHash) -> bool {
+        self.objects.remove(hash).is_some()
+    }
+
+    pub fn retain<F: FnMut(&ObjectHash) -> bool>(&mut self, mut f: F) {
+        self.objects.retain(|hash, _| f(hash));
+    }
+
+    /// The promised objects skipped at or beneath any of the given paths, or all of them if no
+    /// paths are given.
+    pub fn under<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<ObjectHash> {
+        self.objects
+            .iter()
+            .filter(|&(_, skipped)| {
+                paths.is_empty() || paths.iter().any(|path| skipped.starts_with(path))
+            })
+            .map(|(&hash, _)| hash)
+            .collect()
+    }
+}
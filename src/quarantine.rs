@@ -0,0 +1,317 @@
+//! # `quarantine` - set corrupt objects aside, and recover them from remotes.
+//!
+//! Integrity sampling and `fsck` find corrupt objects, but leave them where they are, and every
+//! later read of one fails or returns garbage. `repair` checks each suspect object again, and
+//! moves those which really are corrupt (or missing) out of the local store and into
+//! `.attaca/quarantine`, where they are kept for inspection but never read. It then asks each
+//! configured remote in turn for a copy, keeping the first which hashes correctly; a remote made
+//! of several replicas tries each of them.
+//!
+//! Objects no remote holds a good copy of are lost. For those, `repair` works out exactly what is
+//! broken: every commit reachable from the HEAD, branches, remote refs and stashes whose tree
+//! reaches a lost object, along with the paths in that tree which do, and every path in the index
+//! cached under one. History behind a lost commit cannot be walked, and is not reported.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use futures::prelude::*;
+
+use errors::*;
+use history::stash::StashStack;
+use index::Cached;
+use marshal::{ObjectHash, Object, DataObject, SubtreeEntry};
+use marshal::canonical;
+use repository::Repository;
+use shallow::Shallow;
+use store::{Local, ObjectStore};
+use warning;
+
+
+/// What became of an object given to `repair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The object was read back intact, and left alone.
+    Intact,
+
+    /// The object was quarantined, and a good copy fetched from the named remote.
+    Recovered(String),
+
+    /// The object was quarantined, and no remote had a good copy.
+    Lost,
+}
+
+
+/// A commit whose tree reaches a lost object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AffectedCommit {
+    pub commit: ObjectHash,
+
+    /// The paths in the commit's tree which reach a lost object; the empty path if the root of the
+    /// tree is lost itself. Empty if the commit object itself is lost.
+    pub paths: Vec<PathBuf>,
+}
+
+
+/// The outcome of a repair.
+#[derive(Debug, Clone, Default)]
+pub struct Repair {
+    /// What became of each object, in the order given.
+    pub outcomes: Vec<(ObjectHash, Outcome)>,
+
+    /// The commits broken by lost objects.
+    pub affected_commits: Vec<AffectedCommit>,
+
+    /// The paths in the index cached under a lost object.
+    pub affected_index: Vec<PathBuf>,
+}
+
+
+/// Whether an object in the local store decodes, and hashes to `hash`. A missing object is not
+/// intact either. Failing to read it for any other reason says nothing about the object itself,
+/// and is an error.
+pub fn is_intact(local: &Local, hash: ObjectHash) -> Result<bool> {
+    let bytes = match local.read_raw(hash).wait() {
+        Ok(bytes) => bytes,
+        Err(Error(ErrorKind::ObjectMissing(_), _)) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    Ok(canonical::decode_verified(&bytes, hash).is_some())
+}
+
+
+/// Quarantine whichever of `hashes` are corrupt or missing in the local store, try to fetch good
+/// copies of them from each configured remote in turn, and report what is broken by any which
+/// cannot be recovered. Stops at the first object which cannot be read for some other reason,
+/// without quarantining it.
+pub fn repair(repository: &mut Repository, hashes: &[ObjectHash]) -> Result<Repair> {
+    let mut outcomes = HashMap::new();
+    let mut pending = Vec::new();
+
+    {
+        let ctx = repository.local(())?;
+        for &hash in hashes {
+            if is_intact(ctx.store(), hash)? {
+                outcomes.insert(hash, Outcome::Intact);
+            } else {
+                ctx.store().quarantine(hash)?;
+                pending.push(hash);
+            }
+        }
+        ctx.close().wait()?;
+    }
+
+    let mut remotes = repository.config.remotes.keys().cloned().collect::<Vec<_>>();
+    remotes.sort();
+
+    for remote in remotes {
+        if pending.is_empty() {
+            break;
+        }
+
+        // A remote read writes what it fetches straight into the local store, so what it wrote is
+        // checked afterwards, and quarantined again if it is no good.
+        {
+            let ctx = match repository.remote(&remote, ()) {
+                Ok(ctx) => ctx,
+                Err(error) => {
+                    warning::warn(format!("could not connect to {}: {}", remote, error));
+                    continue;
+                }
+            };
+            for &hash in &pending {
+                let _ = ctx.store().read_object(hash).wait();
+            }
+            ctx.close().wait()?;
+        }
+
+        let ctx = repository.local(())?;
+        let mut still_pending = Vec::new();
+        for hash in pending {
+            if is_intact(ctx.store(), hash)? {
+                outcomes.insert(hash, Outcome::Recovered(remote.clone()));
+            } else {
+                ctx.store().quarantine(hash)?;
+                still_pending.push(hash);
+            }
+        }
+        ctx.close().wait()?;
+        pending = still_pending;
+    }
+
+    let mut repair = Repair::default();
+    if !pending.is_empty() {
+        let lost = pending.iter().cloned().collect::<HashSet<_>>();
+
+        let mut roots = Vec::new();
+        roots.extend(repository.refs.targets());
+        roots.extend(StashStack::open(&repository.paths)?.iter());
+
+        let shallow = repository.shallow.clone();
+        let index_hashes = repository
+            .index
+            .iter()
+            .filter_map(|(path, entry)| match entry.cached {
+                Cached::Hashed(hash, _) => Some((path.to_owned(), hash)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let ctx = repository.local(())?;
+        {
+            let mut walker = Walker::new(ctx.store(), &lost);
+            repair.affected_commits = walker.commits(&roots, &shallow);
+            repair.affected_index = index_hashes
+                .into_iter()
+                .filter(|&(_, hash)| walker.data_affected(hash))
+                .map(|(path, _)| path)
+                .collect();
+        }
+        ctx.close().wait()?;
+    }
+
+    repair.outcomes = hashes
+        .iter()
+        .map(|hash| {
+            (*hash, outcomes.get(hash).cloned().unwrap_or(Outcome::Lost))
+        })
+        .collect();
+
+    Ok(repair)
+}
+
+
+/// Finds what reaches a set of lost objects, remembering the answer for every object it reads.
+/// Objects which cannot be read for any other reason are taken not to reach any.
+struct Walker<'a, S: ObjectStore> {
+    store: &'a S,
+    lost: &'a HashSet<ObjectHash>,
+
+    /// Whether each data object read reaches a lost object.
+    data: HashMap<ObjectHash, bool>,
+
+    /// The paths within each subtree read which reach a lost object.
+    subtrees: HashMap<ObjectHash, Vec<PathBuf>>,
+}
+
+
+impl<'a, S: ObjectStore> Walker<'a, S> {
+    fn new(store: &'a S, lost: &'a HashSet<ObjectHash>) -> Self {
+        Walker {
+            store,
+            lost,
+            data: HashMap::new(),
+            subtrees: HashMap::new(),
+        }
+    }
+
+    fn data_affected(&mut self, hash: ObjectHash) -> bool {
+        if self.lost.contains(&hash) {
+            return true;
+        }
+        if let Some(&affected) = self.data.get(&hash) {
+            return affected;
+        }
+
+        let affected = match self.store.read_object(hash).wait() {
+            Ok(Object::Data(DataObject::Large(large_object))) => {
+                large_object.children.iter().any(
+                    |&(_, child)| self.data_affected(child),
+                )
+            }
+            _ => false,
+        };
+        self.data.insert(hash, affected);
+
+        affected
+    }
+
+    fn subtree_paths(&mut self, hash: ObjectHash) -> Vec<PathBuf> {
+        if self.lost.contains(&hash) {
+            return vec![PathBuf::new()];
+        }
+        if let Some(paths) = self.subtrees.get(&hash) {
+            return paths.clone();
+        }
+
+        let mut paths = Vec::new();
+        if let Ok(Object::Subtree(subtree_object)) = self.store.read_object(hash).wait() {
+            for (name, entry) in &subtree_object.entries {
+                match entry.unannotated() {
+                    SubtreeEntry::Subtree(child) => {
+                        for path in self.subtree_paths(child) {
+                            paths.push(join(name, &path));
+                        }
+                    }
+                    ref other => {
+                        if let Some(data_hash) = other.data_hash() {
+                            if self.data_affected(data_hash) {
+                                paths.push(PathBuf::from(name));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.subtrees.insert(hash, paths.clone());
+
+        paths
+    }
+
+    fn commits(&mut self, roots: &[ObjectHash], shallow: &Shallow) -> Vec<AffectedCommit> {
+        let mut affected = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = roots.to_vec();
+
+        while let Some(hash) = stack.pop() {
+            if !seen.insert(hash) {
+                continue;
+            }
+
+            if self.lost.contains(&hash) {
+                affected.push(AffectedCommit {
+                    commit: hash,
+                    paths: Vec::new(),
+                });
+                continue;
+            }
+
+            let commit_object = match self.store.read_object(hash).wait() {
+                Ok(Object::Commit(commit_object)) => commit_object,
+                _ => continue,
+            };
+
+            let paths = self.subtree_paths(commit_object.subtree);
+            if !paths.is_empty() {
+                affected.push(AffectedCommit { commit: hash, paths });
+            }
+            stack.extend(shallow.parents(&hash, &commit_object).iter().cloned());
+        }
+
+        affected
+    }
+}
+
+
+/// `name/path`, or just `name` if `path` is empty.
+fn join(name: &OsStr, path: &Path) -> PathBuf {
+    if path.as_os_str().is_empty() {
+        PathBuf::from(name)
+    } else {
+        Path::new(name).join(path)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn joins_names_onto_paths() {
+        assert_eq!(join(OsStr::new("a"), Path::new("")), PathBuf::from("a"));
+        assert_eq!(join(OsStr::new("a"), Path::new("b/c")), PathBuf::from("a/b/c"));
+    }
+}
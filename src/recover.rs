@@ -0,0 +1,335 @@
+//! # `recover` - find what an interrupted command left behind, and finish or undo it.
+//!
+//! A command which crashes or is killed can leave state behind in `.attaca`: a lock file which is
+//! never released, a refs or mirror state file which was half written, a staging area of objects
+//! written for refs which were never updated, or a rebase or bisection which was never finished.
+//! `scan` finds all of these, and each `Interrupted` can be resumed, rolled back, or both.
+//!
+//! Transfers keep no state of their own which needs recovering. The catalog of a remote is only
+//! saved once a push or fetch finishes, so an interrupted transfer is resumed by running it
+//! again, which sends only the objects the remote is not known to hold.
+//!
+//! `scan` must be run before the repository is loaded, since loading it cleans up abandoned
+//! staging areas on its own. See `Staging::recover`.
+
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use libc;
+
+use errors::*;
+use history::bisect::Bisector;
+use history::rebase::Rebase;
+use lock::{self, LockFile};
+use repository::Paths;
+use store::{Abandoned, Staging};
+
+
+/// A way of dealing with an interrupted operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Finish what the operation was doing.
+    Resume,
+
+    /// Undo what the operation had done, leaving the repository as it was before it started.
+    Rollback,
+}
+
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Resume => write!(f, "resume"),
+            Action::Rollback => write!(f, "roll back"),
+        }
+    }
+}
+
+
+/// Something left behind by an operation which did not finish.
+#[derive(Debug, Clone)]
+pub enum Interrupted {
+    /// A lock whose holder is no longer running.
+    StaleLock {
+        path: PathBuf,
+        operation: &'static str,
+        pid: Option<libc::pid_t>,
+    },
+
+    /// A lock held by a process which is still running. It may simply not have finished yet.
+    HeldLock {
+        path: PathBuf,
+        operation: &'static str,
+        pid: libc::pid_t,
+    },
+
+    /// A file which was being written to replace `target`. `target` is left as it was.
+    PartialWrite {
+        path: PathBuf,
+        target: PathBuf,
+        operation: &'static str,
+    },
+
+    /// Objects staged by a process which died. See `store::staging`.
+    Staging(Abandoned),
+
+    /// A rebase stopped at a conflict.
+    Rebase(Rebase),
+
+    /// A bisection which was never reset.
+    Bisect,
+}
+
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Interrupted::StaleLock { ref path, operation, pid } => {
+                write!(f, "{} was interrupted, leaving {} locked", operation, path.display())?;
+                match pid {
+                    Some(pid) => write!(f, " by process {}, which is no longer running", pid),
+                    None => write!(f, " with no record of which process held it"),
+                }
+            }
+            Interrupted::HeldLock { ref path, operation, pid } => {
+                write!(
+                    f,
+                    "{} is in progress in process {}, which holds {}",
+                    operation,
+                    pid,
+                    path.display()
+                )
+            }
+            Interrupted::PartialWrite { ref path, ref target, operation } => {
+                write!(
+                    f,
+                    "{} was interrupted while writing {}; {} is unchanged",
+                    operation,
+                    path.display(),
+                    target.display()
+                )
+            }
+            Interrupted::Staging(ref abandoned) => {
+                match abandoned.pid {
+                    Some(pid) => write!(f, "process {} died", pid)?,
+                    None => write!(f, "a process died")?,
+                }
+                if abandoned.marked {
+                    write!(
+                        f,
+                        " while moving objects into the store, after updating refs which may \
+                         point at them ({})",
+                        abandoned.dir.display()
+                    )
+                } else {
+                    write!(
+                        f,
+                        " before updating any refs to point at the objects it wrote ({})",
+                        abandoned.dir.display()
+                    )
+                }
+            }
+            Interrupted::Rebase(ref rebase) => {
+                write!(f, "a rebase of `{}` is in progress", rebase.branch)?;
+                if let Some(ref conflict) = rebase.conflict {
+                    write!(f, ", stopped at a conflict replaying {}", conflict.commit)?;
+                }
+                write!(f, " with {} commits left", rebase.remaining())
+            }
+            Interrupted::Bisect => write!(f, "a bisection is in progress"),
+        }
+    }
+}
+
+
+impl Interrupted {
+    /// The actions which can be taken to deal with this.
+    pub fn actions(&self) -> Vec<Action> {
+        match *self {
+            Interrupted::StaleLock { .. } |
+            Interrupted::PartialWrite { .. } |
+            Interrupted::Bisect => vec![Action::Rollback],
+            Interrupted::HeldLock { .. } => vec![],
+            Interrupted::Staging(ref abandoned) if abandoned.marked => vec![Action::Resume],
+            Interrupted::Staging(_) |
+            Interrupted::Rebase(_) => vec![Action::Resume, Action::Rollback],
+        }
+    }
+
+    /// What taking an action would do, for display before it is taken.
+    pub fn explain(&self, action: Action) -> Option<String> {
+        let explanation = match (self, action) {
+            (&Interrupted::StaleLock { .. }, Action::Rollback) => "remove the lock".to_owned(),
+            (&Interrupted::PartialWrite { .. }, Action::Rollback) => {
+                "remove the partially written file".to_owned()
+            }
+            (&Interrupted::Staging(ref abandoned), Action::Resume) => {
+                if abandoned.marked {
+                    "move the staged objects into the store, as the process would have".to_owned()
+                } else {
+                    "move the staged objects into the store rather than discarding them"
+                        .to_owned()
+                }
+            }
+            (&Interrupted::Staging(_), Action::Rollback) => {
+                "discard the staged objects".to_owned()
+            }
+            (&Interrupted::Rebase(_), Action::Resume) => {
+                "record the worktree as the resolution and carry on, as `attaca rebase \
+                 --continue`"
+                    .to_owned()
+            }
+            (&Interrupted::Rebase(ref rebase), Action::Rollback) => {
+                format!(
+                    "give up, checking out `{}` at {}, as `attaca rebase --abort`",
+                    rebase.branch,
+                    rebase.original
+                )
+            }
+            (&Interrupted::Bisect, Action::Rollback) => {
+                "abandon the bisection, as `attaca bisect reset`".to_owned()
+            }
+            _ => return None,
+        };
+
+        Some(explanation)
+    }
+
+    /// Take an action. Rebases need a loaded repository to check out commits, and so are
+    /// resumed or rolled back through the `rebase` command instead.
+    pub fn apply(self, action: Action, paths: &Paths) -> Result<()> {
+        if !self.actions().contains(&action) {
+            bail!(ErrorKind::RecoveryUnavailable(action.to_string(), self.to_string()));
+        }
+
+        match self {
+            Interrupted::StaleLock { path, .. } => {
+                // The lock may have been taken again since it was found to be stale.
+                if LockFile::holder(&path)?.map_or(false, lock::is_alive) {
+                    bail!(ErrorKind::Locked(path));
+                }
+
+                if path.exists() {
+                    fs::remove_file(&path)?;
+                }
+            }
+            Interrupted::PartialWrite { path, .. } => {
+                if path.exists() {
+                    fs::remove_file(&path)?;
+                }
+            }
+            Interrupted::Staging(abandoned) => {
+                match action {
+                    Action::Resume => abandoned.promote(paths)?,
+                    Action::Rollback => abandoned.discard()?,
+                }
+            }
+            Interrupted::Bisect => Bisector::reset(paths)?,
+            interrupted @ Interrupted::Rebase(_) |
+            interrupted @ Interrupted::HeldLock { .. } => {
+                bail!(ErrorKind::RecoveryUnavailable(
+                    action.to_string(),
+                    interrupted.to_string(),
+                ))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Find everything left behind by interrupted operations. Locks come first, so that clearing
+/// stale ones before the rest frees the refs for rebases to be recovered.
+pub fn scan(paths: &Paths) -> Result<Vec<Interrupted>> {
+    let mut found = Vec::new();
+
+    let files = [
+        (&paths.refs_lock, &paths.refs, "updating the refs"),
+        (&paths.index_lock, &paths.index, "writing the index"),
+        (&paths.mirror_lock, &paths.mirror, "refreshing the mirror"),
+    ];
+    let mut partial = Vec::new();
+
+    for &(lock_path, target, operation) in &files {
+        let temp_path = target.with_extension("bin.tmp");
+        let mut held = false;
+
+        if lock_path.exists() {
+            match LockFile::holder(lock_path)? {
+                Some(pid) if lock::is_alive(pid) => {
+                    held = true;
+                    found.push(Interrupted::HeldLock {
+                        path: lock_path.clone(),
+                        operation,
+                        pid,
+                    });
+                }
+                pid => {
+                    found.push(Interrupted::StaleLock {
+                        path: lock_path.clone(),
+                        operation,
+                        pid,
+                    })
+                }
+            }
+        }
+
+        // A temporary file beside a lock which is held is still being written.
+        if temp_path.exists() && !held {
+            partial.push(Interrupted::PartialWrite {
+                path: temp_path,
+                target: target.clone(),
+                operation,
+            });
+        }
+    }
+
+    found.extend(partial);
+    found.extend(Staging::abandoned(paths)?.into_iter().map(Interrupted::Staging));
+
+    if let Some(rebase) = Rebase::open(paths)? {
+        found.push(Interrupted::Rebase(rebase));
+    }
+
+    if Bisector::open(paths)?.is_some() {
+        found.push(Interrupted::Bisect);
+    }
+
+    Ok(found)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use super::*;
+    use bench::Scratch;
+    use repository;
+
+    #[test]
+    fn scan_and_roll_back() {
+        let scratch = Scratch::new("attaca-recover").unwrap();
+        let paths = repository::init(scratch.path()).unwrap();
+
+        File::create(&paths.refs_lock).unwrap();
+        File::create(paths.refs.with_extension("bin.tmp")).unwrap();
+        fs::create_dir_all(paths.staging.join("unknown-0000000000000000")).unwrap();
+
+        let found = scan(&paths).unwrap();
+        assert_eq!(found.len(), 3);
+        match found[0] {
+            Interrupted::StaleLock { pid: None, .. } => {}
+            ref other => panic!("expected a stale lock, found {:?}", other),
+        }
+
+        for interrupted in found {
+            interrupted.apply(Action::Rollback, &paths).unwrap();
+        }
+
+        assert!(scan(&paths).unwrap().is_empty());
+        assert!(paths.refs.is_file());
+    }
+}
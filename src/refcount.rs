@@ -0,0 +1,557 @@
+//! # `refcount` - counts of the references to each object, for collecting garbage incrementally.
+//!
+//! Finding garbage by marking everything reachable from the refs and sweeping the rest means
+//! reading every object in the store, which takes far too long once there are billions of them.
+//! When a repository's config sets `refcounts`, the local store instead counts, as each object is
+//! written, the references it makes to other objects, in `.attaca/refcounts.bin`. An object which
+//! nothing in the store refers to is unreferenced; unless a ref, a stash or the index names it,
+//! it is garbage. Deleting it releases its own references, and whatever they leave unreferenced
+//! is garbage in turn. `collect` deletes garbage in batches of bounded size, so that each run of
+//! the collector does a bounded amount of work however large the store.
+//!
+//! An object is only collected once it has been unreferenced for a grace period. A commit in
+//! progress writes objects which nothing refers to until it finishes, and may reuse existing
+//! objects which are about to become garbage; the grace period is what keeps the collector from
+//! pulling either out from under it, so it should be longer than any commit takes. Reusing an
+//! unreferenced object restarts its grace period, and is saved at once so that the collector
+//! sees it, however long ago the object became unreferenced.
+//!
+//! Each process records what it writes and deletes as a journal of events, which are replayed on
+//! top of whatever other processes saved in the meantime when it saves, under a lock. Objects
+//! written before counting was enabled are not counted; `Refcounts::rebuild` recounts every object
+//! in the local store. Until then, an object might be referred to only by objects which were never
+//! counted, so the counts are only complete - and `collect` only runs - once they have been
+//! rebuilt, or if counting was enabled while the store was empty. Stores opt in to being collected
+//! by implementing `RefcountedStore`.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bincode;
+use chrono::prelude::*;
+
+use LOCK_TIMEOUT_MS;
+use errors::*;
+use history::stash::StashStack;
+use index::Cached;
+use lock::LockFile;
+use marshal::ObjectHash;
+use marshal::canonical;
+use repository::{Paths, Repository};
+use store::{Packs, RefcountedStore};
+use warning;
+use worktree;
+
+
+/// The counts, as saved.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Counts {
+    /// The number of references to each object from objects in the store. Objects with none are
+    /// left out.
+    refs: HashMap<ObjectHash, u64>,
+
+    /// The objects in the store which nothing in the store refers to, with the time in seconds
+    /// since the epoch at which each became so.
+    unreferenced: HashMap<ObjectHash, i64>,
+
+    /// Whether every object in the store has been counted. See the module docs.
+    complete: bool,
+}
+
+
+/// A change to the counts, made by writing, reusing or deleting an object.
+#[derive(Debug, Clone)]
+enum Event {
+    Written(ObjectHash, Vec<ObjectHash>, i64),
+    Reused(ObjectHash, i64),
+    Deleted(ObjectHash, Vec<ObjectHash>, i64),
+}
+
+
+impl Counts {
+    fn apply(&mut self, event: &Event) {
+        match *event {
+            Event::Written(hash, ref refs, at) => {
+                for referenced in refs {
+                    *self.refs.entry(*referenced).or_insert(0) += 1;
+                    self.unreferenced.remove(referenced);
+                }
+
+                if !self.refs.contains_key(&hash) {
+                    self.unreferenced.insert(hash, at);
+                }
+            }
+            Event::Reused(hash, at) => {
+                if let Some(since) = self.unreferenced.get_mut(&hash) {
+                    *since = cmp::max(*since, at);
+                }
+            }
+            Event::Deleted(hash, ref refs, at) => {
+                self.unreferenced.remove(&hash);
+
+                for referenced in refs {
+                    let released = match self.refs.get_mut(referenced) {
+                        Some(count) => {
+                            *count -= 1;
+                            *count == 0
+                        }
+                        None => false,
+                    };
+
+                    if released {
+                        self.refs.remove(referenced);
+                        self.unreferenced.insert(*referenced, at);
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[derive(Debug)]
+struct RefcountsInner {
+    path: PathBuf,
+    lock_path: PathBuf,
+    counts: Counts,
+
+    /// Everything this process has done since it loaded or last saved the counts.
+    journal: Vec<Event>,
+
+    /// Whether the counts were rebuilt from scratch, so that they replace the saved counts rather
+    /// than being merged with them.
+    replace: bool,
+}
+
+
+impl RefcountsInner {
+    fn load(path: &Path) -> Result<Counts> {
+        if !path.is_file() {
+            return Ok(Counts::default());
+        }
+
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        match bincode::deserialize(&bytes) {
+            Ok(counts) => Ok(counts),
+            Err(_) => {
+                // Counts saved before completeness was recorded are not known to be complete.
+                let (refs, unreferenced) = bincode::deserialize(&bytes)?;
+                Ok(Counts {
+                    refs,
+                    unreferenced,
+                    complete: false,
+                })
+            }
+        }
+    }
+
+    fn save(&mut self) -> Result<()> {
+        if self.journal.is_empty() && !self.replace {
+            return Ok(());
+        }
+
+        let _lock = LockFile::acquire_timeout(
+            &self.lock_path,
+            Duration::from_millis(LOCK_TIMEOUT_MS),
+        )?;
+
+        self.merge()
+    }
+
+    /// Replay the journal on top of the counts as saved, and save the result. The caller must
+    /// hold the lock.
+    fn merge(&mut self) -> Result<()> {
+        if !self.replace {
+            let mut counts = RefcountsInner::load(&self.path)?;
+            for event in &self.journal {
+                counts.apply(event);
+            }
+            self.counts = counts;
+        }
+
+        let temp_path = self.path.with_extension("bin.tmp");
+        let bytes = bincode::serialize(&self.counts, bincode::Infinite)?;
+        File::create(&temp_path)?.write_all(&bytes)?;
+        fs::rename(&temp_path, &self.path)?;
+
+        self.journal.clear();
+        self.replace = false;
+
+        Ok(())
+    }
+
+    fn record(&mut self, event: Event) {
+        self.counts.apply(&event);
+        self.journal.push(event);
+    }
+}
+
+
+impl Drop for RefcountsInner {
+    fn drop(&mut self) {
+        if let Err(error) = self.save() {
+            warning::warn(format!("could not save reference counts: {}", error));
+        }
+    }
+}
+
+
+/// A shared handle to the reference counts, saved when the last handle is dropped.
+#[derive(Debug, Clone)]
+pub struct Refcounts {
+    inner: Arc<Mutex<RefcountsInner>>,
+}
+
+
+impl Refcounts {
+    /// Load the counts of the repository at `paths`, or start counting afresh if there are none.
+    /// Counts started while the local store is empty are complete from the outset.
+    pub fn open(paths: &Paths) -> Result<Self> {
+        let path = paths.refcounts.clone();
+        let lock_path = paths.refcounts_lock.clone();
+        let mut counts = RefcountsInner::load(&path)?;
+        let mut replace = false;
+
+        if !path.is_file() && is_empty_store(paths)? {
+            counts.complete = true;
+            replace = true;
+        }
+
+        Ok(Refcounts {
+            inner: Arc::new(Mutex::new(RefcountsInner {
+                path,
+                lock_path,
+                counts,
+                journal: Vec::new(),
+                replace,
+            })),
+        })
+    }
+
+    /// Count the references `refs` of the object `hash`, once it is written.
+    pub fn written(&self, hash: ObjectHash, refs: Vec<ObjectHash>) {
+        let event = Event::Written(hash, refs, Utc::now().timestamp());
+        self.inner.lock().unwrap().record(event);
+    }
+
+    /// Restart the grace period of the object `hash` if nothing refers to it, as something a
+    /// write in progress is about to write will. The counts are saved at once. Returns `false` if
+    /// the object turns out to have been collected since the counts were loaded.
+    pub fn reused(&self, hash: ObjectHash) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if !inner.counts.unreferenced.contains_key(&hash) {
+            return Ok(true);
+        }
+
+        inner.record(Event::Reused(hash, Utc::now().timestamp()));
+        inner.save()?;
+
+        let counts = &inner.counts;
+        Ok(counts.refs.contains_key(&hash) || counts.unreferenced.contains_key(&hash))
+    }
+
+    /// Release the references of the object `hash`, which made the references `refs`, once it is
+    /// deleted.
+    pub fn deleted(&self, hash: ObjectHash, refs: Vec<ObjectHash>) {
+        let event = Event::Deleted(hash, refs, Utc::now().timestamp());
+        self.inner.lock().unwrap().record(event);
+    }
+
+    /// Whether every object in the store has been counted. See the module docs.
+    pub fn is_complete(&self) -> bool {
+        self.inner.lock().unwrap().counts.complete
+    }
+
+    /// The number of references to `hash` from objects in the store.
+    pub fn get(&self, hash: ObjectHash) -> u64 {
+        self.inner.lock().unwrap().counts.refs.get(&hash).cloned().unwrap_or(0)
+    }
+
+    /// Up to `limit` objects which have been unreferenced since before `before`, in seconds since
+    /// the epoch, leaving out any in `roots`.
+    pub fn unreferenced(
+        &self,
+        before: i64,
+        roots: &HashSet<ObjectHash>,
+        limit: usize,
+    ) -> Vec<ObjectHash> {
+        self.inner
+            .lock()
+            .unwrap()
+            .counts
+            .unreferenced
+            .iter()
+            .filter(|&(hash, &since)| since < before && !roots.contains(hash))
+            .map(|(&hash, _)| hash)
+            .take(limit)
+            .collect()
+    }
+
+    /// Forget everything, and count the references of every object in the local store afresh,
+    /// whether loose or packed.
+    pub fn rebuild(&self, paths: &Paths) -> Result<()> {
+        fn walk(counts: &mut Counts, path: &Path, now: i64) -> Result<()> {
+            for entry_res in fs::read_dir(path)? {
+                let entry = entry_res?;
+                let entry_path = entry.path();
+
+                if entry.metadata()?.is_dir() {
+                    walk(counts, &entry_path, now)?;
+                } else {
+                    let hash = ObjectHash::from_blob_path(&entry_path)?;
+                    let mut bytes = Vec::new();
+                    File::open(&entry_path)?.read_to_end(&mut bytes)?;
+                    let (_, raw_object) = canonical::decode(&bytes)?;
+                    counts.apply(&Event::Written(hash, raw_object.refs(), now));
+                }
+            }
+
+            Ok(())
+        }
+
+        // Objects are visited in no particular order, so one may be marked unreferenced before
+        // the objects referring to it are seen; those are struck off again below.
+        let mut counts = Counts::default();
+        let now = Utc::now().timestamp();
+        if paths.blobs.exists() {
+            walk(&mut counts, &paths.blobs, now)?;
+        }
+        Packs::new(paths.packs.clone()).for_each(|hash, bytes| {
+            // An object may briefly be both loose and packed while a repack finishes.
+            if !paths.blobs.join(hash.to_path()).exists() {
+                let (_, raw_object) = canonical::decode(bytes)?;
+                counts.apply(&Event::Written(hash, raw_object.refs(), now));
+            }
+
+            Ok(())
+        })?;
+        let Counts { refs, unreferenced, .. } = counts;
+        let unreferenced = unreferenced
+            .into_iter()
+            .filter(|&(hash, _)| !refs.contains_key(&hash))
+            .collect();
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.counts = Counts {
+            refs,
+            unreferenced,
+            complete: true,
+        };
+        inner.journal.clear();
+        inner.replace = true;
+
+        Ok(())
+    }
+
+    /// Write the counts back now, rather than when the last handle is dropped.
+    pub fn save(&self) -> Result<()> {
+        self.inner.lock().unwrap().save()
+    }
+
+    /// Take the lock under which the saved counts are changed.
+    fn lock(&self) -> Result<LockFile> {
+        let lock_path = self.inner.lock().unwrap().lock_path.clone();
+        LockFile::acquire_timeout(&lock_path, Duration::from_millis(LOCK_TIMEOUT_MS))
+    }
+
+    /// Merge this process's changes with whatever other processes have saved, under `lock`.
+    fn merge(&self, _lock: &LockFile) -> Result<()> {
+        self.inner.lock().unwrap().merge()
+    }
+}
+
+
+/// Whether the local store at `paths` holds no objects, loose or packed.
+fn is_empty_store(paths: &Paths) -> Result<bool> {
+    fn walk(path: &Path) -> Result<bool> {
+        for entry_res in fs::read_dir(path)? {
+            let entry = entry_res?;
+
+            if !entry.metadata()?.is_dir() || !walk(&entry.path())? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    if paths.blobs.exists() && !walk(&paths.blobs)? {
+        return Ok(false);
+    }
+
+    Ok(Packs::new(paths.packs.clone()).hashes()?.is_empty())
+}
+
+
+/// Every object which must be kept even if nothing in the store refers to it: what the HEAD,
+/// branches, tags, remote refs and stashes point at, and the hashes cached in the index.
+pub fn roots(repository: &Repository) -> Result<HashSet<ObjectHash>> {
+    let mut roots = HashSet::new();
+
+    roots.extend(repository.refs.targets());
+    roots.extend(StashStack::open(&repository.paths)?.iter());
+    for (_, entry) in repository.index.iter() {
+        if let Cached::Hashed(hash, _) = entry.cached {
+            roots.insert(hash);
+        }
+    }
+    roots.extend(worktree::roots(&repository.paths, &repository.refs)?);
+
+    Ok(roots)
+}
+
+
+/// What a run of the collector did.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Collected {
+    /// The number of objects deleted.
+    pub deleted: u64,
+
+    /// Whether the run stopped at its limit with garbage left over.
+    pub incomplete: bool,
+}
+
+
+/// Delete up to `limit` objects from `store` which have been unreferenced for at least `grace`
+/// and are not in `roots`. The objects these leave unreferenced only become garbage once the
+/// grace period has passed for them as well, unless `grace` is zero, in which case they are
+/// collected in the same run.
+pub fn collect<S: RefcountedStore>(
+    store: &S,
+    roots: &HashSet<ObjectHash>,
+    grace: Duration,
+    limit: usize,
+) -> Result<Collected> {
+    let refcounts = match store.refcounts() {
+        Some(refcounts) => refcounts,
+        None => bail!(ErrorKind::RefcountsDisabled),
+    };
+    if !refcounts.is_complete() {
+        bail!(ErrorKind::RefcountsIncomplete);
+    }
+    let mut collected = Collected::default();
+
+    loop {
+        let remaining = limit - collected.deleted as usize;
+
+        // Other processes may have reused objects since the counts were loaded, so each batch is
+        // chosen from the counts as saved, and deleted under the lock so that none of it can be
+        // reused in the meantime.
+        let lock = refcounts.lock()?;
+        refcounts.merge(&lock)?;
+
+        let before = Utc::now().timestamp() - grace.as_secs() as i64 + 1;
+        let batch = refcounts.unreferenced(before, roots, remaining + 1);
+
+        if batch.is_empty() {
+            break;
+        }
+        if remaining == 0 {
+            collected.incomplete = true;
+            break;
+        }
+
+        for &hash in batch.iter().take(remaining) {
+            store.delete_object(hash)?;
+            collected.deleted += 1;
+        }
+
+        refcounts.merge(&lock)?;
+    }
+
+    Ok(collected)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::prelude::*;
+    use futures_cpupool::CpuPool;
+
+    use arc_slice;
+    use bench::{hash_of, Scratch};
+    use catalog::Catalog;
+    use marshal::{self, DataObject, Object, SmallObject};
+    use repository;
+    use store::{Local, ObjectStore};
+
+    #[test]
+    fn deleting_releases_references() {
+        let mut counts = Counts::default();
+        counts.apply(&Event::Written(hash_of(3), vec![], 1));
+        counts.apply(&Event::Written(hash_of(2), vec![hash_of(3)], 2));
+        counts.apply(&Event::Written(hash_of(1), vec![hash_of(2), hash_of(3)], 3));
+
+        assert_eq!(counts.refs.get(&hash_of(3)), Some(&2));
+        assert_eq!(counts.unreferenced.keys().collect::<Vec<_>>(), vec![&hash_of(1)]);
+
+        counts.apply(&Event::Deleted(hash_of(1), vec![hash_of(2), hash_of(3)], 4));
+        assert_eq!(counts.refs.get(&hash_of(3)), Some(&1));
+        assert_eq!(counts.unreferenced.get(&hash_of(2)), Some(&4));
+
+        counts.apply(&Event::Deleted(hash_of(2), vec![hash_of(3)], 5));
+        assert!(counts.refs.is_empty());
+        assert_eq!(counts.unreferenced.keys().collect::<Vec<_>>(), vec![&hash_of(3)]);
+    }
+
+    #[test]
+    fn counts_are_only_complete_from_an_empty_store() {
+        let scratch = Scratch::new("attaca-refcount").unwrap();
+        let paths = repository::init(scratch.path()).unwrap();
+
+        Refcounts::open(&paths).unwrap().save().unwrap();
+        assert!(Refcounts::open(&paths).unwrap().is_complete());
+
+        fs::remove_file(&paths.refcounts).unwrap();
+        let blob_path = paths.blobs.join(hash_of(1).to_path());
+        fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+        File::create(&blob_path).unwrap();
+        assert!(!Refcounts::open(&paths).unwrap().is_complete());
+    }
+
+    #[test]
+    fn objects_reused_while_committing_are_not_collected() {
+        let scratch = Scratch::new("attaca-refcount").unwrap();
+        let paths = Arc::new(repository::init(scratch.path()).unwrap());
+        let catalog = Catalog::load(paths.local_catalog.clone()).unwrap();
+        let pool = CpuPool::new(1);
+
+        // An object written long ago, which nothing has referred to since.
+        let refcounts = Refcounts::open(&paths).unwrap();
+        let chunk = arc_slice::owned(b"reused".to_vec());
+        let hashed = marshal::serialize_and_hash(&Object::Data(DataObject::Small(SmallObject {
+            chunk,
+        })));
+        let object_hash = *hashed.as_hash();
+        Local::new(&paths, &catalog, &pool).write_object(hashed).wait().unwrap();
+        refcounts.inner.lock().unwrap().record(Event::Written(object_hash, vec![], 0));
+        refcounts.save().unwrap();
+
+        // The committing and collecting processes each load the counts for themselves.
+        let open = || {
+            let refcounts = Refcounts::open(&paths).unwrap();
+            Local::new(&paths, &catalog, &pool).with_refcounts(Some(refcounts))
+        };
+        let committing = open();
+        let collecting = open();
+
+        assert!(committing.is_known(object_hash));
+        let roots = HashSet::new();
+        let collected = collect(&collecting, &roots, Duration::from_secs(3600), 10).unwrap();
+        assert_eq!(collected.deleted, 0);
+        assert!(collecting.read_object(object_hash).wait().is_ok());
+
+        let collected = collect(&collecting, &roots, Duration::from_secs(0), 10).unwrap();
+        assert_eq!(collected.deleted, 1);
+    }
+}
@@ -9,33 +9,79 @@
 /// +-_ remote-catalogs
 ///    +-- <remote-name>.catalog
 /// +-- local.catalog
-/// +-- HEAD
+/// +-- refs.bin
+/// +-- index.bin
 /// +-_ blobs
 ///    +-- ... locally stored blobs named by hash
+/// +-_ packs
+///    +-- ... objects moved out of `blobs` by a repack
+/// +-_ quarantine
+///    +-- ... corrupt objects set aside by `repair`
 /// ```
+///
+/// `init` creates this layout, and `find` locates the repository enclosing a directory, so that
+/// commands may be run from anywhere inside a repository.
 
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bincode;
 use futures_cpupool::CpuPool;
 use itertools::Itertools;
+use seahash;
 use toml;
 
-use {METADATA_PATH, BLOBS_PATH, CONFIG_PATH, REMOTE_CATALOGS_PATH, LOCAL_CATALOG_PATH, INDEX_PATH,
-     REFS_PATH};
+use {METADATA_PATH, BLOBS_PATH, CONFIG_PATH, CONFIG_LOCK_PATH, REMOTE_CATALOGS_PATH,
+     LOCAL_CATALOG_PATH, INDEX_PATH, INDEX_LOCK_PATH, PLACEHOLDERS_PATH, REFS_PATH, REFS_LOCK_PATH,
+     LOCK_TIMEOUT_MS, SPARSE_PATH, TELEMETRY_PATH,
+     CORRUPTION_PATH, BISECT_PATH, DAEMON_SOCKET_PATH, REBASE_PATH, BACKREFS_PATH,
+     REFCOUNTS_PATH, REFCOUNTS_LOCK_PATH, PACKS_PATH, QUARANTINE_PATH,
+     STASH_PATH, SHALLOW_PATH, STAGING_PATH, PROMISED_PATH, MIRROR_PATH,
+     MIRROR_LOCK_PATH, SEARCH_PATH, HASH_CACHE_PATH, MATERIALIZED_PATH, HEAD_PATH,
+     WORKTREES_PATH, CONFLICTS_PATH};
+use backrefs::Backrefs;
+use blocklist::{Blocklist, BlockAction};
+#[cfg(feature = "rados")]
+use cache::CacheClient;
 use catalog::{Registry, Catalog, CatalogTrie};
+use chunker::ChunkerSet;
 use context::Context;
 use errors::*;
+use fault;
+use filter::FilterSet;
+use history::driver::MergeDriverSet;
+use hooks::HookSet;
+use identity::{self, GlobalConfig, Role};
 use index::Index;
-use marshal::ObjectHash;
-use store::{Local, Remote, Ceph};
+use lock::LockFile;
+use marshal::{ObjectHash, SubtreeEntry, EntryMetadata, Identity, DataLayout};
+use marshal::canonical::Version;
+use marshal::names::NonUtf8Names;
+use mirror::{self, BranchChange};
+#[cfg(feature = "rados")]
+use marshal::sealed::EncryptionKey;
+#[cfg(feature = "rados")]
+use negotiation::Negotiation;
+use profile;
+use promised::Promised;
+use refcount::Refcounts;
+use shallow::Shallow;
+use sign::SigningKey;
+#[cfg(feature = "rados")]
+use store::Ceph;
+use store::{Local, Packs, Remote, DelayedStore, GuardedStore, ReplicatedStore, RetryPolicy,
+            RetryStore, SimulateCfg, Staging, TimeoutCfg, WritePolicy};
+use telemetry::COUNTERS;
 use trace::Trace;
+use worktree;
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,6 +103,12 @@ pub struct CephCfg {
     /// The working RADOS object pool.
     pub pool: String,
 
+    /// A pool to keep every object other than data objects in, apart from the bulk of the data,
+    /// so that walking trees and history need not wait behind it. Objects written before it was
+    /// set are still found in `pool`.
+    #[serde(default)]
+    pub metadata_pool: Option<String>,
+
     /// The working RADOS user.
     pub user: String,
 
@@ -66,13 +118,98 @@ pub struct CephCfg {
 }
 
 
+/// The persistent configuration data for a LevelDB database of objects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LevelDbCfg {
+    /// Path to the database directory.
+    pub path: PathBuf,
+}
+
+
+/// The persistent configuration data for an S3 bucket of objects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Cfg {
+    /// The name of the bucket.
+    pub bucket: String,
+
+    /// The region the bucket is in, if not the default.
+    #[serde(default)]
+    pub region: Option<String>,
+
+    /// A prefix for the keys of objects, if they do not sit at the root of the bucket.
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ObjectStoreCfg {
     Ceph(CephCfg),
+    LevelDb(LevelDbCfg),
+    S3(S3Cfg),
     Ssh(SshCfg),
 }
 
 
+impl ObjectStoreCfg {
+    /// A short name for the kind of backend.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            ObjectStoreCfg::Ceph(_) => "ceph",
+            ObjectStoreCfg::LevelDb(_) => "leveldb",
+            ObjectStoreCfg::S3(_) => "s3",
+            ObjectStoreCfg::Ssh(_) => "ssh",
+        }
+    }
+
+    /// Whether this build can connect to this kind of backend. Backends which are not yet
+    /// implemented are never available, whatever features were enabled.
+    pub fn is_available(&self) -> bool {
+        self.unavailable().is_none()
+    }
+
+    /// Why this build cannot connect to this kind of backend, if it cannot.
+    pub fn unavailable(&self) -> Option<ErrorKind> {
+        match *self {
+            ObjectStoreCfg::Ceph(_) if !cfg!(feature = "rados") => {
+                Some(ErrorKind::BackendNotCompiled("ceph".to_owned(), "rados".to_owned()))
+            }
+            ObjectStoreCfg::Ceph(_) => None,
+            ref other => Some(ErrorKind::UnsupportedRemoteBackend(other.kind().to_owned())),
+        }
+    }
+}
+
+
+/// The name of the only digest objects are addressed by.
+pub const DIGEST: &str = "sha3-256";
+
+
+fn default_digest() -> String {
+    DIGEST.to_owned()
+}
+
+
+/// How objects are compressed when stored on a remote. While compression is on, objects stored
+/// uncompressed are still read correctly, so it may be turned on for a remote which already holds
+/// objects; it must not be turned off again while the remote holds compressed objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+
+    /// Compress with zstd at the given level.
+    Zstd(i32),
+}
+
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+
 /// The persistent configuration data for an etcd cluster.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EtcdCfg {
@@ -91,6 +228,11 @@ pub enum RefStoreCfg {
 /// The persistent configuration data for a single remote.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoteCfg {
+    /// The bandwidth available for sending objects to the remote, in bytes per second, used to
+    /// estimate how long a push will take.
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+
     /// The remote object store.
     ///
     /// TODO: Support object stores other than Ceph/RADOS.
@@ -100,21 +242,442 @@ pub struct RemoteCfg {
     ///
     /// TODO: Support ref stores other than etcd.
     pub ref_store: EtcdCfg,
+
+    /// Further object stores every object is also written to. Reads try `object_store` first and
+    /// then each replica in order. See `store::ReplicatedStore`.
+    #[serde(default)]
+    pub replicas: Vec<ObjectStoreCfg>,
+
+    /// How many of the object stores must acknowledge a write when there are replicas.
+    #[serde(default)]
+    pub write_policy: WritePolicy,
+
+    /// Simulated network conditions to impose on the remote object store, for development.
+    #[serde(default)]
+    pub simulate: Option<SimulateCfg>,
+
+    /// The digest the remote addresses objects by. Only `sha3-256` is supported; the setting
+    /// exists so that a remote using another is refused rather than silently misread.
+    #[serde(default = "default_digest")]
+    pub digest: String,
+
+    /// How objects sent to the remote are compressed.
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// The path of the key objects sent to the remote are sealed with, relative to the repository
+    /// root, if they are to be encrypted. See `marshal::sealed`.
+    #[serde(default)]
+    pub encryption_key: Option<PathBuf>,
+
+    /// Whether to ask the remote if it already holds an object before sending it, and how long to
+    /// trust its answers. See the `negotiation` module.
+    #[serde(default)]
+    pub negotiation: Option<NegotiationCfg>,
+
+    /// How long each read and write of the remote object store may take before it fails, if
+    /// bounded. See `store::GuardedStore`.
+    #[serde(default)]
+    pub timeouts: Option<TimeoutCfg>,
+
+    /// How to retry reads and writes of the remote object store which fail transiently, if at
+    /// all. See `store::RetryStore`.
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+}
+
+
+fn default_present_ttl() -> u64 {
+    60 * 60
+}
+
+
+fn default_absent_ttl() -> u64 {
+    60
+}
+
+
+/// How long the answers of a remote about which objects it holds are trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NegotiationCfg {
+    /// How long to trust that the remote holds an object, in seconds.
+    #[serde(default = "default_present_ttl")]
+    pub present_ttl: u64,
+
+    /// How long to trust that the remote lacks an object, in seconds.
+    #[serde(default = "default_absent_ttl")]
+    pub absent_ttl: u64,
+}
+
+
+impl Default for NegotiationCfg {
+    fn default() -> Self {
+        NegotiationCfg {
+            present_ttl: default_present_ttl(),
+            absent_ttl: default_absent_ttl(),
+        }
+    }
+}
+
+
+/// Configuration for a blocklist of known-bad objects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlocklistCfg {
+    /// The path of the blocklist file, relative to the repository root.
+    pub path: PathBuf,
+
+    /// Whether to reject or merely flag blocklisted objects.
+    #[serde(default)]
+    pub action: BlockAction,
+}
+
+
+/// Limits on the disk space taken by files hydrated from placeholders. Whichever limits are set
+/// are enforced by evicting the least recently used hydrated files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EvictionCfg {
+    /// The most bytes hydrated files may take up in total.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+
+    /// The most days a hydrated file may go unused.
+    #[serde(default)]
+    pub max_idle_days: Option<u64>,
+}
+
+
+/// Which filesystem metadata, if any, is recorded alongside files when committing. Metadata is
+/// always restored on checkout when present, regardless of this setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataMode {
+    /// Record nothing beyond the executable bit.
+    Off,
+
+    /// Record modification times.
+    Mtime,
+
+    /// Record modification times and ownership.
+    Full,
+}
+
+
+impl Default for MetadataMode {
+    fn default() -> Self {
+        MetadataMode::Off
+    }
+}
+
+
+impl MetadataMode {
+    /// Wrap a subtree entry with whatever metadata this mode records.
+    pub fn annotate(self, entry: SubtreeEntry, mtime: i64, uid: u32, gid: u32) -> SubtreeEntry {
+        let owner = match self {
+            MetadataMode::Off => return entry,
+            MetadataMode::Mtime => None,
+            MetadataMode::Full => Some((uid, gid)),
+        };
+
+        entry.annotated(EntryMetadata { mtime, owner })
+    }
+}
+
+
+/// A command run when committing to produce metadata about the new commit, such as a checksum
+/// manifest or a schema summary. See the `notes` module.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GeneratorCfg {
+    /// The name the generator's output is attached to commits under.
+    pub name: String,
+
+    /// The program to run, followed by its arguments.
+    pub command: Vec<String>,
+
+    /// Whether the generator failing should fail the commit, rather than only being reported.
+    #[serde(default)]
+    pub required: bool,
+}
+
+
+/// When a hook is run. See the `hooks` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HookEvent {
+    /// Once a new commit is written, before any branch points at it.
+    #[serde(rename = "pre-commit")]
+    PreCommit,
+
+    /// Once a branch points at a new commit.
+    #[serde(rename = "post-commit")]
+    PostCommit,
+
+    /// Before any objects are sent to a remote.
+    #[serde(rename = "pre-push")]
+    PrePush,
+}
+
+
+impl HookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HookEvent::PreCommit => "pre-commit",
+            HookEvent::PostCommit => "post-commit",
+            HookEvent::PrePush => "pre-push",
+        }
+    }
+}
+
+
+/// A command run when committing or pushing. See the `hooks` module.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookCfg {
+    pub event: HookEvent,
+
+    /// The program to run, followed by its arguments.
+    pub command: Vec<String>,
+}
+
+
+/// Commands which transform the contents of files matching a pattern as they are committed and
+/// checked out. See the `filter` module.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterCfg {
+    /// The files filtered, relative to the root of the worktree.
+    pub pattern: String,
+
+    /// The program to run, followed by its arguments, to clean files when committing. If empty,
+    /// files are stored as they are.
+    #[serde(default)]
+    pub clean: Vec<String>,
+
+    /// The program to run, followed by its arguments, to smudge files when checking out. If
+    /// empty, files are checked out as they are stored.
+    #[serde(default)]
+    pub smudge: Vec<String>,
+}
+
+
+/// Which files line endings are normalized in. See `filter::Eol`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextMode {
+    /// Files which look like text: those without a NUL byte.
+    Auto,
+
+    /// Every file, as text.
+    Text,
+
+    /// No file; line endings are left alone.
+    Binary,
+}
+
+
+impl Default for TextMode {
+    fn default() -> Self {
+        TextMode::Auto
+    }
+}
+
+
+/// The line endings text files are checked out with. They are always stored with `Lf`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+
+impl Default for LineEnding {
+    fn default() -> Self {
+        LineEnding::Lf
+    }
+}
+
+
+/// How line endings are normalized in files matching a pattern. See `filter::Eol`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EolCfg {
+    /// The files normalized, relative to the root of the worktree.
+    pub pattern: String,
+
+    #[serde(default)]
+    pub text: TextMode,
+
+    #[serde(default)]
+    pub eol: LineEnding,
+}
+
+
+/// A directory snapshotted on a schedule by `backup`. See the `backup` module.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupCfg {
+    /// The name the snapshots' branches are kept under.
+    pub name: String,
+
+    /// The directory to snapshot.
+    pub path: PathBuf,
+
+    /// How often to snapshot, and how many snapshots to keep, under each label.
+    pub schedules: Vec<ScheduleCfg>,
+}
+
+
+/// A schedule on which snapshots are taken and labelled, such as "daily" or "weekly".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleCfg {
+    pub label: String,
+
+    /// The least number of hours between two snapshots with this label.
+    pub every_hours: u64,
+
+    /// The number of snapshots with this label to keep. Older ones are pruned.
+    pub keep: usize,
 }
 
 
 /// The persistent data, stored in a repository's config.toml.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    /// The socket of a shared object cache daemon to consult when reading from remotes, if any.
+    #[serde(default)]
+    pub shared_cache: Option<PathBuf>,
+
+    /// Which filesystem metadata to record when committing.
+    #[serde(default)]
+    pub metadata: MetadataMode,
+
+    /// What to do with file names which are not valid UTF-8 when committing. See the
+    /// `marshal::names` module.
+    #[serde(default)]
+    pub non_utf8_names: NonUtf8Names,
+
+    /// Whether to journal metrics for each command run. See the `telemetry` module.
+    #[serde(default)]
+    pub telemetry: bool,
+
+    /// The percentage of objects read from the local store whose hashes are verified, logging
+    /// any corruption found. See the `integrity` module.
+    #[serde(default)]
+    pub integrity_sample_percent: f64,
+
+    /// Whether to maintain an index of which objects refer to each object. See the `backrefs`
+    /// module.
+    #[serde(default)]
+    pub backrefs: bool,
+
+    /// Whether to count the references to each object, so that garbage can be collected
+    /// incrementally. See the `refcount` module.
+    #[serde(default)]
+    pub refcounts: bool,
+
+    /// Whether to maintain an index over commit messages and paths for `log --grep` and `find`.
+    /// See the `search` module.
+    #[serde(default)]
+    pub search_index: bool,
+
+    /// The path of an Ed25519 key with which to sign new commits, if any.
+    #[serde(default)]
+    pub signing_key: Option<PathBuf>,
+
+    /// Hex-encoded public keys whose commit signatures are trusted.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+
+    /// The newest object format version which every client reading this repository's stores
+    /// supports, with `0` meaning objects are written without any version header. Objects are
+    /// written in the newest version both this client and that limit allow.
+    #[serde(default)]
+    pub max_object_version: Option<u8>,
+
+    /// The identity recorded as the author and committer of new commits, unless overridden by
+    /// the environment. See the `identity` module.
+    #[serde(default)]
+    pub identity: Option<Identity>,
+
+    /// A list of known-bad objects which should not be written.
+    #[serde(default)]
+    pub blocklist: Option<BlocklistCfg>,
+
+    /// The remote pushed to when none is named.
+    #[serde(default)]
+    pub default_push: Option<String>,
+
+    /// The remote fetched from when none is named.
+    #[serde(default)]
+    pub default_pull: Option<String>,
+
+    /// Generators run for each new commit, in order. See the `notes` module.
+    #[serde(default)]
+    pub generators: Vec<GeneratorCfg>,
+
+    /// Commands run before and after commits and before pushes, in order. See the `hooks`
+    /// module.
+    #[serde(default)]
+    pub hooks: Vec<HookCfg>,
+
+    /// Content filters for files matching each pattern; of the patterns matching a file, the last
+    /// listed wins. See the `filter` module.
+    #[serde(default)]
+    pub filters: Vec<FilterCfg>,
+
+    /// Line ending normalization for files matching each pattern; of the patterns matching a
+    /// file, the last listed wins, and a filter in `filters` takes precedence over any. See
+    /// `filter::Eol`.
+    #[serde(default)]
+    pub eol: Vec<EolCfg>,
+
+    /// Limits on the files hydrated from placeholders, enforced whenever files are hydrated. See
+    /// the `evict` module.
+    #[serde(default)]
+    pub eviction: Option<EvictionCfg>,
+
+    /// Directories snapshotted on a schedule. See the `backup` module.
+    #[serde(default)]
+    pub backups: Vec<BackupCfg>,
+
+    /// How the chunks of files are arranged into objects when committing, if not the default.
+    /// See `marshal::DataLayout`.
+    #[serde(default)]
+    pub data_layout: Option<DataLayout>,
+
     /// Named remotes for this repository.
     #[serde(serialize_with = "toml::ser::tables_last")]
     pub remotes: HashMap<String, RemoteCfg>,
+
+    /// The global config, which this config overrides. It is never written back.
+    #[serde(skip)]
+    pub global: GlobalConfig,
 }
 
 
 impl Default for Config {
     fn default() -> Config {
-        Config { remotes: HashMap::new() }
+        Config {
+            shared_cache: None,
+            metadata: MetadataMode::Off,
+            non_utf8_names: NonUtf8Names::Reject,
+            telemetry: false,
+            integrity_sample_percent: 0.0,
+            backrefs: false,
+            refcounts: false,
+            search_index: false,
+            signing_key: None,
+            trusted_keys: Vec::new(),
+            max_object_version: None,
+            identity: None,
+            blocklist: None,
+            default_push: None,
+            default_pull: None,
+            generators: Vec::new(),
+            hooks: Vec::new(),
+            filters: Vec::new(),
+            eol: Vec::new(),
+            eviction: None,
+            backups: Vec::new(),
+            data_layout: None,
+            remotes: HashMap::new(),
+            global: GlobalConfig::default(),
+        }
     }
 }
 
@@ -125,12 +688,58 @@ impl Config {
         let mut config_string = String::new();
         config_file.read_to_string(&mut config_string)?;
 
-        Ok(toml::from_str::<Config>(&config_string)?)
+        let mut config = toml::from_str::<Config>(&config_string)?;
+        config.global = GlobalConfig::open()?;
+
+        Ok(config)
+    }
+
+    /// The remote to push to: `name` if given, and the default push remote otherwise.
+    pub fn push_remote(&self, name: Option<&str>) -> Result<String> {
+        self.named_or_default(name, self.default_push.as_ref(), "push")
+    }
+
+    /// The remote to fetch from: `name` if given, and the default pull remote otherwise.
+    pub fn pull_remote(&self, name: Option<&str>) -> Result<String> {
+        self.named_or_default(name, self.default_pull.as_ref(), "pull")
+    }
+
+    fn named_or_default(
+        &self,
+        name: Option<&str>,
+        default: Option<&String>,
+        direction: &'static str,
+    ) -> Result<String> {
+        let name = match (name, default) {
+            (Some(name), _) => name.to_owned(),
+            (None, Some(default)) => default.clone(),
+            (None, None) => bail!(ErrorKind::NoDefaultRemote(direction)),
+        };
+
+        if !self.remotes.contains_key(&name) {
+            bail!(ErrorKind::RemoteNotFound(name));
+        }
+
+        Ok(name)
+    }
+
+    /// The author of new commits. See the `identity` module.
+    pub fn author(&self) -> Option<Identity> {
+        identity::resolve(self, Role::Author).map(|resolved| resolved.value)
+    }
+
+    /// The committer of new commits. See the `identity` module.
+    pub fn committer(&self) -> Option<Identity> {
+        identity::resolve(self, Role::Committer).map(|resolved| resolved.value)
     }
 }
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The branch the HEAD of a new repository is on.
+pub const DEFAULT_BRANCH: &str = "master";
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Head {
     Detached(ObjectHash),
     LocalRef(String),
@@ -139,16 +748,100 @@ pub enum Head {
 }
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The fully qualified name of a ref. Refs live in three namespaces: local branches are written
+/// `heads/<branch>`, tags `tags/<tag>`, and remote-tracking branches, which record where a
+/// remote's branches were last seen, `remotes/<remote>/<branch>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RefName {
+    Branch(String),
+    Tag(String),
+    RemoteBranch(String, String),
+}
+
+
+impl FromStr for RefName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut split = s.splitn(2, '/');
+        let ref_name = match (split.next(), split.next()) {
+            (Some("heads"), Some(branch)) if !branch.is_empty() => {
+                RefName::Branch(branch.to_owned())
+            }
+            (Some("tags"), Some(tag)) if !tag.is_empty() => RefName::Tag(tag.to_owned()),
+            (Some("remotes"), Some(rest)) => {
+                let mut split = rest.splitn(2, '/');
+                match (split.next(), split.next()) {
+                    (Some(remote), Some(branch)) if !remote.is_empty() && !branch.is_empty() => {
+                        RefName::RemoteBranch(remote.to_owned(), branch.to_owned())
+                    }
+                    _ => bail!(ErrorKind::InvalidRefName(s.to_owned())),
+                }
+            }
+            _ => bail!(ErrorKind::InvalidRefName(s.to_owned())),
+        };
+
+        Ok(ref_name)
+    }
+}
+
+
+impl fmt::Display for RefName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RefName::Branch(ref branch) => write!(f, "heads/{}", branch),
+            RefName::Tag(ref tag) => write!(f, "tags/{}", tag),
+            RefName::RemoteBranch(ref remote, ref branch) => {
+                write!(f, "remotes/{}/{}", remote, branch)
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Refs {
     pub head: Head,
     pub branches: HashMap<String, ObjectHash>,
     pub remotes: HashMap<String, HashMap<String, ObjectHash>>,
+    pub tags: HashMap<String, ObjectHash>,
+}
+
+
+/// The refs file as written before tags were kept.
+#[derive(Deserialize)]
+struct LegacyRefs {
+    head: Head,
+    branches: HashMap<String, ObjectHash>,
+    remotes: HashMap<String, HashMap<String, ObjectHash>>,
+}
+
+
+impl From<LegacyRefs> for Refs {
+    fn from(legacy: LegacyRefs) -> Self {
+        Refs {
+            head: legacy.head,
+            branches: legacy.branches,
+            remotes: legacy.remotes,
+            tags: HashMap::new(),
+        }
+    }
 }
 
 
 impl Refs {
+    /// Read the refs, with the HEAD of the working directory `paths` is for.
     pub fn open(paths: &Paths) -> Result<Self> {
+        let mut refs = Self::open_shared(paths)?;
+        if let Some(ref head_path) = paths.head {
+            refs.head = worktree::read_head(head_path)?;
+        }
+
+        Ok(refs)
+    }
+
+    /// Read the refs file, whose HEAD is that of the repository's own working directory.
+    fn open_shared(paths: &Paths) -> Result<Self> {
         if paths.refs.exists() {
             let mut refs_bytes = Vec::new();
             File::open(&paths.refs)
@@ -157,7 +850,11 @@ impl Refs {
                     refs_file.read_to_end(&mut refs_bytes).map_err(Error::from)
                 })
                 .and_then(|_| {
-                    bincode::deserialize::<Refs>(&refs_bytes).map_err(Error::from)
+                    bincode::deserialize::<Refs>(&refs_bytes)
+                        .or_else(|_| {
+                            bincode::deserialize::<LegacyRefs>(&refs_bytes).map(Refs::from)
+                        })
+                        .map_err(Error::from)
                 })
                 .chain_err(|| ErrorKind::OpenRefs(paths.refs.to_owned()))
         } else {
@@ -165,22 +862,102 @@ impl Refs {
                 head: Head::Root,
                 branches: HashMap::new(),
                 remotes: HashMap::new(),
+                tags: HashMap::new(),
             })
         }
     }
 
-    pub fn close(self, paths: &Paths) -> Result<()> {
+    /// Write back any changes made since `loaded` was read from disk. Changes made on disk by
+    /// other processes in the meantime are preserved, unless they touch the same refs.
+    pub fn close(self, paths: &Paths, loaded: &Refs) -> Result<()> {
+        if &self == loaded {
+            return Ok(());
+        }
+
+        let _lock = Self::lock(paths)?;
+        let mut on_disk = Self::open_shared(paths)?;
+
+        if self.head != loaded.head {
+            match paths.head {
+                Some(ref head_path) => worktree::write_head(head_path, &self.head)?,
+                None => on_disk.head = self.head.clone(),
+            }
+        }
+
+        merge_changes(&mut on_disk.branches, &self.branches, &loaded.branches);
+        merge_changes(&mut on_disk.tags, &self.tags, &loaded.tags);
+
+        let empty = HashMap::new();
+        let remote_names = self.remotes
+            .keys()
+            .chain(loaded.remotes.keys())
+            .cloned()
+            .collect::<HashSet<_>>();
+        for remote_name in remote_names {
+            let current = self.remotes.get(&remote_name).unwrap_or(&empty);
+            let original = loaded.remotes.get(&remote_name).unwrap_or(&empty);
+            let merged = on_disk.remotes.entry(remote_name).or_insert_with(HashMap::new);
+
+            merge_changes(merged, current, original);
+        }
+        on_disk.remotes.retain(|_, branches| !branches.is_empty());
+
+        on_disk.write(paths)
+    }
+
+    /// Acquire the lock guarding the refs file, waiting for other processes to release it.
+    fn lock(paths: &Paths) -> Result<LockFile> {
+        LockFile::acquire_timeout(&paths.refs_lock, Duration::from_millis(LOCK_TIMEOUT_MS))
+    }
+
+    /// Write the refs file. The new refs are written to a temporary file and then moved into
+    /// place, so that readers never observe a partially written file.
+    fn write(&self, paths: &Paths) -> Result<()> {
         let mut refs_bytes = Vec::new();
+        let temp_path = paths.refs.with_extension("bin.tmp");
 
-        bincode::serialize_into(&mut refs_bytes, &self, bincode::Infinite)
+        bincode::serialize_into(&mut refs_bytes, self, bincode::Infinite)
             .map_err(Error::from)
-            .and_then(|_| File::create(&paths.refs).map_err(Error::from))
+            .and_then(|_| File::create(&temp_path).map_err(Error::from))
             .and_then(|mut refs_file| {
-                refs_file.write_all(&refs_bytes).map_err(Error::from)
+                refs_file.write_all(&refs_bytes)?;
+                refs_file.sync_all()?;
+                fault::point("refs.write");
+                fs::rename(&temp_path, &paths.refs)?;
+                Ok(())
             })
             .chain_err(|| ErrorKind::CloseRefs(paths.refs.to_owned()))
     }
 
+    /// Point `branch` at `new` if and only if it currently points at `expected`, where an
+    /// `expected` of `None` means that the branch must not yet exist. The comparison is made
+    /// against the refs on disk while holding the refs lock, so concurrent updates from other
+    /// processes are never lost; on success, the in-memory refs are updated to match.
+    pub fn compare_and_swap(
+        &mut self,
+        paths: &Paths,
+        branch: &str,
+        expected: Option<ObjectHash>,
+        new: ObjectHash,
+    ) -> Result<()> {
+        COUNTERS.add_ref_update();
+
+        let _lock = Self::lock(paths)?;
+        let mut on_disk = Self::open_shared(paths)?;
+        let actual = on_disk.branches.get(branch).cloned();
+
+        if actual != expected {
+            COUNTERS.add_ref_conflict();
+            bail!(ErrorKind::RefConflict(branch.to_owned(), expected, actual));
+        }
+
+        on_disk.branches.insert(branch.to_owned(), new);
+        profile::sync(profile::DISK, "refs.cas", || on_disk.write(paths))?;
+        self.branches.insert(branch.to_owned(), new);
+
+        Ok(())
+    }
+
     pub fn head(&self) -> Option<ObjectHash> {
         match self.head {
             Head::Detached(hash) => Some(hash),
@@ -194,47 +971,345 @@ impl Refs {
             Head::Root => None,
         }
     }
+
+    /// The commit the ref `name` points at, if it exists.
+    pub fn get(&self, name: &RefName) -> Option<ObjectHash> {
+        match *name {
+            RefName::Branch(ref branch) => self.branches.get(branch).cloned(),
+            RefName::Tag(ref tag) => self.tags.get(tag).cloned(),
+            RefName::RemoteBranch(ref remote, ref branch) => {
+                self.remotes
+                    .get(remote)
+                    .and_then(|remote| remote.get(branch))
+                    .cloned()
+            }
+        }
+    }
+
+    /// Every commit the HEAD or any ref points at.
+    pub fn targets(&self) -> Vec<ObjectHash> {
+        let mut targets = Vec::new();
+
+        targets.extend(self.head());
+        targets.extend(self.branches.values().cloned());
+        targets.extend(self.tags.values().cloned());
+        for branches in self.remotes.values() {
+            targets.extend(branches.values().cloned());
+        }
+
+        targets
+    }
+
+    /// Replace the remote-tracking branches of `remote` with `branches`, as last seen on the
+    /// remote, returning how they moved.
+    pub fn track_remote(
+        &mut self,
+        remote: &str,
+        branches: HashMap<String, ObjectHash>,
+    ) -> Vec<BranchChange> {
+        let changes = {
+            let empty = HashMap::new();
+            let tracked = self.remotes.get(remote).unwrap_or(&empty);
+            mirror::diff_branches(tracked, &branches)
+        };
+
+        if branches.is_empty() {
+            self.remotes.remove(remote);
+        } else {
+            self.remotes.insert(remote.to_owned(), branches);
+        }
+
+        changes
+    }
+
+    /// Resolve a revision to a commit hash. A revision is `HEAD`, the name of a local branch or a
+    /// tag, a fully qualified ref name (see `RefName`), a remote branch written `remote/branch`,
+    /// or a full commit hash.
+    pub fn resolve(&self, rev: &str) -> Result<ObjectHash> {
+        if rev == "HEAD" {
+            return self.head().ok_or_else(|| {
+                Error::from_kind(ErrorKind::RevisionNotFound(rev.to_owned()))
+            });
+        }
+
+        if let Some(&hash) = self.branches.get(rev).or_else(|| self.tags.get(rev)) {
+            return Ok(hash);
+        }
+
+        if let Some(hash) = rev.parse::<RefName>().ok().and_then(|name| self.get(&name)) {
+            return Ok(hash);
+        }
+
+        let mut split = rev.splitn(2, '/');
+        if let (Some(remote), Some(branch)) = (split.next(), split.next()) {
+            if let Some(&hash) = self.remotes.get(remote).and_then(|remote| remote.get(branch)) {
+                return Ok(hash);
+            }
+        }
+
+        rev.parse().map_err(|_| {
+            Error::from_kind(ErrorKind::RevisionNotFound(rev.to_owned()))
+        })
+    }
+}
+
+
+/// Apply to `target` every change made between `original` and `current`, leaving entries which
+/// were not changed alone.
+fn merge_changes(
+    target: &mut HashMap<String, ObjectHash>,
+    current: &HashMap<String, ObjectHash>,
+    original: &HashMap<String, ObjectHash>,
+) {
+    let names = current.keys().chain(original.keys()).cloned().collect::<HashSet<_>>();
+
+    for name in names {
+        match (current.get(&name), original.get(&name)) {
+            (Some(new), Some(old)) if new == old => {}
+            (Some(&new), _) => {
+                target.insert(name, new);
+            }
+            (None, Some(_)) => {
+                target.remove(&name);
+            }
+            (None, None) => {}
+        }
+    }
 }
 
 
 #[derive(Debug)]
 pub struct Paths {
     pub base: PathBuf,
+
+    /// The root of the repository whose store, config and refs are used: `base` itself, unless
+    /// this is a linked worktree. See the `worktree` module.
+    pub common: PathBuf,
+
+    /// Where a linked worktree keeps its HEAD, apart from the shared refs. `None` for a
+    /// repository's own working directory, whose HEAD is kept with the refs.
+    pub head: Option<PathBuf>,
+
+    pub worktrees: PathBuf,
     pub metadata: PathBuf,
     pub config: PathBuf,
+    pub config_lock: PathBuf,
     pub blobs: PathBuf,
     pub local_catalog: PathBuf,
     pub remote_catalogs: PathBuf,
     pub index: PathBuf,
+    pub index_lock: PathBuf,
     pub refs: PathBuf,
+    pub refs_lock: PathBuf,
+    pub placeholders: PathBuf,
+    pub materialized: PathBuf,
+    pub sparse: PathBuf,
+    pub telemetry: PathBuf,
+    pub corruption: PathBuf,
+    pub bisect: PathBuf,
+    pub daemon_socket: PathBuf,
+    pub rebase: PathBuf,
+    pub conflicts: PathBuf,
+    pub backrefs: PathBuf,
+    pub refcounts: PathBuf,
+    pub refcounts_lock: PathBuf,
+    pub packs: PathBuf,
+    pub quarantine: PathBuf,
+    pub stash: PathBuf,
+    pub shallow: PathBuf,
+    pub staging: PathBuf,
+    pub promised: PathBuf,
+    pub mirror: PathBuf,
+    pub mirror_lock: PathBuf,
+    pub search: PathBuf,
+    pub hash_cache: PathBuf,
 }
 
 
 impl Paths {
     pub fn new<P: AsRef<Path>>(base_ref: P) -> Self {
         let base = base_ref.as_ref().to_owned();
+
+        // A linked worktree keeps its index, HEAD and checkout state to itself, and shares
+        // everything else with the repository it belongs to.
+        let (common, head) = match worktree::common_root(&base) {
+            Some(common) => (common, Some(base.join(&*HEAD_PATH))),
+            None => (base.clone(), None),
+        };
+        let worktrees = common.join(&*WORKTREES_PATH);
+
         let metadata = base.join(&*METADATA_PATH);
-        let config = base.join(&*CONFIG_PATH);
-        let blobs = base.join(&*BLOBS_PATH);
-        let local_catalog = base.join(&*LOCAL_CATALOG_PATH);
-        let remote_catalogs = base.join(&*REMOTE_CATALOGS_PATH);
+        let config = common.join(&*CONFIG_PATH);
+        let config_lock = common.join(&*CONFIG_LOCK_PATH);
+        let blobs = common.join(&*BLOBS_PATH);
+        let local_catalog = common.join(&*LOCAL_CATALOG_PATH);
+        let remote_catalogs = common.join(&*REMOTE_CATALOGS_PATH);
         let index = base.join(&*INDEX_PATH);
-        let refs = base.join(&*REFS_PATH);
+        let index_lock = base.join(&*INDEX_LOCK_PATH);
+        let refs = common.join(&*REFS_PATH);
+        let refs_lock = common.join(&*REFS_LOCK_PATH);
+        let placeholders = base.join(&*PLACEHOLDERS_PATH);
+        let materialized = base.join(&*MATERIALIZED_PATH);
+        let sparse = base.join(&*SPARSE_PATH);
+        let telemetry = common.join(&*TELEMETRY_PATH);
+        let corruption = common.join(&*CORRUPTION_PATH);
+        let bisect = base.join(&*BISECT_PATH);
+        let daemon_socket = base.join(&*DAEMON_SOCKET_PATH);
+        let rebase = base.join(&*REBASE_PATH);
+        let conflicts = base.join(&*CONFLICTS_PATH);
+        let backrefs = common.join(&*BACKREFS_PATH);
+        let refcounts = common.join(&*REFCOUNTS_PATH);
+        let refcounts_lock = common.join(&*REFCOUNTS_LOCK_PATH);
+        let packs = common.join(&*PACKS_PATH);
+        let quarantine = common.join(&*QUARANTINE_PATH);
+        let stash = common.join(&*STASH_PATH);
+        let shallow = common.join(&*SHALLOW_PATH);
+        let staging = common.join(&*STAGING_PATH);
+        let promised = common.join(&*PROMISED_PATH);
+        let mirror = common.join(&*MIRROR_PATH);
+        let mirror_lock = common.join(&*MIRROR_LOCK_PATH);
+        let search = common.join(&*SEARCH_PATH);
+        let hash_cache = base.join(&*HASH_CACHE_PATH);
 
         Self {
             base,
+            common,
+            head,
+            worktrees,
             metadata,
             blobs,
             config,
+            config_lock,
             local_catalog,
             remote_catalogs,
             index,
+            index_lock,
             refs,
+            refs_lock,
+            placeholders,
+            materialized,
+            sparse,
+            telemetry,
+            corruption,
+            bisect,
+            daemon_socket,
+            rebase,
+            conflicts,
+            backrefs,
+            refcounts,
+            refcounts_lock,
+            packs,
+            quarantine,
+            stash,
+            shallow,
+            staging,
+            promised,
+            mirror,
+            mirror_lock,
+            search,
+            hash_cache,
         }
     }
 }
 
 
+/// Connect to the Ceph cluster backing the remote `name`.
+#[cfg(feature = "rados")]
+fn connect_ceph(
+    config: &Config,
+    paths: &Paths,
+    name: &str,
+    ceph_cfg: &CephCfg,
+    local: Local,
+    remote_catalog: &Catalog,
+    io_pool: &CpuPool,
+) -> Result<Remote> {
+    let remote_config = &config.remotes[name];
+    let mut ceph = Ceph::connect(local, remote_catalog, ceph_cfg, io_pool)?;
+
+    if let Some(ref socket_path) = config.shared_cache {
+        ceph = ceph.with_shared_cache(CacheClient::new(socket_path));
+    }
+
+    if let Some(ref key_path) = remote_config.encryption_key {
+        let encryption_key = EncryptionKey::open(paths.common.join(key_path))?;
+        ceph = ceph.with_encryption_key(Arc::new(encryption_key));
+    }
+
+    if let Some(ref negotiation_cfg) = remote_config.negotiation {
+        let path = paths.remote_catalogs.join(format!("{}.negotiation", name));
+        ceph = ceph.with_negotiation(Negotiation::load(path, negotiation_cfg)?);
+    }
+
+    Ok(Remote::Ceph(ceph.with_compression(remote_config.compression)))
+}
+
+
+/// Without the `rados` feature, Ceph remotes can be configured but not connected to.
+#[cfg(not(feature = "rados"))]
+fn connect_ceph(
+    _config: &Config,
+    _paths: &Paths,
+    _name: &str,
+    _ceph_cfg: &CephCfg,
+    _local: Local,
+    _remote_catalog: &Catalog,
+    _io_pool: &CpuPool,
+) -> Result<Remote> {
+    bail!(ErrorKind::BackendNotCompiled("ceph".to_owned(), "rados".to_owned()))
+}
+
+
+/// Create the metadata directory layout of a new repository at `path`: a default config, refs
+/// with the HEAD on the (as yet empty) branch `DEFAULT_BRANCH`, an empty index, and empty local and
+/// remote object stores. Fails if a repository already exists there.
+pub fn init<P: AsRef<Path>>(path: P) -> Result<Paths> {
+    let paths = Paths::new(path);
+
+    if paths.metadata.is_dir() {
+        bail!(
+            "a repository already exists at {}!",
+            paths.metadata.display()
+        );
+    }
+
+    for dir in &[&paths.metadata, &paths.blobs, &paths.remote_catalogs] {
+        fs::create_dir_all(dir).chain_err(|| {
+            format!("error creating {}", dir.display())
+        })?;
+    }
+
+    File::create(&paths.config)
+        .and_then(|mut cfg_file| {
+            cfg_file.write_all(&toml::to_vec(&Config::default()).unwrap())
+        })
+        .chain_err(|| format!("error creating {}", paths.config.display()))?;
+
+    let mut refs = Refs::open(&paths)?;
+    refs.head = Head::LocalRef(DEFAULT_BRANCH.to_owned());
+    refs.write(&paths)?;
+
+    let paths = Arc::new(paths);
+    Index::open(&paths)?.cleanup()?;
+
+    Ok(Arc::try_unwrap(paths).unwrap())
+}
+
+
+/// Walk up from `path` through its ancestors, returning the root of the first repository found.
+pub fn find<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let mut root = path.as_ref().to_owned();
+
+    while !root.join(&*METADATA_PATH).is_dir() {
+        if !root.pop() {
+            bail!(ErrorKind::RepositoryNotFound(path.as_ref().to_owned()));
+        }
+    }
+
+    Ok(root)
+}
+
+
 /// The type of a valid repository.
 #[derive(Debug)]
 pub struct Repository {
@@ -252,40 +1327,63 @@ pub struct Repository {
 
     /// Refs for local branches, remotes, and also the HEAD.
     pub refs: Refs,
-}
 
+    /// Known-bad objects, checked whenever objects are written.
+    pub blocklist: Blocklist,
 
-impl Repository {
-    /// Initialize a repository.
-    pub fn init<P: AsRef<Path>>(path: P) -> Result<()> {
-        let paths = Paths::new(path);
+    /// The key with which new commits are signed, if any.
+    pub signing_key: Option<Arc<SigningKey>>,
 
-        if paths.metadata.is_dir() {
-            bail!(
-                "a repository already exists at {}!",
-                paths.metadata.display()
-            );
-        }
+    /// The format version new objects are encoded with.
+    pub object_version: Version,
 
-        fs::create_dir_all(&paths.metadata).chain_err(|| {
-            format!("error creating {}", paths.metadata.display())
-        })?;
+    /// The reverse reference index, if enabled.
+    pub backrefs: Option<Backrefs>,
 
-        fs::create_dir_all(&paths.blobs).chain_err(|| {
-            format!("error creating {}", paths.blobs.display())
-        })?;
+    /// The counts of references to each object, if enabled.
+    pub refcounts: Option<Refcounts>,
 
-        fs::create_dir_all(&paths.remote_catalogs).chain_err(|| {
-            format!("error creating {}", paths.remote_catalogs.display())
-        })?;
+    /// Commits whose parents are not held locally, if history was fetched shallowly.
+    pub shallow: Shallow,
 
-        File::create(&paths.config)
-            .and_then(|mut cfg_file| {
-                cfg_file.write_all(&toml::to_vec(&Config::default()).unwrap())
-            })
-            .chain_err(|| format!("error creating {}", paths.config.display()))?;
+    /// Objects left on a remote by a fetch restricted to some paths.
+    pub promised: Promised,
 
-        Ok(())
+    /// Hooks run around commits and pushes. Library users may register their own alongside the
+    /// configured ones.
+    pub hooks: HookSet,
+
+    /// The chunkers files are split with. Library users may register their own for the formats
+    /// they know. See the `chunker` module.
+    pub chunkers: ChunkerSet,
+
+    /// The drivers files changed on both sides of a merge are merged with. Library users may
+    /// register their own for their data formats. See the `history::driver` module.
+    pub merge_drivers: MergeDriverSet,
+
+    /// The filters files are cleaned with when committed and smudged with when checked out.
+    /// Library users may register their own alongside the configured ones. See the `filter`
+    /// module.
+    pub filters: FilterSet,
+
+    /// The staging area new local objects are written into, if any. See `begin_staging`.
+    staging: Option<Staging>,
+
+    /// Staging areas to promote once the refs are written.
+    promoting: Vec<Staging>,
+
+    /// The refs as they were when loaded, so that only our own changes are written back.
+    loaded_refs: Refs,
+
+    /// The config as it was when loaded, serialized, so that it is only written back if changed.
+    loaded_config: Vec<u8>,
+}
+
+
+impl Repository {
+    /// Initialize a repository. See `repository::init`.
+    pub fn init<P: AsRef<Path>>(path: P) -> Result<()> {
+        init(path).map(|_| ())
     }
 
     /// Load repository data.
@@ -299,10 +1397,46 @@ impl Repository {
             );
         }
 
+        // A linked worktree is of no use once the repository it belongs to is moved or deleted.
+        if paths.head.is_some() && !paths.common.join(&*METADATA_PATH).is_dir() {
+            bail!(ErrorKind::RepositoryNotFound(paths.common.clone()));
+        }
+
         let config = Config::open(&paths)?;
+        let loaded_config = toml::to_vec(&config)?;
+        Staging::recover(&paths)?;
         let catalogs = Registry::new(&config, &paths);
         let index = Index::open(&paths)?;
         let refs = Refs::open(&paths)?;
+        let loaded_refs = refs.clone();
+        let blocklist = match config.blocklist {
+            Some(ref blocklist_cfg) => {
+                Blocklist::open(paths.common.join(&blocklist_cfg.path), blocklist_cfg.action)?
+            }
+            None => Blocklist::default(),
+        };
+        let object_version = match config.max_object_version {
+            Some(max) => Version::CURRENT.negotiate_byte(max),
+            None => Version::CURRENT,
+        };
+        let signing_key = match identity::resolve_signing_key(&config, &paths) {
+            Some(key_path) => Some(Arc::new(SigningKey::open(key_path.value)?)),
+            None => None,
+        };
+        let shallow = Shallow::open(&paths)?;
+        let promised = Promised::open(&paths)?;
+        let hooks = HookSet::configured(&paths, &config.hooks);
+        let filters = FilterSet::configured(&paths, &config.eol, &config.filters)?;
+        let backrefs = if config.backrefs {
+            Some(Backrefs::open(paths.backrefs.clone())?)
+        } else {
+            None
+        };
+        let refcounts = if config.refcounts {
+            Some(Refcounts::open(&paths)?)
+        } else {
+            None
+        };
 
         Ok(Repository {
             config,
@@ -310,20 +1444,57 @@ impl Repository {
             paths,
             index,
             refs,
+            blocklist,
+            signing_key,
+            object_version,
+            backrefs,
+            refcounts,
+            shallow,
+            promised,
+            hooks,
+            chunkers: ChunkerSet::default(),
+            merge_drivers: MergeDriverSet::default(),
+            filters,
+            staging: None,
+            promoting: Vec::new(),
+            loaded_refs,
+            loaded_config,
         })
     }
 
-    /// Move backwards towards the root of the filesystem searching for a valid repository.
+    /// Load the repository enclosing `path`. See `repository::find`.
     pub fn find<P: AsRef<Path>>(path: P) -> Result<Repository> {
-        let mut attaca_path = path.as_ref().to_owned();
+        Self::load(find(path)?)
+    }
 
-        while !attaca_path.join(&*METADATA_PATH).is_dir() {
-            if !attaca_path.pop() {
-                bail!(ErrorKind::RepositoryNotFound(path.as_ref().to_owned()));
-            }
-        }
+    /// A fingerprint of the settings which decide how a file's contents become objects: its
+    /// filters, line endings, chunker, and data layout.
+    pub fn normalization(&self) -> u64 {
+        let settings = format!(
+            "{:?} {:?} {:?} {}",
+            self.config.filters,
+            self.config.eol,
+            self.config.data_layout,
+            self.chunkers.describe()
+        );
+
+        seahash::hash(settings.as_bytes())
+    }
 
-        Self::load(attaca_path)
+    /// Forget any hashes of worktree files computed under other normalization settings, so that
+    /// a change to them is not masked by stale hashes.
+    pub fn renormalize(&mut self) {
+        let normalization = self.normalization();
+        self.index.renormalize(normalization);
+    }
+
+    /// Every object in the local store known to refer directly to `hash`. Requires the reverse
+    /// reference index to be enabled.
+    pub fn referrers(&self, hash: ObjectHash) -> Result<Vec<ObjectHash>> {
+        match self.backrefs {
+            Some(ref backrefs) => Ok(backrefs.referrers(hash)),
+            None => bail!(ErrorKind::BackrefsDisabled),
+        }
     }
 
     pub fn make_local_catalog(&self) -> Result<Catalog> {
@@ -358,14 +1529,31 @@ impl Repository {
             }
         }
 
+        for hash in Packs::new(self.paths.packs.clone()).hashes()? {
+            objects.insert(hash);
+        }
+
         Catalog::new(objects, self.paths.local_catalog.to_owned())
     }
 
-    /// Update the `config.toml` file.
+    /// Update the `config.toml` file, if the config was changed. Leaving an unchanged config
+    /// alone keeps commands which never touch it from undoing a change made by another process in
+    /// the meantime.
     fn write_config(&mut self) -> Result<()> {
         let config = toml::to_vec(&self.config)?;
-        let mut file = File::create(&self.paths.config)?;
-        file.write_all(&config)?;
+        if config == self.loaded_config {
+            return Ok(());
+        }
+
+        let _lock = LockFile::acquire_timeout(
+            &self.paths.config_lock,
+            Duration::from_millis(LOCK_TIMEOUT_MS),
+        )?;
+        let temp_path = self.paths.config.with_extension("toml.tmp");
+        File::create(&temp_path)?.write_all(&config)?;
+        fs::rename(&temp_path, &self.paths.config)?;
+        self.loaded_config = config;
+
         Ok(())
     }
 
@@ -377,7 +1565,11 @@ impl Repository {
         trace: T,
     ) -> Result<Context<T, Local>> {
         let catalog = self.catalogs.get(None)?;
-        let store = Local::new(&self.paths, &catalog, io_pool);
+        let store = Local::new(&self.paths, &catalog, io_pool)
+            .with_integrity_sampling(self.config.integrity_sample_percent)
+            .with_backrefs(self.backrefs.clone())
+            .with_refcounts(self.refcounts.clone())
+            .with_staging(self.staging.clone().or_else(|| self.promoting.last().cloned()));
 
         Ok(Context::new(self, trace, store, marshal_pool, io_pool))
     }
@@ -405,18 +1597,64 @@ impl Repository {
                     Error::from_kind(ErrorKind::RemoteNotFound(remote_name.as_ref().to_owned()))
                 },
             )?;
-            let local = Local::new(&self.paths, &local_catalog, io_pool);
+            if remote_config.digest != DIGEST {
+                bail!(ErrorKind::UnsupportedDigest(remote_config.digest.clone()));
+            }
+
+            let local = Local::new(&self.paths, &local_catalog, io_pool)
+                .with_integrity_sampling(self.config.integrity_sample_percent)
+                .with_backrefs(self.backrefs.clone())
+                .with_refcounts(self.refcounts.clone());
 
-            match remote_config.object_store {
+            let connect = |object_store: &ObjectStoreCfg| match *object_store {
                 ObjectStoreCfg::Ceph(ref ceph_cfg) => {
-                    Remote::Ceph(Ceph::connect(
-                        local,
-                        &remote_catalog,
+                    connect_ceph(
+                        &self.config,
+                        &self.paths,
+                        remote_name.as_ref(),
                         ceph_cfg,
+                        local.clone(),
+                        &remote_catalog,
                         io_pool,
-                    )?)
+                    )
+                }
+                ref other => {
+                    bail!(ErrorKind::UnsupportedRemoteBackend(other.kind().to_owned()))
+                }
+            };
+
+            let remote = if remote_config.replicas.is_empty() {
+                connect(&remote_config.object_store)?
+            } else {
+                let stores = Some(&remote_config.object_store)
+                    .into_iter()
+                    .chain(&remote_config.replicas)
+                    .map(&connect)
+                    .collect::<Result<Vec<_>>>()?;
+                let replicated = ReplicatedStore::new(stores, remote_config.write_policy, io_pool);
+
+                Remote::Replicated(Box::new(replicated))
+            };
+
+            let remote = match remote_config.simulate {
+                Some(ref simulate_cfg) => {
+                    Remote::Delayed(Box::new(DelayedStore::new(remote, simulate_cfg)))
                 }
-                ObjectStoreCfg::Ssh(ref _ssh_cfg) => unimplemented!(),
+                None => remote,
+            };
+
+            let remote = match remote_config.timeouts {
+                Some(ref timeout_cfg) => {
+                    Remote::Guarded(Box::new(GuardedStore::new(remote, timeout_cfg)))
+                }
+                None => remote,
+            };
+
+            match remote_config.retry {
+                Some(ref retry_policy) => {
+                    Remote::Retrying(Box::new(RetryStore::new(remote, retry_policy)))
+                }
+                None => remote,
             }
         };
 
@@ -434,12 +1672,128 @@ impl Repository {
         self.remote_with_pools(remote_name, &marshal_pool, &io_pool, trace)
     }
 
+    /// The remote-tracking branch `branch` is pulled from by default, if there is one: the branch
+    /// of the same name on the default pull remote.
+    pub fn upstream(&self, branch: &str) -> Option<RefName> {
+        let remote = self.config.pull_remote(None).ok()?;
+        let upstream = RefName::RemoteBranch(remote, branch.to_owned());
+
+        self.refs.get(&upstream).map(|_| upstream)
+    }
+
+    /// Point `branch` at `new` if and only if it currently points at `expected` (or does not exist,
+    /// if `expected` is `None`.) See `Refs::compare_and_swap`.
+    pub fn compare_and_swap_branch(
+        &mut self,
+        branch: &str,
+        expected: Option<ObjectHash>,
+        new: ObjectHash,
+    ) -> Result<()> {
+        // The new refs are written straight away, so objects staged for them must be kept from
+        // here on, even should we crash before `cleanup`.
+        for staging in &self.promoting {
+            staging.mark()?;
+        }
+
+        self.refs.compare_and_swap(&self.paths, branch, expected, new)?;
+        self.loaded_refs.branches.insert(branch.to_owned(), new);
+
+        Ok(())
+    }
+
+    /// Write objects created through local contexts into a staging area, until `finish_staging`
+    /// is called. Should the repository be dropped first, they are discarded. See the
+    /// `store::staging` module.
+    pub fn begin_staging(&mut self) -> Result<()> {
+        let catalog = self.catalogs.get(None)?;
+        self.staging = Some(Staging::begin(&self.paths, &catalog, self.refcounts.as_ref())?);
+
+        Ok(())
+    }
+
+    /// Stop staging, keeping the staged objects. They are moved into the local store once the
+    /// refs are written by `cleanup`; until then, local contexts read them from the staging area,
+    /// and any further objects written through those contexts are kept along with them.
+    pub fn finish_staging(&mut self) {
+        if let Some(staging) = self.staging.take() {
+            self.promoting.push(staging);
+        }
+    }
+
     /// Clean up and drop the `Repository`, writing persistent data to the filesystem.
     pub fn cleanup(mut self) -> Result<()> {
         self.write_config()?;
-        self.refs.close(&self.paths)?;
+
+        for staging in &self.promoting {
+            staging.mark()?;
+        }
+        self.refs.close(&self.paths, &self.loaded_refs)?;
+        fault::point("cleanup.refs-written");
+        for staging in self.promoting.drain(..) {
+            staging.promote()?;
+        }
+
         self.index.cleanup()?;
 
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bench::Scratch;
+
+    #[test]
+    fn find_from_subdirectory() {
+        let scratch = Scratch::new("attaca-init").unwrap();
+        let root = scratch.path();
+        let nested = root.join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+
+        let paths = init(&root).unwrap();
+        assert!(paths.refs.is_file());
+        assert!(paths.index.is_file());
+        assert!(init(&root).is_err());
+
+        assert_eq!(find(&nested).unwrap(), root);
+        let repository = Repository::find(&nested).unwrap();
+        assert_eq!(repository.paths.base, root);
+    }
+
+    #[test]
+    fn qualified_ref_names() {
+        let mut refs = Refs {
+            head: Head::Root,
+            branches: HashMap::new(),
+            remotes: HashMap::new(),
+            tags: HashMap::new(),
+        };
+        let one = format!("{:064x}", 1).parse().unwrap();
+        let two = format!("{:064x}", 2).parse().unwrap();
+        refs.branches.insert("v1".to_owned(), one);
+        refs.tags.insert("v1".to_owned(), two);
+        refs.track_remote("origin", vec![("feature/x".to_owned(), two)].into_iter().collect());
+
+        for name in &["heads/v1", "tags/v1", "remotes/origin/feature/x"] {
+            assert_eq!(&name.parse::<RefName>().unwrap().to_string(), name);
+        }
+        assert!("remotes/origin".parse::<RefName>().is_err());
+        assert!("v1".parse::<RefName>().is_err());
+
+        assert_eq!(refs.resolve("v1").unwrap(), one);
+        assert_eq!(refs.resolve("tags/v1").unwrap(), two);
+        assert_eq!(refs.resolve("remotes/origin/feature/x").unwrap(), two);
+        assert_eq!(refs.resolve("origin/feature/x").unwrap(), two);
+
+        let legacy = bincode::serialize(
+            &(&refs.head, &refs.branches, &refs.remotes),
+            bincode::Infinite,
+        ).unwrap();
+        let decoded = bincode::deserialize::<LegacyRefs>(&legacy).map(Refs::from).unwrap();
+        assert_eq!(decoded.branches, refs.branches);
+        assert!(decoded.tags.is_empty());
+        assert!(bincode::deserialize::<Refs>(&legacy).is_err());
+    }
+}
@@ -0,0 +1,417 @@
+//! # `search` - a trigram index over commit messages and tree paths.
+//!
+//! Searching history otherwise means reading every commit, and every subtree of every commit,
+//! from the store. When a repository's config sets `search_index`, commit messages (trailers
+//! included) and every path which has appeared in a tree are indexed in `.attaca/search.bin`,
+//! which `log --grep` and `find` consult instead.
+//!
+//! Each document - a message or a path - is lowercased and split into overlapping three-byte
+//! sequences. A search looks up the documents containing every trigram of the pattern, and then
+//! checks each of those for the pattern itself; patterns shorter than three bytes check every
+//! document.
+//!
+//! The index is brought up to date by `refresh` after commits and fetches. Only commits not yet
+//! indexed are read, and a subtree is only walked the first time it is seen at a given path, so
+//! a refresh costs in proportion to what changed. Commits at the edge of a shallow history, and
+//! subtrees which a partial fetch left on the remote, are revisited by later refreshes until they
+//! can be read.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+use bincode;
+use futures::prelude::*;
+
+use errors::*;
+use marshal::{ObjectHash, Object, CommitObject, SubtreeEntry};
+use promised::Promised;
+use repository::{Paths, Repository};
+use shallow::Shallow;
+use store::ObjectStore;
+
+
+/// The trigrams of `text`, lowercased, sorted and without duplicates.
+fn trigrams(text: &str) -> Vec<[u8; 3]> {
+    let lowercase = text.to_lowercase();
+    let mut trigrams = lowercase
+        .as_bytes()
+        .windows(3)
+        .map(|window| [window[0], window[1], window[2]])
+        .collect::<Vec<_>>();
+    trigrams.sort();
+    trigrams.dedup();
+
+    trigrams
+}
+
+
+/// Whether `text` contains `pattern`, which is already lowercased, regardless of case.
+fn matches(text: &str, pattern: &str) -> bool {
+    text.to_lowercase().contains(pattern)
+}
+
+
+/// A set of texts with a trigram index over them. Documents are numbered in the order they were
+/// added, so posting lists are always sorted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Documents {
+    texts: Vec<String>,
+    postings: HashMap<[u8; 3], Vec<u32>>,
+}
+
+
+impl Documents {
+    fn add(&mut self, text: String) -> u32 {
+        let id = self.texts.len() as u32;
+
+        for trigram in trigrams(&text) {
+            self.postings.entry(trigram).or_insert_with(Vec::new).push(id);
+        }
+        self.texts.push(text);
+
+        id
+    }
+
+    /// The documents containing `pattern`, in the order they were added.
+    fn search(&self, pattern: &str) -> Vec<u32> {
+        let pattern = pattern.to_lowercase();
+        let pattern_trigrams = trigrams(&pattern);
+
+        if pattern_trigrams.is_empty() {
+            return (0..self.texts.len() as u32)
+                .filter(|&id| matches(&self.texts[id as usize], &pattern))
+                .collect();
+        }
+
+        let mut lists = Vec::new();
+        for trigram in &pattern_trigrams {
+            match self.postings.get(trigram) {
+                Some(list) => lists.push(list),
+                None => return Vec::new(),
+            }
+        }
+
+        // Intersect starting from the shortest list, which bounds the result.
+        lists.sort_by_key(|list| list.len());
+        let mut candidates = lists[0].clone();
+        for list in &lists[1..] {
+            let set = list.iter().collect::<HashSet<_>>();
+            candidates.retain(|id| set.contains(id));
+        }
+
+        candidates
+            .into_iter()
+            .filter(|&id| matches(&self.texts[id as usize], &pattern))
+            .collect()
+    }
+}
+
+
+/// What the index records of each commit, so that history can be walked without the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CommitMeta {
+    hash: ObjectHash,
+    parents: Vec<ObjectHash>,
+    timestamp: i64,
+}
+
+
+/// The number of documents a refresh added.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Refreshed {
+    pub commits: usize,
+    pub paths: usize,
+}
+
+
+/// A path, and the first commit it was indexed in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found<'a> {
+    pub path: &'a str,
+    pub commit: ObjectHash,
+}
+
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    /// Commit messages, numbered as in `commits`.
+    messages: Documents,
+    commits: Vec<CommitMeta>,
+    commit_ids: HashMap<ObjectHash, u32>,
+
+    /// Paths, numbered as in `introduced`.
+    paths: Documents,
+    introduced: Vec<ObjectHash>,
+    path_ids: HashMap<String, u32>,
+
+    /// Subtrees already walked, by the path they were walked at; `None` is the root.
+    walked: HashSet<(Option<u32>, ObjectHash)>,
+
+    /// Commits whose parents were not held locally when they were indexed.
+    boundary: HashSet<ObjectHash>,
+
+    /// Subtrees which could not be read when they were reached, by path, along with the commit
+    /// they were reached from.
+    pending: Vec<(Option<u32>, ObjectHash, ObjectHash)>,
+}
+
+
+impl SearchIndex {
+    /// Load the index, or start an empty one if there is none.
+    pub fn open(paths: &Paths) -> Result<Self> {
+        if !paths.search.is_file() {
+            return Ok(SearchIndex::default());
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&paths.search)?.read_to_end(&mut bytes)?;
+
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        let bytes = bincode::serialize(self, bincode::Infinite)?;
+        let temp_path = paths.search.with_extension("bin.tmp");
+        File::create(&temp_path)?.write_all(&bytes)?;
+        fs::rename(temp_path, &paths.search)?;
+
+        Ok(())
+    }
+
+    /// Whether the commit `hash` has been indexed.
+    pub fn contains(&self, hash: &ObjectHash) -> bool {
+        self.commit_ids.contains_key(hash)
+    }
+
+    /// Index every commit reachable from `heads` which is not yet indexed, along with the paths
+    /// in their trees.
+    pub fn update<S: ObjectStore>(
+        &mut self,
+        store: &S,
+        shallow: &Shallow,
+        promised: &Promised,
+        heads: &[ObjectHash],
+    ) -> Result<Refreshed> {
+        let mut refreshed = Refreshed::default();
+
+        // Commits at the edge of a shallow history may have had their parents fetched since.
+        let mut stack = heads.to_vec();
+        let deepened = self.boundary
+            .iter()
+            .filter(|hash| !shallow.contains(hash))
+            .cloned()
+            .collect::<Vec<_>>();
+        for hash in deepened {
+            self.boundary.remove(&hash);
+            let id = self.commit_ids[&hash];
+            stack.extend(self.commits[id as usize].parents.iter().cloned());
+        }
+
+        let mut new_commits = Vec::new();
+        let mut seen = HashSet::new();
+        while let Some(hash) = stack.pop() {
+            if self.contains(&hash) || !seen.insert(hash) {
+                continue;
+            }
+
+            let commit = read_commit(store, hash)?;
+            stack.extend(shallow.parents(&hash, &commit).iter().cloned());
+            new_commits.push((hash, commit));
+        }
+
+        // Oldest first, so that each path is attributed to the first commit it appeared in.
+        new_commits.sort_by_key(|&(_, ref commit)| commit.timestamp);
+
+        let pending = self.pending.drain(..).collect::<Vec<_>>();
+        for (dir, subtree, commit_hash) in pending {
+            refreshed.paths += self.walk(store, promised, dir, subtree, commit_hash)?;
+        }
+
+        for (hash, commit) in new_commits {
+            if shallow.contains(&hash) {
+                self.boundary.insert(hash);
+            }
+
+            let id = self.messages.add(commit.message.clone());
+            debug_assert_eq!(id as usize, self.commits.len());
+            self.commits.push(CommitMeta {
+                hash,
+                parents: commit.parents.clone(),
+                timestamp: commit.timestamp.timestamp(),
+            });
+            self.commit_ids.insert(hash, id);
+            refreshed.commits += 1;
+
+            refreshed.paths += self.walk(store, promised, None, commit.subtree, hash)?;
+        }
+
+        Ok(refreshed)
+    }
+
+    /// Index the paths beneath the subtree `subtree`, found at the path `dir`, returning how many
+    /// were new.
+    fn walk<S: ObjectStore>(
+        &mut self,
+        store: &S,
+        promised: &Promised,
+        dir: Option<u32>,
+        subtree: ObjectHash,
+        commit_hash: ObjectHash,
+    ) -> Result<usize> {
+        let mut added = 0;
+        let mut stack = vec![(dir, subtree)];
+
+        while let Some((dir, subtree)) = stack.pop() {
+            if self.walked.contains(&(dir, subtree)) {
+                continue;
+            }
+
+            if promised.contains(&subtree) {
+                self.pending.push((dir, subtree, commit_hash));
+                continue;
+            }
+
+            let entries = match store.read_object(subtree).wait()? {
+                Object::Subtree(subtree_object) => subtree_object.entries,
+                _ => bail!(ErrorKind::ObjectNotASubtree(subtree)),
+            };
+            self.walked.insert((dir, subtree));
+
+            for (name, entry) in entries {
+                let name = name.to_string_lossy();
+                let path = match dir {
+                    Some(dir) => format!("{}/{}", self.paths.texts[dir as usize], name),
+                    None => name.into_owned(),
+                };
+
+                let id = match self.path_ids.get(&path).cloned() {
+                    Some(id) => id,
+                    None => {
+                        let id = self.paths.add(path.clone());
+                        self.introduced.push(commit_hash);
+                        self.path_ids.insert(path, id);
+                        added += 1;
+
+                        id
+                    }
+                };
+
+                if let SubtreeEntry::Subtree(child) = entry {
+                    stack.push((Some(id), child));
+                }
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// The commits reachable from `head` whose messages contain `pattern`, newest first, or
+    /// `None` if `head` has not been indexed.
+    pub fn grep(&self, head: ObjectHash, pattern: &str) -> Option<Vec<ObjectHash>> {
+        if !self.contains(&head) {
+            return None;
+        }
+
+        let matching = self.messages.search(pattern).into_iter().collect::<HashSet<_>>();
+        let mut found = Vec::new();
+        let mut stack = vec![self.commit_ids[&head]];
+        let mut visited = HashSet::new();
+
+        while let Some(id) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+
+            if matching.contains(&id) {
+                found.push(id);
+            }
+
+            let meta = &self.commits[id as usize];
+            stack.extend(meta.parents.iter().filter_map(|parent| self.commit_ids.get(parent)));
+        }
+
+        found.sort_by(|&a, &b| {
+            let (a, b) = (&self.commits[a as usize], &self.commits[b as usize]);
+            b.timestamp.cmp(&a.timestamp)
+        });
+
+        Some(found.into_iter().map(|id| self.commits[id as usize].hash).collect())
+    }
+
+    /// Every indexed path containing `pattern`, in the order they were indexed.
+    pub fn find(&self, pattern: &str) -> Vec<Found> {
+        self.paths
+            .search(pattern)
+            .into_iter()
+            .map(|id| {
+                Found {
+                    path: &self.paths.texts[id as usize],
+                    commit: self.introduced[id as usize],
+                }
+            })
+            .collect()
+    }
+
+    /// The number of commits and paths indexed.
+    pub fn len(&self) -> (usize, usize) {
+        (self.commits.len(), self.paths.texts.len())
+    }
+}
+
+
+fn read_commit<S: ObjectStore>(store: &S, hash: ObjectHash) -> Result<CommitObject> {
+    match store.read_object(hash).wait()? {
+        Object::Commit(commit_object) => Ok(commit_object),
+        _ => bail!(ErrorKind::ObjectNotACommit(hash)),
+    }
+}
+
+
+/// Bring the repository's search index up to date with every branch, tag, remote branch, and the
+/// commits `extra`, if the index is enabled.
+pub fn refresh(repository: &mut Repository, extra: &[ObjectHash]) -> Result<Option<Refreshed>> {
+    if !repository.config.search_index {
+        return Ok(None);
+    }
+
+    let mut heads = extra.to_vec();
+    heads.extend(repository.refs.targets());
+
+    let shallow = repository.shallow.clone();
+    let promised = repository.promised.clone();
+    let mut index = SearchIndex::open(&repository.paths)?;
+
+    let refreshed = {
+        let ctx = repository.local(())?;
+        let refreshed = index.update(ctx.store(), &shallow, &promised, &heads)?;
+        ctx.close().wait()?;
+
+        refreshed
+    };
+
+    if refreshed != Refreshed::default() {
+        index.save(&repository.paths)?;
+    }
+
+    Ok(Some(refreshed))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn search_is_case_insensitive_and_exact() {
+        let mut documents = Documents::default();
+        documents.add("data/Train/images/0001.png".to_owned());
+        documents.add("data/test/images/0001.png".to_owned());
+        documents.add("Fix the TRAINING loop".to_owned());
+
+        assert_eq!(documents.search("train"), vec![0, 2]);
+        assert_eq!(documents.search("test/images"), vec![1]);
+        assert_eq!(documents.search("/t"), vec![0, 1]);
+        assert_eq!(documents.search("training data"), Vec::<u32>::new());
+    }
+}
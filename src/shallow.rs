@@ -0,0 +1,95 @@
+//! # `shallow` - record where locally held history stops.
+//!
+//! A depth-limited fetch brings over only the most recent commits of a history, along with their
+//! trees. The oldest commits fetched still name their parents, but those parents are not in the
+//! local store. Such boundary commits are listed one hash per line in `.attaca/shallow`, so that
+//! walks over history stop at them instead of failing to read what lies beyond. Deepening the
+//! history fetches the parents of the boundary commits and moves the boundary back.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+
+use errors::*;
+use marshal::{ObjectHash, CommitObject};
+use repository::Paths;
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Shallow {
+    commits: HashSet<ObjectHash>,
+}
+
+
+impl Shallow {
+    /// Load the shallow boundary of a repository. A repository with complete history has none.
+    pub fn open(paths: &Paths) -> Result<Self> {
+        if !paths.shallow.exists() {
+            return Ok(Shallow::default());
+        }
+
+        let mut commits = HashSet::new();
+        for line_res in BufReader::new(File::open(&paths.shallow)?).lines() {
+            let line = line_res?;
+            if !line.trim().is_empty() {
+                commits.insert(line.trim().parse()?);
+            }
+        }
+
+        Ok(Shallow { commits })
+    }
+
+    pub fn save(&self, paths: &Paths) -> Result<()> {
+        if self.commits.is_empty() {
+            if paths.shallow.exists() {
+                fs::remove_file(&paths.shallow)?;
+            }
+
+            return Ok(());
+        }
+
+        let mut commits = self.commits.iter().collect::<Vec<_>>();
+        commits.sort();
+
+        let mut file = File::create(&paths.shallow)?;
+        for commit_hash in commits {
+            writeln!(file, "{}", commit_hash)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commits.is_empty()
+    }
+
+    pub fn contains(&self, commit_hash: &ObjectHash) -> bool {
+        self.commits.contains(commit_hash)
+    }
+
+    pub fn insert(&mut self, commit_hash: ObjectHash) -> bool {
+        self.commits.insert(commit_hash)
+    }
+
+    pub fn remove(&mut self, commit_hash: &ObjectHash) -> bool {
+        self.commits.remove(commit_hash)
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = ObjectHash> + 'a {
+        self.commits.iter().cloned()
+    }
+
+    /// The parents of a commit which are held locally: none for a boundary commit, and all of
+    /// them otherwise.
+    pub fn parents<'a>(
+        &self,
+        commit_hash: &ObjectHash,
+        commit: &'a CommitObject,
+    ) -> &'a [ObjectHash] {
+        if self.contains(commit_hash) {
+            &[]
+        } else {
+            &commit.parents
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! # `sign` - Ed25519 signatures over commits.
+//!
+//! A signature is detached from the commit it signs: it is stored in the commit's `signature`
+//! field, but covers the commit's *canonical encoding*, which is the encoding of the commit with
+//! that field cleared. The signature carries the public key of its signer, so a commit can always
+//! be checked for internal consistency; whether that key is trusted is up to the repository's
+//! configuration.
+//!
+//! Signing keys are stored as 64 bytes: the 32-byte secret key followed by the 32-byte public key.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature as Ed25519Signature};
+use rand::OsRng;
+use sha2::Sha512;
+
+use errors::*;
+use marshal::{CommitObject, Object};
+
+
+/// A detached signature over the canonical encoding of a commit.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CommitSignature {
+    /// The Ed25519 public key of the signer.
+    pub public_key: Vec<u8>,
+
+    /// The Ed25519 signature itself.
+    pub signature: Vec<u8>,
+}
+
+
+/// The result of checking a commit's signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// The commit carries no signature.
+    Unsigned,
+
+    /// The signature is valid, and was made by the given public key.
+    Valid(PublicKeyHex),
+
+    /// The commit carries a signature which does not match its contents.
+    Invalid,
+}
+
+
+/// A hex-encoded Ed25519 public key, as it appears in configuration and in output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PublicKeyHex(pub String);
+
+
+impl fmt::Display for PublicKeyHex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+
+/// An Ed25519 keypair used to sign commits.
+pub struct SigningKey {
+    keypair: Keypair,
+}
+
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+
+impl SigningKey {
+    /// Generate a fresh keypair from the operating system's random number generator.
+    pub fn generate() -> Result<Self> {
+        let mut rng = OsRng::new()?;
+        let keypair = Keypair::generate::<Sha512>(&mut rng);
+
+        Ok(SigningKey { keypair })
+    }
+
+    /// Load a keypair from a file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .chain_err(|| ErrorKind::SigningKeyOpen(path.to_owned()))?;
+
+        if bytes.len() != 64 {
+            bail!(ErrorKind::SigningKeyOpen(path.to_owned()));
+        }
+
+        let secret = SecretKey::from_bytes(&bytes[..32]).map_err(|_| {
+            Error::from_kind(ErrorKind::SigningKeyOpen(path.to_owned()))
+        })?;
+        let public = PublicKey::from_bytes(&bytes[32..]).map_err(|_| {
+            Error::from_kind(ErrorKind::SigningKeyOpen(path.to_owned()))
+        })?;
+
+        Ok(SigningKey { keypair: Keypair { secret, public } })
+    }
+
+    /// Write this keypair to a file, truncating it if it exists. Whether or not it did, and
+    /// whatever the umask, the file is made readable and writable only by its owner before the
+    /// key is written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(self.keypair.secret.as_bytes())?;
+        file.write_all(self.keypair.public.as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn public_key(&self) -> PublicKeyHex {
+        PublicKeyHex(to_hex(self.keypair.public.as_bytes()))
+    }
+
+    /// Sign a commit in place, replacing any existing signature.
+    pub fn sign(&self, commit: &mut CommitObject) -> Result<()> {
+        let message = canonical_bytes(commit)?;
+        let signature = self.keypair.sign::<Sha512>(&message);
+
+        commit.signature = Some(CommitSignature {
+            public_key: self.keypair.public.as_bytes().to_vec(),
+            signature: signature.to_bytes().to_vec(),
+        });
+
+        Ok(())
+    }
+}
+
+
+/// The bytes covered by a commit's signature: the encoding of the commit without its signature.
+pub fn canonical_bytes(commit: &CommitObject) -> Result<Vec<u8>> {
+    let mut unsigned = commit.clone();
+    unsigned.signature = None;
+
+    Object::Commit(unsigned).as_raw().to_bytes()
+}
+
+
+/// Check a commit's signature against its contents.
+pub fn verify(commit: &CommitObject) -> Result<Verification> {
+    let commit_signature = match commit.signature {
+        Some(ref commit_signature) => commit_signature,
+        None => return Ok(Verification::Unsigned),
+    };
+
+    let public_key = match PublicKey::from_bytes(&commit_signature.public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return Ok(Verification::Invalid),
+    };
+    let signature = match Ed25519Signature::from_bytes(&commit_signature.signature) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(Verification::Invalid),
+    };
+
+    let message = canonical_bytes(commit)?;
+    if public_key.verify::<Sha512>(&message, &signature) {
+        Ok(Verification::Valid(
+            PublicKeyHex(to_hex(&commit_signature.public_key)),
+        ))
+    } else {
+        Ok(Verification::Invalid)
+    }
+}
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::prelude::*;
+
+    use marshal::ObjectHash;
+
+    fn commit() -> CommitObject {
+        CommitObject {
+            subtree: ObjectHash::zero(),
+            parents: Vec::new(),
+            message: "Test commit.".to_owned(),
+            timestamp: Utc.timestamp(0, 0),
+            signature: None,
+            author: None,
+            committer: None,
+        }
+    }
+
+    #[test]
+    fn sign_then_verify() {
+        let key = SigningKey::generate().unwrap();
+        let mut commit = commit();
+
+        assert_eq!(verify(&commit).unwrap(), Verification::Unsigned);
+        key.sign(&mut commit).unwrap();
+        assert_eq!(
+            verify(&commit).unwrap(),
+            Verification::Valid(key.public_key())
+        );
+
+        commit.message.push('!');
+        assert_eq!(verify(&commit).unwrap(), Verification::Invalid);
+    }
+}
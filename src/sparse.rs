@@ -0,0 +1,94 @@
+//! # `sparse` - restrict a working directory to a subset of the repository.
+//!
+//! A sparse checkout materializes only those files which match a set of glob patterns. The
+//! patterns are stored one per line in `.attaca/sparse`, so that later operations know which part
+//! of the repository the working directory actually represents. Files outside of the sparse view
+//! are never registered with the index or reported as removed, and so commits leave them exactly
+//! as they were in the parent commit.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use errors::*;
+use repository::Paths;
+
+
+#[derive(Debug, Clone)]
+pub struct Sparse {
+    patterns: Vec<String>,
+    globset: GlobSet,
+}
+
+
+impl Sparse {
+    pub fn new<I, S>(patterns: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let patterns = patterns.into_iter().map(Into::into).collect::<Vec<String>>();
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+        let globset = builder.build()?;
+
+        Ok(Sparse { patterns, globset })
+    }
+
+    /// Load the sparse patterns of a repository. Returns `None` if the working directory is not a
+    /// sparse checkout.
+    pub fn open(paths: &Paths) -> Result<Option<Self>> {
+        if !paths.sparse.exists() {
+            return Ok(None);
+        }
+
+        let file = File::open(&paths.sparse).chain_err(|| {
+            ErrorKind::SparseOpen(paths.sparse.clone())
+        })?;
+        let patterns = BufReader::new(file)
+            .lines()
+            .filter(|line_res| {
+                line_res
+                    .as_ref()
+                    .map(|line| !line.trim().is_empty())
+                    .unwrap_or(true)
+            })
+            .collect::<::std::io::Result<Vec<String>>>()?;
+
+        Ok(Some(Self::new(patterns)?))
+    }
+
+    /// Persist a (possibly absent) set of sparse patterns to a repository.
+    pub fn save(sparse_opt: Option<&Self>, paths: &Paths) -> Result<()> {
+        match sparse_opt {
+            Some(sparse) => {
+                let mut file = File::create(&paths.sparse)?;
+                for pattern in &sparse.patterns {
+                    writeln!(file, "{}", pattern)?;
+                }
+            }
+            None if paths.sparse.exists() => fs::remove_file(&paths.sparse)?,
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn globset(&self) -> &GlobSet {
+        &self.globset
+    }
+
+    /// Check whether a path (relative to the repository root) is inside the sparse view.
+    pub fn is_match<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.globset.is_match(path)
+    }
+}
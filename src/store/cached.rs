@@ -0,0 +1,342 @@
+//! # `cached` - a fast local tier in front of a slow store.
+//!
+//! Against a high-latency remote, reading the same objects again - walking the same tree for a
+//! second checkout, or diffing against a commit just fetched - costs a round trip every time.
+//! `CachedStore` puts a fast local store in front of a remote one: reads are tried against the
+//! local tier first, and objects which have to be fetched from the remote are written into the
+//! local tier on the way through. Writes go straight to the remote, which stays the only
+//! authoritative copy.
+//!
+//! The local tier holds at most `capacity` bytes of encoded objects. Past that, the least recently
+//! read objects are evicted from it. Any store which objects can be dropped from can be the local
+//! tier; `CacheDir` keeps them as files in a directory of their own, apart from the repository's
+//! blobs, so that nothing but cached copies is ever evicted.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use futures::future;
+use futures::prelude::*;
+use futures_cpupool::CpuPool;
+
+use arc_slice;
+use errors::*;
+use marshal::{self, Hashed, Object, ObjectHash};
+use store::ObjectStore;
+use warning;
+
+
+/// A store which objects may be dropped from, for use as the local tier of a `CachedStore`.
+pub trait EvictableStore: ObjectStore {
+    /// Drop an object from the store. Dropping an object which is not present is not an error.
+    fn evict(&self, object_hash: ObjectHash) -> Result<()>;
+
+    /// Every object already in the store along with its encoded size, least recently used first.
+    fn cached(&self) -> Result<Vec<(ObjectHash, u64)>>;
+}
+
+
+/// A directory of cached objects, one file per object, laid out as the repository's blobs are.
+#[derive(Debug, Clone)]
+pub struct CacheDir {
+    path: Arc<PathBuf>,
+    io_pool: CpuPool,
+}
+
+
+impl CacheDir {
+    pub fn open<P: Into<PathBuf>>(path: P, io_pool: &CpuPool) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        Ok(CacheDir {
+            path: Arc::new(path),
+            io_pool: io_pool.clone(),
+        })
+    }
+
+    fn path_of(&self, object_hash: ObjectHash) -> PathBuf {
+        self.path.join(object_hash.to_path())
+    }
+}
+
+
+impl ObjectStore for CacheDir {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        let path = self.path_of(object_hash);
+
+        Box::new(self.io_pool.spawn_fn(move || {
+            let mut bytes = Vec::new();
+            File::open(&path)
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .chain_err(|| ErrorKind::OpenLocalObject(object_hash))?;
+
+            // A damaged cache file is dropped, so that the object is fetched anew next time.
+            let object = Object::from_bytes(arc_slice::owned(bytes))?;
+            if marshal::hash(&object) != object_hash {
+                let _ = fs::remove_file(&path);
+                bail!(ErrorKind::OpenLocalObject(object_hash));
+            }
+
+            Ok(object)
+        }))
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        let (object_hash, bytes_opt) = hashed.into_components();
+        let path = self.path_of(object_hash);
+
+        let bytes = match bytes_opt {
+            Some(bytes) => bytes,
+            None => return Box::new(future::ok(false)),
+        };
+
+        Box::new(self.io_pool.spawn_fn(move || {
+            if path.exists() {
+                return Ok(false);
+            }
+
+            fs::create_dir_all(path.parent().unwrap())?;
+            let temp = path.with_extension("tmp");
+            File::create(&temp).and_then(|mut file| file.write_all(&bytes))?;
+            fs::rename(&temp, &path)?;
+
+            Ok(true)
+        }))
+    }
+}
+
+
+impl EvictableStore for CacheDir {
+    fn evict(&self, object_hash: ObjectHash) -> Result<()> {
+        match fs::remove_file(self.path_of(object_hash)) {
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => Ok(other?),
+        }
+    }
+
+    fn cached(&self) -> Result<Vec<(ObjectHash, u64)>> {
+        let mut cached = Vec::new();
+        let mut dirs = vec![((*self.path).clone(), String::new())];
+
+        while let Some((dir, prefix)) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+                if metadata.is_dir() {
+                    dirs.push((entry.path(), name));
+                    continue;
+                }
+
+                // Leftovers of interrupted writes are not objects.
+                let object_hash = match name.parse::<ObjectHash>() {
+                    Ok(object_hash) => object_hash,
+                    Err(_) => continue,
+                };
+
+                let used = metadata
+                    .accessed()
+                    .or_else(|_| metadata.modified())?
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0);
+
+                cached.push((used, object_hash, metadata.len()));
+            }
+        }
+
+        cached.sort();
+        Ok(cached.into_iter().map(|(_, hash, size)| (hash, size)).collect())
+    }
+}
+
+
+/// Which objects the local tier holds, and how recently each was read.
+#[derive(Debug, Default)]
+struct Recency {
+    size: u64,
+    tick: u64,
+
+    objects: HashMap<ObjectHash, (u64, u64)>,
+    by_tick: BTreeMap<u64, ObjectHash>,
+}
+
+
+impl Recency {
+    /// Mark an object as just read. Returns whether the local tier should have it.
+    fn touch(&mut self, object_hash: ObjectHash) -> bool {
+        self.tick += 1;
+        let tick = self.tick;
+
+        match self.objects.get_mut(&object_hash) {
+            Some(&mut (ref mut last_used, _)) => {
+                self.by_tick.remove(last_used);
+                self.by_tick.insert(tick, object_hash);
+                *last_used = tick;
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record an object added to the local tier, returning those which must be evicted to stay
+    /// within `capacity`.
+    fn insert(&mut self, object_hash: ObjectHash, size: u64, capacity: u64) -> Vec<ObjectHash> {
+        if !self.objects.contains_key(&object_hash) {
+            self.tick += 1;
+            self.size += size;
+            self.by_tick.insert(self.tick, object_hash);
+            self.objects.insert(object_hash, (self.tick, size));
+        }
+
+        let mut evicted = Vec::new();
+        while self.size > capacity {
+            let oldest = match self.by_tick.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let evicted_hash = self.by_tick.remove(&oldest).unwrap();
+            self.remove(evicted_hash);
+            evicted.push(evicted_hash);
+        }
+
+        evicted
+    }
+
+    fn remove(&mut self, object_hash: ObjectHash) {
+        if let Some((last_used, size)) = self.objects.remove(&object_hash) {
+            self.by_tick.remove(&last_used);
+            self.size -= size;
+        }
+    }
+}
+
+
+/// A remote store behind a local cache. See the module docs.
+#[derive(Clone)]
+pub struct CachedStore<L: EvictableStore, R: ObjectStore> {
+    local: L,
+    remote: R,
+    capacity: u64,
+    recency: Arc<Mutex<Recency>>,
+}
+
+
+impl<L: EvictableStore, R: ObjectStore> CachedStore<L, R> {
+    /// Put `local` in front of `remote`, holding at most `capacity` bytes in `local`. Objects
+    /// already in `local` are kept, oldest first, as far as the capacity allows.
+    pub fn new(local: &L, remote: &R, capacity: u64) -> Result<Self> {
+        let mut recency = Recency::default();
+        for (object_hash, size) in local.cached()? {
+            for evicted in recency.insert(object_hash, size, capacity) {
+                local.evict(evicted)?;
+            }
+        }
+
+        Ok(CachedStore {
+            local: local.clone(),
+            remote: remote.clone(),
+            capacity,
+            recency: Arc::new(Mutex::new(recency)),
+        })
+    }
+
+    pub fn local(&self) -> &L {
+        &self.local
+    }
+
+    pub fn remote(&self) -> &R {
+        &self.remote
+    }
+
+    /// The number of bytes currently held by the local tier.
+    pub fn cached_bytes(&self) -> u64 {
+        self.recency.lock().unwrap().size
+    }
+}
+
+
+impl<L: EvictableStore, R: ObjectStore> ObjectStore for CachedStore<L, R> {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = R::Write;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        let local = self.local.clone();
+        let remote = self.remote.clone();
+        let capacity = self.capacity;
+        let recency = self.recency.clone();
+
+        let result = {
+            async_block! {
+                if recency.lock().unwrap().touch(object_hash) {
+                    match await!(local.read_object(object_hash)) {
+                        Ok(object) => return Ok(object),
+                        Err(_) => recency.lock().unwrap().remove(object_hash),
+                    }
+                }
+
+                let object = await!(remote.read_object(object_hash))?;
+
+                // The cache is best-effort: failing to fill it never fails the read.
+                let hashed = marshal::serialize_and_hash(&object);
+                let size = hashed.as_bytes().map(|bytes| bytes.len() as u64).unwrap_or(0);
+                if await!(local.write_object(hashed)).is_ok() {
+                    let evicted = recency.lock().unwrap().insert(object_hash, size, capacity);
+                    for evicted_hash in evicted {
+                        if let Err(error) = local.evict(evicted_hash) {
+                            warning::warn(format!(
+                                "could not evict {} from the cache: {}",
+                                evicted_hash,
+                                error
+                            ));
+                        }
+                    }
+                }
+
+                Ok(object)
+            }
+        };
+
+        Box::new(result)
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        self.remote.write_object(hashed)
+    }
+
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.remote.is_known(object_hash)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_read() {
+        let hashes = (1..4)
+            .map(|i| format!("{:02x}", i).repeat(32).parse::<ObjectHash>().unwrap())
+            .collect::<Vec<_>>();
+        let mut recency = Recency::default();
+
+        assert!(recency.insert(hashes[0], 40, 100).is_empty());
+        assert!(recency.insert(hashes[1], 40, 100).is_empty());
+        assert!(recency.touch(hashes[0]));
+
+        assert_eq!(recency.insert(hashes[2], 40, 100), vec![hashes[1]]);
+        assert!(!recency.touch(hashes[1]));
+        assert_eq!(recency.size, 80);
+    }
+}
@@ -4,18 +4,31 @@
 //!
 //! At current the only supported remote is a Ceph/RADOS cluster.
 
+use std::cmp;
+use std::fs;
+use std::result::Result as StdResult;
 use std::sync::{Arc, Mutex};
 
+use zstd;
+
+use futures::future;
 use futures::prelude::*;
 use futures_cpupool::CpuPool;
 use owning_ref::OwningRefMut;
 use rad::{ConnectionBuilder, Connection};
 
+use cache::CacheClient;
 use catalog::Catalog;
 use errors::*;
 use marshal::{Hashed, ObjectHash, Object};
-use repository::CephCfg;
-use store::{ObjectStore, Local};
+use marshal::canonical;
+use marshal::sealed::{self, EncryptionKey};
+use negotiation::Negotiation;
+use profile;
+use repository::{CephCfg, Compression};
+use store::{ObjectStore, Local, RangeStore, RefStore, decompress_stored};
+use store::transaction::{Batch, TransactionalStore};
+use telemetry::{self, COUNTERS, Operation};
 
 
 /// The type of a remote repository.
@@ -31,12 +44,51 @@ pub struct Ceph {
 
     catalog: Catalog,
     inner: Arc<CephInner>,
+
+    /// A shared cache daemon to consult, along with the identity this remote is known to it by.
+    /// See `identity`.
+    shared: Option<(String, CacheClient)>,
+
+    compression: Compression,
+
+    /// The key objects are sealed with before they are sent, if any. See `marshal::sealed`.
+    encryption_key: Option<Arc<EncryptionKey>>,
+
+    /// Remembered answers about which objects the remote holds, if it is asked before sending.
+    negotiation: Option<Negotiation>,
 }
 
 
 struct CephInner {
     conn: Mutex<Connection>,
     pool: String,
+
+    /// What the cluster and pools behind this remote are known by to the shared cache daemon.
+    identity: String,
+
+    /// The pool objects other than data objects are kept in, if not `pool`.
+    metadata_pool: Option<String>,
+}
+
+
+impl CephInner {
+    /// The pool an object is written to, given whether or not it is a data object.
+    fn pool_for(&self, is_data: bool) -> &str {
+        match self.metadata_pool {
+            Some(ref metadata_pool) if !is_data => metadata_pool,
+            _ => &self.pool,
+        }
+    }
+
+    /// The pools an object may be found in, in the order they are searched. The metadata pool
+    /// comes first: failing to find a chunk there costs little next to reading the chunk, whereas
+    /// failing to find a subtree in the data pool would slow every walk of the tree.
+    fn search_order(&self) -> Vec<&str> {
+        match self.metadata_pool {
+            Some(ref metadata_pool) => vec![metadata_pool, &self.pool],
+            None => vec![&self.pool],
+        }
+    }
 }
 
 
@@ -71,6 +123,8 @@ impl Ceph {
         };
 
         let pool = remote_config.pool.clone();
+        let metadata_pool = remote_config.metadata_pool.clone();
+        let identity = identity(remote_config);
 
         Ok(Ceph {
             local,
@@ -78,13 +132,172 @@ impl Ceph {
             io_pool: io_pool.clone(),
 
             catalog: remote_catalog.clone(),
-            inner: Arc::new(CephInner { conn, pool }),
+            inner: Arc::new(CephInner {
+                conn,
+                pool,
+                identity,
+                metadata_pool,
+            }),
+
+            shared: None,
+
+            compression: Compression::None,
+            encryption_key: None,
+
+            negotiation: None,
         })
     }
 
+    /// Compress objects sent to the remote. See `repository::Compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Seal objects sent to the remote with the given key, and open sealed objects read from it.
+    pub fn with_encryption_key(mut self, encryption_key: Arc<EncryptionKey>) -> Self {
+        self.encryption_key = Some(encryption_key);
+        self
+    }
+
+    /// Ask the remote whether it already holds each object missing from the catalog before
+    /// sending it, remembering its answers in `negotiation`. See the `negotiation` module.
+    pub fn with_negotiation(mut self, negotiation: Negotiation) -> Self {
+        self.negotiation = Some(negotiation);
+        self
+    }
+
+    /// Whether objects are stored on the remote as anything other than their encoding, in which
+    /// case they cannot be read straight into the local store.
+    fn transforms(&self) -> bool {
+        self.compression != Compression::None || self.encryption_key.is_some()
+    }
+
+    /// Read an object exactly as the remote stores it: possibly sealed and compressed, and
+    /// without writing it to the local store.
+    pub fn read_stored(
+        &self,
+        object_hash: ObjectHash,
+    ) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let ctxs_res = self.inner
+            .search_order()
+            .into_iter()
+            .map(|pool| self.inner.conn.lock().unwrap().get_pool_context(pool))
+            .collect::<StdResult<Vec<_>, _>>();
+
+        let result = {
+            async_block! {
+                let object_id = object_hash.to_string();
+
+                let mut found = None;
+                let mut last_error = None;
+                for mut ctx in ctxs_res? {
+                    let stat_res = await!(ctx.stat_async(&object_id));
+                    match stat_res {
+                        Ok(stat) => {
+                            found = Some((ctx, stat));
+                            break;
+                        }
+                        Err(error) => last_error = Some(Error::from(error)),
+                    }
+                }
+                let (mut ctx, stat) = match found {
+                    Some(found) => found,
+                    None => return Err(last_error.unwrap()),
+                };
+
+                let mut stored = vec![0; stat.size as usize];
+                let mut total_read = 0;
+                while total_read < stored.len() {
+                    let window = OwningRefMut::new(stored)
+                        .map_mut(|slice| &mut slice[total_read..]);
+                    let (bytes_read, returned) =
+                        await!(ctx.read_async(&object_id, window, total_read as u64))?;
+                    stored = returned.into_inner();
+
+                    if bytes_read == 0 {
+                        bail!(ErrorKind::TruncatedObject(object_hash));
+                    }
+                    total_read += bytes_read as usize;
+                }
+                COUNTERS.add_bytes_fetched(total_read as u64);
+
+                Ok(stored)
+            }
+        };
+
+        Box::new(result)
+    }
+
+    /// Read part of the chunk of a small object, fetching only the bytes which locate the chunk
+    /// and the range itself. See `RangeStore::read_range`. Objects which are compressed or sealed
+    /// on the remote cannot be read in part.
+    pub fn read_range(
+        &self,
+        object_hash: ObjectHash,
+        offset: u64,
+        len: u64,
+    ) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send> {
+        if self.transforms() {
+            return Box::new(future::ok(None));
+        }
+
+        // Only data objects can be read in part, and they are always in the data pool.
+        let ctx_res = self.inner.conn.lock().unwrap().get_pool_context(
+            self.inner.pool_for(true),
+        );
+
+        let result = {
+            async_block! {
+                let mut ctx = ctx_res?;
+                let object_id = object_hash.to_string();
+
+                let prefix = vec![0; canonical::SMALL_PREFIX_SIZE];
+                let (prefix_read, returned) =
+                    await!(ctx.read_async(&object_id, OwningRefMut::new(prefix), 0))?;
+                let prefix = returned.into_inner();
+                let (chunk_start, chunk_len) =
+                    match canonical::small_chunk_span(&prefix[..prefix_read as usize]) {
+                        Some(span) => span,
+                        None => return Ok(None),
+                    };
+
+                let start = cmp::min(offset, chunk_len);
+                let end = cmp::min(offset.saturating_add(len), chunk_len);
+                let mut range = vec![0; (end - start) as usize];
+                let mut total_read = 0;
+                while total_read < range.len() {
+                    let window = OwningRefMut::new(range).map_mut(|slice| &mut slice[total_read..]);
+                    let position = chunk_start + start + total_read as u64;
+                    let (bytes_read, returned) =
+                        await!(ctx.read_async(&object_id, window, position))?;
+                    range = returned.into_inner();
+
+                    if bytes_read == 0 {
+                        bail!(ErrorKind::TruncatedObject(object_hash));
+                    }
+                    total_read += bytes_read as usize;
+                }
+                COUNTERS.add_bytes_fetched(prefix_read as u64 + total_read as u64);
+
+                Ok(Some(range))
+            }
+        };
+
+        Box::new(result)
+    }
+
+    /// Consult a shared cache daemon for objects and for whether or not the remote already
+    /// contains an object. The remote is known to the daemon by the cluster and pools it connects
+    /// to, not by its name, which other repositories may give to another remote entirely.
+    pub fn with_shared_cache(mut self, client: CacheClient) -> Self {
+        self.shared = Some((self.inner.identity.clone(), client));
+        self
+    }
+
     /// Write a single object to the remote repository. Returns `false` and performs no I/O if the
-    /// catalog shows that the remote already contains the object; `true` otherwise.
-    // TODO: Query the remote to see if it contains the object already. If so, don't send.
+    /// catalog shows that the remote already contains the object, or if negotiating and the remote
+    /// says it does; `true` otherwise.
     pub fn write_object(&self, hashed: Hashed) -> Box<Future<Item = bool, Error = Error> + Send> {
         let lock = match self.catalog.try_lock(*hashed.as_hash()) {
             Ok(lock) => lock,
@@ -92,21 +305,72 @@ impl Ceph {
         };
         let (hash, bytes_opt) = hashed.into_components();
 
+        // The shared cache's existence answers come from another process, which is not trusted to
+        // decide whether an object is written.
         match bytes_opt {
             Some(bytes) => {
                 let ctx_res = self.inner.conn.lock().unwrap().get_pool_context(
-                    &self.inner.pool,
+                    self.inner.pool_for(canonical::is_data(&bytes)),
                 );
+                let shared = self.shared.clone();
+                let compression = self.compression;
+                let encryption_key = self.encryption_key.clone();
+                let negotiation = self.negotiation.clone();
+                let size = bytes.len() as u64;
                 let result = {
                     async_block! {
                         let mut ctx = ctx_res?;
-                        await!(ctx.write_full_async(&hash.to_string(), &bytes))?;
+
+                        if let Some(ref negotiation) = negotiation {
+                            let present = match negotiation.get(hash) {
+                                Some(present) => present,
+                                None => {
+                                    // Should asking fail, the object is simply sent.
+                                    let stat = await!(ctx.stat_async(&hash.to_string()));
+                                    let present = stat.is_ok();
+                                    negotiation.record(hash, present);
+                                    present
+                                }
+                            };
+
+                            if present {
+                                lock.release();
+                                return Ok(false);
+                            }
+                        }
+
+                        let refs = match encryption_key {
+                            Some(_) => sealed::stored_refs(&bytes)?,
+                            None => Vec::new(),
+                        };
+                        let stored = match compression {
+                            Compression::None => bytes,
+                            Compression::Zstd(level) => {
+                                zstd::stream::encode_all(&bytes[..], level)?
+                            }
+                        };
+                        let stored = match encryption_key {
+                            Some(ref encryption_key) => encryption_key.seal(hash, refs, &stored)?,
+                            None => stored,
+                        };
+                        await!(ctx.write_full_async(&hash.to_string(), &stored))?;
                         lock.release();
+                        COUNTERS.add_bytes_sent(stored.len() as u64);
+
+                        if let Some(ref negotiation) = negotiation {
+                            negotiation.record(hash, true);
+                        }
+
+                        if let Some((name, shared)) = shared {
+                            shared.insert(&name, hash);
+                        }
+
                         Ok(true)
                     }
                 };
 
-                Box::new(result)
+                let timed = profile::timed(profile::NETWORK, "ceph.write", Some(size), result);
+                Box::new(telemetry::measured(Operation::RemoteWrite, timed))
             }
 
             None => {
@@ -124,19 +388,89 @@ impl Ceph {
         object_hash: ObjectHash,
     ) -> Box<Future<Item = Object, Error = Error> + Send> {
         let local_future = self.local.read_or_allocate_object(object_hash);
-        let ctx_res = self.inner.conn.lock().unwrap().get_pool_context(
-            &self.inner.pool,
-        );
+        let ctxs_res = self.inner
+            .search_order()
+            .into_iter()
+            .map(|pool| self.inner.conn.lock().unwrap().get_pool_context(pool))
+            .collect::<StdResult<Vec<_>, _>>();
+        let shared = self.shared.clone().map(|(_, shared)| shared);
+        let stored_future = if self.transforms() {
+            Some(self.read_stored(object_hash))
+        } else {
+            None
+        };
+        let encryption_key = self.encryption_key.clone();
 
         let result = {
             async_block! {
                 match await!(local_future)? {
-                    Ok(object) => Ok(object),
+                    Ok(object) => {
+                        COUNTERS.add_cache_hit();
+                        Ok(object)
+                    }
                     Err(factory) => {
-                        let mut ctx = ctx_res?;
+                        // Whatever the daemon hands back is only used if it is what was asked for.
+                        let cached = match shared.as_ref().and_then(|s| s.get(object_hash)) {
+                            Some(bytes) => {
+                                let verified = canonical::decode_verified(&bytes, object_hash)
+                                    .is_some();
+                                if verified {
+                                    Some(bytes)
+                                } else {
+                                    None
+                                }
+                            }
+                            None => None,
+                        };
+                        if let Some(bytes) = cached {
+                            COUNTERS.add_cache_hit();
+                            let mut buf = factory.with_size(bytes.len())?;
+                            buf.copy_from_slice(&bytes);
+                            return await!(buf.finish());
+                        }
+
+                        COUNTERS.add_cache_miss();
+
+                        // An object stored sealed or compressed is only its own size once opened
+                        // and decompressed, so it cannot be read straight into the local store.
+                        if let Some(stored_future) = stored_future {
+                            let mut bytes = await!(stored_future)?;
+                            if let Some(ref encryption_key) = encryption_key {
+                                bytes = encryption_key.open_stored(object_hash, bytes)?;
+                            }
+                            let bytes = decompress_stored(bytes)?;
+
+                            // The plaintext of an encrypted remote is kept out of the daemon, which
+                            // serves every process of the user whatever remote they read from.
+                            if encryption_key.is_none() {
+                                if let Some(ref shared) = shared {
+                                    shared.put(object_hash, bytes.clone());
+                                }
+                            }
+
+                            let mut buf = factory.with_size(bytes.len())?;
+                            buf.copy_from_slice(&bytes);
+                            return await!(buf.finish());
+                        }
 
                         let object_id = object_hash.to_string();
-                        let stat = await!(ctx.stat_async(&object_id))?;
+
+                        let mut found = None;
+                        let mut last_error = None;
+                        for mut ctx in ctxs_res? {
+                            let stat_res = await!(ctx.stat_async(&object_id));
+                            match stat_res {
+                                Ok(stat) => {
+                                    found = Some((ctx, stat));
+                                    break;
+                                }
+                                Err(error) => last_error = Some(Error::from(error)),
+                            }
+                        }
+                        let (mut ctx, stat) = match found {
+                            Some(found) => found,
+                            None => return Err(last_error.unwrap()),
+                        };
 
                         let mut buf = OwningRefMut::new(factory.with_size(stat.size as usize)?);
 
@@ -159,17 +493,48 @@ impl Ceph {
                             buf = new_buf;
                         };
 
+                        COUNTERS.add_bytes_fetched(total_read as u64);
+                        if let Some(ref shared) = shared {
+                            shared.put(object_hash, written_buf.to_vec());
+                        }
+
                         await!(written_buf.finish())
                     }
                 }
             }
         };
 
-        Box::new(result)
+        let timed = profile::timed(profile::NETWORK, "ceph.read", None, result);
+        Box::new(telemetry::measured(Operation::RemoteRead, timed))
     }
 }
 
 
+/// What a remote is known by to the shared cache daemon: its user, configuration file, the
+/// configuration options set on top of it, and its pools. Two remotes with the same identity
+/// connect to the same objects, whatever they are named.
+fn identity(remote_config: &CephCfg) -> String {
+    let conf_file = remote_config.conf_file.as_ref().map(|conf_path| {
+        fs::canonicalize(conf_path).unwrap_or_else(|_| conf_path.clone())
+    });
+    let mut conf_options = remote_config
+        .conf_options
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>();
+    conf_options.sort();
+
+    format!(
+        "ceph:{}:{:?}:{}:{}:{}",
+        remote_config.user,
+        conf_file,
+        conf_options.join(","),
+        remote_config.pool,
+        remote_config.metadata_pool.as_ref().map(String::as_str).unwrap_or(""),
+    )
+}
+
+
 impl ObjectStore for Ceph {
     type Read = Box<Future<Item = Object, Error = Error> + Send>;
     type Write = Box<Future<Item = bool, Error = Error> + Send>;
@@ -181,4 +546,39 @@ impl ObjectStore for Ceph {
     fn write_object(&self, hashed: Hashed) -> Self::Write {
         self.write_object(hashed)
     }
+
+    /// An object is known to be on the remote if the catalog or an earlier negotiation says so.
+    /// The shared cache daemon is not asked: what it says is only good enough to skip a single
+    /// write, not to leave an object and everything beneath it out of a transfer.
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        if self.catalog.get(object_hash).is_some() {
+            return true;
+        }
+
+        match self.negotiation {
+            Some(ref negotiation) => negotiation.get(object_hash) == Some(true),
+            None => false,
+        }
+    }
+}
+
+
+impl RangeStore for Ceph {
+    fn read_range(
+        &self,
+        object_hash: ObjectHash,
+        offset: u64,
+        len: u64,
+    ) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send> {
+        self.read_range(object_hash, offset, len)
+    }
+}
+
+
+impl<R: RefStore> TransactionalStore<R> for Ceph {
+    type Transaction = Batch<Ceph, R>;
+
+    fn begin(&self, refs: &R) -> Self::Transaction {
+        Batch::new(self, refs)
+    }
 }
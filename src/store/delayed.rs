@@ -0,0 +1,168 @@
+//! # `delayed` - a store wrapper simulating a slow network, for development.
+//!
+//! `DelayedStore` adds latency, random jitter, and a bandwidth limit to every read and write of
+//! the store it wraps, so that the behavior of push, fetch, and checkout over a WAN can be
+//! examined against a local or LAN store. Reads and writes are limited separately, as though over
+//! the two directions of a single link: transfers in the same direction queue behind each other,
+//! while latency is paid by every operation concurrently.
+//!
+//! Delays are served by sleeping on a dedicated thread pool, so they never hold up the threads
+//! doing real work. A remote is wrapped by giving it a `simulate` table in the repository config.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures_cpupool::CpuPool;
+use rand;
+
+use errors::*;
+use marshal::{Object, ObjectHash, Hashed};
+use store::ObjectStore;
+
+
+/// The number of operations which may be waiting out a delay at once.
+const DELAY_THREADS: usize = 256;
+
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+
+/// Simulated conditions for one direction of traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelayCfg {
+    /// The latency added to every operation, in milliseconds.
+    #[serde(default)]
+    pub latency_ms: u64,
+
+    /// The most random extra latency added to an operation, in milliseconds.
+    #[serde(default)]
+    pub jitter_ms: u64,
+
+    /// The bandwidth shared by every operation, in bytes per second, if limited.
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+}
+
+
+/// Simulated network conditions for a store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimulateCfg {
+    #[serde(default)]
+    pub read: DelayCfg,
+
+    #[serde(default)]
+    pub write: DelayCfg,
+}
+
+
+/// One direction of a simulated link.
+#[derive(Debug)]
+struct Link {
+    cfg: DelayCfg,
+
+    /// When the transfers queued so far will have finished.
+    free_at: Mutex<Instant>,
+}
+
+
+impl Link {
+    fn new(cfg: DelayCfg) -> Self {
+        Link {
+            cfg,
+            free_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Queue a transfer of `bytes` and return how long from now the operation should finish.
+    fn reserve(&self, bytes: u64) -> Duration {
+        let now = Instant::now();
+
+        let transferred = match self.cfg.bandwidth {
+            Some(bandwidth) if bandwidth > 0 => {
+                let mut free_at = self.free_at.lock().unwrap();
+                let nanos = bytes.saturating_mul(NANOS_PER_SEC) / bandwidth;
+                let transfer = Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32);
+                *free_at = cmp::max(*free_at, now) + transfer;
+                *free_at
+            }
+            _ => now,
+        };
+
+        let jitter_ms = if self.cfg.jitter_ms > 0 {
+            rand::random::<u64>() % (self.cfg.jitter_ms + 1)
+        } else {
+            0
+        };
+        let finished = transferred + Duration::from_millis(self.cfg.latency_ms + jitter_ms);
+
+        finished - now
+    }
+}
+
+
+/// A store whose operations are slowed down to simulate a network link. See the module docs.
+#[derive(Clone)]
+pub struct DelayedStore<S: ObjectStore> {
+    inner: S,
+    read: Arc<Link>,
+    write: Arc<Link>,
+    pool: CpuPool,
+}
+
+
+impl<S: ObjectStore> DelayedStore<S> {
+    pub fn new(inner: S, cfg: &SimulateCfg) -> Self {
+        DelayedStore {
+            inner,
+            read: Arc::new(Link::new(cfg.read)),
+            write: Arc::new(Link::new(cfg.write)),
+            pool: CpuPool::new(DELAY_THREADS),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn sleep(&self, duration: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(self.pool.spawn_fn(move || {
+            thread::sleep(duration);
+            Ok(())
+        }))
+    }
+}
+
+
+impl<S: ObjectStore> ObjectStore for DelayedStore<S> {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        let this = self.clone();
+
+        // The size of an object is only known once it has been read, so the delay comes after.
+        Box::new(self.inner.read_object(object_hash).and_then(move |object| {
+            let delay = this.read.reserve(object.encoded_size());
+            this.sleep(delay).map(move |()| object)
+        }))
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        let bytes = hashed.as_bytes().map(|bytes| bytes.len() as u64).unwrap_or(0);
+        let delay = self.write.reserve(bytes);
+        let inner = self.inner.clone();
+
+        Box::new(self.sleep(delay).and_then(move |()| inner.write_object(hashed)))
+    }
+
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.inner.is_known(object_hash)
+    }
+}
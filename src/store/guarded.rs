@@ -0,0 +1,89 @@
+//! # `guarded` - a store wrapper bounding each operation in time, and letting it be cancelled.
+//!
+//! A remote which stops answering would otherwise hold up a push or fetch forever. `GuardedStore`
+//! fails any read or write of the store it wraps which has not finished within its timeout, with
+//! `ErrorKind::TimedOut`, and every operation at all once its `CancelToken` is cancelled, with
+//! `ErrorKind::Cancelled`. A remote is given timeouts by a `timeouts` table in the repository
+//! config; a token is given by whichever operation wants to be able to stop, such as `fetch`.
+
+use std::time::Duration;
+
+use futures::prelude::*;
+
+use cancel::{self, CancelToken};
+use errors::*;
+use marshal::{Object, ObjectHash, Hashed};
+use store::ObjectStore;
+
+
+/// How long each operation on a store may take before it fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeoutCfg {
+    /// The longest a single object read may take, in milliseconds, if bounded.
+    #[serde(default)]
+    pub read_ms: Option<u64>,
+
+    /// The longest a single object write may take, in milliseconds, if bounded.
+    #[serde(default)]
+    pub write_ms: Option<u64>,
+}
+
+
+/// A store whose operations time out, and can be cancelled. See the module docs.
+#[derive(Clone)]
+pub struct GuardedStore<S: ObjectStore> {
+    inner: S,
+    cfg: TimeoutCfg,
+    cancel: Option<CancelToken>,
+}
+
+
+impl<S: ObjectStore> GuardedStore<S> {
+    pub fn new(inner: S, cfg: &TimeoutCfg) -> Self {
+        GuardedStore {
+            inner,
+            cfg: *cfg,
+            cancel: None,
+        }
+    }
+
+    /// Cancel every operation on this store, and its clones, once `cancel` is cancelled.
+    pub fn with_cancel(mut self, cancel: Option<CancelToken>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+
+impl<S: ObjectStore> ObjectStore for GuardedStore<S> {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        Box::new(cancel::guard(
+            self.inner.read_object(object_hash),
+            self.cancel.clone(),
+            self.cfg.read_ms.map(Duration::from_millis),
+        ))
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        Box::new(cancel::guard(
+            self.inner.write_object(hashed),
+            self.cancel.clone(),
+            self.cfg.write_ms.map(Duration::from_millis),
+        ))
+    }
+
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.inner.is_known(object_hash)
+    }
+}
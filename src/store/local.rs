@@ -5,26 +5,37 @@
 
 use std::collections::HashMap;
 use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::sync::{Arc, Mutex};
 
+use futures::future;
 use futures::prelude::*;
 use futures_bufio::BufWriter;
 use futures_cpupool::CpuPool;
 use memmap::{Mmap, Protection};
 use stable_deref_trait::StableDeref;
 
-use arc_slice;
+use arc_slice::{self, ArcSlice};
+use backrefs::Backrefs;
 use catalog::{Catalog, CatalogLock};
 use errors::*;
+use fault;
+use integrity;
 use marshal::{Hashed, ObjectHash, Object};
+use marshal::canonical;
+use profile;
+use refcount::Refcounts;
 use repository::Paths;
-use store::ObjectStore;
+use store::{ObjectStore, Packs, RangeStore, RefcountedStore, RepackableStore, RepackOptions,
+            Repacked, Staging, Statistics, StoreStats};
+use telemetry::{self, Operation};
 
 
 pub struct LocalBufferFactory {
+    backrefs: Option<Backrefs>,
     catalog_lock: CatalogLock,
     object_hash: ObjectHash,
     objects: Arc<Mutex<HashMap<ObjectHash, Object>>>,
@@ -41,6 +52,7 @@ impl LocalBufferFactory {
         let mmap = Mmap::open(&file, Protection::ReadWrite)?;
 
         Ok(LocalBuffer {
+            backrefs: self.backrefs,
             catalog_lock: self.catalog_lock,
             object_hash: self.object_hash,
             objects: self.objects,
@@ -51,6 +63,7 @@ impl LocalBufferFactory {
 
 
 pub struct LocalBuffer {
+    backrefs: Option<Backrefs>,
     catalog_lock: CatalogLock,
     object_hash: ObjectHash,
     objects: Arc<Mutex<HashMap<ObjectHash, Object>>>,
@@ -65,6 +78,9 @@ impl LocalBuffer {
                 self.mmap.flush()?;
                 let slice = arc_slice::mapped(self.mmap);
                 let object = Object::from_bytes(slice)?;
+                if let Some(ref backrefs) = self.backrefs {
+                    backrefs.insert(self.object_hash, &object.refs());
+                }
                 self.objects.lock().unwrap().insert(self.object_hash, object.clone());
                 self.catalog_lock.release();
                 Ok(object)
@@ -104,6 +120,26 @@ pub struct Local {
     io_pool: CpuPool,
     catalog: Catalog,
     objects: Arc<Mutex<HashMap<ObjectHash, Object>>>,
+    sample_percent: f64,
+    backrefs: Option<Backrefs>,
+    refcounts: Option<Refcounts>,
+    staging: Option<Staging>,
+    packs: Packs,
+}
+
+
+/// Read an object from its own file if it has one, and otherwise from a pack.
+fn load(path: &Path, packs: &Packs, object_hash: ObjectHash) -> Result<ArcSlice> {
+    match Mmap::open_path(path, Protection::Read) {
+        Ok(mmap) => return Ok(arc_slice::mapped(mmap)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).chain_err(|| ErrorKind::OpenLocalObject(object_hash)),
+    }
+
+    match packs.read(object_hash).chain_err(|| ErrorKind::OpenLocalObject(object_hash))? {
+        Some(bytes) => Ok(arc_slice::owned(bytes)),
+        None => bail!(ErrorKind::ObjectMissing(object_hash)),
+    }
 }
 
 
@@ -114,9 +150,41 @@ impl Local {
             io_pool: io_pool.clone(),
             catalog: catalog.clone(),
             objects: Arc::new(Mutex::new(HashMap::new())),
+            sample_percent: 0.0,
+            backrefs: None,
+            refcounts: None,
+            staging: None,
+            packs: Packs::new(paths.packs.clone()),
         }
     }
 
+    /// Verify the hashes of the given percentage of objects read from disk, logging any which
+    /// are corrupt. See the `integrity` module.
+    pub fn with_integrity_sampling(self, sample_percent: f64) -> Self {
+        Self {
+            sample_percent,
+            ..self
+        }
+    }
+
+    /// Record the references of every object written in the given reverse reference index. See
+    /// the `backrefs` module.
+    pub fn with_backrefs(self, backrefs: Option<Backrefs>) -> Self {
+        Self { backrefs, ..self }
+    }
+
+    /// Count the references of every object written, and release them as objects are deleted,
+    /// in the given reference counts. See the `refcount` module.
+    pub fn with_refcounts(self, refcounts: Option<Refcounts>) -> Self {
+        Self { refcounts, ..self }
+    }
+
+    /// Write new objects into the given staging area rather than the blob directory. See the
+    /// `staging` module.
+    pub fn with_staging(self, staging: Option<Staging>) -> Self {
+        Self { staging, ..self }
+    }
+
     /// Write an object to the file system. Assuming the file has not yet been written, this will
     /// open and then close a file, and the resulting future will return `true` if the object has
     /// not been written and `false` if the object already exists in the catalog and no I/O was
@@ -126,11 +194,29 @@ impl Local {
             Ok(lock) => {
                 match hashed.into_components() {
                     (hash, Some(bytes)) => {
-                        let path = self.paths.blobs.join(hash.to_path());
+                        let path = match self.staging {
+                            Some(ref staging) => staging.stage(hash),
+                            None => self.paths.blobs.join(hash.to_path()),
+                        };
                         let io_pool = self.io_pool.clone();
+                        let backrefs = self.backrefs.clone();
+                        let refcounts = self.refcounts.clone();
+                        let size = bytes.len() as u64;
 
                         let result = {
                             async_block! {
+                                if let Some(backrefs) = backrefs {
+                                    backrefs.record(hash, &bytes)?;
+                                }
+                                // Counted only once the file is written, so that discarding a
+                                // staged object releases exactly what was counted.
+                                let counted = match refcounts {
+                                    Some(refcounts) => {
+                                        Some((refcounts, canonical::decode(&bytes)?.1.refs()))
+                                    }
+                                    None => None,
+                                };
+
                                 fs::create_dir_all(path.parent().unwrap())?;
                                 let file = File::create(path)?;
                                 let bufwriter =
@@ -143,6 +229,11 @@ impl Local {
 
                                 let bufwriter = await!(bufwriter.flush_buf()).map_err(|(_, err)| err)?;
                                 await!(bufwriter.flush_inner()).map_err(|(_, err)| err)?;
+                                fault::point("local.write-object");
+
+                                if let Some((refcounts, refs)) = counted {
+                                    refcounts.written(hash, refs);
+                                }
 
                                 lock.release();
 
@@ -150,7 +241,9 @@ impl Local {
                             }
                         };
 
-                        return Box::new(result);
+                        let timed =
+                            profile::timed(profile::DISK, "local.write", Some(size), result);
+                        return Box::new(telemetry::measured(Operation::LocalWrite, timed));
                     }
 
                     (_, None) => {
@@ -158,10 +251,61 @@ impl Local {
                     }
                 }
             }
-            Err(entry) => Box::new(entry.map(|_| false)),
+            // Reusing an object the catalog lists, unless another process has since collected it.
+            Err(entry) => {
+                match self.reuse(*hashed.as_hash()) {
+                    Ok(true) => Box::new(entry.map(|_| false)),
+                    Ok(false) => self.write_object(hashed),
+                    Err(err) => Box::new(future::err(err)),
+                }
+            }
+        }
+    }
+
+    /// Whether an object the catalog lists may be reused, restarting its grace period if nothing
+    /// refers to it yet. One which another process has collected since is forgotten, so that it is
+    /// written again. See `Refcounts::reused`.
+    fn reuse(&self, object_hash: ObjectHash) -> Result<bool> {
+        match self.refcounts {
+            Some(ref refcounts) if !refcounts.reused(object_hash)? => {
+                self.catalog.remove(object_hash);
+                self.objects.lock().unwrap().remove(&object_hash);
+                Ok(false)
+            }
+            _ => Ok(true),
         }
     }
 
+    /// The file an object is stored in, whether it is staged or not.
+    fn path_of(&self, object_hash: ObjectHash) -> PathBuf {
+        self.staging
+            .as_ref()
+            .and_then(|staging| staging.path_of(object_hash))
+            .unwrap_or_else(|| self.paths.blobs.join(object_hash.to_path()))
+    }
+
+    /// Read the exact bytes an object is stored as, without decoding them.
+    pub fn read_raw(
+        &self,
+        object_hash: ObjectHash,
+    ) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let path = self.path_of(object_hash);
+        let packs = self.packs.clone();
+        let entry_opt = self.catalog.get(object_hash);
+
+        let result = {
+            async_block! {
+                if let Some(entry) = entry_opt {
+                    await!(entry)?;
+                }
+
+                Ok(load(&path, &packs, object_hash)?.to_vec())
+            }
+        };
+
+        Box::new(self.io_pool.spawn(result))
+    }
+
     /// Load an object from the file system. This will open a file if the object has not already
     /// been loaded.
     /// TODO: Make async.
@@ -169,9 +313,12 @@ impl Local {
         &self,
         object_hash: ObjectHash,
     ) -> Box<Future<Item = Object, Error = Error> + Send> {
-        let path = self.paths.blobs.join(object_hash.to_path());
+        let path = self.path_of(object_hash);
+        let paths = self.paths.clone();
+        let packs = self.packs.clone();
         let objects = self.objects.clone();
         let entry_opt = self.catalog.get(object_hash);
+        let sample = integrity::should_sample(self.sample_percent);
 
         let result = {
             async_block! {
@@ -183,16 +330,22 @@ impl Local {
                     return Ok(local.clone());
                 }
 
-                let bytes = arc_slice::mapped(Mmap::open_path(path, Protection::Read).chain_err(|| ErrorKind::OpenLocalObject(object_hash))?);
+                let bytes = load(&path, &packs, object_hash)?;
                 let object = Object::from_bytes(bytes)?;
 
+                if sample {
+                    integrity::verify(&paths, object_hash, &object)?;
+                }
+
                 objects.lock().unwrap().insert(object_hash, object.clone());
 
                 Ok(object)
             }
         };
 
-        return Box::new(self.io_pool.spawn(result));
+        let spawned = self.io_pool.spawn(result);
+        let timed = profile::timed(profile::DISK, "local.read", None, spawned);
+        return Box::new(telemetry::measured(Operation::LocalRead, timed));
     }
 
     /// Load an object from the file system, *or*, create a new buffer for writing an object. This
@@ -209,10 +362,12 @@ impl Local {
             Ok(lock) => {
                 let path = self.paths.blobs.join(object_hash.to_path());
                 let objects = self.objects.clone();
+                let backrefs = self.backrefs.clone();
 
                 let result = {
                     async_block! {
                         let local_buffer_factory = LocalBufferFactory {
+                            backrefs,
                             catalog_lock: lock,
                             object_hash,
                             objects,
@@ -233,6 +388,35 @@ impl Local {
         }
     }
 
+    /// Move an object out of the store and into the quarantine directory, where it is kept but
+    /// never read, so that a good copy can take its place. A packed object is copied out of its
+    /// pack if it can be read at all, and listed as deleted from it. See the `quarantine` module.
+    pub fn quarantine(&self, object_hash: ObjectHash) -> Result<()> {
+        let _lock = self.packs.lock()?;
+
+        fs::create_dir_all(&self.paths.quarantine)?;
+        let destination = self.paths.quarantine.join(object_hash.to_string());
+        let path = self.paths.blobs.join(object_hash.to_path());
+
+        match fs::rename(&path, &destination) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                if self.packs.hashes()?.contains(&object_hash) {
+                    if let Ok(Some(bytes)) = self.packs.read(object_hash) {
+                        File::create(&destination)?.write_all(&bytes)?;
+                    }
+                    self.packs.mark_deleted(object_hash)?;
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        self.catalog.remove(object_hash);
+        self.objects.lock().unwrap().remove(&object_hash);
+
+        Ok(())
+    }
+
     //     /// Write a fully marshalled batch to the local repository.
     //     pub fn write_batch<T: Trace>(
     //         &mut self,
@@ -277,4 +461,125 @@ impl ObjectStore for Local {
     fn write_object(&self, hashed: Hashed) -> Self::Write {
         self.write_object(hashed)
     }
+
+    /// Every object written to the store, or being written to it, is in the catalog. Should its
+    /// reuse fail to be recorded, it is written instead, which reports the error.
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.catalog.get(object_hash).is_some() && self.reuse(object_hash).unwrap_or(false)
+    }
+}
+
+
+impl RefcountedStore for Local {
+    fn refcounts(&self) -> Option<&Refcounts> {
+        self.refcounts.as_ref()
+    }
+
+    /// Remove an object's file from the blob directory, or list it as deleted from its pack,
+    /// along with any trace of it in the catalog and the reverse reference index.
+    fn delete_object(&self, object_hash: ObjectHash) -> Result<()> {
+        // Hold off any repack, which might otherwise pack the object just before it is deleted.
+        let _lock = self.packs.lock()?;
+
+        let path = self.paths.blobs.join(object_hash.to_path());
+        let refs = match File::open(&path) {
+            Ok(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                canonical::decode(&bytes)?.1.refs()
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                match self.packs.read(object_hash)? {
+                    Some(bytes) => {
+                        self.packs.mark_deleted(object_hash)?;
+                        canonical::decode(&bytes)?.1.refs()
+                    }
+                    None => Vec::new(),
+                }
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Err(err) = fs::remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err).chain_err(|| ErrorKind::DeleteObject(object_hash));
+            }
+        }
+
+        self.catalog.remove(object_hash);
+        self.objects.lock().unwrap().remove(&object_hash);
+        if let Some(ref backrefs) = self.backrefs {
+            backrefs.remove(object_hash, &refs);
+        }
+        if let Some(ref refcounts) = self.refcounts {
+            refcounts.deleted(object_hash, refs);
+        }
+
+        Ok(())
+    }
+}
+
+
+impl RangeStore for Local {
+    /// Local objects are mapped into memory rather than read, so reading one whole costs no more
+    /// than reading part of it.
+    fn read_range(
+        &self,
+        _object_hash: ObjectHash,
+        _offset: u64,
+        _len: u64,
+    ) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send> {
+        Box::new(future::ok(None))
+    }
+}
+
+
+impl RepackableStore for Local {
+    /// Move every object in the blob directory and the existing packs into a single new pack. Every
+    /// packed object is then listed in the catalog, in case it was cleared.
+    fn repack(&self, options: &RepackOptions) -> Result<Repacked> {
+        let repacked = self.packs.repack(&self.paths.blobs, options)?;
+
+        for hash in self.packs.hashes()? {
+            if let Ok(lock) = self.catalog.try_lock(hash) {
+                lock.release();
+            }
+        }
+
+        Ok(repacked)
+    }
+}
+
+
+impl Statistics for Local {
+    /// Count the objects in the blob directory and the packs. Loose objects are each stored in
+    /// their own file, so this is a walk over the blob directory tree.
+    fn stats(&self) -> Result<StoreStats> {
+        fn walk(path: &Path, stats: &mut StoreStats) -> Result<()> {
+            for entry_res in fs::read_dir(path)? {
+                let entry = entry_res?;
+                let metadata = entry.metadata()?;
+
+                if metadata.is_dir() {
+                    walk(&entry.path(), stats)?;
+                } else {
+                    stats.objects += 1;
+                    stats.stored_bytes += metadata.len();
+                }
+            }
+
+            Ok(())
+        }
+
+        let mut stats = StoreStats::default();
+        if self.paths.blobs.exists() {
+            walk(&self.paths.blobs, &mut stats)?;
+        }
+
+        let (packed_objects, packed_bytes) = self.packs.stats()?;
+        stats.objects += packed_objects;
+        stats.stored_bytes += packed_bytes;
+
+        Ok(stats)
+    }
 }
@@ -1,15 +1,58 @@
 use futures::prelude::*;
+use zstd;
 
 use errors::*;
 use marshal::{ObjectHash, Hashed, Object};
+use refcount::Refcounts;
 
+#[cfg(feature = "rados")]
 mod ceph;
+mod cached;
+mod delayed;
 mod empty;
+mod guarded;
 mod local;
+mod pack;
+mod prefetch;
+mod replicated;
+mod retry;
+mod staging;
+mod stats;
+mod throttled;
+mod transaction;
 
+#[cfg(feature = "rados")]
 pub use self::ceph::Ceph;
+pub use self::cached::{CacheDir, CachedStore, EvictableStore};
+pub use self::delayed::{DelayedStore, DelayCfg, SimulateCfg};
 pub use self::empty::Empty;
+pub use self::guarded::{GuardedStore, TimeoutCfg};
 pub use self::local::Local;
+pub use self::pack::{Packs, RepackOptions, Repacked};
+pub use self::prefetch::Prefetch;
+pub use self::replicated::{ReplicatedStore, WritePolicy};
+pub use self::retry::{RetryPolicy, RetryStore, is_transient, retry};
+pub use self::staging::{Abandoned, Staging};
+pub use self::stats::{Statistics, StoreStats, BranchStats, PathUsage, CommitDedup, DedupStats,
+                      branch_stats, path_usage, dedup_stats};
+pub use self::throttled::ThrottledStore;
+pub use self::transaction::{Batch, BranchUpdate, StoreTransaction, TransactionalStore,
+                            update_branches};
+
+
+/// The first bytes of every zstd frame. No encoded object begins with them (see
+/// `marshal::canonical`), so compressed and uncompressed objects can be told apart.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+
+/// Undo whatever compression was applied to an object stored on a remote, once it is unsealed.
+pub fn decompress_stored(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        Ok(zstd::stream::decode_all(&bytes[..])?)
+    } else {
+        Ok(bytes)
+    }
+}
 
 
 pub trait RefStore: Send + Sync + Clone + 'static {
@@ -27,11 +70,59 @@ pub trait ObjectStore: Send + Sync + Clone + 'static {
 
     fn read_object(&self, object_hash: ObjectHash) -> Self::Read;
     fn write_object(&self, hashed: Hashed) -> Self::Write;
+
+    /// Whether the store is known to contain an object already, from what it has on hand and
+    /// without asking anyone. Marshallers check this before encoding an object, so that objects
+    /// which would not be written anyway are not encoded at all. By default nothing is known.
+    fn is_known(&self, _object_hash: ObjectHash) -> bool {
+        false
+    }
+}
+
+
+/// A store which counts the references to each of its objects as they are written and deleted, so
+/// that its garbage can be collected incrementally. See the `refcount` module.
+pub trait RefcountedStore: ObjectStore {
+    /// The store's reference counts, if it keeps them.
+    fn refcounts(&self) -> Option<&Refcounts>;
+
+    /// Delete an object, releasing the references it makes. Deleting an object which is not
+    /// present is not an error.
+    fn delete_object(&self, object_hash: ObjectHash) -> Result<()>;
+}
+
+
+/// A store which can rewrite its objects into packs, compressed and delta-encoded against each
+/// other. See the `pack` module.
+pub trait RepackableStore: ObjectStore {
+    fn repack(&self, options: &RepackOptions) -> Result<Repacked>;
+}
+
+
+/// A store which can read part of the chunk of a small object without fetching the whole object.
+/// See `lazy::ChunkedReader`.
+pub trait RangeStore: ObjectStore {
+    /// Read `len` bytes of the chunk of the small object `object_hash`, starting at `offset`, or
+    /// fewer if the chunk ends first. Returns `None` if the object is not a small object, or if
+    /// the store cannot read it in part, in which case it should be read whole.
+    ///
+    /// Since the object is not read whole, it cannot be checked against its hash.
+    fn read_range(
+        &self,
+        object_hash: ObjectHash,
+        offset: u64,
+        len: u64,
+    ) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send>;
 }
 
 
 pub enum RemoteRead {
+    #[cfg(feature = "rados")]
     Ceph(<Ceph as ObjectStore>::Read),
+    Delayed(<DelayedStore<Remote> as ObjectStore>::Read),
+    Guarded(<GuardedStore<Remote> as ObjectStore>::Read),
+    Replicated(<ReplicatedStore<Remote> as ObjectStore>::Read),
+    Retrying(<RetryStore<Remote> as ObjectStore>::Read),
 }
 
 
@@ -41,14 +132,24 @@ impl Future for RemoteRead {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match *self {
+            #[cfg(feature = "rados")]
             RemoteRead::Ceph(ref mut ceph) => ceph.poll(),
+            RemoteRead::Delayed(ref mut delayed) => delayed.poll(),
+            RemoteRead::Guarded(ref mut guarded) => guarded.poll(),
+            RemoteRead::Replicated(ref mut replicated) => replicated.poll(),
+            RemoteRead::Retrying(ref mut retrying) => retrying.poll(),
         }
     }
 }
 
 
 pub enum RemoteWrite {
+    #[cfg(feature = "rados")]
     Ceph(<Ceph as ObjectStore>::Write),
+    Delayed(<DelayedStore<Remote> as ObjectStore>::Write),
+    Guarded(<GuardedStore<Remote> as ObjectStore>::Write),
+    Replicated(<ReplicatedStore<Remote> as ObjectStore>::Write),
+    Retrying(<RetryStore<Remote> as ObjectStore>::Write),
 }
 
 
@@ -58,7 +159,12 @@ impl Future for RemoteWrite {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match *self {
+            #[cfg(feature = "rados")]
             RemoteWrite::Ceph(ref mut ceph) => ceph.poll(),
+            RemoteWrite::Delayed(ref mut delayed) => delayed.poll(),
+            RemoteWrite::Guarded(ref mut guarded) => guarded.poll(),
+            RemoteWrite::Replicated(ref mut replicated) => replicated.poll(),
+            RemoteWrite::Retrying(ref mut retrying) => retrying.poll(),
         }
     }
 }
@@ -66,7 +172,38 @@ impl Future for RemoteWrite {
 
 #[derive(Clone)]
 pub enum Remote {
+    #[cfg(feature = "rados")]
     Ceph(Ceph),
+
+    /// A remote behind simulated network conditions. See `DelayedStore`.
+    Delayed(Box<DelayedStore<Remote>>),
+
+    /// A remote whose operations time out, and can be cancelled. See `GuardedStore`.
+    Guarded(Box<GuardedStore<Remote>>),
+
+    /// A remote whose objects are written to several stores. See `ReplicatedStore`.
+    Replicated(Box<ReplicatedStore<Remote>>),
+
+    /// A remote whose operations are retried when they fail transiently. See `RetryStore`.
+    Retrying(Box<RetryStore<Remote>>),
+}
+
+
+impl Remote {
+    /// Read an object exactly as the remote stores it, without any key. See `Ceph::read_stored`.
+    pub fn read_stored(
+        &self,
+        object_hash: ObjectHash,
+    ) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        match *self {
+            #[cfg(feature = "rados")]
+            Remote::Ceph(ref ceph) => ceph.read_stored(object_hash),
+            Remote::Delayed(ref delayed) => delayed.inner().read_stored(object_hash),
+            Remote::Guarded(ref guarded) => guarded.inner().read_stored(object_hash),
+            Remote::Replicated(ref replicated) => replicated.stores()[0].read_stored(object_hash),
+            Remote::Retrying(ref retrying) => retrying.inner().read_stored(object_hash),
+        }
+    }
 }
 
 
@@ -76,13 +213,141 @@ impl ObjectStore for Remote {
 
     fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
         match *self {
+            #[cfg(feature = "rados")]
             Remote::Ceph(ref ceph) => RemoteRead::Ceph(ceph.read_object(object_hash)),
+            Remote::Delayed(ref delayed) => RemoteRead::Delayed(delayed.read_object(object_hash)),
+            Remote::Guarded(ref guarded) => RemoteRead::Guarded(guarded.read_object(object_hash)),
+            Remote::Replicated(ref replicated) => {
+                RemoteRead::Replicated(replicated.read_object(object_hash))
+            }
+            Remote::Retrying(ref retrying) => {
+                RemoteRead::Retrying(retrying.read_object(object_hash))
+            }
         }
     }
 
     fn write_object(&self, hashed: Hashed) -> Self::Write {
         match *self {
+            #[cfg(feature = "rados")]
             Remote::Ceph(ref ceph) => RemoteWrite::Ceph(ceph.write_object(hashed)),
+            Remote::Delayed(ref delayed) => RemoteWrite::Delayed(delayed.write_object(hashed)),
+            Remote::Guarded(ref guarded) => RemoteWrite::Guarded(guarded.write_object(hashed)),
+            Remote::Replicated(ref replicated) => {
+                RemoteWrite::Replicated(replicated.write_object(hashed))
+            }
+            Remote::Retrying(ref retrying) => RemoteWrite::Retrying(retrying.write_object(hashed)),
+        }
+    }
+
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        match *self {
+            #[cfg(feature = "rados")]
+            Remote::Ceph(ref ceph) => ceph.is_known(object_hash),
+            Remote::Delayed(ref delayed) => delayed.is_known(object_hash),
+            Remote::Guarded(ref guarded) => guarded.is_known(object_hash),
+            Remote::Replicated(ref replicated) => replicated.is_known(object_hash),
+            Remote::Retrying(ref retrying) => retrying.is_known(object_hash),
+        }
+    }
+}
+
+
+impl RangeStore for Remote {
+    fn read_range(
+        &self,
+        object_hash: ObjectHash,
+        offset: u64,
+        len: u64,
+    ) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send> {
+        match *self {
+            #[cfg(feature = "rados")]
+            Remote::Ceph(ref ceph) => ceph.read_range(object_hash, offset, len),
+            Remote::Delayed(ref delayed) => delayed.inner().read_range(object_hash, offset, len),
+            Remote::Guarded(ref guarded) => guarded.inner().read_range(object_hash, offset, len),
+            Remote::Replicated(ref replicated) => {
+                replicated.stores()[0].read_range(object_hash, offset, len)
+            }
+            Remote::Retrying(ref retrying) => retrying.inner().read_range(object_hash, offset, len),
+        }
+    }
+}
+
+
+pub enum RemoteTransaction<R: RefStore> {
+    #[cfg(feature = "rados")]
+    Ceph(<Ceph as TransactionalStore<R>>::Transaction),
+    Delayed(Batch<DelayedStore<Remote>, R>),
+    Guarded(Batch<GuardedStore<Remote>, R>),
+    Replicated(Batch<ReplicatedStore<Remote>, R>),
+    Retrying(Batch<RetryStore<Remote>, R>),
+}
+
+
+impl<R: RefStore> StoreTransaction for RemoteTransaction<R> {
+    fn write_object(&mut self, hashed: Hashed) {
+        match *self {
+            #[cfg(feature = "rados")]
+            RemoteTransaction::Ceph(ref mut ceph) => ceph.write_object(hashed),
+            RemoteTransaction::Delayed(ref mut delayed) => delayed.write_object(hashed),
+            RemoteTransaction::Guarded(ref mut guarded) => guarded.write_object(hashed),
+            RemoteTransaction::Replicated(ref mut replicated) => replicated.write_object(hashed),
+            RemoteTransaction::Retrying(ref mut retrying) => retrying.write_object(hashed),
+        }
+    }
+
+    fn update_branch(&mut self, branch: String, prev_hash: ObjectHash, new_hash: ObjectHash) {
+        match *self {
+            #[cfg(feature = "rados")]
+            RemoteTransaction::Ceph(ref mut ceph) => {
+                ceph.update_branch(branch, prev_hash, new_hash)
+            }
+            RemoteTransaction::Delayed(ref mut delayed) => {
+                delayed.update_branch(branch, prev_hash, new_hash)
+            }
+            RemoteTransaction::Guarded(ref mut guarded) => {
+                guarded.update_branch(branch, prev_hash, new_hash)
+            }
+            RemoteTransaction::Replicated(ref mut replicated) => {
+                replicated.update_branch(branch, prev_hash, new_hash)
+            }
+            RemoteTransaction::Retrying(ref mut retrying) => {
+                retrying.update_branch(branch, prev_hash, new_hash)
+            }
+        }
+    }
+
+    fn commit(self) -> Box<Future<Item = u64, Error = Error> + Send> {
+        match self {
+            #[cfg(feature = "rados")]
+            RemoteTransaction::Ceph(ceph) => ceph.commit(),
+            RemoteTransaction::Delayed(delayed) => delayed.commit(),
+            RemoteTransaction::Guarded(guarded) => guarded.commit(),
+            RemoteTransaction::Replicated(replicated) => replicated.commit(),
+            RemoteTransaction::Retrying(retrying) => retrying.commit(),
+        }
+    }
+}
+
+
+impl<R: RefStore> TransactionalStore<R> for Remote {
+    type Transaction = RemoteTransaction<R>;
+
+    fn begin(&self, refs: &R) -> Self::Transaction {
+        match *self {
+            #[cfg(feature = "rados")]
+            Remote::Ceph(ref ceph) => RemoteTransaction::Ceph(ceph.begin(refs)),
+            Remote::Delayed(ref delayed) => {
+                RemoteTransaction::Delayed(Batch::new(&**delayed, refs))
+            }
+            Remote::Guarded(ref guarded) => {
+                RemoteTransaction::Guarded(Batch::new(&**guarded, refs))
+            }
+            Remote::Replicated(ref replicated) => {
+                RemoteTransaction::Replicated(Batch::new(&**replicated, refs))
+            }
+            Remote::Retrying(ref retrying) => {
+                RemoteTransaction::Retrying(Batch::new(&**retrying, refs))
+            }
         }
     }
 }
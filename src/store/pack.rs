@@ -0,0 +1,730 @@
+//! # `pack` - many objects in one file, compressed and delta-encoded against each other.
+//!
+//! The local store writes every object to a file of its own under `.attaca/blobs`. That is simple
+//! and quick to write to, but wasteful once there are millions of small objects, and similar
+//! objects, such as successive versions of a large subtree, are each stored in full. Repacking
+//! moves every object into a single pack in `.attaca/packs`, made of two files:
+//!
+//! * `<id>.pack` - the magic bytes `ATTACAPK` and a version byte, followed by one zstd frame per
+//!   object. A frame holds either the object's bytes, or a delta against another object of the
+//!   same kind in the same pack: a sequence of instructions to copy a range of the base object or
+//!   to insert new bytes.
+//! * `<id>.idx` - the offset and length of each object's frame, and the object it is a delta
+//!   against if any, sorted by hash. A pack is only read once its index exists, so the index is
+//!   written last and removed first.
+//!
+//! Loose objects, which have files of their own, are always looked up first, and packs only when
+//! there is no such file. Objects cannot be removed from a pack in place; deleting a packed object
+//! lists it in `.attaca/packs/deleted`, and the next repack leaves it out.
+//!
+//! Repacking and deleting take `.attaca/packs/lock`. Other processes may still have the old packs
+//! loaded when a repack removes them; reads which miss reload the packs before giving up.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bincode;
+use rand;
+use seahash;
+use zstd;
+
+use LOCK_TIMEOUT_MS;
+use errors::*;
+use lock::LockFile;
+use marshal::{ObjectHash, RawObject};
+use marshal::canonical;
+
+
+/// The first bytes of every pack file.
+const MAGIC: &[u8] = b"ATTACAPK";
+
+
+/// The version of the pack format, written after the magic bytes.
+const VERSION: u8 = 1;
+
+
+/// The name of the list of deleted packed objects within the pack directory.
+const DELETED: &str = "deleted";
+
+
+/// The name of the lock within the pack directory.
+const LOCK: &str = "lock";
+
+
+/// The size of the blocks of a base object which a delta may copy from. Matches are found a block
+/// at a time, and then extended in both directions as far as they go.
+const BLOCK_SIZE: usize = 16;
+
+
+/// Delta instruction: copy `len` bytes from `offset` in the base.
+const COPY: u8 = 0;
+
+
+/// Delta instruction: insert the `len` bytes which follow.
+const INSERT: u8 = 1;
+
+
+/// Options for repacking.
+#[derive(Debug, Clone, Copy)]
+pub struct RepackOptions {
+    /// How many of the objects before it, in order of kind and size, each object is tried as a
+    /// delta against.
+    pub window: usize,
+
+    /// The most deltas which may need to be applied to read any one object.
+    pub max_depth: usize,
+
+    /// The zstd compression level.
+    pub level: i32,
+}
+
+
+impl Default for RepackOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            max_depth: 16,
+            level: 3,
+        }
+    }
+}
+
+
+/// What a repack did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Repacked {
+    /// The number of objects in the new pack.
+    pub objects: u64,
+
+    /// The number of those stored as deltas.
+    pub deltas: u64,
+
+    /// The number of loose objects removed, now that they are packed.
+    pub loose_removed: u64,
+
+    /// The number of old packs removed.
+    pub packs_removed: u64,
+
+    /// The space the repacked objects took up before.
+    pub bytes_before: u64,
+
+    /// The space they take up now.
+    pub bytes_after: u64,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    hash: ObjectHash,
+    offset: u64,
+    len: u64,
+    base: Option<ObjectHash>,
+}
+
+
+#[derive(Debug)]
+struct Pack {
+    path: PathBuf,
+    index_path: PathBuf,
+    entries: Vec<IndexEntry>,
+}
+
+
+impl Pack {
+    fn open(index_path: PathBuf) -> Result<Self> {
+        let path = index_path.with_extension("pack");
+
+        let mut header = [0; 9];
+        File::open(&path)?.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != VERSION {
+            bail!(ErrorKind::MalformedPack(path, "unknown header".to_owned()));
+        }
+
+        let entries = bincode::deserialize_from(
+            &mut BufReader::new(File::open(&index_path)?),
+            bincode::Infinite,
+        )?;
+
+        Ok(Pack {
+            path,
+            index_path,
+            entries,
+        })
+    }
+
+    fn find(&self, hash: &ObjectHash) -> Option<&IndexEntry> {
+        self.entries
+            .binary_search_by(|entry| entry.hash.cmp(hash))
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    fn malformed(&self, reason: String) -> Error {
+        Error::from_kind(ErrorKind::MalformedPack(self.path.clone(), reason))
+    }
+
+    /// Read an object's frame, and then those of the chain of objects it is a delta against, and
+    /// apply the deltas from the end of the chain back.
+    fn read(&self, file: &mut File, hash: ObjectHash) -> Result<Vec<u8>> {
+        let mut chain = Vec::new();
+        let mut next = Some(hash);
+
+        while let Some(link) = next {
+            let entry = match self.find(&link) {
+                Some(entry) => entry,
+                None => return Err(self.malformed(format!("the delta base {} is missing", link))),
+            };
+            if chain.len() > self.entries.len() {
+                return Err(self.malformed(format!("the deltas of {} form a cycle", hash)));
+            }
+
+            chain.push(entry);
+            next = entry.base;
+        }
+
+        let mut bytes = self.frame(file, chain.pop().unwrap())?;
+        while let Some(entry) = chain.pop() {
+            let delta = self.frame(file, entry)?;
+            bytes = match apply_delta(&bytes, &delta) {
+                Some(bytes) => bytes,
+                None => {
+                    return Err(self.malformed(format!("the delta of {} is corrupt", entry.hash)))
+                }
+            };
+        }
+
+        Ok(bytes)
+    }
+
+    fn frame(&self, file: &mut File, entry: &IndexEntry) -> Result<Vec<u8>> {
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut compressed = vec![0; entry.len as usize];
+        file.read_exact(&mut compressed)?;
+
+        Ok(zstd::stream::decode_all(&compressed[..])?)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(fs::metadata(&self.path)?.len() + fs::metadata(&self.index_path)?.len())
+    }
+
+    /// Remove the pack, index first.
+    fn remove(&self) -> Result<()> {
+        fs::remove_file(&self.index_path)?;
+        fs::remove_file(&self.path)?;
+
+        Ok(())
+    }
+}
+
+
+#[derive(Debug, Default)]
+struct Loaded {
+    packs: Vec<Pack>,
+    deleted: HashSet<ObjectHash>,
+}
+
+
+impl Loaded {
+    fn load(dir: &Path) -> Result<Self> {
+        let mut loaded = Loaded::default();
+        if !dir.exists() {
+            return Ok(loaded);
+        }
+
+        for entry_res in fs::read_dir(dir)? {
+            let path = entry_res?.path();
+            if path.extension().map_or(false, |extension| extension == "idx") {
+                loaded.packs.push(Pack::open(path)?);
+            }
+        }
+
+        let deleted_path = dir.join(DELETED);
+        if deleted_path.exists() {
+            for line_res in BufReader::new(File::open(&deleted_path)?).lines() {
+                let line = line_res?;
+                if !line.trim().is_empty() {
+                    loaded.deleted.insert(line.trim().parse()?);
+                }
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    fn find(&self, hash: &ObjectHash) -> Option<&Pack> {
+        if self.deleted.contains(hash) {
+            return None;
+        }
+
+        self.packs.iter().find(|pack| pack.find(hash).is_some())
+    }
+
+    fn read(&self, hash: ObjectHash) -> Result<Option<Vec<u8>>> {
+        match self.find(&hash) {
+            Some(pack) => pack.read(&mut File::open(&pack.path)?, hash).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+
+/// Where an object to be repacked is read from.
+enum Source {
+    Loose(PathBuf),
+    Packed,
+}
+
+
+/// The packs of a local store, loaded when first needed.
+#[derive(Debug, Clone)]
+pub struct Packs {
+    dir: PathBuf,
+    loaded: Arc<Mutex<Option<Arc<Loaded>>>>,
+}
+
+
+impl Packs {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            loaded: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn loaded(&self, reload: bool) -> Result<Arc<Loaded>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if reload || loaded.is_none() {
+            *loaded = Some(Arc::new(Loaded::load(&self.dir)?));
+        }
+
+        Ok(loaded.as_ref().unwrap().clone())
+    }
+
+    /// Take the lock which repacking and deleting packed objects hold.
+    pub fn lock(&self) -> Result<LockFile> {
+        fs::create_dir_all(&self.dir)?;
+        LockFile::acquire_timeout(self.dir.join(LOCK), Duration::from_millis(LOCK_TIMEOUT_MS))
+    }
+
+    /// Read a packed object, or return `None` if no pack holds it.
+    pub fn read(&self, hash: ObjectHash) -> Result<Option<Vec<u8>>> {
+        match self.loaded(false)?.read(hash) {
+            Ok(Some(bytes)) => Ok(Some(bytes)),
+            _ => self.loaded(true)?.read(hash),
+        }
+    }
+
+    /// The hash of every packed object which has not been deleted.
+    pub fn hashes(&self) -> Result<Vec<ObjectHash>> {
+        let loaded = self.loaded(true)?;
+        let mut hashes = loaded
+            .packs
+            .iter()
+            .flat_map(|pack| pack.entries.iter().map(|entry| entry.hash))
+            .filter(|hash| !loaded.deleted.contains(hash))
+            .collect::<Vec<_>>();
+        hashes.sort();
+        hashes.dedup();
+
+        Ok(hashes)
+    }
+
+    /// Call `f` with every packed object which has not been deleted.
+    pub fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(ObjectHash, &[u8]) -> Result<()>,
+    {
+        let loaded = self.loaded(true)?;
+        for hash in self.hashes()? {
+            if let Some(bytes) = loaded.read(hash)? {
+                f(hash, &bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The number of packed objects which have not been deleted, and the space the packs take up.
+    pub fn stats(&self) -> Result<(u64, u64)> {
+        let objects = self.hashes()?.len() as u64;
+        let mut bytes = 0;
+        for pack in &self.loaded(false)?.packs {
+            bytes += pack.size()?;
+        }
+
+        Ok((objects, bytes))
+    }
+
+    /// List a packed object as deleted, so that it is no longer read and the next repack leaves it
+    /// out. The caller must hold the lock.
+    pub fn mark_deleted(&self, hash: ObjectHash) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(
+            self.dir.join(DELETED),
+        )?;
+        writeln!(file, "{}", hash)?;
+        *self.loaded.lock().unwrap() = None;
+
+        Ok(())
+    }
+
+    /// Move every object, loose in the blob directory `blobs` or packed, into a single new pack,
+    /// and then remove the loose objects and old packs. Loose objects which do not hash to their
+    /// names, such as those still being written, are left where they are.
+    pub fn repack(&self, blobs: &Path, options: &RepackOptions) -> Result<Repacked> {
+        let _lock = self.lock()?;
+        let old = Loaded::load(&self.dir)?;
+        let mut repacked = Repacked::default();
+
+        let mut sources = HashMap::new();
+        if blobs.exists() {
+            walk_loose(blobs, &mut |hash, path| {
+                sources.insert(hash, Source::Loose(path));
+                Ok(())
+            })?;
+        }
+        for pack in &old.packs {
+            repacked.bytes_before += pack.size()?;
+            for entry in &pack.entries {
+                if !old.deleted.contains(&entry.hash) && !sources.contains_key(&entry.hash) {
+                    sources.insert(entry.hash, Source::Packed);
+                }
+            }
+        }
+
+        let read = |hash: ObjectHash, source: &Source| -> Result<Option<Vec<u8>>> {
+            match *source {
+                Source::Loose(ref path) => {
+                    let mut bytes = Vec::new();
+                    File::open(path)?.read_to_end(&mut bytes)?;
+                    Ok(Some(bytes))
+                }
+                Source::Packed => old.read(hash),
+            }
+        };
+
+        // Order objects by kind and then by size, largest first, so that similar objects end up
+        // within a window of each other and deltas are mostly against larger bases.
+        let mut order = Vec::new();
+        let mut skipped = HashSet::new();
+        for (&hash, source) in &sources {
+            let bytes = match read(hash, source)? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+
+            match kind_of(hash, &bytes) {
+                Some(kind) => order.push((kind, !(bytes.len() as u64), hash)),
+                None => {
+                    if let Source::Packed = *source {
+                        bail!(ErrorKind::MalformedPack(
+                            self.dir.clone(),
+                            format!("the packed object {} is corrupt", hash),
+                        ));
+                    }
+                    skipped.insert(hash);
+                    continue;
+                }
+            }
+
+            if let Source::Loose(_) = *source {
+                repacked.bytes_before += bytes.len() as u64;
+            }
+        }
+        order.sort();
+
+        if !order.is_empty() {
+            fs::create_dir_all(&self.dir)?;
+            let pack_path = self.dir.join(format!("{:016x}.pack", rand::random::<u64>()));
+            let index_path = pack_path.with_extension("idx");
+            let pack_temp_path = pack_path.with_extension("pack.tmp");
+            let index_temp_path = pack_path.with_extension("idx.tmp");
+
+            let mut writer = BufWriter::new(File::create(&pack_temp_path)?);
+            writer.write_all(MAGIC)?;
+            writer.write_all(&[VERSION])?;
+            let mut offset = MAGIC.len() as u64 + 1;
+
+            let mut entries = Vec::with_capacity(order.len());
+            let mut window: VecDeque<(u8, ObjectHash, Vec<u8>, usize)> = VecDeque::new();
+
+            for &(kind, _, hash) in &order {
+                let bytes = read(hash, &sources[&hash])?.unwrap();
+
+                let mut best: Option<(ObjectHash, Vec<u8>, usize)> = None;
+                for &(base_kind, base_hash, ref base_bytes, depth) in &window {
+                    if base_kind != kind || depth >= options.max_depth {
+                        continue;
+                    }
+
+                    let delta = encode_delta(base_bytes, &bytes);
+                    let better = match best {
+                        Some((_, ref best_delta, _)) => delta.len() < best_delta.len(),
+                        None => delta.len() < bytes.len() / 2,
+                    };
+                    if better {
+                        best = Some((base_hash, delta, depth + 1));
+                    }
+                }
+
+                let compressed = match best {
+                    Some((_, ref delta, _)) => zstd::stream::encode_all(&delta[..], options.level)?,
+                    None => zstd::stream::encode_all(&bytes[..], options.level)?,
+                };
+                writer.write_all(&compressed)?;
+
+                entries.push(IndexEntry {
+                    hash,
+                    offset,
+                    len: compressed.len() as u64,
+                    base: best.as_ref().map(|&(base_hash, _, _)| base_hash),
+                });
+                offset += compressed.len() as u64;
+                repacked.objects += 1;
+                if best.is_some() {
+                    repacked.deltas += 1;
+                }
+
+                let depth = best.map_or(0, |(_, _, depth)| depth);
+                window.push_back((kind, hash, bytes, depth));
+                if window.len() > options.window {
+                    window.pop_front();
+                }
+            }
+
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            drop(writer);
+
+            entries.sort_by(|a, b| a.hash.cmp(&b.hash));
+            {
+                let mut index_writer = BufWriter::new(File::create(&index_temp_path)?);
+                bincode::serialize_into(&mut index_writer, &entries, bincode::Infinite)?;
+                index_writer.flush()?;
+                index_writer.get_ref().sync_all()?;
+            }
+
+            fs::rename(&pack_temp_path, &pack_path)?;
+            fs::rename(&index_temp_path, &index_path)?;
+            repacked.bytes_after = fs::metadata(&pack_path)?.len() +
+                fs::metadata(&index_path)?.len();
+        }
+
+        for (hash, source) in sources {
+            if let Source::Loose(path) = source {
+                if skipped.contains(&hash) {
+                    continue;
+                }
+
+                if let Err(err) = fs::remove_file(&path) {
+                    if err.kind() != io::ErrorKind::NotFound {
+                        return Err(err).chain_err(|| ErrorKind::RemoveFile(path.clone()));
+                    }
+                }
+                repacked.loose_removed += 1;
+            }
+        }
+
+        for pack in &old.packs {
+            pack.remove()?;
+            repacked.packs_removed += 1;
+        }
+
+        let deleted_path = self.dir.join(DELETED);
+        if deleted_path.exists() {
+            fs::remove_file(&deleted_path)?;
+        }
+        *self.loaded.lock().unwrap() = None;
+
+        Ok(repacked)
+    }
+}
+
+
+/// Call `f` with the hash and path of every object in the blob directory `blobs`.
+fn walk_loose<F>(path: &Path, f: &mut F) -> Result<()>
+where
+    F: FnMut(ObjectHash, PathBuf) -> Result<()>,
+{
+    for entry_res in fs::read_dir(path)? {
+        let entry = entry_res?;
+        let entry_path = entry.path();
+
+        if entry.metadata()?.is_dir() {
+            walk_loose(&entry_path, f)?;
+        } else {
+            match ObjectHash::from_blob_path(&entry_path) {
+                Ok(hash) => f(hash, entry_path)?,
+                Err(_) => continue,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// The kind of an encoded object, if it decodes and hashes to `hash`: `0` for data, `1` for
+/// subtrees and `2` for commits.
+fn kind_of(hash: ObjectHash, bytes: &[u8]) -> Option<u8> {
+    match canonical::decode_verified(bytes, hash)? {
+        RawObject::Data(_) => Some(0),
+        RawObject::Subtree(_) => Some(1),
+        RawObject::Commit(_) | RawObject::ExtendedCommit(_) => Some(2),
+    }
+}
+
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+
+    loop {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        if shift > 63 {
+            return None;
+        }
+
+        n |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+        shift += 7;
+    }
+}
+
+
+fn insert(delta: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        delta.push(INSERT);
+        write_varint(delta, bytes.len() as u64);
+        delta.extend_from_slice(bytes);
+    }
+}
+
+
+/// Encode `target` as copies of ranges of `base` and insertions of new bytes.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks = HashMap::new();
+    let mut block_start = 0;
+    while block_start + BLOCK_SIZE <= base.len() {
+        blocks
+            .entry(seahash::hash(&base[block_start..block_start + BLOCK_SIZE]))
+            .or_insert(block_start);
+        block_start += BLOCK_SIZE;
+    }
+
+    let mut delta = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i + BLOCK_SIZE <= target.len() {
+        let block = &target[i..i + BLOCK_SIZE];
+        let matched = match blocks.get(&seahash::hash(block)) {
+            Some(&start) if &base[start..start + BLOCK_SIZE] == block => Some(start),
+            _ => None,
+        };
+
+        match matched {
+            Some(mut start) => {
+                let mut copy_start = i;
+                while copy_start > literal_start && start > 0 &&
+                    base[start - 1] == target[copy_start - 1]
+                {
+                    start -= 1;
+                    copy_start -= 1;
+                }
+
+                let mut len = i + BLOCK_SIZE - copy_start;
+                while start + len < base.len() && copy_start + len < target.len() &&
+                    base[start + len] == target[copy_start + len]
+                {
+                    len += 1;
+                }
+
+                insert(&mut delta, &target[literal_start..copy_start]);
+                delta.push(COPY);
+                write_varint(&mut delta, start as u64);
+                write_varint(&mut delta, len as u64);
+
+                i = copy_start + len;
+                literal_start = i;
+            }
+            None => i += 1,
+        }
+    }
+    insert(&mut delta, &target[literal_start..]);
+
+    delta
+}
+
+
+/// Apply a delta made by `encode_delta` to `base`, or return `None` if it is malformed.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut target = Vec::new();
+    let mut pos = 0;
+
+    while pos < delta.len() {
+        let instruction = delta[pos];
+        pos += 1;
+
+        match instruction {
+            COPY => {
+                let start = read_varint(delta, &mut pos)? as usize;
+                let len = read_varint(delta, &mut pos)? as usize;
+                if start.checked_add(len)? > base.len() {
+                    return None;
+                }
+                target.extend_from_slice(&base[start..start + len]);
+            }
+            INSERT => {
+                let len = read_varint(delta, &mut pos)? as usize;
+                if pos.checked_add(len)? > delta.len() {
+                    return None;
+                }
+                target.extend_from_slice(&delta[pos..pos + len]);
+                pos += len;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(target)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deltas_round_trip() {
+        let base = (0..4096u32).map(|i| (i * 31 % 251) as u8).collect::<Vec<_>>();
+        let mut target = base[..2000].to_vec();
+        target[100] ^= 0xFF;
+        target.extend_from_slice(b"something new");
+        target.extend_from_slice(&base[2010..]);
+        target.extend_from_slice(b"and a tail");
+
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < target.len() / 10);
+        assert_eq!(apply_delta(&base, &delta), Some(target.clone()));
+
+        assert_eq!(apply_delta(&[], &encode_delta(&[], &target)), Some(target));
+        assert_eq!(apply_delta(&base, &[COPY, 0xFF]), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! # `prefetch` - reading ahead of a traversal of the object graph.
+//!
+//! Walking a tree or streaming the chunks of a large file reads one object at a time, and only
+//! learns which objects to read next once the last has arrived. Against a remote, every step pays
+//! a full round trip. `Prefetch` wraps any store and, whenever an object is read through it,
+//! begins reading the objects a traversal is likely to want next - the subtrees beneath a subtree,
+//! and the chunks of a large data object - so that they are already in flight when asked for.
+//!
+//! At most `window` objects are read ahead at once; the rest wait their turn in the order they
+//! were found. The data objects of files are never read ahead, since a traversal which only lists
+//! a tree, or checks it out lazily, would never want them.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use futures_cpupool::{CpuFuture, CpuPool};
+
+use errors::*;
+use marshal::{DataObject, Hashed, Object, ObjectHash, SubtreeEntry};
+use store::ObjectStore;
+
+
+#[derive(Default)]
+struct PrefetchState {
+    /// Reads in flight, by hash.
+    pending: HashMap<ObjectHash, CpuFuture<Object, Error>>,
+
+    /// Objects to read once there is room in the window.
+    queued: VecDeque<ObjectHash>,
+}
+
+
+/// A store which reads ahead of whatever is reading from it. See the module docs.
+#[derive(Clone)]
+pub struct Prefetch<S: ObjectStore> {
+    store: S,
+    window: usize,
+
+    /// Drives reads ahead, so that they make progress before anything waits on them.
+    pool: CpuPool,
+
+    state: Arc<Mutex<PrefetchState>>,
+}
+
+
+impl<S: ObjectStore> Prefetch<S> {
+    /// Wrap `store`, reading up to `window` objects ahead. A window of zero reads nothing ahead.
+    pub fn new(store: &S, window: usize) -> Self {
+        Prefetch {
+            store: store.clone(),
+            window,
+            pool: CpuPool::new(1),
+            state: Arc::new(Mutex::new(PrefetchState::default())),
+        }
+    }
+
+    /// Begin reading the given objects ahead, in order, as the window allows.
+    pub fn prefetch<I: IntoIterator<Item = ObjectHash>>(&self, hashes: I) {
+        if self.window == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.queued.extend(hashes);
+
+        while state.pending.len() < self.window {
+            let hash = match state.queued.pop_front() {
+                Some(hash) => hash,
+                None => break,
+            };
+
+            if !state.pending.contains_key(&hash) {
+                let read = self.pool.spawn(self.store.read_object(hash));
+                state.pending.insert(hash, read);
+            }
+        }
+    }
+}
+
+
+/// The objects a traversal is likely to read after `object`.
+fn ahead(object: &Object) -> Vec<ObjectHash> {
+    match *object {
+        Object::Subtree(ref subtree_object) => {
+            subtree_object
+                .entries
+                .values()
+                .filter_map(|entry| match *entry {
+                    SubtreeEntry::Subtree(hash) => Some(hash),
+                    _ => None,
+                })
+                .collect()
+        }
+        Object::Data(DataObject::Large(ref large_object)) => {
+            large_object.children.iter().map(|&(_, hash)| hash).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+
+impl<S: ObjectStore> ObjectStore for Prefetch<S> {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = S::Write;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        let prefetched = self.state.lock().unwrap().pending.remove(&object_hash);
+
+        // A read ahead which failed is retried, so that any error comes from the read asked for.
+        let read: Self::Read = match prefetched {
+            Some(read) => {
+                let store = self.store.clone();
+                Box::new(read.or_else(move |_| store.read_object(object_hash)))
+            }
+            None => Box::new(self.store.read_object(object_hash)),
+        };
+
+        let prefetch = self.clone();
+        Box::new(read.map(move |object| {
+            prefetch.prefetch(ahead(&object));
+            object
+        }))
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        self.store.write_object(hashed)
+    }
+
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.store.is_known(object_hash)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use futures::future::{self, FutureResult};
+
+    use marshal::SubtreeObject;
+
+    /// Serves subtrees from memory, counting how many times each is read.
+    #[derive(Clone, Default)]
+    struct CountingStore {
+        objects: Arc<Mutex<HashMap<ObjectHash, Object>>>,
+        reads: Arc<Mutex<HashMap<ObjectHash, usize>>>,
+    }
+
+    impl ObjectStore for CountingStore {
+        type Read = FutureResult<Object, Error>;
+        type Write = FutureResult<bool, Error>;
+
+        fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+            *self.reads.lock().unwrap().entry(object_hash).or_insert(0) += 1;
+
+            match self.objects.lock().unwrap().get(&object_hash) {
+                Some(object) => future::ok(object.clone()),
+                None => future::err(ErrorKind::ObjectMissing(object_hash).into()),
+            }
+        }
+
+        fn write_object(&self, _hashed: Hashed) -> Self::Write {
+            future::ok(false)
+        }
+    }
+
+    fn subtree(children: &[(&str, ObjectHash)]) -> Object {
+        let entries = children
+            .iter()
+            .map(|&(name, hash)| (name.into(), SubtreeEntry::Subtree(hash)))
+            .collect::<BTreeMap<_, _>>();
+
+        Object::Subtree(SubtreeObject { entries })
+    }
+
+    #[test]
+    fn reads_each_subtree_once() {
+        let hashes = (1..5)
+            .map(|i| format!("{:02x}", i).repeat(32).parse::<ObjectHash>().unwrap())
+            .collect::<Vec<_>>();
+        let store = CountingStore::default();
+
+        {
+            let mut objects = store.objects.lock().unwrap();
+            objects.insert(hashes[0], subtree(&[("a", hashes[1]), ("b", hashes[2])]));
+            objects.insert(hashes[1], subtree(&[("c", hashes[3])]));
+            objects.insert(hashes[2], subtree(&[]));
+            objects.insert(hashes[3], subtree(&[]));
+        }
+
+        // With a window of one, every read must either find its object already read ahead or
+        // read it directly - but never both.
+        let prefetch = Prefetch::new(&store, 1);
+        for &hash in &hashes {
+            prefetch.read_object(hash).wait().unwrap();
+        }
+
+        let reads = store.reads.lock().unwrap();
+        for hash in &hashes {
+            assert_eq!(reads.get(hash), Some(&1));
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! # `replicated` - writing every object to several stores at once.
+//!
+//! A `ReplicatedStore` holds a list of stores - two Ceph pools, say - and writes every object to
+//! all of them, so that losing any one loses nothing. Reads are served by the first store which
+//! has the object, trying each in order.
+//!
+//! How many stores must acknowledge a write before it is considered done is set by a
+//! `WritePolicy`. Under `majority` or `any`, a write finishes as soon as enough stores have
+//! acknowledged it; the writes to the rest carry on in the background, and their failures are
+//! only reported as warnings.
+
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
+
+use errors::*;
+use marshal::{Hashed, Object, ObjectHash};
+use store::ObjectStore;
+use warning;
+
+
+/// How many of the stores of a `ReplicatedStore` must acknowledge a write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WritePolicy {
+    All,
+    Majority,
+    Any,
+}
+
+
+impl Default for WritePolicy {
+    fn default() -> Self {
+        WritePolicy::All
+    }
+}
+
+
+impl WritePolicy {
+    /// The number of acknowledgments needed out of `replicas` stores.
+    pub fn required(&self, replicas: usize) -> usize {
+        match *self {
+            WritePolicy::All => replicas,
+            WritePolicy::Majority => replicas / 2 + 1,
+            WritePolicy::Any => 1,
+        }
+    }
+}
+
+
+/// A store replicated across several others. See the module docs.
+#[derive(Clone)]
+pub struct ReplicatedStore<S: ObjectStore> {
+    stores: Vec<S>,
+    policy: WritePolicy,
+
+    /// Drives writes, so that those still running when enough stores have acknowledged finish.
+    io_pool: CpuPool,
+}
+
+
+impl<S: ObjectStore> ReplicatedStore<S> {
+    /// Replicate across `stores`, which should not be empty, preferring them for reads in order.
+    pub fn new(stores: Vec<S>, policy: WritePolicy, io_pool: &CpuPool) -> Self {
+        assert!(!stores.is_empty(), "a replicated store needs at least one store");
+
+        ReplicatedStore {
+            stores,
+            policy,
+            io_pool: io_pool.clone(),
+        }
+    }
+
+    pub fn stores(&self) -> &[S] {
+        &self.stores
+    }
+
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+}
+
+
+impl<S: ObjectStore> ObjectStore for ReplicatedStore<S> {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        let stores = self.stores.clone();
+
+        let result = {
+            async_block! {
+                let mut last_error = None;
+
+                for store in stores {
+                    match await!(store.read_object(object_hash)) {
+                        Ok(object) => return Ok(object),
+                        Err(error) => last_error = Some(error),
+                    }
+                }
+
+                Err(last_error.unwrap())
+            }
+        };
+
+        Box::new(result)
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        let object_hash = *hashed.as_hash();
+        let required = self.policy.required(self.stores.len());
+
+        let mut writes = FuturesUnordered::new();
+        for store in &self.stores {
+            let (tx, rx) = oneshot::channel();
+            let write = store.write_object(hashed.clone()).then(move |result| {
+                // Nobody may be waiting any more; the write is done either way.
+                if let Err(Err(error)) = tx.send(result) {
+                    warning::warn(format!("replicating {} failed: {}", object_hash, error));
+                }
+
+                Ok::<(), ()>(())
+            });
+
+            self.io_pool.spawn(write).forget();
+            writes.push(rx);
+        }
+
+        let result = {
+            async_block! {
+                let mut acked = 0;
+                let mut fresh = false;
+                let mut last_error = None;
+
+                // A write is only ever abandoned if its task panicked.
+                let writes = writes.map_err(|_| Error::from_kind(ErrorKind::WriteAbandoned));
+
+                #[async]
+                for result in writes {
+                    match result {
+                        Ok(written) => {
+                            acked += 1;
+                            fresh |= written;
+
+                            if acked >= required {
+                                return Ok(fresh);
+                            }
+                        }
+                        Err(error) => last_error = Some(error),
+                    }
+                }
+
+                let kind = ErrorKind::ReplicationFailed(object_hash, acked, required);
+                match last_error {
+                    Some(error) => Err(error).chain_err(|| kind),
+                    None => bail!(kind),
+                }
+            }
+        };
+
+        Box::new(result)
+    }
+
+    /// An object is only known to be replicated once every store knows of it.
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.stores.iter().all(|store| store.is_known(object_hash))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use store::Empty;
+
+    #[test]
+    fn fails_without_enough_acknowledgments() {
+        assert_eq!(WritePolicy::All.required(3), 3);
+        assert_eq!(WritePolicy::Majority.required(3), 2);
+        assert_eq!(WritePolicy::Majority.required(4), 3);
+        assert_eq!(WritePolicy::Any.required(3), 1);
+
+        let object_hash = "01".repeat(32).parse::<ObjectHash>().unwrap();
+        let io_pool = CpuPool::new(1);
+        let replicated = ReplicatedStore::new(vec![Empty, Empty], WritePolicy::Any, &io_pool);
+
+        match replicated.write_object(Hashed::from_hash(object_hash)).wait() {
+            Err(Error(ErrorKind::ReplicationFailed(hash, 0, 1), _)) => {
+                assert_eq!(hash, object_hash)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
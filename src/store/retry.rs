@@ -0,0 +1,329 @@
+//! # `retry` - a store wrapper retrying operations which fail transiently.
+//!
+//! A dropped connection or a busy OSD should not abort a push of a million objects. `RetryStore`
+//! retries every read and write of the store it wraps which fails with a transient error - a
+//! network error, a timeout, or `EAGAIN` from RADOS - waiting longer after each failure, with
+//! random jitter so that many operations failing at once do not all retry at once. Errors which
+//! are not transient are returned immediately. Once a `RetryPolicy` runs out of attempts the last
+//! error is returned, chained under `ErrorKind::RetriesExhausted`.
+//!
+//! A remote is given a policy by a `retry` table in the repository config. Each attempt is bounded
+//! by the remote's `timeouts`, if any, rather than all of them together.
+
+use std::cmp;
+use std::io;
+use std::time::Duration;
+
+use futures::prelude::*;
+use rand;
+
+use cancel::Deadline;
+use errors::*;
+use marshal::{Object, ObjectHash, Hashed};
+use store::ObjectStore;
+
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+
+fn default_initial_backoff_ms() -> u64 {
+    100
+}
+
+
+fn default_max_backoff_ms() -> u64 {
+    10_000
+}
+
+
+/// How many times to try an operation, and how long to wait in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// The most times an operation is tried, counting the first.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+
+    /// How long to wait after the first failure, in milliseconds. The wait doubles after each
+    /// failure thereafter.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+
+    /// The longest to wait after any failure, in milliseconds.
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
+}
+
+
+impl RetryPolicy {
+    /// A policy which tries every operation only once.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// The longest to wait after the `failures`th failure, before jitter.
+    fn ceiling(&self, failures: u32) -> u64 {
+        let doublings = cmp::min(failures.saturating_sub(1), 32);
+        let backoff = self.initial_backoff_ms.saturating_mul(1 << doublings);
+
+        cmp::min(backoff, self.max_backoff_ms)
+    }
+
+    /// How long to wait after the `failures`th failure: somewhere between half of and all of the
+    /// exponentially growing ceiling.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let ceiling = self.ceiling(failures);
+        let half = ceiling / 2;
+        let jitter = rand::random::<u64>() % (ceiling - half + 1);
+
+        Duration::from_millis(half + jitter)
+    }
+}
+
+
+fn is_transient_io(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::Interrupted |
+        io::ErrorKind::WouldBlock => true,
+        _ => false,
+    }
+}
+
+
+/// Whether an error may go away if the operation which failed with it is tried again.
+pub fn is_transient(error: &Error) -> bool {
+    match *error.kind() {
+        ErrorKind::Io(ref error) => is_transient_io(error),
+        #[cfg(feature = "rados")]
+        ErrorKind::Rados(::rad::ErrorKind::Io(ref error)) => is_transient_io(error),
+        ErrorKind::TimedOut(..) => true,
+
+        // Another write of the same object failed; this one may yet succeed.
+        ErrorKind::CatalogLockAbandoned => true,
+        _ => false,
+    }
+}
+
+
+/// Run the future made by `attempt` until it succeeds, fails with an error which is not transient,
+/// or has been tried as many times as `policy` allows.
+pub fn retry<F, T>(
+    policy: RetryPolicy,
+    mut attempt: F,
+) -> Box<Future<Item = T::Item, Error = Error> + Send>
+where
+    F: FnMut() -> T + Send + 'static,
+    T: Future<Error = Error> + Send + 'static,
+    T::Item: Send + 'static,
+{
+    let result = {
+        async_block! {
+            let mut failures = 0;
+
+            loop {
+                let error = match await!(attempt()) {
+                    Ok(item) => return Ok(item),
+                    Err(error) => error,
+                };
+                failures += 1;
+
+                if !is_transient(&error) {
+                    return Err(error);
+                }
+                if failures >= policy.max_attempts {
+                    return Err(Error::with_chain(error, ErrorKind::RetriesExhausted(failures)));
+                }
+
+                await!(Deadline::after(policy.backoff(failures)))?;
+            }
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// A store whose operations are retried when they fail transiently. See the module docs.
+#[derive(Clone)]
+pub struct RetryStore<S: ObjectStore> {
+    inner: S,
+    policy: RetryPolicy,
+}
+
+
+impl<S: ObjectStore> RetryStore<S> {
+    pub fn new(inner: S, policy: &RetryPolicy) -> Self {
+        RetryStore {
+            inner,
+            policy: *policy,
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+
+impl<S: ObjectStore> ObjectStore for RetryStore<S> {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        let inner = self.inner.clone();
+        retry(self.policy, move || inner.read_object(object_hash))
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        let inner = self.inner.clone();
+        retry(self.policy, move || inner.write_object(hashed.clone()))
+    }
+
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.inner.is_known(object_hash)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future;
+    use futures_cpupool::CpuPool;
+
+    use super::*;
+    use arc_slice;
+    use bench::Scratch;
+    use catalog::Catalog;
+    use marshal::{DataObject, SmallObject, serialize_and_hash};
+    use repository;
+    use store::Local;
+
+    /// A store whose first writes are abandoned part way through, as a timed-out write would be,
+    /// failing with a transient error.
+    #[derive(Clone)]
+    struct Flaky<S: ObjectStore> {
+        inner: S,
+        failures: Arc<AtomicUsize>,
+    }
+
+    impl<S: ObjectStore> ObjectStore for Flaky<S> {
+        type Read = S::Read;
+        type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+        fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+            self.inner.read_object(object_hash)
+        }
+
+        fn write_object(&self, hashed: Hashed) -> Self::Write {
+            if self.failures.load(Ordering::SeqCst) == 0 {
+                return Box::new(self.inner.write_object(hashed));
+            }
+
+            self.failures.fetch_sub(1, Ordering::SeqCst);
+            drop(self.inner.write_object(hashed));
+            Box::new(future::err(Error::from(io::Error::from(io::ErrorKind::TimedOut))))
+        }
+
+        fn is_known(&self, object_hash: ObjectHash) -> bool {
+            self.inner.is_known(object_hash)
+        }
+    }
+
+    #[test]
+    fn retried_write_reaches_the_store() {
+        let scratch = Scratch::new("attaca-retry").unwrap();
+        let paths = Arc::new(repository::init(scratch.path()).unwrap());
+        let catalog = Catalog::load(paths.local_catalog.clone()).unwrap();
+        let local = Local::new(&paths, &catalog, &CpuPool::new(1));
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1,
+        };
+        let flaky = Flaky {
+            inner: local.clone(),
+            failures: Arc::new(AtomicUsize::new(1)),
+        };
+        let store = RetryStore::new(flaky, &policy);
+
+        let chunk = arc_slice::owned(b"retried".to_vec());
+        let object = Object::Data(DataObject::Small(SmallObject { chunk }));
+        let hashed = serialize_and_hash(&object);
+        let object_hash = *hashed.as_hash();
+
+        assert!(store.write_object(hashed).wait().unwrap());
+        match local.read_object(object_hash).wait().unwrap() {
+            Object::Data(DataObject::Small(small)) => assert_eq!(&*small.chunk, b"retried"),
+            other => panic!("expected the written chunk, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retries_only_transient_errors() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1,
+        };
+
+        let tries = Arc::new(AtomicUsize::new(0));
+        let counter = tries.clone();
+        let result = retry(policy, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            future::err::<(), _>(Error::from(io::Error::from(io::ErrorKind::ConnectionReset)))
+        }).wait();
+        match *result.unwrap_err().kind() {
+            ErrorKind::RetriesExhausted(3) => {}
+            ref other => panic!("expected retries to run out, got {:?}", other),
+        }
+        assert_eq!(tries.load(Ordering::SeqCst), 3);
+
+        let tries = Arc::new(AtomicUsize::new(0));
+        let counter = tries.clone();
+        let result = retry(policy, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            future::err::<(), _>(Error::from_kind(ErrorKind::Absurd))
+        }).wait();
+        assert!(result.is_err());
+        assert_eq!(tries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_limit() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.ceiling(1), 100);
+        assert_eq!(policy.ceiling(3), 400);
+        assert_eq!(policy.ceiling(40), 10_000);
+        assert!(policy.backoff(2) >= Duration::from_millis(100));
+        assert!(policy.backoff(2) <= Duration::from_millis(200));
+    }
+}
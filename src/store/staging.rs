@@ -0,0 +1,301 @@
+//! # `staging` - hold freshly written objects apart until the refs which need them are written.
+//!
+//! A command which writes many objects and then fails, or is interrupted, would otherwise leave
+//! every object it wrote in the local store until the next garbage collection. While a `Staging`
+//! is active, the local store instead writes new objects under `.attaca/staging/<pid>-<nonce>`.
+//! If the staging is dropped without being promoted, that directory is removed and the objects
+//! are forgotten by the catalog and the reference counts, so nothing is left behind.
+//!
+//! Promotion happens in two steps around writing the refs. Before the refs are written, the
+//! staging directory is marked by renaming it with a `.promote` extension; afterwards, each object
+//! is moved into the blob directory. A staging directory left behind by a process which died is
+//! cleaned up by `Staging::recover` the next time the repository is loaded: marked directories
+//! are finished off, since the refs may already point into them, and unmarked ones are removed.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use libc;
+use rand;
+
+use catalog::Catalog;
+use errors::*;
+use fault;
+use lock;
+use marshal::ObjectHash;
+use marshal::canonical;
+use refcount::Refcounts;
+use repository::Paths;
+use warning;
+
+
+const PROMOTE_EXTENSION: &str = "promote";
+
+
+#[derive(Debug)]
+struct StagingState {
+    dir: PathBuf,
+    staged: HashSet<ObjectHash>,
+    promoted: bool,
+}
+
+
+#[derive(Debug)]
+struct StagingInner {
+    blobs: PathBuf,
+    catalog: Catalog,
+    refcounts: Option<Refcounts>,
+    state: Mutex<StagingState>,
+}
+
+
+impl Drop for StagingInner {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+
+        if !state.promoted {
+            for &hash in &state.staged {
+                self.catalog.remove(hash);
+            }
+
+            if let Some(ref refcounts) = self.refcounts {
+                for &hash in &state.staged {
+                    if let Err(error) = release(refcounts, &state.dir, hash) {
+                        warning::warn(format!(
+                            "could not release the references of staged object {}: {}",
+                            hash,
+                            error
+                        ));
+                    }
+                }
+            }
+
+            if state.dir.exists() {
+                if let Err(error) = fs::remove_dir_all(&state.dir) {
+                    warning::warn(format!(
+                        "could not discard staged objects in {}: {}",
+                        state.dir.display(),
+                        error
+                    ));
+                }
+            }
+        }
+    }
+}
+
+
+/// A set of objects written since staging began. See the module docs.
+#[derive(Debug, Clone)]
+pub struct Staging {
+    inner: Arc<StagingInner>,
+}
+
+
+impl Staging {
+    /// Begin staging objects written to the local store with the given catalog and reference
+    /// counts.
+    pub fn begin(
+        paths: &Paths,
+        catalog: &Catalog,
+        refcounts: Option<&Refcounts>,
+    ) -> Result<Self> {
+        let name = format!("{}-{:016x}", unsafe { libc::getpid() }, rand::random::<u64>());
+        let dir = paths.staging.join(name);
+        fs::create_dir_all(&dir)?;
+
+        Ok(Staging {
+            inner: Arc::new(StagingInner {
+                blobs: paths.blobs.clone(),
+                catalog: catalog.clone(),
+                refcounts: refcounts.cloned(),
+                state: Mutex::new(StagingState {
+                    dir,
+                    staged: HashSet::new(),
+                    promoted: false,
+                }),
+            }),
+        })
+    }
+
+    /// Record that an object is being written, returning the path to write it to.
+    pub fn stage(&self, hash: ObjectHash) -> PathBuf {
+        let mut state = self.inner.state.lock().unwrap();
+        state.staged.insert(hash);
+        state.dir.join(hash.to_path())
+    }
+
+    /// The path of an object, if it is staged here and has not yet been promoted.
+    pub fn path_of(&self, hash: ObjectHash) -> Option<PathBuf> {
+        let state = self.inner.state.lock().unwrap();
+
+        if state.staged.contains(&hash) {
+            Some(state.dir.join(hash.to_path()))
+        } else {
+            None
+        }
+    }
+
+    /// Mark the staged objects to be kept, even should this process die before `promote` is
+    /// called. Must be called before writing any refs which point at the staged objects.
+    pub fn mark(&self) -> Result<()> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.dir.extension().map_or(true, |ext| ext != PROMOTE_EXTENSION) {
+            let marked = state.dir.with_extension(PROMOTE_EXTENSION);
+            fault::point("staging.mark");
+            fs::rename(&state.dir, &marked)?;
+            state.dir = marked;
+        }
+
+        Ok(())
+    }
+
+    /// Move every staged object into the blob directory, marking the staging first if it has not
+    /// been already.
+    pub fn promote(self) -> Result<()> {
+        self.mark()?;
+
+        let mut state = self.inner.state.lock().unwrap();
+        move_into(&state.dir, &self.inner.blobs)?;
+        state.staged.clear();
+        state.promoted = true;
+
+        Ok(())
+    }
+
+    /// Staging directories left behind by processes which are no longer running.
+    pub fn abandoned(paths: &Paths) -> Result<Vec<Abandoned>> {
+        let mut abandoned = Vec::new();
+
+        if !paths.staging.exists() {
+            return Ok(abandoned);
+        }
+
+        for entry_res in fs::read_dir(&paths.staging)? {
+            let dir = entry_res?.path();
+            let name = match dir.file_stem() {
+                Some(stem) => stem.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let pid = name.split('-').next().and_then(|pid| pid.parse::<libc::pid_t>().ok());
+
+            if pid.map_or(false, lock::is_alive) {
+                continue;
+            }
+
+            let marked = dir.extension().map_or(false, |ext| ext == PROMOTE_EXTENSION);
+            abandoned.push(Abandoned { dir, pid, marked });
+        }
+
+        Ok(abandoned)
+    }
+
+    /// Clean up staging directories left behind by processes which are no longer running. Marked
+    /// directories are promoted; unmarked ones are discarded.
+    pub fn recover(paths: &Paths) -> Result<()> {
+        for abandoned in Self::abandoned(paths)? {
+            if abandoned.marked {
+                abandoned.promote(paths)?;
+            } else {
+                abandoned.discard()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+/// A staging directory left behind by a process which died. See `Staging::abandoned`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Abandoned {
+    pub dir: PathBuf,
+
+    /// The ID of the process which was staging, if it could be read from the directory's name.
+    pub pid: Option<libc::pid_t>,
+
+    /// Whether the staging was marked to be kept, in which case refs may point into it.
+    pub marked: bool,
+}
+
+
+impl Abandoned {
+    /// The number of objects in the staging directory.
+    pub fn objects(&self) -> Result<u64> {
+        fn walk(path: &Path) -> Result<u64> {
+            let mut count = 0;
+
+            for entry_res in fs::read_dir(path)? {
+                let entry = entry_res?;
+
+                if entry.metadata()?.is_dir() {
+                    count += walk(&entry.path())?;
+                } else {
+                    count += 1;
+                }
+            }
+
+            Ok(count)
+        }
+
+        walk(&self.dir)
+    }
+
+    /// Move the staged objects into the local store, keeping them.
+    pub fn promote(self, paths: &Paths) -> Result<()> {
+        move_into(&self.dir, &paths.blobs)
+    }
+
+    /// Remove the staged objects.
+    pub fn discard(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+
+        Ok(())
+    }
+}
+
+
+/// Release the references counted for the object `hash`, staged in `dir`, as it is discarded.
+/// Objects are only counted once their file is written, so one without a file is left alone.
+fn release(refcounts: &Refcounts, dir: &Path, hash: ObjectHash) -> Result<()> {
+    let mut bytes = Vec::new();
+    match File::open(dir.join(hash.to_path())) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(_) => return Ok(()),
+    };
+
+    refcounts.deleted(hash, canonical::decode(&bytes)?.1.refs());
+
+    Ok(())
+}
+
+
+/// Move every object file in the staging directory `dir` into `blobs`, and remove `dir`.
+fn move_into(dir: &Path, blobs: &Path) -> Result<()> {
+    fn walk(path: &Path, relative: &Path, blobs: &Path) -> Result<()> {
+        for entry_res in fs::read_dir(path)? {
+            let entry = entry_res?;
+            let relative_path = relative.join(entry.file_name());
+
+            if entry.metadata()?.is_dir() {
+                walk(&entry.path(), &relative_path, blobs)?;
+            } else {
+                let destination = blobs.join(&relative_path);
+                fs::create_dir_all(destination.parent().unwrap())?;
+                fs::rename(entry.path(), destination)?;
+                fault::point("staging.promote");
+            }
+        }
+
+        Ok(())
+    }
+
+    walk(dir, Path::new(""), blobs)?;
+    fs::remove_dir_all(dir)?;
+
+    Ok(())
+}
+
@@ -0,0 +1,436 @@
+//! # `stats` - object counts, sizes, and deduplication.
+//!
+//! `StoreStats` describes everything a store physically holds, and is provided by stores which
+//! can cheaply enumerate their contents through the `Statistics` trait. `BranchStats` describes a
+//! single commit's tree: how much it occupies once deduplicated, against how much it would
+//! occupy as plain files. `PathUsage` breaks the same figures down by top-level path, in the
+//! manner of `du`. `DedupStats` compares the trees of several commits, to show how many chunks
+//! they share and how much deduplication and compression save against storing each as plain
+//! files, which is the information needed to tell whether chunking parameters suit a dataset.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+
+use futures::future::{self, FutureResult};
+use futures::prelude::*;
+use zstd;
+
+use errors::*;
+use graph::{self, Visit, Visitor};
+use marshal::{ObjectHash, Object, DataObject, SubtreeEntry};
+use marshal::canonical::Version;
+use store::ObjectStore;
+
+
+/// Statistics about every object held by a store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// The number of objects held.
+    pub objects: u64,
+
+    /// The total size of every held object, as stored.
+    pub stored_bytes: u64,
+}
+
+
+/// Stores which can enumerate their contents.
+pub trait Statistics: ObjectStore {
+    fn stats(&self) -> Result<StoreStats>;
+}
+
+
+/// Statistics about the tree of a single commit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchStats {
+    /// The number of distinct objects making up the tree.
+    pub objects: u64,
+
+    /// The total size of every distinct object making up the tree, as stored.
+    pub stored_bytes: u64,
+
+    /// The total size of every file in the tree, counting duplicates as many times as they occur.
+    pub logical_bytes: u64,
+}
+
+
+impl BranchStats {
+    /// How many times larger the tree would be as plain files than it is deduplicated, if it
+    /// occupies any space at all.
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        if self.stored_bytes == 0 {
+            None
+        } else {
+            Some(self.logical_bytes as f64 / self.stored_bytes as f64)
+        }
+    }
+}
+
+
+/// The space taken up by a single top-level path of a commit's tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathUsage {
+    /// The total size of every file under the path, counting duplicates as many times as they
+    /// occur.
+    pub logical_bytes: u64,
+
+    /// The total size of every distinct object under the path, as stored. Chunks shared between
+    /// files under the path are counted once.
+    pub unique_bytes: u64,
+
+    /// The part of `unique_bytes` taken up by objects which occur under no other top-level path;
+    /// roughly, the space which deleting the path would free.
+    pub exclusive_bytes: u64,
+}
+
+
+/// How the trees of a single commit among several compare with the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitDedup {
+    pub commit_hash: ObjectHash,
+
+    /// The number of distinct chunks making up the tree.
+    pub chunks: u64,
+
+    /// The total size of every file in the tree, counting duplicates as many times as they occur.
+    pub logical_bytes: u64,
+
+    /// The total size of every distinct object making up the tree, as stored.
+    pub stored_bytes: u64,
+
+    /// The part of `stored_bytes` taken up by objects which occur in no other of the commits
+    /// compared; the cost of keeping this commit in addition to the others.
+    pub unique_bytes: u64,
+}
+
+
+/// Deduplication across the trees of several commits.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DedupStats {
+    /// Each commit compared, in the order given.
+    pub commits: Vec<CommitDedup>,
+
+    /// The number of distinct chunks making up any of the trees.
+    pub chunks: u64,
+
+    /// The number of those chunks which occur in more than one of the trees.
+    pub shared_chunks: u64,
+
+    /// The total size of every file in every tree, as if each commit were stored as plain files.
+    pub logical_bytes: u64,
+
+    /// The total size of every distinct object making up any of the trees, as stored.
+    pub stored_bytes: u64,
+
+    /// What `stored_bytes` would be were every chunk compressed, if a compression level was
+    /// given.
+    pub compressed_bytes: Option<u64>,
+}
+
+
+impl DedupStats {
+    /// The fraction of `logical_bytes` saved by deduplication, if there is anything to save.
+    pub fn dedup_savings(&self) -> Option<f64> {
+        savings(self.stored_bytes, self.logical_bytes)
+    }
+
+    /// The fraction of `logical_bytes` saved by deduplication and compression together, if a
+    /// compression level was given and there is anything to save.
+    pub fn compressed_savings(&self) -> Option<f64> {
+        self.compressed_bytes.and_then(|compressed| savings(compressed, self.logical_bytes))
+    }
+}
+
+
+fn savings(stored: u64, logical: u64) -> Option<f64> {
+    if logical == 0 {
+        None
+    } else {
+        Some(1.0 - stored as f64 / logical as f64)
+    }
+}
+
+
+struct StatsVisitor {
+    version: Version,
+    sizes: HashMap<ObjectHash, u64>,
+    subtrees: HashMap<ObjectHash, Vec<SubtreeEntry>>,
+    data_sizes: HashMap<ObjectHash, u64>,
+    chunks: HashSet<ObjectHash>,
+
+    /// The zstd level to estimate compressed sizes of chunks at, if any.
+    compression_level: Option<i32>,
+
+    /// The sizes of chunks as stored, were they compressed at `compression_level`.
+    compressed_sizes: HashMap<ObjectHash, u64>,
+}
+
+
+impl Visitor for StatsVisitor {
+    type Future = FutureResult<Visit, Error>;
+
+    fn visit(&mut self, hash: ObjectHash, object: Object) -> Self::Future {
+        let size = self.version.header_size() + object.encoded_size();
+        self.sizes.insert(hash, size);
+
+        match object {
+            Object::Subtree(subtree_object) => {
+                let entries = subtree_object.entries.into_iter().map(|(_, entry)| entry);
+                self.subtrees.insert(hash, entries.collect());
+            }
+            Object::Data(DataObject::Small(ref small_object)) => {
+                self.data_sizes.insert(hash, small_object.size());
+                self.chunks.insert(hash);
+
+                if let Some(level) = self.compression_level {
+                    let chunk = &small_object.chunk[..];
+                    let compressed = match zstd::stream::encode_all(chunk, level) {
+                        Ok(compressed) => compressed.len() as u64,
+                        Err(error) => return future::err(error.into()),
+                    };
+
+                    // A chunk which doesn't compress would be stored as it is.
+                    let saved = small_object.size() - cmp::min(compressed, small_object.size());
+                    self.compressed_sizes.insert(hash, size - saved);
+                }
+            }
+            Object::Data(DataObject::Large(ref large_object)) => {
+                self.data_sizes.insert(hash, large_object.size());
+            }
+            Object::Commit(_) => {}
+        }
+
+        future::ok(Visit::Descend)
+    }
+}
+
+
+impl StatsVisitor {
+    fn new(version: Version) -> Self {
+        StatsVisitor {
+            version,
+            sizes: HashMap::new(),
+            subtrees: HashMap::new(),
+            data_sizes: HashMap::new(),
+            chunks: HashSet::new(),
+            compression_level: None,
+            compressed_sizes: HashMap::new(),
+        }
+    }
+
+    fn stored_bytes(&self) -> u64 {
+        self.sizes.values().sum()
+    }
+
+    /// The logical size of an entry whose objects have all been visited.
+    fn logical_size(&self, entry: &SubtreeEntry, memo: &mut HashMap<ObjectHash, u64>) -> u64 {
+        let subtree_hash = match entry.unannotated() {
+            SubtreeEntry::File(_, size) |
+            SubtreeEntry::Executable(_, size) => return size,
+            SubtreeEntry::Symlink(hash) => {
+                return self.data_sizes.get(&hash).cloned().unwrap_or(0);
+            }
+            SubtreeEntry::Subtree(hash) => hash,
+            // A nested repository's objects are counted in its own store.
+            SubtreeEntry::Subrepository(..) => return 0,
+            SubtreeEntry::Annotated(..) => unreachable!(),
+        };
+
+        if let Some(&size) = memo.get(&subtree_hash) {
+            return size;
+        }
+
+        let mut size = 0;
+        for entry in self.subtrees.get(&subtree_hash).into_iter().flat_map(|entries| entries) {
+            size += self.logical_size(entry, memo);
+        }
+
+        memo.insert(subtree_hash, size);
+
+        size
+    }
+}
+
+
+/// Gather statistics about the tree of a commit, as it would be stored with objects encoded with
+/// `version`. History is not included.
+pub fn branch_stats<S: ObjectStore>(
+    store: &S,
+    commit_hash: ObjectHash,
+    version: Version,
+) -> Box<Future<Item = BranchStats, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_hash = match await!(store.read_object(commit_hash))? {
+                Object::Commit(commit_object) => commit_object.subtree,
+                _ => bail!(ErrorKind::ObjectNotACommit(commit_hash)),
+            };
+
+            let visitor = StatsVisitor::new(version);
+            let visitor = await!(graph::visit(&store, Some(subtree_hash), visitor))?;
+            let root = SubtreeEntry::Subtree(subtree_hash);
+            let logical_bytes = visitor.logical_size(&root, &mut HashMap::new());
+
+            Ok(BranchStats {
+                objects: visitor.sizes.len() as u64,
+                stored_bytes: visitor.stored_bytes(),
+                logical_bytes,
+            })
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Break down the tree of a commit by top-level path, as it would be stored with objects encoded
+/// with `version`. History is not included.
+pub fn path_usage<S: ObjectStore>(
+    store: &S,
+    commit_hash: ObjectHash,
+    version: Version,
+) -> Box<Future<Item = BTreeMap<OsString, PathUsage>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_hash = match await!(store.read_object(commit_hash))? {
+                Object::Commit(commit_object) => commit_object.subtree,
+                _ => bail!(ErrorKind::ObjectNotACommit(commit_hash)),
+            };
+            let subtree_object = match await!(store.read_object(subtree_hash))? {
+                Object::Subtree(subtree_object) => subtree_object,
+                _ => bail!(ErrorKind::ObjectNotASubtree(subtree_hash)),
+            };
+
+            let mut visited = Vec::new();
+            for (name, entry) in subtree_object.entries {
+                let visitor = StatsVisitor::new(version);
+                let root = if entry.is_subrepository() { None } else { Some(entry.hash()) };
+                let visitor = await!(graph::visit(&store, root, visitor))?;
+                visited.push((name, entry, visitor));
+            }
+
+            let mut occurrences = HashMap::<ObjectHash, usize>::new();
+            for &(_, _, ref visitor) in &visited {
+                for hash in visitor.sizes.keys() {
+                    *occurrences.entry(*hash).or_insert(0) += 1;
+                }
+            }
+
+            let mut usage = BTreeMap::new();
+            for (name, entry, visitor) in visited {
+                let exclusive_bytes = visitor
+                    .sizes
+                    .iter()
+                    .filter(|&(hash, _)| occurrences[hash] == 1)
+                    .map(|(_, &size)| size)
+                    .sum();
+
+                usage.insert(name, PathUsage {
+                    logical_bytes: visitor.logical_size(&entry, &mut HashMap::new()),
+                    unique_bytes: visitor.stored_bytes(),
+                    exclusive_bytes,
+                });
+            }
+
+            Ok(usage)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Compare the trees of the commits `commit_hashes`, as they would be stored with objects encoded
+/// with `version`. If `compression_level` is given, every chunk is compressed with zstd at that
+/// level to estimate how much compression would save on top of deduplication. History is not
+/// included, and a commit given more than once is only compared once.
+pub fn dedup_stats<S: ObjectStore>(
+    store: &S,
+    commit_hashes: Vec<ObjectHash>,
+    version: Version,
+    compression_level: Option<i32>,
+) -> Box<Future<Item = DedupStats, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let mut seen = HashSet::new();
+            let mut visited = Vec::new();
+            for commit_hash in commit_hashes {
+                if !seen.insert(commit_hash) {
+                    continue;
+                }
+
+                let subtree_hash = match await!(store.read_object(commit_hash))? {
+                    Object::Commit(commit_object) => commit_object.subtree,
+                    _ => bail!(ErrorKind::ObjectNotACommit(commit_hash)),
+                };
+
+                let mut visitor = StatsVisitor::new(version);
+                visitor.compression_level = compression_level;
+                let visitor = await!(graph::visit(&store, Some(subtree_hash), visitor))?;
+                let root = SubtreeEntry::Subtree(subtree_hash);
+                let logical_bytes = visitor.logical_size(&root, &mut HashMap::new());
+
+                visited.push((commit_hash, logical_bytes, visitor));
+            }
+
+            // Every distinct object, with the size it would be stored at compressed.
+            let mut occurrences = HashMap::<ObjectHash, usize>::new();
+            let mut compressed_sizes = HashMap::new();
+            for &(_, _, ref visitor) in &visited {
+                for (hash, &size) in &visitor.sizes {
+                    *occurrences.entry(*hash).or_insert(0) += 1;
+                    let compressed = visitor.compressed_sizes.get(hash).cloned().unwrap_or(size);
+                    compressed_sizes.insert(*hash, compressed);
+                }
+            }
+
+            let mut stats = DedupStats::default();
+            stats.stored_bytes = visited
+                .iter()
+                .flat_map(|&(_, _, ref visitor)| visitor.sizes.iter())
+                .collect::<HashMap<_, _>>()
+                .values()
+                .map(|&&size| size)
+                .sum();
+            if compression_level.is_some() {
+                stats.compressed_bytes = Some(compressed_sizes.values().sum());
+            }
+
+            let mut chunks = HashSet::new();
+            for (commit_hash, logical_bytes, visitor) in visited {
+                let unique_bytes = visitor
+                    .sizes
+                    .iter()
+                    .filter(|&(hash, _)| occurrences[hash] == 1)
+                    .map(|(_, &size)| size)
+                    .sum();
+
+                stats.commits.push(CommitDedup {
+                    commit_hash,
+                    chunks: visitor.chunks.len() as u64,
+                    logical_bytes,
+                    stored_bytes: visitor.stored_bytes(),
+                    unique_bytes,
+                });
+                stats.logical_bytes += logical_bytes;
+                chunks.extend(visitor.chunks);
+            }
+
+            stats.chunks = chunks.len() as u64;
+            stats.shared_chunks = chunks
+                .iter()
+                .filter(|&hash| occurrences[hash] > 1)
+                .count() as u64;
+
+            Ok(stats)
+        }
+    };
+
+    Box::new(result)
+}
@@ -0,0 +1,200 @@
+//! # `throttled` - a store wrapper limiting the bandwidth and concurrency of a transfer.
+//!
+//! Syncing a large snapshot would otherwise use all of a shared link. `ThrottledStore` lets at
+//! most a given number of operations on the store it wraps run at once, and holds each back so
+//! that the bytes read and written through it average out to no more than a given rate. Reads are
+//! held back once they have been read, since their size is only known then, and writes before
+//! they are made; either way the rate over a whole transfer is the same.
+//!
+//! Every clone of a `ThrottledStore` shares its limits, so a whole push or fetch is limited
+//! together rather than each operation on its own.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::prelude::*;
+use futures::task::{self, Task};
+
+use cancel::Deadline;
+use errors::*;
+use marshal::{Object, ObjectHash, Hashed};
+use store::ObjectStore;
+
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+
+#[derive(Debug, Default)]
+struct Slots {
+    in_flight: usize,
+    waiting: VecDeque<Task>,
+}
+
+
+#[derive(Debug)]
+struct Throttle {
+    /// The most bytes per second to transfer, if limited.
+    rate: Option<u64>,
+
+    /// The most operations to run at once, if limited.
+    max_in_flight: Option<usize>,
+
+    /// When the bytes reserved so far will have been transferred at the limited rate.
+    free_at: Mutex<Instant>,
+
+    slots: Mutex<Slots>,
+}
+
+
+impl Throttle {
+    /// Reserve the transfer of `bytes` and return how long from now it should finish.
+    fn reserve(&self, bytes: u64) -> Option<Duration> {
+        let rate = match self.rate {
+            Some(rate) if rate > 0 => rate,
+            _ => return None,
+        };
+
+        let now = Instant::now();
+        let mut free_at = self.free_at.lock().unwrap();
+        let nanos = bytes.saturating_mul(NANOS_PER_SEC) / rate;
+        let transfer = Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32);
+        *free_at = cmp::max(*free_at, now) + transfer;
+
+        Some(*free_at - now)
+    }
+}
+
+
+/// A future which finishes once an operation may start, with the slot it runs in, if limited.
+struct Acquire {
+    throttle: Arc<Throttle>,
+}
+
+
+/// A running operation's slot, given up when dropped.
+struct Slot {
+    throttle: Arc<Throttle>,
+}
+
+
+impl Future for Acquire {
+    type Item = Option<Slot>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Slot>, Error> {
+        let max_in_flight = match self.throttle.max_in_flight {
+            Some(max_in_flight) => cmp::max(max_in_flight, 1),
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let mut slots = self.throttle.slots.lock().unwrap();
+        if slots.in_flight < max_in_flight {
+            slots.in_flight += 1;
+            Ok(Async::Ready(Some(Slot { throttle: self.throttle.clone() })))
+        } else {
+            slots.waiting.push_back(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut slots = self.throttle.slots.lock().unwrap();
+        slots.in_flight -= 1;
+
+        // A waiting operation may have been dropped since, so every one is woken to try again.
+        for waiting in slots.waiting.drain(..) {
+            waiting.notify();
+        }
+    }
+}
+
+
+/// A store whose operations are limited in rate and concurrency. See the module docs.
+#[derive(Clone)]
+pub struct ThrottledStore<S: ObjectStore> {
+    inner: S,
+    throttle: Arc<Throttle>,
+}
+
+
+impl<S: ObjectStore> ThrottledStore<S> {
+    /// Limit `inner` to `rate` bytes per second and `max_in_flight` operations at once, where
+    /// given.
+    pub fn new(inner: S, rate: Option<u64>, max_in_flight: Option<usize>) -> Self {
+        ThrottledStore {
+            inner,
+            throttle: Arc::new(Throttle {
+                rate,
+                max_in_flight,
+                free_at: Mutex::new(Instant::now()),
+                slots: Mutex::new(Slots::default()),
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn acquire(&self) -> Acquire {
+        Acquire { throttle: self.throttle.clone() }
+    }
+}
+
+
+fn wait(delay: Option<Duration>) -> Box<Future<Item = (), Error = Error> + Send> {
+    match delay {
+        Some(delay) => Box::new(Deadline::after(delay)),
+        None => Box::new(future::ok(())),
+    }
+}
+
+
+impl<S: ObjectStore> ObjectStore for ThrottledStore<S> {
+    type Read = Box<Future<Item = Object, Error = Error> + Send>;
+    type Write = Box<Future<Item = bool, Error = Error> + Send>;
+
+    fn read_object(&self, object_hash: ObjectHash) -> Self::Read {
+        let inner = self.inner.clone();
+        let throttle = self.throttle.clone();
+
+        Box::new(self.acquire().and_then(move |slot| {
+            inner.read_object(object_hash).and_then(move |object| {
+                let delay = throttle.reserve(object.encoded_size());
+                wait(delay).map(move |()| {
+                    drop(slot);
+                    object
+                })
+            })
+        }))
+    }
+
+    fn write_object(&self, hashed: Hashed) -> Self::Write {
+        let inner = self.inner.clone();
+        let throttle = self.throttle.clone();
+
+        Box::new(self.acquire().and_then(move |slot| {
+            let bytes = hashed.as_bytes().map(|bytes| bytes.len() as u64).unwrap_or(0);
+            wait(throttle.reserve(bytes)).and_then(move |()| {
+                inner.write_object(hashed).map(move |written| {
+                    drop(slot);
+                    written
+                })
+            })
+        }))
+    }
+
+    fn is_known(&self, object_hash: ObjectHash) -> bool {
+        self.inner.is_known(object_hash)
+    }
+}
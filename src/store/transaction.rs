@@ -0,0 +1,154 @@
+//! # `transaction` - writing many objects and the branch updates which need them as one.
+//!
+//! A push or commit writes a batch of objects and then moves a branch to point at them. Done
+//! naively, a crash partway through can leave the branch moved while some of the objects it needs
+//! were never written. A `StoreTransaction` collects the objects and branch updates of such a
+//! batch, and `commit` only touches any branch once every object in the batch has been durably
+//! written. A crash mid-commit may leave some objects written which nothing refers to, but never a
+//! branch pointing at missing objects.
+//!
+//! That is all a transaction promises; it is not atomic. The branches are updated one at a time,
+//! so a crash or a conflict partway through the updates leaves the earlier branches moved and the
+//! later ones not, though each points at objects which are all written. Objects left behind by an
+//! interrupted transaction are not cleaned up either. Every store orders its writes with `Batch`
+//! for now, and `commit` and `push` still move their branches themselves; a store which can
+//! update several branches at once would provide its own transactions through
+//! `TransactionalStore`.
+
+use futures::future;
+use futures::prelude::*;
+
+use errors::*;
+use marshal::{Hashed, ObjectHash};
+use store::{Local, ObjectStore, RefStore};
+
+
+/// A branch update to be made once the objects of a transaction are written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BranchUpdate {
+    pub branch: String,
+    pub prev_hash: ObjectHash,
+    pub new_hash: ObjectHash,
+}
+
+
+/// A batch of object writes and branch updates, committed all at once.
+pub trait StoreTransaction: Send + 'static {
+    /// Add an object to be written when the transaction is committed.
+    fn write_object(&mut self, hashed: Hashed);
+
+    /// Move `branch` from `prev_hash` to `new_hash` once every object has been written.
+    fn update_branch(&mut self, branch: String, prev_hash: ObjectHash, new_hash: ObjectHash);
+
+    /// Write every object and then update every branch. Returns the number of objects which the
+    /// store did not already have. If any write fails, no branch is updated; if a branch is no
+    /// longer at its expected commit, fails with `RefConflict` and leaves it as it is, along with
+    /// every branch after it, but not the branches updated before it.
+    fn commit(self) -> Box<Future<Item = u64, Error = Error> + Send>;
+}
+
+
+/// An object store which can begin transactions against the given ref store.
+pub trait TransactionalStore<R: RefStore>: ObjectStore {
+    type Transaction: StoreTransaction;
+
+    fn begin(&self, refs: &R) -> Self::Transaction;
+}
+
+
+/// Update every branch in `updates`, in order, stopping at the first which has moved. The
+/// branches updated before it stay updated.
+pub fn update_branches<R: RefStore>(
+    refs: &R,
+    updates: Vec<BranchUpdate>,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let refs = refs.clone();
+
+    let result = {
+        async_block! {
+            for update in updates {
+                let BranchUpdate { branch, prev_hash, new_hash } = update;
+                let actual = await!(refs.compare_and_swap(branch.clone(), prev_hash, new_hash))?;
+
+                if actual != prev_hash {
+                    bail!(ErrorKind::RefConflict(branch, Some(prev_hash), Some(actual)));
+                }
+            }
+
+            Ok(())
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// A transaction over any object store, which writes every object before touching any branch.
+#[derive(Debug)]
+pub struct Batch<O: ObjectStore, R: RefStore> {
+    objects: O,
+    refs: R,
+    writes: Vec<Hashed>,
+    updates: Vec<BranchUpdate>,
+}
+
+
+impl<O: ObjectStore, R: RefStore> Batch<O, R> {
+    pub fn new(objects: &O, refs: &R) -> Self {
+        Batch {
+            objects: objects.clone(),
+            refs: refs.clone(),
+            writes: Vec::new(),
+            updates: Vec::new(),
+        }
+    }
+}
+
+
+impl<O: ObjectStore, R: RefStore> StoreTransaction for Batch<O, R> {
+    fn write_object(&mut self, hashed: Hashed) {
+        self.writes.push(hashed);
+    }
+
+    fn update_branch(&mut self, branch: String, prev_hash: ObjectHash, new_hash: ObjectHash) {
+        self.updates.push(BranchUpdate {
+            branch,
+            prev_hash,
+            new_hash,
+        });
+    }
+
+    fn commit(self) -> Box<Future<Item = u64, Error = Error> + Send> {
+        let Batch {
+            objects,
+            refs,
+            writes,
+            updates,
+        } = self;
+        let writes = writes
+            .into_iter()
+            .map(|hashed| objects.write_object(hashed))
+            .collect::<Vec<_>>();
+
+        let result = {
+            async_block! {
+                let fresh = await!(future::join_all(writes))?;
+                await!(update_branches(&refs, updates))?;
+
+                Ok(fresh.into_iter().filter(|&fresh| fresh).count() as u64)
+            }
+        };
+
+        Box::new(result)
+    }
+}
+
+
+impl<R: RefStore> TransactionalStore<R> for Local {
+    type Transaction = Batch<Local, R>;
+
+    fn begin(&self, refs: &R) -> Self::Transaction {
+        Batch::new(self, refs)
+    }
+}
+
@@ -0,0 +1,167 @@
+//! # `subrepository` - independently versioned repositories nested inside another.
+//!
+//! A large project may be put together from datasets which each have a history of their own.
+//! Each dataset is kept in a repository of its own, nested at some path inside the project's
+//! working directory, and the project's commits record at that path a
+//! `SubtreeEntry::Subrepository` saying where to clone the nested repository from and which of
+//! its commits to check out.
+//!
+//! The nested repository's objects stay in its own store, so nothing which walks the project's
+//! objects follows the entry, and the project's index leaves the nested working directory alone.
+//! Where to clone from is the path of another repository, here or on a shared filesystem, as with
+//! `subtree join --from`; relative paths are relative to the root of the project. Objects are
+//! copied from that repository's local store into the nested one when they are first needed.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+
+use METADATA_PATH;
+use checkout;
+use errors::*;
+use history::subtree;
+use marshal::{ObjectHash, Object, CommitObject, SubtreeEntry, Marshaller, BackedTree, Tree,
+              TreeOp};
+use repository::Repository;
+use store::ObjectStore;
+use trace::Trace;
+
+
+/// A repository nested inside the subtree of a commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subrepository {
+    /// Where the nested repository is, relative to the root of the subtree.
+    pub path: PathBuf,
+
+    /// Where to clone the nested repository from.
+    pub url: String,
+
+    /// The commit of the nested repository to check out.
+    pub commit_hash: ObjectHash,
+}
+
+
+/// Every repository nested in the commit `commit_hash`, in no particular order.
+pub fn list<S: ObjectStore>(
+    store: &S,
+    commit_hash: ObjectHash,
+) -> Box<Future<Item = Vec<Subrepository>, Error = Error> + Send> {
+    let store = store.clone();
+
+    let result = {
+        async_block! {
+            let subtree_hash = match await!(store.read_object(commit_hash))? {
+                Object::Commit(commit_object) => commit_object.subtree,
+                _ => bail!(ErrorKind::ObjectNotACommit(commit_hash)),
+            };
+            let listing = await!(checkout::walk(&store, subtree_hash))?;
+
+            let subrepositories = listing
+                .subrepositories
+                .into_iter()
+                .map(|(path, url, commit_hash)| Subrepository { path, url, commit_hash })
+                .collect();
+
+            Ok(subrepositories)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// Record `subrepository` in the subtree of `head_opt`, replacing whatever was at its path, and
+/// write a commit of the result whose parent is `head_opt`.
+pub fn record<S: ObjectStore, T: Trace>(
+    store: &S,
+    marshaller: &Marshaller<T>,
+    head_opt: Option<ObjectHash>,
+    subrepository: Subrepository,
+    message: String,
+    timestamp: DateTime<Utc>,
+) -> Box<Future<Item = ObjectHash, Error = Error> + Send> {
+    let store = store.clone();
+    let marshaller = marshaller.clone();
+
+    let result = {
+        async_block! {
+            let Subrepository { path, url, commit_hash } = subrepository;
+            let entry = SubtreeEntry::Subrepository(url, commit_hash);
+
+            let subtree_hash = match head_opt {
+                Some(head) => {
+                    let head_commit = match await!(store.read_object(head))? {
+                        Object::Commit(commit_object) => commit_object,
+                        _ => bail!(ErrorKind::ObjectNotACommit(head)),
+                    };
+
+                    let tree = BackedTree::new(store.clone(), SubtreeEntry::Subtree(head_commit.subtree));
+                    let tree = await!(tree.operate(vec![TreeOp::Insert(path, entry)]))?;
+
+                    await!(tree.marshal(marshaller.clone()))?
+                }
+                None => await!(marshaller.process_tree(vec![(path, entry)].into_iter().collect::<Tree>()))?,
+            };
+
+            let commit_hash = await!(marshaller.process(CommitObject {
+                subtree: subtree_hash,
+                parents: head_opt.into_iter().collect(),
+                message,
+                timestamp,
+                signature: None,
+                author: None,
+                committer: None,
+            }))?;
+
+            Ok(commit_hash)
+        }
+    };
+
+    Box::new(result)
+}
+
+
+/// The repository to clone `url` from, for a repository whose working directory is at `root`.
+pub fn source_path(root: &Path, url: &str) -> PathBuf {
+    root.join(url)
+}
+
+
+/// Open the repository nested at `path` in the working directory of `repository`, initializing
+/// an empty one there if there is none yet.
+pub fn open(repository: &Repository, path: &Path) -> Result<Repository> {
+    let root = repository.paths.base.join(path);
+
+    if !root.join(&*METADATA_PATH).is_dir() {
+        fs::create_dir_all(&root)?;
+        Repository::init(&root)?;
+    }
+
+    Repository::load(root)
+}
+
+
+/// Copy the commit `commit_hash` and all of its history into `nested` from the repository at
+/// `source`, unless `nested` already has it. Returns the number of objects copied.
+pub fn fetch(nested: &mut Repository, source: &Path, commit_hash: ObjectHash) -> Result<u64> {
+    if nested.catalogs.get(None)?.get(commit_hash).is_some() {
+        return Ok(0);
+    }
+
+    let mut source = Repository::load(source)?;
+    let copied = {
+        let source_ctx = source.local(())?;
+        let ctx = nested.local(())?;
+        let copied = subtree::copy_history(source_ctx.store(), ctx.store(), commit_hash).wait()?;
+
+        ctx.close().wait()?;
+        source_ctx.close().wait()?;
+
+        copied
+    };
+    source.cleanup()?;
+
+    Ok(copied)
+}
@@ -0,0 +1,539 @@
+//! # `sync` - work out what has to be sent to bring a remote up to date, and send it.
+//!
+//! Negotiation is done against the remote's catalog, the local record of which objects the remote
+//! is known to hold. Any object in the catalog is assumed to have all of its descendants present
+//! on the remote as well, so the walk never descends into it.
+//!
+//! Fetching works the same way in the other direction, against the local catalog, and may be
+//! limited to a number of commits of history. Commits at the limit are recorded as the shallow
+//! boundary (see the `shallow` module), and fetching from them again carries on past them. It may
+//! also be limited to some paths of the tree, in which case the entries left out are recorded as
+//! promised (see the `promised` module) and may be fetched by path later.
+//!
+//! How objects are moved in either direction is set by `TransferOptions`: how fast and how many
+//! at once, how operations which fail transiently are retried, and a `CancelToken` to stop early.
+//!
+//! An object is only recorded in a catalog once it has been written, but its children arrive after
+//! it, so a transfer which fails or is cancelled part of the way through forgets every object it
+//! wrote which refers to others, along with every object whose write it started but never saw
+//! finish. The next attempt walks into them again and picks up where this one stopped, rather than
+//! assuming that whatever lies beneath them is already there.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{self, FutureResult};
+use futures::prelude::*;
+use futures::stream;
+
+use cancel::CancelToken;
+use catalog::Catalog;
+use errors::*;
+use graph::{self, Visit, Visitor};
+use marshal::{ObjectHash, Object, CommitObject, DataObject, SubtreeObject, SubtreeEntry,
+              serialize_and_hash_with};
+use marshal::canonical::Version;
+use promised::Promised;
+use shallow::Shallow;
+use store::{GuardedStore, ObjectStore, RetryPolicy, RetryStore, ThrottledStore, TimeoutCfg};
+
+
+/// The objects which must be sent to bring a remote up to date with some commit.
+#[derive(Debug, Clone, Default)]
+pub struct PushPlan {
+    /// Every object to send, along with its encoded size.
+    pub objects: Vec<(ObjectHash, u64)>,
+
+    /// The total encoded size of every object to send.
+    pub bytes: u64,
+}
+
+
+impl PushPlan {
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// How long sending the plan would take at the given bandwidth, in bytes per second, ignoring
+    /// per-object latency.
+    pub fn eta(&self, bytes_per_second: u64) -> Duration {
+        if bytes_per_second == 0 {
+            return Duration::from_secs(u64::max_value());
+        }
+
+        let secs = self.bytes / bytes_per_second;
+        let nanos = (self.bytes % bytes_per_second) * 1_000_000_000 / bytes_per_second;
+
+        Duration::new(secs, nanos as u32)
+    }
+}
+
+
+struct PlanVisitor {
+    remote_catalog: Catalog,
+    version: Version,
+    plan: PushPlan,
+}
+
+
+impl Visitor for PlanVisitor {
+    type Future = FutureResult<Visit, Error>;
+
+    fn enter(&mut self, hash: ObjectHash) -> bool {
+        self.remote_catalog.get(hash).is_none()
+    }
+
+    fn visit(&mut self, hash: ObjectHash, object: Object) -> Self::Future {
+        let size = self.version.header_size() + object.encoded_size();
+        self.plan.objects.push((hash, size));
+        self.plan.bytes += size;
+
+        future::ok(Visit::Descend)
+    }
+}
+
+
+/// Walk everything reachable from `commit_hash` in the local store, collecting those objects
+/// which the remote catalog does not record, along with their sizes once encoded with `version`.
+pub fn plan_push<S: ObjectStore>(
+    local: &S,
+    remote_catalog: &Catalog,
+    commit_hash: ObjectHash,
+    version: Version,
+) -> Box<Future<Item = PushPlan, Error = Error> + Send> {
+    let visitor = PlanVisitor {
+        remote_catalog: remote_catalog.clone(),
+        version,
+        plan: PushPlan::default(),
+    };
+
+    Box::new(graph::visit(local, Some(commit_hash), visitor).map(
+        |visitor| visitor.plan,
+    ))
+}
+
+
+/// The number of objects sent or received at once, by default.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+
+/// How a push or fetch moves objects.
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    /// The most bytes per second to send or receive, if limited.
+    pub limit_rate: Option<u64>,
+
+    /// The most objects to send or receive at once.
+    pub max_in_flight: usize,
+
+    /// Retry operations which fail transiently according to this policy, if given.
+    pub retry: Option<RetryPolicy>,
+
+    /// Fail the transfer with `ErrorKind::Cancelled` once this is cancelled.
+    pub cancel: Option<CancelToken>,
+}
+
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            limit_rate: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            transfer: TransferOptions::default(),
+        }
+    }
+}
+
+
+impl TransferOptions {
+    /// Wrap the store at the far end of a transfer in these options.
+    fn wrap<S: ObjectStore>(&self, store: &S) -> GuardedStore<ThrottledStore<RetryStore<S>>> {
+        let retry = self.retry.unwrap_or_else(RetryPolicy::never);
+        let retrying = RetryStore::new(store.clone(), &retry);
+        let throttled = ThrottledStore::new(retrying, self.limit_rate, Some(self.max_in_flight));
+
+        GuardedStore::new(throttled, &TimeoutCfg::default()).with_cancel(self.cancel.clone())
+    }
+}
+
+
+/// Whether an object refers to no others, and so is complete as soon as it has been written.
+fn is_leaf(object: &Object) -> bool {
+    match *object {
+        Object::Data(DataObject::Small(_)) => true,
+        _ => false,
+    }
+}
+
+
+/// Forget every object in `incomplete` once `result` has failed, so that a later transfer walks
+/// into them again. See the module docs.
+fn forget_incomplete<T>(
+    result: Result<T>,
+    catalog: &Catalog,
+    incomplete: &Mutex<HashSet<ObjectHash>>,
+) -> Result<T> {
+    if result.is_err() {
+        for &hash in incomplete.lock().unwrap().iter() {
+            catalog.remove(hash);
+        }
+    }
+
+    result
+}
+
+
+/// Send every object in a plan from `source` to `destination`, whose catalog is
+/// `destination_catalog`, returning the number of objects which the destination did not already
+/// have. Up to `transfer.max_in_flight` objects are sent at once.
+pub fn push<S: ObjectStore, D: ObjectStore>(
+    source: &S,
+    destination: &D,
+    destination_catalog: &Catalog,
+    plan: &PushPlan,
+    version: Version,
+    transfer: &TransferOptions,
+) -> Box<Future<Item = u64, Error = Error> + Send> {
+    let source = source.clone();
+    let destination = transfer.wrap(destination);
+    let destination_catalog = destination_catalog.clone();
+    let hashes = plan.objects.iter().map(|&(hash, _)| hash).collect::<Vec<_>>();
+    let incomplete = Arc::new(Mutex::new(HashSet::new()));
+
+    let result = {
+        let incomplete = incomplete.clone();
+
+        stream::iter_ok::<_, Error>(hashes)
+            .map(move |hash| {
+                let destination = destination.clone();
+                let incomplete = incomplete.clone();
+
+                // Every object is incomplete until its write is seen to finish, so that one which
+                // failed, timed out, or was cancelled part of the way is forgotten too.
+                incomplete.lock().unwrap().insert(hash);
+
+                source.read_object(hash).and_then(move |object| {
+                    let leaf = is_leaf(&object);
+                    let hashed = serialize_and_hash_with(&object, version);
+
+                    destination.write_object(hashed).map(move |written| {
+                        // What was there already, and what refers to nothing, is complete.
+                        if leaf || !written {
+                            incomplete.lock().unwrap().remove(&hash);
+                        }
+                        written
+                    })
+                })
+            })
+            .buffer_unordered(cmp::max(transfer.max_in_flight, 1))
+            .fold(0, |sent, written| Ok::<_, Error>(if written { sent + 1 } else { sent }))
+    };
+
+    Box::new(result.then(move |result| {
+        forget_incomplete(result, &destination_catalog, &incomplete)
+    }))
+}
+
+
+/// Files larger than this are fetched only after every commit, tree and smaller file, by default.
+pub const DEFAULT_DEFER_LARGER_THAN: u64 = 16 * 1024 * 1024;
+
+
+/// What to fetch of the history behind some commits.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Fetch only this many generations of commits, counting the heads themselves as the first.
+    pub depth: Option<usize>,
+
+    /// Fetch only the entries of each tree at or beneath these paths, relative to the root of the
+    /// tree, or every entry if there are none.
+    pub paths: Vec<PathBuf>,
+
+    /// Fetch the contents of files larger than this many bytes last, once everything else has
+    /// arrived, or in the order they are found if not given.
+    pub defer_larger_than: Option<u64>,
+
+    /// How objects are received.
+    pub transfer: TransferOptions,
+}
+
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            depth: None,
+            paths: Vec::new(),
+            defer_larger_than: Some(DEFAULT_DEFER_LARGER_THAN),
+            retry: None,
+            cancel: None,
+        }
+    }
+}
+
+
+/// The outcome of a fetch.
+#[derive(Debug, Clone)]
+pub struct Fetched {
+    /// The number of objects read from the remote.
+    pub objects: u64,
+
+    /// The new shallow boundary.
+    pub shallow: Shallow,
+
+    /// The objects which were left on the remote, as well as any promised before and still not
+    /// fetched.
+    pub promised: Promised,
+}
+
+
+struct FetchVisitor {
+    local_catalog: Catalog,
+    shallow: Shallow,
+    promised: Promised,
+    depth: Option<usize>,
+    paths: Vec<PathBuf>,
+    defer_larger_than: Option<u64>,
+    generations: HashMap<ObjectHash, usize>,
+    tree_paths: HashMap<ObjectHash, PathBuf>,
+    deferred: HashSet<ObjectHash>,
+    visited: HashSet<ObjectHash>,
+    fetched: u64,
+
+    /// The objects fetched which refer to others, forgotten again if the fetch fails.
+    incomplete: Arc<Mutex<HashSet<ObjectHash>>>,
+}
+
+
+impl FetchVisitor {
+    /// Whether the entry at `path` is fetched: if it lies beneath one of the selected paths, or
+    /// is a directory which contains one.
+    fn selects(&self, path: &Path) -> bool {
+        self.paths.iter().any(|selected| {
+            path.starts_with(selected) || selected.starts_with(path)
+        })
+    }
+
+    fn visit_commit(&mut self, hash: ObjectHash, commit_object: CommitObject) -> Visit {
+        if !self.paths.is_empty() {
+            self.tree_paths.entry(commit_object.subtree).or_insert_with(PathBuf::new);
+        }
+
+        let generation = self.generations.get(&hash).cloned().unwrap_or(0);
+        let at_limit = self.depth.map_or(false, |depth| generation + 1 >= depth);
+
+        if at_limit && !commit_object.parents.is_empty() {
+            self.shallow.insert(hash);
+            return Visit::Follow(vec![commit_object.subtree]);
+        }
+
+        self.shallow.remove(&hash);
+        for &parent in &commit_object.parents {
+            self.generations.entry(parent).or_insert(generation + 1);
+        }
+
+        Visit::Descend
+    }
+
+    fn visit_subtree(&mut self, hash: ObjectHash, subtree_object: SubtreeObject) -> Visit {
+        let base = self.tree_paths.remove(&hash).unwrap_or_default();
+        let mut follow = Vec::new();
+
+        for (name, entry) in subtree_object.entries {
+            // A nested repository is fetched from where it is cloned from, not along with this one.
+            if entry.is_subrepository() {
+                continue;
+            }
+
+            let path = base.join(name);
+            let entry_hash = entry.hash();
+
+            if self.selects(&path) {
+                if let SubtreeEntry::Subtree(_) = entry.unannotated() {
+                    self.tree_paths.entry(entry_hash).or_insert(path);
+                }
+                follow.push(entry_hash);
+            } else if !self.visited.contains(&entry_hash) &&
+                       self.local_catalog.get(entry_hash).is_none()
+            {
+                self.promised.insert(entry_hash, path);
+            }
+        }
+
+        Visit::Follow(follow)
+    }
+
+    /// Mark the contents of every large file in a subtree to be fetched last.
+    fn defer_large_files(&mut self, subtree_object: &SubtreeObject) {
+        let threshold = match self.defer_larger_than {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        for entry in subtree_object.entries.values() {
+            match entry.unannotated() {
+                SubtreeEntry::File(hash, size) |
+                SubtreeEntry::Executable(hash, size) if size > threshold => {
+                    self.deferred.insert(hash);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+
+impl Visitor for FetchVisitor {
+    type Future = FutureResult<Visit, Error>;
+
+    fn enter(&mut self, hash: ObjectHash) -> bool {
+        // Boundary commits are held locally, but what lies beyond them is not.
+        self.shallow.contains(&hash) || self.local_catalog.get(hash).is_none()
+    }
+
+    fn defer(&mut self, hash: ObjectHash) -> bool {
+        self.deferred.contains(&hash)
+    }
+
+    fn visit(&mut self, hash: ObjectHash, object: Object) -> Self::Future {
+        self.fetched += 1;
+        self.visited.insert(hash);
+        if !is_leaf(&object) {
+            self.incomplete.lock().unwrap().insert(hash);
+        }
+        self.promised.remove(&hash);
+        let deferred = self.deferred.remove(&hash);
+
+        // The chunks of a deferred file are deferred along with it.
+        match object {
+            Object::Subtree(ref subtree_object) => self.defer_large_files(subtree_object),
+            Object::Data(DataObject::Large(ref large_object)) if deferred => {
+                let chunks = large_object.children.iter().map(|&(_, chunk)| chunk);
+                self.deferred.extend(chunks);
+            }
+            _ => {}
+        }
+
+        let visit = match object {
+            Object::Commit(commit_object) => self.visit_commit(hash, commit_object),
+            Object::Subtree(subtree_object) if !self.paths.is_empty() => {
+                self.visit_subtree(hash, subtree_object)
+            }
+            _ => Visit::Descend,
+        };
+
+        future::ok(visit)
+    }
+}
+
+
+/// Copy everything reachable from the commits `heads` from `remote` into the local store, which
+/// `remote` is expected to write everything it reads through to. Objects in the local catalog are
+/// assumed to be present along with everything they refer to, unless they are in `shallow`.
+///
+/// If a depth is given, any fetched commit whose parents were not is added to the returned
+/// shallow boundary, and any boundary commit whose parents were fetched is removed from it. If
+/// paths are given, every tree entry outside of them is left on the remote and added to the
+/// returned promised objects; promised objects which are fetched are removed from them.
+///
+/// Commits, trees and small files are fetched breadth-first as they are found. The contents of
+/// files larger than `defer_larger_than` are put off until all of those have arrived, so that the
+/// history and the layout of every tree can be browsed long before the largest files are in.
+///
+/// If the fetch fails or is cancelled, every object fetched which refers to others is removed from
+/// the local catalog again, so that fetching once more finishes the job.
+pub fn fetch<S: ObjectStore>(
+    remote: &S,
+    local_catalog: &Catalog,
+    shallow: &Shallow,
+    promised: &Promised,
+    heads: Vec<ObjectHash>,
+    options: &FetchOptions,
+) -> Box<Future<Item = Fetched, Error = Error> + Send> {
+    let remote = options.transfer.wrap(remote);
+    let local_catalog = local_catalog.clone();
+    let incomplete = Arc::new(Mutex::new(HashSet::new()));
+
+    let visitor = FetchVisitor {
+        local_catalog: local_catalog.clone(),
+        shallow: shallow.clone(),
+        promised: promised.clone(),
+        depth: options.depth,
+        paths: options.paths.clone(),
+        defer_larger_than: options.defer_larger_than,
+        generations: HashMap::new(),
+        tree_paths: HashMap::new(),
+        deferred: HashSet::new(),
+        visited: HashSet::new(),
+        fetched: 0,
+        incomplete: incomplete.clone(),
+    };
+
+    let result = graph::visit(&remote, heads, visitor).map(|visitor| {
+        let FetchVisitor {
+            fetched,
+            shallow,
+            mut promised,
+            visited,
+            ..
+        } = visitor;
+
+        // An object may be skipped at one path after being fetched at another.
+        promised.retain(|hash| !visited.contains(hash));
+
+        Fetched {
+            objects: fetched,
+            shallow,
+            promised,
+        }
+    });
+
+    Box::new(result.then(move |result| {
+        forget_incomplete(result, &local_catalog, &incomplete)
+    }))
+}
+
+
+/// Fetch a further `depth` generations of history behind every commit of a shallow boundary.
+pub fn deepen<S: ObjectStore>(
+    remote: &S,
+    local_catalog: &Catalog,
+    shallow: &Shallow,
+    promised: &Promised,
+    depth: usize,
+    paths: Vec<PathBuf>,
+    transfer: &TransferOptions,
+) -> Box<Future<Item = Fetched, Error = Error> + Send> {
+    // The boundary commits are themselves the first generation, already held.
+    let heads = shallow.iter().collect();
+    let options = FetchOptions {
+        depth: Some(depth + 1),
+        paths,
+        transfer: transfer.clone(),
+        ..FetchOptions::default()
+    };
+
+    fetch(remote, local_catalog, shallow, promised, heads, &options)
+}
+
+
+/// Fetch the objects promised at or beneath any of `paths`, or every promised object if there are
+/// none, along with everything they refer to.
+pub fn fetch_promised<S: ObjectStore, P: AsRef<Path>>(
+    remote: &S,
+    local_catalog: &Catalog,
+    shallow: &Shallow,
+    promised: &Promised,
+    paths: &[P],
+    transfer: &TransferOptions,
+) -> Box<Future<Item = Fetched, Error = Error> + Send> {
+    let heads = promised.under(paths);
+    let options = FetchOptions {
+        transfer: transfer.clone(),
+        ..FetchOptions::default()
+    };
+
+    fetch(remote, local_catalog, shallow, promised, heads, &options)
+}
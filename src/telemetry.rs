@@ -0,0 +1,520 @@
+//! # `telemetry` - an opt-in, local-only journal of command metrics.
+//!
+//! When a repository's config sets `telemetry = true`, each command run against it appends a
+//! single `Event` to `.attaca/telemetry.jsonl`, one JSON object per line. Nothing is ever sent
+//! anywhere; the journal exists so that users can summarize it (with `attaca stats`) and report
+//! performance problems with concrete numbers.
+//!
+//! Metrics are gathered through process-wide counters which the library bumps as it works. They
+//! are cheap enough to be updated unconditionally, whether or not the journal is enabled. Besides
+//! the amounts of work done, they count every store operation and the total time it took, the
+//! time spent chunking, and attempted and conflicting branch updates.
+//!
+//! Long-running processes, such as the daemons, can also expose the counters for scraping in the
+//! Prometheus text format with `serve_metrics`.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use serde_json;
+
+use errors::*;
+use profile;
+use repository::Paths;
+
+
+/// Process-wide counters of work done.
+#[derive(Debug, Default)]
+pub struct Counters {
+    bytes_split: AtomicUsize,
+    bytes_fetched: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    objects_written: AtomicUsize,
+    cache_hits: AtomicUsize,
+    cache_misses: AtomicUsize,
+    hash_cache_hits: AtomicUsize,
+    hash_cache_misses: AtomicUsize,
+    local_reads: AtomicUsize,
+    local_read_us: AtomicUsize,
+    local_writes: AtomicUsize,
+    local_write_us: AtomicUsize,
+    remote_reads: AtomicUsize,
+    remote_read_us: AtomicUsize,
+    remote_writes: AtomicUsize,
+    remote_write_us: AtomicUsize,
+    chunk_us: AtomicUsize,
+    ref_updates: AtomicUsize,
+    ref_conflicts: AtomicUsize,
+}
+
+
+/// A kind of store operation, counted and timed by `measured`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    LocalRead,
+    LocalWrite,
+    RemoteRead,
+    RemoteWrite,
+}
+
+
+fn micros(duration: Duration) -> usize {
+    (duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64) as usize
+}
+
+
+lazy_static! {
+    /// The counters for this process.
+    pub static ref COUNTERS: Counters = Counters::default();
+}
+
+
+impl Counters {
+    pub fn add_bytes_split(&self, n: u64) {
+        self.bytes_split.fetch_add(n as usize, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_fetched(&self, n: u64) {
+        self.bytes_fetched.fetch_add(n as usize, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_sent(&self, n: u64) {
+        self.bytes_sent.fetch_add(n as usize, Ordering::Relaxed);
+    }
+
+    pub fn add_object_written(&self) {
+        self.objects_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a read which was served without going to a remote.
+    pub fn add_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a read which had to go to a remote.
+    pub fn add_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a file whose hash was found in the hash cache.
+    pub fn add_hash_cache_hit(&self) {
+        self.hash_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a file which had to be hashed because the hash cache had no valid entry for it.
+    pub fn add_hash_cache_miss(&self) {
+        self.hash_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a store operation which took `elapsed` from start to finish.
+    pub fn add_operation(&self, operation: Operation, elapsed: Duration) {
+        let (count, us) = match operation {
+            Operation::LocalRead => (&self.local_reads, &self.local_read_us),
+            Operation::LocalWrite => (&self.local_writes, &self.local_write_us),
+            Operation::RemoteRead => (&self.remote_reads, &self.remote_read_us),
+            Operation::RemoteWrite => (&self.remote_writes, &self.remote_write_us),
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        us.fetch_add(micros(elapsed), Ordering::Relaxed);
+    }
+
+    /// Record time spent finding chunk boundaries.
+    pub fn add_chunk_time(&self, elapsed: Duration) {
+        self.chunk_us.fetch_add(micros(elapsed), Ordering::Relaxed);
+    }
+
+    /// Record an attempt to move a branch from one commit to another.
+    pub fn add_ref_update(&self) {
+        self.ref_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a branch update refused because the branch had moved in the meantime.
+    pub fn add_ref_conflict(&self) {
+        self.ref_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the counters.
+    pub fn snapshot(&self) -> Measurements {
+        Measurements {
+            bytes_split: self.bytes_split.load(Ordering::Relaxed) as u64,
+            bytes_fetched: self.bytes_fetched.load(Ordering::Relaxed) as u64,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed) as u64,
+            objects_written: self.objects_written.load(Ordering::Relaxed) as u64,
+            cache_hits: self.cache_hits.load(Ordering::Relaxed) as u64,
+            cache_misses: self.cache_misses.load(Ordering::Relaxed) as u64,
+            hash_cache_hits: self.hash_cache_hits.load(Ordering::Relaxed) as u64,
+            hash_cache_misses: self.hash_cache_misses.load(Ordering::Relaxed) as u64,
+            local_reads: self.local_reads.load(Ordering::Relaxed) as u64,
+            local_read_us: self.local_read_us.load(Ordering::Relaxed) as u64,
+            local_writes: self.local_writes.load(Ordering::Relaxed) as u64,
+            local_write_us: self.local_write_us.load(Ordering::Relaxed) as u64,
+            remote_reads: self.remote_reads.load(Ordering::Relaxed) as u64,
+            remote_read_us: self.remote_read_us.load(Ordering::Relaxed) as u64,
+            remote_writes: self.remote_writes.load(Ordering::Relaxed) as u64,
+            remote_write_us: self.remote_write_us.load(Ordering::Relaxed) as u64,
+            chunk_us: self.chunk_us.load(Ordering::Relaxed) as u64,
+            ref_updates: self.ref_updates.load(Ordering::Relaxed) as u64,
+            ref_conflicts: self.ref_conflicts.load(Ordering::Relaxed) as u64,
+        }
+    }
+}
+
+
+/// A snapshot of the process-wide counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Measurements {
+    pub bytes_split: u64,
+    pub bytes_fetched: u64,
+    pub bytes_sent: u64,
+    pub objects_written: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+
+    // Journals written before the hash cache existed lack these.
+    #[serde(default)]
+    pub hash_cache_hits: u64,
+    #[serde(default)]
+    pub hash_cache_misses: u64,
+
+    // Nor do those written before store operations were timed.
+    #[serde(default)]
+    pub local_reads: u64,
+    #[serde(default)]
+    pub local_read_us: u64,
+    #[serde(default)]
+    pub local_writes: u64,
+    #[serde(default)]
+    pub local_write_us: u64,
+    #[serde(default)]
+    pub remote_reads: u64,
+    #[serde(default)]
+    pub remote_read_us: u64,
+    #[serde(default)]
+    pub remote_writes: u64,
+    #[serde(default)]
+    pub remote_write_us: u64,
+    #[serde(default)]
+    pub chunk_us: u64,
+    #[serde(default)]
+    pub ref_updates: u64,
+    #[serde(default)]
+    pub ref_conflicts: u64,
+}
+
+
+/// Count and time `future` as a store operation. See `Counters::add_operation`.
+pub fn measured<F: Future>(operation: Operation, future: F) -> Measured<F> {
+    Measured {
+        operation,
+        started: Instant::now(),
+        future,
+    }
+}
+
+
+/// A future counted and timed as a store operation. See `measured`.
+pub struct Measured<F> {
+    operation: Operation,
+    started: Instant,
+    future: F,
+}
+
+
+impl<F: Future> Future for Measured<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.future.poll();
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+
+        COUNTERS.add_operation(self.operation, self.started.elapsed());
+        result
+    }
+}
+
+
+/// Count the time spent polling `chunks` as time spent chunking, and record it as a profile span.
+pub fn chunking<S: Stream>(chunks: S) -> Chunking<S> {
+    Chunking { chunks }
+}
+
+
+/// A stream of chunks whose production is timed. See `chunking`.
+pub struct Chunking<S> {
+    chunks: S,
+}
+
+
+impl<S: Stream> Stream for Chunking<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let started = Instant::now();
+        let chunks = &mut self.chunks;
+        let result = profile::sync(profile::CPU, "chunk", || chunks.poll());
+        COUNTERS.add_chunk_time(started.elapsed());
+
+        result
+    }
+}
+
+
+/// A single journaled command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// The subcommand run, e.g. `commit` or `remote add`.
+    pub command: String,
+
+    /// When the command started.
+    pub started: DateTime<Utc>,
+
+    /// How long the command took to run, in milliseconds.
+    pub duration_ms: u64,
+
+    /// The work done while running the command.
+    pub measurements: Measurements,
+
+    /// The error the command failed with, if it failed.
+    pub error: Option<String>,
+}
+
+
+/// Append an event to a repository's journal.
+pub fn record(paths: &Paths, event: &Event) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(
+        &paths.telemetry,
+    )?;
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+
+    Ok(())
+}
+
+
+/// Read every event in a repository's journal. Lines which cannot be parsed, for example because
+/// a write was interrupted, are skipped.
+pub fn events(paths: &Paths) -> Result<Vec<Event>> {
+    if !paths.telemetry.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut events = Vec::new();
+    for line_res in BufReader::new(File::open(&paths.telemetry)?).lines() {
+        if let Ok(event) = serde_json::from_str(&line_res?) {
+            events.push(event);
+        }
+    }
+
+    Ok(events)
+}
+
+
+/// Delete a repository's journal.
+pub fn clear(paths: &Paths) -> Result<()> {
+    if paths.telemetry.exists() {
+        fs::remove_file(&paths.telemetry)?;
+    }
+
+    Ok(())
+}
+
+
+/// Aggregate statistics for a single command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub runs: u64,
+    pub failures: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+    pub totals: Measurements,
+}
+
+
+impl Summary {
+    pub fn mean_ms(&self) -> u64 {
+        if self.runs == 0 { 0 } else { self.total_ms / self.runs }
+    }
+
+    /// The fraction of reads served without going to a remote, if any reads were made.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let total = self.totals.cache_hits + self.totals.cache_misses;
+        if total == 0 {
+            None
+        } else {
+            Some(self.totals.cache_hits as f64 / total as f64)
+        }
+    }
+
+    /// The fraction of files committed without being rehashed, if any were looked up.
+    pub fn hash_cache_hit_rate(&self) -> Option<f64> {
+        let total = self.totals.hash_cache_hits + self.totals.hash_cache_misses;
+        if total == 0 {
+            None
+        } else {
+            Some(self.totals.hash_cache_hits as f64 / total as f64)
+        }
+    }
+}
+
+
+/// Summarize events by command.
+pub fn summarize<'a, I: IntoIterator<Item = &'a Event>>(events: I) -> BTreeMap<String, Summary> {
+    let mut summaries = BTreeMap::<String, Summary>::new();
+
+    for event in events {
+        let summary = summaries.entry(event.command.clone()).or_insert_with(
+            Summary::default,
+        );
+
+        summary.runs += 1;
+        if event.error.is_some() {
+            summary.failures += 1;
+        }
+        summary.total_ms += event.duration_ms;
+        if event.duration_ms > summary.max_ms {
+            summary.max_ms = event.duration_ms;
+        }
+
+        let totals = &mut summary.totals;
+        totals.bytes_split += event.measurements.bytes_split;
+        totals.bytes_fetched += event.measurements.bytes_fetched;
+        totals.bytes_sent += event.measurements.bytes_sent;
+        totals.objects_written += event.measurements.objects_written;
+        totals.cache_hits += event.measurements.cache_hits;
+        totals.cache_misses += event.measurements.cache_misses;
+        totals.hash_cache_hits += event.measurements.hash_cache_hits;
+        totals.hash_cache_misses += event.measurements.hash_cache_misses;
+        totals.local_reads += event.measurements.local_reads;
+        totals.local_read_us += event.measurements.local_read_us;
+        totals.local_writes += event.measurements.local_writes;
+        totals.local_write_us += event.measurements.local_write_us;
+        totals.remote_reads += event.measurements.remote_reads;
+        totals.remote_read_us += event.measurements.remote_read_us;
+        totals.remote_writes += event.measurements.remote_writes;
+        totals.remote_write_us += event.measurements.remote_write_us;
+        totals.chunk_us += event.measurements.chunk_us;
+        totals.ref_updates += event.measurements.ref_updates;
+        totals.ref_conflicts += event.measurements.ref_conflicts;
+    }
+
+    summaries
+}
+
+
+fn seconds(us: u64) -> f64 {
+    us as f64 / 1_000_000.0
+}
+
+
+/// Render measurements in the Prometheus text exposition format. Every metric is a counter, since
+/// the measurements only ever grow over the life of a process.
+pub fn exposition(m: &Measurements) -> String {
+    let metrics: &[(&str, &str, f64)] = &[
+        ("split_bytes", "Bytes of files split into chunks.", m.bytes_split as f64),
+        ("chunk_seconds", "Time spent finding chunk boundaries.", seconds(m.chunk_us)),
+        ("fetched_bytes", "Bytes read from remotes.", m.bytes_fetched as f64),
+        ("sent_bytes", "Bytes written to remotes.", m.bytes_sent as f64),
+        ("objects_written", "New objects written.", m.objects_written as f64),
+        ("cache_hits", "Reads served without going to a remote.", m.cache_hits as f64),
+        ("cache_misses", "Reads which went to a remote.", m.cache_misses as f64),
+        ("local_reads", "Objects read from the local store.", m.local_reads as f64),
+        ("local_read_seconds", "Time spent reading locally.", seconds(m.local_read_us)),
+        ("local_writes", "Objects written to the local store.", m.local_writes as f64),
+        ("local_write_seconds", "Time spent writing locally.", seconds(m.local_write_us)),
+        ("remote_reads", "Objects read from remotes.", m.remote_reads as f64),
+        ("remote_read_seconds", "Time spent reading from remotes.", seconds(m.remote_read_us)),
+        ("remote_writes", "Objects written to remotes.", m.remote_writes as f64),
+        ("remote_write_seconds", "Time spent writing to remotes.", seconds(m.remote_write_us)),
+        ("ref_updates", "Attempted branch updates.", m.ref_updates as f64),
+        ("ref_conflicts", "Branch updates refused as the branch moved.", m.ref_conflicts as f64),
+    ];
+
+    let mut out = String::new();
+    for &(name, help, value) in metrics {
+        writeln!(out, "# HELP attaca_{}_total {}", name, help).unwrap();
+        writeln!(out, "# TYPE attaca_{}_total counter", name).unwrap();
+        writeln!(out, "attaca_{}_total {}", name, value).unwrap();
+    }
+
+    out
+}
+
+
+/// How long a scrape may take to send its request or read its answer before it is dropped, so
+/// that a client which stalls cannot hold up the scrapes after it.
+const METRICS_TIMEOUT_MS: u64 = 5_000;
+
+
+fn answer(mut stream: TcpStream) -> Result<()> {
+    let timeout = Some(Duration::from_millis(METRICS_TIMEOUT_MS));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    // Whatever was asked for, the answer is the same, so the request is read only so far as to
+    // not reset the connection by closing it with unread data.
+    let mut request = [0; 1024];
+    if stream.read(&mut request)? == 0 {
+        return Ok(());
+    }
+
+    let body = exposition(&COUNTERS.snapshot());
+    write!(stream, "HTTP/1.0 200 OK\r\n")?;
+    write!(stream, "Content-Type: text/plain; version=0.0.4\r\n")?;
+    write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+
+    Ok(())
+}
+
+
+/// Serve this process's counters over HTTP at `addr`, for Prometheus to scrape, from a background
+/// thread. Every request is answered with the current counters, whatever its path.
+pub fn serve_metrics(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).chain_err(
+        || ErrorKind::MetricsBind(addr.to_owned()),
+    )?;
+
+    thread::Builder::new()
+        .name("attaca-metrics".to_owned())
+        .spawn(move || for stream_res in listener.incoming() {
+            if let Ok(stream) = stream_res {
+                let _ = answer(stream);
+            }
+        })?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exposition_is_prometheus_text() {
+        let measurements = Measurements {
+            bytes_split: 42,
+            chunk_us: 1_500_000,
+            ..Measurements::default()
+        };
+        let text = exposition(&measurements);
+
+        assert!(text.contains(
+            "# TYPE attaca_split_bytes_total counter\nattaca_split_bytes_total 42\n",
+        ));
+        assert!(text.contains("\nattaca_chunk_seconds_total 1.5\n"));
+        assert!(text.lines().all(|line| line.starts_with('#') || line.starts_with("attaca_")));
+    }
+}
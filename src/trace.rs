@@ -26,6 +26,9 @@ pub trait Trace: Clone + Send + Sync + Sized + 'static {
 
     fn on_write_object_finish(&self, _object_hash: &ObjectHash, _fresh: bool) {}
 
+    /// Called when a blocklisted object is written because the blocklist only flags matches.
+    fn on_blocked_object(&self, _object_hash: &ObjectHash) {}
+
     fn on_close(&self) {}
 }
 
@@ -0,0 +1,28 @@
+//! # `warning` - problems worth telling the user about, but not worth failing over.
+//!
+//! The library never prints anything itself. Where it carries on past a problem which the user
+//! should still hear about, such as a file it skipped, a replica it could not write to, or state it
+//! could not save while being dropped, it passes a warning to whatever handler the binary has
+//! installed with `set_handler`. Without a handler, warnings are dropped.
+
+use std::fmt;
+use std::sync::RwLock;
+
+
+lazy_static! {
+    static ref HANDLER: RwLock<Option<Box<Fn(&str) + Send + Sync>>> = RwLock::new(None);
+}
+
+
+/// Send every warning to `handler` from now on, in place of any handler set before.
+pub fn set_handler<F: Fn(&str) + Send + Sync + 'static>(handler: F) {
+    *HANDLER.write().unwrap() = Some(Box::new(handler));
+}
+
+
+/// Report a problem which the caller is carrying on past.
+pub fn warn<T: fmt::Display>(warning: T) {
+    if let Some(ref handler) = *HANDLER.read().unwrap() {
+        handler(&warning.to_string());
+    }
+}
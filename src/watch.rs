@@ -0,0 +1,248 @@
+//! # `watch` - continuous tracking of changes to the worktree.
+//!
+//! Updating the index normally means statting every tracked file, which on a worktree of millions
+//! of files takes far longer than hashing the handful which actually changed. A `Watcher` asks the
+//! operating system (inotify on Linux, FSEvents on macOS) to report every change beneath the root
+//! of the worktree, and remembers when each changed path was last touched. Given the timestamp of
+//! the index, it can then say exactly which paths need to be looked at again.
+//!
+//! Events arrive asynchronously, so before answering, the watcher writes a cookie file into
+//! `.attaca` and waits to hear about it: anything which happened before the question was asked
+//! has been heard about by then. The operating system is also allowed to drop events - for
+//! example, when its event queue overflows. When that happens, the watcher stops vouching for
+//! anything which happened before, and callers fall back to a full update.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use notify::{self, RawEvent, RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher};
+
+use METADATA_PATH;
+use errors::*;
+use repository::Paths;
+
+
+/// The prefix of the names of cookie files.
+const COOKIE_PREFIX: &'static str = "watch-cookie-";
+
+
+/// How long to wait, in milliseconds, to hear about a cookie file before giving up on it.
+const COOKIE_TIMEOUT_MS: u64 = 1000;
+
+
+/// The most changed paths we remember. Past this, we forget them all and behave as if events had
+/// been dropped, rather than growing without bound while nobody updates the index.
+const MAX_CHANGED_PATHS: usize = 1 << 20;
+
+
+/// What has changed in the worktree, as far as we know.
+#[derive(Debug)]
+struct Changes {
+    /// When we began watching. We cannot vouch for anything before this.
+    watching_since: DateTime<Utc>,
+
+    /// When we last lost track of changes, if ever.
+    overflowed_at: Option<DateTime<Utc>>,
+
+    /// Every changed path, relative to the root of the worktree, and when it was last changed.
+    paths: HashMap<PathBuf, DateTime<Utc>>,
+
+    /// The number of the last cookie file we heard about.
+    cookie: u64,
+}
+
+
+impl Changes {
+    fn new() -> Self {
+        Changes {
+            watching_since: Utc::now(),
+            overflowed_at: None,
+            paths: HashMap::new(),
+            cookie: 0,
+        }
+    }
+
+    fn touch(&mut self, path: PathBuf) {
+        if self.paths.len() >= MAX_CHANGED_PATHS {
+            self.overflow();
+        }
+
+        self.paths.insert(path, Utc::now());
+    }
+
+    fn overflow(&mut self) {
+        self.overflowed_at = Some(Utc::now());
+        self.paths.clear();
+    }
+
+    fn since(&self, since: &DateTime<Utc>) -> Option<Vec<PathBuf>> {
+        if since < &self.watching_since || self.overflowed_at.map_or(false, |at| since <= &at) {
+            return None;
+        }
+
+        let changed = self.paths
+            .iter()
+            .filter(|&(_, changed_at)| changed_at >= since)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        Some(changed)
+    }
+}
+
+
+#[derive(Debug)]
+struct Shared {
+    changes: Mutex<Changes>,
+
+    /// Signalled whenever we hear about a cookie file.
+    cookie_seen: Condvar,
+}
+
+
+/// Watches a worktree for changes until dropped.
+pub struct Watcher {
+    // Dropping the underlying watcher stops it, which in turn ends the thread collecting events.
+    _watcher: RecommendedWatcher,
+    shared: Arc<Shared>,
+    metadata: PathBuf,
+    next_cookie: Mutex<u64>,
+}
+
+
+impl Watcher {
+    /// Begin watching the worktree of the repository at `paths`.
+    pub fn start(paths: &Paths) -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher: RecommendedWatcher = notify::raw_watcher(tx)?;
+        watcher.watch(&paths.base, RecursiveMode::Recursive)?;
+
+        let shared = Arc::new(Shared {
+            changes: Mutex::new(Changes::new()),
+            cookie_seen: Condvar::new(),
+        });
+
+        {
+            let base = paths.base.clone();
+            let shared = shared.clone();
+
+            thread::spawn(move || for event in rx {
+                record(&base, &shared, event);
+            });
+        }
+
+        Ok(Watcher {
+            _watcher: watcher,
+            shared,
+            metadata: paths.base.join(*METADATA_PATH),
+            next_cookie: Mutex::new(1),
+        })
+    }
+
+    /// Every path, relative to the root of the worktree, which may have changed at or after
+    /// `since`. A changed directory means anything beneath it may have changed. Returns `None` if
+    /// we cannot be sure we saw every change since then.
+    pub fn changed_since(&self, since: &DateTime<Utc>) -> Option<Vec<PathBuf>> {
+        let cookie = {
+            let mut next_cookie = self.next_cookie.lock().unwrap();
+            *next_cookie += 1;
+            *next_cookie - 1
+        };
+
+        let cookie_path = self.metadata.join(format!("{}{}", COOKIE_PREFIX, cookie));
+        if File::create(&cookie_path).is_err() {
+            return None;
+        }
+
+        let changed = {
+            let timeout = Duration::from_millis(COOKIE_TIMEOUT_MS);
+            let mut changes = self.shared.changes.lock().unwrap();
+
+            while changes.cookie < cookie {
+                let (guard, wait) = self.shared.cookie_seen.wait_timeout(changes, timeout).unwrap();
+                changes = guard;
+
+                if wait.timed_out() {
+                    break;
+                }
+            }
+
+            if changes.cookie >= cookie {
+                changes.since(since)
+            } else {
+                None
+            }
+        };
+
+        let _ = fs::remove_file(&cookie_path);
+
+        changed
+    }
+}
+
+
+/// Record a single event reported by the operating system.
+fn record(base: &Path, shared: &Shared, event: RawEvent) {
+    let mut changes = shared.changes.lock().unwrap();
+
+    // Events without a path report that the operating system lost track of what happened.
+    let path = match (event.path, event.op) {
+        (Some(path), Ok(_)) => path,
+        _ => return changes.overflow(),
+    };
+
+    let relative = match path.strip_prefix(base) {
+        Ok(relative) => relative.to_owned(),
+        Err(_) => return,
+    };
+
+    if !relative.starts_with(*METADATA_PATH) {
+        return changes.touch(relative);
+    }
+
+    // The only changes to the repository metadata we care about are our own cookies.
+    let cookie = match relative.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.starts_with(COOKIE_PREFIX) => {
+            name[COOKIE_PREFIX.len()..].parse::<u64>().ok()
+        }
+        _ => None,
+    };
+
+    if let Some(cookie) = cookie {
+        if cookie > changes.cookie {
+            changes.cookie = cookie;
+            shared.cookie_seen.notify_all();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Duration as ChronoDuration;
+
+    #[test]
+    fn overflow_forgets_earlier_changes() {
+        let mut changes = Changes::new();
+        let before = changes.watching_since;
+
+        changes.touch(PathBuf::from("a"));
+        assert_eq!(changes.since(&before), Some(vec![PathBuf::from("a")]));
+        assert_eq!(changes.since(&(before - ChronoDuration::seconds(1))), None);
+
+        changes.overflow();
+        assert_eq!(changes.since(&before), None);
+
+        let after = Utc::now() + ChronoDuration::seconds(1);
+        changes.touch(PathBuf::from("b"));
+        assert_eq!(changes.since(&after), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,290 @@
+//! # `worktree` - more than one working directory attached to the same repository.
+//!
+//! A linked worktree is a working directory with an `.attaca` directory of its own, which holds
+//! its HEAD, its index, and the state of whatever is in progress in it, and a `commondir` file
+//! naming the root of the repository it belongs to. Everything else - the object store, the
+//! config, branches and remotes - is that repository's, so several commits may be checked out at
+//! once without a second copy of the store. `Paths::new` reads `commondir` to decide which paths
+//! belong to the worktree and which to the repository.
+//!
+//! The repository lists its linked worktrees in `.attaca/worktrees.toml`, so that the objects
+//! their HEADs and indices refer to are never collected, and so that no branch is checked out in
+//! two working directories at once; a branch advanced by a commit in one would otherwise leave the
+//! other's files out of date.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bincode;
+use toml;
+
+use {COMMONDIR_PATH, HEAD_PATH, LOCK_TIMEOUT_MS, METADATA_PATH};
+use errors::*;
+use index::{Cached, Index};
+use lock::LockFile;
+use marshal::ObjectHash;
+use repository::{Head, Paths, Refs};
+
+
+/// The root of the repository the working directory at `base` is a linked worktree of, or `None`
+/// if it is not a linked worktree.
+pub fn common_root(base: &Path) -> Option<PathBuf> {
+    let mut bytes = Vec::new();
+    File::open(base.join(&*COMMONDIR_PATH))
+        .and_then(|mut commondir_file| commondir_file.read_to_end(&mut bytes))
+        .ok()?;
+
+    while bytes.last() == Some(&b'\n') {
+        bytes.pop();
+    }
+
+    Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+
+/// Read the HEAD of a linked worktree. A worktree which has never had a HEAD written is on no
+/// commit at all.
+pub fn read_head(path: &Path) -> Result<Head> {
+    if !path.exists() {
+        return Ok(Head::Root);
+    }
+
+    let mut bytes = Vec::new();
+    File::open(path)
+        .map_err(Error::from)
+        .and_then(|mut head_file| head_file.read_to_end(&mut bytes).map_err(Error::from))
+        .and_then(|_| bincode::deserialize(&bytes).map_err(Error::from))
+        .chain_err(|| ErrorKind::OpenRefs(path.to_owned()))
+}
+
+
+/// Write the HEAD of a linked worktree, moving it into place once fully written.
+pub fn write_head(path: &Path, head: &Head) -> Result<()> {
+    let temp_path = path.with_extension("bin.tmp");
+
+    bincode::serialize(head, bincode::Infinite)
+        .map_err(Error::from)
+        .and_then(|bytes| {
+            File::create(&temp_path)?.write_all(&bytes)?;
+            fs::rename(&temp_path, path)?;
+            Ok(())
+        })
+        .chain_err(|| ErrorKind::CloseRefs(path.to_owned()))
+}
+
+
+/// The linked worktrees recorded in `.attaca/worktrees.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Registry {
+    #[serde(default)]
+    worktrees: Vec<PathBuf>,
+}
+
+
+impl Registry {
+    fn open(paths: &Paths) -> Result<Self> {
+        if !paths.worktrees.exists() {
+            return Ok(Registry::default());
+        }
+
+        let mut registry_string = String::new();
+        File::open(&paths.worktrees)?.read_to_string(&mut registry_string)?;
+
+        Ok(toml::from_str(&registry_string)?)
+    }
+
+    fn save(&self, paths: &Paths) -> Result<()> {
+        let temp_path = paths.worktrees.with_extension("toml.tmp");
+        File::create(&temp_path)?.write_all(toml::to_string(self)?.as_bytes())?;
+        fs::rename(temp_path, &paths.worktrees)?;
+
+        Ok(())
+    }
+
+    /// Acquire the lock guarding changes to the registry.
+    fn lock(paths: &Paths) -> Result<LockFile> {
+        LockFile::acquire_timeout(
+            &paths.worktrees.with_extension("lock"),
+            Duration::from_millis(LOCK_TIMEOUT_MS),
+        )
+    }
+}
+
+
+/// A working directory of a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Worktree {
+    /// The root of the working directory.
+    pub path: PathBuf,
+
+    /// What is checked out there, or `None` if a linked worktree has been deleted without being
+    /// removed from the repository.
+    pub head: Option<Head>,
+}
+
+
+/// Every working directory of the repository `paths` belongs to, starting with the repository's
+/// own.
+pub fn list(paths: &Paths) -> Result<Vec<Worktree>> {
+    let mut worktrees = vec![
+        Worktree {
+            path: paths.common.clone(),
+            head: Some(Refs::open(&Paths::new(&paths.common))?.head),
+        },
+    ];
+
+    for path in Registry::open(paths)?.worktrees {
+        let head = if path.join(&*METADATA_PATH).is_dir() {
+            Some(read_head(&path.join(&*HEAD_PATH))?)
+        } else {
+            None
+        };
+
+        worktrees.push(Worktree { path, head });
+    }
+
+    Ok(worktrees)
+}
+
+
+/// The working directory the local branch `branch` is checked out in, if any.
+pub fn checked_out(paths: &Paths, branch: &str) -> Result<Option<PathBuf>> {
+    let worktrees = list(paths)?;
+    let found = worktrees.into_iter().find(|worktree| match worktree.head {
+        Some(Head::LocalRef(ref name)) => name == branch,
+        _ => false,
+    });
+
+    Ok(found.map(|worktree| worktree.path))
+}
+
+
+/// Attach a new working directory at `path` to the repository `paths` belongs to, with the HEAD
+/// `head` and an empty index. Nothing is checked out into it.
+pub fn add(paths: &Paths, path: &Path, head: Head) -> Result<PathBuf> {
+    let _lock = Registry::lock(paths)?;
+
+    if let Head::LocalRef(ref branch) = head {
+        if let Some(checked_out_at) = checked_out(paths, branch)? {
+            bail!(ErrorKind::BranchCheckedOut(branch.clone(), checked_out_at));
+        }
+    }
+
+    fs::create_dir_all(path)?;
+    let base = path.canonicalize()?;
+    let metadata = base.join(&*METADATA_PATH);
+    if metadata.is_dir() {
+        bail!("a repository already exists at {}!", metadata.display());
+    }
+
+    fs::create_dir_all(&metadata)
+        .chain_err(|| format!("error creating {}", metadata.display()))?;
+
+    let common = paths.common.canonicalize()?;
+    File::create(base.join(&*COMMONDIR_PATH))?
+        .write_all(common.as_os_str().as_bytes())?;
+
+    let worktree_paths = Arc::new(Paths::new(&base));
+    write_head(&base.join(&*HEAD_PATH), &head)?;
+    Index::open(&worktree_paths)?.cleanup()?;
+
+    let mut registry = Registry::open(paths)?;
+    registry.worktrees.push(base.clone());
+    registry.save(paths)?;
+
+    Ok(base)
+}
+
+
+/// Detach the linked worktree at `path` from the repository. Its `.attaca` directory is deleted,
+/// along with any changes recorded only in its index; its files are left alone.
+pub fn remove(paths: &Paths, path: &Path) -> Result<()> {
+    let _lock = Registry::lock(paths)?;
+
+    // A worktree whose directory is gone can no longer be canonicalized.
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    let mut registry = Registry::open(paths)?;
+    let position = match registry.worktrees.iter().position(|worktree| worktree == &path) {
+        Some(position) => position,
+        None => bail!(ErrorKind::WorktreeNotFound(path)),
+    };
+
+    let metadata = path.join(&*METADATA_PATH);
+    if metadata.is_dir() {
+        fs::remove_dir_all(&metadata)?;
+    }
+
+    registry.worktrees.remove(position);
+    registry.save(paths)
+}
+
+
+/// The commits checked out in, and objects recorded in the indices of, every working directory
+/// other than that of `paths`, as resolved against `refs`. These must be kept alive along with
+/// the refs.
+pub fn roots(paths: &Paths, refs: &Refs) -> Result<HashSet<ObjectHash>> {
+    let mut roots = HashSet::new();
+
+    for worktree in list(paths)? {
+        let head = match worktree.head {
+            Some(head) => head,
+            None => continue,
+        };
+
+        let worktree_paths = Arc::new(Paths::new(&worktree.path));
+        if worktree_paths.base == paths.base {
+            continue;
+        }
+
+        roots.extend(Refs { head, ..refs.clone() }.head());
+        for (_, entry) in Index::open(&worktree_paths)?.iter() {
+            if let Cached::Hashed(hash, _) = entry.cached {
+                roots.insert(hash);
+            }
+        }
+    }
+
+    Ok(roots)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bench::Scratch;
+    use repository;
+
+    #[test]
+    fn linked_worktree_shares_refs_but_not_head() {
+        let scratch = Scratch::new("attaca-worktree").unwrap();
+        let root = scratch.path();
+
+        let paths = repository::init(root.join("main")).unwrap();
+        let linked = add(&paths, &root.join("linked"), Head::Detached(ObjectHash::zero())).unwrap();
+        let linked_paths = Paths::new(&linked);
+
+        assert_eq!(linked_paths.common, paths.common.canonicalize().unwrap());
+        assert_eq!(linked_paths.refs, linked_paths.common.join(".attaca/refs.bin"));
+        assert_eq!(linked_paths.index, linked.join(".attaca/index.bin"));
+        assert_eq!(
+            Refs::open(&linked_paths).unwrap().head,
+            Head::Detached(ObjectHash::zero())
+        );
+        assert_eq!(
+            Refs::open(&paths).unwrap().head,
+            Head::LocalRef(repository::DEFAULT_BRANCH.to_owned())
+        );
+
+        assert!(add(&paths, &root.join("other"), Head::LocalRef("master".to_owned())).is_err());
+
+        remove(&paths, &linked).unwrap();
+        assert_eq!(list(&paths).unwrap().len(), 1);
+    }
+}